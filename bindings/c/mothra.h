@@ -1,6 +1,8 @@
 #ifndef _MOTHRA_C_H_
 #define _MOTHRA_C_H_
 
+#include <stddef.h>
+
 #ifdef _WIN64
    #define EXPORT __declspec(dllexport)
    #define IMPORT __declspec(dllimport)
@@ -16,16 +18,22 @@ extern "C" {
 EXPORT void network_start(char**, int, char**, int);
 EXPORT void send_gossip(unsigned char*, int, unsigned char*, int);
 EXPORT void send_rpc_request(unsigned char*, int, unsigned char*, int, unsigned char*, int);
-EXPORT void send_rpc_response(unsigned char*, int, unsigned char*, int, unsigned char*, int);
+EXPORT void send_rpc_response(unsigned char*, int, unsigned char*, int, unsigned char, unsigned char*, int);
+EXPORT void send_rpc_response_chunk(unsigned char*, int, unsigned char, unsigned char*, int);
+EXPORT void send_rpc_end_of_stream(unsigned char*, int, unsigned char);
+EXPORT unsigned long long send_dial(unsigned char*, int);
+EXPORT size_t get_dht_snapshot(unsigned char*, size_t);
 
 EXPORT void register_handlers(
-   void (*discovered_peer_ptr)(const unsigned char*, int), 
+   void (*discovered_peer_ptr)(const unsigned char*, int, int), 
    void (*receive_gossip_ptr)(const unsigned char*, int, const unsigned char*, int, const unsigned char*, int, unsigned char*, int), 
    void (*receive_rpc_ptr)(const unsigned char*, int, int, const unsigned char*, int, unsigned char*, int)
 );
+EXPORT void register_error_handler(void (*on_error_ptr)(int, const unsigned char*, int));
+EXPORT void register_dial_handler(void (*dial_result_ptr)(unsigned long long, int, const unsigned char*, int));
        
 // Events functions called by Core
-EXPORT void discovered_peer(const unsigned char*, int);
+EXPORT void discovered_peer(const unsigned char*, int, int);
 EXPORT void receive_gossip(const unsigned char*, int, const unsigned char*, int, const unsigned char*, int, unsigned char*, int);
 EXPORT void receive_rpc(const unsigned char*, int, int, const unsigned char*, int, unsigned char*, int);
 
@@ -32,13 +32,13 @@ namespace Bindings
         public static extern unsafe void SendRequest(byte* methodUtf8, int methodLength, byte* peerUtf8, int peerLength, byte* data, int dataLength);
 
         [DllImport(DllName, EntryPoint = "send_rpc_response", CallingConvention = CallingConvention.Cdecl)]
-        public static extern unsafe void SendResponse(byte* methodUtf8, int methodLength, byte* peerUtf8, int peerLength, byte* data, int dataLength);
+        public static extern unsafe void SendResponse(byte* methodUtf8, int methodLength, byte* peerUtf8, int peerLength, byte index, byte* data, int dataLength);
 
         [DllImport(DllName, EntryPoint = "register_handlers", CallingConvention = CallingConvention.Cdecl)]
         public static extern unsafe void RegisterHandlers(DiscoveredPeer discoveredPeer, ReceiveGossip receiveGossip, ReceiveRpc receiveRpc);
         
         [UnmanagedFunctionPointer(CallingConvention.Cdecl)]
-        public unsafe delegate void DiscoveredPeer(byte* peerUtf8, int peerLength);
+        public unsafe delegate void DiscoveredPeer(byte* peerUtf8, int peerLength, int isNew);
         
         [UnmanagedFunctionPointer(CallingConvention.Cdecl)]
         public unsafe delegate void ReceiveGossip(byte* messageIdUtf8, int messageIdLength, byte* peerIdUtf8, int peerIdLength, byte* topicUtf8, int topicLength, byte* data, int dataLength);
@@ -6,15 +6,15 @@ import java.util.function.Function;
 
 public class Mothra {
     public static final String MOTHRA = "mothra-jni";
-    public static Function<String, Boolean> DiscoveryMessage;
+    public static BiFunction<String, Boolean, Boolean> DiscoveryMessage;
     public static BiFunction<String, byte[], Boolean> ReceivedGossipMessage;
     public static QuadFunction<String, Integer, String, byte[], Boolean> ReceivedRPCMessage;
     public static native void Init();
     public static native void Start(String[] client_constants, String[] args);
     public static native void SendGossip(byte[] topic, byte[] message);
-    public static native void SendRPC(byte[] method, int req_resp, byte[] peer, byte[] message);
-    public static void DiscoveredPeer(byte[] peer) {
-        DiscoveryMessage.apply(new String(peer));
+    public static native void SendRPC(byte[] method, int req_resp, byte[] peer, int index, byte[] message);
+    public static void DiscoveredPeer(byte[] peer, boolean isNew) {
+        DiscoveryMessage.apply(new String(peer), isNew);
     }
     public static void ReceiveGossip(byte[] message_id, byte[] peer_id, byte[] topic, byte[] message) {
         ReceivedGossipMessage.apply(new String(topic), message);
@@ -55,7 +55,7 @@ JNIEXPORT void JNICALL Java_p2p_mothra_Mothra_SendGossip(JNIEnv *jenv, jclass jc
     if (data) (*jenv)->ReleaseByteArrayElements(jenv, jdata, (jbyte *)data, 0);
 }
 
-JNIEXPORT void JNICALL Java_p2p_mothra_Mothra_SendRPC(JNIEnv *jenv, jclass jcls, jbyteArray jmethod, jint jreq_resp, jbyteArray jpeer, jbyteArray jdata){
+JNIEXPORT void JNICALL Java_p2p_mothra_Mothra_SendRPC(JNIEnv *jenv, jclass jcls, jbyteArray jmethod, jint jreq_resp, jbyteArray jpeer, jint jindex, jbyteArray jdata){
     int data_length = (*jenv)->GetArrayLength(jenv, jdata);
     int method_length = (*jenv)->GetArrayLength(jenv, jmethod);
     int peer_length = (*jenv)->GetArrayLength(jenv, jpeer);
@@ -78,14 +78,14 @@ JNIEXPORT void JNICALL Java_p2p_mothra_Mothra_SendRPC(JNIEnv *jenv, jclass jcls,
     if (jreq_resp == 0){
         send_rpc_request(method,method_length,peer,peer_length,data,data_length);
     } else if (jreq_resp == 1){
-        send_rpc_response(method,method_length,peer,peer_length,data,data_length);
+        send_rpc_response(method,method_length,peer,peer_length,(unsigned char)jindex,data,data_length);
     }
     if (data) (*jenv)->ReleaseByteArrayElements(jenv, jdata, (jbyte *)data, 0);
     if (peer) (*jenv)->ReleaseByteArrayElements(jenv, jpeer, (jbyte *)peer, 0);
     if (method) (*jenv)->ReleaseByteArrayElements(jenv, jmethod, (jbyte *)method, 0);
 }
 
-void discovered_peer_callback(const unsigned char* peer, int peer_length) {
+void discovered_peer_callback(const unsigned char* peer, int peer_length, int is_new) {
     JNIEnv *jenv;
     jint rs = (*jvm)->AttachCurrentThread(jvm, (void**)&jenv, NULL);
     assert (rs == JNI_OK);
@@ -103,12 +103,12 @@ void discovered_peer_callback(const unsigned char* peer, int peer_length) {
         if(!jpeer){
             detach(jenv);
         }
-        discoveredpeer_method = (*jenv)->GetStaticMethodID(jenv, mothra_class, "DiscoveredPeer", "([B)V");
+        discoveredpeer_method = (*jenv)->GetStaticMethodID(jenv, mothra_class, "DiscoveredPeer", "([BZ)V");
         if(!discoveredpeer_method){
-            printf("JNI Error: GetStaticMethodID was unable to find method: DiscoveredPeer with signature: ([B)V\n");
+            printf("JNI Error: GetStaticMethodID was unable to find method: DiscoveredPeer with signature: ([BZ)V\n");
             detach(jenv);
         }
-        (*jenv)->CallStaticVoidMethod(jenv, mothra_class, discoveredpeer_method, jpeer);
+        (*jenv)->CallStaticVoidMethod(jenv, mothra_class, discoveredpeer_method, jpeer, is_new ? JNI_TRUE : JNI_FALSE);
     }
 }
 
@@ -8,9 +8,9 @@ extern "C" {
 JNIEXPORT void JNICALL Java_p2p_mothra_Mothra_Init(JNIEnv*, jclass);
 JNIEXPORT void JNICALL Java_p2p_mothra_Mothra_Start(JNIEnv*, jclass, jobjectArray, jobjectArray);
 JNIEXPORT void JNICALL Java_p2p_mothra_Mothra_SendGossip(JNIEnv*, jclass, jbyteArray, jbyteArray);
-JNIEXPORT void JNICALL Java_p2p_mothra_Mothra_SendRPC (JNIEnv*, jclass, jbyteArray, jint, jbyteArray, jbyteArray);
+JNIEXPORT void JNICALL Java_p2p_mothra_Mothra_SendRPC (JNIEnv*, jclass, jbyteArray, jint, jbyteArray, jint, jbyteArray);
 
-void discovered_peer_callback(const unsigned char*, int);
+void discovered_peer_callback(const unsigned char*, int, int);
 void receive_gossip_callback(const unsigned char*, int, const unsigned char*, int, const unsigned char*, int, unsigned char*, int);
 void receive_rpc_callback(const unsigned char*, int, int, const unsigned char*, int, unsigned char*, int);
 
@@ -1,10 +1,12 @@
 use cast::i16;
 use env_logger::Env;
 use mothra::{
-    cli_app, gossip, rpc_request, rpc_response, Mothra, NetworkGlobals, NetworkMessage, Subscriber,
+    cli_app, dht_snapshot, dial, gossip, log_level::RuntimeLevelFilter, rpc::ProtocolId,
+    rpc_end_of_stream, rpc_request, rpc_response, rpc_response_chunk, BufferPool, GossipMessage,
+    Mothra, NetworkError, NetworkErrorKind, NetworkGlobals, NetworkMessage, Subscriber,
     TaskExecutor,
 };
-use slog::{debug, info, o, trace, warn, Drain, Level, Logger};
+use slog::{debug, info, o, trace, warn, Drain, Logger};
 use std::ffi::CStr;
 use std::os::raw::{c_char, c_uchar};
 use std::sync::Arc;
@@ -22,7 +24,7 @@ struct Context {
 
 static mut CONTEXT: Vec<Context> = Vec::new();
 
-type DiscoveredPeerType = unsafe extern "C" fn(peer: *const c_uchar, peer_length: i16);
+type DiscoveredPeerType = unsafe extern "C" fn(peer: *const c_uchar, peer_length: i16, is_new: i16);
 type ReceiveGossipType = unsafe extern "C" fn(
     message_id: *const c_uchar,
     message_id_length: i16,
@@ -42,35 +44,56 @@ type ReceiveRpcType = unsafe extern "C" fn(
     data: *mut c_uchar,
     data_length: i16,
 );
+type OnErrorType = unsafe extern "C" fn(kind: i16, context: *const c_uchar, context_length: i16);
+type DialResultType = unsafe extern "C" fn(
+    token: u64,
+    success: i16,
+    peer_or_error: *const c_uchar,
+    peer_or_error_length: i16,
+);
 static mut DISCOVERED_PEER_PTR: Option<DiscoveredPeerType> = None;
 static mut RECEIVE_GOSSIP_PTR: Option<ReceiveGossipType> = None;
 static mut RECEIVE_RPC_PTR: Option<ReceiveRpcType> = None;
+static mut ON_ERROR_PTR: Option<OnErrorType> = None;
+static mut DIAL_RESULT_PTR: Option<DialResultType> = None;
+
+/// Passes an error to the registered error handler, if any.
+fn report_error(kind: NetworkErrorKind, context: String) {
+    let context_length = i16(context.len()).unwrap_or(i16::MAX);
+    unsafe {
+        if let Some(on_error) = ON_ERROR_PTR {
+            on_error(kind as i16, context.as_ptr(), context_length)
+        }
+    };
+}
 
-struct Client;
+struct Client {
+    /// The pool the payloads are given back to once the callbacks return.
+    pool: Arc<BufferPool>,
+}
 
 impl Client {
-    pub fn new() -> Self {
-        Client {}
+    pub fn new(pool: Arc<BufferPool>) -> Self {
+        Client { pool }
     }
 }
 
 impl Subscriber for Client {
     fn init(&mut self, network_send: mpsc::UnboundedSender<NetworkMessage>, fork_id: Vec<u8>) {}
 
-    fn discovered_peer(&self, peer: String) {
+    fn discovered_peer(&self, peer: String, new: bool) {
         let peer_length = i16(peer.len()).unwrap();
-        unsafe { DISCOVERED_PEER_PTR.unwrap()(peer.as_ptr(), peer_length) };
+        unsafe { DISCOVERED_PEER_PTR.unwrap()(peer.as_ptr(), peer_length, i16::from(new)) };
     }
 
-    fn receive_gossip(
-        &self,
-        message_id: String,
-        sequence_number: u64,
-        agent_string: String,
-        peer_id: String,
-        topic: String,
-        mut data: Vec<u8>,
-    ) {
+    fn receive_gossip(&self, message: GossipMessage) {
+        let GossipMessage {
+            message_id,
+            peer_id,
+            topic,
+            mut data,
+            ..
+        } = message;
         let message_id_length = i16(topic.len()).unwrap();
         let peer_id_length = i16(topic.len()).unwrap();
         let topic_length = i16(topic.len()).unwrap();
@@ -87,9 +110,17 @@ impl Subscriber for Client {
                 data_length,
             )
         };
+        self.pool.give(data);
     }
 
-    fn receive_rpc(&self, method: String, req_resp: u8, peer: String, mut data: Vec<u8>) {
+    fn receive_rpc(
+        &self,
+        method: String,
+        req_resp: u8,
+        _protocol: Option<ProtocolId>,
+        peer: String,
+        mut data: Vec<u8>,
+    ) {
         let method_length = i16(method.len()).unwrap();
         let peer_length = i16(peer.len()).unwrap();
         let data_length = i16(data.len()).unwrap();
@@ -104,6 +135,24 @@ impl Subscriber for Client {
                 data_length,
             )
         };
+        self.pool.give(data);
+    }
+
+    fn on_error(&self, kind: NetworkErrorKind, context: String) {
+        report_error(kind, context);
+    }
+
+    fn dial_result(&self, token: u64, result: Result<String, String>) {
+        let (success, peer_or_error) = match result {
+            Ok(peer) => (1, peer),
+            Err(error) => (0, error),
+        };
+        let length = i16(peer_or_error.len()).unwrap_or(i16::MAX);
+        unsafe {
+            if let Some(dial_result) = DIAL_RESULT_PTR {
+                dial_result(token, success, peer_or_error.as_ptr(), length)
+            }
+        };
     }
 }
 
@@ -118,6 +167,19 @@ pub unsafe extern "C" fn register_handlers(
     RECEIVE_RPC_PTR = Some(receive_rpc);
 }
 
+/// Registers a handler for the failures of sends and of the network service. It is optional and
+/// may be registered after `register_handlers`.
+#[no_mangle]
+pub unsafe extern "C" fn register_error_handler(on_error: OnErrorType) {
+    ON_ERROR_PTR = Some(on_error);
+}
+
+/// Registers a handler for the outcome of the dials requested with `send_dial`. It is optional.
+#[no_mangle]
+pub unsafe extern "C" fn register_dial_handler(dial_result: DialResultType) {
+    DIAL_RESULT_PTR = Some(dial_result);
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn network_start(
     client_constants: *mut *mut c_char,
@@ -180,22 +242,16 @@ pub unsafe extern "C" fn network_start(
     let decorator = slog_term::TermDecorator::new().build();
     let drain = slog_term::CompactFormat::new(decorator).build().fuse();
     let drain = slog_async::Async::new(drain).build();
-    let drain = match config.debug_level.as_str() {
-        "info" => drain.filter_level(Level::Info),
-        "debug" => drain.filter_level(Level::Debug),
-        "trace" => drain.filter_level(Level::Trace),
-        "warn" => drain.filter_level(Level::Warning),
-        "error" => drain.filter_level(Level::Error),
-        "crit" => drain.filter_level(Level::Critical),
-        _ => drain.filter_level(Level::Info),
-    };
+    // filtered on the config's log level, which NetworkMessage::SetLogLevel changes
+    let drain = RuntimeLevelFilter::new(drain, config.log_level.clone());
     let slog = Logger::root(drain.fuse(), o!());
     let log = slog.new(o!("FFI" => "Mothra"));
     // TODO: build the current enr_fork_id for adding to our local ENR
     let enr_fork_id = [0u8; 32].to_vec();
     let meta_data = [0u8; 32].to_vec();
     let ping_data = [0u8; 32].to_vec();
-    let client = Box::new(Client::new()) as Box<dyn Subscriber + Send>;
+    let client = Box::new(Client::new(config.network_config.buffer_pool.clone()))
+        as Box<dyn Subscriber + Send>;
     let mut runtime = Runtime::new()
         .map_err(|e| format!("Failed to start runtime: {:?}", e))
         .unwrap();
@@ -239,7 +295,8 @@ pub unsafe extern "C" fn send_gossip(
         str::from_utf8_unchecked(slice::from_raw_parts(topic, topic_length)).into(),
         slice::from_raw_parts_mut(data, data_length).to_vec(),
         CONTEXT[0].log.clone(),
-    );
+    )
+    .unwrap_or_else(|e: NetworkError| report_error(e.kind, e.context));
 }
 
 #[no_mangle]
@@ -257,7 +314,8 @@ pub unsafe extern "C" fn send_rpc_request(
         str::from_utf8_unchecked(slice::from_raw_parts(peer, peer_length)).into(),
         slice::from_raw_parts_mut(data, data_length).to_vec(),
         CONTEXT[0].log.clone(),
-    );
+    )
+    .unwrap_or_else(|e: NetworkError| report_error(e.kind, e.context));
 }
 
 #[no_mangle]
@@ -266,6 +324,7 @@ pub unsafe extern "C" fn send_rpc_response(
     method_length: usize,
     peer: *mut c_uchar,
     peer_length: usize,
+    index: c_uchar,
     data: *mut c_uchar,
     data_length: usize,
 ) {
@@ -273,7 +332,81 @@ pub unsafe extern "C" fn send_rpc_response(
         CONTEXT[0].network_send.clone(),
         str::from_utf8_unchecked(slice::from_raw_parts(method, method_length)).into(),
         str::from_utf8_unchecked(slice::from_raw_parts(peer, peer_length)).into(),
+        index,
         slice::from_raw_parts_mut(data, data_length).to_vec(),
         CONTEXT[0].log.clone(),
-    );
+    )
+    .unwrap_or_else(|e: NetworkError| report_error(e.kind, e.context));
+}
+
+/// Sends a chunk of a response of several chunks, which `send_rpc_end_of_stream` ends. `index` is
+/// the first byte of the request's data passed to the rpc handler.
+#[no_mangle]
+pub unsafe extern "C" fn send_rpc_response_chunk(
+    peer: *mut c_uchar,
+    peer_length: usize,
+    index: c_uchar,
+    data: *mut c_uchar,
+    data_length: usize,
+) {
+    rpc_response_chunk(
+        CONTEXT[0].network_send.clone(),
+        str::from_utf8_unchecked(slice::from_raw_parts(peer, peer_length)).into(),
+        index,
+        slice::from_raw_parts_mut(data, data_length).to_vec(),
+        CONTEXT[0].log.clone(),
+    )
+    .unwrap_or_else(|e: NetworkError| report_error(e.kind, e.context));
+}
+
+/// Ends the response of several chunks to the request received with `index`.
+#[no_mangle]
+pub unsafe extern "C" fn send_rpc_end_of_stream(
+    peer: *mut c_uchar,
+    peer_length: usize,
+    index: c_uchar,
+) {
+    rpc_end_of_stream(
+        CONTEXT[0].network_send.clone(),
+        str::from_utf8_unchecked(slice::from_raw_parts(peer, peer_length)).into(),
+        index,
+        CONTEXT[0].log.clone(),
+    )
+    .unwrap_or_else(|e: NetworkError| report_error(e.kind, e.context));
+}
+
+/// Dials an address and returns the token its outcome is passed to the dial handler with, or 0 if
+/// the dial could not be requested.
+#[no_mangle]
+pub unsafe extern "C" fn send_dial(address: *mut c_uchar, address_length: usize) -> u64 {
+    dial(
+        CONTEXT[0].network_send.clone(),
+        str::from_utf8_unchecked(slice::from_raw_parts(address, address_length)).into(),
+        CONTEXT[0].log.clone(),
+    )
+    .unwrap_or_else(|e: NetworkError| {
+        report_error(e.kind, e.context);
+        0
+    })
+}
+
+/// Writes the discovery routing table snapshot into `buffer` as text, one line per query count,
+/// bucket and ENR, and returns its length. If the snapshot is longer than `capacity` nothing is
+/// written, and the length returned is the capacity to call again with. Returns 0 if the network
+/// service has stopped. Blocks until the network service answers, so it must not be called from a
+/// handler.
+#[no_mangle]
+pub unsafe extern "C" fn get_dht_snapshot(buffer: *mut c_uchar, capacity: usize) -> usize {
+    let snapshot = match futures::executor::block_on(dht_snapshot(CONTEXT[0].network_send.clone()))
+    {
+        Ok(snapshot) => snapshot.to_string(),
+        Err(e) => {
+            report_error(e.kind, e.context);
+            return 0;
+        }
+    };
+    if snapshot.len() <= capacity {
+        slice::from_raw_parts_mut(buffer, snapshot.len()).copy_from_slice(snapshot.as_bytes());
+    }
+    snapshot.len()
 }
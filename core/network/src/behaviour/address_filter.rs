@@ -0,0 +1,104 @@
+//! Filters the listen addresses peers advertise through identify.
+//!
+//! Peers may advertise addresses that cannot be dialed: unspecified addresses, duplicates and,
+//! when we are on the public internet, addresses in private ranges. These are dropped before the
+//! addresses are stored.
+use crate::Multiaddr;
+use libp2p::core::multiaddr::Protocol;
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Returns true if the address is a loopback, link-local or private range address.
+pub fn is_private(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_private_v4(ip),
+        IpAddr::V6(ip) => is_private_v6(ip),
+    }
+}
+
+fn is_private_v4(ip: &Ipv4Addr) -> bool {
+    ip.is_private() || ip.is_loopback() || ip.is_link_local()
+}
+
+fn is_private_v6(ip: &Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_loopback()
+        // unique local, fc00::/7
+        || first & 0xfe00 == 0xfc00
+        // link local, fe80::/10
+        || first & 0xffc0 == 0xfe80
+}
+
+/// The IP address of a multiaddr, if it has one.
+pub fn ip_of(address: &Multiaddr) -> Option<IpAddr> {
+    address.iter().find_map(|protocol| match protocol {
+        Protocol::Ip4(ip) => Some(IpAddr::V4(ip)),
+        Protocol::Ip6(ip) => Some(IpAddr::V6(ip)),
+        _ => None,
+    })
+}
+
+/// Drops unspecified and duplicate addresses, and private ones unless `allow_private`, keeping at
+/// most `max_addresses` of the rest. Returns the number of addresses dropped as bogus.
+pub fn filter_addresses(
+    addresses: &mut Vec<Multiaddr>,
+    max_addresses: usize,
+    allow_private: bool,
+) -> usize {
+    let before = addresses.len();
+    let mut seen = HashSet::new();
+    addresses.retain(|address| {
+        let bogus = match ip_of(address) {
+            Some(ip) => ip.is_unspecified() || (!allow_private && is_private(&ip)),
+            None => false,
+        };
+        !bogus && seen.insert(address.clone())
+    });
+    let dropped = before - addresses.len();
+    addresses.truncate(max_addresses);
+    dropped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_addresses() {
+        let parse = |addresses: &[&str]| -> Vec<Multiaddr> {
+            addresses.iter().map(|a| a.parse().unwrap()).collect()
+        };
+        let advertised = parse(&[
+            "/ip4/1.2.3.4/tcp/9000",
+            "/ip4/0.0.0.0/tcp/9000",
+            "/ip4/1.2.3.4/tcp/9000",
+            "/ip4/192.168.1.10/tcp/9000",
+            "/ip6/::/tcp/9000",
+            "/ip6/fd00::1/tcp/9000",
+            "/ip6/2001:db8::1/tcp/9000",
+            "/dns4/example.com/tcp/9000",
+        ]);
+
+        let mut addresses = advertised.clone();
+        assert_eq!(filter_addresses(&mut addresses, 10, false), 5);
+        assert_eq!(
+            addresses,
+            parse(&[
+                "/ip4/1.2.3.4/tcp/9000",
+                "/ip6/2001:db8::1/tcp/9000",
+                "/dns4/example.com/tcp/9000",
+            ])
+        );
+
+        let mut addresses = advertised;
+        assert_eq!(filter_addresses(&mut addresses, 3, true), 3);
+        assert_eq!(
+            addresses,
+            parse(&[
+                "/ip4/1.2.3.4/tcp/9000",
+                "/ip4/192.168.1.10/tcp/9000",
+                "/ip6/fd00::1/tcp/9000",
+            ])
+        );
+    }
+}
@@ -0,0 +1,54 @@
+//! Gossip sent straight to chosen peers, outside of the mesh.
+//!
+//! The messages are built the way gossipsub builds the ones it publishes, so that the peers
+//! receiving them handle and forward them like any other gossip.
+use crate::PeerId;
+use libp2p::gossipsub::{protocol::GossipsubMessage, TopicHash};
+
+/// The source of the messages this node publishes: its peer id, or the placeholder gossipsub
+/// uses when configured to leave the source out.
+pub fn source(local_peer_id: &PeerId, no_source_id: bool) -> PeerId {
+    if no_source_id {
+        PeerId::from_bytes(vec![0, 1, 0]).expect("Valid peer id")
+    } else {
+        local_peer_id.clone()
+    }
+}
+
+/// A message published by `source` on `topic`.
+pub fn message(
+    source: &PeerId,
+    data: Vec<u8>,
+    sequence_number: u64,
+    topic: TopicHash,
+) -> GossipsubMessage {
+    GossipsubMessage {
+        source: source.clone(),
+        data,
+        sequence_number,
+        topics: vec![topic],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NetworkConfig;
+
+    #[test]
+    fn test_source_is_left_out_as_configured() {
+        let local_peer_id = PeerId::random();
+        let config = NetworkConfig::default();
+        let placeholder = source(&local_peer_id, config.gs_config.no_source_id);
+        // by default the local peer id is not revealed
+        assert_ne!(placeholder, local_peer_id);
+        assert_eq!(placeholder, source(&PeerId::random(), true));
+        assert_eq!(source(&local_peer_id, false), local_peer_id);
+
+        let topic = TopicHash::from_raw("topic");
+        let message = message(&placeholder, vec![1, 2], 7, topic.clone());
+        assert_eq!(message.source, placeholder);
+        assert_eq!(message.topics, vec![topic]);
+        assert_eq!(message.sequence_number, 7);
+    }
+}
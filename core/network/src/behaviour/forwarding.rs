@@ -0,0 +1,303 @@
+//! Forwarding budgets for control and data topics, and topics whose forwarding is paused.
+//!
+//! Control topics carry small messages that must not wait behind bulk traffic, such as votes or
+//! heartbeats; every other topic is a data topic. Each class has its own budget of messages
+//! forwarded per interval. A forward within its class's budget goes out at once, others wait in
+//! the class's queue for the next interval, so a burst of data can only delay other data. When a
+//! queue is full its oldest forward is dropped.
+//!
+//! Forwarding can also be paused on a topic, for example while the application is overloaded and
+//! would forward stale data. Messages on the topic are still received, but none is forwarded,
+//! including those waiting for a budget, until forwarding is resumed.
+use crate::limits::Limits;
+use crate::{MessageId, PeerId};
+use lru::LruCache;
+use std::collections::{HashSet, VecDeque};
+
+/// The class of a topic.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TopicClass {
+    /// Small, high priority messages, subject to the control size limit.
+    Control,
+    /// Everything else.
+    Data,
+}
+
+/// What became of a forward.
+#[derive(Debug, PartialEq)]
+pub enum Forward {
+    /// The message can be forwarded now.
+    Now(PeerId, MessageId),
+    /// The message waits for its class's budget.
+    Waiting,
+    /// Forwarding is paused on the topic of the message, so it is not forwarded.
+    Paused,
+}
+
+/// A message waiting for its class's budget to be forwarded.
+struct WaitingForward {
+    propagation_source: PeerId,
+    message_id: MessageId,
+    /// The topic of the message, unless it was forgotten.
+    topic: Option<String>,
+}
+
+/// The forwards of one class.
+struct ClassQueue {
+    /// The number of forwards per interval, unlimited if `None`.
+    budget: Option<usize>,
+    /// The forwards left in the current interval.
+    remaining: usize,
+    waiting: VecDeque<WaitingForward>,
+}
+
+impl ClassQueue {
+    fn new(budget: Option<usize>) -> Self {
+        ClassQueue {
+            budget,
+            remaining: budget.unwrap_or_default(),
+            waiting: VecDeque::new(),
+        }
+    }
+
+    /// Takes a forward from the budget, if any is left.
+    fn take(&mut self) -> bool {
+        match self.budget {
+            None => true,
+            Some(_) if self.remaining > 0 => {
+                self.remaining -= 1;
+                true
+            }
+            Some(_) => false,
+        }
+    }
+}
+
+/// The forwarding budgets of the control and data topics.
+pub struct Forwarding {
+    control_topics: HashSet<String>,
+    /// The topic of each message received, by message id.
+    topics: LruCache<MessageId, String>,
+    /// The topics whose messages are not forwarded.
+    paused: HashSet<String>,
+    control: ClassQueue,
+    data: ClassQueue,
+    /// The number of forwards waiting per class.
+    max_queued: usize,
+}
+
+impl Forwarding {
+    pub fn new(
+        control_topics: impl IntoIterator<Item = String>,
+        control_budget: Option<usize>,
+        data_budget: Option<usize>,
+        limits: &Limits,
+    ) -> Self {
+        Forwarding {
+            control_topics: control_topics.into_iter().collect(),
+            topics: LruCache::new(limits.forwarded_message_topics),
+            paused: HashSet::new(),
+            control: ClassQueue::new(control_budget),
+            data: ClassQueue::new(data_budget),
+            max_queued: limits.queued_forwards,
+        }
+    }
+
+    /// Returns true if either class has a budget, so forwards have to be counted.
+    pub fn enabled(&self) -> bool {
+        self.control.budget.is_some() || self.data.budget.is_some()
+    }
+
+    /// Returns the class of a topic.
+    pub fn class(&self, topic: &str) -> TopicClass {
+        if self.control_topics.contains(topic) {
+            TopicClass::Control
+        } else {
+            TopicClass::Data
+        }
+    }
+
+    /// Remembers the topic of a received message, for when it is forwarded.
+    pub fn received(&mut self, message_id: MessageId, topic: String) {
+        self.topics.put(message_id, topic);
+    }
+
+    /// Stops forwarding messages on `topic`. The forwards of the topic waiting for a budget are
+    /// dropped; their number is returned.
+    pub fn pause(&mut self, topic: &str) -> usize {
+        self.paused.insert(topic.to_string());
+        let mut dropped = 0;
+        for queue in [&mut self.control, &mut self.data].iter_mut() {
+            let before = queue.waiting.len();
+            queue
+                .waiting
+                .retain(|forward| forward.topic.as_deref() != Some(topic));
+            dropped += before - queue.waiting.len();
+        }
+        dropped
+    }
+
+    /// Forwards messages on `topic` again. Returns false if forwarding was not paused.
+    pub fn resume(&mut self, topic: &str) -> bool {
+        self.paused.remove(topic)
+    }
+
+    /// Returns true if forwarding is paused on `topic`.
+    pub fn is_paused(&self, topic: &str) -> bool {
+        self.paused.contains(topic)
+    }
+
+    /// Forwards a message now if its class has budget left, otherwise queues it. A forward
+    /// dropped to make room in the queue is passed to `dropped`.
+    pub fn forward(
+        &mut self,
+        propagation_source: PeerId,
+        message_id: MessageId,
+        dropped: impl FnOnce(MessageId),
+    ) -> Forward {
+        let topic = self.topics.pop(&message_id);
+        let class = match &topic {
+            Some(topic) if self.paused.contains(topic) => return Forward::Paused,
+            Some(topic) => self.class(topic),
+            None => TopicClass::Data,
+        };
+        let queue = match class {
+            TopicClass::Control => &mut self.control,
+            TopicClass::Data => &mut self.data,
+        };
+        // forwards already waiting go first
+        if queue.waiting.is_empty() && queue.take() {
+            return Forward::Now(propagation_source, message_id);
+        }
+        if queue.waiting.len() >= self.max_queued {
+            if let Some(oldest) = queue.waiting.pop_front() {
+                dropped(oldest.message_id);
+            }
+        }
+        queue.waiting.push_back(WaitingForward {
+            propagation_source,
+            message_id,
+            topic,
+        });
+        Forward::Waiting
+    }
+
+    /// Starts a new interval, refilling the budgets. Returns the waiting forwards that fit in
+    /// them, control forwards first.
+    pub fn refill(&mut self) -> Vec<(PeerId, MessageId)> {
+        let mut ready = Vec::new();
+        for queue in [&mut self.control, &mut self.data].iter_mut() {
+            queue.remaining = queue.budget.unwrap_or_default();
+            while !queue.waiting.is_empty() && queue.take() {
+                if let Some(forward) = queue.waiting.pop_front() {
+                    ready.push((forward.propagation_source, forward.message_id));
+                }
+            }
+        }
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u8) -> MessageId {
+        MessageId(n.to_string())
+    }
+
+    #[test]
+    fn test_independent_budgets() {
+        let peer_id = PeerId::random();
+        let mut forwarding = Forwarding::new(
+            vec!["votes".to_string()],
+            Some(2),
+            Some(1),
+            &Limits::default(),
+        );
+        assert!(forwarding.enabled());
+        assert_eq!(forwarding.class("votes"), TopicClass::Control);
+        assert_eq!(forwarding.class("blocks"), TopicClass::Data);
+        for n in 0..3 {
+            forwarding.received(id(n), "blocks".to_string());
+        }
+        for n in 10..12 {
+            forwarding.received(id(n), "votes".to_string());
+        }
+
+        let mut dropped = vec![];
+        let mut forward = |forwarding: &mut Forwarding, n| {
+            forwarding.forward(peer_id.clone(), id(n), |id| dropped.push(id))
+        };
+        // the data budget is spent by the first data message
+        assert_eq!(
+            forward(&mut forwarding, 0),
+            Forward::Now(peer_id.clone(), id(0))
+        );
+        assert_eq!(forward(&mut forwarding, 1), Forward::Waiting);
+        assert_eq!(forward(&mut forwarding, 2), Forward::Waiting);
+        // which does not hold back control messages
+        assert_eq!(
+            forward(&mut forwarding, 10),
+            Forward::Now(peer_id.clone(), id(10))
+        );
+        assert_eq!(
+            forward(&mut forwarding, 11),
+            Forward::Now(peer_id.clone(), id(11))
+        );
+        assert!(dropped.is_empty());
+
+        let ready: Vec<MessageId> = forwarding.refill().into_iter().map(|(_, id)| id).collect();
+        assert_eq!(ready, vec![id(1)]);
+        let ready: Vec<MessageId> = forwarding.refill().into_iter().map(|(_, id)| id).collect();
+        assert_eq!(ready, vec![id(2)]);
+        assert!(forwarding.refill().is_empty());
+    }
+
+    #[test]
+    fn test_full_queue_drops_oldest() {
+        let limits = Limits {
+            queued_forwards: 2,
+            ..Limits::default()
+        };
+        let mut forwarding = Forwarding::new(vec![], None, Some(0), &limits);
+        let mut dropped = vec![];
+        for n in 0..3 {
+            let forward = forwarding.forward(PeerId::random(), id(n), |id| dropped.push(id));
+            assert_eq!(forward, Forward::Waiting);
+        }
+        assert_eq!(dropped, vec![id(0)]);
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let mut forwarding = Forwarding::new(vec![], None, None, &Limits::default());
+        assert!(!forwarding.enabled());
+        let forward = forwarding.forward(PeerId::random(), id(0), |_| panic!("dropped"));
+        assert!(matches!(forward, Forward::Now(..)));
+    }
+
+    #[test]
+    fn test_pause() {
+        let peer_id = PeerId::random();
+        let mut forwarding = Forwarding::new(vec![], None, Some(0), &Limits::default());
+        for n in 0..3 {
+            forwarding.received(id(n), "blocks".to_string());
+        }
+        forwarding.received(id(3), "attestations".to_string());
+        let mut forward = |forwarding: &mut Forwarding, n| {
+            forwarding.forward(peer_id.clone(), id(n), |_| panic!("dropped"))
+        };
+        assert_eq!(forward(&mut forwarding, 0), Forward::Waiting);
+        assert_eq!(forward(&mut forwarding, 3), Forward::Waiting);
+
+        // the waiting forwards of the topic are dropped with the pause
+        assert_eq!(forwarding.pause("blocks"), 1);
+        assert!(forwarding.is_paused("blocks"));
+        assert_eq!(forward(&mut forwarding, 1), Forward::Paused);
+
+        assert!(forwarding.resume("blocks"));
+        assert!(!forwarding.resume("blocks"));
+        assert_eq!(forward(&mut forwarding, 2), Forward::Waiting);
+    }
+}
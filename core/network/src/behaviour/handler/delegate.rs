@@ -1,4 +1,6 @@
 use crate::rpc::*;
+#[cfg(feature = "kad")]
+use libp2p::kad::{record::store::MemoryStore, Kademlia};
 use libp2p::{
     core::either::{EitherError, EitherOutput},
     core::upgrade::{EitherUpgrade, InboundUpgrade, OutboundUpgrade, SelectUpgrade, UpgradeError},
@@ -17,6 +19,11 @@ use std::task::{Context, Poll};
 type GossipHandler = <Gossipsub as NetworkBehaviour>::ProtocolsHandler;
 type RPCHandler = <RPC as NetworkBehaviour>::ProtocolsHandler;
 type IdentifyHandler = <Identify as NetworkBehaviour>::ProtocolsHandler;
+#[cfg(feature = "kad")]
+pub type KadHandler = <Kademlia<MemoryStore> as NetworkBehaviour>::ProtocolsHandler;
+/// Without the `kad` feature the Kademlia handler is replaced by one that supports no protocol.
+#[cfg(not(feature = "kad"))]
+pub type KadHandler = libp2p::swarm::protocols_handler::DummyProtocolsHandler;
 
 /// Handler that combines client's Behaviours' handlers in a delegating manner.
 pub(super) struct DelegatingHandler {
@@ -26,14 +33,22 @@ pub(super) struct DelegatingHandler {
     rpc_handler: RPCHandler,
     /// Handler for the Identify protocol.
     identify_handler: IdentifyHandler,
+    /// Handler for the Kademlia protocol, if the `kad` feature is enabled.
+    kad_handler: KadHandler,
 }
 
 impl DelegatingHandler {
-    pub fn new(gossipsub: &mut Gossipsub, rpc: &mut RPC, identify: &mut Identify) -> Self {
+    pub fn new(
+        gossipsub: &mut Gossipsub,
+        rpc: &mut RPC,
+        identify: &mut Identify,
+        kad_handler: KadHandler,
+    ) -> Self {
         DelegatingHandler {
             gossip_handler: gossipsub.new_handler(),
             rpc_handler: rpc.new_handler(),
             identify_handler: identify.new_handler(),
+            kad_handler,
         }
     }
 
@@ -62,6 +77,7 @@ pub enum DelegateIn {
     Gossipsub(<GossipHandler as ProtocolsHandler>::InEvent),
     RPC(<RPCHandler as ProtocolsHandler>::InEvent),
     Identify(<IdentifyHandler as ProtocolsHandler>::InEvent),
+    Kad(<KadHandler as ProtocolsHandler>::InEvent),
 }
 
 /// Wrapper around the `ProtocolsHandler::OutEvent` types of the handlers.
@@ -70,6 +86,7 @@ pub enum DelegateOut {
     Gossipsub(<GossipHandler as ProtocolsHandler>::OutEvent),
     RPC(<RPCHandler as ProtocolsHandler>::OutEvent),
     Identify(Box<<IdentifyHandler as ProtocolsHandler>::OutEvent>),
+    Kad(Box<<KadHandler as ProtocolsHandler>::OutEvent>),
 }
 
 /// Wrapper around the `ProtocolsHandler::Error` types of the handlers.
@@ -79,6 +96,7 @@ pub enum DelegateError {
     Gossipsub(<GossipHandler as ProtocolsHandler>::Error),
     RPC(<RPCHandler as ProtocolsHandler>::Error),
     Identify(<IdentifyHandler as ProtocolsHandler>::Error),
+    Kad(<KadHandler as ProtocolsHandler>::Error),
 }
 
 impl std::error::Error for DelegateError {}
@@ -92,6 +110,7 @@ impl std::fmt::Display for DelegateError {
             DelegateError::Gossipsub(err) => err.fmt(formater),
             DelegateError::RPC(err) => err.fmt(formater),
             DelegateError::Identify(err) => err.fmt(formater),
+            DelegateError::Kad(err) => err.fmt(formater),
         }
     }
 }
@@ -100,7 +119,10 @@ pub type DelegateInProto = SelectUpgrade<
     <GossipHandler as ProtocolsHandler>::InboundProtocol,
     SelectUpgrade<
         <RPCHandler as ProtocolsHandler>::InboundProtocol,
-        <IdentifyHandler as ProtocolsHandler>::InboundProtocol,
+        SelectUpgrade<
+            <IdentifyHandler as ProtocolsHandler>::InboundProtocol,
+            <KadHandler as ProtocolsHandler>::InboundProtocol,
+        >,
     >,
 >;
 
@@ -108,7 +130,10 @@ pub type DelegateOutProto = EitherUpgrade<
     <GossipHandler as ProtocolsHandler>::OutboundProtocol,
     EitherUpgrade<
         <RPCHandler as ProtocolsHandler>::OutboundProtocol,
-        <IdentifyHandler as ProtocolsHandler>::OutboundProtocol,
+        EitherUpgrade<
+            <IdentifyHandler as ProtocolsHandler>::OutboundProtocol,
+            <KadHandler as ProtocolsHandler>::OutboundProtocol,
+        >,
     >,
 >;
 
@@ -117,7 +142,10 @@ pub type DelegateOutInfo = EitherOutput<
     <GossipHandler as ProtocolsHandler>::OutboundOpenInfo,
     EitherOutput<
         <RPCHandler as ProtocolsHandler>::OutboundOpenInfo,
-        <IdentifyHandler as ProtocolsHandler>::OutboundOpenInfo,
+        EitherOutput<
+            <IdentifyHandler as ProtocolsHandler>::OutboundOpenInfo,
+            <KadHandler as ProtocolsHandler>::OutboundOpenInfo,
+        >,
     >,
 >;
 
@@ -133,16 +161,21 @@ impl ProtocolsHandler for DelegatingHandler {
         let gossip_proto = self.gossip_handler.listen_protocol();
         let rpc_proto = self.rpc_handler.listen_protocol();
         let identify_proto = self.identify_handler.listen_protocol();
+        let kad_proto = self.kad_handler.listen_protocol();
 
         let timeout = gossip_proto
             .timeout()
             .max(rpc_proto.timeout())
             .max(identify_proto.timeout())
+            .max(kad_proto.timeout())
             .clone();
 
         let select = SelectUpgrade::new(
             gossip_proto.into_upgrade().1,
-            SelectUpgrade::new(rpc_proto.into_upgrade().1, identify_proto.into_upgrade().1),
+            SelectUpgrade::new(
+                rpc_proto.into_upgrade().1,
+                SelectUpgrade::new(identify_proto.into_upgrade().1, kad_proto.into_upgrade().1),
+            ),
         );
 
         SubstreamProtocol::new(select).with_timeout(timeout)
@@ -160,9 +193,13 @@ impl ProtocolsHandler for DelegatingHandler {
                 self.rpc_handler.inject_fully_negotiated_inbound(out)
             }
             // Identify
-            EitherOutput::Second(EitherOutput::Second(out)) => {
+            EitherOutput::Second(EitherOutput::Second(EitherOutput::First(out))) => {
                 self.identify_handler.inject_fully_negotiated_inbound(out)
             }
+            // Kademlia
+            EitherOutput::Second(EitherOutput::Second(EitherOutput::Second(out))) => {
+                self.kad_handler.inject_fully_negotiated_inbound(out)
+            }
         }
     }
 
@@ -185,11 +222,18 @@ impl ProtocolsHandler for DelegatingHandler {
                 .inject_fully_negotiated_outbound(protocol, info),
             // Identify
             (
-                EitherOutput::Second(EitherOutput::Second(protocol)),
-                EitherOutput::Second(EitherOutput::Second(info)),
+                EitherOutput::Second(EitherOutput::Second(EitherOutput::First(protocol))),
+                EitherOutput::Second(EitherOutput::Second(EitherOutput::First(info))),
             ) => self
                 .identify_handler
                 .inject_fully_negotiated_outbound(protocol, info),
+            // Kademlia
+            (
+                EitherOutput::Second(EitherOutput::Second(EitherOutput::Second(protocol))),
+                EitherOutput::Second(EitherOutput::Second(EitherOutput::Second(info))),
+            ) => self
+                .kad_handler
+                .inject_fully_negotiated_outbound(protocol, info),
             // Reaching here means we got a protocol and info for different behaviours
             _ => unreachable!("output and protocol don't match"),
         }
@@ -200,6 +244,7 @@ impl ProtocolsHandler for DelegatingHandler {
             DelegateIn::Gossipsub(ev) => self.gossip_handler.inject_event(ev),
             DelegateIn::RPC(ev) => self.rpc_handler.inject_event(ev),
             DelegateIn::Identify(ev) => self.identify_handler.inject_event(ev),
+            DelegateIn::Kad(ev) => self.kad_handler.inject_event(ev),
         }
     }
 
@@ -260,7 +305,7 @@ impl ProtocolsHandler for DelegatingHandler {
                 }
             },
             // Identify
-            EitherOutput::Second(EitherOutput::Second(info)) => match error {
+            EitherOutput::Second(EitherOutput::Second(EitherOutput::First(info))) => match error {
                 ProtocolsHandlerUpgrErr::Upgrade(UpgradeError::Select(err)) => {
                     self.identify_handler.inject_dial_upgrade_error(
                         info,
@@ -274,7 +319,7 @@ impl ProtocolsHandler for DelegatingHandler {
                     .identify_handler
                     .inject_dial_upgrade_error(info, ProtocolsHandlerUpgrErr::Timeout),
                 ProtocolsHandlerUpgrErr::Upgrade(UpgradeError::Apply(EitherError::B(
-                    EitherError::B(err),
+                    EitherError::B(EitherError::A(err)),
                 ))) => self.identify_handler.inject_dial_upgrade_error(
                     info,
                     ProtocolsHandlerUpgrErr::Upgrade(UpgradeError::Apply(err)),
@@ -283,6 +328,30 @@ impl ProtocolsHandler for DelegatingHandler {
                     unreachable!("info and error don't match")
                 }
             },
+            // Kademlia
+            EitherOutput::Second(EitherOutput::Second(EitherOutput::Second(info))) => match error {
+                ProtocolsHandlerUpgrErr::Upgrade(UpgradeError::Select(err)) => {
+                    self.kad_handler.inject_dial_upgrade_error(
+                        info,
+                        ProtocolsHandlerUpgrErr::Upgrade(UpgradeError::Select(err)),
+                    )
+                }
+                ProtocolsHandlerUpgrErr::Timer => self
+                    .kad_handler
+                    .inject_dial_upgrade_error(info, ProtocolsHandlerUpgrErr::Timer),
+                ProtocolsHandlerUpgrErr::Timeout => self
+                    .kad_handler
+                    .inject_dial_upgrade_error(info, ProtocolsHandlerUpgrErr::Timeout),
+                ProtocolsHandlerUpgrErr::Upgrade(UpgradeError::Apply(EitherError::B(
+                    EitherError::B(EitherError::B(err)),
+                ))) => self.kad_handler.inject_dial_upgrade_error(
+                    info,
+                    ProtocolsHandlerUpgrErr::Upgrade(UpgradeError::Apply(err)),
+                ),
+                ProtocolsHandlerUpgrErr::Upgrade(UpgradeError::Apply(_)) => {
+                    unreachable!("info and error don't match")
+                }
+            },
         }
     }
 
@@ -291,6 +360,7 @@ impl ProtocolsHandler for DelegatingHandler {
             .connection_keep_alive()
             .max(self.rpc_handler.connection_keep_alive())
             .max(self.identify_handler.connection_keep_alive())
+            .max(self.kad_handler.connection_keep_alive())
     }
 
     fn poll(
@@ -349,8 +419,28 @@ impl ProtocolsHandler for DelegatingHandler {
             }
             Poll::Ready(ProtocolsHandlerEvent::OutboundSubstreamRequest { protocol, info }) => {
                 return Poll::Ready(ProtocolsHandlerEvent::OutboundSubstreamRequest {
-                    protocol: protocol.map_upgrade(|u| EitherUpgrade::B(EitherUpgrade::B(u))),
-                    info: EitherOutput::Second(EitherOutput::Second(info)),
+                    protocol: protocol
+                        .map_upgrade(|u| EitherUpgrade::B(EitherUpgrade::B(EitherUpgrade::A(u)))),
+                    info: EitherOutput::Second(EitherOutput::Second(EitherOutput::First(info))),
+                });
+            }
+            Poll::Pending => (),
+        };
+
+        match self.kad_handler.poll(cx) {
+            Poll::Ready(ProtocolsHandlerEvent::Custom(event)) => {
+                return Poll::Ready(ProtocolsHandlerEvent::Custom(DelegateOut::Kad(Box::new(
+                    event,
+                ))));
+            }
+            Poll::Ready(ProtocolsHandlerEvent::Close(event)) => {
+                return Poll::Ready(ProtocolsHandlerEvent::Close(DelegateError::Kad(event)));
+            }
+            Poll::Ready(ProtocolsHandlerEvent::OutboundSubstreamRequest { protocol, info }) => {
+                return Poll::Ready(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    protocol: protocol
+                        .map_upgrade(|u| EitherUpgrade::B(EitherUpgrade::B(EitherUpgrade::B(u)))),
+                    info: EitherOutput::Second(EitherOutput::Second(EitherOutput::Second(info))),
                 });
             }
             Poll::Pending => (),
@@ -1,7 +1,10 @@
+use crate::clock;
 use crate::rpc::*;
+use crate::KeepAlivePolicy;
 use delegate::DelegatingHandler;
 pub(super) use delegate::{
     DelegateError, DelegateIn, DelegateInProto, DelegateOut, DelegateOutInfo, DelegateOutProto,
+    KadHandler,
 };
 use libp2p::{
     core::upgrade::{InboundUpgrade, OutboundUpgrade},
@@ -13,6 +16,7 @@ use libp2p::{
     swarm::{NegotiatedSubstream, ProtocolsHandler},
 };
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 mod delegate;
 
@@ -22,22 +26,96 @@ pub struct BehaviourHandler {
     delegate: DelegatingHandler,
     /// Flag indicating if the handler is shutting down.
     shutting_down: bool,
+    /// When the connection is closed for being idle.
+    keep_alive_policy: KeepAlivePolicy,
+    /// How long the connection may go without traffic under `KeepAlivePolicy::Idle`.
+    idle_timeout: Duration,
+    /// Whether the peer is in one of our gossipsub meshes.
+    in_mesh: bool,
+    /// The last time gossip messages or application RPC were exchanged with the peer.
+    last_traffic: Instant,
 }
 
 impl BehaviourHandler {
-    pub fn new(gossipsub: &mut Gossipsub, rpc: &mut RPC, identify: &mut Identify) -> Self {
+    pub fn new(
+        gossipsub: &mut Gossipsub,
+        rpc: &mut RPC,
+        identify: &mut Identify,
+        kad_handler: KadHandler,
+        keep_alive_policy: KeepAlivePolicy,
+        idle_timeout: Duration,
+    ) -> Self {
         BehaviourHandler {
-            delegate: DelegatingHandler::new(gossipsub, rpc, identify),
+            delegate: DelegatingHandler::new(gossipsub, rpc, identify, kad_handler),
             shutting_down: false,
+            keep_alive_policy,
+            idle_timeout,
+            in_mesh: false,
+            last_traffic: clock::now(),
+        }
+    }
+
+    /// Records traffic sent to the peer, which keeps the connection alive under
+    /// `KeepAlivePolicy::Idle`. Gossip control messages and the RPC protocols managed by the
+    /// behaviour do not count.
+    fn observe_sent(&mut self, event: &DelegateIn) {
+        let is_traffic = match event {
+            DelegateIn::Gossipsub(rpc) => !rpc.messages.is_empty(),
+            DelegateIn::RPC(RPCSend::Request(_, request)) => {
+                matches!(request.protocol(), Protocol::Status | Protocol::Custom(_))
+            }
+            DelegateIn::RPC(RPCSend::Response(_, response)) => matches!(
+                response,
+                RPCCodedResponse::Success(RPCResponse::Status(_))
+                    | RPCCodedResponse::Success(RPCResponse::Custom(_))
+            ),
+            DelegateIn::Identify(_) | DelegateIn::Kad(_) => false,
+        };
+        if is_traffic {
+            self.last_traffic = clock::now();
+        }
+    }
+
+    /// Records traffic received from the peer, like `observe_sent`.
+    fn observe_received(&mut self, event: &DelegateOut) {
+        let is_traffic = match event {
+            DelegateOut::Gossipsub(rpc) => !rpc.messages.is_empty(),
+            DelegateOut::RPC(Ok(RPCReceived::Request(_, request, _))) => {
+                matches!(request.protocol(), Protocol::Status | Protocol::Custom(_))
+            }
+            DelegateOut::RPC(Ok(RPCReceived::Response(_, response, _))) => {
+                matches!(response, RPCResponse::Status(_) | RPCResponse::Custom(_))
+            }
+            DelegateOut::RPC(_) | DelegateOut::Identify(_) | DelegateOut::Kad(_) => false,
+        };
+        if is_traffic {
+            self.last_traffic = clock::now();
         }
     }
 }
 
+/// The keep-alive of a connection under `policy`, given when it becomes idle and the keep-alive of
+/// the protocols that hold it open on their own.
+fn policy_keep_alive(
+    policy: KeepAlivePolicy,
+    in_mesh: bool,
+    idle_deadline: Instant,
+    protocols: KeepAlive,
+) -> KeepAlive {
+    match policy {
+        KeepAlivePolicy::Always => KeepAlive::Yes,
+        KeepAlivePolicy::Idle if in_mesh => KeepAlive::Yes,
+        KeepAlivePolicy::Idle => protocols.max(KeepAlive::Until(idle_deadline)),
+    }
+}
+
 #[derive(Clone)]
 pub enum BehaviourHandlerIn {
     Delegate(DelegateIn),
     /// Start the shutdown process.
     Shutdown(Option<(RequestId, RPCRequest)>),
+    /// Whether the peer is in one of our gossipsub meshes, which keeps the connection alive.
+    MeshMembership(bool),
 }
 
 pub enum BehaviourHandlerOut {
@@ -75,12 +153,16 @@ impl ProtocolsHandler for BehaviourHandler {
 
     fn inject_event(&mut self, event: Self::InEvent) {
         match event {
-            BehaviourHandlerIn::Delegate(delegated_ev) => self.delegate.inject_event(delegated_ev),
+            BehaviourHandlerIn::Delegate(delegated_ev) => {
+                self.observe_sent(&delegated_ev);
+                self.delegate.inject_event(delegated_ev)
+            }
             /* Events comming from the behaviour */
             BehaviourHandlerIn::Shutdown(last_message) => {
                 self.shutting_down = true;
                 self.delegate.rpc_mut().shutdown(last_message);
             }
+            BehaviourHandlerIn::MeshMembership(in_mesh) => self.in_mesh = in_mesh,
         }
     }
 
@@ -95,13 +177,17 @@ impl ProtocolsHandler for BehaviourHandler {
     }
 
     fn connection_keep_alive(&self) -> KeepAlive {
+        let rpc_keep_alive = self.delegate.rpc().connection_keep_alive();
+        let identify_keep_alive = self.delegate.identify().connection_keep_alive();
         if self.shutting_down {
-            let rpc_keep_alive = self.delegate.rpc().connection_keep_alive();
-            let identify_keep_alive = self.delegate.identify().connection_keep_alive();
-            rpc_keep_alive.max(identify_keep_alive)
-        } else {
-            KeepAlive::Yes
+            return rpc_keep_alive.max(identify_keep_alive);
         }
+        policy_keep_alive(
+            self.keep_alive_policy,
+            self.in_mesh,
+            clock::wall_deadline(self.last_traffic + self.idle_timeout),
+            rpc_keep_alive.max(identify_keep_alive),
+        )
     }
 
     fn poll(
@@ -117,9 +203,10 @@ impl ProtocolsHandler for BehaviourHandler {
     > {
         match self.delegate.poll(cx) {
             Poll::Ready(ProtocolsHandlerEvent::Custom(event)) => {
+                self.observe_received(&event);
                 return Poll::Ready(ProtocolsHandlerEvent::Custom(
                     BehaviourHandlerOut::Delegate(Box::new(event)),
-                ))
+                ));
             }
             Poll::Ready(ProtocolsHandlerEvent::Close(err)) => {
                 return Poll::Ready(ProtocolsHandlerEvent::Close(err))
@@ -136,3 +223,34 @@ impl ProtocolsHandler for BehaviourHandler {
         Poll::Pending
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy_keep_alive() {
+        let idle_deadline = Instant::now() + Duration::from_secs(60);
+        let later = KeepAlive::Until(idle_deadline + Duration::from_secs(60));
+        let idle = KeepAlivePolicy::Idle;
+        assert_eq!(
+            policy_keep_alive(KeepAlivePolicy::Always, false, idle_deadline, KeepAlive::No),
+            KeepAlive::Yes
+        );
+        // mesh peers are never idle
+        assert_eq!(
+            policy_keep_alive(idle, true, idle_deadline, KeepAlive::No),
+            KeepAlive::Yes
+        );
+        assert_eq!(
+            policy_keep_alive(idle, false, idle_deadline, KeepAlive::No),
+            KeepAlive::Until(idle_deadline)
+        );
+        // open substreams keep the connection alive past the idle deadline
+        assert_eq!(policy_keep_alive(idle, false, idle_deadline, later), later);
+        assert_eq!(
+            policy_keep_alive(idle, false, idle_deadline, KeepAlive::Yes),
+            KeepAlive::Yes
+        );
+    }
+}
@@ -0,0 +1,102 @@
+//! Provider and content records stored in a Kademlia DHT, alongside discv5.
+//!
+//! discv5 finds peers but stores nothing for the application. With the `kad` feature the swarm
+//! also runs a libp2p Kademlia behaviour under a protocol name of its own, so it only forms a DHT
+//! with other mothra nodes. Its routing table is filled from our connections: peers we dial are
+//! added once they confirm the protocol, and peers that dial us once identify reports they
+//! support it. Records are kept in memory, so they are lost when the node stops.
+//!
+//! Each query started for the application carries the channel its outcome is sent to.
+use crate::PeerId;
+use futures::channel::oneshot;
+use libp2p::kad::{
+    record::store::MemoryStore, GetProvidersOk, GetRecordOk, Kademlia, KademliaConfig,
+    KademliaEvent, QueryId, QueryResult,
+};
+use std::collections::HashMap;
+
+/// The protocol name of the DHT, which keeps it apart from the IPFS DHT.
+pub const KAD_PROTOCOL_NAME: &[u8] = b"/mothra/kad/1.0.0";
+
+/// Receives the outcome of a DHT query, or why it failed.
+pub type KadReply<T> = oneshot::Sender<Result<T, String>>;
+
+/// A query waiting for its outcome.
+enum PendingQuery {
+    StartProviding(KadReply<()>),
+    GetProviders(KadReply<Vec<PeerId>>),
+    PutRecord(KadReply<()>),
+    GetRecord(KadReply<Vec<Vec<u8>>>),
+}
+
+/// Builds the Kademlia behaviour of the node, with an in-memory record store.
+pub fn new_kademlia(local_peer_id: PeerId) -> Kademlia<MemoryStore> {
+    let mut config = KademliaConfig::default();
+    config.set_protocol_name(KAD_PROTOCOL_NAME);
+    let store = MemoryStore::new(local_peer_id.clone());
+    Kademlia::with_config(local_peer_id, store, config)
+}
+
+/// The queries started for the application, by id.
+#[derive(Default)]
+pub struct KadQueries {
+    queries: HashMap<QueryId, PendingQuery>,
+}
+
+impl KadQueries {
+    pub fn start_providing(&mut self, id: QueryId, reply: KadReply<()>) {
+        self.queries.insert(id, PendingQuery::StartProviding(reply));
+    }
+
+    pub fn get_providers(&mut self, id: QueryId, reply: KadReply<Vec<PeerId>>) {
+        self.queries.insert(id, PendingQuery::GetProviders(reply));
+    }
+
+    pub fn put_record(&mut self, id: QueryId, reply: KadReply<()>) {
+        self.queries.insert(id, PendingQuery::PutRecord(reply));
+    }
+
+    pub fn get_record(&mut self, id: QueryId, reply: KadReply<Vec<Vec<u8>>>) {
+        self.queries.insert(id, PendingQuery::GetRecord(reply));
+    }
+
+    /// Sends the outcome of a finished query to its channel. Events of queries the application
+    /// did not start, such as republishing, are ignored.
+    pub fn on_event(&mut self, event: KademliaEvent) {
+        let (id, result) = match event {
+            KademliaEvent::QueryResult { id, result, .. } => (id, result),
+            _ => return,
+        };
+        let query = match self.queries.remove(&id) {
+            Some(query) => query,
+            None => return,
+        };
+        // the receivers may have been dropped by an application no longer interested
+        match (query, result) {
+            (PendingQuery::StartProviding(reply), QueryResult::StartProviding(result)) => {
+                let _ = reply.send(result.map(|_| ()).map_err(|e| format!("{:?}", e)));
+            }
+            (PendingQuery::GetProviders(reply), QueryResult::GetProviders(result)) => {
+                let _ = reply.send(
+                    result
+                        .map(|GetProvidersOk { providers, .. }| providers.into_iter().collect())
+                        .map_err(|e| format!("{:?}", e)),
+                );
+            }
+            (PendingQuery::PutRecord(reply), QueryResult::PutRecord(result)) => {
+                let _ = reply.send(result.map(|_| ()).map_err(|e| format!("{:?}", e)));
+            }
+            (PendingQuery::GetRecord(reply), QueryResult::GetRecord(result)) => {
+                let _ = reply.send(
+                    result
+                        .map(|GetRecordOk { records, .. }| {
+                            records.into_iter().map(|record| record.value).collect()
+                        })
+                        .map_err(|e| format!("{:?}", e)),
+                );
+            }
+            // a query id is only ever given to one kind of query
+            _ => {}
+        }
+    }
+}
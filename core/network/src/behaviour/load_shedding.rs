@@ -0,0 +1,127 @@
+//! A node-wide cap on the gossip received, shedding the least important topics first.
+//!
+//! The cap counts the messages let through in each second, from all peers and topics. Every topic
+//! has a priority, 0 unless configured, and the higher its priority the more of the cap its
+//! messages may use: a message of priority `p` is let through while fewer than
+//! `cap * (p + 1) / (max + 1)` messages were let through in the second, `max` being the highest
+//! priority configured. As traffic grows, the least important topics are thus dropped first, and
+//! the most important ones keep the whole cap.
+//!
+//! Each second in which messages were dropped is reported, so the application can react, for
+//! example by leaving bulk topics until the spike passes.
+use crate::clock;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// The length of the periods the cap applies to.
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// A second in which the node received more gossip than it lets through.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Overload {
+    /// The messages received in the second.
+    pub received: usize,
+    /// The number of messages dropped, by topic.
+    pub shed: HashMap<String, usize>,
+}
+
+/// Counts the gossip let through in the current second.
+pub struct LoadShedder {
+    /// The messages let through per second, unlimited if `None`.
+    cap: Option<u32>,
+    priorities: HashMap<String, u8>,
+    max_priority: u8,
+    window_start: Instant,
+    /// The messages let through in the current second.
+    admitted: usize,
+    /// The messages received in the current second, and those dropped.
+    overload: Overload,
+    /// The last second that dropped messages, until it is taken.
+    report: Option<Overload>,
+}
+
+impl LoadShedder {
+    pub fn new(cap: Option<u32>, priorities: HashMap<String, u8>) -> Self {
+        LoadShedder {
+            cap,
+            max_priority: priorities.values().copied().max().unwrap_or_default(),
+            priorities,
+            window_start: clock::now(),
+            admitted: 0,
+            overload: Overload::default(),
+            report: None,
+        }
+    }
+
+    /// Returns true if a message on `topic` received at `now` is let through.
+    pub fn admit(&mut self, topic: &str, now: Instant) -> bool {
+        let cap = match self.cap {
+            Some(cap) => cap as usize,
+            None => return true,
+        };
+        if now.saturating_duration_since(self.window_start) >= WINDOW {
+            let finished = std::mem::take(&mut self.overload);
+            if !finished.shed.is_empty() {
+                self.report = Some(finished);
+            }
+            self.window_start = now;
+            self.admitted = 0;
+        }
+        self.overload.received += 1;
+        let priority = self.priorities.get(topic).copied().unwrap_or_default() as usize;
+        let share = cap * (priority + 1) / (self.max_priority as usize + 1);
+        if self.admitted < share {
+            self.admitted += 1;
+            true
+        } else {
+            *self.overload.shed.entry(topic.to_string()).or_default() += 1;
+            false
+        }
+    }
+
+    /// Takes the report of the last second in which messages were dropped, if it was not taken
+    /// yet. A second is reported once the first message after it arrives.
+    pub fn take_report(&mut self) -> Option<Overload> {
+        self.report.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shed_low_priority_first() {
+        let mut priorities = HashMap::new();
+        priorities.insert("blocks".to_string(), 1);
+        let mut shedder = LoadShedder::new(Some(4), priorities);
+        let now = Instant::now();
+
+        // bulk topics get half the cap, the blocks all of it
+        assert!(shedder.admit("bulk", now));
+        assert!(shedder.admit("bulk", now));
+        assert!(!shedder.admit("bulk", now));
+        assert!(shedder.admit("blocks", now));
+        assert!(shedder.admit("blocks", now));
+        assert!(!shedder.admit("blocks", now));
+        assert!(shedder.take_report().is_none());
+
+        // the next second starts afresh and reports the last one
+        let later = now + WINDOW;
+        assert!(shedder.admit("bulk", later));
+        let mut shed = HashMap::new();
+        shed.insert("bulk".to_string(), 1);
+        shed.insert("blocks".to_string(), 1);
+        assert_eq!(shedder.take_report(), Some(Overload { received: 6, shed }));
+        assert!(shedder.take_report().is_none());
+    }
+
+    #[test]
+    fn test_uncapped() {
+        let mut shedder = LoadShedder::new(None, HashMap::new());
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert!(shedder.admit("bulk", now));
+        }
+    }
+}
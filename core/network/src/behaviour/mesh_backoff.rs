@@ -0,0 +1,206 @@
+//! Backoff between pruning a peer from a gossipsub mesh and grafting it again.
+//!
+//! Our gossipsub version has no notion of backoff, so meshes can churn: a pruned peer is grafted
+//! again on the next heartbeat. When a peer is pruned from a topic's mesh, by us or by the peer,
+//! neither side should graft the other on that topic until the backoff expires. Grafts sent by a
+//! peer during the backoff are refused, and a peer grafting within the flood threshold of a prune
+//! is penalized.
+//!
+//! Our own grafts during a backoff are withheld. Gossipsub cannot be told, so it counts the peer in
+//! its mesh, and sends it the topic's messages in full, until it prunes the peer itself.
+use crate::clock;
+use crate::TopicHash;
+use libp2p::gossipsub::protocol::GossipsubControlAction;
+use libp2p::PeerId;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// What to do with a graft between a peer and a topic's mesh.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GraftCheck {
+    /// No backoff applies.
+    Allowed,
+    /// The peer is backing off from the topic.
+    Backoff,
+    /// The peer is backing off and was pruned less than the flood threshold ago.
+    Flood,
+}
+
+struct Backoff {
+    pruned_at: Instant,
+    until: Instant,
+}
+
+/// The peers backing off from each topic's mesh.
+pub struct MeshBackoff {
+    prune_backoff: Duration,
+    unsubscribe_backoff: Duration,
+    graft_flood_threshold: Duration,
+    backoffs: HashMap<(PeerId, TopicHash), Backoff>,
+}
+
+impl MeshBackoff {
+    pub fn new(
+        prune_backoff: Duration,
+        unsubscribe_backoff: Duration,
+        graft_flood_threshold: Duration,
+    ) -> Self {
+        MeshBackoff {
+            prune_backoff,
+            unsubscribe_backoff,
+            graft_flood_threshold,
+            backoffs: HashMap::new(),
+        }
+    }
+
+    /// Records that a peer was pruned from a topic's mesh, by either side.
+    pub fn pruned(&mut self, peer_id: &PeerId, topic: &TopicHash) {
+        self.start(peer_id, topic, self.prune_backoff);
+    }
+
+    /// Records that we left a topic whose mesh the peer was in.
+    pub fn unsubscribed(&mut self, peer_id: &PeerId, topic: &TopicHash) {
+        self.start(peer_id, topic, self.unsubscribe_backoff);
+    }
+
+    /// Checks a graft between a peer and a topic's mesh.
+    pub fn check_graft(&self, peer_id: &PeerId, topic: &TopicHash) -> GraftCheck {
+        let now = clock::now();
+        match self.backoffs.get(&(peer_id.clone(), topic.clone())) {
+            Some(backoff) if backoff.until > now => {
+                if now.duration_since(backoff.pruned_at) < self.graft_flood_threshold {
+                    GraftCheck::Flood
+                } else {
+                    GraftCheck::Backoff
+                }
+            }
+            _ => GraftCheck::Allowed,
+        }
+    }
+
+    /// Applies the backoffs to the control messages we send a peer: prunes start a backoff, and
+    /// grafts on topics the peer is backing off from are removed. Returns the topics of the
+    /// withheld grafts.
+    pub fn withhold_grafts(
+        &mut self,
+        peer_id: &PeerId,
+        actions: &mut Vec<GossipsubControlAction>,
+    ) -> Vec<TopicHash> {
+        let mut withheld = Vec::new();
+        for action in std::mem::take(actions) {
+            match action {
+                GossipsubControlAction::Prune { topic_hash } => {
+                    self.pruned(peer_id, &topic_hash);
+                    actions.push(GossipsubControlAction::Prune { topic_hash });
+                }
+                GossipsubControlAction::Graft { topic_hash }
+                    if self.check_graft(peer_id, &topic_hash) != GraftCheck::Allowed =>
+                {
+                    withheld.push(topic_hash);
+                }
+                action => actions.push(action),
+            }
+        }
+        withheld
+    }
+
+    /// Starts a backoff, unless one is already running. Expired backoffs are removed.
+    fn start(&mut self, peer_id: &PeerId, topic: &TopicHash, duration: Duration) {
+        if duration == Duration::from_secs(0) {
+            return;
+        }
+        let now = clock::now();
+        self.backoffs.retain(|_, backoff| backoff.until > now);
+        self.backoffs
+            .entry((peer_id.clone(), topic.clone()))
+            .or_insert(Backoff {
+                pruned_at: now,
+                until: now + duration,
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_graft_checks() {
+        let peer_id = PeerId::random();
+        let topic = TopicHash::from_raw("topic");
+        let other_topic = TopicHash::from_raw("other");
+
+        let mut backoff = MeshBackoff::new(
+            Duration::from_secs(60),
+            Duration::from_secs(10),
+            Duration::from_secs(10),
+        );
+        assert_eq!(backoff.check_graft(&peer_id, &topic), GraftCheck::Allowed);
+        backoff.pruned(&peer_id, &topic);
+        assert_eq!(backoff.check_graft(&peer_id, &topic), GraftCheck::Flood);
+        assert_eq!(
+            backoff.check_graft(&peer_id, &other_topic),
+            GraftCheck::Allowed
+        );
+
+        let mut backoff = MeshBackoff::new(
+            Duration::from_secs(60),
+            Duration::from_secs(10),
+            Duration::from_secs(0),
+        );
+        backoff.pruned(&peer_id, &topic);
+        assert_eq!(backoff.check_graft(&peer_id, &topic), GraftCheck::Backoff);
+
+        // a zero backoff disables it
+        let mut backoff = MeshBackoff::new(
+            Duration::from_secs(0),
+            Duration::from_secs(0),
+            Duration::from_secs(10),
+        );
+        backoff.pruned(&peer_id, &topic);
+        backoff.unsubscribed(&peer_id, &other_topic);
+        assert_eq!(backoff.check_graft(&peer_id, &topic), GraftCheck::Allowed);
+        assert_eq!(
+            backoff.check_graft(&peer_id, &other_topic),
+            GraftCheck::Allowed
+        );
+    }
+
+    #[test]
+    fn test_withhold_grafts() {
+        let peer_id = PeerId::random();
+        let pruned = TopicHash::from_raw("pruned");
+        let other = TopicHash::from_raw("other");
+        let mut backoff = MeshBackoff::new(
+            Duration::from_secs(60),
+            Duration::from_secs(10),
+            Duration::from_secs(10),
+        );
+
+        // a prune we send starts a backoff
+        let mut actions = vec![GossipsubControlAction::Prune {
+            topic_hash: pruned.clone(),
+        }];
+        assert!(backoff.withhold_grafts(&peer_id, &mut actions).is_empty());
+        assert_eq!(actions.len(), 1);
+        assert_eq!(backoff.check_graft(&peer_id, &pruned), GraftCheck::Flood);
+
+        // so a graft on the topic is withheld, while the others are sent
+        let mut actions = vec![
+            GossipsubControlAction::Graft {
+                topic_hash: pruned.clone(),
+            },
+            GossipsubControlAction::Graft {
+                topic_hash: other.clone(),
+            },
+        ];
+        assert_eq!(
+            backoff.withhold_grafts(&peer_id, &mut actions),
+            vec![pruned]
+        );
+        match actions.as_slice() {
+            [GossipsubControlAction::Graft { topic_hash }] => assert_eq!(*topic_hash, other),
+            actions => panic!("unexpected actions: {:?}", actions),
+        }
+    }
+}
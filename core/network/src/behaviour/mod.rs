@@ -1,17 +1,44 @@
-use crate::peer_manager::{PeerManager, PeerManagerEvent};
+use crate::clock;
+use crate::peer_manager::{
+    PeerAction, PeerManager, PeerManagerEvent, Rep, ScoreSource, StatusCheck,
+};
 use crate::rpc::*;
-use crate::types::{EnrForkId, GossipKind, GossipTopic, SubnetId};
+use crate::types::{
+    bridged_copy, open_bridged, seal_bridged, ActiveForks, BridgedCopy, EnrForkId, ForkRelay,
+    GossipKind, GossipTopic, McacheEstimate, MeanSize, MemoryUsage, PendingIwants, PingHandler,
+    QueueDepths, Stats, SubnetId, TopicBridges, TopicValidator, UnsubscribedTopicPolicy,
+    LRU_ENTRY_OVERHEAD,
+};
 
-use crate::{error, Enr, NetworkConfig, NetworkGlobals, TopicHash};
+use crate::config::PRIVATE_AGENT_VERSION;
+use crate::discovery::{DhtSnapshot, EnrPredicate};
+use crate::log_context;
+use crate::{
+    error, Enr, EnrExt, KeepAlivePolicy, MessageTransforms, NetworkConfig, NetworkGlobals,
+    TaskExecutor, TopicEncryption, TopicHash, TopicKey,
+};
+use forwarding::{Forward, Forwarding, TopicClass};
 use futures::prelude::*;
 use handler::{BehaviourHandler, BehaviourHandlerIn, BehaviourHandlerOut, DelegateIn, DelegateOut};
+#[cfg(feature = "kad")]
+use kad::KadQueries;
+#[cfg(feature = "kad")]
+pub use kad::KadReply;
+#[cfg(feature = "kad")]
+use libp2p::kad::{
+    record::{store::MemoryStore, Key, Record},
+    Kademlia, KademliaEvent, Quorum,
+};
 use libp2p::{
     core::{
         connection::{ConnectedPoint, ConnectionId, ListenerId},
-        identity::Keypair,
+        identity::{Keypair, PublicKey},
         Multiaddr,
     },
-    gossipsub::{Gossipsub, GossipsubEvent, MessageId},
+    gossipsub::{
+        protocol::{GossipsubControlAction, GossipsubMessage, GossipsubRpc},
+        Gossipsub, GossipsubEvent, MessageId,
+    },
     identify::{Identify, IdentifyEvent},
     swarm::{
         NetworkBehaviour, NetworkBehaviourAction as NBAction, NotifyHandler, PollParameters,
@@ -19,18 +46,43 @@ use libp2p::{
     },
     PeerId,
 };
+use load_shedding::LoadShedder;
+pub use load_shedding::Overload;
 use lru::LruCache;
-use slog::{crit, debug, o};
+use mesh_backoff::{GraftCheck, MeshBackoff};
+use outbound_buffer::{BufferedMessage, OutboundBuffer};
+use publishers::TopicPublishers;
+use rate_limit::{MessageRateLimits, RateCheck};
+use rendezvous::Registrations;
+use slog::{crit, debug, o, trace, warn};
 use std::{
+    collections::{HashMap, HashSet, VecDeque},
     marker::PhantomData,
-    sync::Arc,
+    mem::size_of,
+    sync::{atomic::Ordering, Arc},
     task::{Context, Poll},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
+mod address_filter;
+mod direct;
+mod forwarding;
 mod handler;
-
-const MAX_IDENTIFY_ADDRESSES: usize = 10;
+#[cfg(feature = "kad")]
+mod kad;
+mod load_shedding;
+mod mesh_backoff;
+mod opportunistic_graft;
+mod outbound_buffer;
+mod publishers;
+mod rate_limit;
+mod rendezvous;
+
+/// How often the publish buffer is checked for messages past their deadline.
+const PUBLISH_EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The interval the forwarding budgets of the control and data topics are given for.
+const FORWARD_BUDGET_INTERVAL: Duration = Duration::from_secs(1);
 
 /// Builds the network behaviour that manages the core protocols of eth2.
 /// This core behaviour is managed by `Behaviour` which adds peer management to all core
@@ -44,6 +96,25 @@ pub struct Behaviour {
     // TODO: Using id for initial interop. This will be removed by mainnet.
     /// Provides IP addresses and peer information.
     identify: Identify,
+    /// Stores provider and content records for the application.
+    #[cfg(feature = "kad")]
+    kademlia: Kademlia<MemoryStore>,
+    /// The DHT queries started for the application, waiting for their outcome.
+    #[cfg(feature = "kad")]
+    kad_queries: KadQueries,
+    /// The agent version advertised through identify.
+    agent_version: String,
+    /// The protocol version advertised through identify.
+    protocol_version: String,
+    /// Whether the agent version is hidden from peers.
+    private: bool,
+    /// The public key of the node, needed to rebuild the identify behaviour.
+    local_public_key: PublicKey,
+    /// The identify behaviour with the versions last updated, replacing `identify` once it has no
+    /// event ready.
+    updated_identify: Option<Identify>,
+    /// The RPC substreams open on each connection.
+    rpc_substreams: HashMap<PeerId, HashMap<ConnectionId, SubstreamCounts>>,
     /// The peer manager that keeps track of peer's reputation and status.
     peer_manager: PeerManager,
     /// The events generated by this behaviour to be consumed in the swarm poll.
@@ -54,12 +125,114 @@ pub struct Behaviour {
     meta_data: Vec<u8>,
     /// The current ping data of the node
     ping_data: Vec<u8>,
+    /// The application's handling of ping payloads, replacing `ping_data` if set.
+    ping_handler: Option<PingHandler>,
+    /// The RPC protocols registered by the application.
+    custom_protocols: CustomProtocols,
     /// A collections of variables accessible outside the network service.
     network_globals: Arc<NetworkGlobals>,
-    /// Keeps track of the current EnrForkId for upgrading gossipsub topics.
+    /// How messages on topics we are not subscribed to are handled.
+    unsubscribed_topic_policy: UnsubscribedTopicPolicy,
+    /// Peers that are kept out of our gossipsub mesh.
+    no_graft_peers: HashSet<PeerId>,
+    /// Keeps peers pruned from a mesh from being grafted again too soon.
+    mesh_backoff: MeshBackoff,
+    /// The peers in each of our gossipsub meshes, as seen from the grafts and prunes exchanged.
+    mesh: HashMap<TopicHash, HashSet<PeerId>>,
+    /// The mesh size at which a subscribed topic is reported ready for publishing, gossipsub's
+    /// lower mesh bound.
+    ready_mesh_size: usize,
+    /// Fires when the meshes are checked for poorly reputed peers.
+    opportunistic_graft_interval: Option<tokio::time::Interval>,
+    /// The number of peers grafted into a poorly reputed mesh at a time.
+    opportunistic_graft_peers: usize,
+    /// The median mesh reputation below which peers are grafted opportunistically.
+    opportunistic_graft_threshold: Rep,
+    /// Messages published while no peer is subscribed to their topic.
+    outbound_buffer: OutboundBuffer,
+    /// Fires when the publish buffer is checked for expired messages, if it is enabled.
+    publish_expiry_interval: Option<tokio::time::Interval>,
+    /// Forwards received messages within the budgets of the control and data topics, unless
+    /// forwarding is paused on their topic.
+    forwarding: Forwarding,
+    /// Fires when the forwarding budgets are refilled, if there are any.
+    forwarding_interval: Option<tokio::time::Interval>,
+    /// The largest message accepted or published on a control topic.
+    control_max_message_size: usize,
+    /// Whether publishing on a topic we are not subscribed to fails.
+    reject_unsubscribed_publish: bool,
+    /// The application's rule for topic names, if it has one.
+    topic_validator: Option<TopicValidator>,
+    /// When connections to peers are closed for being idle.
+    keep_alive_policy: KeepAlivePolicy,
+    /// How long a connection may go without traffic under `KeepAlivePolicy::Idle`.
+    keep_alive_idle_timeout: Duration,
+    /// The maximum number of listen addresses stored per peer.
+    max_identify_addresses: usize,
+    /// Whether private range addresses advertised by peers are kept when we are public.
+    allow_private_addresses: bool,
+    /// Whether each gossipsub control message is logged.
+    log_gossip_control: bool,
+    /// Whether subscribed topics are exchanged with peers when they connect.
+    topic_exchange: bool,
+    /// Whether Status requests are answered with the status in the network globals.
+    auto_status: bool,
+    /// The namespaces we register under at the rendezvous points.
+    rendezvous_namespaces: Vec<String>,
+    /// The registrations of other nodes, if we are a rendezvous point.
+    rendezvous_registrations: Option<Registrations>,
+    /// Fires when our registrations at the rendezvous points are refreshed, if there are any.
+    rendezvous_interval: Option<tokio::time::Interval>,
+    /// The peers allowed to send messages on restricted topics.
+    topic_publishers: TopicPublishers,
+    /// The rate at which each peer may send messages on each topic.
+    message_rate_limits: MessageRateLimits,
+    /// The node-wide cap on the messages received, applied by topic priority.
+    load_shedder: LoadShedder,
+    /// The transforms applied to the messages of each topic.
+    message_transforms: MessageTransforms,
+    /// The topics whose received messages are re-published to other topics.
+    topic_bridges: TopicBridges,
+    /// The copies of the messages bridged, by us or by others.
+    bridged_copies: LruCache<BridgedCopy, ()>,
+    /// The keys of the encrypted topics, shared with their transforms.
+    topic_encryption: TopicEncryption,
+    /// How long messages under a replaced topic key are still accepted.
+    topic_key_grace: Duration,
+    /// The peers whose handlers were told they are in one of our meshes.
+    kept_alive_mesh_peers: HashSet<PeerId>,
+    /// Changes of mesh membership waiting to be sent to the peers' handlers.
+    mesh_membership_updates: VecDeque<(PeerId, NotifyHandler, bool)>,
+    /// Messages published directly to peers, waiting to be sent to their handlers.
+    direct_messages: VecDeque<(PeerId, GossipsubRpc)>,
+    /// The ids of messages we published directly.
+    direct_message_ids: LruCache<MessageId, ()>,
+    /// The ids of the gossip messages received recently, from any peer.
+    received_message_ids: LruCache<MessageId, ()>,
+    /// The mean length of the message ids remembered.
+    message_id_sizes: MeanSize,
+    /// The messages gossipsub keeps in its message cache.
+    mcache: McacheEstimate,
+    /// The messages peers asked for with IWANT, to measure how often the mcache has them.
+    pending_iwants: PendingIwants,
+    /// The sequence number of the next directly published message.
+    direct_sequence_number: u64,
+    /// Computes gossipsub message ids, as configured.
+    message_id_fn: fn(&GossipsubMessage) -> MessageId,
+    /// The peer id of the node.
+    local_peer_id: PeerId,
+    /// The source of directly published messages, as gossipsub would set it.
+    direct_source: PeerId,
+    /// The forks served, for resolving fork-scoped topics.
     // NOTE: This can be accessed via the network_globals ENR. However we keep it here for quick
     // lookups for every gossipsub message send.
-    enr_fork_id: EnrForkId,
+    forks: ActiveForks,
+    /// The fork-scoped topics subscribed to, each joined once per fork served.
+    fork_scoped_topics: HashSet<GossipTopic>,
+    /// Whether the gossip of fork-scoped topics is relayed between the forks served.
+    bridge_forks: bool,
+    /// The messages relayed between the forks served.
+    fork_relay: ForkRelay,
     /// Logger for behaviour actions.
     log: slog::Logger,
 }
@@ -70,6 +243,8 @@ macro_rules! delegate_to_behaviours {
         $self.gossipsub.$fn($($arg),*);
         $self.mothra_rpc.$fn($($arg),*);
         $self.identify.$fn($($arg),*);
+        #[cfg(feature = "kad")]
+        $self.kademlia.$fn($($arg),*);
     };
 }
 
@@ -78,10 +253,17 @@ impl NetworkBehaviour for Behaviour {
     type OutEvent = BehaviourEvent;
 
     fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        #[cfg(feature = "kad")]
+        let kad_handler = self.kademlia.new_handler();
+        #[cfg(not(feature = "kad"))]
+        let kad_handler = Default::default();
         BehaviourHandler::new(
             &mut self.gossipsub,
             &mut self.mothra_rpc,
             &mut self.identify,
+            kad_handler,
+            self.keep_alive_policy,
+            self.keep_alive_idle_timeout,
         )
     }
 
@@ -90,10 +272,29 @@ impl NetworkBehaviour for Behaviour {
     }
 
     fn inject_connected(&mut self, peer_id: &PeerId) {
+        Stats::increment(&self.network_globals.stats.peers_connected);
         delegate_to_behaviours!(self, inject_connected, peer_id);
     }
 
     fn inject_disconnected(&mut self, peer_id: &PeerId) {
+        Stats::increment(&self.network_globals.stats.peers_disconnected);
+        for peers in self.network_globals.topic_peers.write().values_mut() {
+            peers.remove(peer_id);
+        }
+        for peers in self.mesh.values_mut() {
+            if peers.remove(peer_id) {
+                self.network_globals
+                    .mesh_prunes
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.kept_alive_mesh_peers.remove(peer_id);
+        self.network_globals.unmute(peer_id);
+        if let Some(registrations) = self.rendezvous_registrations.as_mut() {
+            registrations.remove_peer(peer_id);
+        }
+        self.message_rate_limits.remove_peer(peer_id);
+        self.pending_iwants.remove_peer(peer_id);
         delegate_to_behaviours!(self, inject_disconnected, peer_id);
     }
 
@@ -103,6 +304,23 @@ impl NetworkBehaviour for Behaviour {
         conn_id: &ConnectionId,
         endpoint: &ConnectedPoint,
     ) {
+        let first_connection = !self.peer_manager.has_connection(peer_id);
+        self.peer_manager
+            .connection_established(peer_id, *conn_id, endpoint);
+        // topics are exchanged once, over the first connection to the peer
+        if self.topic_exchange && first_connection {
+            self.send_topics_request(peer_id.clone());
+        }
+        if self.peer_manager.is_rendezvous_point(peer_id) && first_connection {
+            self.send_rendezvous_requests(peer_id);
+        }
+        if self.kept_alive_mesh_peers.contains(peer_id) {
+            self.mesh_membership_updates.push_back((
+                peer_id.clone(),
+                NotifyHandler::One(*conn_id),
+                true,
+            ));
+        }
         delegate_to_behaviours!(
             self,
             inject_connection_established,
@@ -118,6 +336,8 @@ impl NetworkBehaviour for Behaviour {
         conn_id: &ConnectionId,
         endpoint: &ConnectedPoint,
     ) {
+        self.peer_manager.connection_closed(peer_id, conn_id);
+        self.update_rpc_substreams(peer_id, *conn_id, None);
         delegate_to_behaviours!(self, inject_connection_closed, peer_id, conn_id, endpoint);
     }
 
@@ -131,6 +351,7 @@ impl NetworkBehaviour for Behaviour {
     }
 
     fn inject_dial_failure(&mut self, peer_id: &PeerId) {
+        Stats::increment(&self.network_globals.stats.dial_failures);
         delegate_to_behaviours!(self, inject_dial_failure, peer_id);
     }
 
@@ -162,9 +383,31 @@ impl NetworkBehaviour for Behaviour {
         match event {
             // Events comming from the handler, redirected to each behaviour
             BehaviourHandlerOut::Delegate(delegate) => match *delegate {
-                DelegateOut::Gossipsub(ev) => self.gossipsub.inject_event(peer_id, conn_id, ev),
+                DelegateOut::Gossipsub(mut ev) => {
+                    // neither delivered nor counted for or against the peer
+                    self.network_globals.drop_muted(&peer_id, &mut ev.messages);
+                    self.observe_messages(&peer_id, &ev.messages);
+                    self.observe_control(&peer_id, &ev.control_msgs, false);
+                    self.observe_iwants(&peer_id, &ev.control_msgs);
+                    if self.no_graft_peers.contains(&peer_id) {
+                        // Their grafts are ignored. Their subscriptions are kept, so they are
+                        // still counted on their topics and sent gossip and fanout publishes.
+                        ev.control_msgs.retain(|action| match action {
+                            GossipsubControlAction::Graft { .. } => false,
+                            _ => true,
+                        });
+                    }
+                    self.drop_invalid_subscriptions(&peer_id, &mut ev);
+                    self.apply_inbound_backoff(&peer_id, &mut ev);
+                    self.track_mesh(&peer_id, &ev.control_msgs, false);
+                    self.gossipsub.inject_event(peer_id, conn_id, ev)
+                }
                 DelegateOut::RPC(ev) => self.mothra_rpc.inject_event(peer_id, conn_id, ev),
                 DelegateOut::Identify(ev) => self.identify.inject_event(peer_id, conn_id, *ev),
+                #[cfg(feature = "kad")]
+                DelegateOut::Kad(ev) => self.kademlia.inject_event(peer_id, conn_id, *ev),
+                #[cfg(not(feature = "kad"))]
+                DelegateOut::Kad(ev) => void::unreachable(*ev),
             },
             /* Custom events sent BY the handler */
             BehaviourHandlerOut::Custom => {
@@ -182,8 +425,8 @@ impl NetworkBehaviour for Behaviour {
         macro_rules! poll_behaviour {
             /* $behaviour:  The sub-behaviour being polled.
              * $on_event_fn:  Function to call if we get an event from the sub-behaviour.
-             * $notify_handler_event_closure:  Closure mapping the received event type to
-             *     the one that the handler should get.
+             * $notify_handler_event_closure:  Closure mapping the peer id and the received
+             *     event type to the one that the handler should get.
              */
             ($behaviour: ident, $on_event_fn: ident, $notify_handler_event_closure: expr) => {
                 loop {
@@ -209,7 +452,7 @@ impl NetworkBehaviour for Behaviour {
                                     // call the closure mapping the received event to the needed one
                                     // in order to notify the handler
                                     event: BehaviourHandlerIn::Delegate(
-                                        $notify_handler_event_closure(event),
+                                        $notify_handler_event_closure(&peer_id, event),
                                     ),
                                 });
                             }
@@ -223,9 +466,17 @@ impl NetworkBehaviour for Behaviour {
             };
         }
 
-        poll_behaviour!(gossipsub, on_gossip_event, DelegateIn::Gossipsub);
-        poll_behaviour!(mothra_rpc, on_rpc_event, DelegateIn::RPC);
-        poll_behaviour!(identify, on_identify_event, DelegateIn::Identify);
+        poll_behaviour!(gossipsub, on_gossip_event, |peer_id, rpc| {
+            self.apply_outbound_backoff(peer_id, rpc)
+        });
+        poll_behaviour!(mothra_rpc, on_rpc_event, |_, event| DelegateIn::RPC(event));
+        poll_behaviour!(identify, on_identify_event, |_, event| {
+            DelegateIn::Identify(event)
+        });
+        // identify has no event ready, so it can be replaced without losing any
+        self.replace_identify();
+        #[cfg(feature = "kad")]
+        poll_behaviour!(kademlia, on_kad_event, |_, event| DelegateIn::Kad(event));
 
         self.custom_poll(cx)
     }
@@ -237,34 +488,183 @@ impl Behaviour {
         local_key: &Keypair,
         config: &NetworkConfig,
         network_globals: Arc<NetworkGlobals>,
+        executor: TaskExecutor,
         log: &slog::Logger,
     ) -> error::Result<Self> {
         let local_peer_id = local_key.public().into_peer_id();
         let behaviour_log = log.new(o!());
 
+        let agent_version = if config.private {
+            PRIVATE_AGENT_VERSION.to_string()
+        } else {
+            config.agent_version.clone()
+        };
         let identify = Identify::new(
             config.protocol_version.clone(),
-            config.agent_version.clone(),
+            agent_version.clone(),
             local_key.public(),
         );
 
-        let enr_fork_id = network_globals.local_fork_id();
+        let forks = ActiveForks::new(
+            network_globals.local_fork_id(),
+            network_globals.local_secondary_fork_id(),
+        );
 
         let meta_data = network_globals.meta_data.read().clone();
 
         let ping_data = network_globals.ping_data.read().clone();
 
+        config.rpc_versions.check()?;
+        let mut rpc_versions = config.rpc_versions.clone();
+        if config.private {
+            rpc_versions.keep_preferred(Protocol::Status);
+        }
+        if !config.topic_exchange {
+            rpc_versions.remove(Protocol::Topics);
+        }
+        if !config.rendezvous_server && config.rendezvous_points.is_empty() {
+            rpc_versions.remove(Protocol::Rendezvous);
+        }
+
+        let mut message_transforms = config.message_transforms.clone();
+        let topic_encryption = network_globals.topic_encryption.clone();
+        for (topic, key) in &config.topic_keys {
+            topic_encryption.set_key(topic.clone(), key.clone());
+            message_transforms.register(topic.clone(), Arc::new(topic_encryption.clone()));
+        }
+
+        let forwarding = Forwarding::new(
+            config.control_topics.clone(),
+            config.control_forward_budget,
+            config.data_forward_budget,
+            &config.limits,
+        );
+
+        let codec_workers = CodecWorkers::new(config.rpc_codec_workers)
+            .map_err(|e| format!("Failed to start the RPC codec workers: {}", e))?;
+
         Ok(Behaviour {
-            mothra_rpc: RPC::new(log.clone()),
-            gossipsub: Gossipsub::new(local_peer_id, config.gs_config.clone()),
+            mothra_rpc: RPC::new(
+                RPCConfig {
+                    versions: rpc_versions,
+                    prefix: config.rpc_protocol_prefix.clone(),
+                    custom: config.custom_protocols.clone(),
+                    max_substream_age: config.rpc_substream_max_age,
+                    pool: network_globals.buffer_pool.clone(),
+                    workers: Arc::new(codec_workers),
+                    limits: Arc::new(config.limits.clone()),
+                },
+                log.clone(),
+            ),
+            gossipsub: Gossipsub::new(local_peer_id.clone(), config.gs_config.clone()),
             identify,
-            peer_manager: PeerManager::new(local_key, config, network_globals.clone(), log)?,
+            #[cfg(feature = "kad")]
+            kademlia: kad::new_kademlia(local_peer_id.clone()),
+            #[cfg(feature = "kad")]
+            kad_queries: KadQueries::default(),
+            agent_version,
+            protocol_version: config.protocol_version.clone(),
+            private: config.private,
+            local_public_key: local_key.public(),
+            updated_identify: None,
+            rpc_substreams: HashMap::new(),
+            peer_manager: PeerManager::new(
+                local_key,
+                config,
+                network_globals.clone(),
+                executor,
+                log,
+            )?,
             events: Vec::new(),
             peers_to_dc: Vec::new(),
             meta_data,
             ping_data,
+            ping_handler: config.ping_handler.clone(),
+            custom_protocols: config.custom_protocols.clone(),
             network_globals,
-            enr_fork_id,
+            unsubscribed_topic_policy: config.unsubscribed_topic_policy,
+            no_graft_peers: config.no_graft_peers.iter().cloned().collect(),
+            mesh_backoff: MeshBackoff::new(
+                config.gossip_prune_backoff,
+                config.gossip_unsubscribe_backoff,
+                config.gossip_graft_flood_threshold,
+            ),
+            mesh: HashMap::new(),
+            ready_mesh_size: config.gs_config.mesh_n_low,
+            opportunistic_graft_interval: config
+                .opportunistic_graft_interval
+                .map(tokio::time::interval),
+            opportunistic_graft_peers: config.opportunistic_graft_peers,
+            log_gossip_control: config.log_gossip_control,
+            topic_exchange: config.topic_exchange,
+            auto_status: config.auto_status,
+            rendezvous_namespaces: config.rendezvous_namespaces.clone(),
+            rendezvous_registrations: if config.rendezvous_server {
+                Some(Registrations::new(
+                    config.limits.served_rendezvous_namespaces,
+                    config.limits.rendezvous_registrations,
+                    config.limits.rendezvous_namespaces_per_peer,
+                ))
+            } else {
+                None
+            },
+            rendezvous_interval: if config.rendezvous_points.is_empty() {
+                None
+            } else {
+                Some(tokio::time::interval(rendezvous::REFRESH_INTERVAL))
+            },
+            topic_publishers: TopicPublishers::new(config.topic_publishers.clone()),
+            message_rate_limits: MessageRateLimits::new(
+                config.peer_topic_rate_limit,
+                config.topic_rate_limits.clone(),
+            ),
+            load_shedder: LoadShedder::new(
+                config.inbound_message_rate_limit,
+                config.topic_priorities.clone(),
+            ),
+            message_transforms,
+            topic_bridges: config.topic_bridges.clone(),
+            bridged_copies: LruCache::new(config.limits.bridged_message_ids),
+            topic_encryption,
+            topic_key_grace: config.topic_key_grace,
+            max_identify_addresses: config.limits().max_identify_addresses,
+            allow_private_addresses: config.allow_private_addresses,
+            opportunistic_graft_threshold: config.opportunistic_graft_threshold,
+            outbound_buffer: OutboundBuffer::new(config.publish_buffer_size),
+            publish_expiry_interval: if config.publish_buffer_size > 0 {
+                Some(tokio::time::interval(PUBLISH_EXPIRY_INTERVAL))
+            } else {
+                None
+            },
+            forwarding_interval: if forwarding.enabled() {
+                Some(tokio::time::interval(FORWARD_BUDGET_INTERVAL))
+            } else {
+                None
+            },
+            forwarding,
+            control_max_message_size: config.control_max_message_size,
+            reject_unsubscribed_publish: config.reject_unsubscribed_publish,
+            topic_validator: config.topic_validator.clone(),
+            keep_alive_policy: config.keep_alive_policy,
+            keep_alive_idle_timeout: config.keep_alive_idle_timeout,
+            kept_alive_mesh_peers: HashSet::new(),
+            mesh_membership_updates: VecDeque::new(),
+            direct_messages: VecDeque::new(),
+            direct_message_ids: LruCache::new(config.limits.direct_message_ids),
+            received_message_ids: LruCache::new(config.limits.received_message_ids),
+            message_id_sizes: MeanSize::default(),
+            mcache: McacheEstimate::new(
+                config.gs_config.heartbeat_interval * config.gs_config.history_length as u32,
+            ),
+            pending_iwants: PendingIwants::default(),
+            direct_sequence_number: 0,
+            message_id_fn: config.gs_config.message_id_fn,
+            direct_source: direct::source(&local_peer_id, config.gs_config.no_source_id),
+            local_peer_id,
+            forks,
+            fork_scoped_topics: HashSet::new(),
+            bridge_forks: config.bridge_forks,
+            fork_relay: ForkRelay::new(config.limits.bridged_message_ids),
             log: behaviour_log,
         })
     }
@@ -279,18 +679,121 @@ impl Behaviour {
         &self.gossipsub
     }
 
+    /// Changes the agent and protocol versions advertised through identify. Identify does not
+    /// push updates, so connected peers see the new values when they next identify us. In private
+    /// mode the agent version is not changed.
+    pub fn update_identify(
+        &mut self,
+        agent_version: Option<String>,
+        protocol_version: Option<String>,
+    ) {
+        match agent_version {
+            Some(_) if self.private => {
+                debug!(self.log, "Agent version not updated in private mode");
+            }
+            Some(agent_version) => self.agent_version = agent_version,
+            None => {}
+        }
+        if let Some(protocol_version) = protocol_version {
+            self.protocol_version = protocol_version;
+        }
+        // The versions are fixed when identify is built, so it is replaced, once the events it has
+        // ready are handled. See `replace_identify`.
+        self.updated_identify = Some(Identify::new(
+            self.protocol_version.clone(),
+            self.agent_version.clone(),
+            self.local_public_key.clone(),
+        ));
+        debug!(self.log, "Updated identify info";
+            "agent_version" => &self.agent_version,
+            "protocol_version" => &self.protocol_version);
+    }
+
+    /// Swaps in the identify behaviour built by `update_identify`, if any. The connection handlers
+    /// are kept, and the new behaviour is told about the open connections so it can answer requests
+    /// on them.
+    fn replace_identify(&mut self) {
+        if let Some(identify) = self.updated_identify.take() {
+            self.identify = identify;
+            for (peer_id, conn_id, endpoint) in self.peer_manager.connections() {
+                self.identify
+                    .inject_connection_established(peer_id, conn_id, endpoint);
+            }
+        }
+    }
+
     /* Pubsub behaviour functions */
 
-    /// Subscribes to a gossipsub topic kind
-    pub fn subscribe_kind(&mut self, kind: GossipKind) -> bool {
+    /// Subscribes to a gossipsub topic kind. A fork-scoped topic is joined for every fork served.
+    /// Fails, joining none of them, if the topic validator rejects the topic of any fork.
+    pub fn subscribe_kind(&mut self, kind: GossipKind) -> Result<bool, String> {
         let gossip_topic = GossipTopic::new(kind);
-        self.subscribe(gossip_topic)
+        let topics = self.forks.topics(&gossip_topic);
+        for topic in &topics {
+            self.validate_topic(topic.as_str())?;
+        }
+        if gossip_topic.is_fork_scoped() {
+            self.fork_scoped_topics.insert(gossip_topic.clone());
+        }
+        let mut subscribed = false;
+        for topic in topics {
+            subscribed |= self.subscribe(topic);
+        }
+        Ok(subscribed)
     }
 
-    /// Unsubscribes from a gossipsub topic kind
+    /// Checks a topic name against the topic validator.
+    fn validate_topic(&self, topic: &str) -> Result<(), String> {
+        match &self.topic_validator {
+            Some(validator) => validator.validate(topic),
+            None => Ok(()),
+        }
+    }
+
+    /// Unsubscribes from a gossipsub topic kind, for every fork served if it is fork-scoped.
     pub fn unsubscribe_kind(&mut self, kind: GossipKind) -> bool {
         let gossip_topic = GossipTopic::new(kind);
-        self.unsubscribe(gossip_topic)
+        self.fork_scoped_topics.remove(&gossip_topic);
+        let mut unsubscribed = false;
+        for topic in self.forks.topics(&gossip_topic) {
+            unsubscribed |= self.unsubscribe(topic);
+        }
+        unsubscribed
+    }
+
+    /// Changes the forks served: fork-scoped topics are joined for the forks added and left for
+    /// the forks no longer served, and the ENR is updated to advertise the new forks. This moves
+    /// a node through a fork transition, for example by adding the upcoming fork as the
+    /// secondary, then making it current with the old fork as the secondary, and finally dropping
+    /// the old fork.
+    pub fn update_forks(
+        &mut self,
+        current: EnrForkId,
+        secondary: Option<EnrForkId>,
+    ) -> Result<Enr, String> {
+        let forks = ActiveForks::new(current, secondary);
+        let fork_scoped_topics: Vec<GossipTopic> =
+            self.fork_scoped_topics.iter().cloned().collect();
+        for fork_scoped_topic in &fork_scoped_topics {
+            let joined = self.forks.topics(fork_scoped_topic);
+            let wanted = forks.topics(fork_scoped_topic);
+            // join the new topics before leaving the old, so the node stays reachable throughout
+            for topic in wanted.iter().filter(|topic| !joined.contains(topic)) {
+                self.subscribe(topic.clone());
+            }
+            for topic in joined.into_iter().filter(|topic| !wanted.contains(topic)) {
+                self.unsubscribe(topic);
+            }
+        }
+        *self.network_globals.fork_id.write() = forks.current().clone();
+        *self.network_globals.secondary_fork_id.write() = forks.secondary().cloned();
+        debug!(self.log, "Updated the forks served";
+            "current" => hex::encode(forks.current()),
+            "secondary" => forks.secondary().map(hex::encode));
+        self.forks = forks;
+        self.peer_manager
+            .discovery_mut()
+            .update_fork_enr(&self.forks)
     }
 
     /// Subscribes to a gossipsub topic.
@@ -301,8 +804,13 @@ impl Behaviour {
             .write()
             .insert(topic.clone());
 
+        self.mesh.entry(topic.hash()).or_default();
+
         let topic_str: String = topic.clone().into();
-        debug!(self.log, "Subscribed to topic"; "topic" => topic_str);
+        debug!(
+            log_context::topic(&self.log, &topic_str),
+            "Subscribed to topic"
+        );
         self.gossipsub.subscribe(topic.into())
     }
 
@@ -313,26 +821,301 @@ impl Behaviour {
             .gossipsub_subscriptions
             .write()
             .remove(&topic);
+        self.network_globals
+            .ready_topics
+            .write()
+            .remove(topic.as_str());
+        // the mesh peers are pruned when we leave, and back off for the unsubscribe backoff
+        if let Some(peers) = self.network_globals.topic_peers.read().get(&topic.hash()) {
+            for peer_id in peers {
+                self.mesh_backoff.unsubscribed(peer_id, &topic.hash());
+            }
+        }
+        // leaving the topic prunes its whole mesh
+        if let Some(peers) = self.mesh.remove(&topic.hash()) {
+            self.network_globals
+                .mesh_prunes
+                .fetch_add(peers.len(), Ordering::Relaxed);
+            for peer_id in &peers {
+                self.update_mesh_membership(peer_id);
+            }
+        }
         // unsubscribe from the topic
         self.gossipsub.unsubscribe(topic.into())
     }
 
-    /// Publishes a list of messages on the pubsub (gossipsub) behaviour, choosing the encoding.
-    pub fn publish(&mut self, topic: GossipTopic, message: Vec<u8>) {
+    /// Publishes a message on the pubsub (gossipsub) behaviour. If no peer is subscribed to the
+    /// topic, the message is held in the publish buffer until one subscribes. A message still held
+    /// once `ttl` has passed is dropped with `BehaviourEvent::PublishExpired` rather than
+    /// published late. A fork-scoped topic is published on the current fork; to publish on the
+    /// secondary fork, give its topic with the fork id filled in. With `bridge_forks` set, a
+    /// message on a fork-scoped topic subscribed to is published on the secondary fork as well.
+    /// With `reject_unsubscribed_publish` set, publishing on a topic we are not subscribed to
+    /// fails with `BehaviourEvent::PublishFailed`, as does publishing on a topic the topic
+    /// validator rejects.
+    pub fn publish(&mut self, topic: GossipTopic, message: Vec<u8>, ttl: Option<Duration>) {
+        if self.bridge_forks && topic.is_fork_scoped() {
+            let current = topic.for_fork(self.forks.current());
+            let others = self.fork_relay.targets(
+                &self.forks,
+                &self.fork_scoped_topics,
+                current.as_str(),
+                &message,
+            );
+            for to in others {
+                self.publish_message(to, message.clone(), ttl, None);
+            }
+        }
+        self.publish_message(topic, message, ttl, None);
+    }
+
+    /// Publishes a message as in `publish`, bridged from the message `origin` if given.
+    fn publish_message(
+        &mut self,
+        topic: GossipTopic,
+        message: Vec<u8>,
+        ttl: Option<Duration>,
+        origin: Option<MessageId>,
+    ) {
+        let topic = topic.for_fork(self.forks.current());
+        if let Err(error) = self.validate_topic(topic.as_str()) {
+            self.events
+                .push(BehaviourEvent::PublishFailed { topic, error });
+            return;
+        }
+        if self.reject_unsubscribed_publish
+            && !self
+                .network_globals
+                .gossipsub_subscriptions
+                .read()
+                .contains(&topic)
+        {
+            self.events.push(BehaviourEvent::PublishFailed {
+                topic,
+                error: "Not subscribed to the topic".into(),
+            });
+            return;
+        }
+        let subscribed = self
+            .network_globals
+            .topic_peers
+            .read()
+            .get(&topic.hash())
+            .map_or(false, |peers| !peers.is_empty());
+        if subscribed || !self.outbound_buffer.enabled() {
+            self.publish_now(topic, message, origin);
+            return;
+        }
+        debug!(
+            log_context::topic(&self.log, topic.as_str()),
+            "No peers subscribed, holding the message"
+        );
+        let buffered = BufferedMessage {
+            topic,
+            data: message,
+            deadline: ttl.map(|ttl| clock::now() + ttl),
+            origin,
+        };
+        if let Some(dropped) = self.outbound_buffer.push(buffered) {
+            self.drop_buffered(dropped);
+        }
+    }
+
+    /// Transforms and publishes a message on gossipsub.
+    fn publish_now(&mut self, topic: GossipTopic, message: Vec<u8>, origin: Option<MessageId>) {
+        if self.control_message_too_large(&topic, message.len()) {
+            return;
+        }
+        let message = match self.transform_outbound(&topic, message) {
+            Some(message) => self.seal_outbound(&topic, origin.as_ref(), message),
+            None => return,
+        };
+        if origin.is_some() {
+            self.network_globals
+                .bridged_messages
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        self.network_globals.stats.gossip_published(message.len());
+        self.add_to_mcache(message.len());
         self.gossipsub.publish(&topic.into(), message);
     }
 
+    /// Counts a message of `bytes` bytes kept in gossipsub's message cache.
+    fn add_to_mcache(&mut self, bytes: usize) {
+        let id = self.message_id_sizes.estimate(1, size_of::<MessageId>());
+        self.mcache
+            .add(clock::now(), size_of::<GossipsubMessage>() + bytes + id);
+    }
+
+    /// Publishes the messages held for a topic a peer has subscribed to, except those past their
+    /// deadline.
+    fn flush_buffered(&mut self, topic: &TopicHash) {
+        let now = clock::now();
+        for message in self.outbound_buffer.take(topic) {
+            if message.deadline.map_or(false, |deadline| deadline <= now) {
+                self.drop_buffered(message);
+            } else {
+                self.publish_now(message.topic, message.data, message.origin);
+            }
+        }
+    }
+
+    /// Fails the publish of a message larger than the control size limit on a control topic.
+    fn control_message_too_large(&mut self, topic: &GossipTopic, size: usize) -> bool {
+        if size <= self.control_max_message_size
+            || self.forwarding.class(topic.as_str()) != TopicClass::Control
+        {
+            return false;
+        }
+        self.events.push(BehaviourEvent::PublishFailed {
+            topic: topic.clone(),
+            error: format!(
+                "Message of {} bytes exceeds the control topic limit of {} bytes",
+                size, self.control_max_message_size
+            ),
+        });
+        true
+    }
+
+    /// Hands a message dropped from the publish buffer back to the application.
+    fn drop_buffered(&mut self, message: BufferedMessage) {
+        debug!(
+            log_context::topic(&self.log, message.topic.as_str()),
+            "Dropping an unpublished message"
+        );
+        self.network_globals
+            .expired_publishes
+            .fetch_add(1, Ordering::Relaxed);
+        // a bridged message was not published by the application
+        if message.origin.is_some() {
+            return;
+        }
+        self.events.push(BehaviourEvent::PublishExpired {
+            topic: message.topic,
+            message: message.data,
+        });
+    }
+
+    /// Sends a message straight to the given peers, bypassing the mesh. Only connected peers
+    /// subscribed to the topic are sent the message; they forward it like any other gossip.
+    /// Returns the number of peers the message was sent to. A fork-scoped topic is resolved as
+    /// in `publish`.
+    pub fn publish_to_peers(
+        &mut self,
+        topic: GossipTopic,
+        message: Vec<u8>,
+        peers: &[PeerId],
+    ) -> usize {
+        let topic = topic.for_fork(self.forks.current());
+        let topic_hash = topic.hash();
+        let targets: HashSet<PeerId> =
+            match self.network_globals.topic_peers.read().get(&topic_hash) {
+                Some(subscribed) => peers
+                    .iter()
+                    .filter(|peer_id| subscribed.contains(peer_id))
+                    .cloned()
+                    .collect(),
+                None => return 0,
+            };
+        if targets.is_empty() || self.control_message_too_large(&topic, message.len()) {
+            return 0;
+        }
+        let message = match self.transform_outbound(&topic, message) {
+            Some(message) => self.seal_outbound(&topic, None, message),
+            None => return 0,
+        };
+
+        let gs_msg = direct::message(
+            &self.direct_source,
+            message,
+            self.direct_sequence_number,
+            topic_hash,
+        );
+        self.direct_sequence_number = self.direct_sequence_number.wrapping_add(1);
+        self.network_globals
+            .stats
+            .gossip_published(gs_msg.data.len());
+        let id = (self.message_id_fn)(&gs_msg);
+        self.message_id_sizes.add(id.0.len());
+        self.direct_message_ids.put(id, ());
+        for peer_id in &targets {
+            self.direct_messages.push_back((
+                peer_id.clone(),
+                GossipsubRpc {
+                    messages: vec![gs_msg.clone()],
+                    subscriptions: Vec::new(),
+                    control_msgs: Vec::new(),
+                },
+            ));
+        }
+        targets.len()
+    }
+
     /// Forwards a message that is waiting in gossipsub's mcache. Messages are only propagated
     /// once validated by the beacon chain.
     pub fn propagate_message(&mut self, propagation_source: &PeerId, message_id: MessageId) {
-        self.gossipsub
-            .propagate_message(&message_id, propagation_source);
+        let dropped_forwards = &self.network_globals.dropped_forwards;
+        let forward = self
+            .forwarding
+            .forward(propagation_source.clone(), message_id, |_| {
+                dropped_forwards.fetch_add(1, Ordering::Relaxed);
+            });
+        match forward {
+            Forward::Now(propagation_source, message_id) => {
+                self.gossipsub
+                    .propagate_message(&message_id, &propagation_source);
+            }
+            Forward::Waiting => {}
+            Forward::Paused => {
+                trace!(
+                    log_context::peer(&self.log, &propagation_source),
+                    "Forwarding paused, message not propagated"
+                );
+            }
+        }
+    }
+
+    /// Stops forwarding the messages received on a topic, while still receiving them and keeping
+    /// our place in its mesh.
+    pub fn pause_forwarding(&mut self, topic: &str) {
+        let dropped = self.forwarding.pause(topic);
+        debug!(log_context::topic(&self.log, topic), "Forwarding paused";
+            "dropped_forwards" => dropped);
+    }
+
+    /// Forwards the messages received on a topic again.
+    pub fn resume_forwarding(&mut self, topic: &str) {
+        if self.forwarding.resume(topic) {
+            debug!(log_context::topic(&self.log, topic), "Forwarding resumed");
+        }
     }
 
     /// Send a request to a peer over RPC.
     pub fn send_request(&mut self, peer_id: PeerId, request_id: RequestId, request: Request) {
-        self.mothra_rpc
-            .send_request(peer_id, request_id, request.into());
+        let request = match request {
+            Request::Status(data) => RPCRequest::Status(data),
+            Request::Goodbye(data) => RPCRequest::Goodbye(data),
+            Request::Custom(name, data) => match self.custom_protocols.get(&name) {
+                Some(Protocol::Custom(custom)) => RPCRequest::Custom(custom, data),
+                _ => {
+                    // the protocol is not served, so no peer would negotiate it
+                    self.events.push(BehaviourEvent::RPCFailed {
+                        id: request_id,
+                        peer_id,
+                        error: RPCError::UnsupportedProtocol,
+                    });
+                    return;
+                }
+            },
+        };
+        Stats::increment(&self.network_globals.stats.rpc_requests_sent);
+        self.mothra_rpc.send_request(peer_id, request_id, request);
+    }
+
+    /// Returns true if `name` is a protocol registered in `NetworkConfig::custom_protocols`, that
+    /// requests can be sent with.
+    pub fn serves_protocol(&self, name: &str) -> bool {
+        self.custom_protocols.get(name).is_some()
     }
 
     /// Send a successful response to a peer over RPC.
@@ -342,6 +1125,7 @@ impl Behaviour {
         id: PeerRequestId,
         response: Response,
     ) {
+        Stats::increment(&self.network_globals.stats.rpc_responses_sent);
         self.mothra_rpc.send_response(peer_id, id, response.into())
     }
 
@@ -362,9 +1146,32 @@ impl Behaviour {
 
     /* Peer management functions */
 
+    /// Queues a peer for disconnection, returning the action that shuts down its connection after
+    /// sending it a Goodbye carrying `reason`.
+    fn goodbye(
+        &mut self,
+        peer_id: PeerId,
+        reason: Vec<u8>,
+    ) -> NBAction<BehaviourHandlerIn, BehaviourEvent> {
+        self.peers_to_dc.push(peer_id.clone());
+        NBAction::NotifyHandler {
+            peer_id,
+            handler: NotifyHandler::Any,
+            event: BehaviourHandlerIn::Shutdown(Some((
+                RequestId::Behaviour,
+                RPCRequest::Goodbye(reason),
+            ))),
+        }
+    }
+
     /// Notify discovery that the peer has been banned.
     // TODO: Remove this and integrate all disconnection/banning logic inside the peer manager.
-    pub fn peer_banned(&mut self, _peer_id: PeerId) {}
+    pub fn peer_banned(&mut self, peer_id: PeerId) {
+        self.network_globals.unmute(&peer_id);
+        if let Some(registrations) = self.rendezvous_registrations.as_mut() {
+            registrations.remove_peer(&peer_id);
+        }
+    }
 
     /// Notify discovery that the peer has been unbanned.
     // TODO: Remove this and integrate all disconnection/banning logic inside the peer manager.
@@ -375,16 +1182,75 @@ impl Behaviour {
         self.peer_manager.discovery_mut().table_entries_enr()
     }
 
+    /// Returns the routing table entries, their bucket occupancy and the pending discovery
+    /// queries.
+    pub fn dht_snapshot(&mut self) -> DhtSnapshot {
+        self.peer_manager.discovery_mut().snapshot()
+    }
+
     /// Add an ENR to the routing table of the discovery mechanism.
     pub fn add_enr(&mut self, enr: Enr) {
         self.peer_manager.discovery_mut().add_enr(enr);
     }
 
+    /// Sets fields of the local ENR and republishes it under a new sequence number, keeping the
+    /// existing connections. See `Discovery::update_enr`.
+    pub fn update_enr(&mut self, fields: Vec<(String, Vec<u8>)>) -> Result<Enr, String> {
+        self.peer_manager.discovery_mut().update_enr(fields)
+    }
+
+    /// Searches for up to `target` peers whose ENR matches `predicate`. Matching peers are
+    /// returned with `BehaviourEvent::MatchingPeersDiscovered`, possibly over several events.
+    pub fn discover_matching_peers(&mut self, predicate: EnrPredicate, target: usize) {
+        self.peer_manager
+            .discovery_mut()
+            .discover_matching_peers(predicate, target);
+    }
+
     /// Attempts to discover new peers for a given subnet. The `min_ttl` gives the time at which we
     /// would like to retain the peers for.
     pub fn discover_subnet_peers(&mut self, subnet_id: SubnetId, min_ttl: Option<Instant>) {
-        //TODO: not sure yet
-        //self.peer_manager.discover_subnet_peers(subnet_id, min_ttl)
+        self.peer_manager.discover_subnet_peers(subnet_id, min_ttl)
+    }
+
+    /* DHT functions */
+
+    /// Announces in the DHT that the node provides `key`. The announcement is republished
+    /// periodically until the node stops.
+    #[cfg(feature = "kad")]
+    pub fn start_providing(&mut self, key: Vec<u8>, reply: KadReply<()>) {
+        match self.kademlia.start_providing(Key::new(&key)) {
+            Ok(id) => self.kad_queries.start_providing(id, reply),
+            Err(e) => {
+                let _ = reply.send(Err(format!("{:?}", e)));
+            }
+        }
+    }
+
+    /// Searches the DHT for the peers providing `key`.
+    #[cfg(feature = "kad")]
+    pub fn get_providers(&mut self, key: Vec<u8>, reply: KadReply<Vec<PeerId>>) {
+        let id = self.kademlia.get_providers(Key::new(&key));
+        self.kad_queries.get_providers(id, reply);
+    }
+
+    /// Stores `value` under `key` in the DHT, replacing the value any peer storing the key had.
+    #[cfg(feature = "kad")]
+    pub fn put_record(&mut self, key: Vec<u8>, value: Vec<u8>, reply: KadReply<()>) {
+        let record = Record::new(Key::new(&key), value);
+        match self.kademlia.put_record(record, Quorum::One) {
+            Ok(id) => self.kad_queries.put_record(id, reply),
+            Err(e) => {
+                let _ = reply.send(Err(format!("{:?}", e)));
+            }
+        }
+    }
+
+    /// Searches the DHT for the value stored under `key`.
+    #[cfg(feature = "kad")]
+    pub fn get_record(&mut self, key: Vec<u8>, reply: KadReply<Vec<Vec<u8>>>) {
+        let id = self.kademlia.get_record(&Key::new(&key), Quorum::One);
+        self.kad_queries.get_record(id, reply);
     }
 
     /* Private internal functions */
@@ -397,23 +1263,47 @@ impl Behaviour {
     }
 
     /// Sends a Ping request to the peer.
-    fn ping(&mut self, id: RequestId, peer_id: PeerId) {
-        debug!(self.log, "Sending Ping"; "request_id" => id, "peer_id" => peer_id.to_string());
+    pub fn ping(&mut self, id: RequestId, peer_id: PeerId) {
+        debug!(
+            log_context::rpc(&self.log, &peer_id, Protocol::Ping, id),
+            "Sending Ping"
+        );
+        Stats::increment(&self.network_globals.stats.rpc_requests_sent);
 
+        let ping = self.ping_payload(&peer_id);
         self.mothra_rpc
-            .send_request(peer_id, id, RPCRequest::Ping(self.ping_data.clone()));
+            .send_request(peer_id, id, RPCRequest::Ping(ping));
     }
 
     /// Sends a Pong response to the peer.
     fn pong(&mut self, id: PeerRequestId, peer_id: PeerId) {
-        debug!(self.log, "Sending Pong"; "request_id" => id.1, "peer_id" => peer_id.to_string());
-        let event = RPCCodedResponse::Success(RPCResponse::Pong(self.ping_data.clone()));
+        debug!(
+            log_context::rpc(&self.log, &peer_id, Protocol::Ping, id.1),
+            "Sending Pong"
+        );
+        Stats::increment(&self.network_globals.stats.rpc_responses_sent);
+        let event = RPCCodedResponse::Success(RPCResponse::Pong(self.ping_payload(&peer_id)));
         self.mothra_rpc.send_response(peer_id, id, event);
     }
 
+    /// The payload of a Ping or Pong sent to a peer.
+    fn ping_payload(&self, peer_id: &PeerId) -> Vec<u8> {
+        match &self.ping_handler {
+            Some(handler) => handler.payload(peer_id),
+            None => self.ping_data.clone(),
+        }
+    }
+
     /// Sends a METADATA request to a peer.
     fn send_meta_data_request(&mut self, peer_id: PeerId) {
-        debug!(self.log, "Sending MetaData request"; "peer_id" => peer_id.to_string());
+        let log = log_context::rpc(
+            &self.log,
+            &peer_id,
+            Protocol::MetaData,
+            RequestId::Behaviour,
+        );
+        debug!(log, "Sending MetaData request");
+        Stats::increment(&self.network_globals.stats.rpc_requests_sent);
         let event = RPCRequest::MetaData;
         self.mothra_rpc
             .send_request(peer_id, RequestId::Behaviour, event);
@@ -421,11 +1311,212 @@ impl Behaviour {
 
     /// Sends a METADATA response to a peer.
     fn send_meta_data_response(&mut self, id: PeerRequestId, peer_id: PeerId) {
-        debug!(self.log, "Sending MetaData response"; "peer_id" => peer_id.to_string());
+        let log = log_context::rpc(&self.log, &peer_id, Protocol::MetaData, id.1);
+        debug!(log, "Sending MetaData response");
+        Stats::increment(&self.network_globals.stats.rpc_responses_sent);
         let event = RPCCodedResponse::Success(RPCResponse::MetaData(self.meta_data.clone()));
         self.mothra_rpc.send_response(peer_id, id, event);
     }
 
+    /// Answers a peer's Status request with our status.
+    fn send_status_response(&mut self, id: PeerRequestId, peer_id: PeerId, status: &StatusMessage) {
+        let log = log_context::rpc(&self.log, &peer_id, Protocol::Status, id.1);
+        debug!(log, "Sending Status response"; "head_slot" => status.head_slot);
+        Stats::increment(&self.network_globals.stats.rpc_responses_sent);
+        let event = RPCCodedResponse::Success(RPCResponse::Status(status.encode()));
+        self.mothra_rpc.send_response(peer_id, id, event);
+    }
+
+    /// The topics we are subscribed to, encoded for the topics RPC.
+    fn local_topics(&self) -> Vec<u8> {
+        let topics = self
+            .network_globals
+            .gossipsub_subscriptions
+            .read()
+            .iter()
+            .map(|topic| topic.as_str().to_string())
+            .collect();
+        Topics { topics }.encode()
+    }
+
+    /// Sends our subscribed topics to a peer, asking for its own in return.
+    fn send_topics_request(&mut self, peer_id: PeerId) {
+        let log = log_context::rpc(&self.log, &peer_id, Protocol::Topics, RequestId::Behaviour);
+        debug!(log, "Sending Topics request");
+        Stats::increment(&self.network_globals.stats.rpc_requests_sent);
+        let event = RPCRequest::Topics(self.local_topics());
+        self.mothra_rpc
+            .send_request(peer_id, RequestId::Behaviour, event);
+    }
+
+    /// Sends our subscribed topics in response to a peer's.
+    fn send_topics_response(&mut self, id: PeerRequestId, peer_id: PeerId) {
+        let log = log_context::rpc(&self.log, &peer_id, Protocol::Topics, id.1);
+        debug!(log, "Sending Topics response");
+        Stats::increment(&self.network_globals.stats.rpc_responses_sent);
+        let event = RPCCodedResponse::Success(RPCResponse::Topics(self.local_topics()));
+        self.mothra_rpc.send_response(peer_id, id, event);
+    }
+
+    /// Stores the topics a peer sent in a topics request or response.
+    fn on_peer_topics(&mut self, peer_id: &PeerId, topics: &[u8]) {
+        match Topics::decode(topics) {
+            Some(Topics { mut topics }) => {
+                topics.retain(|topic| self.validate_topic(topic).is_ok());
+                debug!(log_context::peer(&self.log, peer_id), "Received peer topics";
+                    "topics" => topics.len());
+                self.network_globals
+                    .peers
+                    .write()
+                    .add_topics(peer_id, topics);
+            }
+            None => {
+                debug!(
+                    log_context::peer(&self.log, peer_id),
+                    "Peer sent malformed topics"
+                );
+                self.peer_manager.report_peer(
+                    peer_id,
+                    PeerAction::LowToleranceError,
+                    ScoreSource::Transport,
+                );
+            }
+        }
+    }
+
+    /// The addresses we register at rendezvous points: the TCP addresses of our ENR, then the
+    /// addresses we listen on.
+    fn local_rendezvous_addresses(&self) -> Vec<Multiaddr> {
+        let mut addresses = self.local_enr().multiaddr_tcp();
+        addresses.extend(self.network_globals.listen_multiaddrs());
+        address_filter::filter_addresses(&mut addresses, rendezvous::MAX_ADDRESSES, true);
+        addresses
+    }
+
+    /// Registers under our namespaces at a rendezvous point, and asks it for the peers registered
+    /// under them.
+    fn send_rendezvous_requests(&mut self, peer_id: &PeerId) {
+        let log = log_context::rpc(
+            &self.log,
+            peer_id,
+            Protocol::Rendezvous,
+            RequestId::Behaviour,
+        );
+        debug!(log, "Sending rendezvous requests");
+        let addresses = self.local_rendezvous_addresses();
+        for namespace in &self.rendezvous_namespaces {
+            let register = RendezvousMessage::Register {
+                namespace: namespace.clone(),
+                addresses: addresses.clone(),
+                ttl: rendezvous::DEFAULT_TTL,
+            };
+            let discover = RendezvousMessage::Discover {
+                namespace: namespace.clone(),
+                limit: rendezvous::MAX_DISCOVERED as u16,
+            };
+            for message in vec![register, discover] {
+                Stats::increment(&self.network_globals.stats.rpc_requests_sent);
+                self.mothra_rpc.send_request(
+                    peer_id.clone(),
+                    RequestId::Behaviour,
+                    RPCRequest::Rendezvous(message.encode()),
+                );
+            }
+        }
+    }
+
+    /// Answers a rendezvous request from a peer, if we are a rendezvous point.
+    fn on_rendezvous_request(&mut self, id: PeerRequestId, peer_id: PeerId, message: &[u8]) {
+        let now = clock::now();
+        let response = match (
+            self.rendezvous_registrations.as_mut(),
+            RendezvousMessage::decode(message),
+        ) {
+            (None, _) => RPCCodedResponse::ServerError("Not a rendezvous point".to_string().into()),
+            (
+                Some(registrations),
+                Some(RendezvousMessage::Register {
+                    namespace,
+                    addresses,
+                    ttl,
+                }),
+            ) => {
+                debug!(log_context::rpc(&self.log, &peer_id, Protocol::Rendezvous, id.1),
+                    "Peer registered at rendezvous"; "namespace" => &namespace);
+                let connected_from: Vec<Multiaddr> = self
+                    .peer_manager
+                    .connections_of(&peer_id)
+                    .map(|(_, endpoint)| endpoint.get_remote_address().clone())
+                    .collect();
+                let message = match registrations.register(
+                    peer_id.clone(),
+                    namespace,
+                    addresses,
+                    &connected_from,
+                    ttl,
+                    now,
+                ) {
+                    Ok(ttl) => RendezvousMessage::Registered { ttl },
+                    Err(refusal) => RendezvousMessage::Refused(refusal),
+                };
+                RPCCodedResponse::Success(RPCResponse::Rendezvous(message.encode()))
+            }
+            (Some(registrations), Some(RendezvousMessage::Discover { namespace, limit })) => {
+                let found = registrations.discover(&namespace, limit as usize, &peer_id, now);
+                let message = RendezvousMessage::Registrations(found);
+                RPCCodedResponse::Success(RPCResponse::Rendezvous(message.encode()))
+            }
+            (Some(_), _) => {
+                debug!(
+                    log_context::rpc(&self.log, &peer_id, Protocol::Rendezvous, id.1),
+                    "Peer sent a malformed rendezvous request"
+                );
+                RPCCodedResponse::InvalidRequest("Invalid rendezvous request".to_string().into())
+            }
+        };
+        Stats::increment(&self.network_globals.stats.rpc_responses_sent);
+        self.mothra_rpc.send_response(peer_id, id, response);
+    }
+
+    /// Dials the peers a rendezvous point told us about.
+    fn on_rendezvous_response(&mut self, peer_id: &PeerId, message: &[u8]) {
+        let log = log_context::rpc(
+            &self.log,
+            peer_id,
+            Protocol::Rendezvous,
+            RequestId::Behaviour,
+        );
+        match RendezvousMessage::decode(message) {
+            Some(RendezvousMessage::Refused(refusal)) => {
+                warn!(log, "Rendezvous point refused our registration"; "reason" => refusal.to_string());
+            }
+            Some(RendezvousMessage::Registered { ttl }) => {
+                debug!(log, "Registered at rendezvous point"; "ttl" => ttl);
+            }
+            Some(RendezvousMessage::Registrations(registrations)) => {
+                debug!(log, "Peers found at rendezvous point"; "peers" => registrations.len());
+                for (found, mut addresses) in registrations {
+                    address_filter::filter_addresses(
+                        &mut addresses,
+                        self.max_identify_addresses,
+                        self.allow_private_addresses,
+                    );
+                    if found != self.local_peer_id && !addresses.is_empty() {
+                        self.peer_manager.rendezvous_peer_found(found, addresses);
+                    }
+                }
+            }
+            _ => {
+                debug!(log, "Rendezvous point sent a malformed response");
+                self.peer_manager.report_peer(
+                    peer_id,
+                    PeerAction::LowToleranceError,
+                    ScoreSource::Transport,
+                );
+            }
+        }
+    }
+
     /// Returns a reference to the peer manager to allow the swarm to notify the manager of peer
     /// status
     pub fn peer_manager(&mut self) -> &mut PeerManager {
@@ -434,8 +1525,135 @@ impl Behaviour {
 
     fn on_gossip_event(&mut self, event: GossipsubEvent) {
         match event {
-            GossipsubEvent::Message(propagation_source, id, gs_msg) => {
-
+            GossipsubEvent::Message(propagation_source, id, mut gs_msg) => {
+                self.add_to_mcache(gs_msg.data.len());
+                if self.direct_message_ids.contains(&id) {
+                    // a peer relayed a message we published directly
+                    return;
+                }
+                if !self.filter_unsubscribed_topics(&propagation_source, &mut gs_msg.topics) {
+                    return;
+                }
+                let class = match gs_msg.topics.first() {
+                    Some(topic) => self.forwarding.class(topic.as_str()),
+                    None => TopicClass::Data,
+                };
+                if class == TopicClass::Control && gs_msg.data.len() > self.control_max_message_size
+                {
+                    debug!(log_context::peer(&self.log, &propagation_source),
+                        "Dropped gossip over the control topic size limit";
+                        "message_id" => id.to_string(),
+                        "size" => gs_msg.data.len());
+                    self.network_globals
+                        .oversized_control_messages
+                        .fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                if !self
+                    .topic_publishers
+                    .allows(&propagation_source, &gs_msg.topics)
+                {
+                    debug!(log_context::peer(&self.log, &propagation_source),
+                        "Dropped gossip from a peer not allowed on its topic";
+                        "message_id" => id.to_string());
+                    self.network_globals
+                        .rejected_publisher_messages
+                        .fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                if !self.within_rate(&propagation_source, &id, &gs_msg.topics) {
+                    self.network_globals
+                        .rate_limited_messages
+                        .fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                if let Some(topic) = gs_msg.topics.first() {
+                    let admitted = self.load_shedder.admit(topic.as_str(), clock::now());
+                    if let Some(overload) = self.load_shedder.take_report() {
+                        warn!(self.log, "Gossip received over the node's rate was dropped";
+                            "received" => overload.received,
+                            "shed" => overload.shed.values().sum::<usize>());
+                        self.events.push(BehaviourEvent::Overloaded(overload));
+                    }
+                    if !admitted {
+                        self.network_globals
+                            .shed_messages
+                            .fetch_add(1, Ordering::Relaxed);
+                        return;
+                    }
+                }
+                self.network_globals
+                    .stats
+                    .gossip_received(gs_msg.data.len());
+                let (origin, data) = match gs_msg.topics.first() {
+                    Some(topic) if self.topic_bridges.is_target(topic.as_str()) => {
+                        match open_bridged(gs_msg.data, &self.network_globals.buffer_pool) {
+                            Ok(opened) => opened,
+                            Err(e) => {
+                                let log = log_context::peer(&self.log, &propagation_source);
+                                debug!(log_context::topic(&log, topic.as_str()),
+                                    "Dropped gossip without a valid bridge envelope";
+                                    "message_id" => id.to_string(),
+                                    "error" => e);
+                                self.network_globals
+                                    .transform_failures
+                                    .fetch_add(1, Ordering::Relaxed);
+                                self.peer_manager.report_peer(
+                                    &propagation_source,
+                                    PeerAction::HighToleranceError,
+                                    ScoreSource::Gossip,
+                                );
+                                return;
+                            }
+                        }
+                    }
+                    _ => (None, gs_msg.data),
+                };
+                let message = match gs_msg.topics.first() {
+                    Some(topic) => match self.message_transforms.inbound(topic.as_str(), data) {
+                        Ok(message) => message,
+                        Err(e) => {
+                            let log = log_context::peer(&self.log, &propagation_source);
+                            debug!(log_context::topic(&log, topic.as_str()),
+                                    "Dropped gossip that could not be transformed";
+                                    "message_id" => id.to_string(),
+                                    "error" => e);
+                            self.network_globals
+                                .transform_failures
+                                .fetch_add(1, Ordering::Relaxed);
+                            self.peer_manager.report_peer(
+                                &propagation_source,
+                                PeerAction::HighToleranceError,
+                                ScoreSource::Gossip,
+                            );
+                            return;
+                        }
+                    },
+                    None => data,
+                };
+                if let Some(origin) = &origin {
+                    if self
+                        .bridged_copies
+                        .put(bridged_copy(origin, &message), ())
+                        .is_some()
+                    {
+                        trace!(self.log, "Dropped a copy of a message already bridged";
+                            "message_id" => id.to_string(),
+                            "origin" => origin.to_string());
+                        self.network_globals
+                            .duplicate_bridged_messages
+                            .fetch_add(1, Ordering::Relaxed);
+                        return;
+                    }
+                }
+                if let Some(topic) = gs_msg.topics.first() {
+                    self.forwarding
+                        .received(id.clone(), topic.as_str().to_string());
+                    self.bridge(&id, topic.as_str(), origin.as_ref(), &message);
+                    if self.bridge_forks {
+                        self.relay_between_forks(&id, topic.as_str(), &message);
+                    }
+                }
                 let client = self.network_globals.client(&propagation_source);
                 let agent_string = client.agent_string.unwrap_or_default();
                 self.events.push(BehaviourEvent::PubsubMessage {
@@ -443,35 +1661,612 @@ impl Behaviour {
                     sequence_number: gs_msg.sequence_number,
                     agent_string: agent_string,
                     source: propagation_source,
+                    publisher: gs_msg.source,
                     topics: gs_msg.topics,
-                    message: gs_msg.data,
+                    message,
                 });
             }
             GossipsubEvent::Subscribed { peer_id, topic } => {
+                self.network_globals
+                    .topic_peers
+                    .write()
+                    .entry(topic.clone())
+                    .or_default()
+                    .insert(peer_id.clone());
+                self.flush_buffered(&topic);
                 self.events
                     .push(BehaviourEvent::PeerSubscribed(peer_id, topic));
             }
-            GossipsubEvent::Unsubscribed { .. } => {}
+            GossipsubEvent::Unsubscribed { peer_id, topic } => {
+                if let Some(peers) = self.network_globals.topic_peers.write().get_mut(&topic) {
+                    peers.remove(&peer_id);
+                }
+            }
+        }
+    }
+
+    /// Drops the subscriptions of a peer to topics the topic validator rejects, so they are never
+    /// tracked or grafted on.
+    fn drop_invalid_subscriptions(&self, peer_id: &PeerId, rpc: &mut GossipsubRpc) {
+        if self.topic_validator.is_none() {
+            return;
+        }
+        rpc.subscriptions.retain(|subscription| {
+            match self.validate_topic(subscription.topic_hash.as_str()) {
+                Ok(()) => true,
+                Err(error) => {
+                    debug!(log_context::peer(&self.log, peer_id), "Ignoring subscription";
+                        "topic" => subscription.topic_hash.as_str(), "error" => error);
+                    false
+                }
+            }
+        });
+    }
+
+    /// Records the prunes sent by a peer and refuses its grafts on topics it is backing off from.
+    /// Refused grafts are answered with a prune, and grafts that flood us are penalized.
+    fn apply_inbound_backoff(&mut self, peer_id: &PeerId, rpc: &mut GossipsubRpc) {
+        let mut refused = Vec::new();
+        let mut flooded = false;
+        for action in rpc.control_msgs.drain(..).collect::<Vec<_>>() {
+            match action {
+                GossipsubControlAction::Prune { topic_hash } => {
+                    self.mesh_backoff.pruned(peer_id, &topic_hash);
+                    rpc.control_msgs
+                        .push(GossipsubControlAction::Prune { topic_hash });
+                }
+                GossipsubControlAction::Graft { topic_hash } => {
+                    match self.mesh_backoff.check_graft(peer_id, &topic_hash) {
+                        GraftCheck::Allowed => rpc
+                            .control_msgs
+                            .push(GossipsubControlAction::Graft { topic_hash }),
+                        GraftCheck::Backoff => refused.push(topic_hash),
+                        GraftCheck::Flood => {
+                            flooded = true;
+                            refused.push(topic_hash);
+                        }
+                    }
+                }
+                action => rpc.control_msgs.push(action),
+            }
+        }
+        if refused.is_empty() {
+            return;
+        }
+
+        debug!(log_context::peer(&self.log, peer_id), "Refused grafts from a peer backing off";
+            "topics" => format!("{:?}", refused));
+        if flooded {
+            self.peer_manager.report_peer(
+                peer_id,
+                PeerAction::MidToleranceError,
+                ScoreSource::Gossip,
+            );
+        }
+        self.direct_messages.push_back((
+            peer_id.clone(),
+            GossipsubRpc {
+                messages: Vec::new(),
+                subscriptions: Vec::new(),
+                control_msgs: refused
+                    .into_iter()
+                    .map(|topic_hash| GossipsubControlAction::Prune { topic_hash })
+                    .collect(),
+            },
+        ));
+    }
+
+    /// Records the prunes we send and withholds our grafts on topics the peer is backing off from.
+    /// The peer is left out of our view of the mesh, though gossipsub still counts it in.
+    fn apply_outbound_backoff(&mut self, peer_id: &PeerId, mut rpc: GossipsubRpc) -> DelegateIn {
+        let withheld = self
+            .mesh_backoff
+            .withhold_grafts(peer_id, &mut rpc.control_msgs);
+        if !withheld.is_empty() {
+            debug!(log_context::peer(&self.log, peer_id), "Withheld grafts to a peer backing off";
+                "topics" => format!("{:?}", withheld));
+        }
+        if self.no_graft_peers.contains(peer_id) {
+            // as with a backoff, gossipsub still counts the peer in its mesh
+            rpc.control_msgs.retain(|action| match action {
+                GossipsubControlAction::Graft { .. } => false,
+                _ => true,
+            });
+        }
+
+        self.track_mesh(peer_id, &rpc.control_msgs, true);
+        self.observe_control(peer_id, &rpc.control_msgs, true);
+        self.observe_iwant_answers(peer_id, &rpc.messages);
+        DelegateIn::Gossipsub(rpc)
+    }
+
+    /// Updates the meshes from the grafts and prunes exchanged with a peer. Grafts received on
+    /// topics we are not subscribed to are not accepted by gossipsub, so are ignored.
+    fn track_mesh(&mut self, peer_id: &PeerId, actions: &[GossipsubControlAction], outbound: bool) {
+        for action in actions {
+            match action {
+                GossipsubControlAction::Graft { topic_hash } => {
+                    let inserted = match self.mesh.get_mut(topic_hash) {
+                        Some(peers) => peers.insert(peer_id.clone()),
+                        None if outbound => self
+                            .mesh
+                            .entry(topic_hash.clone())
+                            .or_default()
+                            .insert(peer_id.clone()),
+                        None => false,
+                    };
+                    if inserted {
+                        self.network_globals
+                            .mesh_grafts
+                            .fetch_add(1, Ordering::Relaxed);
+                        self.update_mesh_membership(peer_id);
+                        self.check_topic_ready(topic_hash);
+                    }
+                }
+                GossipsubControlAction::Prune { topic_hash } => {
+                    if let Some(true) = self
+                        .mesh
+                        .get_mut(topic_hash)
+                        .map(|peers| peers.remove(peer_id))
+                    {
+                        self.network_globals
+                            .mesh_prunes
+                            .fetch_add(1, Ordering::Relaxed);
+                        self.update_mesh_membership(peer_id);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Reports a subscribed topic as ready the first time its mesh reaches the healthy size, from
+    /// which on published messages are likely to propagate.
+    fn check_topic_ready(&mut self, topic_hash: &TopicHash) {
+        let mesh_size = self.mesh.get(topic_hash).map_or(0, HashSet::len);
+        if mesh_size < self.ready_mesh_size {
+            return;
+        }
+        let topic = GossipTopic::new(topic_hash.as_str().to_string());
+        if !self
+            .network_globals
+            .gossipsub_subscriptions
+            .read()
+            .contains(&topic)
+        {
+            return;
+        }
+        if self
+            .network_globals
+            .ready_topics
+            .write()
+            .insert(topic.as_str().to_string())
+        {
+            debug!(log_context::topic(&self.log, topic.as_str()), "Topic mesh ready";
+                "mesh_size" => mesh_size);
+            self.events.push(BehaviourEvent::TopicReady(topic));
+        }
+    }
+
+    /// Records the size of each gossip message received from a peer, and whether the peer was the
+    /// first to deliver it.
+    fn observe_messages(&mut self, peer_id: &PeerId, messages: &[GossipsubMessage]) {
+        if messages.is_empty() {
+            return;
+        }
+        let mut sizes = self.network_globals.gossip_message_sizes.write();
+        let mut peers = self.network_globals.peers.write();
+        let mut contribution = peers
+            .peer_info_mut(peer_id)
+            .map(|info| &mut info.gossip_contribution);
+        for message in messages {
+            let id = (self.message_id_fn)(message);
+            self.message_id_sizes.add(id.0.len());
+            let first = self.received_message_ids.put(id, ()).is_none();
+            sizes.observe(message.data.len());
+            if let Some(contribution) = contribution.as_mut() {
+                contribution.record(first, message.data.len());
+            }
+        }
+    }
+
+    /// Counts the control messages exchanged with a peer, globally and in its `PeerInfo`, and
+    /// logs them if `log_gossip_control` is set.
+    fn observe_control(
+        &self,
+        peer_id: &PeerId,
+        actions: &[GossipsubControlAction],
+        outbound: bool,
+    ) {
+        if actions.is_empty() {
+            return;
+        }
+        {
+            let mut totals = self.network_globals.gossip_control.write();
+            let mut peers = self.network_globals.peers.write();
+            let mut peer_stats = peers
+                .peer_info_mut(peer_id)
+                .map(|info| &mut info.gossip_control);
+            for action in actions {
+                totals.record(action, outbound);
+                if let Some(stats) = peer_stats.as_mut() {
+                    stats.record(action, outbound);
+                }
+            }
+        }
+
+        if !self.log_gossip_control {
+            return;
+        }
+        let direction = if outbound { "sent" } else { "received" };
+        let log = log_context::peer(&self.log, peer_id);
+        for action in actions {
+            let (kind, topic, message_ids) = match action {
+                GossipsubControlAction::Graft { topic_hash } => ("graft", topic_hash.as_str(), 0),
+                GossipsubControlAction::Prune { topic_hash } => ("prune", topic_hash.as_str(), 0),
+                GossipsubControlAction::IHave {
+                    topic_hash,
+                    message_ids,
+                } => ("ihave", topic_hash.as_str(), message_ids.len()),
+                GossipsubControlAction::IWant { message_ids } => ("iwant", "", message_ids.len()),
+            };
+            debug!(log_context::topic(&log, topic), "Gossipsub control message";
+                "direction" => direction,
+                "kind" => kind,
+                "message_ids" => message_ids);
+        }
+    }
+
+    /// Records the messages a peer asked for with IWANT.
+    fn observe_iwants(&mut self, peer_id: &PeerId, actions: &[GossipsubControlAction]) {
+        let message_ids: Vec<MessageId> = actions
+            .iter()
+            .filter_map(|action| match action {
+                GossipsubControlAction::IWant { message_ids } => Some(message_ids),
+                _ => None,
+            })
+            .flatten()
+            .cloned()
+            .collect();
+        if message_ids.is_empty() {
+            return;
+        }
+        let requested = self.pending_iwants.requested(peer_id, &message_ids);
+        self.network_globals
+            .iwant_requested_messages
+            .fetch_add(requested, Ordering::Relaxed);
+    }
+
+    /// Counts the messages sent to a peer that answer its IWANTs, the mcache hits.
+    fn observe_iwant_answers(&mut self, peer_id: &PeerId, messages: &[GossipsubMessage]) {
+        // message ids are only computed while the peer waits for an answer
+        if messages.is_empty() || !self.pending_iwants.is_waiting(peer_id) {
+            return;
+        }
+        let served = messages
+            .iter()
+            .filter(|message| {
+                let id = (self.message_id_fn)(message);
+                self.pending_iwants.served(peer_id, &id)
+            })
+            .count();
+        self.network_globals
+            .iwant_served_messages
+            .fetch_add(served, Ordering::Relaxed);
+    }
+
+    /// Tells the handlers of a peer whether it is in one of our meshes, if that changed. Under
+    /// `KeepAlivePolicy::Idle` this keeps connections to mesh peers alive.
+    fn update_mesh_membership(&mut self, peer_id: &PeerId) {
+        if self.keep_alive_policy != KeepAlivePolicy::Idle {
+            return;
+        }
+        let in_mesh = self.mesh.values().any(|peers| peers.contains(peer_id));
+        let changed = if in_mesh {
+            self.kept_alive_mesh_peers.insert(peer_id.clone())
+        } else {
+            self.kept_alive_mesh_peers.remove(peer_id)
+        };
+        if changed {
+            self.mesh_membership_updates
+                .push_back((peer_id.clone(), NotifyHandler::All, in_mesh));
+        }
+    }
+
+    /// Sends a GRAFT to the best reputed peers on a topic when the median reputation of its mesh
+    /// is below the threshold. See the `opportunistic_graft` module.
+    fn graft_opportunistically(&mut self) {
+        let mut grafts = Vec::new();
+        {
+            let peers = self.network_globals.peers.read();
+            let topic_peers = self.network_globals.topic_peers.read();
+            for (topic, mesh) in &self.mesh {
+                let candidates = opportunistic_graft::candidates(
+                    mesh,
+                    topic_peers.get(topic).into_iter().flatten(),
+                    |peer_id| peers.reputation(peer_id),
+                    |peer_id| self.mesh_backoff.check_graft(peer_id, topic) == GraftCheck::Allowed,
+                    self.opportunistic_graft_threshold,
+                    self.opportunistic_graft_peers,
+                );
+                grafts.extend(
+                    candidates
+                        .into_iter()
+                        .map(|peer_id| (peer_id, topic.clone())),
+                );
+            }
+        }
+
+        for (peer_id, topic_hash) in grafts {
+            if !self.peer_manager.has_connection(&peer_id) {
+                continue;
+            }
+            let log = log_context::peer(&self.log, &peer_id);
+            debug!(
+                log_context::topic(&log, topic_hash.as_str()),
+                "Grafting peer opportunistically"
+            );
+            // the peer adds us to its mesh, ours is left to gossipsub
+            let rpc = GossipsubRpc {
+                messages: Vec::new(),
+                subscriptions: Vec::new(),
+                control_msgs: vec![GossipsubControlAction::Graft { topic_hash }],
+            };
+            self.direct_messages.push_back((peer_id, rpc));
+            self.network_globals
+                .opportunistic_grafts
+                .fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records the RPC substreams open on a connection, or that it closed, and publishes the
+    /// peer's total to the network globals.
+    fn update_rpc_substreams(
+        &mut self,
+        peer_id: &PeerId,
+        conn_id: ConnectionId,
+        counts: Option<SubstreamCounts>,
+    ) {
+        let connections = self.rpc_substreams.entry(peer_id.clone()).or_default();
+        match counts {
+            Some(counts) => {
+                connections.insert(conn_id, counts);
+            }
+            None => {
+                connections.remove(&conn_id);
+            }
+        }
+        let total: SubstreamCounts = connections.values().copied().sum();
+        if connections.is_empty() {
+            self.rpc_substreams.remove(peer_id);
+            self.network_globals.rpc_substreams.write().remove(peer_id);
+        } else {
+            self.network_globals
+                .rpc_substreams
+                .write()
+                .insert(peer_id.clone(), total);
+        }
+    }
+
+    /// Applies the `UnsubscribedTopicPolicy` to the topics of a received message. Returns false
+    /// if no topic is left to deliver the message on.
+    fn filter_unsubscribed_topics(
+        &mut self,
+        propagation_source: &PeerId,
+        topics: &mut Vec<TopicHash>,
+    ) -> bool {
+        if self.unsubscribed_topic_policy == UnsubscribedTopicPolicy::Deliver {
+            return true;
+        }
+        let subscribed: HashSet<TopicHash> = self
+            .network_globals
+            .gossipsub_subscriptions
+            .read()
+            .iter()
+            .map(|topic| topic.hash())
+            .collect();
+        let unsubscribed = self.unsubscribed_topic_policy.filter(topics, &subscribed);
+        if unsubscribed.is_empty() {
+            return true;
+        }
+
+        debug!(log_context::peer(&self.log, propagation_source),
+            "Received gossip on unsubscribed topics";
+            "topics" => format!("{:?}", unsubscribed),
+            "policy" => format!("{:?}", self.unsubscribed_topic_policy));
+        if self.unsubscribed_topic_policy == UnsubscribedTopicPolicy::Penalize {
+            self.peer_manager.report_peer(
+                propagation_source,
+                PeerAction::HighToleranceError,
+                ScoreSource::Gossip,
+            );
+        }
+        !topics.is_empty()
+    }
+
+    /// Takes a message of `propagation_source` from the peer's bucket on each topic of the message
+    /// we are subscribed to, returning false if the peer is over its rate on any of them.
+    ///
+    /// The peer is not penalized: gossipsub only hands over the first copy of a message, so the
+    /// rate counts the messages the peer happened to relay first rather than all it sent, and an
+    /// honest peer relaying a burst published by others would be held against it.
+    fn within_rate(
+        &mut self,
+        propagation_source: &PeerId,
+        id: &MessageId,
+        topics: &[TopicHash],
+    ) -> bool {
+        // topics we are not subscribed to get no buckets, so peers cannot grow them at will
+        let subscribed: HashSet<TopicHash> = self
+            .network_globals
+            .gossipsub_subscriptions
+            .read()
+            .iter()
+            .map(|topic| topic.hash())
+            .collect();
+        let now = clock::now();
+        let mut within = true;
+        for topic in topics.iter().filter(|topic| subscribed.contains(topic)) {
+            let check = self
+                .message_rate_limits
+                .check(propagation_source, topic.as_str(), now);
+            if let RateCheck::Limited { first } = check {
+                within = false;
+                if first {
+                    let log = log_context::peer(&self.log, propagation_source);
+                    debug!(log_context::topic(&log, topic.as_str()),
+                        "Dropping gossip over the peer's rate on the topic";
+                        "message_id" => id.to_string());
+                }
+            }
+        }
+        within
+    }
+
+    /// Sets the key of an encrypted topic, encrypting the topic from now on if it was not before.
+    pub fn set_topic_key(&mut self, topic: String, key: TopicKey) {
+        if self.topic_encryption.set_key(topic.clone(), key) {
+            self.message_transforms
+                .register(topic, Arc::new(self.topic_encryption.clone()));
+        }
+    }
+
+    /// Replaces the key of an encrypted topic at once, accepting messages under the old key for
+    /// the grace window. Returns the generation of the new key, or `None` if the topic is not
+    /// encrypted.
+    pub fn rotate_topic_key(&mut self, topic: &str, key: TopicKey) -> Option<u64> {
+        self.topic_encryption
+            .rotate_key(topic, key, self.topic_key_grace)
+    }
+
+    /// Accepts messages under a new key of an encrypted topic, without publishing under it yet.
+    /// Returns the generation of the key, or `None` if the topic is not encrypted.
+    pub fn add_topic_key(&mut self, topic: &str, key: TopicKey) -> Option<u64> {
+        self.topic_encryption.add_key(topic, key)
+    }
+
+    /// Publishes the messages of an encrypted topic under a key added with `add_topic_key`,
+    /// accepting messages under the keys it replaces for the grace window. Returns the generation
+    /// of the key, or `None` if the topic is not encrypted or the key was not added.
+    pub fn activate_topic_key(&mut self, topic: &str, key: &TopicKey) -> Option<u64> {
+        self.topic_encryption
+            .activate_key(topic, key, self.topic_key_grace)
+    }
+
+    /// Re-publishes a received message to the topics bridged from its topic, with its id as the
+    /// origin. A message that was bridged from `origin` itself is not bridged again, so bridges
+    /// cannot loop.
+    fn bridge(&mut self, id: &MessageId, topic: &str, origin: Option<&MessageId>, message: &[u8]) {
+        let bridges = self.topic_bridges.from_topic(topic, origin).to_vec();
+        for bridge in bridges {
+            let to = GossipTopic::new(bridge.to);
+            let data = match &bridge.transform {
+                Some(transform) => match transform(topic, message.to_vec()) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        debug!(log_context::topic(&self.log, topic),
+                            "Could not transform a message to bridge";
+                            "to" => to.as_str(),
+                            "message_id" => id.to_string(),
+                            "error" => e);
+                        self.network_globals
+                            .transform_failures
+                            .fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                },
+                None => message.to_vec(),
+            };
+            // a copy bridged by another node is then not delivered to us after the original
+            self.bridged_copies.put(bridged_copy(id, &data), ());
+            trace!(log_context::topic(&self.log, topic), "Bridging a message";
+                "to" => to.as_str(),
+                "message_id" => id.to_string());
+            self.publish_message(to, data, None, Some(id.clone()));
+        }
+    }
+
+    /// Re-publishes a message received on a fork-scoped topic on the other fork served, with its
+    /// id as the origin. Data already relayed, or received from the other fork, is not relayed
+    /// again.
+    fn relay_between_forks(&mut self, id: &MessageId, topic: &str, message: &[u8]) {
+        let targets =
+            self.fork_relay
+                .targets(&self.forks, &self.fork_scoped_topics, topic, message);
+        for to in targets {
+            trace!(log_context::topic(&self.log, topic), "Relaying a message to another fork";
+                "to" => to.as_str(),
+                "message_id" => id.to_string());
+            self.publish_message(to, message.to_vec(), None, Some(id.clone()));
+        }
+    }
+
+    /// Puts a message published on a target topic of bridges in the bridge envelope.
+    fn seal_outbound(
+        &self,
+        topic: &GossipTopic,
+        origin: Option<&MessageId>,
+        message: Vec<u8>,
+    ) -> Vec<u8> {
+        if self.topic_bridges.is_target(topic.as_str()) {
+            seal_bridged(origin, message)
+        } else {
+            message
+        }
+    }
+
+    /// Applies the transforms of the topic to a message being published, or returns `None` if
+    /// one of them failed and the message must not be sent. Failures are reported with
+    /// `BehaviourEvent::PublishFailed`.
+    fn transform_outbound(&mut self, topic: &GossipTopic, message: Vec<u8>) -> Option<Vec<u8>> {
+        match self.message_transforms.outbound(topic.as_str(), message) {
+            Ok(message) => Some(message),
+            Err(e) => {
+                warn!(log_context::topic(&self.log, topic.as_str()),
+                    "Could not transform a message to publish"; "error" => &e);
+                self.network_globals
+                    .transform_failures
+                    .fetch_add(1, Ordering::Relaxed);
+                self.events.push(BehaviourEvent::PublishFailed {
+                    topic: topic.clone(),
+                    error: e,
+                });
+                None
+            }
         }
     }
 
     /// Queues the response to be sent upwards as long at it was requested outside the Behaviour.
-    fn propagate_response(&mut self, id: RequestId, peer_id: PeerId, response: Response) {
+    fn propagate_response(
+        &mut self,
+        id: RequestId,
+        peer_id: PeerId,
+        response: Response,
+        protocol_id: ProtocolId,
+    ) {
         if !matches!(id, RequestId::Behaviour) {
             self.events.push(BehaviourEvent::ResponseReceived {
                 peer_id,
                 id,
                 response,
+                protocol_id,
             });
         }
     }
 
     /// Convenience function to propagate a request.
-    fn propagate_request(&mut self, id: PeerRequestId, peer_id: PeerId, request: Request) {
+    fn propagate_request(
+        &mut self,
+        id: PeerRequestId,
+        peer_id: PeerId,
+        request: Request,
+        protocol_id: ProtocolId,
+    ) {
         self.events.push(BehaviourEvent::RequestReceived {
             peer_id,
             id,
             request,
+            protocol_id,
         });
     }
 
@@ -481,15 +2276,25 @@ impl Behaviour {
         // The METADATA and PING RPC responses are handled within the behaviour and not propagated
         match message.event {
             Err(handler_err) => {
+                Stats::increment(&self.network_globals.stats.rpc_errors);
                 match handler_err {
-                    HandlerErr::Inbound {
-                        id: _,
-                        proto,
-                        error,
-                    } => {
-                        if matches!(error, RPCError::HandlerRejected) {
-                            // this peer's request got canceled
-                            // TODO: cancel processing for this request
+                    HandlerErr::Inbound { id, proto, error } => {
+                        if matches!(error, RPCError::HandlerRejected | RPCError::StreamTimeout) {
+                            // this peer's request got cancelled before we responded, any response
+                            // to it is dropped
+                            self.network_globals
+                                .peers
+                                .write()
+                                .request_cancelled(&peer_id, proto, &error);
+                            // only Status and custom requests are propagated to the network
+                            if matches!(proto, Protocol::Status | Protocol::Custom(_)) {
+                                self.events.push(BehaviourEvent::RequestCancelled {
+                                    peer_id: peer_id.clone(),
+                                    id: (handler_id, id),
+                                    protocol: proto,
+                                    error: error.clone(),
+                                });
+                            }
                         }
                         // Inform the peer manager of the error.
                         // An inbound error here means we sent an error to the peer, or the stream
@@ -507,18 +2312,26 @@ impl Behaviour {
                     }
                 }
             }
-            Ok(RPCReceived::Request(id, request)) => {
+            Ok(RPCReceived::Request(id, request, protocol_id)) => {
+                Stats::increment(&self.network_globals.stats.rpc_requests_received);
                 let peer_request_id = (handler_id, id);
                 match request {
                     /* Behaviour managed protocols: Ping and Metadata */
                     RPCRequest::Ping(ping) => {
                         // inform the peer manager and send the response
                         debug!(
-                            self.log,
-                            "Behaviour RPCRequest::Ping received from: {:?}", peer_id
+                            log_context::rpc(&self.log, &peer_id, Protocol::Ping, id),
+                            "Received Ping request"
                         );
-                        //TODO: JR - peer manager won't be properly updated until i serialize externally
-                        //self.peer_manager.ping_request(&peer_id, ping.data);
+                        // the peer manager is only updated when the application can read the
+                        // payload
+                        if let Some(seq) = self
+                            .ping_handler
+                            .as_ref()
+                            .and_then(|handler| handler.received(&peer_id, &ping))
+                        {
+                            self.peer_manager.ping_request(&peer_id, seq);
+                        }
                         // send a ping response
                         self.pong(peer_request_id, peer_id);
                     }
@@ -527,12 +2340,21 @@ impl Behaviour {
                         self.send_meta_data_response((handler_id, id), peer_id);
                         // TODO: inform the peer manager?
                     }
+                    RPCRequest::Topics(topics) => {
+                        self.on_peer_topics(&peer_id, &topics);
+                        self.send_topics_response(peer_request_id, peer_id);
+                    }
+                    RPCRequest::Rendezvous(message) => {
+                        self.on_rendezvous_request(peer_request_id, peer_id, &message);
+                    }
                     RPCRequest::Goodbye(reason) => {
                         // let the peer manager know this peer is in the process of disconnecting
                         self.peer_manager._disconnecting_peer(&peer_id);
                         // queue for disconnection without a goodbye message
-                        debug!(self.log, "Behaviour received a Goodbye, queueing for disconnection";
-                            "peer_id" => peer_id.to_string());
+                        debug!(
+                            log_context::rpc(&self.log, &peer_id, Protocol::Goodbye, id),
+                            "Received Goodbye, queueing for disconnection"
+                        );
                         self.peers_to_dc.push(peer_id.clone());
                         // TODO: do not propagate (Age comment)
                         //TODO: JR raise event to decode before calling propagate_request
@@ -541,46 +2363,160 @@ impl Behaviour {
                     /* Protocols propagated to the Network */
                     RPCRequest::Status(msg) => {
                         debug!(
-                            self.log,
-                            "Behaviour RPCRequest::Status received from: {:?}", peer_id
+                            log_context::rpc(&self.log, &peer_id, Protocol::Status, id),
+                            "Received Status request"
                         );
                         // inform the peer manager that we have received a status from a peer
                         self.peer_manager.peer_statusd(&peer_id);
-                        // propagate the STATUS message upwards
-                        self.propagate_request(peer_request_id, peer_id, Request::Status(msg))
+                        // a malformed status is answered with an error, and a peer on another
+                        // network is sent a Goodbye by the peer manager
+                        match self.peer_manager.status_received(&peer_id, &msg) {
+                            StatusCheck::Accepted => {}
+                            StatusCheck::Malformed => {
+                                let response =
+                                    RPCCodedResponse::InvalidRequest("Malformed Status".into());
+                                Stats::increment(&self.network_globals.stats.rpc_responses_sent);
+                                self.mothra_rpc
+                                    .send_response(peer_id, peer_request_id, response);
+                                return;
+                            }
+                            StatusCheck::Irrelevant => return,
+                        }
+                        // a peer is answered with the status of its fork, when we serve two
+                        let local_status = if self.auto_status {
+                            match StatusMessage::decode(&msg) {
+                                Some(status) => {
+                                    self.network_globals.local_status_on(&status.fork_digest)
+                                }
+                                None => self.network_globals.local_status(),
+                            }
+                        } else {
+                            None
+                        };
+                        if let Some(status) = local_status {
+                            self.send_status_response(peer_request_id, peer_id.clone(), &status);
+                            self.events.push(BehaviourEvent::StatusAnswered {
+                                peer_id,
+                                status: msg,
+                            });
+                        } else {
+                            // propagate the STATUS message upwards
+                            self.propagate_request(
+                                peer_request_id,
+                                peer_id,
+                                Request::Status(msg),
+                                protocol_id,
+                            )
+                        }
+                    }
+                    RPCRequest::Custom(custom, data) => {
+                        debug!(
+                            log_context::rpc(&self.log, &peer_id, Protocol::Custom(custom), id),
+                            "Received custom request"
+                        );
+                        self.propagate_request(
+                            peer_request_id,
+                            peer_id,
+                            Request::Custom(custom.name().to_string(), data),
+                            protocol_id,
+                        )
                     }
                     _ => (),
                 }
             }
-            Ok(RPCReceived::Response(id, resp)) => {
+            Ok(RPCReceived::EndOfStream(id, protocol_id)) => {
+                debug!(
+                    log_context::rpc(&self.log, &peer_id, protocol_id.message_name, id),
+                    "Response stream ended"
+                );
+                self.propagate_response(id, peer_id, Response::EndOfStream, protocol_id);
+            }
+            Ok(RPCReceived::Substreams {
+                counts,
+                force_closed,
+            }) => {
+                if force_closed > 0 {
+                    self.network_globals
+                        .force_closed_substreams
+                        .fetch_add(force_closed, Ordering::Relaxed);
+                }
+                self.update_rpc_substreams(&peer_id, handler_id, Some(counts));
+            }
+            Ok(RPCReceived::Response(id, resp, protocol_id)) => {
+                Stats::increment(&self.network_globals.stats.rpc_responses_received);
                 match resp {
                     /* Behaviour managed protocols */
                     RPCResponse::Pong(ping) => {
                         debug!(
-                            self.log,
-                            "Behaviour RPCResponse::Pong received from: {:?}", peer_id
+                            log_context::rpc(&self.log, &peer_id, Protocol::Ping, id),
+                            "Received Pong"
                         );
-                        //TODO: JR - raise event to decode
-                        //self.peer_manager.pong_response(&peer_id, ping.data)
+                        if let Some(seq) = self
+                            .ping_handler
+                            .as_ref()
+                            .and_then(|handler| handler.received(&peer_id, &ping))
+                        {
+                            self.peer_manager.pong_response(&peer_id, seq);
+                        }
+                        // only pings requested outside the behaviour are propagated
+                        if !matches!(id, RequestId::Behaviour) {
+                            self.events.push(BehaviourEvent::PongReceived {
+                                peer_id,
+                                id,
+                                data: ping,
+                            });
+                        }
                     }
                     RPCResponse::MetaData(meta_data) => {
                         debug!(
-                            self.log,
-                            "Behaviour RPCResponse::MetaData received from: {:?}", peer_id
+                            log_context::rpc(&self.log, &peer_id, Protocol::MetaData, id),
+                            "Received MetaData response"
                         );
-                        //self.peer_manager.meta_data_response(&peer_id, meta_data)
+                        self.peer_manager.meta_data_received(&peer_id, &meta_data);
+                    }
+                    RPCResponse::Topics(topics) => {
+                        self.on_peer_topics(&peer_id, &topics);
+                    }
+                    RPCResponse::Rendezvous(message) => {
+                        self.on_rendezvous_response(&peer_id, &message);
                     }
                     /* Network propagated protocols */
                     RPCResponse::Status(msg) => {
                         debug!(
-                            self.log,
-                            "Behaviour RPCResponse::Status received from: {:?}", peer_id
+                            log_context::rpc(&self.log, &peer_id, Protocol::Status, id),
+                            "Received Status response"
                         );
                         // inform the peer manager that we have received a status from a peer
                         self.peer_manager.peer_statusd(&peer_id);
-                        // propagate the STATUS message upwards
-                        //TODO: JR- raise event to decode
-                        self.propagate_response(id, peer_id, Response::Status(msg));
+                        // propagate the STATUS message upwards, or fail the request if the peer
+                        // manager rejects it
+                        let check = self.peer_manager.status_received(&peer_id, &msg);
+                        if check == StatusCheck::Accepted {
+                            self.propagate_response(
+                                id,
+                                peer_id,
+                                Response::Status(msg),
+                                protocol_id,
+                            );
+                        } else if !matches!(id, RequestId::Behaviour) {
+                            self.events.push(BehaviourEvent::RPCFailed {
+                                id,
+                                peer_id,
+                                error: RPCError::InvalidData,
+                            });
+                        }
+                    }
+                    RPCResponse::Custom(data) => {
+                        debug!(
+                            log_context::rpc(&self.log, &peer_id, protocol_id.message_name, id),
+                            "Received custom response"
+                        );
+                        let response = if protocol_id.message_name.is_chunked() {
+                            Response::Chunk(data)
+                        } else {
+                            Response::Custom(data)
+                        };
+                        self.propagate_response(id, peer_id, response, protocol_id);
                     }
                     _ => (),
                 }
@@ -588,11 +2524,47 @@ impl Behaviour {
         }
     }
 
+    /// Returns the approximate bytes of the events and messages waiting in the behaviour.
+    fn queued_bytes(&self) -> usize {
+        let direct_messages = self
+            .direct_messages
+            .iter()
+            .map(|(_, rpc)| {
+                let data = rpc.messages.iter().map(|message| message.data.len());
+                size_of::<(PeerId, GossipsubRpc)>()
+                    + rpc.messages.len() * size_of::<GossipsubMessage>()
+                    + data.sum::<usize>()
+            })
+            .sum::<usize>();
+        self.events.len() * size_of::<BehaviourEvent>()
+            + self.peers_to_dc.len() * size_of::<PeerId>()
+            + self.mesh_membership_updates.len() * size_of::<(PeerId, NotifyHandler, bool)>()
+            + direct_messages
+            + self.outbound_buffer.bytes()
+    }
+
     /// Consumes the events list when polled.
     fn custom_poll(
         &mut self,
         cx: &mut Context,
     ) -> Poll<NBAction<BehaviourHandlerIn, BehaviourEvent>> {
+        let depths = &self.network_globals.queue_depths;
+        QueueDepths::set(&depths.behaviour_events, self.events.len());
+        QueueDepths::set(&depths.peers_to_dc, self.peers_to_dc.len());
+        QueueDepths::set(&depths.rpc_events, self.mothra_rpc.queued_events());
+        let usage = &self.network_globals.memory_usage;
+        let ids = self.direct_message_ids.len() + self.received_message_ids.len();
+        let id_entry = size_of::<MessageId>() + LRU_ENTRY_OVERHEAD;
+        let bridged_copies = self.bridged_copies.len()
+            * (size_of::<BridgedCopy>() + LRU_ENTRY_OVERHEAD)
+            + self.fork_relay.seen() * (size_of::<[u8; 32]>() + LRU_ENTRY_OVERHEAD);
+        MemoryUsage::set(
+            &usage.seen_message_ids,
+            self.message_id_sizes.estimate(ids, id_entry) + bridged_copies,
+        );
+        MemoryUsage::set(&usage.mcache, self.mcache.bytes(clock::now()));
+        MemoryUsage::set(&usage.behaviour_queues, self.queued_bytes());
+
         // handle pending disconnections to perform
         if !self.peers_to_dc.is_empty() {
             return Poll::Ready(NBAction::NotifyHandler {
@@ -602,16 +2574,87 @@ impl Behaviour {
             });
         }
 
+        // check the meshes for poorly reputed peers
+        while let Some(Poll::Ready(Some(_))) = self
+            .opportunistic_graft_interval
+            .as_mut()
+            .map(|interval| interval.poll_next_unpin(cx))
+        {
+            self.graft_opportunistically();
+        }
+
+        // drop the buffered messages past their deadline
+        while let Some(Poll::Ready(Some(_))) = self
+            .publish_expiry_interval
+            .as_mut()
+            .map(|interval| interval.poll_next_unpin(cx))
+        {
+            for message in self.outbound_buffer.expire(clock::now()) {
+                self.drop_buffered(message);
+            }
+        }
+
+        // forward the messages that waited for the forwarding budgets
+        while let Some(Poll::Ready(Some(_))) = self
+            .forwarding_interval
+            .as_mut()
+            .map(|interval| interval.poll_next_unpin(cx))
+        {
+            for (propagation_source, message_id) in self.forwarding.refill() {
+                self.gossipsub
+                    .propagate_message(&message_id, &propagation_source);
+            }
+        }
+
+        // refresh our registrations at the rendezvous points before they expire
+        while let Some(Poll::Ready(Some(_))) = self
+            .rendezvous_interval
+            .as_mut()
+            .map(|interval| interval.poll_next_unpin(cx))
+        {
+            for peer_id in self.peer_manager.connected_rendezvous_points() {
+                self.send_rendezvous_requests(&peer_id);
+            }
+        }
+
+        // tell handlers whether their peer is in one of our meshes
+        if let Some((peer_id, handler, in_mesh)) = self.mesh_membership_updates.pop_front() {
+            return Poll::Ready(NBAction::NotifyHandler {
+                peer_id,
+                handler,
+                event: BehaviourHandlerIn::MeshMembership(in_mesh),
+            });
+        }
+
+        // send the messages published directly to peers
+        if let Some((peer_id, rpc)) = self.direct_messages.pop_front() {
+            self.observe_control(&peer_id, &rpc.control_msgs, true);
+            return Poll::Ready(NBAction::NotifyHandler {
+                peer_id,
+                handler: NotifyHandler::Any,
+                event: BehaviourHandlerIn::Delegate(DelegateIn::Gossipsub(rpc)),
+            });
+        }
+
         // check the peer manager for events
         loop {
             match self.peer_manager.poll_next_unpin(cx) {
                 Poll::Ready(Some(event)) => match event {
+                    PeerManagerEvent::Dial(peer_id) if peer_id == self.local_peer_id => {
+                        warn!(self.log, "Refusing to dial our own peer id");
+                        self.network_globals
+                            .self_dials_rejected
+                            .fetch_add(1, Ordering::Relaxed);
+                    }
                     PeerManagerEvent::Dial(peer_id) => {
                         return Poll::Ready(NBAction::DialPeer {
                             peer_id,
                             condition: libp2p::swarm::DialPeerCondition::Disconnected,
                         });
                     }
+                    PeerManagerEvent::DialAddress(address) => {
+                        return Poll::Ready(NBAction::DialAddress { address });
+                    }
                     PeerManagerEvent::SocketUpdated(address) => {
                         return Poll::Ready(NBAction::ReportObservedAddr { address });
                     }
@@ -630,21 +2673,38 @@ impl Behaviour {
                         self.send_meta_data_request(peer_id);
                     }
                     PeerManagerEvent::DisconnectPeer(peer_id) => {
-                        debug!(self.log, "PeerManager requested to disconnect a peer";
-                            "peer_id" => peer_id.to_string());
-                        // queue for disabling
-                        self.peers_to_dc.push(peer_id.clone());
-                        // send one goodbye
+                        debug!(
+                            log_context::peer(&self.log, &peer_id),
+                            "PeerManager requested to disconnect a peer"
+                        );
+                        return Poll::Ready(self.goodbye(peer_id, vec![]));
+                    }
+                    PeerManagerEvent::Goodbye(peer_id, reason) => {
+                        debug!(
+                            log_context::peer(&self.log, &peer_id),
+                            "PeerManager requested to say goodbye to a peer";
+                            "reason" => reason.to_string()
+                        );
+                        let reason: u64 = reason.into();
+                        return Poll::Ready(self.goodbye(peer_id, reason.to_le_bytes().to_vec()));
+                    }
+                    PeerManagerEvent::CloseConnection(peer_id, conn_id) => {
                         return Poll::Ready(NBAction::NotifyHandler {
                             peer_id,
-                            handler: NotifyHandler::Any,
-                            event: BehaviourHandlerIn::Shutdown(Some((
-                                RequestId::Behaviour,
-                                RPCRequest::Goodbye(vec![]),
-                                //RPCRequest::Goodbye(GoodbyeReason::Fault),
-                            ))),
+                            handler: NotifyHandler::One(conn_id),
+                            event: BehaviourHandlerIn::Shutdown(None),
                         });
                     }
+                    PeerManagerEvent::MatchingPeers(enrs) => {
+                        return Poll::Ready(NBAction::GenerateEvent(
+                            BehaviourEvent::MatchingPeersDiscovered(enrs),
+                        ));
+                    }
+                    PeerManagerEvent::PeersDiscovered(enrs) => {
+                        return Poll::Ready(NBAction::GenerateEvent(
+                            BehaviourEvent::PeersDiscovered(enrs),
+                        ));
+                    }
                 },
                 Poll::Pending => break,
                 Poll::Ready(None) => break, // peer manager ended
@@ -665,28 +2725,68 @@ impl Behaviour {
                 mut info,
                 observed_addr,
             } => {
-                if info.listen_addrs.len() > MAX_IDENTIFY_ADDRESSES {
-                    debug!(
-                        self.log,
-                        "More than 10 addresses have been identified, truncating"
-                    );
-                    info.listen_addrs.truncate(MAX_IDENTIFY_ADDRESSES);
+                // private addresses are only useful to peers on the same private network
+                let local_is_public = self.network_globals.local_enr().ip().map_or(false, |ip| {
+                    !ip.is_unspecified() && !address_filter::is_private(&ip.into())
+                });
+                let advertised = info.listen_addrs.len();
+                let dropped = address_filter::filter_addresses(
+                    &mut info.listen_addrs,
+                    self.max_identify_addresses,
+                    self.allow_private_addresses || !local_is_public,
+                );
+                if dropped > 0 || advertised - dropped > self.max_identify_addresses {
+                    debug!(log_context::peer(&self.log, &peer_id), "Filtered identified addresses";
+                        "advertised" => advertised,
+                        "bogus" => dropped,
+                        "kept" => info.listen_addrs.len());
                 }
                 // send peer info to the peer manager.
                 self.peer_manager.identify(&peer_id, &info);
+                // peers that dialed us only get into the DHT routing table once their listen
+                // addresses are known
+                #[cfg(feature = "kad")]
+                {
+                    if info
+                        .protocols
+                        .iter()
+                        .any(|protocol| protocol.as_bytes() == kad::KAD_PROTOCOL_NAME)
+                    {
+                        for address in &info.listen_addrs {
+                            self.kademlia.add_address(&peer_id, address.clone());
+                        }
+                    }
+                }
 
-                debug!(self.log, "Identified Peer"; "peer" => format!("{}", peer_id),
-                "protocol_version" => info.protocol_version,
-                "agent_version" => info.agent_version,
-                "listening_ addresses" => format!("{:?}", info.listen_addrs),
-                "observed_address" => format!("{:?}", observed_addr),
-                "protocols" => format!("{:?}", info.protocols)
-                );
+                self.events.push(BehaviourEvent::PeerIdentified {
+                    peer_id: peer_id.clone(),
+                    agent_version: info.agent_version.clone(),
+                    protocol_version: info.protocol_version.clone(),
+                    protocols: info.protocols.clone(),
+                });
+
+                debug!(log_context::peer(&self.log, &peer_id), "Identified Peer";
+                    "protocol_version" => info.protocol_version,
+                    "agent_version" => info.agent_version,
+                    "listen_addresses" => format!("{:?}", info.listen_addrs),
+                    "observed_address" => format!("{:?}", observed_addr),
+                    "protocols" => format!("{:?}", info.protocols));
             }
             IdentifyEvent::Sent { .. } => {}
             IdentifyEvent::Error { .. } => {}
         }
     }
+
+    #[cfg(feature = "kad")]
+    fn on_kad_event(&mut self, event: KademliaEvent) {
+        if let KademliaEvent::RoutingUpdated { peer, .. } = &event {
+            trace!(
+                log_context::peer(&self.log, peer),
+                "DHT routing table updated"
+            );
+        }
+        self.kad_queries.on_event(event);
+    }
 }
 
 /* Public API types */
@@ -702,15 +2802,8 @@ pub enum Request {
     Status(Vec<u8>),
     /// A Goobye message.
     Goodbye(Vec<u8>),
-}
-
-impl std::convert::From<Request> for RPCRequest {
-    fn from(req: Request) -> RPCRequest {
-        match req {
-            Request::Goodbye(r) => RPCRequest::Goodbye(r),
-            Request::Status(s) => RPCRequest::Status(s),
-        }
-    }
+    /// A request of a protocol registered in `NetworkConfig::custom_protocols`, by name.
+    Custom(String, Vec<u8>),
 }
 
 /// The type of RPC responses the Behaviour informs it has received, and allows for sending.
@@ -723,6 +2816,12 @@ impl std::convert::From<Request> for RPCRequest {
 pub enum Response {
     /// A Status message.
     Status(Vec<u8>),
+    /// A response of a protocol registered in `NetworkConfig::custom_protocols`.
+    Custom(Vec<u8>),
+    /// A chunk of a response of a custom protocol answering with several chunks.
+    Chunk(Vec<u8>),
+    /// The end of a response of several chunks. Sending it closes the stream.
+    EndOfStream,
 }
 
 //TODO: not sure yet
@@ -730,6 +2829,10 @@ impl std::convert::From<Response> for RPCCodedResponse {
     fn from(resp: Response) -> RPCCodedResponse {
         match resp {
             Response::Status(s) => RPCCodedResponse::Success(RPCResponse::Status(s)),
+            Response::Custom(c) | Response::Chunk(c) => {
+                RPCCodedResponse::Success(RPCResponse::Custom(c))
+            }
+            Response::EndOfStream => RPCCodedResponse::StreamTermination,
         }
     }
 }
@@ -756,6 +2859,20 @@ pub enum BehaviourEvent {
         id: PeerRequestId,
         /// Request the peer sent.
         request: Request,
+        /// The protocol the request was negotiated with, giving its version and encoding.
+        protocol_id: ProtocolId,
+    },
+    /// A request that was propagated with `RequestReceived` was cancelled before it was
+    /// responded to. Any response to it is dropped.
+    RequestCancelled {
+        /// The peer that sent the request.
+        peer_id: PeerId,
+        /// Identifier of the cancelled request.
+        id: PeerRequestId,
+        /// The protocol of the cancelled request.
+        protocol: Protocol,
+        /// Why the request was cancelled.
+        error: RPCError,
     },
     ResponseReceived {
         /// Peer that sent the response.
@@ -764,6 +2881,8 @@ pub enum BehaviourEvent {
         id: RequestId,
         /// Response the peer sent.
         response: Response,
+        /// The protocol the request was negotiated with, giving its version and encoding.
+        protocol_id: ProtocolId,
     },
     PubsubMessage {
         /// The gossipsub message id. Used when propagating blocks after validation.
@@ -774,6 +2893,8 @@ pub enum BehaviourEvent {
         agent_string: String,
         /// The peer from which we received this message, not the peer that published it.
         source: PeerId,
+        /// The peer that published this message, as claimed by the message.
+        publisher: PeerId,
         /// The topics that this message was sent on.
         topics: Vec<TopicHash>,
         /// The message itself.
@@ -781,6 +2902,61 @@ pub enum BehaviourEvent {
     },
     /// Subscribed to peer for given topic
     PeerSubscribed(PeerId, TopicHash),
+    /// A published message was dropped before any peer subscribed to its topic, because its
+    /// deadline passed or the publish buffer was full.
+    PublishExpired {
+        /// The topic the message was published on.
+        topic: GossipTopic,
+        /// The message as it was published, before any topic transforms.
+        message: Vec<u8>,
+    },
+    /// A message could not be published, because a transform of its topic failed on it, it was
+    /// too large for its topic, or we are not subscribed to its topic.
+    PublishFailed {
+        /// The topic the message was published on.
+        topic: GossipTopic,
+        /// Why the publish failed.
+        error: String,
+    },
+    /// The mesh of a subscribed topic reached a healthy size for the first time since we
+    /// subscribed to it.
+    TopicReady(GossipTopic),
+    /// Gossip was received over the node's rate in the last second, and the messages of the least
+    /// important topics were dropped.
+    Overloaded(Overload),
     /// Inform the network to send a Status to this peer.
     StatusPeer(PeerId),
+    /// A Status request was answered with `NetworkGlobals::local_status`, without being
+    /// propagated.
+    StatusAnswered {
+        /// The peer that sent the request.
+        peer_id: PeerId,
+        /// The status the peer sent, as received.
+        status: Vec<u8>,
+    },
+    /// A peer answered a Ping that was sent from outside the behaviour.
+    PongReceived {
+        /// The peer that sent the pong.
+        peer_id: PeerId,
+        /// Id of the ping request.
+        id: RequestId,
+        /// The ping data returned by the peer.
+        data: Vec<u8>,
+    },
+    /// A peer has been identified via the identify protocol.
+    PeerIdentified {
+        /// The identified peer.
+        peer_id: PeerId,
+        /// The agent version the peer advertised.
+        agent_version: String,
+        /// The protocol version the peer advertised.
+        protocol_version: String,
+        /// The protocols the peer supports.
+        protocols: Vec<String>,
+    },
+    /// Peers matching the predicate of a `discover_matching_peers` search were found.
+    MatchingPeersDiscovered(Vec<Enr>),
+    /// Peers were found by a discovery query. Peers are found again by later queries as long as
+    /// they stay in the routing table.
+    PeersDiscovered(Vec<Enr>),
 }
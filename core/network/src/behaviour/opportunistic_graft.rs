@@ -0,0 +1,84 @@
+//! The peers asked into a poorly reputed mesh.
+//!
+//! When the median reputation of a topic's mesh falls below a threshold, the best reputed peers
+//! on the topic outside of the mesh are sent a GRAFT. This version of gossipsub cannot be told to
+//! add a peer to its own mesh, so the graft only adds this node to the meshes of those peers: they
+//! then send us the topic's messages in full, while our own mesh, and so where the messages we
+//! publish and forward go, is still chosen by gossipsub.
+use crate::peer_manager::Rep;
+use crate::PeerId;
+use std::collections::HashSet;
+
+/// Returns up to `count` peers of `topic_peers` to graft into `mesh`, the best reputed first, if
+/// the median reputation of the mesh is below `threshold`. Only peers better reputed than the
+/// median and for which `allowed` holds are chosen.
+pub fn candidates<'a>(
+    mesh: &HashSet<PeerId>,
+    topic_peers: impl IntoIterator<Item = &'a PeerId>,
+    reputation: impl Fn(&PeerId) -> Rep,
+    allowed: impl Fn(&PeerId) -> bool,
+    threshold: Rep,
+    count: usize,
+) -> Vec<PeerId> {
+    let mut reputations: Vec<Rep> = mesh.iter().map(|peer_id| reputation(peer_id)).collect();
+    reputations.sort_unstable();
+    let median = match reputations.get(reputations.len() / 2) {
+        Some(median) if *median < threshold => *median,
+        _ => return Vec::new(),
+    };
+    let mut candidates: Vec<(Rep, &PeerId)> = topic_peers
+        .into_iter()
+        .filter(|peer_id| !mesh.contains(*peer_id) && allowed(*peer_id))
+        .map(|peer_id| (reputation(peer_id), peer_id))
+        .filter(|(rep, _)| *rep > median)
+        .collect();
+    candidates.sort_by(|a, b| b.0.cmp(&a.0));
+    candidates
+        .into_iter()
+        .take(count)
+        .map(|(_, peer_id)| peer_id.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_candidates() {
+        let peers: Vec<PeerId> = (0..6).map(|_| PeerId::random()).collect();
+        let reputations: HashMap<PeerId, Rep> = peers
+            .iter()
+            .cloned()
+            .zip(vec![10, 20, 30, 40, 50, 60])
+            .collect();
+        let reputation = |peer_id: &PeerId| reputations[peer_id];
+        let mesh: HashSet<PeerId> = peers[..3].iter().cloned().collect();
+
+        // the median of the mesh is 20, so the best reputed outsiders are chosen first
+        assert_eq!(
+            candidates(&mesh, &peers, reputation, |_| true, 25, 2),
+            vec![peers[5].clone(), peers[4].clone()]
+        );
+        // peers that may not be grafted are skipped
+        assert_eq!(
+            candidates(&mesh, &peers, reputation, |p| *p != peers[5], 25, 2),
+            vec![peers[4].clone(), peers[3].clone()]
+        );
+        // a well reputed mesh is left alone
+        assert!(candidates(&mesh, &peers, reputation, |_| true, 20, 2).is_empty());
+        // as is an empty one, which gossipsub fills itself
+        assert!(candidates(&HashSet::new(), &peers, reputation, |_| true, 25, 2).is_empty());
+    }
+
+    #[test]
+    fn test_only_better_peers() {
+        let peers: Vec<PeerId> = (0..3).map(|_| PeerId::random()).collect();
+        let reputations: HashMap<PeerId, Rep> =
+            peers.iter().cloned().zip(vec![10, 10, 5]).collect();
+        let reputation = |peer_id: &PeerId| reputations[peer_id];
+        let mesh: HashSet<PeerId> = peers[..1].iter().cloned().collect();
+        assert!(candidates(&mesh, &peers, reputation, |_| true, 50, 2).is_empty());
+    }
+}
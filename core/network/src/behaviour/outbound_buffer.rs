@@ -0,0 +1,137 @@
+//! Messages published before any peer is subscribed to their topic.
+//!
+//! Gossipsub drops a message published on a topic none of our peers are subscribed to. Such
+//! messages are held here instead, and published once a peer subscribes to the topic. A message
+//! may be given a deadline: time-sensitive payloads are better not published at all than
+//! published late, so a message still held at its deadline is dropped and handed back to the
+//! application.
+use crate::{GossipTopic, MessageId, TopicHash};
+use std::collections::VecDeque;
+use std::time::Instant;
+
+/// A message waiting for a peer to subscribe to its topic.
+#[derive(Debug, PartialEq)]
+pub struct BufferedMessage {
+    pub topic: GossipTopic,
+    pub data: Vec<u8>,
+    /// The time after which the message is no longer published.
+    pub deadline: Option<Instant>,
+    /// The message this one was bridged from, if it was bridged.
+    pub origin: Option<MessageId>,
+}
+
+impl BufferedMessage {
+    fn expired(&self, now: Instant) -> bool {
+        self.deadline.map_or(false, |deadline| deadline <= now)
+    }
+}
+
+/// The messages waiting for subscribers, oldest first.
+pub struct OutboundBuffer {
+    capacity: usize,
+    messages: VecDeque<BufferedMessage>,
+}
+
+impl OutboundBuffer {
+    /// Creates a buffer holding up to `capacity` messages. With no capacity, nothing is held.
+    pub fn new(capacity: usize) -> Self {
+        OutboundBuffer {
+            capacity,
+            messages: VecDeque::new(),
+        }
+    }
+
+    /// Returns true if messages can be held.
+    pub fn enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Returns the approximate bytes of the messages held.
+    pub fn bytes(&self) -> usize {
+        self.messages
+            .iter()
+            .map(|message| std::mem::size_of::<BufferedMessage>() + message.data.len())
+            .sum()
+    }
+
+    /// Holds a message. If the buffer is full the oldest message is dropped and returned.
+    pub fn push(&mut self, message: BufferedMessage) -> Option<BufferedMessage> {
+        let dropped = if self.messages.len() >= self.capacity {
+            self.messages.pop_front()
+        } else {
+            None
+        };
+        self.messages.push_back(message);
+        dropped
+    }
+
+    /// Takes the messages held for a topic, in the order they were published.
+    pub fn take(&mut self, topic: &TopicHash) -> Vec<BufferedMessage> {
+        let (taken, kept): (VecDeque<_>, VecDeque<_>) = self
+            .messages
+            .drain(..)
+            .partition(|message| &message.topic.hash() == topic);
+        self.messages = kept;
+        taken.into()
+    }
+
+    /// Takes the messages whose deadline has passed.
+    pub fn expire(&mut self, now: Instant) -> Vec<BufferedMessage> {
+        if !self.messages.iter().any(|message| message.expired(now)) {
+            return Vec::new();
+        }
+        let (expired, kept): (VecDeque<_>, VecDeque<_>) = self
+            .messages
+            .drain(..)
+            .partition(|message| message.expired(now));
+        self.messages = kept;
+        expired.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn message(topic: &str, data: u8, deadline: Option<Instant>) -> BufferedMessage {
+        BufferedMessage {
+            topic: GossipTopic::new(topic.to_string()),
+            data: vec![data],
+            deadline,
+            origin: None,
+        }
+    }
+
+    #[test]
+    fn test_take_and_expire() {
+        let now = Instant::now();
+        let mut buffer = OutboundBuffer::new(3);
+        assert!(buffer.enabled());
+        assert!(!OutboundBuffer::new(0).enabled());
+
+        assert_eq!(buffer.push(message("a", 1, None)), None);
+        assert_eq!(
+            buffer.push(message("b", 2, Some(now + Duration::from_secs(1)))),
+            None
+        );
+        assert_eq!(buffer.push(message("a", 3, Some(now))), None);
+        // the oldest message makes room
+        assert_eq!(
+            buffer.push(message("b", 4, None)),
+            Some(message("a", 1, None))
+        );
+
+        assert_eq!(buffer.expire(now), vec![message("a", 3, Some(now))]);
+        assert_eq!(buffer.expire(now), vec![]);
+
+        let topic = GossipTopic::new("b".to_string()).hash();
+        let taken: Vec<u8> = buffer
+            .take(&topic)
+            .into_iter()
+            .map(|message| message.data[0])
+            .collect();
+        assert_eq!(taken, vec![2, 4]);
+        assert_eq!(buffer.take(&topic), vec![]);
+    }
+}
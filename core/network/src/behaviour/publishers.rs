@@ -0,0 +1,59 @@
+//! The peers allowed to send messages on restricted topics.
+//!
+//! This version of gossipsub neither signs messages nor fills in their source, so who published a
+//! message cannot be told. A topic's allowlist is instead checked against the peer the message was
+//! received from: on a restricted topic, only messages sent to us directly by an allowed peer are
+//! accepted. A message relayed by any other peer is dropped, even if an allowed peer published it,
+//! so the allowlist must name the peers trusted to relay as well as the publishers, or the
+//! publishers must be connected to every node that restricts the topic.
+use crate::TopicHash;
+use libp2p::PeerId;
+use std::collections::{HashMap, HashSet};
+
+/// The peers allowed to send messages on each restricted topic.
+#[derive(Debug, Default)]
+pub struct TopicPublishers {
+    allowed: HashMap<String, HashSet<PeerId>>,
+}
+
+impl TopicPublishers {
+    pub fn new(allowed: HashMap<String, HashSet<PeerId>>) -> Self {
+        TopicPublishers { allowed }
+    }
+
+    /// Returns true if a message on `topics` received from `propagation_source` is accepted on
+    /// every restricted topic among them.
+    pub fn allows(&self, propagation_source: &PeerId, topics: &[TopicHash]) -> bool {
+        topics.iter().all(|topic| {
+            self.allowed
+                .get(topic.as_str())
+                .map_or(true, |allowed| allowed.contains(propagation_source))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allows_only_listed_peers_on_restricted_topics() {
+        let publisher = PeerId::random();
+        let other = PeerId::random();
+        let mut allowed = HashMap::new();
+        allowed.insert(
+            "/mothra/control".to_string(),
+            vec![publisher.clone()].into_iter().collect(),
+        );
+        let publishers = TopicPublishers::new(allowed);
+
+        let control = TopicHash::from_raw("/mothra/control");
+        let data = TopicHash::from_raw("/mothra/data");
+        assert!(publishers.allows(&publisher, &[control.clone()]));
+        assert!(!publishers.allows(&other, &[control.clone()]));
+        // unrestricted topics accept anyone
+        assert!(publishers.allows(&other, &[data.clone()]));
+        // a message on several topics must be allowed on each
+        assert!(!publishers.allows(&other, &[data, control]));
+    }
+}
@@ -0,0 +1,142 @@
+//! Limits on the rate of gossip each peer sends on each topic.
+//!
+//! Gossipsub accepts any number of valid messages from a peer, so a single peer can flood the
+//! application with a topic. Each peer instead gets a bucket per limited topic, holding up to a
+//! second's worth of messages and refilled continuously at the topic's rate. A message arriving
+//! at an empty bucket is dropped before it reaches the application, and so is never propagated.
+//! Gossipsub has already seen the message by then, so a later copy from another peer is dropped
+//! as a duplicate and the message is lost to the node.
+use crate::PeerId;
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// Whether a message fits within its peer's rate on its topic.
+#[derive(Debug, PartialEq)]
+pub enum RateCheck {
+    Allowed,
+    /// The peer is over its rate. `first` is true for the first message dropped since the peer
+    /// was last within it, so a burst is only logged once.
+    Limited {
+        first: bool,
+    },
+}
+
+/// The messages a peer may still send on a topic.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Whether a message was dropped since the bucket last had a token.
+    limited: bool,
+}
+
+/// The rate limits of the topics, and the buckets of the peers.
+pub struct MessageRateLimits {
+    /// The messages per second a peer may send on a topic without its own limit, if any.
+    default_rate: Option<u32>,
+    /// The messages per second a peer may send on each topic with its own limit.
+    topic_rates: HashMap<String, u32>,
+    buckets: HashMap<PeerId, HashMap<String, Bucket>>,
+}
+
+impl MessageRateLimits {
+    pub fn new(default_rate: Option<u32>, topic_rates: HashMap<String, u32>) -> Self {
+        MessageRateLimits {
+            default_rate,
+            topic_rates,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Returns the messages per second a peer may send on `topic`, `None` if unlimited.
+    fn rate(&self, topic: &str) -> Option<u32> {
+        self.topic_rates.get(topic).copied().or(self.default_rate)
+    }
+
+    /// Takes a message of `peer_id` on `topic` from its bucket.
+    pub fn check(&mut self, peer_id: &PeerId, topic: &str, now: Instant) -> RateCheck {
+        let rate = match self.rate(topic) {
+            Some(rate) => f64::from(rate),
+            None => return RateCheck::Allowed,
+        };
+        let bucket = self
+            .buckets
+            .entry(peer_id.clone())
+            .or_default()
+            .entry(topic.to_string())
+            .or_insert_with(|| Bucket {
+                tokens: rate,
+                updated: now,
+                limited: false,
+            });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.limited = false;
+            RateCheck::Allowed
+        } else {
+            let first = !bucket.limited;
+            bucket.limited = true;
+            RateCheck::Limited { first }
+        }
+    }
+
+    /// Forgets the buckets of a disconnected peer.
+    pub fn remove_peer(&mut self, peer_id: &PeerId) {
+        self.buckets.remove(peer_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_rate_per_peer_and_topic() {
+        let mut topic_rates = HashMap::new();
+        topic_rates.insert("blocks".to_string(), 2);
+        let mut limits = MessageRateLimits::new(Some(1), topic_rates);
+        let a = PeerId::random();
+        let b = PeerId::random();
+        let now = Instant::now();
+
+        assert_eq!(limits.check(&a, "blocks", now), RateCheck::Allowed);
+        assert_eq!(limits.check(&a, "blocks", now), RateCheck::Allowed);
+        assert_eq!(
+            limits.check(&a, "blocks", now),
+            RateCheck::Limited { first: true }
+        );
+        assert_eq!(
+            limits.check(&a, "blocks", now),
+            RateCheck::Limited { first: false }
+        );
+        // other topics and peers have buckets of their own
+        assert_eq!(limits.check(&a, "votes", now), RateCheck::Allowed);
+        assert_eq!(
+            limits.check(&a, "votes", now),
+            RateCheck::Limited { first: true }
+        );
+        assert_eq!(limits.check(&b, "blocks", now), RateCheck::Allowed);
+
+        // the bucket refills at the rate of the topic
+        let later = now + Duration::from_millis(500);
+        assert_eq!(limits.check(&a, "blocks", later), RateCheck::Allowed);
+        assert_eq!(
+            limits.check(&a, "blocks", later),
+            RateCheck::Limited { first: true }
+        );
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let mut limits = MessageRateLimits::new(None, HashMap::new());
+        let peer_id = PeerId::random();
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert_eq!(limits.check(&peer_id, "blocks", now), RateCheck::Allowed);
+        }
+        assert!(limits.buckets.is_empty());
+    }
+}
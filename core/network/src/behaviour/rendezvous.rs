@@ -0,0 +1,354 @@
+//! The registrations kept by a rendezvous point.
+//!
+//! Where UDP is blocked discv5 cannot find peers, so nodes register instead at rendezvous points
+//! they are configured with, over the RPC. A registration holds the addresses of a node under an
+//! application namespace until its TTL runs out, and nodes asking the rendezvous point for the
+//! namespace are told about it. Nodes refresh their registrations well before they expire.
+//!
+//! This is a protocol of mothra's own, carried by its RPC. It is not the libp2p rendezvous
+//! protocol and does not interoperate with its implementations.
+//!
+//! A node may only register addresses on the hosts it is connected to the rendezvous point from,
+//! so that the rendezvous point cannot be used to have other nodes dial arbitrary hosts. The
+//! namespaces, the registrations per namespace and the namespaces a node registers under are
+//! bounded by `Limits`. The registrations of a node are dropped once it disconnects or is banned,
+//! so a node cannot hold namespaces it no longer serves for the rest of their TTL.
+use super::address_filter::ip_of;
+use crate::rpc::RegistrationRefusal;
+use crate::{Multiaddr, PeerId};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// The TTL nodes register for, in seconds.
+pub const DEFAULT_TTL: u64 = 2 * 60 * 60;
+
+/// The time between refreshes of our registrations, and of the peers we know from them.
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(DEFAULT_TTL / 2);
+
+/// The longest TTL a registration is accepted for, in seconds.
+const MAX_TTL: u64 = 72 * 60 * 60;
+
+/// The longest namespace accepted, in bytes.
+const MAX_NAMESPACE_LENGTH: usize = 255;
+
+/// The number of addresses kept per registration.
+pub const MAX_ADDRESSES: usize = 10;
+
+/// The number of peers returned by a discovery.
+pub const MAX_DISCOVERED: usize = 100;
+
+/// A node registered under a namespace.
+struct Registration {
+    addresses: Vec<Multiaddr>,
+    expires: Instant,
+}
+
+/// The registrations of a rendezvous point, by namespace.
+pub struct Registrations {
+    namespaces: HashMap<String, HashMap<PeerId, Registration>>,
+    /// The number of namespaces registrations are kept under.
+    max_namespaces: usize,
+    /// The number of registrations kept per namespace.
+    max_registrations: usize,
+    /// The number of namespaces a peer is kept registered under.
+    max_per_peer: usize,
+}
+
+impl Registrations {
+    pub fn new(max_namespaces: usize, max_registrations: usize, max_per_peer: usize) -> Self {
+        Registrations {
+            namespaces: HashMap::new(),
+            max_namespaces,
+            max_registrations,
+            max_per_peer,
+        }
+    }
+
+    /// Registers `peer_id` at `addresses` under `namespace`, replacing its previous registration.
+    /// Only the addresses on a host of `connected_from`, the addresses the peer is connected to
+    /// us from, are kept. Returns the TTL the registration was accepted for, capped at the longest
+    /// TTL, or why it was refused.
+    pub fn register(
+        &mut self,
+        peer_id: PeerId,
+        namespace: String,
+        mut addresses: Vec<Multiaddr>,
+        connected_from: &[Multiaddr],
+        ttl: u64,
+        now: Instant,
+    ) -> Result<u64, RegistrationRefusal> {
+        if namespace.is_empty() || namespace.len() > MAX_NAMESPACE_LENGTH {
+            return Err(RegistrationRefusal::InvalidNamespace);
+        }
+        if ttl == 0 {
+            return Err(RegistrationRefusal::InvalidTtl);
+        }
+        let hosts: HashSet<_> = connected_from.iter().filter_map(ip_of).collect();
+        addresses.retain(|address| ip_of(address).map_or(false, |ip| hosts.contains(&ip)));
+        if addresses.is_empty() {
+            return Err(RegistrationRefusal::NoAddresses);
+        }
+        self.expire(now);
+        let refreshed = self
+            .namespaces
+            .get(&namespace)
+            .map_or(false, |registrations| registrations.contains_key(&peer_id));
+        if !refreshed && self.namespaces_of(&peer_id) >= self.max_per_peer {
+            return Err(RegistrationRefusal::TooManyForPeer);
+        }
+        if !self.namespaces.contains_key(&namespace) && self.namespaces.len() >= self.max_namespaces
+        {
+            return Err(RegistrationRefusal::TooManyNamespaces);
+        }
+        let registrations = self.namespaces.entry(namespace).or_default();
+        if registrations.len() >= self.max_registrations && !refreshed {
+            return Err(RegistrationRefusal::NamespaceFull);
+        }
+        let ttl = ttl.min(MAX_TTL);
+        addresses.truncate(MAX_ADDRESSES);
+        registrations.insert(
+            peer_id,
+            Registration {
+                addresses,
+                expires: now + Duration::from_secs(ttl),
+            },
+        );
+        Ok(ttl)
+    }
+
+    /// Drops the registrations of a peer, under every namespace.
+    pub fn remove_peer(&mut self, peer_id: &PeerId) {
+        for registrations in self.namespaces.values_mut() {
+            registrations.remove(peer_id);
+        }
+        self.namespaces
+            .retain(|_, registrations| !registrations.is_empty());
+    }
+
+    /// The number of namespaces `peer_id` is registered under.
+    fn namespaces_of(&self, peer_id: &PeerId) -> usize {
+        self.namespaces
+            .values()
+            .filter(|registrations| registrations.contains_key(peer_id))
+            .count()
+    }
+
+    /// Returns up to `limit` of the peers registered under `namespace`, other than `requester`,
+    /// with their addresses.
+    pub fn discover(
+        &mut self,
+        namespace: &str,
+        limit: usize,
+        requester: &PeerId,
+        now: Instant,
+    ) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        self.expire(now);
+        self.namespaces
+            .get(namespace)
+            .into_iter()
+            .flat_map(|registrations| registrations.iter())
+            .filter(|(peer_id, _)| *peer_id != requester)
+            .take(limit.min(MAX_DISCOVERED))
+            .map(|(peer_id, registration)| (peer_id.clone(), registration.addresses.clone()))
+            .collect()
+    }
+
+    /// Drops the registrations whose TTL ran out, and the namespaces left empty.
+    fn expire(&mut self, now: Instant) {
+        for registrations in self.namespaces.values_mut() {
+            registrations.retain(|_, registration| registration.expires > now);
+        }
+        self.namespaces
+            .retain(|_, registrations| !registrations.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(port: u16) -> Multiaddr {
+        format!("/ip4/10.0.0.1/tcp/{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn test_register_and_discover() {
+        let now = Instant::now();
+        let mut registrations = Registrations::new(10, 10, 10);
+        let a = PeerId::random();
+        let b = PeerId::random();
+        let from = [address(40000)];
+        assert_eq!(
+            registrations.register(a.clone(), "mothra".into(), vec![address(1)], &from, 60, now),
+            Ok(60)
+        );
+        assert_eq!(
+            registrations.register(
+                b.clone(),
+                "mothra".into(),
+                vec![address(2)],
+                &from,
+                2 * MAX_TTL,
+                now
+            ),
+            Ok(MAX_TTL)
+        );
+        // refused registrations
+        assert_eq!(
+            registrations.register(a.clone(), "".into(), vec![address(1)], &from, 60, now),
+            Err(RegistrationRefusal::InvalidNamespace)
+        );
+        assert_eq!(
+            registrations.register(a.clone(), "other".into(), vec![], &from, 60, now),
+            Err(RegistrationRefusal::NoAddresses)
+        );
+        // a registration expiring at once is refused rather than accepted for a TTL of 0
+        assert_eq!(
+            registrations.register(a.clone(), "other".into(), vec![address(1)], &from, 0, now),
+            Err(RegistrationRefusal::InvalidTtl)
+        );
+
+        // the requester is not told about itself
+        assert_eq!(
+            registrations.discover("mothra", 10, &a, now),
+            vec![(b.clone(), vec![address(2)])]
+        );
+        assert_eq!(
+            registrations
+                .discover("mothra", 10, &PeerId::random(), now)
+                .len(),
+            2
+        );
+        assert_eq!(
+            registrations
+                .discover("mothra", 1, &PeerId::random(), now)
+                .len(),
+            1
+        );
+        assert!(registrations.discover("other", 10, &a, now).is_empty());
+
+        // a registration is gone once its TTL runs out
+        let later = now + Duration::from_secs(61);
+        assert_eq!(
+            registrations.discover("mothra", 10, &b, later),
+            Vec::<(PeerId, Vec<Multiaddr>)>::new()
+        );
+        assert_eq!(registrations.discover("mothra", 10, &a, later).len(), 1);
+    }
+
+    #[test]
+    fn test_register_only_connected_hosts() {
+        let now = Instant::now();
+        let mut registrations = Registrations::new(10, 10, 10);
+        let peer_id = PeerId::random();
+        let other_host: Multiaddr = "/ip4/10.0.0.2/tcp/1".parse().unwrap();
+
+        // an address on another host is not kept, and a registration left without any is refused
+        let ttl = registrations.register(
+            peer_id.clone(),
+            "mothra".into(),
+            vec![other_host.clone(), address(1)],
+            &[address(40000)],
+            60,
+            now,
+        );
+        assert_eq!(ttl, Ok(60));
+        assert_eq!(
+            registrations.discover("mothra", 10, &PeerId::random(), now),
+            vec![(peer_id.clone(), vec![address(1)])]
+        );
+        let ttl = registrations.register(
+            peer_id,
+            "mothra".into(),
+            vec![other_host],
+            &[address(40000)],
+            60,
+            now,
+        );
+        assert_eq!(ttl, Err(RegistrationRefusal::NoAddresses));
+    }
+
+    #[test]
+    fn test_bounded_namespaces_and_registrations() {
+        let now = Instant::now();
+        let mut registrations = Registrations::new(2, 2, 10);
+        let from = [address(40000)];
+        let mut register = |peer_id: &PeerId, namespace: &str| {
+            registrations.register(
+                peer_id.clone(),
+                namespace.into(),
+                vec![address(1)],
+                &from,
+                60,
+                now,
+            )
+        };
+        let (a, b, c) = (PeerId::random(), PeerId::random(), PeerId::random());
+        assert_eq!(register(&a, "one"), Ok(60));
+        assert_eq!(register(&b, "one"), Ok(60));
+        // the namespace is full, but a registered peer may refresh its registration
+        assert_eq!(register(&c, "one"), Err(RegistrationRefusal::NamespaceFull));
+        assert_eq!(register(&a, "one"), Ok(60));
+        assert_eq!(register(&c, "two"), Ok(60));
+        // no namespace is added beyond the limit
+        assert_eq!(
+            register(&c, "three"),
+            Err(RegistrationRefusal::TooManyNamespaces)
+        );
+    }
+
+    #[test]
+    fn test_namespaces_per_peer() {
+        let now = Instant::now();
+        let mut registrations = Registrations::new(10, 10, 2);
+        let from = [address(40000)];
+        let mut register = |peer_id: &PeerId, namespace: &str| {
+            registrations.register(
+                peer_id.clone(),
+                namespace.into(),
+                vec![address(1)],
+                &from,
+                60,
+                now,
+            )
+        };
+        let (a, b) = (PeerId::random(), PeerId::random());
+        assert_eq!(register(&a, "one"), Ok(60));
+        assert_eq!(register(&a, "two"), Ok(60));
+        // a peer cannot take up more namespaces, but may refresh those it holds
+        assert_eq!(
+            register(&a, "three"),
+            Err(RegistrationRefusal::TooManyForPeer)
+        );
+        assert_eq!(register(&a, "two"), Ok(60));
+        assert_eq!(register(&b, "three"), Ok(60));
+    }
+
+    #[test]
+    fn test_peer_registrations_are_removed() {
+        let now = Instant::now();
+        let mut registrations = Registrations::new(1, 10, 10);
+        let from = [address(40000)];
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let register = |registrations: &mut Registrations, peer_id: &PeerId, namespace: &str| {
+            registrations.register(
+                peer_id.clone(),
+                namespace.into(),
+                vec![address(1)],
+                &from,
+                60,
+                now,
+            )
+        };
+        assert_eq!(register(&mut registrations, &a, "one"), Ok(60));
+        assert_eq!(register(&mut registrations, &b, "one"), Ok(60));
+        registrations.remove_peer(&a);
+        assert_eq!(
+            registrations.discover("one", 10, &PeerId::random(), now),
+            vec![(b.clone(), vec![address(1)])]
+        );
+        // the namespace left empty no longer counts towards the limit
+        registrations.remove_peer(&b);
+        assert_eq!(register(&mut registrations, &a, "two"), Ok(60));
+    }
+}
@@ -0,0 +1,23 @@
+//! The time read by the network's own expiries and schedules.
+//!
+//! The network reads the time through `now` rather than `Instant::now`, from tokio's clock. Under
+//! the `simulation` feature that clock can be paused and advanced by a test, which then also moves
+//! the intervals and delay queues of the behaviour, the peer manager and the RPC handlers.
+//! Otherwise tokio's clock is the system's monotonic clock.
+//!
+//! Not every part of the network follows the virtual time. Deadlines handed to libp2p, such as
+//! those of `KeepAlive::Until`, are waited for by libp2p on the system's clock, so they are
+//! converted with `wall_deadline` each time they are handed over. The heartbeat of gossipsub runs
+//! on a timer of its own, inside libp2p, and keeps to the system's clock.
+use std::time::Instant;
+
+/// The current time.
+pub fn now() -> Instant {
+    tokio::time::Instant::now().into_std()
+}
+
+/// Converts `deadline`, read from `now`, to a deadline on the system's clock, as libp2p waits
+/// for: the time left until `deadline` counts from the system's current time.
+pub fn wall_deadline(deadline: Instant) -> Instant {
+    Instant::now() + deadline.saturating_duration_since(now())
+}
@@ -1,17 +1,36 @@
 extern crate target_info;
+use crate::keys::KeyType;
+use crate::limits::{Limits, DEFAULT_MAX_IDENTIFY_ADDRESSES};
+use crate::peer_manager::{
+    CapabilityFields, IdentityVerifier, Rep, DEFAULT_MAX_DC_PEERS, DEFAULT_REPUTATION,
+};
+use crate::rpc::{
+    CustomProtocols, ProtocolVersions, DEFAULT_PROTOCOL_PREFIX, DEFAULT_RPC_CODEC_WORKERS,
+};
+use crate::types::{
+    BufferPool, EnrForkId, MessageTransforms, PingHandler, SubnetId, TopicBridges, TopicKey,
+    TopicValidator, UnsubscribedTopicPolicy,
+};
 use crate::Enr;
 use crate::{error, DEFAULT_CLIENT_NAME};
 use discv5::{Discv5Config, Discv5ConfigBuilder};
 use libp2p::gossipsub::{GossipsubConfig, GossipsubConfigBuilder, GossipsubMessage, MessageId};
-use libp2p::Multiaddr;
+use libp2p::{Multiaddr, PeerId};
 use serde_derive::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 use target_info::Target;
 
 pub const GOSSIP_MAX_SIZE: usize = 1_048_576;
 
+/// The agent version advertised in private mode, which reveals neither the client nor its
+/// version.
+pub const PRIVATE_AGENT_VERSION: &str = "libp2p";
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(default)]
 /// Network configuration
@@ -31,6 +50,17 @@ pub struct Config {
     /// The TCP port that libp2p listens on.
     pub libp2p_port: u16,
 
+    /// The TCP port libp2p listens on for WebSocket connections, such as those of browser peers.
+    /// No WebSocket listener is opened if this is not set.
+    pub ws_port: Option<u16>,
+
+    /// The PEM or DER encoded certificate chain of the WebSocket listener. Together with
+    /// `ws_tls_key`, it makes the listener accept secure WebSockets (wss) rather than plain ones.
+    pub ws_tls_cert: Option<PathBuf>,
+
+    /// The PEM or DER encoded private key of `ws_tls_cert`.
+    pub ws_tls_key: Option<PathBuf>,
+
     /// UDP port that discovery listens on.
     pub discovery_port: u16,
 
@@ -47,9 +77,50 @@ pub struct Config {
     /// Target number of connected peers.
     pub max_peers: usize,
 
-    /// A secp256k1 secret key, as bytes in ASCII-encoded hex.
+    /// The maximum number of disconnected peers remembered in the peer database. Peers the
+    /// database has no room for are forgotten, starting with the lowest reputation.
+    pub max_disconnected_peers: usize,
+
+    /// The maximum number of dials to discovered peers in progress at a time. Further discovered
+    /// peers wait in a queue.
+    pub max_pending_dials: usize,
+
+    /// The number of peers from the last run, best reputation first, dialed on startup before
+    /// discovery finds new ones. 0 disables these dials.
+    pub warm_dial_peers: usize,
+
+    /// The delay before the next address of a peer known at several addresses is dialed, while
+    /// the earlier addresses are still being dialed. An address that fails moves the dial on to
+    /// the next address at once. `None`, the default, dials the addresses one after the other.
+    pub dial_stagger: Option<Duration>,
+
+    /// Subnets whose peers are dialed first, read from the `attnets` field of discovered ENRs.
+    /// Only peers on our fork are dialed before them.
+    pub priority_subnets: Vec<SubnetId>,
+
+    /// The minimum number of connected peers wanted on subnets, read from the `attnets` field of
+    /// their ENR. Subnets below their quota are searched for peers, whose peers are then dialed
+    /// like those of the priority subnets.
+    pub subnet_peer_quotas: HashMap<SubnetId, usize>,
+
+    /// When at `max_peers`, the lowest reputation inbound peer may be disconnected to admit a
+    /// trusted peer, or a peer on a priority subnet that too few connected peers are on or on a
+    /// subnet below its quota. At most one peer is pre-empted per interval, and only peers
+    /// connected for longer than the interval. `None`, the default, disables pre-emption.
+    pub peer_preemption_interval: Option<Duration>,
+
+    /// The time it takes for the components of a peer's reputation to decay to half their value.
+    /// `None`, the default, disables decay, so reputation changes are permanent.
+    pub score_half_life: Option<Duration>,
+
+    /// The type of key used for the node's identity. This determines the type of the key
+    /// generated when none exists on disk, and how `secret_key_hex` is parsed.
+    pub key_type: KeyType,
+
+    /// A secret key of type `key_type`, as bytes in ASCII-encoded hex.
     ///
-    /// With or without `0x` prefix.
+    /// With or without `0x` prefix. It is used instead of the key stored in the network directory,
+    /// and startup fails if that key is a different one.
     #[serde(skip)]
     pub secret_key_hex: Option<String>,
 
@@ -61,18 +132,282 @@ pub struct Config {
     #[serde(skip)]
     pub discv5_config: Discv5Config,
 
+    /// The versions of each RPC protocol supported, most preferred first. Versions after the first
+    /// need a codec registered with `ProtocolVersions::register`, so they are only set through the
+    /// API: there is no command line flag for them, as a codec cannot be given on the command line.
+    #[serde(skip)]
+    pub rpc_versions: ProtocolVersions,
+
+    /// The prefix of the RPC protocol ids, `/eth2/beacon_chain/req` in
+    /// `/eth2/beacon_chain/req/status/1/ssz_snappy`. Networks other than mothra's use their own
+    /// prefix so that their peers do not negotiate RPCs with mothra nodes.
+    pub rpc_protocol_prefix: String,
+
+    /// The request/response protocols defined by the application, served under
+    /// `rpc_protocol_prefix` alongside the built in ones. Their requests and responses reach the
+    /// application like those of the built in protocols, named after the protocol.
+    #[serde(skip)]
+    pub custom_protocols: CustomProtocols,
+
+    /// RPC substreams open for longer than this are logged and force closed, to avoid leaking
+    /// them on flaky connections. `None` leaves substreams open.
+    pub rpc_substream_max_age: Option<Duration>,
+
+    /// The threads RPC payloads are compressed and decompressed on, so that a large response does
+    /// not hold up the other substreams of its connection. With none, payloads are coded on the
+    /// task polling the connection.
+    pub rpc_codec_workers: usize,
+
     /// List of nodes to initially connect to.
     pub boot_nodes: Vec<Enr>,
 
+    /// A file of ENRs exported from the routing table of another node, added to the routing table
+    /// on startup along with the boot nodes. A missing file is logged and ignored.
+    pub dht_import_file: Option<PathBuf>,
+
     /// List of libp2p nodes to initially connect to.
     pub libp2p_nodes: Vec<Multiaddr>,
 
     /// Disables the discovery protocol from starting.
     pub disable_discovery: bool,
 
-    /// List of extra topics to initially subscribe to as strings.
+    /// Listens on this port of the in-memory transport instead of on TCP, for simulations. See
+    /// `simulation::node_config`.
+    #[cfg(feature = "simulation")]
+    #[serde(skip)]
+    pub memory_port: Option<u64>,
+
+    /// Peers that are kept connected, reconnecting whenever they disconnect. Each address must
+    /// end with the `/p2p/` peer id of the node.
+    pub static_peers: Vec<Multiaddr>,
+
+    /// Rendezvous points to register at under `rendezvous_namespaces`, and to ask for the peers
+    /// registered under them. Like static peers they are kept connected, and each address must
+    /// end with the `/p2p/` peer id of the node. Unlike discv5, rendezvous only needs TCP. The
+    /// protocol is mothra's own, so the rendezvous points must be mothra nodes: it is not the
+    /// libp2p rendezvous protocol.
+    pub rendezvous_points: Vec<Multiaddr>,
+
+    /// The namespaces to register under at the rendezvous points.
+    pub rendezvous_namespaces: Vec<String>,
+
+    /// Serves as a rendezvous point, keeping the registrations of other nodes within the
+    /// `served_rendezvous_namespaces`, `rendezvous_registrations` and
+    /// `rendezvous_namespaces_per_peer` limits. A node may only register addresses on the hosts
+    /// it is connected from, and its registrations are dropped when it disconnects.
+    pub rendezvous_server: bool,
+
+    /// Regular expressions of the Identify agent strings allowed to stay connected. When empty,
+    /// every agent not denied is allowed.
+    pub agent_allow: Vec<String>,
+
+    /// Regular expressions of the Identify agent strings that are disconnected and penalized.
+    pub agent_deny: Vec<String>,
+
+    /// Peers whose Identify protocol version does not start with this prefix, such as `mothra/`,
+    /// are disconnected and penalized as running an incompatible protocol. `None` keeps peers of
+    /// any protocol version.
+    pub required_protocol_prefix: Option<String>,
+
+    /// Only dials out: no libp2p listener is opened and the ENR carries no addresses, so other
+    /// nodes can neither discover nor dial this node. Discovery queries are sent from an
+    /// ephemeral UDP port.
+    pub outbound_only: bool,
+
+    /// Hides the details that identify the client: identify advertises `PRIVATE_AGENT_VERSION`
+    /// instead of `agent_version`, the ENR carries no fields beyond those mothra sets, and Status
+    /// is only served at its most preferred version. The Status payload itself is the
+    /// application's, which should leave its version out.
+    pub private: bool,
+
+    /// List of extra topics to initially subscribe to as strings. Topics containing
+    /// `FORK_PLACEHOLDER` are joined once for each fork served.
     pub topics: Vec<String>,
 
+    /// A second fork id served alongside the current one during a fork transition: fork-scoped
+    /// topics are joined for both forks and the ENR advertises both, so the node can bridge the
+    /// networks on either side. RPC protocols are not fork-scoped and are shared by both.
+    pub secondary_fork_id: Option<EnrForkId>,
+
+    /// Relays the gossip of fork-scoped topics between the current and the secondary fork, and
+    /// publishes the messages of fork-scoped topics on both. Has no effect without a secondary
+    /// fork.
+    pub bridge_forks: bool,
+
+    /// How gossipsub messages on topics we are not subscribed to are handled.
+    pub unsubscribed_topic_policy: UnsubscribedTopicPolicy,
+
+    /// Fails the publish of a message on a topic we are not subscribed to, rather than sending it
+    /// to the topic's fanout peers. Catches topics misspelt by the application, whose messages
+    /// would otherwise go to a fanout with no peers.
+    pub reject_unsubscribed_publish: bool,
+
+    /// Checks the names of the topics subscribed and published to, and of those peers announce.
+    /// Subscribing or publishing to a topic it rejects fails, and the topics it rejects are dropped
+    /// from the subscriptions peers send.
+    #[serde(skip)]
+    pub topic_validator: Option<TopicValidator>,
+
+    /// Topics of small, urgent messages. Their messages are forwarded within
+    /// `control_forward_budget`, apart from the other topics, and may not exceed
+    /// `control_max_message_size`.
+    pub control_topics: Vec<String>,
+
+    /// The largest message, in bytes, received or published on a control topic. Larger received
+    /// messages are dropped and larger published messages fail.
+    pub control_max_message_size: usize,
+
+    /// The number of received messages on control topics forwarded per second. Forwards over the
+    /// budget wait for the next second. `None` forwards without limit.
+    pub control_forward_budget: Option<usize>,
+
+    /// The number of received messages on the other topics forwarded per second. `None` forwards
+    /// without limit.
+    pub data_forward_budget: Option<usize>,
+
+    /// Peers that are never grafted into our gossipsub mesh: their grafts are ignored and ours to
+    /// them are withheld. They remain subscribed, so they are still sent gossip and fanout
+    /// publishes, and can be reached over RPC.
+    #[serde(skip)]
+    pub no_graft_peers: Vec<PeerId>,
+
+    /// The only peers whose messages are accepted on a topic, by topic. Messages on these topics
+    /// are checked against the peer they were received from, as this version of gossipsub does not
+    /// sign messages: a message relayed by a peer that is not listed is dropped before reaching the
+    /// application, and so is never propagated, even if a listed peer published it. List the peers
+    /// trusted to relay the topic along with its publishers.
+    #[serde(skip)]
+    pub topic_publishers: HashMap<String, HashSet<PeerId>>,
+
+    /// The number of messages per second each peer may send on a topic, with bursts of up to a
+    /// second's worth, on the topics the node is subscribed to. Messages over the rate are dropped
+    /// before reaching the application, without penalizing the peer, which may only be relaying
+    /// them. `None` leaves the topics without a limit of their own unlimited. A rate must be at
+    /// least 1.
+    pub peer_topic_rate_limit: Option<u32>,
+
+    /// The number of messages per second each peer may send on the given topics, overriding
+    /// `peer_topic_rate_limit`.
+    pub topic_rate_limits: HashMap<String, u32>,
+
+    /// The number of gossip messages per second the node lets through, from all peers. Over it,
+    /// the topics with the lowest `topic_priorities` are dropped first. `None` lets every message
+    /// through.
+    pub inbound_message_rate_limit: Option<u32>,
+
+    /// The priority of topics under `inbound_message_rate_limit`, 0 for those not given. Topics
+    /// of a higher priority may use more of the rate.
+    pub topic_priorities: HashMap<String, u8>,
+
+    /// The application's check of the identity of discovered and connected peers.
+    #[serde(skip)]
+    pub identity_verifier: Option<Arc<dyn IdentityVerifier>>,
+
+    /// Produces the payload of the Pings and Pongs sent, and interprets those received, in place
+    /// of the fixed `ping_data` the network is started with.
+    #[serde(skip)]
+    pub ping_handler: Option<PingHandler>,
+
+    /// The metadata fields read into capability flags of peers, for selecting peers with
+    /// `PeerDB::peers_with_capability`. Metadata is requested from peers as they connect when any
+    /// field is registered.
+    pub capability_fields: CapabilityFields,
+
+    /// The transforms applied to the data of messages published and received on each topic.
+    #[serde(skip)]
+    pub message_transforms: MessageTransforms,
+
+    /// The topics whose received messages are re-published to other topics, and the topics whose
+    /// messages carry the bridge envelope. See the `bridge` module.
+    #[serde(skip)]
+    pub topic_bridges: TopicBridges,
+
+    /// The buffers inbound message payloads are decoded into. An application can keep a clone
+    /// and give back the payloads it is done with, for the next messages to reuse.
+    #[serde(skip)]
+    pub buffer_pool: Arc<BufferPool>,
+
+    /// The keys of the topics whose messages are encrypted, by topic. Encryption runs after the
+    /// other transforms of the topic on publish. Messages that do not decrypt are dropped and
+    /// count against the peer that sent them.
+    #[serde(skip)]
+    pub topic_keys: HashMap<String, TopicKey>,
+
+    /// How long messages encrypted under a topic key are still accepted after it is replaced.
+    pub topic_key_grace: Duration,
+
+    /// The number of published messages held while no peer is subscribed to their topic. They are
+    /// published once a peer subscribes, or dropped at their deadline. Zero, the default, publishes
+    /// every message at once, as gossipsub drops those with no subscribers. Messages published
+    /// without a TTL may be held until the buffer is full, so a buffer suits applications that
+    /// give their messages a TTL.
+    pub publish_buffer_size: usize,
+
+    /// The time after a peer is pruned from a topic's mesh, by us or by the peer, before either
+    /// side may graft the other on that topic again. Zero disables the backoff.
+    pub gossip_prune_backoff: Duration,
+
+    /// The backoff, in place of `gossip_prune_backoff`, for the peers of a topic we leave.
+    pub gossip_unsubscribe_backoff: Duration,
+
+    /// Peers that graft this soon after being pruned are penalized. Zero disables the penalty.
+    pub gossip_graft_flood_threshold: Duration,
+
+    /// How often each mesh is checked for poorly reputed peers. When the median reputation of a
+    /// mesh falls below `opportunistic_graft_threshold`, better reputed peers on the topic are
+    /// sent a GRAFT, adding this node to their meshes; this node's own mesh is left to gossipsub.
+    /// `None`, the default, disables opportunistic grafting.
+    pub opportunistic_graft_interval: Option<Duration>,
+
+    /// The number of peers sent a GRAFT for a mesh at each opportunistic graft.
+    pub opportunistic_graft_peers: usize,
+
+    /// The median mesh reputation below which peers are grafted opportunistically.
+    pub opportunistic_graft_threshold: Rep,
+
+    /// When connections to peers are closed for being idle.
+    pub keep_alive_policy: KeepAlivePolicy,
+
+    /// How long a connection may go without traffic under `KeepAlivePolicy::Idle`.
+    pub keep_alive_idle_timeout: Duration,
+
+    /// The sizes of the caches and queues, and the timeouts of requests and dials.
+    pub limits: Limits,
+
+    /// The maximum number of listen addresses stored for a peer from identify. Addresses beyond
+    /// this are dropped. When changed from its default it overrides
+    /// `limits.max_identify_addresses`, see `Config::limits`.
+    #[deprecated(note = "use `limits.max_identify_addresses`")]
+    pub max_identify_addresses: usize,
+
+    /// Keeps loopback, link-local and private range addresses advertised by peers. These are
+    /// dropped by default when our ENR has a public address, but lab networks may need them.
+    pub allow_private_addresses: bool,
+
+    /// Logs every gossipsub control message sent or received, at debug level. Meant for studying
+    /// mesh dynamics, as it is verbose. The messages are counted either way.
+    pub log_gossip_control: bool,
+
+    /// Exchanges the list of subscribed topics with each peer over RPC when it connects, so the
+    /// topics a peer serves are known before gossipsub meshes form.
+    pub topic_exchange: bool,
+
+    /// Answers Status requests with `NetworkGlobals::local_status`, once the application has set
+    /// it, rather than propagating them. While two forks are served, peers whose status decodes as
+    /// a `StatusMessage` are answered with the status set for their fork. The requests answered
+    /// are reported with `BehaviourEvent::StatusAnswered`.
+    pub auto_status: bool,
+
+    /// Expects the Status messages of peers to be SSZ encoded `StatusMessage`s. Peers sending a
+    /// status that does not decode are penalized, and those on a fork we do not serve, or whose
+    /// finalized checkpoint conflicts with our status on their fork, are sent a Goodbye and
+    /// disconnected. Other networks send statuses of their own, as raw bytes.
+    pub typed_status: bool,
+
+    /// Penalizes peers that deliver less than this fraction of the gossip they send us before
+    /// any other peer does. `None` only records how useful each peer is.
+    pub min_gossip_usefulness: Option<f64>,
+
     /// Introduces randomization in network propagation of messages. This should only be set for
     /// testing purposes and will likely be removed in future versions.
     // TODO: Remove this functionality for mainnet
@@ -81,6 +416,7 @@ pub struct Config {
 
 impl Default for Config {
     /// Generate a default network configuration.
+    #[allow(deprecated)]
     fn default() -> Self {
         let agent_version = format!(
             "{}/{}/{}",
@@ -133,18 +469,87 @@ impl Default for Config {
             network_dir,
             listen_address: "127.0.0.1".parse().expect("valid ip address"),
             libp2p_port: 9000,
+            ws_port: None,
+            ws_tls_cert: None,
+            ws_tls_key: None,
             discovery_port: 9000,
             enr_address: None,
             enr_udp_port: None,
             enr_tcp_port: None,
             max_peers: 50,
+            max_disconnected_peers: DEFAULT_MAX_DC_PEERS,
+            max_pending_dials: 8,
+            warm_dial_peers: 8,
+            dial_stagger: None,
+            priority_subnets: vec![],
+            subnet_peer_quotas: HashMap::new(),
+            peer_preemption_interval: None,
+            score_half_life: None,
+            key_type: KeyType::default(),
             secret_key_hex: None,
             gs_config,
             discv5_config,
+            rpc_versions: ProtocolVersions::default(),
+            rpc_protocol_prefix: DEFAULT_PROTOCOL_PREFIX.into(),
+            custom_protocols: CustomProtocols::default(),
+            rpc_substream_max_age: Some(Duration::from_secs(120)),
+            rpc_codec_workers: DEFAULT_RPC_CODEC_WORKERS,
             boot_nodes: vec![],
+            dht_import_file: None,
             libp2p_nodes: vec![],
             disable_discovery: false,
+            #[cfg(feature = "simulation")]
+            memory_port: None,
+            static_peers: vec![],
+            rendezvous_points: vec![],
+            rendezvous_namespaces: vec![DEFAULT_CLIENT_NAME.to_string()],
+            rendezvous_server: false,
+            agent_allow: vec![],
+            agent_deny: vec![],
+            required_protocol_prefix: None,
+            outbound_only: false,
+            private: false,
             topics: vec![],
+            secondary_fork_id: None,
+            bridge_forks: false,
+            unsubscribed_topic_policy: UnsubscribedTopicPolicy::default(),
+            reject_unsubscribed_publish: false,
+            topic_validator: None,
+            control_topics: vec![],
+            control_max_message_size: 4096,
+            control_forward_budget: None,
+            data_forward_budget: None,
+            no_graft_peers: vec![],
+            topic_publishers: HashMap::new(),
+            peer_topic_rate_limit: None,
+            topic_rate_limits: HashMap::new(),
+            inbound_message_rate_limit: None,
+            topic_priorities: HashMap::new(),
+            identity_verifier: None,
+            ping_handler: None,
+            capability_fields: CapabilityFields::default(),
+            message_transforms: MessageTransforms::default(),
+            topic_bridges: TopicBridges::default(),
+            buffer_pool: Arc::new(BufferPool::default()),
+            topic_keys: HashMap::new(),
+            topic_key_grace: Duration::from_secs(60),
+            publish_buffer_size: 0,
+            gossip_prune_backoff: Duration::from_secs(0),
+            gossip_unsubscribe_backoff: Duration::from_secs(0),
+            gossip_graft_flood_threshold: Duration::from_secs(0),
+            opportunistic_graft_interval: None,
+            opportunistic_graft_peers: 2,
+            opportunistic_graft_threshold: DEFAULT_REPUTATION,
+            keep_alive_policy: KeepAlivePolicy::default(),
+            keep_alive_idle_timeout: Duration::from_secs(60),
+            limits: Limits::default(),
+            max_identify_addresses: DEFAULT_MAX_IDENTIFY_ADDRESSES,
+            allow_private_addresses: false,
+            log_gossip_control: false,
+            topic_exchange: false,
+            auto_status: false,
+            typed_status: false,
+            min_gossip_usefulness: None,
             propagation_percentage: None,
         }
     }
@@ -154,6 +559,46 @@ impl Config {
     pub fn new() -> Self {
         Config::default()
     }
+
+    /// The limits in effect, with the deprecated `max_identify_addresses` applied if it was
+    /// changed from its default.
+    #[allow(deprecated)]
+    pub fn limits(&self) -> Limits {
+        let mut limits = self.limits.clone();
+        if self.max_identify_addresses != DEFAULT_MAX_IDENTIFY_ADDRESSES {
+            limits.max_identify_addresses = self.max_identify_addresses;
+        }
+        limits
+    }
+}
+
+/// When an established connection is closed for being idle.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum KeepAlivePolicy {
+    /// Connections stay open until the peer is disconnected.
+    Always,
+    /// Connections are closed once they have no RPC substreams open and no gossip messages or
+    /// application RPC were exchanged for `keep_alive_idle_timeout`. Connections to peers in one
+    /// of our gossipsub meshes are never idle.
+    Idle,
+}
+
+impl Default for KeepAlivePolicy {
+    fn default() -> Self {
+        KeepAlivePolicy::Always
+    }
+}
+
+impl FromStr for KeepAlivePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "always" => Ok(KeepAlivePolicy::Always),
+            "idle" => Ok(KeepAlivePolicy::Idle),
+            _ => Err(format!("Unknown keep alive policy: {}", s)),
+        }
+    }
 }
 
 pub fn unused_port(transport: &str) -> error::Result<u16> {
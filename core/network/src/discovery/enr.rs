@@ -1,19 +1,29 @@
-use super::enr_ext::CombinedKeyExt;
+use super::enr_ext::{CombinedKeyExt, EnrExt};
 use super::ENR_FILENAME;
 use crate::types::{Enr, EnrBitfield, EnrForkId};
 use crate::NetworkConfig;
 pub use discv5::enr::{self, CombinedKey, EnrBuilder};
 use libp2p::core::identity::Keypair;
+use serde_derive::Serialize;
 use slog::{debug, warn};
+use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
+use std::net::IpAddr;
 use std::path::Path;
 use std::str::FromStr;
 
 /// The ENR field specifying the fork id.
 pub const ETH2_ENR_KEY: &str = "eth2";
+/// The ENR field specifying the second fork id served during a fork transition, if any.
+pub const SECONDARY_FORK_ENR_KEY: &str = "eth2alt";
+/// The ENR fields specifying fork ids.
+const FORK_ENR_KEYS: &[&str] = &[ETH2_ENR_KEY, SECONDARY_FORK_ENR_KEY];
 /// The ENR field specifying the subnet bitfield.
 pub const BITFIELD_ENR_KEY: &str = "attnets";
+/// The prefix of the ENR fields holding application service records, followed by the service key.
+pub const SERVICE_ENR_PREFIX: &str = "svc:";
 
 /// Loads an ENR from file if it exists and matches the current NodeId and sequence number. If none
 /// exists, generates a new one.
@@ -30,7 +40,16 @@ pub fn build_or_load_enr(
     // Note: Discovery should update the ENR record's IP to the external IP as seen by the
     // majority of our peers, if the CLI doesn't expressly forbid it.
     let enr_key = CombinedKey::from_libp2p(&local_key)?;
-    let mut local_enr = build_enr(&enr_key, config, enr_fork_id)?;
+    let secondary_fork_id = config.secondary_fork_id.clone();
+    let mut local_enr = match build_enr(&enr_key, config, enr_fork_id.clone(), secondary_fork_id) {
+        Ok(enr) => enr,
+        // the record is limited in size, and is still useful without the second fork
+        Err(e) if config.secondary_fork_id.is_some() => {
+            warn!(log, "The secondary fork id does not fit in the ENR, leaving it out"; "error" => e);
+            build_enr(&enr_key, config, enr_fork_id, None)?
+        }
+        Err(e) => return Err(e),
+    };
 
     let enr_f = config.network_dir.join(ENR_FILENAME);
     if let Ok(mut enr_file) = File::open(enr_f.clone()) {
@@ -42,7 +61,11 @@ pub fn build_or_load_enr(
                     Ok(disk_enr) => {
                         // if the same node id, then we may need to update our sequence number
                         if local_enr.node_id() == disk_enr.node_id() {
-                            if compare_enr(&local_enr, &disk_enr) {
+                            // in private mode, fields added to the stored ENR could identify the
+                            // client
+                            if compare_enr(&local_enr, &disk_enr)
+                                && !(config.private && has_extra_fields(&local_enr, &disk_enr))
+                            {
                                 debug!(log, "ENR loaded from disk"; "file" => format!("{:?}", enr_f));
                                 // the stored ENR has the same configuration, use it
                                 return Ok(disk_enr);
@@ -74,31 +97,40 @@ fn build_enr(
     enr_key: &CombinedKey,
     config: &NetworkConfig,
     enr_fork_id: EnrForkId,
+    secondary_fork_id: Option<EnrForkId>,
 ) -> Result<Enr, String> {
     let mut builder = EnrBuilder::new("v4");
-    if let Some(enr_address) = config.enr_address {
-        builder.ip(enr_address);
-    }
-    if let Some(udp_port) = config.enr_udp_port {
-        builder.udp(udp_port);
+    // an outbound-only node can't be reached, so it doesn't advertise any address
+    if !config.outbound_only {
+        if let Some(enr_address) = config.enr_address {
+            builder.ip(enr_address);
+        }
+        if let Some(udp_port) = config.enr_udp_port {
+            builder.udp(udp_port);
+        }
+        // we always give it our listening tcp port
+        // TODO: Add uPnP support to map udp and tcp ports
+        let tcp_port = config.enr_tcp_port.unwrap_or_else(|| config.libp2p_port);
+        builder.tcp(tcp_port);
     }
-    // we always give it our listening tcp port
-    // TODO: Add uPnP support to map udp and tcp ports
-    let tcp_port = config.enr_tcp_port.unwrap_or_else(|| config.libp2p_port);
-    builder.tcp(tcp_port);
 
     // set the `eth2` field on our ENR
 
     // TODO: fix this
     builder.add_value(ETH2_ENR_KEY.into(), enr_fork_id);
+    if let Some(secondary_fork_id) = secondary_fork_id {
+        builder.add_value(SECONDARY_FORK_ENR_KEY.into(), secondary_fork_id);
+    }
 
     // set the "attnets" field on our ENR
     // TODO: fix this
     let bitfield = [0u8; 8].to_vec();
     builder.add_value(BITFIELD_ENR_KEY.into(), bitfield);
 
+    if !config.outbound_only {
+        builder.tcp(config.libp2p_port);
+    }
     builder
-        .tcp(config.libp2p_port)
         .build(enr_key)
         .map_err(|e| format!("Could not build Local ENR: {:?}", e))
 }
@@ -112,6 +144,7 @@ fn compare_enr(local_enr: &Enr, disk_enr: &Enr) -> bool {
         && local_enr.tcp() == disk_enr.tcp()
         // must match on the same fork
         && local_enr.get(ETH2_ENR_KEY) == disk_enr.get(ETH2_ENR_KEY)
+        && local_enr.get(SECONDARY_FORK_ENR_KEY) == disk_enr.get(SECONDARY_FORK_ENR_KEY)
         // take preference over disk udp port if one is not specified
         && (local_enr.udp().is_none() || local_enr.udp() == disk_enr.udp())
         // we need the BITFIELD_ENR_KEY key to match, otherwise we use a new ENR. This will likely only
@@ -119,6 +152,44 @@ fn compare_enr(local_enr: &Enr, disk_enr: &Enr) -> bool {
         && local_enr.get(BITFIELD_ENR_KEY) == disk_enr.get(BITFIELD_ENR_KEY)
 }
 
+/// Returns true if `disk_enr` has fields that `local_enr` does not have, other than the addresses
+/// discovery may have set.
+fn has_extra_fields(local_enr: &Enr, disk_enr: &Enr) -> bool {
+    const ADDRESS_KEYS: [&[u8]; 6] = [b"ip", b"ip6", b"udp", b"udp6", b"tcp", b"tcp6"];
+    disk_enr
+        .iter()
+        .any(|(key, _)| local_enr.get(key).is_none() && !ADDRESS_KEYS.contains(&key.as_slice()))
+}
+
+/// The fork ids an ENR advertises: its current fork and, during a transition, its second fork.
+pub fn enr_fork_ids(enr: &Enr) -> impl Iterator<Item = &[u8]> {
+    // a removed secondary fork is left as an empty value
+    FORK_ENR_KEYS
+        .iter()
+        .filter_map(move |key| enr.get(key))
+        .filter(|fork_id| !fork_id.is_empty())
+}
+
+/// Whether two ENRs advertise a fork in common.
+pub fn shares_fork(enr: &Enr, other: &Enr) -> bool {
+    enr_fork_ids(enr).any(|fork_id| enr_fork_ids(other).any(|other| other == fork_id))
+}
+
+/// Checks the fields an application sets in the local ENR. The fork fields are only set through
+/// the forks served, so that they stay in step with the topics joined.
+pub fn check_enr_fields(fields: &[(String, Vec<u8>)]) -> Result<(), String> {
+    match fields
+        .iter()
+        .find(|(key, _)| FORK_ENR_KEYS.contains(&key.as_str()))
+    {
+        Some((key, _)) => Err(format!(
+            "The ENR field {} is set by updating the forks",
+            key
+        )),
+        None => Ok(()),
+    }
+}
+
 /// Saves an ENR to disk
 pub fn save_enr_to_disk(dir: &Path, enr: &Enr, log: &slog::Logger) {
     let _ = std::fs::create_dir_all(dir);
@@ -136,3 +207,270 @@ pub fn save_enr_to_disk(dir: &Path, enr: &Enr, log: &slog::Logger) {
         }
     }
 }
+
+/// The decoded fields of an ENR.
+#[derive(Debug, Clone, Serialize)]
+pub struct EnrInfo {
+    /// The base64 encoding of the record.
+    pub enr: String,
+    /// The sequence number of the record.
+    pub seq: u64,
+    /// The discv5 node id.
+    pub node_id: String,
+    /// The libp2p peer id derived from the record's public key.
+    pub peer_id: String,
+    pub ip: Option<IpAddr>,
+    pub udp: Option<u16>,
+    pub tcp: Option<u16>,
+    pub ip6: Option<IpAddr>,
+    pub udp6: Option<u16>,
+    pub tcp6: Option<u16>,
+    /// The multiaddrs that can be built from the record.
+    pub multiaddrs: Vec<String>,
+    /// Every key/value pair of the record, with the values hex encoded.
+    pub fields: Vec<(String, String)>,
+}
+
+impl From<&Enr> for EnrInfo {
+    fn from(enr: &Enr) -> Self {
+        EnrInfo {
+            enr: enr.to_base64(),
+            seq: enr.seq(),
+            node_id: enr.node_id().to_string(),
+            peer_id: enr.peer_id().to_string(),
+            ip: enr.ip().map(Into::into),
+            udp: enr.udp(),
+            tcp: enr.tcp(),
+            ip6: enr.ip6().map(Into::into),
+            udp6: enr.udp6(),
+            tcp6: enr.tcp6(),
+            multiaddrs: enr
+                .multiaddr_p2p()
+                .iter()
+                .map(|addr| addr.to_string())
+                .collect(),
+            fields: enr
+                .iter()
+                .map(|(key, value)| (String::from_utf8_lossy(key).into(), hex::encode(value)))
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for EnrInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "enr: {}", self.enr)?;
+        writeln!(f, "seq: {}", self.seq)?;
+        writeln!(f, "node_id: {}", self.node_id)?;
+        writeln!(f, "peer_id: {}", self.peer_id)?;
+        for addr in &self.multiaddrs {
+            writeln!(f, "multiaddr: {}", addr)?;
+        }
+        write!(f, "fields:")?;
+        for (key, value) in &self.fields {
+            write!(f, "\n  {}: 0x{}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// The fields to change when re-signing an ENR. Fields left as `None` keep their current value.
+#[derive(Debug, Clone, Default)]
+pub struct EnrUpdate {
+    pub ip: Option<IpAddr>,
+    pub udp: Option<u16>,
+    pub tcp: Option<u16>,
+    /// Arbitrary key/value pairs to insert or overwrite.
+    pub fields: Vec<(String, Vec<u8>)>,
+}
+
+/// Returns the ENR field advertising the service `service_key`.
+pub fn service_enr_key(service_key: &str) -> String {
+    format!("{}{}", SERVICE_ENR_PREFIX, service_key)
+}
+
+/// Returns the service records an ENR advertises, by service key.
+pub fn enr_services(enr: &Enr) -> HashMap<String, Vec<u8>> {
+    enr.iter()
+        .filter_map(|(key, value)| {
+            let key = std::str::from_utf8(key).ok()?;
+            if !key.starts_with(SERVICE_ENR_PREFIX) {
+                return None;
+            }
+            Some((key[SERVICE_ENR_PREFIX.len()..].to_string(), value.to_vec()))
+        })
+        .collect()
+}
+
+/// Decodes a base64-encoded ENR into its fields.
+pub fn decode_enr(enr_str: &str) -> Result<EnrInfo, String> {
+    let enr = Enr::from_str(enr_str).map_err(|e| format!("Invalid ENR: {:?}", e))?;
+    Ok(EnrInfo::from(&enr))
+}
+
+/// Generates a new ENR for the given key and address.
+pub fn generate_enr(
+    local_key: &Keypair,
+    ip: Option<IpAddr>,
+    udp_port: Option<u16>,
+    tcp_port: Option<u16>,
+) -> Result<Enr, String> {
+    let enr_key = CombinedKey::from_libp2p(local_key)?;
+    let mut builder = EnrBuilder::new("v4");
+    if let Some(ip) = ip {
+        builder.ip(ip);
+    }
+    if let Some(udp_port) = udp_port {
+        builder.udp(udp_port);
+    }
+    if let Some(tcp_port) = tcp_port {
+        builder.tcp(tcp_port);
+    }
+    builder
+        .build(&enr_key)
+        .map_err(|e| format!("Could not build ENR: {:?}", e))
+}
+
+/// Applies `update` to `enr`, re-signing it with `local_key` and increasing its sequence number.
+///
+/// The key must be the one the ENR was originally signed with.
+pub fn resign_enr(enr: &Enr, local_key: &Keypair, update: EnrUpdate) -> Result<Enr, String> {
+    if enr.peer_id() != local_key.public().into_peer_id() {
+        return Err("The key does not match the ENR's public key".into());
+    }
+    let enr_key = CombinedKey::from_libp2p(local_key)?;
+    let mut enr = enr.clone();
+    let sign_err = |e| format!("Could not re-sign ENR: {:?}", e);
+    if let Some(ip) = update.ip {
+        enr.set_ip(ip, &enr_key).map_err(sign_err)?;
+    }
+    if let Some(udp_port) = update.udp {
+        enr.set_udp(udp_port, &enr_key).map_err(sign_err)?;
+    }
+    if let Some(tcp_port) = update.tcp {
+        enr.set_tcp(tcp_port, &enr_key).map_err(sign_err)?;
+    }
+    for (key, value) in update.fields {
+        enr.insert(key.as_str(), value, &enr_key)
+            .map_err(sign_err)?;
+    }
+    Ok(enr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_resign_decode() {
+        let keypair = Keypair::generate_secp256k1();
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let enr = generate_enr(&keypair, Some(ip), Some(9000), Some(9000)).unwrap();
+        assert_eq!(enr.tcp(), Some(9000));
+
+        let update = EnrUpdate {
+            tcp: Some(9001),
+            fields: vec![("custom".into(), vec![1, 2, 3])],
+            ..Default::default()
+        };
+        let resigned = resign_enr(&enr, &keypair, update).unwrap();
+        assert!(resigned.seq() > enr.seq());
+
+        let info = decode_enr(&resigned.to_base64()).unwrap();
+        assert_eq!(info.peer_id, enr.peer_id().to_string());
+        assert_eq!(info.tcp, Some(9001));
+        assert_eq!(info.udp, Some(9000));
+        assert!(info
+            .fields
+            .contains(&("custom".to_string(), "010203".to_string())));
+    }
+
+    #[test]
+    fn test_has_extra_fields() {
+        let keypair = Keypair::generate_secp256k1();
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let local = generate_enr(&keypair, None, Some(9000), Some(9000)).unwrap();
+        // addresses discovery found are not extra fields
+        let update = EnrUpdate {
+            ip: Some(ip),
+            ..Default::default()
+        };
+        let disk = resign_enr(&local, &keypair, update).unwrap();
+        assert!(!has_extra_fields(&local, &disk));
+
+        // a field added by the application could identify the client
+        let update = EnrUpdate {
+            fields: vec![("client".into(), b"mothra".to_vec())],
+            ..Default::default()
+        };
+        let disk = resign_enr(&local, &keypair, update).unwrap();
+        assert!(has_extra_fields(&local, &disk));
+        assert!(!has_extra_fields(&disk, &local));
+    }
+
+    #[test]
+    fn test_check_enr_fields() {
+        assert!(check_enr_fields(&[]).is_ok());
+        assert!(check_enr_fields(&[("custom".into(), vec![1])]).is_ok());
+        for key in FORK_ENR_KEYS {
+            let fields = vec![("custom".into(), vec![1]), (key.to_string(), vec![2])];
+            assert!(check_enr_fields(&fields).is_err());
+        }
+    }
+
+    #[test]
+    fn test_enr_services() {
+        let keypair = Keypair::generate_secp256k1();
+        let enr = generate_enr(&keypair, None, None, Some(9000)).unwrap();
+        assert!(enr_services(&enr).is_empty());
+
+        let update = EnrUpdate {
+            fields: vec![
+                (service_enr_key("relay"), vec![1]),
+                (service_enr_key("archive"), vec![]),
+                ("custom".into(), vec![2]),
+            ],
+            ..Default::default()
+        };
+        let services = enr_services(&resign_enr(&enr, &keypair, update).unwrap());
+        assert_eq!(services.len(), 2);
+        assert_eq!(services["relay"], vec![1]);
+        assert_eq!(services["archive"], Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_resign_rejects_other_key() {
+        let keypair = Keypair::generate_secp256k1();
+        let enr = generate_enr(&keypair, None, None, Some(9000)).unwrap();
+        let other = Keypair::generate_secp256k1();
+        assert!(resign_enr(&enr, &other, EnrUpdate::default()).is_err());
+    }
+
+    #[test]
+    fn test_shares_fork() {
+        let keypair = Keypair::generate_secp256k1();
+        let enr = generate_enr(&keypair, None, None, Some(9000)).unwrap();
+        let with_forks = |forks: Vec<(&str, Vec<u8>)>| {
+            let update = EnrUpdate {
+                fields: forks
+                    .into_iter()
+                    .map(|(key, fork_id)| (key.to_string(), fork_id))
+                    .collect(),
+                ..Default::default()
+            };
+            resign_enr(&enr, &keypair, update).unwrap()
+        };
+        let old = with_forks(vec![(ETH2_ENR_KEY, vec![1])]);
+        let bridge = with_forks(vec![
+            (ETH2_ENR_KEY, vec![2]),
+            (SECONDARY_FORK_ENR_KEY, vec![1]),
+        ]);
+        let new = with_forks(vec![
+            (ETH2_ENR_KEY, vec![2]),
+            (SECONDARY_FORK_ENR_KEY, vec![]),
+        ]);
+        assert_eq!(enr_fork_ids(&bridge).count(), 2);
+        assert!(shares_fork(&old, &bridge) && shares_fork(&bridge, &new));
+        assert!(!shares_fork(&old, &new));
+    }
+}
@@ -1,27 +1,36 @@
 ///! This manages the discovery and management of peers.
 pub(crate) mod enr;
 pub mod enr_ext;
+mod predicate;
+mod snapshot;
 
 // Allow external use of the lighthouse ENR builder
 pub use enr::CombinedKey;
+pub use enr::{
+    decode_enr, enr_fork_ids, enr_services, generate_enr, resign_enr, service_enr_key, shares_fork,
+    EnrInfo, EnrUpdate,
+};
 pub use enr_ext::{CombinedKeyExt, EnrExt};
 pub use libp2p::core::identity::Keypair;
+pub use predicate::EnrPredicate;
+pub use snapshot::{import_enrs, DhtSnapshot};
 
-use crate::types::{EnrForkId, SubnetId};
+use crate::clock;
+use crate::types::{ActiveForks, SubnetId};
 use crate::{error, Enr, NetworkConfig, NetworkGlobals};
 use discv5::{enr::NodeId, Discv5, Discv5Event};
-use enr::{BITFIELD_ENR_KEY, ETH2_ENR_KEY};
+use enr::{BITFIELD_ENR_KEY, ETH2_ENR_KEY, SECONDARY_FORK_ENR_KEY};
 use futures::prelude::*;
 use futures::stream::FuturesUnordered;
 use libp2p::core::PeerId;
 use lru::LruCache;
 use slog::{crit, debug, info, trace, warn};
 use std::{
-    collections::VecDeque,
+    collections::{HashSet, VecDeque},
     net::SocketAddr,
     path::Path,
     pin::Pin,
-    sync::Arc,
+    sync::{atomic::Ordering, Arc},
     task::{Context, Poll},
     time::Instant,
 };
@@ -48,6 +57,8 @@ pub enum DiscoveryEvent {
     QueryResult(Option<Instant>, Vec<Enr>),
     /// This indicates that our local UDP socketaddr has been updated and we should inform libp2p.
     SocketUpdated(SocketAddr),
+    /// Peers matching the predicate of a `discover_matching_peers` search were found.
+    MatchingPeers(Vec<Enr>),
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -60,20 +71,23 @@ enum QueryType {
     },
     /// We are searching for more peers without ENR or time constraints.
     FindPeers,
+    /// We are searching for peers whose ENR matches a predicate.
+    Predicate {
+        predicate: EnrPredicate,
+        /// The number of matching peers still wanted.
+        target: usize,
+        /// The matching peers already found.
+        found: HashSet<PeerId>,
+        retries: usize,
+    },
 }
 
 impl QueryType {
     /// Returns true if this query has expired.
     pub fn expired(&self) -> bool {
         match self {
-            Self::FindPeers => false,
-            Self::Subnet { min_ttl, .. } => {
-                if let Some(ttl) = min_ttl {
-                    ttl > &Instant::now()
-                } else {
-                    true
-                }
-            }
+            Self::FindPeers | Self::Predicate { .. } => false,
+            Self::Subnet { min_ttl, .. } => min_ttl.map_or(false, |ttl| ttl < clock::now()),
         }
     }
 
@@ -83,7 +97,7 @@ impl QueryType {
     /// connected peers with this `min_ttl`
     pub fn min_ttl(&self) -> Option<Instant> {
         match self {
-            Self::FindPeers => None,
+            Self::FindPeers | Self::Predicate { .. } => None,
             Self::Subnet { min_ttl, .. } => *min_ttl,
         }
     }
@@ -135,6 +149,9 @@ pub struct Discovery {
     /// A queue of discovery queries to be processed.
     queued_queries: VecDeque<QueryType>,
 
+    /// Peers matching a predicate that are waiting to be returned.
+    matching_peers: VecDeque<Vec<Enr>>,
+
     /// Active discovery queries.
     active_queries: FuturesUnordered<std::pin::Pin<Box<dyn Future<Output = QueryResult> + Send>>>,
 
@@ -167,16 +184,45 @@ impl Discovery {
 
         info!(log, "ENR Initialised"; "enr" => local_enr.to_base64(), "seq" => local_enr.seq(), "id"=> format!("{}",local_enr.node_id()), "ip" => format!("{:?}", local_enr.ip()), "udp"=> format!("{:?}", local_enr.udp()), "tcp" => format!("{:?}", local_enr.tcp()));
 
-        let listen_socket = SocketAddr::new(config.listen_address, config.discovery_port);
+        let (listen_socket, discv5_config) = if config.outbound_only {
+            // discovery still needs a socket to send queries from, but it must not advertise it
+            let mut discv5_config = config.discv5_config.clone();
+            discv5_config.enr_update = false;
+            (SocketAddr::new(config.listen_address, 0), discv5_config)
+        } else {
+            let listen_socket = SocketAddr::new(config.listen_address, config.discovery_port);
+            (listen_socket, config.discv5_config.clone())
+        };
 
         // convert the keypair into an ENR key
         let enr_key: CombinedKey = CombinedKey::from_libp2p(&local_key)?;
 
-        let mut discv5 = Discv5::new(local_enr, enr_key, config.discv5_config.clone())
+        let mut discv5 = Discv5::new(local_enr.clone(), enr_key, discv5_config)
             .map_err(|e| format!("Discv5 service failed. Error: {:?}", e))?;
 
-        // Add bootnodes to routing table
-        for bootnode_enr in config.boot_nodes.clone() {
+        let imported_enrs = match &config.dht_import_file {
+            Some(path) => match import_enrs(path) {
+                Ok(enrs) => {
+                    info!(log, "Imported routing table entries"; "file" => format!("{:?}", path), "entries" => enrs.len());
+                    enrs
+                }
+                Err(e) => {
+                    warn!(log, "Could not import routing table entries"; "file" => format!("{:?}", path), "error" => e.to_string());
+                    Vec::new()
+                }
+            },
+            None => Vec::new(),
+        };
+
+        // Add bootnodes and imported entries to routing table
+        for bootnode_enr in config.boot_nodes.iter().cloned().chain(imported_enrs) {
+            if bootnode_enr.node_id() == local_enr.node_id() {
+                warn!(log, "Ignoring our own ENR in the boot nodes");
+                network_globals
+                    .self_dials_rejected
+                    .fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
             debug!(
                 log,
                 "Adding node to routing table";
@@ -207,10 +253,11 @@ impl Discovery {
         // Obtain the event stream
 
         Ok(Self {
-            cached_enrs: LruCache::new(50),
+            cached_enrs: LruCache::new(config.limits.cached_enrs),
             network_globals,
             find_peer_active: false,
             queued_queries: VecDeque::with_capacity(10),
+            matching_peers: VecDeque::new(),
             active_queries: FuturesUnordered::new(),
             discv5,
             event_stream,
@@ -240,8 +287,68 @@ impl Discovery {
         }
     }
 
+    /// Searches for up to `target` peers whose ENR matches `predicate`. Matching peers already in
+    /// the routing table are returned first and random walks are made for the rest, until enough
+    /// are found or the retries run out.
+    pub fn discover_matching_peers(&mut self, predicate: EnrPredicate, target: usize) {
+        if !self.started || target == 0 {
+            return;
+        }
+
+        let mut found = HashSet::new();
+        let known: Vec<Enr> = self
+            .discv5
+            .table_entries_enr()
+            .into_iter()
+            .filter(|enr| predicate.matches(enr) && found.insert(enr.peer_id()))
+            .take(target)
+            .collect();
+        let target = target - known.len();
+        if !known.is_empty() {
+            self.matching_peers.push_back(known);
+        }
+
+        if target > 0 {
+            trace!(self.log, "Queuing a predicate discovery request"; "target" => target);
+            self.queued_queries.push_back(QueryType::Predicate {
+                predicate,
+                target,
+                found,
+                retries: 0,
+            });
+        }
+    }
+
+    /// Searches for peers on a subnet, read from the `attnets` field of their ENR. The peers found
+    /// are returned as a query result, to be dialed. A search already queued for the subnet is
+    /// not queued again.
+    pub fn discover_subnet_peers(&mut self, subnet_id: SubnetId, min_ttl: Option<Instant>) {
+        if !self.started {
+            return;
+        }
+        let queued = self.queued_queries.iter().any(|query| match query {
+            QueryType::Subnet {
+                subnet_id: queued, ..
+            } => *queued == subnet_id,
+            _ => false,
+        });
+        if !queued {
+            trace!(self.log, "Queuing a subnet discovery request"; "subnet_id" => subnet_id);
+            self.queued_queries.push_back(QueryType::Subnet {
+                subnet_id,
+                min_ttl,
+                retries: 0,
+            });
+        }
+    }
+
     /// Add an ENR to the routing table of the discovery mechanism.
     pub fn add_enr(&mut self, enr: Enr) {
+        if self.is_local(&enr) {
+            warn!(self.log, "Ignoring our own ENR");
+            return;
+        }
+
         // add the enr to seen caches
         self.cached_enrs.put(enr.peer_id(), enr.clone());
 
@@ -259,6 +366,17 @@ impl Discovery {
         self.discv5.table_entries_enr()
     }
 
+    /// Returns the routing table entries and the discovery queries in progress.
+    pub fn snapshot(&mut self) -> DhtSnapshot {
+        let entries = self.discv5.table_entries_enr();
+        DhtSnapshot::new(
+            &self.discv5.local_enr().node_id(),
+            &entries,
+            self.active_queries.len(),
+            self.queued_queries.len(),
+        )
+    }
+
     /// Returns the ENR of a known peer if it exists.
     pub fn enr_of_peer(&mut self, peer_id: &PeerId) -> Option<Enr> {
         // first search the local cache
@@ -273,21 +391,74 @@ impl Discovery {
         }
     }
 
-    /// Updates the `eth2` field of our local ENR.
-    pub fn update_eth2_enr(&mut self, enr_fork_id: EnrForkId) {
-        let _ = self
+    /// Returns true if the ENR is our own, counting it as a rejected self dial.
+    fn is_local(&self, enr: &Enr) -> bool {
+        if enr.node_id() != self.discv5.local_enr().node_id() {
+            return false;
+        }
+        self.network_globals
+            .self_dials_rejected
+            .fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Removes our own ENR from discovery results. Behind a hairpin NAT, queries can return it.
+    fn remove_local(&self, enrs: &mut Vec<Enr>) {
+        let before = enrs.len();
+        enrs.retain(|enr| !self.is_local(enr));
+        if enrs.len() < before {
+            warn!(self.log, "Discovery returned our own ENR, ignoring it");
+        }
+    }
+
+    /// Updates the fork fields of our local ENR. A secondary fork that no longer fits in the
+    /// record is left out with a warning. Peers on the forks are searched for right away.
+    pub fn update_fork_enr(&mut self, forks: &ActiveForks) -> Result<Enr, String> {
+        self.discv5
+            .enr_insert(ETH2_ENR_KEY, forks.current().clone())
+            .map_err(|e| format!("Could not update eth2 ENR field: {:?}", e))?;
+        // the field cannot be removed, so a fork no longer served is left empty
+        let secondary = forks.secondary().cloned().unwrap_or_default();
+        let advertised = self
             .discv5
-            .enr_insert(ETH2_ENR_KEY, enr_fork_id)
-            .map_err(|e| {
-                warn!(
-                    self.log,
-                    "Could not update eth2 ENR field";
-                    "error" => format!("{:?}", e)
-                )
-            });
+            .local_enr()
+            .get(SECONDARY_FORK_ENR_KEY)
+            .is_some();
+        if !secondary.is_empty() || advertised {
+            if let Err(e) = self.discv5.enr_insert(SECONDARY_FORK_ENR_KEY, secondary) {
+                warn!(self.log, "The secondary fork id does not fit in the ENR, leaving it out";
+                    "error" => format!("{:?}", e));
+            }
+        }
 
         // replace the global version with discovery version
-        *self.network_globals.local_enr.write() = self.discv5.local_enr();
+        let enr = self.discv5.local_enr();
+        *self.network_globals.local_enr.write() = enr.clone();
+        enr::save_enr_to_disk(Path::new(&self.enr_dir), &enr, &self.log);
+        self.discover_peers();
+        Ok(enr)
+    }
+
+    /// Sets fields of our local ENR, which is republished under a new sequence number. With no
+    /// fields the record is left as it is. The fork fields are refused, as they are set by
+    /// `update_fork_enr`. Peers learn of the update the next time discv5 contacts them, starting
+    /// with a peer discovery made right away, while libp2p connections are unaffected.
+    pub fn update_enr(&mut self, fields: Vec<(String, Vec<u8>)>) -> Result<Enr, String> {
+        if fields.is_empty() {
+            return Ok(self.discv5.local_enr());
+        }
+        enr::check_enr_fields(&fields)?;
+        for (key, value) in fields {
+            self.discv5
+                .enr_insert(&key, value)
+                .map_err(|e| format!("Could not update ENR field {}: {:?}", key, e))?;
+        }
+
+        let enr = self.discv5.local_enr();
+        *self.network_globals.local_enr.write() = enr.clone();
+        enr::save_enr_to_disk(Path::new(&self.enr_dir), &enr, &self.log);
+        self.discover_peers();
+        Ok(enr)
     }
 
     /* Internal Functions */
@@ -314,7 +485,14 @@ impl Discovery {
                     self.find_peer_active = true;
                     self.start_query(QueryType::FindPeers, FIND_NODE_QUERY_CLOSEST_PEERS);
                 }
-                Some(QueryType::Subnet { .. }) => {}
+                Some(query @ QueryType::Predicate { .. }) => {
+                    debug!(self.log, "Searching for peers matching a predicate");
+                    self.start_query(query, FIND_NODE_QUERY_CLOSEST_PEERS);
+                }
+                Some(query @ QueryType::Subnet { .. }) => {
+                    debug!(self.log, "Searching for subnet peers");
+                    self.start_query(query, FIND_NODE_QUERY_CLOSEST_PEERS);
+                }
                 None => {} // Queue is empty
             }
         }
@@ -350,7 +528,7 @@ impl Discovery {
     }
 
     /// Drives the queries returning any results from completed queries.
-    fn poll_queries(&mut self, cx: &mut Context) -> Option<(Option<Instant>, Vec<Enr>)> {
+    fn poll_queries(&mut self, cx: &mut Context) -> Option<DiscoveryEvent> {
         while let Poll::Ready(Some(query_future)) = self.active_queries.poll_next_unpin(cx) {
             match query_future.0 {
                 QueryType::FindPeers => {
@@ -359,20 +537,83 @@ impl Discovery {
                         Ok(r) if r.is_empty() => {
                             debug!(self.log, "Discovery query yielded no results.");
                         }
-                        Ok(r) => {
+                        Ok(mut r) => {
+                            self.remove_local(&mut r);
                             debug!(self.log, "Discovery query completed"; "peers_found" => r.len());
-                            return Some((None, r));
+                            return Some(DiscoveryEvent::QueryResult(None, r));
                         }
                         Err(e) => {
                             warn!(self.log, "Discovery query failed"; "error" => e.to_string());
                         }
                     }
                 }
+                QueryType::Predicate {
+                    predicate,
+                    target,
+                    mut found,
+                    retries,
+                } => {
+                    let matching: Vec<Enr> = match query_future.1 {
+                        Ok(mut r) => {
+                            self.remove_local(&mut r);
+                            r.into_iter()
+                                .filter(|enr| predicate.matches(enr) && found.insert(enr.peer_id()))
+                                .take(target)
+                                .collect()
+                        }
+                        Err(e) => {
+                            warn!(self.log, "Predicate discovery query failed"; "error" => e.to_string());
+                            Vec::new()
+                        }
+                    };
+                    let target = target - matching.len();
+                    if target > 0 {
+                        if retries + 1 < MAX_DISCOVERY_RETRY {
+                            self.queued_queries.push_back(QueryType::Predicate {
+                                predicate,
+                                target,
+                                found,
+                                retries: retries + 1,
+                            });
+                        } else {
+                            debug!(self.log, "Predicate discovery ended without reaching its target";
+                                "missing_peers" => target);
+                        }
+                    }
+                    if !matching.is_empty() {
+                        debug!(self.log, "Predicate discovery query completed"; "peers_found" => matching.len());
+                        return Some(DiscoveryEvent::MatchingPeers(matching));
+                    }
+                }
                 QueryType::Subnet {
                     subnet_id,
                     min_ttl,
                     retries,
-                } => {}
+                } => {
+                    let predicate = EnrPredicate::on_subnet(subnet_id);
+                    let peers: Vec<Enr> = match query_future.1 {
+                        Ok(mut r) => {
+                            self.remove_local(&mut r);
+                            r.into_iter().filter(|enr| predicate.matches(enr)).collect()
+                        }
+                        Err(e) => {
+                            warn!(self.log, "Subnet discovery query failed"; "error" => e.to_string());
+                            Vec::new()
+                        }
+                    };
+                    if peers.len() < TARGET_SUBNET_PEERS && retries + 1 < MAX_DISCOVERY_RETRY {
+                        self.queued_queries.push_back(QueryType::Subnet {
+                            subnet_id,
+                            min_ttl,
+                            retries: retries + 1,
+                        });
+                    }
+                    if !peers.is_empty() {
+                        debug!(self.log, "Subnet discovery query completed";
+                            "subnet_id" => subnet_id, "peers_found" => peers.len());
+                        return Some(DiscoveryEvent::QueryResult(min_ttl, peers));
+                    }
+                }
             }
         }
         None
@@ -387,14 +628,23 @@ impl Discovery {
         // Process the query queue
         self.process_queue();
 
+        // Return the matching peers found in the routing table
+        if let Some(matching) = self.matching_peers.pop_front() {
+            return Poll::Ready(DiscoveryEvent::MatchingPeers(matching));
+        }
+
         // Drive the queries and return any results from completed queries
-        if let Some((min_ttl, result)) = self.poll_queries(cx) {
+        if let Some(event) = self.poll_queries(cx) {
             // cache the found ENR's
-            for enr in result.iter().cloned() {
-                self.cached_enrs.put(enr.peer_id(), enr);
+            if let DiscoveryEvent::QueryResult(_, result) | DiscoveryEvent::MatchingPeers(result) =
+                &event
+            {
+                for enr in result.iter().cloned() {
+                    self.cached_enrs.put(enr.peer_id(), enr);
+                }
             }
             // return the result to the peer manager
-            return Poll::Ready(DiscoveryEvent::QueryResult(min_ttl, result));
+            return Poll::Ready(event);
         }
 
         // Process the server event stream
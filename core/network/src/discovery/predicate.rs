@@ -0,0 +1,100 @@
+//! Filters on the fields of an ENR, used to discover peers with particular properties.
+use super::enr::{service_enr_key, BITFIELD_ENR_KEY};
+use crate::types::SubnetId;
+use crate::Enr;
+use serde_derive::{Deserialize, Serialize};
+
+/// A condition on an ENR. Predicates are plain data so applications can build and serialize them.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum EnrPredicate {
+    /// Matches every ENR.
+    Any,
+    /// The ENR has a field with this key.
+    HasKey(String),
+    /// The field with this key holds exactly this value.
+    Equals { key: String, value: Vec<u8> },
+    /// The field with this key is a bitfield with this bit set. Bits are numbered from the least
+    /// significant bit of the first byte, as in the `attnets` field.
+    BitSet { key: String, bit: usize },
+    /// The ENR advertises a TCP port, so the peer can be dialed.
+    HasTcp,
+    /// Every predicate matches.
+    All(Vec<EnrPredicate>),
+    /// At least one predicate matches.
+    AnyOf(Vec<EnrPredicate>),
+    /// The predicate does not match.
+    Not(Box<EnrPredicate>),
+}
+
+impl EnrPredicate {
+    /// Matches the ENRs whose `attnets` field has the bit of a subnet set.
+    pub fn on_subnet(subnet_id: SubnetId) -> Self {
+        EnrPredicate::BitSet {
+            key: BITFIELD_ENR_KEY.into(),
+            bit: subnet_id as usize,
+        }
+    }
+
+    /// Matches the ENRs advertising a record for the service `service_key`.
+    pub fn provides(service_key: &str) -> Self {
+        EnrPredicate::HasKey(service_enr_key(service_key))
+    }
+
+    /// Returns true if the ENR meets the predicate.
+    pub fn matches(&self, enr: &Enr) -> bool {
+        match self {
+            EnrPredicate::Any => true,
+            EnrPredicate::HasKey(key) => enr.get(key.as_str()).is_some(),
+            EnrPredicate::Equals { key, value } => enr
+                .get(key.as_str())
+                .map_or(false, |field| field[..] == value[..]),
+            EnrPredicate::BitSet { key, bit } => enr
+                .get(key.as_str())
+                .and_then(|field| field.get(bit / 8).copied())
+                .map_or(false, |byte| byte & (1 << (bit % 8)) != 0),
+            EnrPredicate::HasTcp => enr.tcp().is_some(),
+            EnrPredicate::All(predicates) => predicates.iter().all(|p| p.matches(enr)),
+            EnrPredicate::AnyOf(predicates) => predicates.iter().any(|p| p.matches(enr)),
+            EnrPredicate::Not(predicate) => !predicate.matches(enr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::{generate_enr, resign_enr, EnrUpdate};
+    use libp2p::core::identity::Keypair;
+
+    #[test]
+    fn test_predicates() {
+        let keypair = Keypair::generate_secp256k1();
+        let enr = generate_enr(&keypair, None, None, Some(9000)).unwrap();
+        let update = EnrUpdate {
+            fields: vec![("services".into(), vec![0b0000_0100, 0b0000_0001])],
+            ..Default::default()
+        };
+        let enr = resign_enr(&enr, &keypair, update).unwrap();
+
+        let service = |bit| EnrPredicate::BitSet {
+            key: "services".into(),
+            bit,
+        };
+        assert!(service(2).matches(&enr));
+        assert!(service(8).matches(&enr));
+        assert!(!service(3).matches(&enr));
+        assert!(!service(64).matches(&enr));
+
+        assert!(EnrPredicate::HasKey("services".into()).matches(&enr));
+        assert!(!EnrPredicate::HasKey("other".into()).matches(&enr));
+        assert!(EnrPredicate::Equals {
+            key: "services".into(),
+            value: vec![0b0000_0100, 0b0000_0001],
+        }
+        .matches(&enr));
+        assert!(EnrPredicate::All(vec![EnrPredicate::HasTcp, service(2)]).matches(&enr));
+        assert!(!EnrPredicate::All(vec![EnrPredicate::HasTcp, service(3)]).matches(&enr));
+        assert!(EnrPredicate::AnyOf(vec![service(3), service(8)]).matches(&enr));
+        assert!(EnrPredicate::Not(Box::new(service(3))).matches(&enr));
+    }
+}
@@ -0,0 +1,141 @@
+//! A view of the discovery routing table, for diagnosing discovery.
+//!
+//! The ENRs of a snapshot can be exported to a file, which a new node of the same deployment
+//! imports on startup to fill its routing table without waiting for queries through the boot
+//! nodes. The file holds one ENR in base64 per line.
+use crate::Enr;
+use discv5::enr::NodeId;
+use serde_derive::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, prelude::*};
+use std::path::Path;
+use std::str::FromStr;
+
+/// The entries of the routing table, how they fill its buckets, and the queries in progress.
+#[derive(Clone, Debug, Serialize)]
+pub struct DhtSnapshot {
+    /// The ENRs in the routing table, in base64.
+    pub entries: Vec<String>,
+    /// The number of entries in each non-empty bucket, by log2 distance from the local node.
+    pub buckets: BTreeMap<u32, usize>,
+    /// The number of discovery queries running.
+    pub active_queries: usize,
+    /// The number of discovery queries waiting for a running query to finish.
+    pub queued_queries: usize,
+}
+
+impl DhtSnapshot {
+    pub fn new(
+        local_node_id: &NodeId,
+        entries: &[Enr],
+        active_queries: usize,
+        queued_queries: usize,
+    ) -> Self {
+        let mut buckets = BTreeMap::new();
+        for enr in entries {
+            if let Some(distance) = log2_distance(&local_node_id.raw(), &enr.node_id().raw()) {
+                *buckets.entry(distance).or_default() += 1;
+            }
+        }
+        DhtSnapshot {
+            entries: entries.iter().map(|enr| enr.to_base64()).collect(),
+            buckets,
+            active_queries,
+            queued_queries,
+        }
+    }
+
+    /// Writes the ENRs of the routing table to `path`, replacing the file.
+    pub fn export(&self, path: &Path) -> io::Result<()> {
+        let contents: String = self
+            .entries
+            .iter()
+            .map(|enr| format!("{}\n", enr))
+            .collect();
+        File::create(path).and_then(|mut f| f.write_all(contents.as_bytes()))
+    }
+}
+
+/// Reads the ENRs of a file written by `DhtSnapshot::export`. Lines that are not ENRs are skipped.
+pub fn import_enrs(path: &Path) -> io::Result<Vec<Enr>> {
+    let mut contents = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut contents))?;
+    Ok(contents
+        .lines()
+        .filter_map(|line| Enr::from_str(line.trim()).ok())
+        .collect())
+}
+
+/// One line per query count, bucket and entry.
+impl fmt::Display for DhtSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "queries {} {}", self.active_queries, self.queued_queries)?;
+        for (distance, count) in &self.buckets {
+            writeln!(f, "bucket {} {}", distance, count)?;
+        }
+        for enr in &self.entries {
+            writeln!(f, "enr {}", enr)?;
+        }
+        Ok(())
+    }
+}
+
+/// The index of the bucket a node id falls in: the position of the highest bit in which it
+/// differs from the local node id, counting from 1. `None` for the local node id itself.
+fn log2_distance(local: &[u8; 32], other: &[u8; 32]) -> Option<u32> {
+    local
+        .iter()
+        .zip(other.iter())
+        .position(|(a, b)| a != b)
+        .map(|i| {
+            let xor = local[i] ^ other[i];
+            (32 - i as u32) * 8 - xor.leading_zeros()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::generate_enr;
+    use libp2p::core::identity::Keypair;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_export_and_import() {
+        let enr = || generate_enr(&Keypair::generate_secp256k1(), None, None, Some(9000)).unwrap();
+        let local = enr();
+        let entries = vec![enr(), enr()];
+        let snapshot = DhtSnapshot::new(&local.node_id(), &entries, 1, 0);
+        assert_eq!(snapshot.buckets.values().sum::<usize>(), 2);
+
+        let dir = TempDir::new("dht_snapshot").unwrap();
+        let path = dir.path().join("dht.txt");
+        assert!(import_enrs(&path).is_err());
+        snapshot.export(&path).unwrap();
+
+        // a corrupted line is skipped
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(b"not-an-enr\n").unwrap();
+
+        assert_eq!(import_enrs(&path).unwrap(), entries);
+    }
+
+    #[test]
+    fn test_log2_distance() {
+        let local = [0u8; 32];
+        assert_eq!(log2_distance(&local, &local), None);
+
+        let mut other = [0u8; 32];
+        other[31] = 1;
+        assert_eq!(log2_distance(&local, &other), Some(1));
+        other[31] = 0b1010;
+        assert_eq!(log2_distance(&local, &other), Some(4));
+        other[0] = 0x80;
+        assert_eq!(log2_distance(&local, &other), Some(256));
+    }
+}
@@ -1,24 +1,103 @@
+use futures::channel::oneshot;
 use futures::prelude::*;
 use slog::{debug, trace};
+use std::pin::Pin;
+use std::sync::Arc;
 use tokio::runtime::Handle;
 
-/// A wrapper over a runtime handle which can spawn async and blocking tasks.
+/// A task handed to a `Spawn`.
+pub type BoxedTask = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Runs the tasks of the network service on a runtime.
+///
+/// It is implemented for a tokio `Handle`. Embedders driving their own runtime implement it, or
+/// use `TaskExecutor::from_fn`, to keep the tasks on that runtime. The tasks use tokio 0.2 timers
+/// and sockets, so they must be polled where a tokio reactor is entered, for instance through
+/// `Handle::enter`.
+pub trait Spawn: Send + Sync {
+    /// Runs a task to completion in the background.
+    fn spawn(&self, task: BoxedTask);
+
+    /// Runs a closure that may block on a thread of its own. The returned future completes with
+    /// the closure. By default each closure gets a new thread.
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) -> BoxedTask {
+        let (done_tx, done_rx) = oneshot::channel();
+        std::thread::spawn(move || {
+            task();
+            let _ = done_tx.send(());
+        });
+        Box::pin(done_rx.map(|_| ()))
+    }
+}
+
+impl Spawn for Handle {
+    fn spawn(&self, task: BoxedTask) {
+        Handle::spawn(self, task);
+    }
+
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) -> BoxedTask {
+        Box::pin(Handle::spawn_blocking(self, task).map(|_| ()))
+    }
+}
+
+/// A `Spawn` made of a spawn function.
+struct SpawnFn<F>(F);
+
+impl<F> Spawn for SpawnFn<F>
+where
+    F: Fn(BoxedTask) + Send + Sync,
+{
+    fn spawn(&self, task: BoxedTask) {
+        (self.0)(task)
+    }
+}
+
+/// Spawns async and blocking tasks that are shut down when the exit future fires.
 #[derive(Clone)]
 pub struct TaskExecutor {
-    /// The handle to the runtime on which tasks are spawned
-    pub(crate) handle: Handle,
+    /// Where tasks are spawned.
+    pub(crate) spawner: Arc<dyn Spawn>,
+    /// The runtime handle the executor was created with, if any.
+    pub(crate) handle: Option<Handle>,
     /// The receiver exit future which on receiving shuts down the task
     pub(crate) exit: exit_future::Exit,
     pub(crate) log: slog::Logger,
 }
 
 impl TaskExecutor {
-    /// Create a new task executor.
+    /// Create a new task executor spawning on a tokio runtime.
     pub fn new(handle: Handle, exit: exit_future::Exit, log: slog::Logger) -> Self {
-        Self { handle, exit, log }
+        Self {
+            spawner: Arc::new(handle.clone()),
+            handle: Some(handle),
+            exit,
+            log,
+        }
     }
 
-    /// Spawn a future on the tokio runtime wrapped in an `exit_future::Exit`. The task is canceled
+    /// Create a new task executor spawning with `spawner`.
+    pub fn with_spawner(
+        spawner: Arc<dyn Spawn>,
+        exit: exit_future::Exit,
+        log: slog::Logger,
+    ) -> Self {
+        Self {
+            spawner,
+            handle: None,
+            exit,
+            log,
+        }
+    }
+
+    /// Create a new task executor spawning with a function. Blocking tasks get a thread each.
+    pub fn from_fn<F>(spawn: F, exit: exit_future::Exit, log: slog::Logger) -> Self
+    where
+        F: Fn(BoxedTask) + Send + Sync + 'static,
+    {
+        Self::with_spawner(Arc::new(SpawnFn(spawn)), exit, log)
+    }
+
+    /// Spawn a future on the runtime wrapped in an `exit_future::Exit`. The task is canceled
     /// when the corresponding exit_future `Signal` is fired/dropped.
     ///
     /// This function generates prometheus metrics on number of tasks and task duration.
@@ -36,10 +115,10 @@ impl TaskExecutor {
 
             futures::future::ready(())
         });
-        self.handle.spawn(future);
+        self.spawner.spawn(Box::pin(future));
     }
 
-    /// Spawn a future on the tokio runtime. This function does not wrap the task in an `exit_future::Exit`
+    /// Spawn a future on the runtime. This function does not wrap the task in an `exit_future::Exit`
     /// like [spawn](#method.spawn).
     /// The caller of this function is responsible for wrapping up the task with an `exit_future::Exit` to  
     /// ensure that the task gets canceled appropriately.
@@ -52,19 +131,19 @@ impl TaskExecutor {
         name: &'static str,
     ) {
         let future = task.then(move |_| futures::future::ready(()));
-        self.handle.spawn(future);
+        self.spawner.spawn(Box::pin(future));
     }
 
-    /// Spawn a blocking task on a dedicated tokio thread pool wrapped in an exit future.
+    /// Spawn a blocking task on a thread where it may block, wrapped in an exit future.
     pub fn spawn_blocking<F>(&self, task: F, name: &'static str)
     where
         F: FnOnce() -> () + Send + 'static,
     {
         let exit = self.exit.clone();
         let log = self.log.clone();
-        let join_handle = self.handle.spawn_blocking(task);
+        let done = self.spawner.spawn_blocking(Box::new(task));
 
-        let future = future::select(join_handle, exit).then(move |either| {
+        let future = future::select(done, exit).then(move |either| {
             match either {
                 future::Either::Left(_) => trace!(log, "Blocking task completed"; "task" => name),
                 future::Either::Right(_) => {
@@ -73,12 +152,14 @@ impl TaskExecutor {
             }
             futures::future::ready(())
         });
-        self.handle.spawn(future);
+        self.spawner.spawn(Box::pin(future));
     }
 
-    /// Returns the underlying runtime handle.
+    /// Returns the underlying runtime handle. Executors created with a spawner of their own return
+    /// the handle of the runtime the caller runs on, and panic outside of one.
+    #[deprecated(note = "executors may spawn on runtimes other than tokio; use the spawn methods")]
     pub fn runtime_handle(&self) -> Handle {
-        self.handle.clone()
+        self.handle.clone().unwrap_or_else(Handle::current)
     }
 
     /// Returns a copy of the `exit_future::Exit`.
@@ -97,3 +178,53 @@ impl discv5::Executor for TaskExecutor {
         self.spawn(future, "discv5")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    /// An executor that queues its tasks for the test to run.
+    fn queueing_executor(exit: exit_future::Exit) -> (TaskExecutor, Arc<Mutex<Vec<BoxedTask>>>) {
+        let tasks = Arc::new(Mutex::new(Vec::new()));
+        let queue = tasks.clone();
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let spawn = move |task: BoxedTask| queue.lock().unwrap().push(task);
+        (TaskExecutor::from_fn(spawn, exit, log), tasks)
+    }
+
+    #[test]
+    fn test_spawn_fn() {
+        let (signal, exit) = exit_future::signal();
+        let (executor, tasks) = queueing_executor(exit);
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let counter = runs.clone();
+        executor.spawn(
+            async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+            },
+            "task",
+        );
+        // blocking tasks get a thread, and are awaited by a task of the spawner
+        let counter = runs.clone();
+        executor.spawn_blocking(
+            move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            },
+            "blocking",
+        );
+        for task in tasks.lock().unwrap().drain(..) {
+            block_on(task);
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+
+        // tasks are shut down when the exit future fires
+        executor.spawn(future::pending(), "pending");
+        signal.fire().unwrap();
+        let task = tasks.lock().unwrap().pop().unwrap();
+        block_on(task);
+    }
+}
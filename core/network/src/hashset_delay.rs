@@ -5,6 +5,7 @@
 /// entries.
 const DEFAULT_DELAY: u64 = 30;
 
+use crate::clock;
 use futures::prelude::*;
 use std::{
     collections::HashMap,
@@ -71,7 +72,7 @@ where
             let delay_key = self.expirations.insert(key.clone(), entry_duration.clone());
             let entry = MapEntry {
                 key: delay_key,
-                value: Instant::now() + entry_duration,
+                value: clock::now() + entry_duration,
             };
             self.entries.insert(key, entry);
         }
@@ -0,0 +1,222 @@
+//! Management of the node's identity key, which is shared by libp2p and discv5.
+//!
+//! The key is stored in the network directory under `NETWORK_KEY_FILENAME`. A secp256k1 key is
+//! stored as its 32 byte secret, an ed25519 key as its 64 byte secret and public key pair, so the
+//! length of the file identifies the key type.
+use crate::types::error;
+use libp2p::core::identity::{ed25519, secp256k1, Keypair};
+use libp2p::PeerId;
+use serde_derive::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+use std::str::FromStr;
+
+/// The file name of the node's key inside the network directory.
+pub const NETWORK_KEY_FILENAME: &str = "key";
+
+/// The key types supported by both libp2p and discv5.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum KeyType {
+    Secp256k1,
+    Ed25519,
+}
+
+impl Default for KeyType {
+    fn default() -> Self {
+        KeyType::Secp256k1
+    }
+}
+
+impl FromStr for KeyType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "secp256k1" => Ok(KeyType::Secp256k1),
+            "ed25519" => Ok(KeyType::Ed25519),
+            _ => Err(format!("Unsupported key type: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for KeyType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyType::Secp256k1 => write!(f, "secp256k1"),
+            KeyType::Ed25519 => write!(f, "ed25519"),
+        }
+    }
+}
+
+/// Returns the type of a keypair, if it is one mothra supports.
+pub fn key_type(keypair: &Keypair) -> Option<KeyType> {
+    match keypair {
+        Keypair::Secp256k1(_) => Some(KeyType::Secp256k1),
+        Keypair::Ed25519(_) => Some(KeyType::Ed25519),
+        _ => None,
+    }
+}
+
+/// Generates a new keypair of the given type.
+pub fn generate_keypair(key_type: KeyType) -> Keypair {
+    match key_type {
+        KeyType::Secp256k1 => Keypair::generate_secp256k1(),
+        KeyType::Ed25519 => Keypair::generate_ed25519(),
+    }
+}
+
+/// Parses a hex encoded secret key of the given type, with or without a `0x` prefix.
+pub fn keypair_from_hex(hex_bytes: &str, key_type: KeyType) -> error::Result<Keypair> {
+    let hex_bytes = if hex_bytes.starts_with("0x") {
+        hex_bytes[2..].to_string()
+    } else {
+        hex_bytes.to_string()
+    };
+
+    hex::decode(&hex_bytes)
+        .map_err(|e| format!("Failed to parse p2p secret key bytes: {:?}", e).into())
+        .and_then(|bytes| keypair_from_bytes(bytes, key_type))
+}
+
+/// Builds a keypair from the raw bytes of a secret key of the given type.
+pub fn keypair_from_bytes(mut bytes: Vec<u8>, key_type: KeyType) -> error::Result<Keypair> {
+    match key_type {
+        KeyType::Secp256k1 => secp256k1::SecretKey::from_bytes(&mut bytes)
+            .map(|secret| Keypair::Secp256k1(secret.into()))
+            .map_err(|e| format!("Unable to parse secp256k1 secret key: {:?}", e).into()),
+        KeyType::Ed25519 => ed25519::SecretKey::from_bytes(&mut bytes)
+            .map(|secret| Keypair::Ed25519(secret.into()))
+            .map_err(|e| format!("Unable to parse ed25519 secret key: {:?}", e).into()),
+    }
+}
+
+/// Parses a key given in the config. A different key stored in `network_dir` is refused, as the
+/// configured key would silently change the identity of the node.
+pub fn configured_keypair(
+    network_dir: &Path,
+    hex_bytes: &str,
+    key_type: KeyType,
+) -> error::Result<Keypair> {
+    let keypair = keypair_from_hex(hex_bytes, key_type)?;
+    let key_file = network_dir.join(NETWORK_KEY_FILENAME);
+    if let Ok(stored) = load_keypair_file(&key_file) {
+        if stored.public() != keypair.public() {
+            return Err(format!(
+                "The configured network key differs from the key stored at {:?}",
+                key_file
+            )
+            .into());
+        }
+    }
+    Ok(keypair)
+}
+
+/// Reads a key file written by `save_keypair_file`.
+pub fn load_keypair_file(path: &Path) -> error::Result<Keypair> {
+    let mut key_bytes: Vec<u8> = Vec::with_capacity(64);
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut key_bytes))
+        .map_err(|e| format!("Could not read network key file {:?}: {}", path, e))?;
+    match key_bytes.len() {
+        32 => keypair_from_bytes(key_bytes, KeyType::Secp256k1),
+        64 => ed25519::Keypair::decode(&mut key_bytes)
+            .map(Keypair::Ed25519)
+            .map_err(|e| format!("Unable to parse ed25519 keypair: {:?}", e).into()),
+        len => Err(format!("Network key file has an invalid length: {}", len).into()),
+    }
+}
+
+/// Writes a keypair to `path`, creating the parent directory if needed.
+pub fn save_keypair_file(path: &Path, keypair: &Keypair) -> error::Result<()> {
+    let bytes = match keypair {
+        Keypair::Secp256k1(key) => key.secret().to_bytes().to_vec(),
+        Keypair::Ed25519(key) => key.encode().to_vec(),
+        _ => return Err("Unsupported key type".into()),
+    };
+    if let Some(dir) = path.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    File::create(path)
+        .and_then(|mut f| f.write_all(&bytes))
+        .map_err(|e| format!("Could not write network key file {:?}: {}", path, e).into())
+}
+
+/// Generates a new key of the given type and stores it in `network_dir`.
+///
+/// An existing key is only replaced if `overwrite` is set, as replacing it changes the node's
+/// identity.
+pub fn generate_key(
+    network_dir: &Path,
+    key_type: KeyType,
+    overwrite: bool,
+) -> error::Result<PeerId> {
+    store_key(network_dir, generate_keypair(key_type), overwrite)
+}
+
+/// Imports a hex encoded secret key of the given type into `network_dir`.
+///
+/// An existing key is only replaced if `overwrite` is set.
+pub fn import_key(
+    network_dir: &Path,
+    hex_bytes: &str,
+    key_type: KeyType,
+    overwrite: bool,
+) -> error::Result<PeerId> {
+    store_key(
+        network_dir,
+        keypair_from_hex(hex_bytes, key_type)?,
+        overwrite,
+    )
+}
+
+/// Returns the `PeerId` of the key stored in `network_dir`.
+pub fn export_peer_id(network_dir: &Path) -> error::Result<PeerId> {
+    load_keypair_file(&network_dir.join(NETWORK_KEY_FILENAME))
+        .map(|keypair| keypair.public().into_peer_id())
+}
+
+fn store_key(network_dir: &Path, keypair: Keypair, overwrite: bool) -> error::Result<PeerId> {
+    let key_file = network_dir.join(NETWORK_KEY_FILENAME);
+    if key_file.exists() && !overwrite {
+        return Err(format!("A network key already exists at {:?}", key_file).into());
+    }
+    save_keypair_file(&key_file, &keypair)?;
+    Ok(keypair.public().into_peer_id())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_file_round_trip() {
+        let dir = tempdir::TempDir::new("mothra_keys").unwrap();
+        for kind in &[KeyType::Secp256k1, KeyType::Ed25519] {
+            let peer_id = generate_key(dir.path(), *kind, true).unwrap();
+            assert_eq!(export_peer_id(dir.path()).unwrap(), peer_id);
+            let keypair = load_keypair_file(&dir.path().join(NETWORK_KEY_FILENAME)).unwrap();
+            assert_eq!(key_type(&keypair), Some(*kind));
+        }
+    }
+
+    #[test]
+    fn test_configured_key_conflict() {
+        let dir = tempdir::TempDir::new("mothra_keys").unwrap();
+        let hex_bytes = hex::encode([1u8; 32]);
+        let configured = |dir: &Path| configured_keypair(dir, &hex_bytes, KeyType::Secp256k1);
+        assert!(configured(dir.path()).is_ok());
+        // the same key on disk is no conflict
+        import_key(dir.path(), &hex_bytes, KeyType::Secp256k1, false).unwrap();
+        assert!(configured(dir.path()).is_ok());
+        generate_key(dir.path(), KeyType::Secp256k1, true).unwrap();
+        assert!(configured(dir.path()).is_err());
+    }
+
+    #[test]
+    fn test_no_overwrite() {
+        let dir = tempdir::TempDir::new("mothra_keys").unwrap();
+        generate_key(dir.path(), KeyType::Secp256k1, false).unwrap();
+        assert!(generate_key(dir.path(), KeyType::Ed25519, false).is_err());
+    }
+}
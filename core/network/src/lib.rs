@@ -1,24 +1,53 @@
 pub mod behaviour;
+mod clock;
 mod config;
 mod discovery;
 mod executor;
 mod hashset_delay;
+pub mod keys;
+mod limits;
+mod log_context;
 mod peer_manager;
 pub mod rpc;
 mod service;
+#[cfg(feature = "simulation")]
+pub mod simulation;
+#[doc(hidden)]
+pub mod testing;
+mod tls;
 pub mod types;
 
-pub use crate::types::{error, Enr, EnrBitfield, EnrForkId, GossipTopic, NetworkGlobals};
-pub use behaviour::{BehaviourEvent, PeerRequestId, Request, Response};
-pub use config::unused_port;
+pub use crate::types::{
+    error, ActiveForks, BridgeTransform, BufferPool, BufferPoolStats, ControlCounts, ControlStats,
+    DecodedTopic, Enr, EnrBitfield, EnrForkId, GossipContribution, GossipTopic, Histogram,
+    MemoryUsage, MemoryUsageSnapshot, MessageTransform, MessageTransforms, NetworkGlobals,
+    NetworkGlobalsParams, PingHandler, PingPayloadFn, PingReceivedFn, QueueDepths,
+    QueueDepthsSnapshot, SizeHistogram, Stats, StatsSnapshot, TopicBridges, TopicCipher,
+    TopicEncryption, TopicKey, TopicValidator, TopicValidatorFn, UnsubscribedTopicPolicy,
+    FORK_PLACEHOLDER, TOPIC_KEY_LENGTH,
+};
+#[cfg(feature = "kad")]
+pub use behaviour::KadReply;
+pub use behaviour::{BehaviourEvent, Overload, PeerRequestId, Request, Response};
 pub use config::Config as NetworkConfig;
-pub use discovery::{CombinedKeyExt, EnrExt};
+pub use config::{unused_port, KeepAlivePolicy};
+pub use discovery::{
+    decode_enr, enr_fork_ids, enr_services, generate_enr, resign_enr, service_enr_key, shares_fork,
+    CombinedKeyExt, DhtSnapshot, EnrExt, EnrInfo, EnrPredicate, EnrUpdate,
+};
 pub use discv5;
-pub use executor::TaskExecutor;
+pub use executor::{BoxedTask, Spawn, TaskExecutor};
 pub use hashset_delay::HashSetDelay;
+pub use keys::{KeyType, NETWORK_KEY_FILENAME};
 pub use libp2p::gossipsub::{MessageId, Topic, TopicHash};
-pub use libp2p::{core::ConnectedPoint, PeerId, Swarm};
+pub use libp2p::identify::IdentifyInfo;
+pub use libp2p::{core::identity::Keypair, core::ConnectedPoint, PeerId, Swarm};
 pub use libp2p::{multiaddr, Multiaddr};
-pub use peer_manager::{client::Client, PeerDB, PeerInfo};
-pub use service::{Libp2pEvent, Service, NETWORK_KEY_FILENAME};
+pub use limits::Limits;
+pub use peer_manager::{
+    client::Client, Capabilities, CapabilityField, CapabilityFields, ConnectionDirection,
+    ConnectionState, IdentityVerifier, PeerAction, PeerDB, PeerInfo, PeerQuery, PeerQueryResult,
+    PeerScore, PeerScoreSnapshot, ScoreSource, SubnetPeerCount, MAX_CAPABILITY_BYTES,
+};
+pub use service::{Libp2pEvent, Service};
 pub const DEFAULT_CLIENT_NAME: &str = "mothra";
@@ -0,0 +1,289 @@
+//! The sizes of the network's caches and queues, and the timeouts of its requests and dials.
+//!
+//! Every bound that keeps the memory or the work of a node in check is gathered here, so
+//! operators can review and tune them in one place, and tests can shrink them to reach the bounds
+//! quickly. They are set in `NetworkConfig::limits`, by name with `Limits::set`, and checked with
+//! `Limits::validate` when the network service starts. Timeouts have millisecond precision by
+//! name, and any precision through their fields.
+use serde_derive::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// The default of `Limits::max_identify_addresses`.
+pub(crate) const DEFAULT_MAX_IDENTIFY_ADDRESSES: usize = 10;
+
+/// The limits that are durations.
+const DURATIONS: [&str; 5] = [
+    "dial_timeout",
+    "rpc_ttfb_timeout",
+    "rpc_request_timeout",
+    "rpc_response_timeout",
+    "rpc_shutdown_timeout",
+];
+
+/// The limits of the network.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Limits {
+    /// The ids of the messages published directly, remembered to drop copies relayed back to us.
+    pub direct_message_ids: usize,
+    /// The ids of the gossip messages received, remembered to tell first deliveries from
+    /// duplicates.
+    pub received_message_ids: usize,
+    /// The ids of the messages bridged, by this node or others, remembered to deliver the copies
+    /// of a message bridged by several nodes once.
+    pub bridged_message_ids: usize,
+    /// The received messages whose topic is remembered until they are forwarded.
+    pub forwarded_message_topics: usize,
+    /// The forwards waiting for the budget of their class, per class. The oldest are dropped.
+    pub queued_forwards: usize,
+    /// The ENRs of peers found by discovery, kept for dialing them.
+    pub cached_enrs: usize,
+    /// The peers found through rendezvous points whose addresses are kept.
+    pub rendezvous_peers: usize,
+    /// The namespaces a rendezvous point keeps registrations under.
+    pub served_rendezvous_namespaces: usize,
+    /// The registrations a rendezvous point keeps per namespace.
+    pub rendezvous_registrations: usize,
+    /// The namespaces a rendezvous point keeps a single node registered under.
+    pub rendezvous_namespaces_per_peer: usize,
+    /// The peers waiting to be dialed. The lowest priority are dropped.
+    pub queued_dials: usize,
+    /// The time after which a pending dial no longer counts towards `max_pending_dials`.
+    pub dial_timeout: Duration,
+    /// The listen addresses stored for a peer from identify. Addresses beyond this are dropped.
+    pub max_identify_addresses: usize,
+    /// The peers kept on disk to be dialed again on startup.
+    pub stored_peers: usize,
+    /// The simultaneous connections to a single peer. Allowing more than one, simultaneous dials
+    /// are resolved by the peer manager rather than by whichever connection the swarm rejects.
+    pub max_connections_per_peer: usize,
+    /// The maximum size of an RPC request or response chunk, before compression.
+    pub max_rpc_size: usize,
+    /// The time allowed for the first byte of a request to arrive.
+    pub rpc_ttfb_timeout: Duration,
+    /// The time allowed for a whole request to arrive.
+    pub rpc_request_timeout: Duration,
+    /// The time allowed for each response to a request, and for the response to a received
+    /// request to be sent.
+    pub rpc_response_timeout: Duration,
+    /// The time the RPC has to finish its substreams once a connection shuts down.
+    pub rpc_shutdown_timeout: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            direct_message_ids: 1024,
+            received_message_ids: 8192,
+            bridged_message_ids: 4096,
+            forwarded_message_topics: 8192,
+            queued_forwards: 1024,
+            cached_enrs: 50,
+            rendezvous_peers: 1024,
+            served_rendezvous_namespaces: 64,
+            rendezvous_registrations: 1000,
+            rendezvous_namespaces_per_peer: 4,
+            queued_dials: 256,
+            dial_timeout: Duration::from_secs(30),
+            max_identify_addresses: DEFAULT_MAX_IDENTIFY_ADDRESSES,
+            stored_peers: 256,
+            max_connections_per_peer: 1,
+            max_rpc_size: 1_048_576,
+            rpc_ttfb_timeout: Duration::from_secs(5),
+            rpc_request_timeout: Duration::from_secs(15),
+            rpc_response_timeout: Duration::from_secs(10),
+            rpc_shutdown_timeout: Duration::from_secs(15),
+        }
+    }
+}
+
+impl Limits {
+    /// The limits by name, counts as they are and durations in milliseconds.
+    pub fn values(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            ("direct_message_ids", self.direct_message_ids as u64),
+            ("received_message_ids", self.received_message_ids as u64),
+            ("bridged_message_ids", self.bridged_message_ids as u64),
+            (
+                "forwarded_message_topics",
+                self.forwarded_message_topics as u64,
+            ),
+            ("queued_forwards", self.queued_forwards as u64),
+            ("cached_enrs", self.cached_enrs as u64),
+            ("rendezvous_peers", self.rendezvous_peers as u64),
+            (
+                "served_rendezvous_namespaces",
+                self.served_rendezvous_namespaces as u64,
+            ),
+            (
+                "rendezvous_registrations",
+                self.rendezvous_registrations as u64,
+            ),
+            (
+                "rendezvous_namespaces_per_peer",
+                self.rendezvous_namespaces_per_peer as u64,
+            ),
+            ("queued_dials", self.queued_dials as u64),
+            ("dial_timeout", millis(self.dial_timeout)),
+            ("max_identify_addresses", self.max_identify_addresses as u64),
+            ("stored_peers", self.stored_peers as u64),
+            (
+                "max_connections_per_peer",
+                self.max_connections_per_peer as u64,
+            ),
+            ("max_rpc_size", self.max_rpc_size as u64),
+            ("rpc_ttfb_timeout", millis(self.rpc_ttfb_timeout)),
+            ("rpc_request_timeout", millis(self.rpc_request_timeout)),
+            ("rpc_response_timeout", millis(self.rpc_response_timeout)),
+            ("rpc_shutdown_timeout", millis(self.rpc_shutdown_timeout)),
+        ]
+    }
+
+    /// Sets a limit by name, a count or a duration in milliseconds.
+    pub fn set(&mut self, name: &str, value: u64) -> Result<(), String> {
+        let count = value as usize;
+        let millis = Duration::from_millis(value);
+        match name {
+            "direct_message_ids" => self.direct_message_ids = count,
+            "received_message_ids" => self.received_message_ids = count,
+            "bridged_message_ids" => self.bridged_message_ids = count,
+            "forwarded_message_topics" => self.forwarded_message_topics = count,
+            "queued_forwards" => self.queued_forwards = count,
+            "cached_enrs" => self.cached_enrs = count,
+            "rendezvous_peers" => self.rendezvous_peers = count,
+            "served_rendezvous_namespaces" => self.served_rendezvous_namespaces = count,
+            "rendezvous_registrations" => self.rendezvous_registrations = count,
+            "rendezvous_namespaces_per_peer" => self.rendezvous_namespaces_per_peer = count,
+            "queued_dials" => self.queued_dials = count,
+            "dial_timeout" => self.dial_timeout = millis,
+            "max_identify_addresses" => self.max_identify_addresses = count,
+            "stored_peers" => self.stored_peers = count,
+            "max_connections_per_peer" => self.max_connections_per_peer = count,
+            "max_rpc_size" => self.max_rpc_size = count,
+            "rpc_ttfb_timeout" => self.rpc_ttfb_timeout = millis,
+            "rpc_request_timeout" => self.rpc_request_timeout = millis,
+            "rpc_response_timeout" => self.rpc_response_timeout = millis,
+            "rpc_shutdown_timeout" => self.rpc_shutdown_timeout = millis,
+            _ => return Err(format!("Unknown limit: {}", name)),
+        }
+        Ok(())
+    }
+
+    /// Sets a limit from a `name=value` string, for example `queued_dials=512`. Durations are in
+    /// seconds, or in milliseconds with an `ms` suffix, as in `rpc_ttfb_timeout=500ms`.
+    pub fn set_from_str(&mut self, s: &str) -> Result<(), String> {
+        let mut parts = s.splitn(2, '=');
+        let name = parts.next().unwrap_or_default();
+        let value = parts.next().unwrap_or_default();
+        let invalid = || format!("Invalid limit: {}", s);
+        let unit_start = value
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(value.len());
+        let (number, unit) = value.split_at(unit_start);
+        let scale = match (DURATIONS.contains(&name), unit) {
+            (false, "") | (true, "ms") => 1,
+            (true, "") | (true, "s") => 1000,
+            _ => return Err(invalid()),
+        };
+        let value = number
+            .parse::<u64>()
+            .ok()
+            .and_then(|number| number.checked_mul(scale))
+            .ok_or_else(invalid)?;
+        self.set(name, value)
+    }
+
+    /// Checks that the limits can be applied. Every cache, queue and timeout must be non-zero,
+    /// and a request cannot be given less time than its first byte.
+    pub fn validate(&self) -> Result<(), String> {
+        // the stored peers and identify addresses may be turned off
+        let optional = ["max_identify_addresses", "stored_peers"];
+        if let Some((name, _)) = self
+            .values()
+            .into_iter()
+            .find(|(name, value)| *value == 0 && !optional.contains(name))
+        {
+            return Err(format!("The {} limit must be greater than 0", name));
+        }
+        if self.rpc_request_timeout < self.rpc_ttfb_timeout {
+            return Err(
+                "The rpc_request_timeout limit must be at least the rpc_ttfb_timeout".into(),
+            );
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Limits {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let values: Vec<String> = self
+            .values()
+            .iter()
+            .map(|(name, value)| {
+                let unit = if DURATIONS.contains(name) { "ms" } else { "" };
+                format!("{}={}{}", name, value, unit)
+            })
+            .collect();
+        f.write_str(&values.join(","))
+    }
+}
+
+/// A duration in whole milliseconds.
+fn millis(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_and_validate() {
+        let mut limits = Limits::default();
+        assert!(limits.validate().is_ok());
+        limits.set_from_str("queued_dials=4").unwrap();
+        limits.set_from_str("rpc_request_timeout=30").unwrap();
+        assert_eq!(limits.queued_dials, 4);
+        assert_eq!(limits.rpc_request_timeout, Duration::from_secs(30));
+
+        assert!(limits.set_from_str("unknown=1").is_err());
+        assert!(limits.set_from_str("queued_dials").is_err());
+        assert!(limits.set_from_str("queued_dials=-1").is_err());
+        assert!(limits.set_from_str("queued_dials=4ms").is_err());
+
+        // durations may be given in milliseconds
+        limits.set_from_str("rpc_ttfb_timeout=250ms").unwrap();
+        assert_eq!(limits.rpc_ttfb_timeout, Duration::from_millis(250));
+        limits.set_from_str("dial_timeout=2s").unwrap();
+        assert_eq!(limits.dial_timeout, Duration::from_secs(2));
+
+        // every limit can be set as it is shown
+        for (name, value) in Limits::default().values() {
+            limits.set(name, value).unwrap();
+        }
+        assert_eq!(limits, Limits::default());
+        let mut shown = Limits::default();
+        shown.rpc_ttfb_timeout = Duration::from_millis(250);
+        for limit in shown.to_string().split(',') {
+            limits.set_from_str(limit).unwrap();
+        }
+        assert_eq!(limits, shown);
+
+        limits.set("stored_peers", 0).unwrap();
+        assert!(limits.validate().is_ok());
+        limits.set("cached_enrs", 0).unwrap();
+        assert!(limits.validate().is_err());
+        limits.set("cached_enrs", 1).unwrap();
+        limits.set("rpc_request_timeout", 1).unwrap();
+        assert!(limits.validate().is_err());
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_deprecated_max_identify_addresses() {
+        let mut config = crate::NetworkConfig::default();
+        config.limits.max_identify_addresses = 4;
+        assert_eq!(config.limits().max_identify_addresses, 4);
+        config.max_identify_addresses = 2;
+        assert_eq!(config.limits().max_identify_addresses, 2);
+    }
+}
@@ -0,0 +1,65 @@
+//! Child loggers that carry the context of a log line as structured keys.
+//!
+//! A line about a peer, a topic or an RPC request carries it under the same key wherever it is
+//! logged: `peer_id`, `topic`, `protocol` and `request_id`. Downstream, the logs of one peer can
+//! then be filtered on the key, rather than by searching message texts for its id.
+use crate::rpc::Protocol;
+use crate::PeerId;
+use slog::{o, Logger};
+use std::panic::RefUnwindSafe;
+
+/// Returns a child of `log` for lines about `peer_id`.
+pub fn peer(log: &Logger, peer_id: &PeerId) -> Logger {
+    log.new(o!("peer_id" => peer_id.to_string()))
+}
+
+/// Returns a child of `log` for lines about `topic`.
+pub fn topic(log: &Logger, topic: &str) -> Logger {
+    log.new(o!("topic" => topic.to_string()))
+}
+
+/// Returns a child of `log` for lines about the request `request_id` of `protocol`, exchanged
+/// with `peer_id`.
+pub fn rpc<I>(log: &Logger, peer_id: &PeerId, protocol: Protocol, request_id: I) -> Logger
+where
+    I: slog::Value + Send + Sync + RefUnwindSafe + 'static,
+{
+    log.new(o!(
+        "peer_id" => peer_id.to_string(),
+        "protocol" => protocol.to_string(),
+        "request_id" => request_id,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::RequestId;
+    use crate::testing::Collect;
+    use slog::{debug, Drain};
+
+    #[test]
+    fn test_context_keys() {
+        let collect = Collect::default();
+        let log = Logger::root(collect.clone().fuse(), o!());
+        let peer_id = PeerId::random();
+
+        let rpc_log = rpc(&log, &peer_id, Protocol::Ping, RequestId::Sync(7));
+        debug!(rpc_log, "Sending Ping");
+        debug!(topic(&peer(&log, &peer_id), "blocks"), "Grafting peer");
+
+        let mut keys = collect.0.lock().clone();
+        keys.sort();
+        let peer_id = peer_id.to_string();
+        assert_eq!(
+            keys,
+            vec![
+                ("peer_id".to_string(), peer_id.clone()),
+                ("peer_id".to_string(), peer_id),
+                ("protocol".to_string(), "ping".to_string()),
+                ("request_id".to_string(), "7".to_string()),
+                ("topic".to_string(), "blocks".to_string()),
+            ]
+        );
+    }
+}
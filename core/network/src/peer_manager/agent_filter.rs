@@ -0,0 +1,59 @@
+//! Accepts or rejects peers by the agent string they report through Identify.
+use regex::RegexSet;
+
+/// Rules on agent strings. An agent is rejected if it matches a deny rule, or if there are allow
+/// rules and it matches none of them.
+#[derive(Debug)]
+pub struct AgentFilter {
+    allow: Option<RegexSet>,
+    deny: Option<RegexSet>,
+}
+
+impl AgentFilter {
+    /// Compiles the allow and deny patterns.
+    pub fn new(allow: &[String], deny: &[String]) -> Result<Self, String> {
+        let compile = |patterns: &[String]| {
+            if patterns.is_empty() {
+                return Ok(None);
+            }
+            RegexSet::new(patterns)
+                .map(Some)
+                .map_err(|e| format!("Invalid agent pattern: {}", e))
+        };
+        Ok(AgentFilter {
+            allow: compile(allow)?,
+            deny: compile(deny)?,
+        })
+    }
+
+    /// Returns true if a peer with this agent string may stay connected.
+    pub fn accepts(&self, agent: &str) -> bool {
+        match (&self.allow, &self.deny) {
+            (_, Some(deny)) if deny.is_match(agent) => false,
+            (Some(allow), _) => allow.is_match(agent),
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allow_and_deny() {
+        let filter = AgentFilter::new(
+            &["^mothra/".to_string(), "^lighthouse/".to_string()],
+            &["^mothra/v0\\.1\\.".to_string()],
+        )
+        .unwrap();
+        assert!(filter.accepts("mothra/v0.2.0/x86_64-linux"));
+        assert!(filter.accepts("lighthouse/v0.2.8"));
+        assert!(!filter.accepts("mothra/v0.1.3/x86_64-linux"));
+        assert!(!filter.accepts("prysm/v1.0.0"));
+
+        let open = AgentFilter::new(&[], &[]).unwrap();
+        assert!(open.accepts("anything"));
+        assert!(AgentFilter::new(&["(".to_string()], &[]).is_err());
+    }
+}
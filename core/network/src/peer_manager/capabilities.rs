@@ -0,0 +1,186 @@
+//! Capability flags read from the metadata of peers.
+//!
+//! The metadata a peer sends is opaque to the network, its layout belongs to the application. An
+//! application that packs flags into it, such as the subnets a peer serves or the services it
+//! offers, registers the byte ranges holding them as `CapabilityFields`. When a peer's metadata
+//! arrives, the registered fields are read into the peer's `Capabilities`, so that requests can be
+//! routed with `PeerDB::peers_with_capability` rather than by decoding metadata.
+use serde_derive::{Deserialize, Serialize};
+
+/// The most bytes of capability flags read from the metadata of a peer, over all fields.
+pub const MAX_CAPABILITY_BYTES: usize = 1024;
+
+/// A range of metadata bytes holding capability flags.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CapabilityField {
+    /// The name the field's flags are looked up by, unique among the registered fields.
+    pub name: String,
+    /// The position of the field in the metadata.
+    pub offset: usize,
+    /// The length of the field, in bytes.
+    pub length: usize,
+}
+
+/// The metadata fields read into capabilities. Their flags are numbered in the order the fields
+/// were registered, and within a field from the least significant bit of its first byte, as in an
+/// SSZ bitvector.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct CapabilityFields {
+    fields: Vec<CapabilityField>,
+}
+
+impl CapabilityFields {
+    /// Registers the `length` bytes at `offset` in the metadata as the field `name`. Returns the
+    /// capability bit of the first flag of the field. Fails for an empty field, a name already
+    /// registered, a field ending past the largest offset, or fields holding more than
+    /// `MAX_CAPABILITY_BYTES` bytes in all.
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        offset: usize,
+        length: usize,
+    ) -> Result<usize, String> {
+        let name = name.into();
+        if name.is_empty() || length == 0 {
+            return Err(format!("Invalid capability field: {}", name));
+        }
+        if self.fields.iter().any(|field| field.name == name) {
+            return Err(format!("Capability field {} is already registered", name));
+        }
+        if offset.checked_add(length).is_none() {
+            return Err(format!(
+                "Capability field {} ends past the largest offset",
+                name
+            ));
+        }
+        let bytes = self.fields.iter().map(|field| field.length).sum::<usize>();
+        if bytes
+            .checked_add(length)
+            .map_or(true, |bytes| bytes > MAX_CAPABILITY_BYTES)
+        {
+            return Err(format!(
+                "Capability fields hold more than {} bytes with {}",
+                MAX_CAPABILITY_BYTES, name
+            ));
+        }
+        let first_bit = self.bits();
+        self.fields.push(CapabilityField {
+            name,
+            offset,
+            length,
+        });
+        Ok(first_bit)
+    }
+
+    /// Returns true if no fields are registered.
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Returns the capability bit of flag `flag` of the field `name`, if the field is registered
+    /// and holds that many flags.
+    pub fn bit(&self, name: &str, flag: usize) -> Option<usize> {
+        let mut first_bit = 0;
+        for field in &self.fields {
+            let flags = field.length.saturating_mul(8);
+            if field.name == name {
+                return first_bit.checked_add(flag).filter(|_| flag < flags);
+            }
+            first_bit = first_bit.saturating_add(flags);
+        }
+        None
+    }
+
+    /// Reads the capabilities in a peer's metadata. The flags of a field that is cut short by the
+    /// end of the metadata are not set.
+    pub fn parse(&self, meta_data: &[u8]) -> Capabilities {
+        let mut bits = Vec::with_capacity(self.bits() / 8);
+        for field in &self.fields {
+            let bytes = field
+                .offset
+                .checked_add(field.length)
+                .and_then(|end| meta_data.get(field.offset..end));
+            match bytes {
+                Some(bytes) => bits.extend_from_slice(bytes),
+                None => bits.resize(bits.len() + field.length, 0),
+            }
+        }
+        Capabilities { bits }
+    }
+
+    /// The number of capability bits of the registered fields.
+    fn bits(&self) -> usize {
+        self.fields
+            .iter()
+            .map(|field| field.length.saturating_mul(8))
+            .fold(0, usize::saturating_add)
+    }
+}
+
+/// The capability flags of a peer.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Capabilities {
+    bits: Vec<u8>,
+}
+
+impl Capabilities {
+    /// Returns true if the capability `bit` is set.
+    pub fn has(&self, bit: usize) -> bool {
+        self.bits
+            .get(bit / 8)
+            .map_or(false, |byte| byte & (1 << (bit % 8)) != 0)
+    }
+
+    /// The flags, in the bytes of the fields they were read from.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_fields() {
+        let mut fields = CapabilityFields::default();
+        // an SSZ encoded seq_number followed by a 64 subnet bitvector and a byte of service bits
+        assert_eq!(fields.register("subnets", 8, 8), Ok(0));
+        assert_eq!(fields.register("services", 16, 1), Ok(64));
+        assert_eq!(fields.bit("subnets", 3), Some(3));
+        assert_eq!(fields.bit("services", 1), Some(65));
+        assert_eq!(fields.bit("services", 8), None);
+        assert_eq!(fields.bit("unknown", 0), None);
+
+        let mut meta_data = vec![0; 17];
+        meta_data[8] = 0b1000;
+        meta_data[16] = 0b10;
+        let capabilities = fields.parse(&meta_data);
+        assert!(capabilities.has(3));
+        assert!(capabilities.has(65));
+        assert!(!capabilities.has(0));
+        assert!(!capabilities.has(1000));
+
+        // a field missing from short metadata has no flags
+        let capabilities = fields.parse(&meta_data[..16]);
+        assert!(capabilities.has(3));
+        assert!(!capabilities.has(65));
+        assert_eq!(capabilities.as_bytes().len(), 9);
+    }
+
+    #[test]
+    fn test_invalid_fields() {
+        let mut fields = CapabilityFields::default();
+        assert!(fields.register("", 0, 1).is_err());
+        assert!(fields.register("empty", 0, 0).is_err());
+        assert!(fields.register("overflow", usize::MAX, 1).is_err());
+        assert!(fields.register("huge", 0, usize::MAX / 4).is_err());
+        assert_eq!(
+            fields.register("subnets", 0, MAX_CAPABILITY_BYTES - 1),
+            Ok(0)
+        );
+        assert!(fields.register("subnets", 0, 1).is_err());
+        assert!(fields.register("services", 0, 2).is_err());
+        assert!(fields.register("services", 0, 1).is_ok());
+    }
+}
@@ -24,6 +24,16 @@ pub enum ClientKind {
     Unknown,
 }
 
+impl ClientKind {
+    /// The name of the client, or "unknown".
+    pub fn name(&self) -> &str {
+        match self {
+            ClientKind::Known(name) => name,
+            ClientKind::Unknown => "unknown",
+        }
+    }
+}
+
 impl Default for Client {
     fn default() -> Self {
         Client {
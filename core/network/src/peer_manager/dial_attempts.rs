@@ -0,0 +1,155 @@
+//! Dials peers known at several addresses, happy eyeballs style (RFC 8305).
+//!
+//! Libp2p dials the addresses of a peer one after the other, moving on only once an address has
+//! failed, so a peer listed with a few dead addresses first takes several connection timeouts to
+//! reach. Instead the addresses are handed out staggered: the next address is dialed as soon as
+//! an attempt fails, or once the stagger delay passes without a connection, while the earlier
+//! attempts carry on. The first address to connect wins and the addresses not dialed yet are
+//! dropped.
+use crate::{Multiaddr, PeerId};
+use std::collections::{HashMap, VecDeque};
+
+/// What is left of the dial of a peer after one of its addresses failed.
+#[derive(Debug, PartialEq)]
+pub enum DialFailure {
+    /// The next address to dial the peer at.
+    Next(PeerId, Multiaddr),
+    /// Other addresses of the peer are still being dialed.
+    Pending,
+    /// Every address of the peer failed.
+    Exhausted(PeerId),
+}
+
+/// The dial of a peer across its addresses.
+struct DialAttempt {
+    /// The addresses not dialed yet, most preferred first.
+    remaining: VecDeque<Multiaddr>,
+    /// The number of addresses being dialed.
+    in_flight: usize,
+}
+
+/// The peers being dialed at several addresses.
+#[derive(Default)]
+pub struct DialAttempts {
+    attempts: HashMap<PeerId, DialAttempt>,
+    /// The peer each address being dialed belongs to.
+    dialing: HashMap<Multiaddr, PeerId>,
+}
+
+impl DialAttempts {
+    /// Starts dialing a peer at `addresses`, most preferred first, replacing any dial of the peer
+    /// in progress. Returns the address to dial first.
+    pub fn start(&mut self, peer_id: PeerId, addresses: Vec<Multiaddr>) -> Option<Multiaddr> {
+        self.finish(&peer_id);
+        self.attempts.insert(
+            peer_id.clone(),
+            DialAttempt {
+                remaining: addresses.into(),
+                in_flight: 0,
+            },
+        );
+        let first = self.next(&peer_id);
+        if first.is_none() {
+            self.attempts.remove(&peer_id);
+        }
+        first
+    }
+
+    /// Takes the next address to dial a peer at, if the peer is being dialed and has addresses
+    /// left. Addresses already being dialed for another peer are skipped.
+    pub fn next(&mut self, peer_id: &PeerId) -> Option<Multiaddr> {
+        let attempt = self.attempts.get_mut(peer_id)?;
+        while let Some(address) = attempt.remaining.pop_front() {
+            if !self.dialing.contains_key(&address) {
+                attempt.in_flight += 1;
+                self.dialing.insert(address.clone(), peer_id.clone());
+                return Some(address);
+            }
+        }
+        None
+    }
+
+    /// Records that dialing `address` failed. Returns `None` if the address was not dialed here.
+    pub fn failed(&mut self, address: &Multiaddr) -> Option<DialFailure> {
+        let peer_id = self.dialing.remove(address)?;
+        if let Some(attempt) = self.attempts.get_mut(&peer_id) {
+            attempt.in_flight -= 1;
+        }
+        if let Some(next) = self.next(&peer_id) {
+            return Some(DialFailure::Next(peer_id, next));
+        }
+        match self.attempts.get(&peer_id) {
+            Some(attempt) if attempt.in_flight > 0 => Some(DialFailure::Pending),
+            _ => {
+                self.attempts.remove(&peer_id);
+                Some(DialFailure::Exhausted(peer_id))
+            }
+        }
+    }
+
+    /// Ends the dial of a peer, once it connected. The outcome of the addresses still being
+    /// dialed is ignored.
+    pub fn finish(&mut self, peer_id: &PeerId) {
+        if self.attempts.remove(peer_id).is_some() {
+            self.dialing.retain(|_, dialed| dialed != peer_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(port: u16) -> Multiaddr {
+        format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn test_staggered_addresses() {
+        let peer_id = PeerId::random();
+        let mut attempts = DialAttempts::default();
+        assert_eq!(attempts.start(peer_id.clone(), vec![]), None);
+        assert_eq!(attempts.failed(&address(1)), None);
+
+        let addresses = vec![address(1), address(2), address(3), address(4)];
+        assert_eq!(attempts.start(peer_id.clone(), addresses), Some(address(1)));
+        // the stagger delay passed
+        assert_eq!(attempts.next(&peer_id), Some(address(2)));
+        // a failure moves on to the next address at once
+        assert_eq!(
+            attempts.failed(&address(1)),
+            Some(DialFailure::Next(peer_id.clone(), address(3)))
+        );
+        assert_eq!(attempts.next(&peer_id), Some(address(4)));
+        assert_eq!(attempts.next(&peer_id), None);
+        assert_eq!(attempts.failed(&address(2)), Some(DialFailure::Pending));
+        assert_eq!(attempts.failed(&address(4)), Some(DialFailure::Pending));
+        assert_eq!(
+            attempts.failed(&address(3)),
+            Some(DialFailure::Exhausted(peer_id.clone()))
+        );
+        assert_eq!(attempts.failed(&address(3)), None);
+    }
+
+    #[test]
+    fn test_connected_peer() {
+        let peer_id = PeerId::random();
+        let other_id = PeerId::random();
+        let mut attempts = DialAttempts::default();
+        attempts.start(peer_id.clone(), vec![address(1), address(2), address(3)]);
+        // an address shared with a dial in progress is skipped
+        assert_eq!(
+            attempts.start(other_id.clone(), vec![address(1), address(4)]),
+            Some(address(4))
+        );
+        assert_eq!(attempts.next(&peer_id), Some(address(2)));
+
+        attempts.finish(&peer_id);
+        assert_eq!(attempts.next(&peer_id), None);
+        assert_eq!(attempts.failed(&address(2)), None);
+        assert_eq!(
+            attempts.failed(&address(4)),
+            Some(DialFailure::Exhausted(other_id))
+        );
+    }
+}
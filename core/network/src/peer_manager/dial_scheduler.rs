@@ -0,0 +1,199 @@
+//! Paces the dialing of peers returned by discovery.
+//!
+//! A discovery query can return many peers at once and dialing all of them together floods the
+//! swarm with connection attempts. Discovered peers are instead queued by priority and only a
+//! limited number of dials are pending at a time. A dial stops being pending when the peer
+//! connects, the dial fails, or the dial timeout of the `Limits` passes without either.
+use crate::clock;
+use crate::limits::Limits;
+use crate::PeerId;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// How urgently a discovered peer should be dialed. Peers on our fork come first, then peers on
+/// more of the priority subnets.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DialPriority {
+    /// The peer's ENR has the same `eth2` field as ours.
+    pub same_fork: bool,
+    /// The number of priority subnets the peer's ENR advertises.
+    pub subnets: usize,
+}
+
+/// The queue of discovered peers and the dials in progress.
+pub struct DialScheduler {
+    max_pending: usize,
+    /// The maximum number of peers waiting to be dialed. Once full, the lowest priority are
+    /// dropped.
+    max_queued: usize,
+    /// The time after which a pending dial no longer counts towards the limit.
+    timeout: Duration,
+    /// The peers being dialed and when the dial started.
+    pending: HashMap<PeerId, Instant>,
+    /// Peers waiting to be dialed, highest priority first. Peers of equal priority are dialed in
+    /// the order they were discovered.
+    queue: VecDeque<(PeerId, DialPriority)>,
+}
+
+impl DialScheduler {
+    pub fn new(max_pending: usize, limits: &Limits) -> Self {
+        DialScheduler {
+            max_pending: max_pending.max(1),
+            max_queued: limits.queued_dials,
+            timeout: limits.dial_timeout,
+            pending: HashMap::new(),
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// Queues a peer to be dialed. Returns false if the peer is already queued or being dialed,
+    /// or if the queue is full of peers with a higher priority.
+    pub fn queue(&mut self, peer_id: PeerId, priority: DialPriority) -> bool {
+        if self.pending.contains_key(&peer_id) || self.queue.iter().any(|(id, _)| *id == peer_id) {
+            return false;
+        }
+        let index = self
+            .queue
+            .iter()
+            .position(|(_, queued)| *queued < priority)
+            .unwrap_or_else(|| self.queue.len());
+        if index >= self.max_queued {
+            return false;
+        }
+        self.queue.insert(index, (peer_id, priority));
+        self.queue.truncate(self.max_queued);
+        true
+    }
+
+    /// Records that the dial to a peer is over, whether the peer connected or not.
+    pub fn dial_finished(&mut self, peer_id: &PeerId) {
+        self.pending.remove(peer_id);
+    }
+
+    /// Takes the next peer to dial, unless the maximum number of dials are pending. Queued peers
+    /// for which `skip` returns true, for example because they connected in the meantime, are
+    /// dropped.
+    pub fn next_dial(&mut self, skip: impl Fn(&PeerId) -> bool) -> Option<PeerId> {
+        let now = clock::now();
+        self.expire_pending(now);
+        while self.pending.len() < self.max_pending {
+            let (peer_id, _) = self.queue.pop_front()?;
+            if skip(&peer_id) {
+                continue;
+            }
+            self.pending.insert(peer_id.clone(), now);
+            return Some(peer_id);
+        }
+        None
+    }
+
+    /// The next peer `next_dial` would take with the same `skip`, without taking it. Skipped
+    /// peers are dropped as by `next_dial`.
+    pub fn peek(&mut self, skip: impl Fn(&PeerId) -> bool) -> Option<&PeerId> {
+        self.expire_pending(clock::now());
+        if self.pending.len() >= self.max_pending {
+            return None;
+        }
+        while self
+            .queue
+            .front()
+            .map_or(false, |(peer_id, _)| skip(peer_id))
+        {
+            self.queue.pop_front();
+        }
+        self.queue.front().map(|(peer_id, _)| peer_id)
+    }
+
+    /// Stops counting the dials started longer than the timeout ago as pending.
+    fn expire_pending(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        self.pending
+            .retain(|_, started| now.duration_since(*started) < timeout);
+    }
+
+    /// The number of dials in progress.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// The number of peers waiting to be dialed.
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dial_order_and_limit() {
+        let mut scheduler = DialScheduler::new(2, &Limits::default());
+        let low = PeerId::random();
+        let subnet = PeerId::random();
+        let fork = PeerId::random();
+        let banned = PeerId::random();
+
+        assert!(scheduler.queue(low.clone(), DialPriority::default()));
+        assert!(scheduler.queue(
+            subnet.clone(),
+            DialPriority {
+                same_fork: false,
+                subnets: 2,
+            }
+        ));
+        assert!(scheduler.queue(
+            banned.clone(),
+            DialPriority {
+                same_fork: true,
+                subnets: 0,
+            }
+        ));
+        assert!(scheduler.queue(
+            fork.clone(),
+            DialPriority {
+                same_fork: true,
+                subnets: 0,
+            }
+        ));
+        // duplicates are not queued again
+        assert!(!scheduler.queue(low.clone(), DialPriority::default()));
+        assert_eq!(scheduler.queued(), 4);
+
+        let skip = |peer_id: &PeerId| *peer_id == banned;
+        assert_eq!(scheduler.peek(|_| false), Some(&banned));
+        // a skipped peer is not peeked at, and dropped
+        assert_eq!(scheduler.peek(skip), Some(&fork));
+        assert_eq!(scheduler.queued(), 3);
+        assert_eq!(scheduler.next_dial(skip), Some(fork.clone()));
+        assert_eq!(scheduler.next_dial(skip), Some(subnet.clone()));
+        // two dials are pending
+        assert_eq!(scheduler.peek(skip), None);
+        assert_eq!(scheduler.next_dial(skip), None);
+        assert!(!scheduler.queue(fork.clone(), DialPriority::default()));
+
+        scheduler.dial_finished(&fork);
+        assert_eq!(scheduler.pending(), 1);
+        assert_eq!(scheduler.next_dial(skip), Some(low));
+        assert_eq!(scheduler.queued(), 0);
+    }
+
+    #[test]
+    fn test_full_queue_keeps_highest_priority() {
+        let limits = Limits {
+            queued_dials: 1,
+            ..Limits::default()
+        };
+        let mut scheduler = DialScheduler::new(1, &limits);
+        let fork = DialPriority {
+            same_fork: true,
+            subnets: 0,
+        };
+        assert!(scheduler.queue(PeerId::random(), DialPriority::default()));
+        let best = PeerId::random();
+        assert!(scheduler.queue(best.clone(), fork));
+        assert!(!scheduler.queue(PeerId::random(), DialPriority::default()));
+        assert_eq!(scheduler.queued(), 1);
+        assert_eq!(scheduler.peek(|_| false), Some(&best));
+    }
+}
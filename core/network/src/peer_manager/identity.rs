@@ -0,0 +1,25 @@
+//! A hook for applications to verify the identity of peers against an external source.
+//!
+//! Permissioned networks use it to keep out peers that are not registered members, for instance
+//! those missing from a staking set or an allowlist service. Peers are checked when their ENR is
+//! discovered, before they are dialed, and again once they identify themselves after connecting.
+use crate::{Enr, PeerId};
+use libp2p::identify::IdentifyInfo;
+use std::fmt;
+
+/// Decides which peers may be connected to. Both checks allow every peer by default.
+///
+/// The checks run on the network thread, so answers from a remote registry should be cached by
+/// the implementation rather than fetched on each call.
+pub trait IdentityVerifier: fmt::Debug + Send + Sync {
+    /// Returns true if a peer discovered with `enr` may be dialed.
+    fn verify_enr(&self, _enr: &Enr) -> bool {
+        true
+    }
+
+    /// Returns true if a connected peer that identified itself with `info` may stay connected.
+    /// Denied peers are sent a Goodbye and banned.
+    fn verify_identify(&self, _peer_id: &PeerId, _info: &IdentifyInfo) -> bool {
+        true
+    }
+}
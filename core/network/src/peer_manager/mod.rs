@@ -1,32 +1,61 @@
 //! Implementation of a peer management system.
 
 pub use self::peerdb::*;
-use crate::discovery::{Discovery, DiscoveryEvent};
+use crate::clock;
+use crate::discovery::{enr_services, shares_fork, Discovery, DiscoveryEvent, EnrPredicate};
 use crate::error;
-use crate::rpc::{MetaData, Protocol, RPCError, RPCResponseErrorCode};
+use crate::log_context;
+use crate::rpc::{
+    GoodbyeReason, MetaData, Protocol, RPCError, RPCResponseErrorCode, StatusMessage,
+};
 use crate::types::SubnetId;
-use crate::{Enr, EnrExt, HashSetDelay, NetworkConfig, NetworkGlobals, PeerId};
+use crate::{Enr, EnrExt, HashSetDelay, NetworkConfig, NetworkGlobals, PeerId, TaskExecutor};
 use futures::prelude::*;
 use futures::Stream;
-use libp2p::core::multiaddr::Protocol as MProtocol;
+use libp2p::core::{connection::ConnectionId, multiaddr::Protocol as MProtocol, ConnectedPoint};
 use libp2p::identify::IdentifyInfo;
-use slog::{crit, debug, error};
+use lru::LruCache;
+use slog::{crit, debug, error, trace, warn};
 use smallvec::SmallVec;
 use std::{
+    collections::{HashMap, HashSet},
     net::SocketAddr,
+    path::PathBuf,
     pin::Pin,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     task::{Context, Poll},
     time::{Duration, Instant},
 };
 
 pub use libp2p::core::{identity::Keypair, Multiaddr};
 
+mod agent_filter;
+mod capabilities;
 pub mod client;
+mod dial_attempts;
+mod dial_scheduler;
+mod identity;
 mod peer_info;
+mod peer_store;
 mod peerdb;
+mod query;
+mod score;
+mod subnet_quota;
 
-pub use peer_info::{PeerConnectionStatus::*, PeerInfo};
+use agent_filter::AgentFilter;
+pub use capabilities::{Capabilities, CapabilityField, CapabilityFields, MAX_CAPABILITY_BYTES};
+use dial_attempts::{DialAttempts, DialFailure};
+use dial_scheduler::{DialPriority, DialScheduler};
+pub use identity::IdentityVerifier;
+pub use peer_info::{CancelledRequest, PeerConnectionStatus::*, PeerInfo};
+use peer_store::StoredPeer;
+pub use query::{ConnectionDirection, ConnectionState, PeerQuery, PeerQueryResult};
+pub use score::{PeerScore, PeerScoreSnapshot, ScoreSource};
+pub use subnet_quota::SubnetPeerCount;
+use subnet_quota::SubnetQuotas;
 /// The minimum reputation before a peer is disconnected.
 // Most likely this needs tweaking.
 const _MIN_REP_BEFORE_BAN: Rep = 10;
@@ -39,6 +68,14 @@ const PING_INTERVAL: u64 = 30;
 /// The heartbeat performs regular updates such as updating reputations and performing discovery
 /// requests. This defines the interval in seconds.  
 const HEARTBEAT_INTERVAL: u64 = 30;
+/// The time in seconds between writes of the connected peers to disk.
+const STORE_PEERS_INTERVAL: u64 = 600;
+
+/// The number of gossip messages a peer must have sent before its usefulness is scored.
+const MIN_USEFULNESS_MESSAGES: u64 = 100;
+
+/// The number of connected peers a priority subnet needs before its peers stop pre-empting others.
+const MIN_PRIORITY_SUBNET_PEERS: usize = 2;
 
 /// The main struct that handles peer's reputation and connection status.
 pub struct PeerManager {
@@ -56,6 +93,63 @@ pub struct PeerManager {
     discovery: Discovery,
     /// The heartbeat interval to perform routine maintenance.
     heartbeat: tokio::time::Interval,
+    /// The time it takes for a peer's reputation components to halve. `None` disables decay.
+    score_half_life: Option<Duration>,
+    /// The open connections to each peer and their endpoints. Duplicate connections are no longer
+    /// tracked once they are being closed.
+    connections: HashMap<PeerId, SmallVec<[(ConnectionId, ConnectedPoint); 2]>>,
+    /// When each connected peer connected.
+    connected_since: HashMap<PeerId, Instant>,
+    /// Peers that are always reconnected to, and their addresses.
+    static_peers: HashMap<PeerId, Vec<Multiaddr>>,
+    /// The rendezvous points, which are also static peers.
+    rendezvous_points: HashSet<PeerId>,
+    /// The addresses of the peers found at rendezvous points.
+    rendezvous_addresses: LruCache<PeerId, Vec<Multiaddr>>,
+    /// Decides which agent strings are allowed to stay connected.
+    agent_filter: AgentFilter,
+    /// The prefix the protocol version of peers must start with, if any.
+    required_protocol_prefix: Option<String>,
+    /// Whether statuses are `StatusMessage`s, checked against the forks we serve.
+    typed_status: bool,
+    /// The metadata fields read into the capabilities of peers.
+    capability_fields: CapabilityFields,
+    /// The application's check of peer identities, if any.
+    identity_verifier: Option<Arc<dyn IdentityVerifier>>,
+    /// Whether discovery is disabled, leaving the static peers and boot nodes as the only peers.
+    discovery_disabled: bool,
+    /// Limits and orders the dials to discovered peers.
+    dial_scheduler: DialScheduler,
+    /// The peers being dialed at several addresses.
+    dial_attempts: DialAttempts,
+    /// The peers whose next address is dialed once their entry expires, if addresses are dialed
+    /// staggered.
+    dial_stagger: Option<HashSetDelay<PeerId>>,
+    /// Subnets whose peers are dialed before others, including the subnets with a quota.
+    priority_subnets: Vec<SubnetId>,
+    /// The minimum numbers of connected peers wanted on subnets.
+    subnet_quotas: SubnetQuotas,
+    /// The minimum time between pre-emptions, if peers may be pre-empted.
+    preemption_interval: Option<Duration>,
+    /// When a peer was last pre-empted.
+    last_preemption: Option<Instant>,
+    /// The directory the connected peers are stored in.
+    network_dir: PathBuf,
+    /// The number of peers stored.
+    max_stored_peers: usize,
+    /// When the peers were last written to disk.
+    last_stored: Instant,
+    /// Whether the peers are being written to disk.
+    storing: Arc<AtomicBool>,
+    /// Runs the writes of the peers to disk, off the event loop.
+    executor: TaskExecutor,
+    /// The fraction of its gossip a peer must deliver first to avoid being penalized. `None`
+    /// leaves usefulness out of scoring.
+    min_gossip_usefulness: Option<f64>,
+    /// Whether peers are reached over the in-memory transport, at the port their ENR gives as
+    /// TCP port.
+    #[cfg(feature = "simulation")]
+    memory_transport: bool,
     /// The logger associated with the `PeerManager`.
     log: slog::Logger,
 }
@@ -64,6 +158,7 @@ pub struct PeerManager {
 /// Each variant has an associated reputation change.
 // To easily assess the behaviour of reputation changes the number of variants should stay low, and
 // somewhat generic.
+#[derive(Debug, Clone, Copy)]
 pub enum PeerAction {
     /// We should not communicate more with this peer.
     /// This action will cause the peer to get banned.
@@ -102,6 +197,8 @@ impl PeerAction {
 pub enum PeerManagerEvent {
     /// Dial a PeerId.
     Dial(PeerId),
+    /// Dial an address of a peer that is being dialed at several addresses.
+    DialAddress(Multiaddr),
     /// Inform libp2p that our external socket addr has been updated.
     SocketUpdated(Multiaddr),
     /// Sends a STATUS to a peer.
@@ -112,6 +209,25 @@ pub enum PeerManagerEvent {
     MetaData(PeerId),
     /// The peer should be disconnected.
     DisconnectPeer(PeerId),
+    /// The peer should be sent a Goodbye giving the reason, then disconnected.
+    Goodbye(PeerId, GoodbyeReason),
+    /// A duplicate connection to a peer should be closed, keeping the peer connected.
+    CloseConnection(PeerId, ConnectionId),
+    /// Peers matching the predicate of a discovery request were found.
+    MatchingPeers(Vec<Enr>),
+    /// Peers were found by a discovery query.
+    PeersDiscovered(Vec<Enr>),
+}
+
+/// What became of a status a peer sent, as checked by `PeerManager::status_received`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StatusCheck {
+    /// The status may reach the application.
+    Accepted,
+    /// The status does not decode.
+    Malformed,
+    /// The peer is on another fork or chain, and is being sent a Goodbye.
+    Irrelevant,
 }
 
 impl PeerManager {
@@ -120,6 +236,7 @@ impl PeerManager {
         local_key: &Keypair,
         config: &NetworkConfig,
         network_globals: Arc<NetworkGlobals>,
+        executor: TaskExecutor,
         log: &slog::Logger,
     ) -> error::Result<Self> {
         // start the discovery service
@@ -128,16 +245,83 @@ impl PeerManager {
         // start searching for peers
         discovery.discover_peers();
 
+        // dial the best peers of the last run while discovery gets going
+        let mut events = SmallVec::new();
+        if config.warm_dial_peers > 0 {
+            let stored_peers =
+                peer_store::load_peers(&config.network_dir, config.limits.stored_peers, log);
+            for (i, stored) in stored_peers.into_iter().enumerate() {
+                let peer_id = stored.enr.peer_id();
+                discovery.add_enr(stored.enr);
+                if i < config.warm_dial_peers {
+                    debug!(log, "Dialing stored peer"; "peer_id" => peer_id.to_string(),
+                        "reputation" => stored.reputation);
+                    events.push(PeerManagerEvent::Dial(peer_id));
+                }
+            }
+        }
+
         let heartbeat = tokio::time::interval(tokio::time::Duration::from_secs(HEARTBEAT_INTERVAL));
 
+        let mut priority_subnets = config.priority_subnets.clone();
+        for subnet_id in config.subnet_peer_quotas.keys() {
+            if !priority_subnets.contains(subnet_id) {
+                priority_subnets.push(*subnet_id);
+            }
+        }
+
+        let mut static_peers = peers_by_id(&config.static_peers, "Static peer")?;
+        let rendezvous_points = peers_by_id(&config.rendezvous_points, "Rendezvous point")?;
+        for (peer_id, addresses) in rendezvous_points.iter() {
+            static_peers
+                .entry(peer_id.clone())
+                .or_default()
+                .extend(addresses.iter().cloned());
+        }
+        let agent_filter = AgentFilter::new(&config.agent_allow, &config.agent_deny)?;
+        {
+            let mut peerdb = network_globals.peers.write();
+            peerdb.set_max_disconnected_peers(config.max_disconnected_peers);
+            for peer_id in static_peers.keys() {
+                peerdb.add_trusted_peer(peer_id.clone());
+            }
+        }
+
         Ok(PeerManager {
             network_globals,
-            events: SmallVec::new(),
+            events,
             ping_peers: HashSetDelay::new(Duration::from_secs(PING_INTERVAL)),
             status_peers: HashSetDelay::new(Duration::from_secs(STATUS_INTERVAL)),
             target_peers: config.max_peers, //TODO: Add support for target peers and max peers
             discovery,
             heartbeat,
+            score_half_life: config.score_half_life,
+            connections: HashMap::new(),
+            connected_since: HashMap::new(),
+            static_peers,
+            rendezvous_points: rendezvous_points.into_iter().map(|(id, _)| id).collect(),
+            rendezvous_addresses: LruCache::new(config.limits.rendezvous_peers),
+            agent_filter,
+            required_protocol_prefix: config.required_protocol_prefix.clone(),
+            typed_status: config.typed_status,
+            capability_fields: config.capability_fields.clone(),
+            identity_verifier: config.identity_verifier.clone(),
+            discovery_disabled: config.disable_discovery,
+            dial_scheduler: DialScheduler::new(config.max_pending_dials, &config.limits),
+            dial_attempts: DialAttempts::default(),
+            dial_stagger: config.dial_stagger.map(HashSetDelay::new),
+            priority_subnets,
+            subnet_quotas: SubnetQuotas::new(config.subnet_peer_quotas.clone()),
+            preemption_interval: config.peer_preemption_interval,
+            last_preemption: None,
+            network_dir: config.network_dir.clone(),
+            max_stored_peers: config.limits.stored_peers,
+            last_stored: clock::now(),
+            storing: Arc::new(AtomicBool::new(false)),
+            executor,
+            min_gossip_usefulness: config.min_gossip_usefulness,
+            #[cfg(feature = "simulation")]
+            memory_transport: config.memory_port.is_some(),
             log: log.clone(),
         })
     }
@@ -157,8 +341,7 @@ impl PeerManager {
     }
 
     /// A request to find peers on a given subnet.
-    /// TODO
-    /*pub fn discover_subnet_peers(&mut self, subnet_id: SubnetId, min_ttl: Option<Instant>) {
+    pub fn discover_subnet_peers(&mut self, subnet_id: SubnetId, min_ttl: Option<Instant>) {
         // Extend the time to maintain peers if required.
         if let Some(min_ttl) = min_ttl {
             self.network_globals
@@ -169,17 +352,67 @@ impl PeerManager {
 
         // request the subnet query from discovery
         self.discovery.discover_subnet_peers(subnet_id, min_ttl);
-    }*/
+    }
 
     /// A STATUS message has been received from a peer. This resets the status timer.
     pub fn peer_statusd(&mut self, peer_id: &PeerId) {
         self.status_peers.insert(peer_id.clone());
     }
 
+    /// Checks a status a peer sent, in a request or a response, if statuses are typed. A status
+    /// that does not decode is penalized. A peer whose status is for a fork we do not serve, or
+    /// whose finalized checkpoint conflicts with ours, is sent a Goodbye and disconnected.
+    pub fn status_received(&mut self, peer_id: &PeerId, status: &[u8]) -> StatusCheck {
+        if !self.typed_status {
+            return StatusCheck::Accepted;
+        }
+        let status = match StatusMessage::decode(status) {
+            Some(status) => status,
+            None => {
+                debug!(self.log, "Received a malformed Status"; "peer_id" => peer_id.to_string(), "length" => status.len());
+                self.network_globals
+                    .malformed_statuses
+                    .fetch_add(1, Ordering::Relaxed);
+                self.report_peer(
+                    peer_id,
+                    PeerAction::LowToleranceError,
+                    ScoreSource::Transport,
+                );
+                return StatusCheck::Malformed;
+            }
+        };
+        let on_our_fork = std::iter::once(self.network_globals.local_fork_id())
+            .chain(self.network_globals.local_secondary_fork_id())
+            .any(|fork_id| status.is_on_fork(&fork_id));
+        let on_our_chain = self
+            .network_globals
+            .local_status_on(&status.fork_digest)
+            .map_or(true, |local| !status.conflicts_with(&local));
+        let fork_digest = status.fork_digest;
+        if let Some(peer_info) = self.network_globals.peers.write().peer_info_mut(peer_id) {
+            peer_info.status = Some(status);
+        }
+        if !on_our_fork || !on_our_chain {
+            debug!(self.log, "Disconnecting peer on another fork or chain";
+                "peer_id" => peer_id.to_string(), "fork_digest" => hex::encode(fork_digest));
+            self.network_globals
+                .irrelevant_peers
+                .fetch_add(1, Ordering::Relaxed);
+            self.report_peer(peer_id, PeerAction::LowToleranceError, ScoreSource::Policy);
+            self.events.push(PeerManagerEvent::Goodbye(
+                peer_id.clone(),
+                GoodbyeReason::IrrelevantNetwork,
+            ));
+            return StatusCheck::Irrelevant;
+        }
+        StatusCheck::Accepted
+    }
+
     /// Updates the state of the peer as disconnected.
     pub fn notify_disconnect(&mut self, peer_id: &PeerId) {
         //self.update_reputations();
         self.network_globals.peers.write().disconnect(peer_id);
+        self.dial_scheduler.dial_finished(peer_id);
 
         // remove the ping and status timer for the peer
         self.ping_peers.remove(peer_id);
@@ -198,6 +431,93 @@ impl PeerManager {
         self.connect_peer(peer_id, ConnectingType::OutgoingConnected)
     }
 
+    /// Registers a new connection to a peer.
+    ///
+    /// When two nodes dial each other at the same time both connections get established, if
+    /// `Limits::max_connections_per_peer` allows it. Of the duplicates, the connection dialed by
+    /// the node with the lower `PeerId` is kept and the others are closed. As both nodes apply the
+    /// same rule, they close the same connection.
+    ///
+    /// A trusted peer connecting while we are at the peer target makes room for itself by
+    /// pre-empting an inbound peer.
+    pub fn connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        conn_id: ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        self.dial_scheduler.dial_finished(peer_id);
+        if let ConnectedPoint::Dialer { address } = endpoint {
+            self.dial_attempts.finish(peer_id);
+            if let Some(stagger) = self.dial_stagger.as_mut() {
+                stagger.remove(peer_id);
+            }
+            // the address may have been dialed for another peer, whose dial failed
+            self.address_unreachable(None, address);
+            self.network_globals
+                .peers
+                .write()
+                .dialed_at(peer_id, address.clone());
+        }
+        let connections = self.connections.entry(peer_id.clone()).or_default();
+        connections.push((conn_id, endpoint.clone()));
+        if connections.len() < 2 {
+            self.connected_since.insert(peer_id.clone(), clock::now());
+            // the peer database does not count the new peer yet
+            let trusted = self.network_globals.peers.read().is_trusted(peer_id);
+            if trusted && self.network_globals.connected_peers() >= self.target_peers {
+                self.preempt_inbound_peer();
+            }
+            return;
+        }
+
+        let local_peer_id = self.network_globals.local_peer_id();
+        let keep_dialed = local_peer_id.as_bytes() < peer_id.as_bytes();
+        for id in close_duplicates(connections, keep_dialed) {
+            debug!(self.log, "Closing duplicate connection";
+                "peer_id" => peer_id.to_string(), "connection" => format!("{:?}", id));
+            self.network_globals
+                .duplicate_connections_closed
+                .fetch_add(1, Ordering::Relaxed);
+            self.events
+                .push(PeerManagerEvent::CloseConnection(peer_id.clone(), id));
+        }
+    }
+
+    /// Removes a closed connection to a peer.
+    pub fn connection_closed(&mut self, peer_id: &PeerId, conn_id: &ConnectionId) {
+        if let Some(connections) = self.connections.get_mut(peer_id) {
+            connections.retain(|(id, _)| id != conn_id);
+            if connections.is_empty() {
+                self.connections.remove(peer_id);
+                self.connected_since.remove(peer_id);
+            }
+        }
+    }
+
+    /// A dial to `address` failed. The next address of the peer is dialed if it has any left,
+    /// otherwise the peer is marked as disconnected.
+    pub fn address_unreachable(&mut self, peer_id: Option<&PeerId>, address: &Multiaddr) {
+        match self.dial_attempts.failed(address) {
+            Some(DialFailure::Next(peer_id, next)) => self.dial_address(peer_id, next),
+            Some(DialFailure::Pending) => {}
+            Some(DialFailure::Exhausted(peer_id)) => {
+                if let Some(stagger) = self.dial_stagger.as_mut() {
+                    stagger.remove(&peer_id);
+                }
+                self.notify_disconnect(&peer_id);
+            }
+            None => {
+                if let Some(peer_id) = peer_id {
+                    // another address may have connected already
+                    if !self.network_globals.peers.read().is_connected(peer_id) {
+                        self.notify_disconnect(peer_id);
+                    }
+                }
+            }
+        }
+    }
+
     /// Updates the database informing that a peer is being dialed.
     pub fn dialing_peer(&mut self, peer_id: &PeerId) -> bool {
         self.connect_peer(peer_id, ConnectingType::Dialing)
@@ -209,32 +529,102 @@ impl PeerManager {
         true
     }
 
-    /// Reports a peer for some action.
+    /// Disconnects every connected peer with a Goodbye, as when shutting down. Their connections
+    /// close once the responses still being written to them are sent.
+    pub fn disconnect_all(&mut self) {
+        let peers: Vec<PeerId> = self
+            .network_globals
+            .peers
+            .read()
+            .connected_peer_ids()
+            .cloned()
+            .collect();
+        for peer_id in peers {
+            self.events.push(PeerManagerEvent::DisconnectPeer(peer_id));
+        }
+    }
+
+    /// Reports a peer for some action. The reputation change is attributed to `source`.
     ///
     /// If the peer doesn't exist, log a warning and insert defaults.
-    pub fn report_peer(&mut self, peer_id: &PeerId, action: PeerAction) {
+    pub fn report_peer(&mut self, peer_id: &PeerId, action: PeerAction, source: ScoreSource) {
         //TODO: Check these. There are double disconnects for example (Age comment)
         // self.update_reputations();
         self.network_globals
             .peers
             .write()
-            .add_reputation(peer_id, action.rep_change());
+            .add_reputation(peer_id, action.rep_change(), source);
         // self.update_reputations();
     }
 
     /// Updates `PeerInfo` with `identify` information.
+    /// Peers whose agent string is rejected by the agent filter, or whose protocol version lacks
+    /// the required prefix, are penalized and disconnected.
+    /// Peers denied by the identity verifier are banned and disconnected.
     pub fn identify(&mut self, peer_id: &PeerId, info: &IdentifyInfo) {
         if let Some(peer_info) = self.network_globals.peers.write().peer_info_mut(peer_id) {
             peer_info.client = client::Client::from_identify_info(info);
             peer_info.listening_addresses = info.listen_addrs.clone();
         } else {
             crit!(self.log, "Received an Identify response from an unknown peer"; "peer_id" => peer_id.to_string());
+            return;
+        }
+
+        if !self.agent_filter.accepts(&info.agent_version) {
+            debug!(self.log, "Disconnecting peer with rejected agent";
+                "peer_id" => peer_id.to_string(), "agent_version" => &info.agent_version);
+            self.network_globals
+                .rejected_agents
+                .fetch_add(1, Ordering::Relaxed);
+            self.report_peer(peer_id, PeerAction::LowToleranceError, ScoreSource::Policy);
+            self.events
+                .push(PeerManagerEvent::DisconnectPeer(peer_id.clone()));
+            return;
+        }
+
+        let required_prefix = self.required_protocol_prefix.as_deref();
+        if !protocol_compatible(required_prefix, &info.protocol_version) {
+            debug!(self.log, "Disconnecting peer with incompatible protocol version";
+                "peer_id" => peer_id.to_string(), "protocol_version" => &info.protocol_version);
+            self.network_globals
+                .incompatible_protocols
+                .fetch_add(1, Ordering::Relaxed);
+            self.report_peer(peer_id, PeerAction::LowToleranceError, ScoreSource::Policy);
+            self.events
+                .push(PeerManagerEvent::DisconnectPeer(peer_id.clone()));
+            return;
+        }
+
+        let verified = self
+            .identity_verifier
+            .as_ref()
+            .map_or(true, |verifier| verifier.verify_identify(peer_id, info));
+        if !verified {
+            debug!(self.log, "Banning peer denied by the identity verifier";
+                "peer_id" => peer_id.to_string());
+            self.network_globals
+                .rejected_identities
+                .fetch_add(1, Ordering::Relaxed);
+            self.report_peer(peer_id, PeerAction::Fatal, ScoreSource::Policy);
+            self.network_globals.peers.write().ban(peer_id);
+            self.events
+                .push(PeerManagerEvent::DisconnectPeer(peer_id.clone()));
         }
     }
 
     pub fn handle_rpc_error(&mut self, peer_id: &PeerId, protocol: Protocol, err: &RPCError) {
         let client = self.network_globals.client(peer_id);
-        debug!(self.log, "RPCError"; "protocol" => protocol.to_string(), "err" => err.to_string(), "client" => client.to_string());
+        debug!(log_context::peer(&self.log, peer_id), "RPCError";
+            "protocol" => protocol.to_string(),
+            "error" => err.to_string(),
+            "client" => client.to_string());
+        // a failed metadata request is sent again on the next ping
+        if let Protocol::MetaData = protocol {
+            self.network_globals
+                .peers
+                .write()
+                .meta_data_request_ended(peer_id);
+        }
 
         // Map this error to a `PeerAction` (if any)
         let peer_action = match err {
@@ -273,6 +663,9 @@ impl PeerManager {
                     Protocol::Goodbye => return,
                     Protocol::MetaData => PeerAction::LowToleranceError,
                     Protocol::Status => PeerAction::LowToleranceError,
+                    // the topics and rendezvous protocols are optional
+                    Protocol::Topics => return,
+                    Protocol::Rendezvous => return,
                     _ => return,
                 }
             }
@@ -281,69 +674,102 @@ impl PeerManager {
                 Protocol::Goodbye => return,
                 Protocol::MetaData => return,
                 Protocol::Status => return,
+                Protocol::Topics => return,
+                Protocol::Rendezvous => return,
                 _ => return,
             },
             RPCError::NegotiationTimeout => PeerAction::HighToleranceError,
         };
 
-        self.report_peer(peer_id, peer_action);
+        self.report_peer(peer_id, peer_action, ScoreSource::Transport);
     }
 
     /// A ping request has been received.
     // NOTE: The behaviour responds with a PONG automatically
     // TODO: Update last seen
     pub fn ping_request(&mut self, peer_id: &PeerId, seq: u64) {
-        if let Some(peer_info) = self.network_globals.peers.read().peer_info(peer_id) {
-            // received a ping
-            // reset the to-ping timer for this peer
-            debug!(self.log, "Received a ping request"; "peer_id" => peer_id.to_string(), "seq_no" => seq);
-            self.ping_peers.insert(peer_id.clone());
-
-            // if the sequence number is unknown send an update the meta data of the peer.
-            if let Some(meta_data) = &peer_info.meta_data {
-                if meta_data.seq_number < seq {
-                    debug!(self.log, "Requesting new metadata from peer";
-                        "peer_id" => peer_id.to_string(), "known_seq_no" => meta_data.seq_number, "ping_seq_no" => seq);
-                    self.events
-                        .push(PeerManagerEvent::MetaData(peer_id.clone()));
-                }
-            } else {
-                // if we don't know the meta-data, request it
-                debug!(self.log, "Requesting first metadata from peer";
-                    "peer_id" => peer_id.to_string());
-                self.events
-                    .push(PeerManagerEvent::MetaData(peer_id.clone()));
-            }
-        } else {
+        if self
+            .network_globals
+            .peers
+            .read()
+            .peer_info(peer_id)
+            .is_none()
+        {
             crit!(self.log, "Received a PING from an unknown peer";
                 "peer_id" => peer_id.to_string());
+            return;
         }
+        // received a ping
+        // reset the to-ping timer for this peer
+        debug!(self.log, "Received a ping request"; "peer_id" => peer_id.to_string(), "seq_no" => seq);
+        self.ping_peers.insert(peer_id.clone());
+
+        // if the sequence number is unknown send an update the meta data of the peer.
+        self.request_meta_data(peer_id, seq);
     }
 
     /// A PONG has been returned from a peer.
     // TODO: Update last seen
     pub fn pong_response(&mut self, peer_id: &PeerId, seq: u64) {
-        if let Some(peer_info) = self.network_globals.peers.read().peer_info(peer_id) {
-            // received a pong
-
-            // if the sequence number is unknown send update the meta data of the peer.
-            if let Some(meta_data) = &peer_info.meta_data {
-                if meta_data.seq_number < seq {
-                    debug!(self.log, "Requesting new metadata from peer";
-                        "peer_id" => peer_id.to_string(), "known_seq_no" => meta_data.seq_number, "pong_seq_no" => seq);
-                    self.events
-                        .push(PeerManagerEvent::MetaData(peer_id.clone()));
-                }
-            } else {
-                // if we don't know the meta-data, request it
-                debug!(self.log, "Requesting first metadata from peer";
-                    "peer_id" => peer_id.to_string());
-                self.events
-                    .push(PeerManagerEvent::MetaData(peer_id.clone()));
-            }
-        } else {
+        if self
+            .network_globals
+            .peers
+            .read()
+            .peer_info(peer_id)
+            .is_none()
+        {
             crit!(self.log, "Received a PONG from an unknown peer"; "peer_id" => peer_id.to_string());
+            return;
+        }
+        // if the sequence number is unknown send update the meta data of the peer.
+        self.request_meta_data(peer_id, seq);
+    }
+
+    /// Requests the metadata of a peer that announced the sequence number `seq`, unless metadata
+    /// at least as recent is known or already requested.
+    fn request_meta_data(&mut self, peer_id: &PeerId, seq: u64) {
+        if self
+            .network_globals
+            .peers
+            .write()
+            .request_meta_data(peer_id, seq)
+        {
+            debug!(self.log, "Requesting new metadata from peer";
+                "peer_id" => peer_id.to_string(), "seq_no" => seq);
+            self.events
+                .push(PeerManagerEvent::MetaData(peer_id.clone()));
+        }
+    }
+
+    /// Records the metadata a peer sent under the sequence number it was requested for, and reads
+    /// the peer's capabilities from it.
+    pub fn meta_data_received(&mut self, peer_id: &PeerId, meta_data: &[u8]) {
+        // metadata requested on connection, before a ping announced its sequence number, is not
+        // recorded
+        let requested_seq = self
+            .network_globals
+            .peers
+            .write()
+            .meta_data_request_ended(peer_id);
+        if let Some(seq_number) = requested_seq {
+            self.meta_data_response(
+                peer_id,
+                MetaData {
+                    seq_number,
+                    attnets: Vec::new(),
+                },
+            );
         }
+        if self.capability_fields.is_empty() {
+            return;
+        }
+        let capabilities = self.capability_fields.parse(meta_data);
+        trace!(self.log, "Read peer capabilities";
+            "peer_id" => peer_id.to_string(), "capabilities" => hex::encode(capabilities.as_bytes()));
+        self.network_globals
+            .peers
+            .write()
+            .set_capabilities(peer_id, capabilities);
     }
 
     /// Received a metadata response from a peer.
@@ -373,7 +799,30 @@ impl PeerManager {
 
     // Handles the libp2p request to obtain multiaddrs for peer_id's in order to dial them.
     pub fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
-        if let Some(enr) = self.discovery.enr_of_peer(peer_id) {
+        let addresses = self.known_addresses(peer_id);
+        let stagger = match self.dial_stagger.as_mut() {
+            Some(stagger) if addresses.len() > 1 => stagger,
+            _ => return addresses,
+        };
+        // libp2p dials the first address, the others are dialed staggered
+        match self.dial_attempts.start(peer_id.clone(), addresses) {
+            Some(first) => {
+                stagger.insert(peer_id.clone());
+                vec![first]
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /* Internal functions */
+
+    /// The addresses a peer is known at: the addresses of a static peer or of its ENR, followed by
+    /// the addresses it listens on according to identify. The address the peer was last dialed
+    /// at successfully comes first.
+    fn known_addresses(&self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        let mut addresses = if let Some(addresses) = self.static_peers.get(peer_id) {
+            addresses.clone()
+        } else if let Some(enr) = self.discovery.enr_of_peer(peer_id) {
             // ENR's may have multiple Multiaddrs. The multi-addr associated with the UDP
             // port is removed, which is assumed to be associated with the discv5 protocol (and
             // therefore irrelevant for other libp2p components).
@@ -386,15 +835,36 @@ impl PeerManager {
                     })
                     .is_none()
             });
+            #[cfg(feature = "simulation")]
+            if self.memory_transport {
+                out_list = out_list
+                    .iter()
+                    .filter_map(crate::simulation::memory_address_of)
+                    .collect();
+            }
 
             out_list
+        } else if let Some(addresses) = self.rendezvous_addresses.peek(peer_id) {
+            addresses.clone()
         } else {
-            // PeerId is not known
             Vec::new()
+        };
+
+        match self.network_globals.peers.read().peer_info(peer_id) {
+            Some(info) => with_identified_addresses(addresses, info),
+            None => addresses,
         }
     }
 
-    /* Internal functions */
+    /// Dials the next address of a peer dialed at several addresses.
+    fn dial_address(&mut self, peer_id: PeerId, address: Multiaddr) {
+        trace!(self.log, "Dialing another address of peer";
+            "peer_id" => peer_id.to_string(), "address" => address.to_string());
+        if let Some(stagger) = self.dial_stagger.as_mut() {
+            stagger.insert(peer_id);
+        }
+        self.events.push(PeerManagerEvent::DialAddress(address));
+    }
 
     // The underlying discovery server has updated our external IP address. We send this up to
     // notify libp2p.
@@ -407,40 +877,293 @@ impl PeerManager {
         self.events.push(PeerManagerEvent::SocketUpdated(multiaddr));
     }
 
-    /// Peers that have been returned by discovery requests are dialed here if they are suitable.
+    /// Dials the static peers that are neither connected nor being dialed. Banned static peers are
+    /// dialed again once their ban expires.
+    fn reconnect_static_peers(&mut self) {
+        let peerdb = self.network_globals.peers.read();
+        for peer_id in static_peers_to_dial(self.static_peers.keys(), &peerdb) {
+            debug!(self.log, "Dialing static peer"; "peer_id" => peer_id.to_string());
+            self.events.push(PeerManagerEvent::Dial(peer_id));
+        }
+    }
+
+    /// Discovered peers that are not connected, dialed, banned or denied by the identity verifier
+    /// are queued on the dial scheduler, which paces the dials made by `dial_queued_peers`.
     ///
     /// NOTE: By dialing `PeerId`s and not multiaddrs, libp2p requests the multiaddr associated
     /// with a new `PeerId` which involves a discovery routing table lookup. We could dial the
     /// multiaddr here, however this could relate to duplicate PeerId's etc. If the lookup
     /// proves resource constraining, we should switch to multiaddr dialling here.
     fn peers_discovered(&mut self, peers: &[Enr], min_ttl: Option<Instant>) {
+        let local_enr = self.discovery.local_enr();
         for enr in peers {
             let peer_id = enr.peer_id();
-
-            // if we need more peers, attempt a connection
-            if self.network_globals.connected_or_dialing_peers() < self.target_peers
-                && !self
-                    .network_globals
-                    .peers
-                    .read()
-                    .is_connected_or_dialing(&peer_id)
-                && !self.network_globals.peers.read().peer_banned(&peer_id)
             {
-                debug!(self.log, "Dialing discovered peer"; "peer_id"=> peer_id.to_string());
-                // TODO: Update output
-                // This should be updated with the peer dialing. In fact created once the peer is
-                // dialed
-                if let Some(min_ttl) = min_ttl {
+                let mut peerdb = self.network_globals.peers.write();
+                // the ENR may be newer than the one the peer was indexed with
+                peerdb.set_services(&peer_id, enr_services(enr));
+                if peerdb.is_connected_or_dialing(&peer_id) || peerdb.peer_banned(&peer_id) {
+                    continue;
+                }
+            }
+            if let Some(verifier) = &self.identity_verifier {
+                if !verifier.verify_enr(enr) {
+                    trace!(self.log, "Discovered peer denied by the identity verifier";
+                        "peer_id" => peer_id.to_string());
                     self.network_globals
-                        .peers
-                        .write()
-                        .update_min_ttl(&peer_id, min_ttl);
+                        .rejected_identities
+                        .fetch_add(1, Ordering::Relaxed);
+                    continue;
                 }
-                self.events.push(PeerManagerEvent::Dial(peer_id));
+            }
+
+            // TODO: Update output
+            // This should be updated with the peer dialing. In fact created once the peer is
+            // dialed
+            if let Some(min_ttl) = min_ttl {
+                self.network_globals
+                    .peers
+                    .write()
+                    .update_min_ttl(&peer_id, min_ttl);
+            }
+            let priority = self.dial_priority(&local_enr, enr);
+            if self.dial_scheduler.queue(peer_id.clone(), priority) {
+                trace!(self.log, "Queued discovered peer for dialing";
+                    "peer_id" => peer_id.to_string(), "priority" => format!("{:?}", priority));
             }
         }
     }
 
+    /// Returns true if we have an open connection to the peer.
+    pub fn has_connection(&self, peer_id: &PeerId) -> bool {
+        self.connections.contains_key(peer_id)
+    }
+
+    /// The open connections to a peer and their endpoints.
+    pub fn connections_of(
+        &self,
+        peer_id: &PeerId,
+    ) -> impl Iterator<Item = &(ConnectionId, ConnectedPoint)> {
+        self.connections.get(peer_id).into_iter().flatten()
+    }
+
+    /// Every open connection and its endpoint.
+    pub fn connections(&self) -> impl Iterator<Item = (&PeerId, &ConnectionId, &ConnectedPoint)> {
+        self.connections.iter().flat_map(|(peer_id, connections)| {
+            connections
+                .iter()
+                .map(move |(conn_id, endpoint)| (peer_id, conn_id, endpoint))
+        })
+    }
+
+    /// Returns true if the peer is one of the configured rendezvous points.
+    pub fn is_rendezvous_point(&self, peer_id: &PeerId) -> bool {
+        self.rendezvous_points.contains(peer_id)
+    }
+
+    /// Returns the rendezvous points we are connected to.
+    pub fn connected_rendezvous_points(&self) -> Vec<PeerId> {
+        let peerdb = self.network_globals.peers.read();
+        self.rendezvous_points
+            .iter()
+            .filter(|peer_id| peerdb.is_connected(peer_id))
+            .cloned()
+            .collect()
+    }
+
+    /// Queues a peer found at a rendezvous point for dialing at `addresses`, unless it is
+    /// connected, being dialed or banned. Such peers have no ENR, so they are dialed after the
+    /// peers discv5 found.
+    pub fn rendezvous_peer_found(&mut self, peer_id: PeerId, addresses: Vec<Multiaddr>) {
+        self.rendezvous_addresses.put(peer_id.clone(), addresses);
+        {
+            let peerdb = self.network_globals.peers.read();
+            if peerdb.is_connected_or_dialing(&peer_id) || peerdb.peer_banned(&peer_id) {
+                return;
+            }
+        }
+        if self
+            .dial_scheduler
+            .queue(peer_id.clone(), DialPriority::default())
+        {
+            trace!(self.log, "Queued rendezvous peer for dialing"; "peer_id" => peer_id.to_string());
+        }
+    }
+
+    /// How urgently a discovered peer should be dialed, based on its ENR.
+    fn dial_priority(&self, local_enr: &Enr, enr: &Enr) -> DialPriority {
+        let same_fork = shares_fork(enr, local_enr);
+        let subnets = self
+            .priority_subnets
+            .iter()
+            .filter(|subnet_id| on_subnet(enr, **subnet_id))
+            .count();
+        DialPriority { same_fork, subnets }
+    }
+
+    /// Dials queued peers while we need more peers and the scheduler allows more pending dials.
+    fn dial_queued_peers(&mut self) {
+        while self.network_globals.connected_peers() + self.dial_scheduler.pending()
+            < self.target_peers
+        {
+            let network_globals = &self.network_globals;
+            let next = self.dial_scheduler.next_dial(|peer_id| {
+                let peerdb = network_globals.peers.read();
+                peerdb.is_connected_or_dialing(peer_id) || peerdb.peer_banned(peer_id)
+            });
+            match next {
+                Some(peer_id) => {
+                    debug!(self.log, "Dialing discovered peer"; "peer_id"=> peer_id.to_string());
+                    self.events.push(PeerManagerEvent::Dial(peer_id));
+                }
+                None => break,
+            }
+        }
+        self.preempt_for_subnet_peer();
+    }
+
+    /// At the peer target, makes room for the next queued peer if it is on a subnet below its
+    /// quota, or on a priority subnet fewer than `MIN_PRIORITY_SUBNET_PEERS` connected peers are
+    /// on.
+    fn preempt_for_subnet_peer(&mut self) {
+        if self.preemption_interval.is_none()
+            || self.network_globals.connected_peers() + self.dial_scheduler.pending()
+                < self.target_peers
+        {
+            return;
+        }
+        let network_globals = &self.network_globals;
+        let next = self.dial_scheduler.peek(|peer_id| {
+            let peerdb = network_globals.peers.read();
+            peerdb.is_connected_or_dialing(peer_id) || peerdb.peer_banned(peer_id)
+        });
+        let peer_id = match next {
+            Some(peer_id) => peer_id.clone(),
+            None => return,
+        };
+        let enr = match self.discovery.enr_of_peer(&peer_id) {
+            Some(enr) => enr,
+            None => return,
+        };
+        let connected_enrs = self.connected_enrs();
+        let below_quota = self
+            .network_globals
+            .subnet_peers
+            .read()
+            .iter()
+            .any(|(subnet_id, count)| count.is_short() && on_subnet(&enr, *subnet_id));
+        let needed = below_quota
+            || self.priority_subnets.iter().any(|subnet_id| {
+                on_subnet(&enr, *subnet_id)
+                    && connected_enrs
+                        .iter()
+                        .filter(|connected| on_subnet(connected, *subnet_id))
+                        .count()
+                        < MIN_PRIORITY_SUBNET_PEERS
+            });
+        if !needed || !self.preempt_inbound_peer() {
+            return;
+        }
+
+        let network_globals = &self.network_globals;
+        let next = self.dial_scheduler.next_dial(|peer_id| {
+            let peerdb = network_globals.peers.read();
+            peerdb.is_connected_or_dialing(peer_id) || peerdb.peer_banned(peer_id)
+        });
+        if let Some(peer_id) = next {
+            debug!(self.log, "Dialing peer for an under-provisioned subnet";
+                "peer_id" => peer_id.to_string());
+            self.events.push(PeerManagerEvent::Dial(peer_id));
+        }
+    }
+
+    /// The known ENRs of the connected peers.
+    fn connected_enrs(&mut self) -> Vec<Enr> {
+        let connected: Vec<PeerId> = self
+            .network_globals
+            .peers
+            .read()
+            .connected_peer_ids()
+            .cloned()
+            .collect();
+        connected
+            .iter()
+            .filter_map(|peer_id| self.discovery.enr_of_peer(peer_id))
+            .collect()
+    }
+
+    /// Counts the connected peers on the subnets with a quota, publishing the counts in
+    /// `NetworkGlobals::subnet_peers`, and searches for peers on the subnets below their quota.
+    fn maintain_subnet_quotas(&mut self) {
+        if self.subnet_quotas.is_empty() {
+            return;
+        }
+        let connected_enrs = self.connected_enrs();
+        let counts = self.subnet_quotas.update(|subnet_id| {
+            connected_enrs
+                .iter()
+                .filter(|enr| on_subnet(enr, subnet_id))
+                .count()
+        });
+
+        for (subnet_id, count) in counts.iter().filter(|(_, count)| count.is_short()) {
+            let reported = self
+                .network_globals
+                .subnet_peers
+                .read()
+                .get(subnet_id)
+                .map_or(false, |previous| previous.unsatisfiable);
+            if count.unsatisfiable && !reported {
+                warn!(self.log, "Not enough peers found for subnet";
+                    "subnet_id" => subnet_id, "connected" => count.connected, "quota" => count.quota);
+            }
+            if !self.discovery_disabled {
+                self.discovery.discover_subnet_peers(*subnet_id, None);
+            }
+        }
+        *self.network_globals.subnet_peers.write() = counts;
+    }
+
+    /// Disconnects the lowest reputation inbound peer to make room for a more valuable one.
+    /// Returns false if no peer may be pre-empted.
+    ///
+    /// To keep peers from being swapped back and forth, at most one peer is pre-empted per
+    /// pre-emption interval, and peers connected for less than the interval are kept. Trusted
+    /// peers are never pre-empted.
+    fn preempt_inbound_peer(&mut self) -> bool {
+        let interval = match self.preemption_interval {
+            Some(interval) => interval,
+            None => return false,
+        };
+        let now = clock::now();
+        if self
+            .last_preemption
+            .map_or(false, |last| now.duration_since(last) < interval)
+        {
+            return false;
+        }
+
+        let preempted = preemption_candidate(
+            &self.network_globals.peers.read(),
+            &self.connections,
+            &self.connected_since,
+            now,
+            interval,
+        );
+        match preempted {
+            Some(peer_id) => {
+                debug!(self.log, "Pre-empting inbound peer"; "peer_id" => peer_id.to_string());
+                self.last_preemption = Some(now);
+                self.network_globals
+                    .preempted_peers
+                    .fetch_add(1, Ordering::Relaxed);
+                self.events.push(PeerManagerEvent::DisconnectPeer(peer_id));
+                true
+            }
+            None => false,
+        }
+    }
+
     /// Registers a peer as connected. The `ingoing` parameter determines if the peer is being
     /// dialed or connecting to us.
     ///
@@ -452,6 +1175,10 @@ impl PeerManager {
         // TODO: remove after timed updates
         //self.update_reputations();
 
+        let services = self
+            .discovery
+            .enr_of_peer(peer_id)
+            .map(|enr| enr_services(&enr));
         {
             let mut peerdb = self.network_globals.peers.write();
             if peerdb.connection_status(peer_id).map(|c| c.is_banned()) == Some(true) {
@@ -466,11 +1193,19 @@ impl PeerManager {
                 ConnectingType::IngoingConnected => peerdb.connect_outgoing(peer_id),
                 ConnectingType::OutgoingConnected => peerdb.connect_ingoing(peer_id),
             }
+            if let Some(services) = services {
+                peerdb.set_services(peer_id, services);
+            }
         }
 
         // start a ping and status timer for the peer
         self.ping_peers.insert(peer_id.clone());
         self.status_peers.insert(peer_id.clone());
+        // the capabilities of the peer are in its metadata
+        if !self.capability_fields.is_empty() && !matches!(connection, ConnectingType::Dialing) {
+            self.events
+                .push(PeerManagerEvent::MetaData(peer_id.clone()));
+        }
 
         true
     }
@@ -501,7 +1236,7 @@ impl PeerManager {
         // TODO: Provide a back-off time for discovery queries. I.e Queue many initially, then only
         // perform discoveries over a larger fixed interval. Perhaps one every 6 heartbeats
         let peer_count = self.network_globals.connected_or_dialing_peers();
-        if peer_count < self.target_peers {
+        if peer_count < self.target_peers && !self.discovery_disabled {
             // If we need more peers, queue a discovery lookup.
             self.discovery.discover_peers();
         }
@@ -509,10 +1244,214 @@ impl PeerManager {
         // TODO: If we have too many peers, remove peers that are not required for subnet
         // validation.
 
-        // TODO: Perform peer reputation maintenance here
+        self.reconnect_static_peers();
+
+        self.maintain_subnet_quotas();
+
+        if let Some(half_life) = self.score_half_life {
+            self.network_globals
+                .peers
+                .write()
+                .decay_reputations(half_life);
+        }
+
+        if let Some(min_usefulness) = self.min_gossip_usefulness {
+            self.score_gossip_usefulness(min_usefulness);
+        }
+
+        if clock::now().duration_since(self.last_stored)
+            >= Duration::from_secs(STORE_PEERS_INTERVAL)
+        {
+            self.store_peers();
+        }
+    }
+
+    /// Penalizes the connected peers that deliver less than `min_usefulness` of their gossip
+    /// first, once they have sent enough messages to judge.
+    fn score_gossip_usefulness(&mut self, min_usefulness: f64) {
+        let useless: Vec<PeerId> = self
+            .network_globals
+            .peers
+            .read()
+            .connected_peers()
+            .filter(|(_, info)| {
+                let contribution = &info.gossip_contribution;
+                contribution.messages() >= MIN_USEFULNESS_MESSAGES
+                    && contribution
+                        .usefulness()
+                        .map_or(false, |usefulness| usefulness < min_usefulness)
+            })
+            .map(|(peer_id, _)| peer_id.clone())
+            .collect();
+        for peer_id in useless {
+            debug!(self.log, "Peer mostly sends duplicate gossip"; "peer_id" => peer_id.to_string());
+            self.report_peer(
+                &peer_id,
+                PeerAction::HighToleranceError,
+                ScoreSource::Gossip,
+            );
+        }
+    }
+
+    /// Writes the peers we are or have been connected to, with a known ENR, to disk on a blocking
+    /// task. Only peers that identified themselves are stored, leaving out peers that were dialed
+    /// but never connected. Nothing is written while a previous write is still running.
+    fn store_peers(&mut self) {
+        if self.storing.swap(true, Ordering::AcqRel) {
+            debug!(self.log, "Stored peers still being written");
+            return;
+        }
+        self.last_stored = clock::now();
+        let mut peers: Vec<(PeerId, Rep)> = self
+            .network_globals
+            .peers
+            .read()
+            .peers()
+            .filter(|(_, info)| {
+                let identified = match info.client.kind {
+                    client::ClientKind::Known(_) => true,
+                    client::ClientKind::Unknown => false,
+                };
+                identified
+                    && (info.connection_status.is_connected()
+                        || info.connection_status.is_disconnected())
+            })
+            .map(|(peer_id, info)| (peer_id.clone(), info.reputation))
+            .collect();
+        peers.sort_by(|a, b| b.1.cmp(&a.1));
+
+        let discovery = &mut self.discovery;
+        let stored_peers: Vec<StoredPeer> = peers
+            .into_iter()
+            .filter_map(|(peer_id, reputation)| {
+                let enr = discovery.enr_of_peer(&peer_id)?;
+                Some(StoredPeer { reputation, enr })
+            })
+            .take(self.max_stored_peers)
+            .collect();
+        let network_dir = self.network_dir.clone();
+        let storing = self.storing.clone();
+        let log = self.log.clone();
+        self.executor.spawn_blocking(
+            move || {
+                peer_store::save_peers(&network_dir, &stored_peers, &log);
+                storing.store(false, Ordering::Release);
+            },
+            "store_peers",
+        );
     }
 }
 
+/// Adds the addresses a peer listens on according to identify to `addresses`, and puts the
+/// address it was last dialed at successfully first.
+fn with_identified_addresses(mut addresses: Vec<Multiaddr>, info: &PeerInfo) -> Vec<Multiaddr> {
+    // a peer's loopback addresses are only reachable from its own host
+    for address in info.listening_addresses.iter().filter(|a| !is_loopback(a)) {
+        if !addresses.contains(address) {
+            addresses.push(address.clone());
+        }
+    }
+    if let Some(dialed) = &info.dialed_address {
+        addresses.retain(|address| address != dialed);
+        addresses.insert(0, dialed.clone());
+    }
+    addresses
+}
+
+/// Returns true if the address is on the loopback interface.
+fn is_loopback(address: &Multiaddr) -> bool {
+    address.iter().any(|protocol| match protocol {
+        MProtocol::Ip4(ip) => ip.is_loopback(),
+        MProtocol::Ip6(ip) => ip.is_loopback(),
+        _ => false,
+    })
+}
+
+/// Returns true if the `attnets` field of the ENR has the subnet's bit set.
+fn on_subnet(enr: &Enr, subnet_id: SubnetId) -> bool {
+    EnrPredicate::on_subnet(subnet_id).matches(enr)
+}
+
+/// Groups the addresses of static peers or rendezvous points, `what` they are, by the `PeerId`
+/// each of them ends with.
+fn peers_by_id(
+    addresses: &[Multiaddr],
+    what: &str,
+) -> error::Result<HashMap<PeerId, Vec<Multiaddr>>> {
+    let mut peers: HashMap<PeerId, Vec<Multiaddr>> = HashMap::new();
+    for address in addresses {
+        let mut address = address.clone();
+        let peer_id = match address.pop() {
+            Some(MProtocol::P2p(hash)) => PeerId::from_multihash(hash).ok(),
+            _ => None,
+        }
+        .ok_or_else(|| format!("{} address has no peer id: {}", what, address))?;
+        peers.entry(peer_id).or_default().push(address);
+    }
+    Ok(peers)
+}
+
+/// The static peers that need to be dialed: those neither connected, being dialed nor banned.
+fn static_peers_to_dial<'a>(
+    static_peers: impl IntoIterator<Item = &'a PeerId>,
+    peerdb: &PeerDB,
+) -> Vec<PeerId> {
+    static_peers
+        .into_iter()
+        .filter(|peer_id| !peerdb.is_connected_or_dialing(peer_id) && !peerdb.peer_banned(peer_id))
+        .cloned()
+        .collect()
+}
+
+/// Whether a peer identifying with `protocol_version` runs a protocol compatible with ours: one
+/// starting with the required prefix, if any.
+fn protocol_compatible(required_prefix: Option<&str>, protocol_version: &str) -> bool {
+    required_prefix.map_or(true, |prefix| protocol_version.starts_with(prefix))
+}
+
+/// Keeps one of the connections to a peer, the first dialed by us if `keep_dialed` and by the peer
+/// otherwise, or the oldest if none was. Returns the others, which are no longer tracked, so that
+/// each is closed once.
+fn close_duplicates(
+    connections: &mut SmallVec<[(ConnectionId, ConnectedPoint); 2]>,
+    keep_dialed: bool,
+) -> Vec<ConnectionId> {
+    let keep = connections
+        .iter()
+        .position(|(_, endpoint)| endpoint.is_dialer() == keep_dialed)
+        .unwrap_or(0);
+    let kept = connections.remove(keep);
+    let closed = connections.drain(..).map(|(id, _)| id).collect();
+    connections.push(kept);
+    closed
+}
+
+/// The peer pre-empted to make room for a subnet peer: the worst reputed untrusted peer that only
+/// has inbound connections, connected for at least `interval`.
+fn preemption_candidate(
+    peerdb: &PeerDB,
+    connections: &HashMap<PeerId, SmallVec<[(ConnectionId, ConnectedPoint); 2]>>,
+    connected_since: &HashMap<PeerId, Instant>,
+    now: Instant,
+    interval: Duration,
+) -> Option<PeerId> {
+    peerdb
+        .connected_peers()
+        .filter(|(peer_id, _)| {
+            let inbound = connections.get(*peer_id).map_or(false, |connections| {
+                connections
+                    .iter()
+                    .all(|(_, endpoint)| !endpoint.is_dialer())
+            });
+            let settled = connected_since
+                .get(*peer_id)
+                .map_or(false, |since| now.duration_since(*since) >= interval);
+            inbound && settled && !peerdb.is_trusted(peer_id)
+        })
+        .min_by_key(|(_, info)| info.reputation)
+        .map(|(peer_id, _)| peer_id.clone())
+}
+
 impl Stream for PeerManager {
     type Item = PeerManagerEvent;
 
@@ -527,11 +1466,42 @@ impl Stream for PeerManager {
             match event {
                 DiscoveryEvent::SocketUpdated(socket_addr) => self.socket_updated(socket_addr),
                 DiscoveryEvent::QueryResult(min_ttl, peers) => {
-                    self.peers_discovered(&peers, min_ttl)
+                    self.peers_discovered(&peers, min_ttl);
+                    if !peers.is_empty() {
+                        self.events.push(PeerManagerEvent::PeersDiscovered(peers));
+                    }
+                }
+                DiscoveryEvent::MatchingPeers(peers) => {
+                    self.peers_discovered(&peers, None);
+                    self.events.push(PeerManagerEvent::MatchingPeers(peers));
                 }
             }
         }
 
+        // dial queued peers as pending dials finish
+        if self.dial_scheduler.queued() > 0 {
+            self.dial_queued_peers();
+        }
+
+        // dial the next address of peers that have not connected within the stagger delay
+        loop {
+            let polled = match self.dial_stagger.as_mut() {
+                Some(stagger) => stagger.poll_next_unpin(cx),
+                None => break,
+            };
+            match polled {
+                Poll::Ready(Some(Ok(peer_id))) => {
+                    if let Some(address) = self.dial_attempts.next(&peer_id) {
+                        self.dial_address(peer_id, address);
+                    }
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    error!(self.log, "Failed to check for addresses to dial"; "error" => format!("{}",e))
+                }
+                Poll::Ready(None) | Poll::Pending => break,
+            }
+        }
+
         // poll the timeouts for pings and status'
         loop {
             match self.ping_peers.poll_next_unpin(cx) {
@@ -577,3 +1547,149 @@ enum ConnectingType {
     /// We have successfully dialed a peer.
     OutgoingConnected,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slog::{o, Drain};
+
+    #[test]
+    fn test_protocol_compatible() {
+        assert!(protocol_compatible(None, "other/libp2p"));
+        assert!(protocol_compatible(Some("mothra/"), "mothra/libp2p"));
+        assert!(!protocol_compatible(Some("mothra/"), "other/libp2p"));
+        // the prefix is matched in full, including its separator
+        assert!(!protocol_compatible(Some("mothra/"), "mothra2/libp2p"));
+        assert!(!protocol_compatible(Some("mothra/"), ""));
+    }
+
+    fn endpoint(dialed: bool) -> ConnectedPoint {
+        let address: Multiaddr = "/ip4/10.0.0.1/tcp/9000".parse().unwrap();
+        if dialed {
+            ConnectedPoint::Dialer { address }
+        } else {
+            ConnectedPoint::Listener {
+                local_addr: address.clone(),
+                send_back_addr: address,
+            }
+        }
+    }
+
+    #[test]
+    fn test_close_duplicates() {
+        let id = ConnectionId::new;
+        // we dialed the first connection and the peer the second
+        let mut local: SmallVec<[(ConnectionId, ConnectedPoint); 2]> = SmallVec::new();
+        local.push((id(1), endpoint(true)));
+        local.push((id(2), endpoint(false)));
+        let mut remote: SmallVec<[(ConnectionId, ConnectedPoint); 2]> = SmallVec::new();
+        remote.push((id(2), endpoint(true)));
+        remote.push((id(1), endpoint(false)));
+        // only one side has the lower peer id, and both keep the connection it dialed
+        assert_eq!(close_duplicates(&mut local, true), vec![id(2)]);
+        assert_eq!(close_duplicates(&mut remote, false), vec![id(2)]);
+        assert_eq!(local.as_slice(), &[(id(1), endpoint(true))]);
+        assert_eq!(remote.as_slice(), &[(id(1), endpoint(false))]);
+
+        // a later connection closes only itself, not the duplicates closed before
+        local.push((id(3), endpoint(false)));
+        assert_eq!(close_duplicates(&mut local, true), vec![id(3)]);
+        assert_eq!(local.as_slice(), &[(id(1), endpoint(true))]);
+
+        // with no connection in the preferred direction, the oldest is kept
+        let mut inbound: SmallVec<[(ConnectionId, ConnectedPoint); 2]> = SmallVec::new();
+        inbound.push((id(4), endpoint(false)));
+        inbound.push((id(5), endpoint(false)));
+        assert_eq!(close_duplicates(&mut inbound, true), vec![id(5)]);
+        assert_eq!(inbound.as_slice(), &[(id(4), endpoint(false))]);
+    }
+
+    #[test]
+    fn test_identified_addresses() {
+        let address = |a: &str| -> Multiaddr { a.parse().unwrap() };
+        let enr_address = address("/ip4/10.0.0.1/tcp/9000");
+        let mut info = PeerInfo {
+            listening_addresses: vec![
+                address("/ip4/127.0.0.1/tcp/9000"),
+                enr_address.clone(),
+                address("/ip6/::1/tcp/9000"),
+                address("/ip4/10.0.0.2/tcp/9000"),
+            ],
+            ..PeerInfo::default()
+        };
+
+        // loopback addresses and known addresses are not added
+        assert_eq!(
+            with_identified_addresses(vec![enr_address.clone()], &info),
+            vec![enr_address.clone(), address("/ip4/10.0.0.2/tcp/9000")]
+        );
+        // the address that connected last is dialed first
+        info.dialed_address = Some(address("/ip4/10.0.0.2/tcp/9000"));
+        assert_eq!(
+            with_identified_addresses(vec![enr_address.clone()], &info),
+            vec![address("/ip4/10.0.0.2/tcp/9000"), enr_address]
+        );
+    }
+
+    #[test]
+    fn test_static_peers_to_dial() {
+        let log = slog::Logger::root(slog::Discard.fuse(), o!());
+        let mut peerdb = PeerDB::new(&log);
+        let peers: Vec<PeerId> = (0..4).map(|_| PeerId::random()).collect();
+        peerdb.connect_outgoing(&peers[0]);
+        peerdb.dialing_peer(&peers[1]);
+        peerdb.connect_ingoing(&peers[2]);
+        peerdb.ban(&peers[2]);
+
+        // only the peer that is neither connected, being dialed nor banned is dialed
+        assert_eq!(
+            static_peers_to_dial(&peers, &peerdb),
+            vec![peers[3].clone()]
+        );
+        // and a peer is dialed again once it disconnects
+        peerdb.disconnect(&peers[0]);
+        assert_eq!(
+            static_peers_to_dial(&peers, &peerdb),
+            vec![peers[0].clone(), peers[3].clone()]
+        );
+    }
+
+    #[test]
+    fn test_preemption_candidate() {
+        let log = slog::Logger::root(slog::Discard.fuse(), o!());
+        let mut peerdb = PeerDB::new(&log);
+        let now = Instant::now();
+        let interval = Duration::from_secs(60);
+        let mut connections = HashMap::new();
+        let mut connected_since = HashMap::new();
+        let mut connect = |peer_id: &PeerId, dialed: bool, rep: Rep, since: Instant| {
+            peerdb.connect_ingoing(peer_id);
+            peerdb.peer_info_mut(peer_id).unwrap().reputation = rep;
+            let mut peer_connections = SmallVec::new();
+            peer_connections.push((ConnectionId::new(0), endpoint(dialed)));
+            connections.insert(peer_id.clone(), peer_connections);
+            connected_since.insert(peer_id.clone(), since);
+        };
+        let settled = now - interval;
+        let inbound = PeerId::random();
+        let worse_inbound = PeerId::random();
+        let outbound = PeerId::random();
+        let recent = PeerId::random();
+        connect(&inbound, false, 50, settled);
+        connect(&worse_inbound, false, 20, settled);
+        connect(&outbound, true, 10, settled);
+        connect(&recent, false, 10, now);
+
+        // the worst reputed settled peer with only inbound connections is chosen
+        assert_eq!(
+            preemption_candidate(&peerdb, &connections, &connected_since, now, interval),
+            Some(worse_inbound.clone())
+        );
+        // trusted peers are never pre-empted
+        peerdb.add_trusted_peer(worse_inbound);
+        assert_eq!(
+            preemption_candidate(&peerdb, &connections, &connected_since, now, interval),
+            Some(inbound)
+        );
+    }
+}
@@ -1,12 +1,17 @@
+use super::capabilities::Capabilities;
 use super::client::Client;
 use super::peerdb::{Rep, DEFAULT_REPUTATION};
-use crate::rpc::MetaData;
-use crate::types::SubnetId;
+use super::score::PeerScore;
+use crate::clock;
+use crate::rpc::{MetaData, StatusMessage};
+use crate::types::{ControlStats, GossipContribution, SubnetId};
 use crate::Multiaddr;
 use serde::{
     ser::{SerializeStructVariant, Serializer},
     Serialize,
 };
+use std::collections::{HashMap, HashSet};
+use std::mem::size_of;
 use std::time::Instant;
 use PeerConnectionStatus::*;
 
@@ -17,19 +22,54 @@ pub struct PeerInfo {
     _status: PeerStatus,
     /// The peers reputation
     pub reputation: Rep,
+    /// The components the reputation is derived from.
+    pub score: PeerScore,
     /// Client managing this peer
     pub client: Client,
     /// Connection status of this peer
     pub connection_status: PeerConnectionStatus,
     /// The known listening addresses of this peer.
     pub listening_addresses: Vec<Multiaddr>,
+    /// The address this peer was last dialed at successfully.
+    pub dialed_address: Option<Multiaddr>,
     /// The ENR subnet bitfield of the peer. This may be determined after it's initial
     /// connection.
     pub meta_data: Option<MetaData>,
+    /// The metadata sequence number announced by the peer when its metadata was last requested,
+    /// while the request is outstanding.
+    pub requested_meta_data_seq: Option<u64>,
+    /// The capability flags read from the peer's metadata.
+    pub capabilities: Capabilities,
+    /// The last status the peer sent, if statuses are typed.
+    pub status: Option<StatusMessage>,
     /// The time we would like to retain this peer. After this time, the peer is no longer
     /// necessary.
     #[serde(skip)]
     pub min_ttl: Option<Instant>,
+    /// The number of requests from this peer that were cancelled before we responded.
+    pub cancelled_requests: usize,
+    /// The most recent request from this peer that was cancelled before we responded.
+    pub last_cancelled_request: Option<CancelledRequest>,
+    /// The gossipsub control messages exchanged with this peer.
+    pub gossip_control: ControlStats,
+    /// The gossip messages this peer delivered first, and the duplicates it sent.
+    pub gossip_contribution: GossipContribution,
+    /// The topics the peer is subscribed to, if it sent them through the topics RPC.
+    pub topics: Option<HashSet<String>>,
+    /// The application service records advertised in the peer's ENR, by service key.
+    pub services: HashMap<String, Vec<u8>>,
+}
+
+/// An inbound request that was abandoned before a response was sent.
+#[derive(Clone, Debug, Serialize)]
+pub struct CancelledRequest {
+    /// The protocol of the request.
+    pub protocol: String,
+    /// Why the request was cancelled.
+    pub cause: String,
+    /// When the request was cancelled.
+    #[serde(skip)]
+    pub at: Instant,
 }
 
 impl Default for PeerInfo {
@@ -37,16 +77,55 @@ impl Default for PeerInfo {
         PeerInfo {
             _status: Default::default(),
             reputation: DEFAULT_REPUTATION,
+            score: PeerScore::default(),
             client: Client::default(),
             connection_status: Default::default(),
             listening_addresses: vec![],
+            dialed_address: None,
             meta_data: None,
+            requested_meta_data_seq: None,
+            capabilities: Capabilities::default(),
+            status: None,
             min_ttl: None,
+            cancelled_requests: 0,
+            last_cancelled_request: None,
+            gossip_control: ControlStats::default(),
+            gossip_contribution: GossipContribution::default(),
+            topics: None,
+            services: HashMap::new(),
         }
     }
 }
 
 impl PeerInfo {
+    /// Returns the approximate bytes held by this information, including what it owns on the
+    /// heap.
+    pub fn approximate_size(&self) -> usize {
+        let client = &self.client;
+        let client = client.version.len()
+            + client.os_version.len()
+            + client.protocol_version.len()
+            + client.agent_string.as_ref().map_or(0, String::len);
+        let addresses = self
+            .listening_addresses
+            .iter()
+            .chain(&self.dialed_address)
+            .map(|address| size_of::<Multiaddr>() + address.len())
+            .sum::<usize>();
+        let topics = self.topics.iter().flatten().map(String::len).sum::<usize>();
+        let services = self
+            .services
+            .iter()
+            .map(|(key, record)| key.len() + record.len())
+            .sum::<usize>();
+        let meta_data = self
+            .meta_data
+            .as_ref()
+            .map_or(0, |meta_data| meta_data.attnets.len())
+            + self.capabilities.as_bytes().len();
+        size_of::<PeerInfo>() + client + addresses + topics + services + meta_data
+    }
+
     /// Returns if the peer is subscribed to a given `SubnetId`
     pub fn on_subnet(&self, subnet_id: SubnetId) -> bool {
         if let Some(meta_data) = &self.meta_data {
@@ -59,6 +138,13 @@ impl PeerInfo {
         }
         false
     }
+
+    /// Returns true if the peer told us it is subscribed to `topic`.
+    pub fn serves_topic(&self, topic: &str) -> bool {
+        self.topics
+            .as_ref()
+            .map_or(false, |topics| topics.contains(topic))
+    }
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -199,14 +285,14 @@ impl PeerConnectionStatus {
     /// Modifies the status to Disconnected and sets the last seen instant to now
     pub fn disconnect(&mut self) {
         *self = Disconnected {
-            since: Instant::now(),
+            since: clock::now(),
         };
     }
 
     /// Modifies the status to Banned
     pub fn ban(&mut self) {
         *self = Banned {
-            since: Instant::now(),
+            since: clock::now(),
         };
     }
 
@@ -0,0 +1,105 @@
+//! Keeps the peers we have been connected to on disk, so they can be dialed again on startup.
+//!
+//! Each line of the file holds the reputation of a peer and its ENR in base64, separated by a
+//! space. Lines that cannot be read are skipped.
+use super::peerdb::Rep;
+use crate::Enr;
+use slog::{debug, warn};
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+use std::str::FromStr;
+
+/// The file in the network directory holding the stored peers.
+pub const PEER_STORE_FILENAME: &str = "peers.dat";
+/// The file the stored peers are written to before replacing `PEER_STORE_FILENAME`.
+const PEER_STORE_TEMP_FILENAME: &str = "peers.dat.tmp";
+
+/// A previously connected peer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredPeer {
+    pub reputation: Rep,
+    pub enr: Enr,
+}
+
+/// Loads up to `max_peers` stored peers, best reputation first. A missing file holds no peers.
+pub fn load_peers(dir: &Path, max_peers: usize, log: &slog::Logger) -> Vec<StoredPeer> {
+    let mut contents = String::new();
+    if let Err(e) =
+        File::open(dir.join(PEER_STORE_FILENAME)).and_then(|mut f| f.read_to_string(&mut contents))
+    {
+        debug!(log, "No stored peers loaded"; "error" => e.to_string());
+        return Vec::new();
+    }
+
+    let mut peers: Vec<StoredPeer> = contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let reputation = fields.next()?.parse().ok()?;
+            let enr = Enr::from_str(fields.next()?).ok()?;
+            Some(StoredPeer { reputation, enr })
+        })
+        .collect();
+    peers.sort_by(|a, b| b.reputation.cmp(&a.reputation));
+    peers.truncate(max_peers);
+    debug!(log, "Loaded stored peers"; "peers" => peers.len());
+    peers
+}
+
+/// Replaces the stored peers. The peers are written to a temporary file that is then renamed over
+/// the stored peers, so an interrupted write leaves the previous peers in place.
+pub fn save_peers(dir: &Path, peers: &[StoredPeer], log: &slog::Logger) {
+    let contents: String = peers
+        .iter()
+        .map(|peer| format!("{} {}\n", peer.reputation, peer.enr.to_base64()))
+        .collect();
+    let _ = std::fs::create_dir_all(dir);
+    let temp_file = dir.join(PEER_STORE_TEMP_FILENAME);
+    if let Err(e) = File::create(&temp_file)
+        .and_then(|mut f| f.write_all(contents.as_bytes()).and_then(|_| f.sync_all()))
+        .and_then(|_| std::fs::rename(&temp_file, dir.join(PEER_STORE_FILENAME)))
+    {
+        warn!(log, "Could not write stored peers to file";
+            "file" => format!("{:?}", dir.join(PEER_STORE_FILENAME)), "error" => e.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::generate_enr;
+    use libp2p::core::identity::Keypair;
+    use slog::{o, Discard};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_save_and_load() {
+        let log = slog::Logger::root(Discard, o!());
+        let dir = TempDir::new("peer_store").unwrap();
+        assert!(load_peers(dir.path(), 10, &log).is_empty());
+
+        let peer = |reputation| StoredPeer {
+            reputation,
+            enr: generate_enr(&Keypair::generate_secp256k1(), None, None, Some(9000)).unwrap(),
+        };
+        let peers = vec![peer(10), peer(200), peer(100)];
+        save_peers(dir.path(), &peers, &log);
+        // the temporary file is renamed over the stored peers
+        assert!(!dir.path().join(PEER_STORE_TEMP_FILENAME).exists());
+
+        // a corrupted line is skipped
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(dir.path().join(PEER_STORE_FILENAME))
+            .unwrap();
+        file.write_all(b"50 not-an-enr\n").unwrap();
+
+        let loaded = load_peers(dir.path(), 10, &log);
+        assert_eq!(
+            loaded,
+            vec![peers[1].clone(), peers[2].clone(), peers[0].clone()]
+        );
+        assert_eq!(load_peers(dir.path(), 1, &log), vec![peers[1].clone()]);
+    }
+}
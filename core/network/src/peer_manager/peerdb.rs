@@ -1,10 +1,16 @@
-use super::peer_info::{PeerConnectionStatus, PeerInfo};
+use super::capabilities::Capabilities;
+use super::peer_info::{CancelledRequest, PeerConnectionStatus, PeerInfo};
+use super::query::{PeerQuery, PeerQueryResult};
+use super::score::{PeerScoreSnapshot, ScoreSource};
+use crate::clock;
 use crate::rpc::methods::MetaData;
+use crate::rpc::{Protocol, RPCError};
 use crate::types::SubnetId;
-use crate::PeerId;
+use crate::{Multiaddr, PeerId};
 use slog::{crit, debug, trace, warn};
-use std::collections::{hash_map::Entry, HashMap};
-use std::time::Instant;
+use std::collections::{hash_map::Entry, BTreeMap, HashMap, HashSet};
+use std::mem::size_of;
+use std::time::{Duration, Instant};
 
 /// A peer's reputation (perceived potential usefulness)
 pub type Rep = u8;
@@ -15,8 +21,8 @@ pub struct RepChange {
     diff: Rep,
 }
 
-/// Max number of disconnected nodes to remember
-const MAX_DC_PEERS: usize = 30;
+/// The default maximum number of disconnected nodes to remember.
+pub const DEFAULT_MAX_DC_PEERS: usize = 30;
 
 /// The default starting reputation for an unknown peer.
 pub const DEFAULT_REPUTATION: Rep = 50;
@@ -27,6 +33,10 @@ pub struct PeerDB {
     peers: HashMap<PeerId, PeerInfo>,
     /// Tracking of number of disconnected nodes
     n_dc: usize,
+    /// The maximum number of disconnected nodes to remember.
+    max_dc_peers: usize,
+    /// Peers that are never dropped from the database.
+    trusted_peers: HashSet<PeerId>,
     /// PeerDB's logger
     log: slog::Logger,
 }
@@ -57,12 +67,56 @@ impl PeerDB {
         Self {
             log: log.clone(),
             n_dc: 0,
+            max_dc_peers: DEFAULT_MAX_DC_PEERS,
+            trusted_peers: HashSet::new(),
             peers: HashMap::new(),
         }
     }
 
+    /// Sets the maximum number of disconnected peers to remember, dropping peers if there are
+    /// more.
+    pub fn set_max_disconnected_peers(&mut self, max_dc_peers: usize) {
+        self.max_dc_peers = max_dc_peers;
+        self.shrink_to_fit();
+    }
+
+    /// Marks a peer as trusted. Trusted peers are remembered after they disconnect.
+    pub fn add_trusted_peer(&mut self, peer_id: PeerId) {
+        self.trusted_peers.insert(peer_id);
+    }
+
+    /// Returns true if the peer is trusted.
+    pub fn is_trusted(&self, peer_id: &PeerId) -> bool {
+        self.trusted_peers.contains(peer_id)
+    }
+
     /* Getters */
 
+    /// The number of peers in the database.
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// Returns true if no peers are known.
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// Returns the approximate bytes held by the database.
+    pub fn approximate_size(&self) -> usize {
+        self.peers
+            .iter()
+            .map(|(peer_id, info)| {
+                size_of::<PeerId>() + peer_id.as_bytes().len() + info.approximate_size()
+            })
+            .sum()
+    }
+
+    /// The number of disconnected peers in the database.
+    pub fn disconnected_len(&self) -> usize {
+        self.n_dc
+    }
+
     /// Gives the reputation of a peer, or DEFAULT_REPUTATION if it is unknown.
     pub fn reputation(&self, peer_id: &PeerId) -> Rep {
         self.peers
@@ -139,6 +193,42 @@ impl PeerDB {
             .map(|(peer_id, _)| peer_id)
     }
 
+    /// Gives an iterator of all connected peers that told us they are subscribed to `topic`.
+    pub fn peers_on_topic<'a>(&'a self, topic: &'a str) -> impl Iterator<Item = &'a PeerId> {
+        self.peers
+            .iter()
+            .filter(move |(_, info)| {
+                info.connection_status.is_connected() && info.serves_topic(topic)
+            })
+            .map(|(peer_id, _)| peer_id)
+    }
+
+    /// Gives an iterator of all connected peers whose metadata sets the capability `bit`.
+    pub fn peers_with_capability(&self, bit: usize) -> impl Iterator<Item = &PeerId> {
+        self.peers
+            .iter()
+            .filter(move |(_, info)| {
+                info.connection_status.is_connected() && info.capabilities.has(bit)
+            })
+            .map(|(peer_id, _)| peer_id)
+    }
+
+    /// Gives the known peers, other than banned ones, that advertise a record for the service
+    /// `service_key`, with the record.
+    pub fn peers_providing<'a>(
+        &'a self,
+        service_key: &'a str,
+    ) -> impl Iterator<Item = (&'a PeerId, &'a [u8])> {
+        self.peers
+            .iter()
+            .filter(|(_, info)| !info.connection_status.is_banned())
+            .filter_map(move |(peer_id, info)| {
+                info.services
+                    .get(service_key)
+                    .map(|record| (peer_id, record.as_slice()))
+            })
+    }
+
     /// Gives the ids of all known disconnected peers.
     pub fn disconnected_peers(&self) -> impl Iterator<Item = &PeerId> {
         self.peers
@@ -216,10 +306,18 @@ impl PeerDB {
             self.n_dc = self.n_dc.saturating_sub(1);
         }
         info.connection_status = PeerConnectionStatus::Dialing {
-            since: Instant::now(),
+            since: clock::now(),
         };
     }
 
+    /// Records the address a peer was dialed at successfully, to dial it there first next time.
+    pub fn dialed_at(&mut self, peer_id: &PeerId, address: Multiaddr) {
+        self.peers
+            .entry(peer_id.clone())
+            .or_default()
+            .dialed_address = Some(address);
+    }
+
     /// Update min ttl of a peer.
     pub fn update_min_ttl(&mut self, peer_id: &PeerId, min_ttl: Instant) {
         let info = self.peers.entry(peer_id.clone()).or_default();
@@ -229,7 +327,7 @@ impl PeerDB {
             info.min_ttl = Some(min_ttl);
 
             let min_ttl_secs = min_ttl
-                .checked_duration_since(Instant::now())
+                .checked_duration_since(clock::now())
                 .map(|duration| duration.as_secs())
                 .unwrap_or_else(|| 0);
             debug!(self.log, "Updating the time a peer is required for"; "peer_id" => peer_id.to_string(), "future_min_ttl_secs" => min_ttl_secs);
@@ -249,7 +347,7 @@ impl PeerDB {
                     info.min_ttl = Some(min_ttl);
                 }
                 let min_ttl_secs = min_ttl
-                    .checked_duration_since(Instant::now())
+                    .checked_duration_since(clock::now())
                     .map(|duration| duration.as_secs())
                     .unwrap_or_else(|| 0);
                 trace!(log, "Updating minimum duration a peer is required for"; "peer_id" => peer_id.to_string(), "min_ttl" => min_ttl_secs);
@@ -291,20 +389,32 @@ impl PeerDB {
         self.shrink_to_fit();
     }
 
-    /// Drops the peers with the lowest reputation so that the number of
-    /// disconnected peers is less than MAX_DC_PEERS
+    /// Drops disconnected peers so that there are at most `max_dc_peers` of them. The peers with
+    /// the lowest reputation go first and, among equals, those disconnected the longest. Trusted
+    /// peers are kept, as are banned peers, which are not counted as disconnected.
     pub fn shrink_to_fit(&mut self) {
-        // for caution, but the difference should never be > 1
-        while self.n_dc > MAX_DC_PEERS {
+        while self.n_dc > self.max_dc_peers {
+            let trusted_peers = &self.trusted_peers;
             let to_drop = self
                 .peers
                 .iter()
-                .filter(|(_, info)| info.connection_status.is_disconnected())
-                .min_by_key(|(_, info)| info.reputation)
-                .map(|(id, _)| id.clone())
-                .unwrap(); // should be safe since n_dc > MAX_DC_PEERS > 0
-            self.peers.remove(&to_drop);
-            self.n_dc = self.n_dc.saturating_sub(1);
+                .filter(|(id, _)| !trusted_peers.contains(id))
+                .filter_map(|(id, info)| match info.connection_status {
+                    PeerConnectionStatus::Disconnected { since } => {
+                        Some((id, info.reputation, since))
+                    }
+                    _ => None,
+                })
+                .min_by_key(|(_, reputation, since)| (*reputation, *since))
+                .map(|(id, _, _)| id.clone());
+            match to_drop {
+                Some(to_drop) => {
+                    self.peers.remove(&to_drop);
+                    self.n_dc = self.n_dc.saturating_sub(1);
+                }
+                // only trusted peers are left
+                None => break,
+            }
         }
     }
 
@@ -331,19 +441,91 @@ impl PeerDB {
         }
     }
 
+    /// Returns true if the metadata of a peer announcing the sequence number `seq` in a ping
+    /// should be requested, that is if `seq` is newer than the sequence number of the metadata
+    /// known and of any metadata already requested. The request is then recorded.
+    pub fn request_meta_data(&mut self, peer_id: &PeerId, seq: u64) -> bool {
+        let info = match self.peers.get_mut(peer_id) {
+            Some(info) => info,
+            None => return false,
+        };
+        let known = info
+            .meta_data
+            .as_ref()
+            .map(|meta_data| meta_data.seq_number);
+        let newer = |other: Option<u64>| other.map_or(true, |other| other < seq);
+        let wanted = newer(known) && newer(info.requested_meta_data_seq);
+        if wanted {
+            info.requested_meta_data_seq = Some(seq);
+        }
+        wanted
+    }
+
+    /// Ends the outstanding metadata request to a peer, returning the sequence number it was
+    /// requested for.
+    pub fn meta_data_request_ended(&mut self, peer_id: &PeerId) -> Option<u64> {
+        self.peers
+            .get_mut(peer_id)
+            .and_then(|info| info.requested_meta_data_seq.take())
+    }
+
+    /// Replaces the topics a peer is subscribed to.
+    pub fn add_topics(&mut self, peer_id: &PeerId, topics: Vec<String>) {
+        if let Some(peer_info) = self.peers.get_mut(peer_id) {
+            peer_info.topics = Some(topics.into_iter().collect());
+        } else {
+            debug!(self.log, "Topics received from an unknown peer"; "peer_id" => peer_id.to_string());
+        }
+    }
+
+    /// Replaces the service records of a known peer with those of its ENR. The records of peers
+    /// not in the db are indexed once they are dialed or connect.
+    pub fn set_services(&mut self, peer_id: &PeerId, services: HashMap<String, Vec<u8>>) {
+        if let Some(peer_info) = self.peers.get_mut(peer_id) {
+            peer_info.services = services;
+        }
+    }
+
+    /// Sets the capabilities of a peer, read from its metadata.
+    pub fn set_capabilities(&mut self, peer_id: &PeerId, capabilities: Capabilities) {
+        if let Some(peer_info) = self.peers.get_mut(peer_id) {
+            peer_info.capabilities = capabilities;
+        }
+    }
+
+    /// Records that a request from a peer was cancelled before we responded to it.
+    pub fn request_cancelled(&mut self, peer_id: &PeerId, protocol: Protocol, cause: &RPCError) {
+        if let Some(peer_info) = self.peers.get_mut(peer_id) {
+            peer_info.cancelled_requests += 1;
+            peer_info.last_cancelled_request = Some(CancelledRequest {
+                protocol: protocol.to_string(),
+                cause: cause.to_string(),
+                at: clock::now(),
+            });
+        } else {
+            debug!(self.log, "Request cancelled for an unknown peer"; "peer_id" => peer_id.to_string());
+        }
+    }
+
     /// Sets the reputation of peer.
     #[allow(dead_code)]
     pub(super) fn set_reputation(&mut self, peer_id: &PeerId, rep: Rep) {
         if let Some(peer_info) = self.peers.get_mut(peer_id) {
-            peer_info.reputation = rep;
+            peer_info.score.set_reputation(rep);
+            peer_info.reputation = peer_info.score.reputation();
         } else {
             crit!(self.log, "Tried to modify reputation for an unknown peer"; "peer_id" => peer_id.to_string());
         }
     }
 
-    /// Adds to a peer's reputation by `change`. If the reputation exceeds Rep's
-    /// upper (lower) bounds, it stays at the maximum (minimum) value.
-    pub(super) fn add_reputation(&mut self, peer_id: &PeerId, change: RepChange) {
+    /// Adds to a peer's reputation by `change`, attributing it to `source`. If the reputation
+    /// exceeds Rep's upper (lower) bounds, it stays at the maximum (minimum) value.
+    pub(super) fn add_reputation(
+        &mut self,
+        peer_id: &PeerId,
+        change: RepChange,
+        source: ScoreSource,
+    ) {
         let log_ref = &self.log;
         let info = match self.peers.entry(peer_id.clone()) {
             Entry::Vacant(_) => {
@@ -354,17 +536,74 @@ impl PeerDB {
             Entry::Occupied(e) => e.into_mut(),
         };
 
-        info.reputation = if change.is_good {
-            info.reputation.saturating_add(change.diff)
-        } else {
-            info.reputation.saturating_sub(change.diff)
-        };
+        let diff = f64::from(change.diff);
+        info.score
+            .add(source, if change.is_good { diff } else { -diff });
+        info.reputation = info.score.reputation();
+    }
+
+    /// Decays the reputation components of all known peers, halving them every `half_life`.
+    pub(super) fn decay_reputations(&mut self, half_life: Duration) {
+        let now = clock::now();
+        for info in self.peers.values_mut() {
+            info.score.decay(half_life, now);
+            info.reputation = info.score.reputation();
+        }
+    }
+
+    /// Returns the reputation and its components for every known peer, to help determine why a
+    /// peer was disconnected or banned.
+    pub fn score_snapshot(&self) -> Vec<PeerScoreSnapshot> {
+        self.peers
+            .iter()
+            .map(|(peer_id, info)| PeerScoreSnapshot {
+                peer_id: peer_id.to_string(),
+                reputation: info.reputation,
+                connection_status: info.connection_status.clone(),
+                score: info.score.clone(),
+            })
+            .collect()
+    }
+
+    /// Counts the connected peers per client name and, within each client, per client version,
+    /// as reported by Identify.
+    pub fn client_counts(&self) -> BTreeMap<String, BTreeMap<String, usize>> {
+        let mut counts: BTreeMap<String, BTreeMap<String, usize>> = BTreeMap::new();
+        for (_, info) in self.connected_peers() {
+            *counts
+                .entry(info.client.kind.name().to_string())
+                .or_default()
+                .entry(info.client.version.clone())
+                .or_default() += 1;
+        }
+        counts
+    }
+
+    /// Returns a page of the peers matching `query`, ordered by `PeerId`.
+    pub fn query(&self, query: &PeerQuery) -> PeerQueryResult {
+        let now = clock::now();
+        let mut matching: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, info)| query.matches(info, now))
+            .collect();
+        matching.sort_by(|(a, _), (b, _)| a.as_bytes().cmp(b.as_bytes()));
+        let total = matching.len();
+        let peers = matching
+            .into_iter()
+            .skip(query.offset)
+            .take(query.limit.unwrap_or(usize::max_value()))
+            .map(|(peer_id, info)| (peer_id.clone(), info.clone()))
+            .collect();
+        PeerQueryResult { peers, total }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::peer_manager::client::ClientKind;
+    use crate::peer_manager::{ConnectionDirection, ConnectionState};
     use slog::{o, Drain};
 
     pub fn build_log(level: slog::Level, enabled: bool) -> slog::Logger {
@@ -384,6 +623,30 @@ mod tests {
         PeerDB::new(&log)
     }
 
+    #[test]
+    fn test_meta_data_requested_once_per_seq() {
+        let mut pdb = get_db();
+        let peer = PeerId::random();
+        pdb.connect_ingoing(&peer);
+
+        assert!(pdb.request_meta_data(&peer, 1));
+        // the same sequence number while the request is outstanding
+        assert!(!pdb.request_meta_data(&peer, 1));
+        assert_eq!(pdb.meta_data_request_ended(&peer), Some(1));
+        pdb.add_metadata(
+            &peer,
+            MetaData {
+                seq_number: 1,
+                attnets: Vec::new(),
+            },
+        );
+        // the same sequence number once the metadata is known
+        assert!(!pdb.request_meta_data(&peer, 1));
+        assert!(!pdb.request_meta_data(&peer, 0));
+        assert!(pdb.request_meta_data(&peer, 2));
+        assert!(!pdb.request_meta_data(&PeerId::random(), 1));
+    }
+
     #[test]
     fn test_peer_connected_successfully() {
         let mut pdb = get_db();
@@ -440,28 +703,142 @@ mod tests {
         let random_peer = PeerId::random();
         let change = RepChange::good(0);
         pdb.connect_ingoing(&random_peer);
-        pdb.add_reputation(&random_peer, change);
+        pdb.add_reputation(&random_peer, change, ScoreSource::Transport);
         assert_eq!(pdb.reputation(&random_peer), DEFAULT_REPUTATION);
 
         // overflowing change is capped
         let random_peer = PeerId::random();
         let change = RepChange::worst();
         pdb.connect_ingoing(&random_peer);
-        pdb.add_reputation(&random_peer, change);
+        pdb.add_reputation(&random_peer, change, ScoreSource::Transport);
         assert_eq!(pdb.reputation(&random_peer), Rep::min_value());
 
         let random_peer = PeerId::random();
         let change = RepChange::good(Rep::max_value());
         pdb.connect_ingoing(&random_peer);
-        pdb.add_reputation(&random_peer, change);
+        pdb.add_reputation(&random_peer, change, ScoreSource::Transport);
         assert_eq!(pdb.reputation(&random_peer), Rep::max_value());
     }
 
+    #[test]
+    fn test_reputation_decay() {
+        let mut pdb = get_db();
+        let random_peer = PeerId::random();
+        pdb.connect_ingoing(&random_peer);
+        pdb.add_reputation(&random_peer, RepChange::bad(40), ScoreSource::Transport);
+        pdb.add_reputation(&random_peer, RepChange::bad(8), ScoreSource::Application);
+        assert_eq!(pdb.reputation(&random_peer), DEFAULT_REPUTATION - 48);
+
+        // after one half-life each component is halved
+        let half_life = Duration::from_secs(60);
+        let info = pdb.peer_info_mut(&random_peer).unwrap();
+        info.score.decay(half_life, clock::now() + half_life);
+        info.reputation = info.score.reputation();
+        assert_eq!(pdb.reputation(&random_peer), DEFAULT_REPUTATION - 24);
+
+        let snapshot = pdb.score_snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].reputation, DEFAULT_REPUTATION - 24);
+        assert!((snapshot[0].score.transport_errors + 20.0).abs() < 1e-6);
+        assert!((snapshot[0].score.application_reports + 4.0).abs() < 1e-6);
+        assert_eq!(snapshot[0].score.gossip_penalties, 0.0);
+    }
+
+    #[test]
+    fn test_client_counts() {
+        let mut pdb = get_db();
+        let versions = [
+            ("mothra", "v0.1.0"),
+            ("mothra", "v0.1.0"),
+            ("mothra", "v0.2.0"),
+        ];
+        for (kind, version) in versions.iter() {
+            let peer = PeerId::random();
+            pdb.connect_ingoing(&peer);
+            let client = &mut pdb.peer_info_mut(&peer).unwrap().client;
+            client.kind = ClientKind::Known(kind.to_string());
+            client.version = version.to_string();
+        }
+        // peers without Identify info and disconnected peers
+        pdb.connect_outgoing(&PeerId::random());
+        let gone = PeerId::random();
+        pdb.connect_ingoing(&gone);
+        pdb.disconnect(&gone);
+
+        let counts = pdb.client_counts();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts["mothra"]["v0.1.0"], 2);
+        assert_eq!(counts["mothra"]["v0.2.0"], 1);
+        assert_eq!(counts["unknown"]["unknown"], 1);
+    }
+
+    #[test]
+    fn test_query() {
+        let mut pdb = get_db();
+        let mut outbound = Vec::new();
+        for _ in 0..5 {
+            let peer = PeerId::random();
+            pdb.connect_outgoing(&peer);
+            outbound.push(peer);
+        }
+        let inbound = PeerId::random();
+        pdb.connect_ingoing(&inbound);
+        pdb.set_reputation(&inbound, 10);
+        let banned = PeerId::random();
+        pdb.connect_ingoing(&banned);
+        pdb.ban(&banned);
+
+        let query = PeerQuery {
+            state: Some(ConnectionState::Connected),
+            ..Default::default()
+        };
+        assert_eq!(pdb.query(&query).total, 6);
+
+        let query = PeerQuery {
+            direction: Some(ConnectionDirection::Inbound),
+            ..Default::default()
+        };
+        let result = pdb.query(&query);
+        assert_eq!(result.total, 1);
+        assert_eq!(result.peers[0].0, inbound);
+
+        let query = PeerQuery {
+            max_reputation: Some(20),
+            ..Default::default()
+        };
+        assert_eq!(pdb.query(&query).total, 1);
+
+        let query = PeerQuery {
+            state: Some(ConnectionState::Banned),
+            ..Default::default()
+        };
+        assert_eq!(pdb.query(&query).peers[0].0, banned);
+
+        // pages don't overlap and cover every match in order
+        outbound.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        let mut query = PeerQuery {
+            direction: Some(ConnectionDirection::Outbound),
+            limit: Some(2),
+            ..Default::default()
+        };
+        let mut paged = Vec::new();
+        loop {
+            let result = pdb.query(&query);
+            assert_eq!(result.total, 5);
+            if result.peers.is_empty() {
+                break;
+            }
+            paged.extend(result.peers.into_iter().map(|(peer_id, _)| peer_id));
+            query.offset += 2;
+        }
+        assert_eq!(paged, outbound);
+    }
+
     #[test]
     fn test_disconnected_are_bounded() {
         let mut pdb = get_db();
 
-        for _ in 0..MAX_DC_PEERS + 1 {
+        for _ in 0..DEFAULT_MAX_DC_PEERS + 1 {
             let p = PeerId::random();
             pdb.connect_ingoing(&p);
         }
@@ -471,7 +848,38 @@ mod tests {
             pdb.disconnect(&p);
         }
 
-        assert_eq!(pdb.n_dc, MAX_DC_PEERS);
+        assert_eq!(pdb.n_dc, DEFAULT_MAX_DC_PEERS);
+    }
+
+    #[test]
+    fn test_eviction_keeps_trusted_and_reputable_peers() {
+        let mut pdb = get_db();
+        pdb.set_max_disconnected_peers(2);
+
+        let trusted = PeerId::random();
+        let good = PeerId::random();
+        let bad = PeerId::random();
+        let newest = PeerId::random();
+        pdb.add_trusted_peer(trusted.clone());
+        for p in &[&trusted, &good, &bad, &newest] {
+            pdb.connect_ingoing(p);
+        }
+        pdb.set_reputation(&trusted, 0);
+        pdb.set_reputation(&good, 100);
+        pdb.set_reputation(&bad, 10);
+
+        pdb.disconnect(&trusted);
+        pdb.disconnect(&good);
+        pdb.disconnect(&bad);
+        // the trusted peer has the lowest reputation but is kept
+        assert!(pdb.peer_info(&trusted).is_some());
+        assert!(pdb.peer_info(&bad).is_none());
+
+        pdb.disconnect(&newest);
+        assert!(pdb.peer_info(&good).is_some());
+        assert!(pdb.peer_info(&newest).is_none());
+        assert_eq!(pdb.disconnected_len(), 2);
+        assert_eq!(pdb.len(), 2);
     }
 
     #[test]
@@ -515,6 +923,48 @@ mod tests {
         assert_eq!(the_best, best_peers.into_iter().map(|p| p.0).next());
     }
 
+    #[test]
+    fn test_request_cancelled() {
+        let mut pdb = get_db();
+        let random_peer = PeerId::random();
+        pdb.connect_ingoing(&random_peer);
+
+        pdb.request_cancelled(&random_peer, Protocol::Status, &RPCError::HandlerRejected);
+        pdb.request_cancelled(&random_peer, Protocol::Status, &RPCError::StreamTimeout);
+        let info = pdb.peer_info(&random_peer).unwrap();
+        assert_eq!(info.cancelled_requests, 2);
+        let cancelled = info.last_cancelled_request.as_ref().unwrap();
+        assert_eq!(cancelled.protocol, Protocol::Status.to_string());
+        assert_eq!(cancelled.cause, RPCError::StreamTimeout.to_string());
+    }
+
+    #[test]
+    fn test_peers_providing() {
+        let mut pdb = get_db();
+        let relay = PeerId::random();
+        let banned = PeerId::random();
+        let unknown = PeerId::random();
+        let services = |value: u8| {
+            let mut services = HashMap::new();
+            services.insert("relay".to_string(), vec![value]);
+            services
+        };
+        pdb.connect_ingoing(&relay);
+        pdb.connect_ingoing(&banned);
+        pdb.set_services(&relay, services(1));
+        pdb.set_services(&banned, services(2));
+        pdb.set_services(&unknown, services(3));
+        pdb.ban(&banned);
+
+        let providers: Vec<_> = pdb.peers_providing("relay").collect();
+        assert_eq!(providers, vec![(&relay, &[1u8][..])]);
+        assert_eq!(pdb.peers_providing("archive").count(), 0);
+
+        // a newer ENR without the record withdraws it
+        pdb.set_services(&relay, HashMap::new());
+        assert_eq!(pdb.peers_providing("relay").count(), 0);
+    }
+
     #[test]
     fn test_disconnected_consistency() {
         let mut pdb = get_db();
@@ -0,0 +1,149 @@
+//! Filtered, paginated lookups of the `PeerDB` for introspection.
+//!
+//! Matching peers are returned as owned copies in a stable order (by `PeerId`), so the `PeerDB`
+//! lock is only held for the duration of the query and a large peer set can be walked a page at
+//! a time.
+use super::peer_info::{PeerConnectionStatus, PeerInfo};
+use super::peerdb::Rep;
+use crate::types::SubnetId;
+use crate::PeerId;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// The connection state a queried peer must be in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionState {
+    Connected,
+    Dialing,
+    Disconnected,
+    Banned,
+}
+
+impl FromStr for ConnectionState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "connected" => Ok(ConnectionState::Connected),
+            "dialing" => Ok(ConnectionState::Dialing),
+            "disconnected" => Ok(ConnectionState::Disconnected),
+            "banned" => Ok(ConnectionState::Banned),
+            _ => Err(format!("Unknown connection state: {}", s)),
+        }
+    }
+}
+
+/// The direction of a connection a queried peer must have.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionDirection {
+    /// The peer has at least one connection it dialed.
+    Inbound,
+    /// The peer has at least one connection we dialed.
+    Outbound,
+}
+
+impl FromStr for ConnectionDirection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "inbound" => Ok(ConnectionDirection::Inbound),
+            "outbound" => Ok(ConnectionDirection::Outbound),
+            _ => Err(format!("Unknown connection direction: {}", s)),
+        }
+    }
+}
+
+/// The conditions a peer must meet to be returned by `PeerDB::query`. Unset conditions match
+/// every peer.
+#[derive(Clone, Debug, Default)]
+pub struct PeerQuery {
+    pub state: Option<ConnectionState>,
+    pub direction: Option<ConnectionDirection>,
+    /// The lowest reputation to match, inclusive.
+    pub min_reputation: Option<Rep>,
+    /// The highest reputation to match, inclusive.
+    pub max_reputation: Option<Rep>,
+    /// The client name, as reported by Identify.
+    pub client: Option<String>,
+    /// A subnet the peer must be on.
+    pub subnet: Option<SubnetId>,
+    /// A topic the peer must have told us it is subscribed to.
+    pub topic: Option<String>,
+    /// Only match peers that are connected or were seen at most this long ago.
+    pub seen_within: Option<Duration>,
+    /// The number of matching peers to skip.
+    pub offset: usize,
+    /// The maximum number of peers to return.
+    pub limit: Option<usize>,
+}
+
+/// A page of peers matching a `PeerQuery`.
+#[derive(Clone, Debug)]
+pub struct PeerQueryResult {
+    pub peers: Vec<(PeerId, PeerInfo)>,
+    /// The number of matching peers, over all pages.
+    pub total: usize,
+}
+
+impl PeerQuery {
+    /// Returns true if the peer meets every condition of the query.
+    pub fn matches(&self, info: &PeerInfo, now: Instant) -> bool {
+        let status = &info.connection_status;
+        if let Some(state) = self.state {
+            let in_state = match state {
+                ConnectionState::Connected => status.is_connected(),
+                ConnectionState::Dialing => status.is_dialing(),
+                ConnectionState::Disconnected => status.is_disconnected(),
+                ConnectionState::Banned => status.is_banned(),
+            };
+            if !in_state {
+                return false;
+            }
+        }
+        if let Some(direction) = self.direction {
+            let (n_in, n_out) = status.connections();
+            let has_direction = match direction {
+                ConnectionDirection::Inbound => n_in > 0,
+                ConnectionDirection::Outbound => n_out > 0,
+            };
+            if !has_direction {
+                return false;
+            }
+        }
+        let min_reputation = self.min_reputation.unwrap_or_else(Rep::min_value);
+        let max_reputation = self.max_reputation.unwrap_or_else(Rep::max_value);
+        if info.reputation < min_reputation || info.reputation > max_reputation {
+            return false;
+        }
+        if let Some(client) = &self.client {
+            if info.client.kind.name() != client {
+                return false;
+            }
+        }
+        if let Some(subnet) = self.subnet {
+            if !info.on_subnet(subnet) {
+                return false;
+            }
+        }
+        if let Some(topic) = &self.topic {
+            if !info.serves_topic(topic) {
+                return false;
+            }
+        }
+        if let Some(seen_within) = self.seen_within {
+            let last_seen = match status {
+                PeerConnectionStatus::Connected { .. } => Some(now),
+                PeerConnectionStatus::Disconnected { since }
+                | PeerConnectionStatus::Banned { since }
+                | PeerConnectionStatus::Dialing { since } => Some(*since),
+                PeerConnectionStatus::Unknown => None,
+            };
+            match last_seen {
+                Some(last_seen) if now.saturating_duration_since(last_seen) <= seen_within => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+}
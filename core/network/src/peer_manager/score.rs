@@ -0,0 +1,112 @@
+//! The components that make up a peer's reputation.
+//!
+//! Reputation changes are accumulated per source, so that the reason a peer's reputation dropped
+//! (and possibly got it banned) can be inspected later. Each component decays exponentially
+//! towards zero, which pulls the reputation of a peer back towards `DEFAULT_REPUTATION` over time.
+use super::peer_info::PeerConnectionStatus;
+use super::peerdb::{Rep, DEFAULT_REPUTATION};
+use crate::clock;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// The origin of a reputation change.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScoreSource {
+    /// Errors on the transport, such as failed or timed out RPC requests.
+    Transport,
+    /// Misbehaviour on gossipsub.
+    Gossip,
+    /// Reports made by the application.
+    Application,
+    /// Violations of local policy, such as a rejected agent string.
+    Policy,
+}
+
+/// The accumulated reputation changes of a peer, split by source.
+#[derive(Clone, Debug, Serialize)]
+pub struct PeerScore {
+    /// The sum of changes caused by transport and RPC errors.
+    pub transport_errors: f64,
+    /// The sum of changes caused by gossipsub misbehaviour.
+    pub gossip_penalties: f64,
+    /// The sum of changes reported by the application.
+    pub application_reports: f64,
+    /// The sum of changes caused by local policy violations.
+    pub policy_penalties: f64,
+    /// The last time the components were decayed.
+    #[serde(skip)]
+    last_decay: Instant,
+}
+
+impl Default for PeerScore {
+    fn default() -> Self {
+        PeerScore {
+            transport_errors: 0.0,
+            gossip_penalties: 0.0,
+            application_reports: 0.0,
+            policy_penalties: 0.0,
+            last_decay: clock::now(),
+        }
+    }
+}
+
+impl PeerScore {
+    /// Adds `diff` to the component of `source`.
+    pub fn add(&mut self, source: ScoreSource, diff: f64) {
+        match source {
+            ScoreSource::Transport => self.transport_errors += diff,
+            ScoreSource::Gossip => self.gossip_penalties += diff,
+            ScoreSource::Application => self.application_reports += diff,
+            ScoreSource::Policy => self.policy_penalties += diff,
+        }
+    }
+
+    /// Resets the components such that the peer has reputation `rep`.
+    pub fn set_reputation(&mut self, rep: Rep) {
+        self.transport_errors = 0.0;
+        self.gossip_penalties = 0.0;
+        self.policy_penalties = 0.0;
+        self.application_reports = f64::from(rep) - f64::from(DEFAULT_REPUTATION);
+    }
+
+    /// The reputation resulting from the components, bounded by `Rep`.
+    pub fn reputation(&self) -> Rep {
+        let rep = f64::from(DEFAULT_REPUTATION)
+            + self.transport_errors
+            + self.gossip_penalties
+            + self.application_reports
+            + self.policy_penalties;
+        rep.max(f64::from(Rep::min_value()))
+            .min(f64::from(Rep::max_value()))
+            .round() as Rep
+    }
+
+    /// Decays every component by the time elapsed since the last decay, halving it every
+    /// `half_life`.
+    pub fn decay(&mut self, half_life: Duration, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_decay);
+        self.last_decay = now;
+        if half_life.as_nanos() == 0 {
+            return;
+        }
+        let factor = 0.5f64.powf(elapsed.as_secs_f64() / half_life.as_secs_f64());
+        self.transport_errors *= factor;
+        self.gossip_penalties *= factor;
+        self.application_reports *= factor;
+        self.policy_penalties *= factor;
+    }
+}
+
+/// The reputation of a peer and its components at a point in time.
+#[derive(Clone, Debug, Serialize)]
+pub struct PeerScoreSnapshot {
+    /// The peer the snapshot is for.
+    pub peer_id: String,
+    /// The reputation of the peer.
+    pub reputation: Rep,
+    /// The connection status of the peer, which shows whether it is banned.
+    pub connection_status: PeerConnectionStatus,
+    /// The components of the reputation.
+    #[serde(flatten)]
+    pub score: PeerScore,
+}
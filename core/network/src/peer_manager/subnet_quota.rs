@@ -0,0 +1,114 @@
+//! Minimum numbers of connected peers kept on subnets.
+//!
+//! Every heartbeat the connected peers on each subnet with a quota are counted, from the
+//! `attnets` field of their ENR, and the subnets below their quota are searched for more peers.
+//! A subnet still below its quota after `UNSATISFIABLE_HEARTBEATS` heartbeats in a row is
+//! reported as unsatisfiable, as the network probably has too few peers on it, until it reaches
+//! its quota again.
+use crate::types::SubnetId;
+use serde_derive::Serialize;
+use std::collections::HashMap;
+
+/// The number of heartbeats in a row a subnet can be below its quota before it is reported as
+/// unsatisfiable.
+const UNSATISFIABLE_HEARTBEATS: usize = 10;
+
+/// The connected peers of a subnet with a peer quota.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct SubnetPeerCount {
+    /// The number of connected peers on the subnet.
+    pub connected: usize,
+    /// The minimum number of connected peers wanted on the subnet.
+    pub quota: usize,
+    /// True if searches have not brought the subnet to its quota for a while.
+    pub unsatisfiable: bool,
+}
+
+impl SubnetPeerCount {
+    /// Returns true if the subnet has fewer connected peers than its quota.
+    pub fn is_short(&self) -> bool {
+        self.connected < self.quota
+    }
+}
+
+/// The subnet quotas and how long each subnet has been short of its quota.
+pub struct SubnetQuotas {
+    quotas: HashMap<SubnetId, usize>,
+    /// The number of heartbeats in a row each subnet has been below its quota.
+    short_heartbeats: HashMap<SubnetId, usize>,
+}
+
+impl SubnetQuotas {
+    pub fn new(quotas: HashMap<SubnetId, usize>) -> Self {
+        SubnetQuotas {
+            quotas,
+            short_heartbeats: HashMap::new(),
+        }
+    }
+
+    /// Returns true if no subnet has a quota.
+    pub fn is_empty(&self) -> bool {
+        self.quotas.is_empty()
+    }
+
+    /// Counts the connected peers of each subnet with `count`, once per heartbeat.
+    pub fn update(
+        &mut self,
+        count: impl Fn(SubnetId) -> usize,
+    ) -> HashMap<SubnetId, SubnetPeerCount> {
+        let short_heartbeats = &mut self.short_heartbeats;
+        self.quotas
+            .iter()
+            .map(|(subnet_id, quota)| {
+                let connected = count(*subnet_id);
+                let short = short_heartbeats.entry(*subnet_id).or_default();
+                if connected < *quota {
+                    *short += 1;
+                } else {
+                    *short = 0;
+                }
+                let count = SubnetPeerCount {
+                    connected,
+                    quota: *quota,
+                    unsatisfiable: *short >= UNSATISFIABLE_HEARTBEATS,
+                };
+                (*subnet_id, count)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unsatisfiable_subnets() {
+        let mut quotas = HashMap::new();
+        quotas.insert(1, 2);
+        quotas.insert(2, 1);
+        let mut quotas = SubnetQuotas::new(quotas);
+
+        for _ in 1..UNSATISFIABLE_HEARTBEATS {
+            let counts = quotas.update(|subnet_id| if subnet_id == 1 { 1 } else { 3 });
+            assert!(counts[&1].is_short());
+            assert!(!counts[&1].unsatisfiable);
+            assert!(!counts[&2].is_short());
+        }
+        let counts = quotas.update(|subnet_id| if subnet_id == 1 { 1 } else { 3 });
+        assert_eq!(
+            counts[&1],
+            SubnetPeerCount {
+                connected: 1,
+                quota: 2,
+                unsatisfiable: true,
+            }
+        );
+        assert!(!counts[&2].unsatisfiable);
+
+        // reaching the quota clears the report
+        let counts = quotas.update(|_| 2);
+        assert!(!counts[&1].is_short());
+        assert!(!counts[&1].unsatisfiable);
+    }
+}
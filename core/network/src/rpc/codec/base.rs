@@ -154,6 +154,7 @@ mod tests {
     use super::super::snappy::*;
     use super::*;
     use crate::rpc::protocol::*;
+    use std::sync::Arc;
 
     #[test]
     fn test_decode_status_message() {
@@ -161,9 +162,15 @@ mod tests {
         let mut buf = BytesMut::new();
         buf.extend_from_slice(&message);
 
-        let snappy_protocol_id = ProtocolId::new(Protocol::Status, Version::V1, Encoding::Snappy);
+        let snappy_protocol_id = ProtocolId::new(
+            DEFAULT_PROTOCOL_PREFIX,
+            Protocol::Status,
+            Version::V1,
+            Encoding::Snappy,
+        );
 
-        let mut snappy_outbound_codec = SnappyOutboundCodec::new(snappy_protocol_id, 1_048_576);
+        let mut snappy_outbound_codec =
+            SnappyOutboundCodec::new(snappy_protocol_id, 1_048_576, Arc::default());
 
         // decode message just as snappy message
         let snappy_decoded_message = snappy_outbound_codec.decode(&mut buf.clone());
@@ -177,4 +184,95 @@ mod tests {
         let _ = dbg!(snappy_decoded_message);
         let _ = dbg!(snappy_decoded_chunk);
     }
+
+    #[test]
+    fn test_custom_round_trip() {
+        let blocks = CustomProtocols::default()
+            .register("blocks_by_root", 8, 1024, Encoding::Snappy)
+            .unwrap();
+        let custom = match blocks {
+            Protocol::Custom(custom) => custom,
+            _ => unreachable!(),
+        };
+        let protocol_id = ProtocolId::new(
+            DEFAULT_PROTOCOL_PREFIX,
+            blocks,
+            Version::V1,
+            Encoding::Snappy,
+        );
+        let mut outbound = SnappyOutboundCodec::new(protocol_id.clone(), 1, Arc::default());
+        let mut inbound = SnappyInboundCodec::new(protocol_id, 1, Arc::default());
+
+        // the request is within the protocol's own limit, though not the default one
+        let request = RPCRequest::Custom(custom, vec![1, 2, 3, 4]);
+        let mut buf = BytesMut::new();
+        outbound.encode(request.clone(), &mut buf).unwrap();
+        assert_eq!(inbound.decode(&mut buf).unwrap(), Some(request));
+        assert!(buf.is_empty());
+
+        let response = vec![7; 1024];
+        inbound
+            .encode(
+                RPCCodedResponse::Success(RPCResponse::Custom(response.clone())),
+                &mut buf,
+            )
+            .unwrap();
+        assert_eq!(
+            outbound.decode(&mut buf).unwrap(),
+            Some(RPCResponse::Custom(response))
+        );
+
+        // over the protocol's limit
+        let request = RPCRequest::Custom(custom, vec![0; 9]);
+        assert!(outbound.encode(request, &mut buf).is_err());
+    }
+
+    /// Prefixes payloads with a marker byte.
+    struct Marked;
+
+    impl VersionCodec for Marked {
+        fn encode(&self, _protocol: Protocol, mut payload: Vec<u8>) -> Result<Vec<u8>, String> {
+            payload.insert(0, 0xff);
+            Ok(payload)
+        }
+
+        fn decode(&self, _protocol: Protocol, mut payload: Vec<u8>) -> Result<Vec<u8>, String> {
+            match payload.first() {
+                Some(&0xff) => Ok(payload.split_off(1)),
+                _ => Err("Missing marker".into()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_version_codec_round_trip() {
+        let protocol_id = ProtocolId::new(
+            DEFAULT_PROTOCOL_PREFIX,
+            Protocol::Status,
+            Version::new(2).unwrap(),
+            Encoding::Snappy,
+        );
+        let codec: Arc<dyn VersionCodec> = Arc::new(Marked);
+        let mut outbound = SnappyOutboundCodec::new(protocol_id.clone(), 1024, Arc::default())
+            .with_version_codec(Some(codec.clone()));
+        let mut inbound = SnappyInboundCodec::new(protocol_id.clone(), 1024, Arc::default())
+            .with_version_codec(Some(codec));
+
+        let request = RPCRequest::Status(vec![1, 2, 3]);
+        let mut buf = BytesMut::new();
+        outbound.encode(request.clone(), &mut buf).unwrap();
+        assert_eq!(inbound.decode(&mut buf.clone()).unwrap(), Some(request));
+
+        // a peer without the codec sees the encoded payload, and its payloads are rejected
+        let mut plain = SnappyInboundCodec::new(protocol_id.clone(), 1024, Arc::default());
+        assert_eq!(
+            plain.decode(&mut buf).unwrap(),
+            Some(RPCRequest::Status(vec![0xff, 1, 2, 3]))
+        );
+        let mut plain = SnappyOutboundCodec::new(protocol_id, 1024, Arc::default());
+        plain
+            .encode(RPCRequest::Status(vec![1, 2, 3]), &mut buf)
+            .unwrap();
+        assert!(inbound.decode(&mut buf).is_err());
+    }
 }
@@ -1,5 +1,6 @@
 pub(crate) mod base;
 pub(crate) mod snappy;
+pub(crate) mod worker;
 
 use self::base::{BaseInboundCodec, BaseOutboundCodec};
 use self::snappy::{SnappyInboundCodec, SnappyOutboundCodec};
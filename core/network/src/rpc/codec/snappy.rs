@@ -1,18 +1,54 @@
 use crate::rpc::methods::*;
 use crate::rpc::{
     codec::base::OutboundCodec,
-    protocol::{Encoding, Protocol, ProtocolId, RPCError, Version},
+    protocol::{Encoding, Protocol, ProtocolId, RPCError, VersionCodec},
 };
 use crate::rpc::{RPCCodedResponse, RPCRequest, RPCResponse};
+use crate::types::BufferPool;
 use libp2p::bytes::BytesMut;
 use snap::read::FrameDecoder;
 use snap::write::FrameEncoder;
 use std::io::Cursor;
 use std::io::ErrorKind;
 use std::io::{Read, Write};
+use std::sync::Arc;
 use tokio_util::codec::{Decoder, Encoder};
 use unsigned_varint::codec::Uvi;
 
+/// Gives back the buffer of a message that could not be decoded.
+fn invalid_data(pool: &BufferPool, decoded_buffer: Vec<u8>) -> RPCError {
+    pool.give(decoded_buffer);
+    RPCError::InvalidData
+}
+
+/// Encodes a payload in the negotiated version, if the version has a codec.
+fn encode_version(
+    codec: &Option<Arc<dyn VersionCodec>>,
+    protocol: Protocol,
+    payload: Vec<u8>,
+) -> Result<Vec<u8>, RPCError> {
+    match codec {
+        Some(codec) => codec
+            .encode(protocol, payload)
+            .map_err(|_| RPCError::InternalError("failed to encode the payload in its version")),
+        None => Ok(payload),
+    }
+}
+
+/// Decodes a payload received in the negotiated version, if the version has a codec.
+fn decode_version(
+    codec: &Option<Arc<dyn VersionCodec>>,
+    protocol: Protocol,
+    payload: Vec<u8>,
+) -> Result<Vec<u8>, RPCError> {
+    match codec {
+        Some(codec) => codec
+            .decode(protocol, payload)
+            .map_err(|_| RPCError::InvalidData),
+        None => Ok(payload),
+    }
+}
+
 /* Inbound Codec */
 
 pub struct SnappyInboundCodec {
@@ -21,10 +57,14 @@ pub struct SnappyInboundCodec {
     len: Option<usize>,
     /// Maximum bytes that can be sent in one req/resp chunked responses.
     max_packet_size: usize,
+    /// The buffers decoded messages are written to.
+    pool: Arc<BufferPool>,
+    /// Converts the payloads of versions after the first.
+    version_codec: Option<Arc<dyn VersionCodec>>,
 }
 
 impl SnappyInboundCodec {
-    pub fn new(protocol: ProtocolId, max_packet_size: usize) -> Self {
+    pub fn new(protocol: ProtocolId, max_packet_size: usize, pool: Arc<BufferPool>) -> Self {
         let uvi_codec = Uvi::default();
         // this encoding only applies to ssz_snappy.
         debug_assert_eq!(protocol.encoding, Encoding::Snappy);
@@ -34,8 +74,16 @@ impl SnappyInboundCodec {
             protocol,
             len: None,
             max_packet_size,
+            pool,
+            version_codec: None,
         }
     }
+
+    /// Converts payloads with the codec of the negotiated version.
+    pub fn with_version_codec(mut self, version_codec: Option<Arc<dyn VersionCodec>>) -> Self {
+        self.version_codec = version_codec;
+        self
+    }
 }
 
 // Encoder for inbound streams: Encodes RPC Responses sent to peers.
@@ -48,13 +96,24 @@ impl Encoder<RPCCodedResponse> for SnappyInboundCodec {
                 RPCResponse::Status(res) => res,
                 RPCResponse::Pong(res) => res,
                 RPCResponse::MetaData(res) => res,
+                RPCResponse::Topics(res) => res,
+                RPCResponse::Rendezvous(res) => res,
+                RPCResponse::Custom(res) => res,
             },
             RPCCodedResponse::InvalidRequest(err) => err.to_vec(),
             RPCCodedResponse::ServerError(err) => err.to_vec(),
             RPCCodedResponse::Unknown(err) => err.to_vec(),
+            RPCCodedResponse::StreamTermination => {
+                unreachable!("Code error - attempting to encode a stream termination")
+            }
         };
+        let bytes = encode_version(&self.version_codec, self.protocol.message_name, bytes)?;
         //  encoded bytes should be within `max_packet_size`
-        if bytes.len() > self.max_packet_size {
+        let max_packet_size = self
+            .protocol
+            .message_name
+            .max_response_size(self.max_packet_size);
+        if bytes.len() > max_packet_size {
             return Err(RPCError::InternalError(
                 "attempting to encode data > max_packet_size".into(),
             ));
@@ -94,11 +153,15 @@ impl Decoder for SnappyInboundCodec {
         let length = self.len.expect("length should be Some");
 
         // Should not attempt to decode rpc chunks with length > max_packet_size
-        if length > self.max_packet_size {
+        let max_packet_size = self
+            .protocol
+            .message_name
+            .max_request_size(self.max_packet_size);
+        if length > max_packet_size {
             return Err(RPCError::InvalidData);
         }
         let mut reader = FrameDecoder::new(Cursor::new(&src));
-        let mut decoded_buffer = vec![0; length];
+        let mut decoded_buffer = self.pool.take(length);
 
         match reader.read_exact(&mut decoded_buffer) {
             Ok(()) => {
@@ -106,52 +169,78 @@ impl Decoder for SnappyInboundCodec {
                 let n = reader.get_ref().position();
                 self.len = None;
                 let _read_bytes = src.split_to(n as usize);
+                // MetaData requests carry no payload to decode
+                let decoded_buffer = if self.protocol.message_name == Protocol::MetaData {
+                    decoded_buffer
+                } else {
+                    decode_version(
+                        &self.version_codec,
+                        self.protocol.message_name,
+                        decoded_buffer,
+                    )?
+                };
                 match self.protocol.message_name {
-                    Protocol::Status => match self.protocol.version {
-                        Version::V1 => {
-                            if decoded_buffer.len() > 0 {
-                                Ok(Some(RPCRequest::Status(decoded_buffer)))
-                            } else {
-                                Err(RPCError::InvalidData)
-                            }
+                    Protocol::Status => {
+                        if decoded_buffer.len() > 0 {
+                            Ok(Some(RPCRequest::Status(decoded_buffer)))
+                        } else {
+                            Err(invalid_data(&self.pool, decoded_buffer))
+                        }
+                    }
+                    Protocol::Goodbye => {
+                        if decoded_buffer.len() > 0 {
+                            Ok(Some(RPCRequest::Goodbye(decoded_buffer)))
+                        } else {
+                            Err(invalid_data(&self.pool, decoded_buffer))
+                        }
+                    }
+                    Protocol::Ping => {
+                        if decoded_buffer.len() > 0 {
+                            Ok(Some(RPCRequest::Ping(decoded_buffer)))
+                        } else {
+                            Err(invalid_data(&self.pool, decoded_buffer))
                         }
-                    },
-                    Protocol::Goodbye => match self.protocol.version {
-                        Version::V1 => {
-                            if decoded_buffer.len() > 0 {
-                                Ok(Some(RPCRequest::Goodbye(decoded_buffer)))
-                            } else {
-                                Err(RPCError::InvalidData)
-                            }
+                    }
+                    Protocol::MetaData => {
+                        if decoded_buffer.len() > 0 {
+                            Err(invalid_data(&self.pool, decoded_buffer))
+                        } else {
+                            self.pool.give(decoded_buffer);
+                            Ok(Some(RPCRequest::MetaData))
                         }
-                    },
-                    Protocol::Ping => match self.protocol.version {
-                        Version::V1 => {
-                            if decoded_buffer.len() > 0 {
-                                Ok(Some(RPCRequest::Ping(decoded_buffer)))
-                            } else {
-                                Err(RPCError::InvalidData)
-                            }
+                    }
+                    Protocol::Topics => {
+                        if decoded_buffer.len() > 0 {
+                            Ok(Some(RPCRequest::Topics(decoded_buffer)))
+                        } else {
+                            Err(invalid_data(&self.pool, decoded_buffer))
                         }
-                    },
-                    Protocol::MetaData => match self.protocol.version {
-                        Version::V1 => {
-                            if decoded_buffer.len() > 0 {
-                                Err(RPCError::InvalidData)
-                            } else {
-                                Ok(Some(RPCRequest::MetaData))
-                            }
+                    }
+                    Protocol::Rendezvous => {
+                        if decoded_buffer.len() > 0 {
+                            Ok(Some(RPCRequest::Rendezvous(decoded_buffer)))
+                        } else {
+                            Err(invalid_data(&self.pool, decoded_buffer))
                         }
-                    },
+                    }
+                    // the application decides what a custom request may hold
+                    Protocol::Custom(custom) => {
+                        Ok(Some(RPCRequest::Custom(custom, decoded_buffer)))
+                    }
                 }
             }
             Err(e) => match e.kind() {
                 // Haven't received enough bytes to decode yet
                 // TODO: check if this is the only Error variant where we return `Ok(None)`
                 ErrorKind::UnexpectedEof => {
+                    // the buffer is taken again once more bytes arrive
+                    self.pool.give(decoded_buffer);
                     return Ok(None);
                 }
-                _ => return Err(e).map_err(RPCError::from),
+                _ => {
+                    self.pool.give(decoded_buffer);
+                    return Err(e).map_err(RPCError::from);
+                }
             },
         }
     }
@@ -164,10 +253,14 @@ pub struct SnappyOutboundCodec {
     protocol: ProtocolId,
     /// Maximum bytes that can be sent in one req/resp chunked responses.
     max_packet_size: usize,
+    /// The buffers decoded messages are written to.
+    pool: Arc<BufferPool>,
+    /// Converts the payloads of versions after the first.
+    version_codec: Option<Arc<dyn VersionCodec>>,
 }
 
 impl SnappyOutboundCodec {
-    pub fn new(protocol: ProtocolId, max_packet_size: usize) -> Self {
+    pub fn new(protocol: ProtocolId, max_packet_size: usize, pool: Arc<BufferPool>) -> Self {
         let uvi_codec = Uvi::default();
         // this encoding only applies to ssz_snappy.
         debug_assert_eq!(protocol.encoding, Encoding::Snappy);
@@ -177,8 +270,16 @@ impl SnappyOutboundCodec {
             protocol,
             max_packet_size,
             len: None,
+            pool,
+            version_codec: None,
         }
     }
+
+    /// Converts payloads with the codec of the negotiated version.
+    pub fn with_version_codec(mut self, version_codec: Option<Arc<dyn VersionCodec>>) -> Self {
+        self.version_codec = version_codec;
+        self
+    }
 }
 
 // Encoder for outbound streams: Encodes RPC Requests to peers
@@ -191,9 +292,17 @@ impl Encoder<RPCRequest> for SnappyOutboundCodec {
             RPCRequest::Goodbye(req) => req,
             RPCRequest::Ping(req) => req,
             RPCRequest::MetaData => return Ok(()), // no metadata to encode
+            RPCRequest::Topics(req) => req,
+            RPCRequest::Rendezvous(req) => req,
+            RPCRequest::Custom(_, req) => req,
         };
+        let bytes = encode_version(&self.version_codec, self.protocol.message_name, bytes)?;
         //  encoded bytes should be within `max_packet_size`
-        if bytes.len() > self.max_packet_size {
+        let max_packet_size = self
+            .protocol
+            .message_name
+            .max_request_size(self.max_packet_size);
+        if bytes.len() > max_packet_size {
             return Err(RPCError::InternalError(
                 "attempting to encode data > max_packet_size",
             ));
@@ -238,55 +347,78 @@ impl Decoder for SnappyOutboundCodec {
         let length = self.len.expect("length should be Some");
 
         // Should not attempt to decode rpc chunks with length > max_packet_size
-        if length > self.max_packet_size {
+        let max_packet_size = self
+            .protocol
+            .message_name
+            .max_response_size(self.max_packet_size);
+        if length > max_packet_size {
             return Err(RPCError::InvalidData);
         }
         let mut reader = FrameDecoder::new(Cursor::new(&src));
-        let mut decoded_buffer = vec![0; length];
+        let mut decoded_buffer = self.pool.take(length);
         match reader.read_exact(&mut decoded_buffer) {
             Ok(()) => {
                 // `n` is how many bytes the reader read in the compressed stream
                 let n = reader.get_ref().position();
                 self.len = None;
                 let _read_byts = src.split_to(n as usize);
+                let decoded_buffer = decode_version(
+                    &self.version_codec,
+                    self.protocol.message_name,
+                    decoded_buffer,
+                )?;
                 match self.protocol.message_name {
-                    Protocol::Status => match self.protocol.version {
-                        Version::V1 => {
-                            if decoded_buffer.len() > 0 {
-                                Ok(Some(RPCResponse::Status(decoded_buffer)))
-                            } else {
-                                Err(RPCError::InvalidData)
-                            }
+                    Protocol::Status => {
+                        if decoded_buffer.len() > 0 {
+                            Ok(Some(RPCResponse::Status(decoded_buffer)))
+                        } else {
+                            Err(invalid_data(&self.pool, decoded_buffer))
+                        }
+                    }
+                    Protocol::Goodbye => Err(invalid_data(&self.pool, decoded_buffer)),
+                    Protocol::Ping => {
+                        if decoded_buffer.len() > 0 {
+                            Ok(Some(RPCResponse::Pong(decoded_buffer)))
+                        } else {
+                            Err(invalid_data(&self.pool, decoded_buffer))
+                        }
+                    }
+                    Protocol::MetaData => {
+                        if decoded_buffer.len() > 0 {
+                            Ok(Some(RPCResponse::MetaData(decoded_buffer)))
+                        } else {
+                            Err(invalid_data(&self.pool, decoded_buffer))
                         }
-                    },
-                    Protocol::Goodbye => Err(RPCError::InvalidData),
-                    Protocol::Ping => match self.protocol.version {
-                        Version::V1 => {
-                            if decoded_buffer.len() > 0 {
-                                Ok(Some(RPCResponse::Pong(decoded_buffer)))
-                            } else {
-                                Err(RPCError::InvalidData)
-                            }
+                    }
+                    Protocol::Topics => {
+                        if decoded_buffer.len() > 0 {
+                            Ok(Some(RPCResponse::Topics(decoded_buffer)))
+                        } else {
+                            Err(invalid_data(&self.pool, decoded_buffer))
                         }
-                    },
-                    Protocol::MetaData => match self.protocol.version {
-                        Version::V1 => {
-                            if decoded_buffer.len() > 0 {
-                                Ok(Some(RPCResponse::MetaData(decoded_buffer)))
-                            } else {
-                                Err(RPCError::InvalidData)
-                            }
+                    }
+                    Protocol::Rendezvous => {
+                        if decoded_buffer.len() > 0 {
+                            Ok(Some(RPCResponse::Rendezvous(decoded_buffer)))
+                        } else {
+                            Err(invalid_data(&self.pool, decoded_buffer))
                         }
-                    },
+                    }
+                    Protocol::Custom(_) => Ok(Some(RPCResponse::Custom(decoded_buffer))),
                 }
             }
             Err(e) => match e.kind() {
                 // Haven't received enough bytes to decode yet
                 // TODO: check if this is the only Error variant where we return `Ok(None)`
                 ErrorKind::UnexpectedEof => {
+                    // the buffer is taken again once more bytes arrive
+                    self.pool.give(decoded_buffer);
                     return Ok(None);
                 }
-                _ => return Err(e).map_err(RPCError::from),
+                _ => {
+                    self.pool.give(decoded_buffer);
+                    return Err(e).map_err(RPCError::from);
+                }
             },
         }
     }
@@ -313,22 +445,29 @@ impl OutboundCodec<RPCRequest> for SnappyOutboundCodec {
             return Err(RPCError::InvalidData);
         }
         let mut reader = FrameDecoder::new(Cursor::new(&src));
-        let mut decoded_buffer = vec![0; length];
+        let mut decoded_buffer = self.pool.take(length);
         match reader.read_exact(&mut decoded_buffer) {
             Ok(()) => {
                 // `n` is how many bytes the reader read in the compressed stream
                 let n = reader.get_ref().position();
                 self.len = None;
                 let _read_bytes = src.split_to(n as usize);
-                Ok(Some(String::from_utf8_lossy(&decoded_buffer).into()))
+                let error = String::from_utf8_lossy(&decoded_buffer).into_owned();
+                self.pool.give(decoded_buffer);
+                Ok(Some(error))
             }
             Err(e) => match e.kind() {
                 // Haven't received enough bytes to decode yet
                 // TODO: check if this is the only Error variant where we return `Ok(None)`
                 ErrorKind::UnexpectedEof => {
+                    // the buffer is taken again once more bytes arrive
+                    self.pool.give(decoded_buffer);
                     return Ok(None);
                 }
-                _ => return Err(e).map_err(RPCError::from),
+                _ => {
+                    self.pool.give(decoded_buffer);
+                    return Err(e).map_err(RPCError::from);
+                }
             },
         }
     }
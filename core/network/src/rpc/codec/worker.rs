@@ -0,0 +1,491 @@
+//! A small pool of threads the RPC codec runs on, so that compressing or decompressing a large
+//! payload does not hold up the task polling the connection, and with it every other substream.
+//!
+//! `WorkerFramed` takes the place of `Framed` for RPC substreams. It reads and writes the socket
+//! itself, and hands the codec to a worker along with the bytes read or the item to send. The
+//! codec comes back with the result, so a substream has at most one job in flight and its
+//! messages are decoded and encoded in order, while the substreams of every connection share the
+//! workers. Payloads smaller than `INLINE_SIZE` are coded in place, as handing them over costs
+//! more than coding them.
+use crate::rpc::{RPCCodedResponse, RPCRequest, RPCResponse};
+use futures::channel::oneshot;
+use futures::prelude::*;
+use futures::ready;
+use libp2p::bytes::{Buf, BytesMut};
+use parking_lot::Mutex;
+use std::io;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{mpsc, Arc};
+use std::task::{Context, Poll};
+use std::thread;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// The default number of threads the RPC codec runs on.
+pub const DEFAULT_RPC_CODEC_WORKERS: usize = 2;
+
+/// Payloads of fewer bytes are coded on the task polling the connection.
+const INLINE_SIZE: usize = 16 * 1024;
+
+/// The bytes reserved in the read buffer before each read.
+const READ_CAPACITY: usize = 8 * 1024;
+
+/// The encoded bytes buffered before they are written out, rather than waiting for a flush.
+const BACKPRESSURE_BOUNDARY: usize = 8 * 1024;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The threads RPC payloads are compressed and decompressed on.
+#[derive(Debug, Default)]
+pub struct CodecWorkers {
+    /// Hands jobs to the threads, `None` if every payload is coded in place.
+    jobs: Option<Mutex<mpsc::Sender<Job>>>,
+}
+
+impl CodecWorkers {
+    /// Starts `threads` workers. With none, every payload is coded on the task polling its
+    /// connection. The workers stop once the pool is dropped.
+    pub fn new(threads: usize) -> io::Result<Self> {
+        if threads == 0 {
+            return Ok(CodecWorkers::default());
+        }
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for index in 0..threads {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("rpc-codec-{}", index))
+                .spawn(move || loop {
+                    // the lock is released before the job runs
+                    let job = match receiver.lock().recv() {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    // a codec that panics fails its substream rather than the worker
+                    let _ = catch_unwind(AssertUnwindSafe(job));
+                })?;
+        }
+        Ok(CodecWorkers {
+            jobs: Some(Mutex::new(sender)),
+        })
+    }
+
+    /// Runs `job`, which codes a payload of `size` bytes, on a worker if the payload is large
+    /// enough and in place otherwise.
+    fn run<T: Send + 'static>(
+        &self,
+        size: usize,
+        job: impl FnOnce() -> T + Send + 'static,
+    ) -> Pending<T> {
+        match &self.jobs {
+            Some(jobs) if size >= INLINE_SIZE => {
+                let (sender, receiver) = oneshot::channel();
+                let job: Job = Box::new(move || {
+                    let _ = sender.send(job());
+                });
+                // the workers only stop once the pool is dropped, but should they be gone the
+                // job is run here
+                if let Err(mpsc::SendError(job)) = jobs.lock().send(job) {
+                    job();
+                }
+                Pending::Worker(receiver)
+            }
+            _ => Pending::Done(Some(job())),
+        }
+    }
+}
+
+/// The result of a job, ready or to come from a worker.
+enum Pending<T> {
+    Done(Option<T>),
+    Worker(oneshot::Receiver<T>),
+}
+
+impl<T> Pending<T> {
+    fn poll(&mut self, cx: &mut Context) -> Poll<io::Result<T>> {
+        match self {
+            Pending::Done(result) => Poll::Ready(Ok(result.take().expect("polled once"))),
+            Pending::Worker(receiver) => receiver
+                .poll_unpin(cx)
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "the RPC codec panicked")),
+        }
+    }
+}
+
+/// A job's result, which hands the codec back.
+enum Done<C: Decoder> {
+    Decoded(C, BytesMut, Result<Option<C::Item>, C::Error>),
+    Encoded(C, BytesMut, Result<(), C::Error>),
+}
+
+/// The size of the payload of an item, which decides where it is encoded.
+pub trait PayloadSize {
+    fn payload_size(&self) -> usize;
+}
+
+impl PayloadSize for RPCRequest {
+    fn payload_size(&self) -> usize {
+        match self {
+            RPCRequest::Status(data)
+            | RPCRequest::Goodbye(data)
+            | RPCRequest::Ping(data)
+            | RPCRequest::Topics(data)
+            | RPCRequest::Rendezvous(data)
+            | RPCRequest::Custom(_, data) => data.len(),
+            RPCRequest::MetaData => 0,
+        }
+    }
+}
+
+impl PayloadSize for RPCCodedResponse {
+    fn payload_size(&self) -> usize {
+        match self {
+            RPCCodedResponse::Success(response) => match response {
+                RPCResponse::Status(data)
+                | RPCResponse::Pong(data)
+                | RPCResponse::MetaData(data)
+                | RPCResponse::Topics(data)
+                | RPCResponse::Rendezvous(data)
+                | RPCResponse::Custom(data) => data.len(),
+            },
+            RPCCodedResponse::InvalidRequest(error)
+            | RPCCodedResponse::ServerError(error)
+            | RPCCodedResponse::Unknown(error) => error.len(),
+            RPCCodedResponse::StreamTermination => 0,
+        }
+    }
+}
+
+/// A substream framed by a codec that runs on the `CodecWorkers`.
+pub struct WorkerFramed<T, C: Decoder> {
+    io: T,
+    /// The codec, unless a job has it.
+    codec: Option<C>,
+    workers: Arc<CodecWorkers>,
+    job: Option<Pending<Done<C>>>,
+    /// The bytes read and not decoded yet, `None` while a job decodes them.
+    read_buffer: Option<BytesMut>,
+    /// Whether the bytes read may hold a message, because more arrived or one was just decoded.
+    decodable: bool,
+    /// Whether the socket reached the end of its input.
+    eof: bool,
+    /// The outcome of the last decoding job, until the stream is polled.
+    decoded: Option<Result<Option<C::Item>, C::Error>>,
+    write_buffer: BytesMut,
+    /// The error of the last encoding job, until the sink is polled.
+    encode_error: Option<C::Error>,
+    /// Whether a job failed and took the codec with it. The stream then ends and the sink fails.
+    poisoned: bool,
+}
+
+// the codec is never pinned
+impl<T: Unpin, C: Decoder> Unpin for WorkerFramed<T, C> {}
+
+impl<T, C> WorkerFramed<T, C>
+where
+    C: Decoder + Send + 'static,
+    C::Item: Send + 'static,
+    C::Error: Send + 'static,
+{
+    pub fn new(io: T, codec: C, workers: Arc<CodecWorkers>) -> Self {
+        WorkerFramed {
+            io,
+            codec: Some(codec),
+            workers,
+            job: None,
+            read_buffer: Some(BytesMut::new()),
+            decodable: false,
+            eof: false,
+            decoded: None,
+            write_buffer: BytesMut::new(),
+            encode_error: None,
+            poisoned: false,
+        }
+    }
+
+    /// Waits for the job in flight, if any, and takes back the codec and buffer it had. Fails if
+    /// a job failed, now or before, as the codec is lost.
+    fn poll_job(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        if self.poisoned {
+            return Poll::Ready(Err(poisoned()));
+        }
+        if let Some(job) = self.job.as_mut() {
+            let done = ready!(job.poll(cx));
+            self.job = None;
+            match done {
+                Ok(done) => self.complete(done),
+                Err(e) => {
+                    self.poisoned = true;
+                    return Poll::Ready(Err(e));
+                }
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn complete(&mut self, done: Done<C>) {
+        match done {
+            Done::Decoded(codec, buffer, result) => {
+                self.codec = Some(codec);
+                self.read_buffer = Some(buffer);
+                self.decoded = Some(result);
+            }
+            Done::Encoded(codec, encoded, result) => {
+                self.codec = Some(codec);
+                self.write_buffer.extend_from_slice(&encoded);
+                self.encode_error = result.err();
+            }
+        }
+    }
+
+    fn start_decode(&mut self) {
+        let mut codec = self.codec.take().expect("no job is in flight");
+        let mut buffer = self.read_buffer.take().expect("no job is in flight");
+        let eof = self.eof;
+        self.job = Some(self.workers.run(buffer.len(), move || {
+            let result = if eof {
+                codec.decode_eof(&mut buffer)
+            } else {
+                codec.decode(&mut buffer)
+            };
+            Done::Decoded(codec, buffer, result)
+        }));
+    }
+
+    /// Writes out the encoded bytes.
+    fn poll_write_buffer(&mut self, cx: &mut Context) -> Poll<io::Result<()>>
+    where
+        T: AsyncWrite + Unpin,
+    {
+        while !self.write_buffer.is_empty() {
+            let written = ready!(Pin::new(&mut self.io).poll_write(cx, &self.write_buffer))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write the RPC frame to the substream",
+                )));
+            }
+            self.write_buffer.advance(written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T, C> Stream for WorkerFramed<T, C>
+where
+    T: AsyncRead + Unpin,
+    C: Decoder + Send + 'static,
+    C::Item: Send + 'static,
+    C::Error: Send + 'static,
+{
+    type Item = Result<C::Item, C::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        // the failure was returned when the job failed
+        if this.poisoned {
+            return Poll::Ready(None);
+        }
+        loop {
+            if let Err(e) = ready!(this.poll_job(cx)) {
+                return Poll::Ready(Some(Err(e.into())));
+            }
+            match this.decoded.take() {
+                Some(Ok(Some(item))) => {
+                    // the bytes left may hold the next message
+                    this.decodable = true;
+                    return Poll::Ready(Some(Ok(item)));
+                }
+                Some(Ok(None)) => this.decodable = false,
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => {}
+            }
+            let buffered = this.read_buffer.as_ref().map_or(0, BytesMut::len);
+            if this.decodable && !(this.eof && buffered == 0) {
+                this.start_decode();
+                continue;
+            }
+            if this.eof {
+                return Poll::Ready(None);
+            }
+            let buffer = this.read_buffer.as_mut().expect("no job is in flight");
+            buffer.reserve(READ_CAPACITY);
+            match ready!(Pin::new(&mut this.io).poll_read_buf(cx, buffer)) {
+                Ok(0) => this.eof = true,
+                Ok(_) => {}
+                Err(e) => return Poll::Ready(Some(Err(e.into()))),
+            }
+            this.decodable = true;
+        }
+    }
+}
+
+impl<T, C, I> Sink<I> for WorkerFramed<T, C>
+where
+    T: AsyncWrite + Unpin,
+    C: Decoder + Encoder<I, Error = <C as Decoder>::Error> + Send + 'static,
+    C::Item: Send + 'static,
+    <C as Decoder>::Error: Send + 'static,
+    I: PayloadSize + Send + 'static,
+{
+    type Error = <C as Decoder>::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_job(cx))?;
+        if let Some(e) = this.encode_error.take() {
+            return Poll::Ready(Err(e));
+        }
+        if this.write_buffer.len() >= BACKPRESSURE_BOUNDARY {
+            ready!(this.poll_write_buffer(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: I) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        if this.poisoned {
+            return Err(poisoned().into());
+        }
+        let mut codec = this
+            .codec
+            .take()
+            .expect("poll_ready is called before start_send");
+        let mut pending = this.workers.run(item.payload_size(), move || {
+            let mut encoded = BytesMut::new();
+            let result = codec.encode(item, &mut encoded);
+            Done::Encoded(codec, encoded, result)
+        });
+        match &mut pending {
+            // an item encoded in place fails here, as with `Framed`
+            Pending::Done(done) => {
+                this.complete(done.take().expect("the job is done"));
+                this.encode_error.take().map_or(Ok(()), Err)
+            }
+            Pending::Worker(_) => {
+                this.job = Some(pending);
+                Ok(())
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_job(cx))?;
+        if let Some(e) = this.encode_error.take() {
+            return Poll::Ready(Err(e));
+        }
+        ready!(this.poll_write_buffer(cx))?;
+        ready!(Pin::new(&mut this.io).poll_flush(cx))?;
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        ready!(Pin::new(&mut self.get_mut().io).poll_shutdown(cx))?;
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn poisoned() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "the RPC codec failed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::codec::{
+        base::{BaseInboundCodec, BaseOutboundCodec},
+        snappy::{SnappyInboundCodec, SnappyOutboundCodec},
+        InboundCodec, OutboundCodec,
+    };
+    use crate::rpc::protocol::{Encoding, Protocol, ProtocolId, Version};
+    use crate::rpc::DEFAULT_PROTOCOL_PREFIX;
+    use std::io::Cursor;
+
+    fn protocol() -> ProtocolId {
+        ProtocolId::new(
+            DEFAULT_PROTOCOL_PREFIX,
+            Protocol::Status,
+            Version::V1,
+            Encoding::Snappy,
+        )
+    }
+
+    /// Sends `responses` through one framed substream and reads them back through another.
+    async fn round_trip(workers: usize, responses: Vec<RPCResponse>) -> Vec<RPCResponse> {
+        let workers = Arc::new(CodecWorkers::new(workers).unwrap());
+        let codec = InboundCodec::Snappy(BaseInboundCodec::new(SnappyInboundCodec::new(
+            protocol(),
+            1_048_576,
+            Arc::default(),
+        )));
+        let mut sender = WorkerFramed::new(Vec::new(), codec, workers.clone());
+        for response in responses {
+            sender
+                .send(RPCCodedResponse::Success(response))
+                .await
+                .unwrap();
+        }
+
+        let codec = OutboundCodec::Snappy(BaseOutboundCodec::new(SnappyOutboundCodec::new(
+            protocol(),
+            1_048_576,
+            Arc::default(),
+        )));
+        let receiver = WorkerFramed::new(Cursor::new(sender.io), codec, workers);
+        receiver
+            .map(|response| match response {
+                Ok(RPCCodedResponse::Success(response)) => response,
+                other => panic!("unexpected response {:?}", other),
+            })
+            .collect()
+            .await
+    }
+
+    /// A codec that panics on every payload.
+    struct PanickingCodec;
+
+    impl Decoder for PanickingCodec {
+        type Item = ();
+        type Error = io::Error;
+
+        fn decode(&mut self, _: &mut BytesMut) -> Result<Option<()>, io::Error> {
+            panic!("decoding failed")
+        }
+    }
+
+    impl Encoder<RPCRequest> for PanickingCodec {
+        type Error = io::Error;
+
+        fn encode(&mut self, _: RPCRequest, _: &mut BytesMut) -> Result<(), io::Error> {
+            panic!("encoding failed")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_job_poisons_the_substream() {
+        let workers = Arc::new(CodecWorkers::new(1).unwrap());
+        // large enough to be decoded on a worker
+        let input = Cursor::new(vec![0; INLINE_SIZE * 2]);
+        let mut framed = WorkerFramed::new(input, PanickingCodec, workers);
+        assert!(framed.next().await.unwrap().is_err());
+        // the codec is lost with the job, later polls neither panic nor decode
+        assert!(framed.next().await.is_none());
+        assert!(framed.next().await.is_none());
+        assert!(framed.send(RPCRequest::MetaData).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ordered_on_workers() {
+        // the large payloads go to the workers, the small one is coded in place
+        let responses = vec![
+            RPCResponse::Status(vec![1; 100_000]),
+            RPCResponse::Status(vec![2; 10]),
+            RPCResponse::Status(vec![3; 50_000]),
+        ];
+        assert_eq!(round_trip(2, responses.clone()).await, responses);
+        assert_eq!(round_trip(0, responses.clone()).await, responses);
+    }
+}
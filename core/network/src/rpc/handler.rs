@@ -2,8 +2,9 @@
 #![allow(clippy::cognitive_complexity)]
 
 use super::methods::{RPCCodedResponse, RequestId};
-use super::protocol::{Protocol, RPCError, RPCProtocol, RPCRequest};
+use super::protocol::{OutboundRequest, Protocol, ProtocolId, RPCError, RPCProtocol, RPCRequest};
 use super::{RPCReceived, RPCSend};
+use crate::clock;
 use crate::rpc::protocol::{InboundFramed, OutboundFramed};
 use fnv::FnvHashMap;
 use futures::prelude::*;
@@ -22,23 +23,42 @@ use std::{
     task::{Context, Poll},
     time::{Duration, Instant},
 };
-use tokio::time::{delay_queue, delay_until, Delay, DelayQueue, Instant as TInstant};
+use tokio::time::{
+    delay_queue, delay_until, interval, Delay, DelayQueue, Instant as TInstant, Interval,
+};
 
 //TODO: Implement check_timeout() on the substream types
 
-/// The time (in seconds) before a substream that is awaiting a response from the user times out.
-pub const RESPONSE_TIMEOUT: u64 = 10;
-
 /// The number of times to retry an outbound upgrade in the case of IO errors.
 const IO_ERROR_RETRIES: u8 = 3;
 
-/// Maximum time given to the handler to perform shutdown operations.
-const SHUTDOWN_TIMEOUT_SECS: u8 = 15;
+/// How often open substreams are checked against the maximum substream age.
+const SUBSTREAM_WATCHDOG_INTERVAL_SECS: u64 = 10;
 
 /// Identifier of inbound and outbound substreams from the handler's perspective.
 #[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
 pub struct SubstreamId(usize);
 
+/// The number of RPC substreams open on a connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SubstreamCounts {
+    /// Substreams opened by the peer that are awaiting or sending our response.
+    pub inbound: usize,
+    /// Substreams we opened that are awaiting the peer's response.
+    pub outbound: usize,
+}
+
+impl std::iter::Sum for SubstreamCounts {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(SubstreamCounts::default(), |total, counts| {
+            SubstreamCounts {
+                inbound: total.inbound + counts.inbound,
+                outbound: total.outbound + counts.outbound,
+            }
+        })
+    }
+}
+
 /// An error encoutered by the handler.
 pub enum HandlerErr {
     /// An error ocurred for this peer's request. This can occurr during protocol negotiation,
@@ -81,9 +101,16 @@ pub struct RPCHandler {
     /// Current number of concurrent outbound substreams being opened.
     dial_negotiated: u32,
 
-    /// Current inbound substreams awaiting processing.
-    inbound_substreams:
-        FnvHashMap<SubstreamId, (InboundSubstreamState, Option<delay_queue::Key>, Protocol)>,
+    /// Current inbound substreams awaiting processing, with the time they were opened.
+    inbound_substreams: FnvHashMap<
+        SubstreamId,
+        (
+            InboundSubstreamState,
+            Option<delay_queue::Key>,
+            Protocol,
+            Instant,
+        ),
+    >,
 
     /// Inbound substream `DelayQueue` which keeps track of when an inbound substream will timeout.
     inbound_substreams_delay: DelayQueue<SubstreamId>,
@@ -119,6 +146,16 @@ pub struct RPCHandler {
     /// This keeps track of the number of attempts.
     outbound_io_error_retries: u8,
 
+    /// Checks for substreams open for longer than the given maximum age, which are then force
+    /// closed. `None` if substreams are never force closed.
+    substream_watchdog: Option<(Interval, Duration)>,
+
+    /// The open substreams last reported to the behaviour.
+    reported_substreams: SubstreamCounts,
+
+    /// The number of substreams force closed since the last report to the behaviour.
+    force_closed_substreams: usize,
+
     /// Logger for handling RPC streams
     log: slog::Logger,
 }
@@ -150,6 +187,10 @@ struct OutboundInfo {
     remaining_chunks: Option<usize>,
     /// RequestId as given by the application that sent the request.
     req_id: RequestId,
+    /// The protocol id negotiated with the peer.
+    protocol_id: ProtocolId,
+    /// When the substream was opened.
+    opened_at: Instant,
 }
 
 pub enum InboundSubstreamState {
@@ -255,6 +296,7 @@ impl RPCHandler {
     pub fn new(
         listen_protocol: SubstreamProtocol<RPCProtocol>,
         inactive_timeout: Duration,
+        max_substream_age: Option<Duration>,
         log: &slog::Logger,
     ) -> Self {
         RPCHandler {
@@ -275,6 +317,14 @@ impl RPCHandler {
             keep_alive: KeepAlive::Yes,
             inactive_timeout,
             outbound_io_error_retries: 0,
+            substream_watchdog: max_substream_age.map(|max_age| {
+                (
+                    interval(Duration::from_secs(SUBSTREAM_WATCHDOG_INTERVAL_SECS)),
+                    max_age,
+                )
+            }),
+            reported_substreams: SubstreamCounts::default(),
+            force_closed_substreams: 0,
             log: log.clone(),
         }
     }
@@ -313,9 +363,9 @@ impl RPCHandler {
                 self.dial_queue.push((id, req));
             }
 
-            self.state = HandlerState::ShuttingDown(delay_until(
-                TInstant::now() + Duration::from_secs(SHUTDOWN_TIMEOUT_SECS as u64),
-            ));
+            self.state = HandlerState::ShuttingDown(delay_until(TInstant::from_std(
+                clock::now() + self.listen_protocol.upgrade().limits.rpc_shutdown_timeout,
+            )));
         }
         self.update_keep_alive();
     }
@@ -341,14 +391,9 @@ impl RPCHandler {
     // NOTE: If the substream has closed due to inactivity, or the substream is in the
     // wrong state a response will fail silently.
     fn send_response(&mut self, inbound_id: SubstreamId, response: RPCCodedResponse) {
-        // Variables indicating if the response is an error response or a multi-part
-        // response
-        let res_is_error = response.is_error();
-        let res_is_multiple = response.multiple_responses();
-
         // check if the stream matching the response still exists
         let (substream_state, protocol) = match self.inbound_substreams.get_mut(&inbound_id) {
-            Some((substream_state, _, protocol)) => (substream_state, protocol),
+            Some((substream_state, _, protocol, _)) => (substream_state, protocol),
             None => {
                 warn!(self.log, "Stream has expired. Response not sent";
                     "response" => response.to_string(), "id" => inbound_id);
@@ -356,6 +401,11 @@ impl RPCHandler {
             }
         };
 
+        // Variables indicating if the response is an error response or a multi-part
+        // response
+        let res_is_error = response.is_error();
+        let res_is_multiple = response.multiple_responses(*protocol);
+
         // If the response we are sending is an error, report back for handling
         match response {
             RPCCodedResponse::InvalidRequest(ref reason)
@@ -382,15 +432,21 @@ impl RPCHandler {
         }
 
         match std::mem::replace(substream_state, InboundSubstreamState::Poisoned) {
-            InboundSubstreamState::ResponseIdle(substream) => {
-                // send the response
-                // if it's a single rpc request or an error close the stream after.
-                *substream_state = InboundSubstreamState::ResponsePendingSend {
-                    substream,
-                    message: response,
-                    closing: !res_is_multiple | res_is_error,
+            InboundSubstreamState::ResponseIdle(substream) => match response {
+                // close the stream if there is no response
+                RPCCodedResponse::StreamTermination => {
+                    *substream_state = InboundSubstreamState::Closing(substream);
                 }
-            }
+                _ => {
+                    // send the response
+                    // if it's a single rpc request or an error close the stream after.
+                    *substream_state = InboundSubstreamState::ResponsePendingSend {
+                        substream,
+                        message: response,
+                        closing: !res_is_multiple | res_is_error,
+                    }
+                }
+            },
             InboundSubstreamState::ResponsePendingSend {
                 substream,
                 message,
@@ -474,7 +530,7 @@ impl RPCHandler {
 
         match self.keep_alive {
             KeepAlive::Yes if should_shutdown => {
-                self.keep_alive = KeepAlive::Until(Instant::now() + self.inactive_timeout);
+                self.keep_alive = KeepAlive::Until(clock::now() + self.inactive_timeout);
             }
             KeepAlive::Yes => {} // We continue being active
             KeepAlive::Until(_) if should_shutdown => {} // Already deemed inactive
@@ -485,6 +541,63 @@ impl RPCHandler {
             KeepAlive::No => {} // currently not used
         }
     }
+
+    /// The number of substreams currently open.
+    fn substream_counts(&self) -> SubstreamCounts {
+        SubstreamCounts {
+            inbound: self.inbound_substreams.len(),
+            outbound: self.outbound_substreams.len(),
+        }
+    }
+
+    /// Drops the substreams that have been open for longer than `max_age`, resetting them. Their
+    /// requests are reported as rejected by the handler.
+    fn close_stale_substreams(&mut self, max_age: Duration) {
+        let now = clock::now();
+        let stale_inbound = self
+            .inbound_substreams
+            .iter()
+            .filter(|(_, (_, _, _, opened_at))| now.duration_since(*opened_at) > max_age)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in stale_inbound {
+            if let Some((_, delay_key, proto, opened_at)) = self.inbound_substreams.remove(&id) {
+                warn!(self.log, "Force closing stale inbound substream"; "id" => id,
+                    "protocol" => proto.to_string(), "age" => format!("{:?}", now.duration_since(opened_at)));
+                if let Some(delay_key) = delay_key {
+                    self.inbound_substreams_delay.remove(&delay_key);
+                }
+                self.queued_outbound_items.remove(&id);
+                self.pending_errors.push(HandlerErr::Inbound {
+                    id,
+                    proto,
+                    error: RPCError::HandlerRejected,
+                });
+                self.force_closed_substreams += 1;
+            }
+        }
+
+        let stale_outbound = self
+            .outbound_substreams
+            .iter()
+            .filter(|(_, info)| now.duration_since(info.opened_at) > max_age)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in stale_outbound {
+            if let Some(info) = self.outbound_substreams.remove(&id) {
+                warn!(self.log, "Force closing stale outbound substream"; "id" => id,
+                    "protocol" => info.proto.to_string(), "age" => format!("{:?}", now.duration_since(info.opened_at)));
+                self.outbound_substreams_delay.remove(&info.delay_key);
+                self.pending_errors.push(HandlerErr::Outbound {
+                    id: info.req_id,
+                    proto: info.proto,
+                    error: RPCError::HandlerRejected,
+                });
+                self.force_closed_substreams += 1;
+            }
+        }
+        self.update_keep_alive();
+    }
 }
 
 impl ProtocolsHandler for RPCHandler {
@@ -492,7 +605,7 @@ impl ProtocolsHandler for RPCHandler {
     type OutEvent = Result<RPCReceived, HandlerErr>;
     type Error = RPCError;
     type InboundProtocol = RPCProtocol;
-    type OutboundProtocol = RPCRequest;
+    type OutboundProtocol = OutboundRequest;
     type OutboundOpenInfo = (RequestId, RPCRequest); // Keep track of the id and the request
 
     fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol> {
@@ -508,24 +621,32 @@ impl ProtocolsHandler for RPCHandler {
             return;
         }
 
-        let (req, substream) = substream;
+        let (req, protocol_id, substream) = substream;
 
         // store requests that expect responses
         if req.expected_responses() > 0 {
             // Store the stream and tag the output.
             let delay_key = self.inbound_substreams_delay.insert(
                 self.current_inbound_substream_id,
-                Duration::from_secs(RESPONSE_TIMEOUT),
+                self.listen_protocol.upgrade().limits.rpc_response_timeout,
             );
             let awaiting_stream = InboundSubstreamState::ResponseIdle(Box::new(substream));
             self.inbound_substreams.insert(
                 self.current_inbound_substream_id,
-                (awaiting_stream, Some(delay_key), req.protocol()),
+                (
+                    awaiting_stream,
+                    Some(delay_key),
+                    req.protocol(),
+                    clock::now(),
+                ),
             );
         }
 
-        self.events_out
-            .push(RPCReceived::Request(self.current_inbound_substream_id, req));
+        self.events_out.push(RPCReceived::Request(
+            self.current_inbound_substream_id,
+            req,
+            protocol_id,
+        ));
         self.current_inbound_substream_id.0 += 1;
 
         self.update_keep_alive();
@@ -537,6 +658,7 @@ impl ProtocolsHandler for RPCHandler {
         request_info: Self::OutboundOpenInfo,
     ) {
         self.dial_negotiated -= 1;
+        let (out, protocol_id) = out;
         let (id, request) = request_info;
         let proto = request.protocol();
 
@@ -556,7 +678,7 @@ impl ProtocolsHandler for RPCHandler {
             // new outbound request. Store the stream and tag the output.
             let delay_key = self.outbound_substreams_delay.insert(
                 self.current_outbound_substream_id,
-                Duration::from_secs(RESPONSE_TIMEOUT),
+                self.listen_protocol.upgrade().limits.rpc_response_timeout,
             );
             let awaiting_stream = OutboundSubstreamState::RequestPendingResponse {
                 substream: Box::new(out),
@@ -578,6 +700,8 @@ impl ProtocolsHandler for RPCHandler {
                         proto,
                         remaining_chunks: expected_responses,
                         req_id: id,
+                        protocol_id,
+                        opened_at: clock::now(),
                     },
                 )
                 .is_some()
@@ -648,7 +772,11 @@ impl ProtocolsHandler for RPCHandler {
     }
 
     fn connection_keep_alive(&self) -> KeepAlive {
-        self.keep_alive
+        // the deadline is kept on the network's clock, libp2p waits for it on the system's
+        match self.keep_alive {
+            KeepAlive::Until(deadline) => KeepAlive::Until(clock::wall_deadline(deadline)),
+            keep_alive => keep_alive,
+        }
     }
 
     fn poll(
@@ -690,12 +818,28 @@ impl ProtocolsHandler for RPCHandler {
             }
         }
 
+        // force close substreams that have been open for too long
+        if let Some((watchdog, max_age)) = self.substream_watchdog.as_mut() {
+            let max_age = *max_age;
+            let mut check = false;
+            while let Poll::Ready(Some(_)) = watchdog.poll_next_unpin(cx) {
+                check = true;
+            }
+            if check {
+                self.close_stale_substreams(max_age);
+                if !self.pending_errors.is_empty() {
+                    let err_info = self.pending_errors.remove(0);
+                    return Poll::Ready(ProtocolsHandlerEvent::Custom(Err(err_info)));
+                }
+            }
+        }
+
         // purge expired inbound substreams and send an error
         loop {
             match self.inbound_substreams_delay.poll_next_unpin(cx) {
                 Poll::Ready(Some(Ok(inbound_id))) => {
                     // handle a stream timeout for various states
-                    if let Some((substream_state, delay_key, protocol)) =
+                    if let Some((substream_state, delay_key, protocol, _)) =
                         self.inbound_substreams.get_mut(inbound_id.get_ref())
                     {
                         // the delay has been removed
@@ -913,6 +1057,13 @@ impl ProtocolsHandler for RPCHandler {
                                         &mut drive_stream_further,
                                     );
                                 } else {
+                                    // the response has not been sent yet, inform back to cancel
+                                    // this request's processing
+                                    self.pending_errors.push(HandlerErr::Inbound {
+                                        id: request_id,
+                                        proto: entry.get().2,
+                                        error: RPCError::HandlerRejected,
+                                    });
                                     entry.get_mut().0 = InboundSubstreamState::Closing(substream);
                                     drive_stream_further = true;
                                 }
@@ -1008,8 +1159,10 @@ impl ProtocolsHandler for RPCHandler {
                                         request,
                                     };
                                 substream_entry.remaining_chunks = Some(remaining_chunks);
-                                self.outbound_substreams_delay
-                                    .reset(delay_key, Duration::from_secs(RESPONSE_TIMEOUT));
+                                self.outbound_substreams_delay.reset(
+                                    delay_key,
+                                    self.listen_protocol.upgrade().limits.rpc_response_timeout,
+                                );
                             }
                         } else {
                             // either this is a single response request or we received an
@@ -1020,9 +1173,15 @@ impl ProtocolsHandler for RPCHandler {
                         // Check what type of response we got and report it accordingly
                         let id = entry.get().req_id;
                         let proto = entry.get().proto;
+                        let protocol_id = entry.get().protocol_id.clone();
 
                         let received = match response {
-                            RPCCodedResponse::Success(resp) => Ok(RPCReceived::Response(id, resp)),
+                            RPCCodedResponse::Success(resp) => {
+                                Ok(RPCReceived::Response(id, resp, protocol_id))
+                            }
+                            RPCCodedResponse::StreamTermination => {
+                                Ok(RPCReceived::EndOfStream(id, protocol_id))
+                            }
                             RPCCodedResponse::InvalidRequest(ref r)
                             | RPCCodedResponse::ServerError(ref r)
                             | RPCCodedResponse::Unknown(ref r) => {
@@ -1047,9 +1206,16 @@ impl ProtocolsHandler for RPCHandler {
                         // drop the stream
                         let delay_key = &entry.get().delay_key;
                         let request_id = *&entry.get().req_id;
+                        let protocol_id = entry.get().protocol_id.clone();
                         self.outbound_substreams_delay.remove(delay_key);
                         entry.remove_entry();
                         self.update_keep_alive();
+                        // notify the application that the chunks of the response ended
+                        if request.protocol().is_chunked() {
+                            return Poll::Ready(ProtocolsHandlerEvent::Custom(Ok(
+                                RPCReceived::EndOfStream(request_id, protocol_id),
+                            )));
+                        }
 
                         // else we return an error, stream should not have closed early.
                         let outbound_err = HandlerErr::Outbound {
@@ -1084,9 +1250,16 @@ impl ProtocolsHandler for RPCHandler {
                             let delay_key = &entry.get().delay_key;
                             let protocol = entry.get().proto;
                             let request_id = entry.get().req_id;
+                            let protocol_id = entry.get().protocol_id.clone();
                             self.outbound_substreams_delay.remove(delay_key);
                             entry.remove_entry();
                             self.update_keep_alive();
+                            // notify the application that the chunks of the response ended
+                            if protocol.is_chunked() {
+                                return Poll::Ready(ProtocolsHandlerEvent::Custom(Ok(
+                                    RPCReceived::EndOfStream(request_id, protocol_id),
+                                )));
+                            }
                         }
                         Poll::Pending => {
                             entry.get_mut().state = OutboundSubstreamState::Closing(substream);
@@ -1106,11 +1279,31 @@ impl ProtocolsHandler for RPCHandler {
             let (id, req) = self.dial_queue.remove(0);
             self.dial_queue.shrink_to_fit();
             self.update_keep_alive();
+            let upgrade = self.listen_protocol.upgrade();
+            let request = OutboundRequest {
+                request: req.clone(),
+                versions: upgrade.versions.get(req.protocol()).to_vec(),
+                codecs: upgrade.versions.codecs().clone(),
+                prefix: upgrade.prefix.clone(),
+                pool: upgrade.pool.clone(),
+                workers: upgrade.workers.clone(),
+                limits: upgrade.limits.clone(),
+            };
             return Poll::Ready(ProtocolsHandlerEvent::OutboundSubstreamRequest {
-                protocol: SubstreamProtocol::new(req.clone()),
+                protocol: SubstreamProtocol::new(request),
                 info: (id, req),
             });
         }
+
+        // report changes of the open substreams
+        let counts = self.substream_counts();
+        if counts != self.reported_substreams || self.force_closed_substreams > 0 {
+            self.reported_substreams = counts;
+            return Poll::Ready(ProtocolsHandlerEvent::Custom(Ok(RPCReceived::Substreams {
+                counts,
+                force_closed: std::mem::take(&mut self.force_closed_substreams),
+            })));
+        }
         Poll::Pending
     }
 }
@@ -1126,6 +1319,10 @@ fn apply_queued_responses(
             *new_items_to_send = true;
             // we have queued items
             match queue.remove(0) {
+                RPCCodedResponse::StreamTermination => {
+                    // close the stream if this is a stream termination
+                    InboundSubstreamState::Closing(Box::new(substream))
+                }
                 chunk => InboundSubstreamState::ResponsePendingSend {
                     substream: Box::new(substream),
                     message: chunk,
@@ -1,7 +1,10 @@
 //! Available RPC methods types and ids.
 
+use super::protocol::Protocol;
 use crate::types::EnrBitfield;
+use crate::{Multiaddr, PeerId};
 use serde::Serialize;
+use std::convert::TryFrom;
 use std::ops::Deref;
 
 /// Maximum number of blocks in a single request.
@@ -59,25 +62,72 @@ pub enum RequestId {
     Behaviour,
 }
 
+/// The length of an SSZ encoded `StatusMessage`.
+pub const STATUS_MESSAGE_LENGTH: usize = 4 + 32 + 8 + 32 + 8;
+
 /// The STATUS request/response handshake message.
-#[derive(Clone, Debug, PartialEq)]
+///
+/// Encoded as an SSZ container of fixed size fields: the fork digest, the finalized root, the
+/// finalized epoch, the head root and the head slot, with integers little-endian. Networks with
+/// a status of their own keep sending raw bytes in `Request::Status` and `Response::Status`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
 pub struct StatusMessage {
     /// The fork version of the chain we are broadcasting.
     pub fork_digest: [u8; 4],
 
     /// Latest finalized root.
-    pub finalized_root: Vec<u8>,
+    pub finalized_root: [u8; 32],
 
     /// Latest finalized epoch.
     pub finalized_epoch: u64,
 
     /// The latest block root.
-    pub head_root: Vec<u8>,
+    pub head_root: [u8; 32],
 
     /// The slot associated with the latest block root.
     pub head_slot: u64,
 }
 
+impl StatusMessage {
+    /// Encodes the status as an SSZ container of `STATUS_MESSAGE_LENGTH` bytes.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(STATUS_MESSAGE_LENGTH);
+        bytes.extend_from_slice(&self.fork_digest);
+        bytes.extend_from_slice(&self.finalized_root);
+        bytes.extend_from_slice(&self.finalized_epoch.to_le_bytes());
+        bytes.extend_from_slice(&self.head_root);
+        bytes.extend_from_slice(&self.head_slot.to_le_bytes());
+        bytes
+    }
+
+    /// Returns true if the status is for the fork of `fork_id`, an ENR fork id starting with the
+    /// fork digest. A fork id too short to hold a digest matches any status.
+    pub fn is_on_fork(&self, fork_id: &[u8]) -> bool {
+        fork_id.len() < self.fork_digest.len() || fork_id[..4] == self.fork_digest
+    }
+
+    /// Returns true if the status is on another chain than `other`: both finalized the same epoch,
+    /// at different roots.
+    pub fn conflicts_with(&self, other: &StatusMessage) -> bool {
+        self.finalized_epoch == other.finalized_epoch && self.finalized_root != other.finalized_root
+    }
+
+    /// Decodes a status, returning `None` if the bytes are not exactly a status.
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != STATUS_MESSAGE_LENGTH {
+            return None;
+        }
+        let mut reader = Reader { bytes, at: 0 };
+        let mut status = StatusMessage::default();
+        status.fork_digest.copy_from_slice(reader.take(4)?);
+        status.finalized_root.copy_from_slice(reader.take(32)?);
+        status.finalized_epoch = reader.u64()?;
+        status.head_root.copy_from_slice(reader.take(32)?);
+        status.head_slot = reader.u64()?;
+        Some(status)
+    }
+}
+
 /// The PING request/response message.
 #[derive(Clone, Debug, PartialEq)]
 pub struct Ping {
@@ -94,6 +144,248 @@ pub struct MetaData {
     pub attnets: EnrBitfield,
 }
 
+/// The TOPICS request/response message: the topics the sender is subscribed to.
+///
+/// Encoded as a little-endian `u16` count followed by each topic as a little-endian `u16` length
+/// and its UTF-8 bytes. The count is always present, so the encoding is never empty.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Topics {
+    pub topics: Vec<String>,
+}
+
+impl Topics {
+    /// Encodes the topic list. Topics beyond `u16::MAX`, or longer than `u16::MAX` bytes, are
+    /// left out.
+    pub fn encode(&self) -> Vec<u8> {
+        let topics: Vec<&String> = self
+            .topics
+            .iter()
+            .filter(|topic| topic.len() <= u16::MAX as usize)
+            .take(u16::MAX as usize)
+            .collect();
+        let mut bytes = (topics.len() as u16).to_le_bytes().to_vec();
+        for topic in topics {
+            bytes.extend_from_slice(&(topic.len() as u16).to_le_bytes());
+            bytes.extend_from_slice(topic.as_bytes());
+        }
+        bytes
+    }
+
+    /// Decodes a topic list, returning `None` if the bytes are malformed.
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let read_u16 = |bytes: &[u8], at: usize| -> Option<usize> {
+            let field = bytes.get(at..at + 2)?;
+            Some(u16::from_le_bytes([field[0], field[1]]) as usize)
+        };
+        let count = read_u16(bytes, 0)?;
+        let mut at = 2;
+        let mut topics = Vec::with_capacity(count);
+        for _ in 0..count {
+            let len = read_u16(bytes, at)?;
+            let topic = bytes.get(at + 2..at + 2 + len)?;
+            topics.push(String::from_utf8(topic.to_vec()).ok()?);
+            at += 2 + len;
+        }
+        if at != bytes.len() {
+            return None;
+        }
+        Some(Topics { topics })
+    }
+}
+
+/// The RENDEZVOUS request/response messages, after the libp2p rendezvous protocol: nodes register
+/// their addresses under a namespace at a rendezvous point, which tells other nodes asking for the
+/// namespace about them.
+///
+/// Encoded as a tag byte followed by the fields of the message. Strings, addresses and peer ids
+/// are prefixed with their length and lists with their count, both as a little-endian `u16`. TTLs
+/// are little-endian `u64` seconds.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RendezvousMessage {
+    /// Registers the sender at its `addresses` under `namespace`, for `ttl` seconds.
+    Register {
+        namespace: String,
+        addresses: Vec<Multiaddr>,
+        ttl: u64,
+    },
+    /// The response to an accepted `Register`: the TTL the registration was accepted for.
+    Registered { ttl: u64 },
+    /// The response to a refused `Register`, with the reason it was refused.
+    Refused(RegistrationRefusal),
+    /// Asks for up to `limit` of the peers registered under `namespace`.
+    Discover { namespace: String, limit: u16 },
+    /// The response to `Discover`: the peers registered under the namespace, with their addresses.
+    Registrations(Vec<(PeerId, Vec<Multiaddr>)>),
+}
+
+impl RendezvousMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        fn put(bytes: &mut Vec<u8>, field: &[u8]) {
+            bytes.extend_from_slice(&(field.len() as u16).to_le_bytes());
+            bytes.extend_from_slice(field);
+        }
+        fn put_addresses(bytes: &mut Vec<u8>, addresses: &[Multiaddr]) {
+            bytes.extend_from_slice(&(addresses.len() as u16).to_le_bytes());
+            for address in addresses {
+                put(bytes, &address.to_vec());
+            }
+        }
+        let mut bytes = Vec::new();
+        match self {
+            RendezvousMessage::Register {
+                namespace,
+                addresses,
+                ttl,
+            } => {
+                bytes.push(0);
+                put(&mut bytes, namespace.as_bytes());
+                put_addresses(&mut bytes, addresses);
+                bytes.extend_from_slice(&ttl.to_le_bytes());
+            }
+            RendezvousMessage::Registered { ttl } => {
+                bytes.push(1);
+                bytes.extend_from_slice(&ttl.to_le_bytes());
+            }
+            RendezvousMessage::Discover { namespace, limit } => {
+                bytes.push(2);
+                put(&mut bytes, namespace.as_bytes());
+                bytes.extend_from_slice(&limit.to_le_bytes());
+            }
+            RendezvousMessage::Refused(refusal) => {
+                bytes.push(4);
+                bytes.push(*refusal as u8);
+            }
+            RendezvousMessage::Registrations(registrations) => {
+                bytes.push(3);
+                bytes.extend_from_slice(&(registrations.len() as u16).to_le_bytes());
+                for (peer_id, addresses) in registrations {
+                    put(&mut bytes, peer_id.as_bytes());
+                    put_addresses(&mut bytes, addresses);
+                }
+            }
+        }
+        bytes
+    }
+
+    /// Decodes a message, returning `None` if the bytes are malformed.
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader { bytes, at: 0 };
+        let message = match reader.take(1)?[0] {
+            0 => RendezvousMessage::Register {
+                namespace: reader.string()?,
+                addresses: reader.addresses()?,
+                ttl: reader.u64()?,
+            },
+            1 => RendezvousMessage::Registered { ttl: reader.u64()? },
+            2 => RendezvousMessage::Discover {
+                namespace: reader.string()?,
+                limit: reader.u16()?,
+            },
+            3 => {
+                let count = reader.u16()?;
+                let mut registrations = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let peer_id = PeerId::from_bytes(reader.field()?.to_vec()).ok()?;
+                    registrations.push((peer_id, reader.addresses()?));
+                }
+                RendezvousMessage::Registrations(registrations)
+            }
+            4 => RendezvousMessage::Refused(RegistrationRefusal::from_u8(reader.take(1)?[0])?),
+            _ => return None,
+        };
+        if reader.at != bytes.len() {
+            return None;
+        }
+        Some(message)
+    }
+}
+
+/// Why a rendezvous point refused a registration, encoded as a byte.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegistrationRefusal {
+    /// The namespace is empty or too long.
+    InvalidNamespace = 0,
+    /// The TTL is 0.
+    InvalidTtl = 1,
+    /// None of the addresses is on a host the node is connected from.
+    NoAddresses = 2,
+    /// The rendezvous point keeps no more namespaces.
+    TooManyNamespaces = 3,
+    /// The namespace holds no more registrations.
+    NamespaceFull = 4,
+    /// The node is registered under as many namespaces as a node may be.
+    TooManyForPeer = 5,
+}
+
+impl RegistrationRefusal {
+    fn from_u8(byte: u8) -> Option<Self> {
+        Some(match byte {
+            0 => RegistrationRefusal::InvalidNamespace,
+            1 => RegistrationRefusal::InvalidTtl,
+            2 => RegistrationRefusal::NoAddresses,
+            3 => RegistrationRefusal::TooManyNamespaces,
+            4 => RegistrationRefusal::NamespaceFull,
+            5 => RegistrationRefusal::TooManyForPeer,
+            _ => return None,
+        })
+    }
+}
+
+impl std::fmt::Display for RegistrationRefusal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            RegistrationRefusal::InvalidNamespace => "invalid namespace",
+            RegistrationRefusal::InvalidTtl => "invalid ttl",
+            RegistrationRefusal::NoAddresses => "no address on a connected host",
+            RegistrationRefusal::TooManyNamespaces => "too many namespaces",
+            RegistrationRefusal::NamespaceFull => "namespace full",
+            RegistrationRefusal::TooManyForPeer => "too many namespaces for the peer",
+        };
+        f.write_str(reason)
+    }
+}
+
+/// Reads the fields of an encoded `RendezvousMessage` or `StatusMessage`.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let field = self.bytes.get(self.at..self.at + len)?;
+        self.at += len;
+        Some(field)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let field = self.take(2)?;
+        Some(u16::from_le_bytes([field[0], field[1]]))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        let mut value = [0; 8];
+        value.copy_from_slice(self.take(8)?);
+        Some(u64::from_le_bytes(value))
+    }
+
+    fn field(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()?;
+        self.take(len as usize)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        String::from_utf8(self.field()?.to_vec()).ok()
+    }
+
+    fn addresses(&mut self) -> Option<Vec<Multiaddr>> {
+        let count = self.u16()?;
+        (0..count)
+            .map(|_| Multiaddr::try_from(self.field()?.to_vec()).ok())
+            .collect()
+    }
+}
+
 /// The reason given for a `Goodbye` message.
 ///
 /// Note: any unknown `u64::into(n)` will resolve to `Goodbye::Unknown` for any unknown `n`,
@@ -144,6 +436,15 @@ pub enum RPCResponse {
 
     /// A response to a META_DATA request.
     MetaData(Vec<u8>),
+
+    /// A response to a TOPICS request.
+    Topics(Vec<u8>),
+
+    /// A response to a RENDEZVOUS request.
+    Rendezvous(Vec<u8>),
+
+    /// A response to a request of a custom protocol.
+    Custom(Vec<u8>),
 }
 
 /// The structured response containing a result/code indicating success or failure
@@ -161,6 +462,9 @@ pub enum RPCCodedResponse {
 
     /// There was an unknown response.
     Unknown(ErrorType),
+
+    /// Ends a response of several chunks. Nothing is sent, the stream is closed.
+    StreamTermination,
 }
 
 /// The code assigned to an erroneous `RPCResponse`.
@@ -179,6 +483,7 @@ impl RPCCodedResponse {
             RPCCodedResponse::InvalidRequest(_) => Some(1),
             RPCCodedResponse::ServerError(_) => Some(2),
             RPCCodedResponse::Unknown(_) => Some(255),
+            RPCCodedResponse::StreamTermination => None,
         }
     }
 
@@ -208,17 +513,23 @@ impl RPCCodedResponse {
         }
     }
 
-    /// Specifies which response allows for multiple chunks for the stream handler.
-    pub fn multiple_responses(&self) -> bool {
+    /// Specifies which response allows for multiple chunks for the stream handler, when answering
+    /// a request of `protocol`.
+    pub fn multiple_responses(&self, protocol: Protocol) -> bool {
         match self {
             RPCCodedResponse::Success(resp) => match resp {
                 RPCResponse::Status(_) => false,
                 RPCResponse::Pong(_) => false,
                 RPCResponse::MetaData(_) => false,
+                RPCResponse::Topics(_) => false,
+                RPCResponse::Rendezvous(_) => false,
+                RPCResponse::Custom(_) => protocol.is_chunked(),
             },
             RPCCodedResponse::InvalidRequest(_) => true,
             RPCCodedResponse::ServerError(_) => true,
             RPCCodedResponse::Unknown(_) => true,
+            // stream terminations are part of responses that have chunks
+            RPCCodedResponse::StreamTermination => true,
         }
     }
 
@@ -226,14 +537,14 @@ impl RPCCodedResponse {
     /// sent.
     pub fn is_error(&self) -> bool {
         match self {
-            RPCCodedResponse::Success(_) => false,
+            RPCCodedResponse::Success(_) | RPCCodedResponse::StreamTermination => false,
             _ => true,
         }
     }
 
     pub fn error_code(&self) -> Option<RPCResponseErrorCode> {
         match self {
-            RPCCodedResponse::Success(_) => None,
+            RPCCodedResponse::Success(_) | RPCCodedResponse::StreamTermination => None,
             RPCCodedResponse::InvalidRequest(_) => Some(RPCResponseErrorCode::InvalidRequest),
             RPCCodedResponse::ServerError(_) => Some(RPCResponseErrorCode::ServerError),
             RPCCodedResponse::Unknown(_) => Some(RPCResponseErrorCode::Unknown),
@@ -264,6 +575,9 @@ impl std::fmt::Display for RPCResponse {
             RPCResponse::Status(status) => write!(f, "{:?}", status),
             RPCResponse::Pong(ping) => write!(f, "Pong: {:?}", ping),
             RPCResponse::MetaData(metadata) => write!(f, "Metadata: {:?}", metadata),
+            RPCResponse::Topics(topics) => write!(f, "Topics: {:?}", topics),
+            RPCResponse::Rendezvous(message) => write!(f, "Rendezvous: {:?}", message),
+            RPCResponse::Custom(data) => write!(f, "Custom: {:?}", data),
         }
     }
 }
@@ -275,6 +589,7 @@ impl std::fmt::Display for RPCCodedResponse {
             RPCCodedResponse::InvalidRequest(err) => write!(f, "Invalid Request: {:?}", err),
             RPCCodedResponse::ServerError(err) => write!(f, "Server Error: {:?}", err),
             RPCCodedResponse::Unknown(err) => write!(f, "Unknown Error: {:?}", err),
+            RPCCodedResponse::StreamTermination => write!(f, "Stream Termination"),
         }
     }
 }
@@ -304,3 +619,116 @@ impl slog::Value for RequestId {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topics_encoding() {
+        let topics = Topics {
+            topics: vec![
+                "/mothra/topic_a".into(),
+                "".into(),
+                "/mothra/topic_b".into(),
+            ],
+        };
+        let bytes = topics.encode();
+        assert_eq!(Topics::decode(&bytes), Some(topics));
+
+        let empty = Topics::default().encode();
+        assert_eq!(empty, vec![0, 0]);
+        assert_eq!(Topics::decode(&empty), Some(Topics::default()));
+
+        // truncated and trailing bytes are rejected
+        assert_eq!(Topics::decode(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(Topics::decode(&[bytes.clone(), vec![0]].concat()), None);
+        assert_eq!(Topics::decode(&[1, 0, 2, 0, 0xff, 0xfe]), None);
+    }
+
+    #[test]
+    fn test_status_encoding() {
+        let status = StatusMessage {
+            fork_digest: [1, 2, 3, 4],
+            finalized_root: [5; 32],
+            finalized_epoch: 10,
+            head_root: [6; 32],
+            head_slot: 330,
+        };
+        let bytes = status.encode();
+        assert_eq!(bytes.len(), STATUS_MESSAGE_LENGTH);
+        assert_eq!(&bytes[36..44], &10u64.to_le_bytes());
+        assert_eq!(StatusMessage::decode(&bytes), Some(status));
+
+        // truncated and trailing bytes are rejected
+        let extended = [bytes.clone(), vec![7; 8]].concat();
+        assert_eq!(StatusMessage::decode(&extended), None);
+        assert_eq!(StatusMessage::decode(&bytes[..bytes.len() - 1]), None);
+    }
+
+    #[test]
+    fn test_status_fork() {
+        let status = StatusMessage {
+            fork_digest: [1, 2, 3, 4],
+            ..StatusMessage::default()
+        };
+        assert!(status.is_on_fork(&[1, 2, 3, 4, 0, 0, 0, 1]));
+        assert!(!status.is_on_fork(&[1, 2, 3, 5, 0, 0, 0, 1]));
+        assert!(status.is_on_fork(&[]));
+
+        let finalized = StatusMessage {
+            finalized_root: [1; 32],
+            finalized_epoch: 10,
+            ..status.clone()
+        };
+        let other_chain = StatusMessage {
+            finalized_root: [2; 32],
+            ..finalized.clone()
+        };
+        let ahead = StatusMessage {
+            finalized_epoch: 11,
+            ..other_chain.clone()
+        };
+        assert!(finalized.conflicts_with(&other_chain));
+        assert!(!finalized.conflicts_with(&finalized));
+        // a root finalized at another epoch cannot be compared
+        assert!(!finalized.conflicts_with(&ahead));
+    }
+
+    #[test]
+    fn test_rendezvous_encoding() {
+        let address: Multiaddr = "/ip4/10.0.0.1/tcp/9000".parse().unwrap();
+        let messages = vec![
+            RendezvousMessage::Register {
+                namespace: "mothra".into(),
+                addresses: vec![address.clone()],
+                ttl: 7200,
+            },
+            RendezvousMessage::Registered { ttl: 0 },
+            RendezvousMessage::Refused(RegistrationRefusal::NamespaceFull),
+            RendezvousMessage::Discover {
+                namespace: "".into(),
+                limit: 100,
+            },
+            RendezvousMessage::Registrations(vec![
+                (PeerId::random(), vec![address.clone()]),
+                (PeerId::random(), vec![]),
+            ]),
+        ];
+        for message in messages {
+            let bytes = message.encode();
+            assert_eq!(RendezvousMessage::decode(&bytes), Some(message));
+            assert_eq!(RendezvousMessage::decode(&bytes[..bytes.len() - 1]), None);
+            assert_eq!(
+                RendezvousMessage::decode(&[bytes.clone(), vec![0]].concat()),
+                None
+            );
+        }
+        assert_eq!(RendezvousMessage::decode(&[]), None);
+        assert_eq!(RendezvousMessage::decode(&[4]), None);
+        assert_eq!(RendezvousMessage::decode(&[4, 6]), None);
+        // an address that is not a multiaddr
+        let bytes = [&[0, 0, 0, 1, 0, 1, 0, 0xff][..], &7200u64.to_le_bytes()].concat();
+        assert_eq!(RendezvousMessage::decode(&bytes), None);
+    }
+}
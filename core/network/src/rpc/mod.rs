@@ -4,6 +4,9 @@
 //! direct peer-to-peer communication primarily for sending/receiving chain information for
 //! syncing.
 
+use crate::limits::Limits;
+use crate::log_context;
+use crate::types::BufferPool;
 use handler::RPCHandler;
 use libp2p::core::{connection::ConnectionId, ConnectedPoint};
 use libp2p::swarm::{
@@ -12,16 +15,24 @@ use libp2p::swarm::{
 };
 use libp2p::{Multiaddr, PeerId};
 use slog::{debug, o};
+use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::time::Duration;
 
 pub(crate) use handler::HandlerErr;
-pub(crate) use methods::{MetaData, Ping, RPCCodedResponse, RPCResponse};
+pub(crate) use methods::{
+    MetaData, Ping, RPCCodedResponse, RPCResponse, RegistrationRefusal, RendezvousMessage, Topics,
+};
 pub(crate) use protocol::{RPCProtocol, RPCRequest};
 
-pub use handler::SubstreamId;
+pub use codec::worker::{CodecWorkers, DEFAULT_RPC_CODEC_WORKERS};
+pub use handler::{SubstreamCounts, SubstreamId};
 pub use methods::{GoodbyeReason, RPCResponseErrorCode, RequestId, StatusMessage};
-pub use protocol::{Protocol, RPCError};
+pub use protocol::{
+    validate_protocol_prefix, CustomProtocol, CustomProtocols, Encoding, Protocol, ProtocolId,
+    ProtocolVersions, RPCError, Version, VersionCodec, VersionCodecs, DEFAULT_PROTOCOL_PREFIX,
+    MAX_PROTOCOL_NAME_LENGTH,
+};
 
 pub(crate) mod codec;
 mod handler;
@@ -47,14 +58,27 @@ pub enum RPCReceived {
     /// A request received from the outside.
     ///
     /// The `SubstreamId` is given by the `RPCHandler` as it identifies this request with the
-    /// *inbound* substream over which it is managed.
-    Request(SubstreamId, RPCRequest),
+    /// *inbound* substream over which it is managed. The `ProtocolId` is the protocol the request
+    /// was negotiated with.
+    Request(SubstreamId, RPCRequest, ProtocolId),
     /// A response received from the outside.
     ///
     /// The `RequestId` corresponds to the application given ID of the original request sent to the
     /// peer. The second parameter is a single chunk of a response. These go over *outbound*
-    /// connections.
-    Response(RequestId, RPCResponse),
+    /// connections. The `ProtocolId` is the protocol the request was negotiated with.
+    Response(RequestId, RPCResponse, ProtocolId),
+    /// Marks the end of a response of several chunks. The peer closed the stream, or sent all
+    /// the chunks the protocol allows.
+    EndOfStream(RequestId, ProtocolId),
+    /// The substreams open on the connection changed. This is generated by the handler, not
+    /// received from the peer.
+    ///
+    /// `force_closed` is the number of substreams dropped since the last report because they were
+    /// open for longer than the maximum substream age.
+    Substreams {
+        counts: SubstreamCounts,
+        force_closed: usize,
+    },
 }
 
 impl std::fmt::Display for RPCSend {
@@ -76,24 +100,77 @@ pub struct RPCMessage {
     pub event: <RPCHandler as ProtocolsHandler>::OutEvent,
 }
 
+/// The protocols served by `RPC`, and how their messages are exchanged.
+pub struct RPCConfig {
+    /// The versions of each protocol we support.
+    pub versions: ProtocolVersions,
+    /// The prefix of the protocol ids.
+    pub prefix: String,
+    /// The protocols registered by the application.
+    pub custom: CustomProtocols,
+    /// Substreams open for longer than this are force closed.
+    pub max_substream_age: Option<Duration>,
+    /// The buffers received messages are decoded into.
+    pub pool: Arc<BufferPool>,
+    /// The threads RPC payloads are encoded and decoded on.
+    pub workers: Arc<CodecWorkers>,
+    /// The maximum size and the timeouts of requests and responses.
+    pub limits: Arc<Limits>,
+}
+
 /// Implements the libp2p `NetworkBehaviour` trait and therefore manages network-level
 /// logic.
 pub struct RPC {
     /// Queue of events to be processed.
     events: Vec<NetworkBehaviourAction<RPCSend, RPCMessage>>,
+    /// The versions of each protocol we support.
+    versions: ProtocolVersions,
+    /// The prefix of the protocol ids.
+    prefix: String,
+    /// The protocols registered by the application.
+    custom: CustomProtocols,
+    /// Substreams open for longer than this are force closed.
+    max_substream_age: Option<Duration>,
+    /// The buffers received messages are decoded into.
+    pool: Arc<BufferPool>,
+    /// The threads RPC payloads are encoded and decoded on.
+    workers: Arc<CodecWorkers>,
+    /// The maximum size and the timeouts of requests and responses.
+    limits: Arc<Limits>,
     /// Slog logger for RPC behaviour.
     log: slog::Logger,
 }
 
 impl RPC {
-    pub fn new(log: slog::Logger) -> Self {
+    pub fn new(config: RPCConfig, log: slog::Logger) -> Self {
+        let RPCConfig {
+            versions,
+            prefix,
+            custom,
+            max_substream_age,
+            pool,
+            workers,
+            limits,
+        } = config;
         let log = log.new(o!("service" => "libp2p_rpc"));
         RPC {
             events: Vec::new(),
+            versions,
+            prefix,
+            custom,
+            max_substream_age,
+            pool,
+            workers,
+            limits,
             log,
         }
     }
 
+    /// Returns the number of events waiting to be polled.
+    pub fn queued_events(&self) -> usize {
+        self.events.len()
+    }
+
     /// Sends an RPC response.
     ///
     /// The peer must be connected for this to succeed.
@@ -128,8 +205,16 @@ impl NetworkBehaviour for RPC {
 
     fn new_handler(&mut self) -> Self::ProtocolsHandler {
         RPCHandler::new(
-            SubstreamProtocol::new(RPCProtocol {}),
+            SubstreamProtocol::new(RPCProtocol {
+                versions: self.versions.clone(),
+                prefix: self.prefix.clone(),
+                custom: self.custom.clone(),
+                pool: self.pool.clone(),
+                workers: self.workers.clone(),
+                limits: self.limits.clone(),
+            }),
             Duration::from_secs(30),
+            self.max_substream_age,
             &self.log,
         )
     }
@@ -142,7 +227,8 @@ impl NetworkBehaviour for RPC {
     // Use connection established/closed instead of these currently
     fn inject_connected(&mut self, peer_id: &PeerId) {
         // find the peer's meta-data
-        debug!(self.log, "Requesting new peer's metadata"; "peer_id" => format!("{}",peer_id));
+        let log = log_context::rpc(&self.log, peer_id, Protocol::MetaData, RequestId::Behaviour);
+        debug!(log, "Requesting new peer's metadata");
         let rpc_event = RPCSend::Request(RequestId::Behaviour, RPCRequest::MetaData);
         self.events.push(NetworkBehaviourAction::NotifyHandler {
             peer_id: peer_id.clone(),
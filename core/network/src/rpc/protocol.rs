@@ -1,37 +1,32 @@
 #![allow(clippy::type_complexity)]
 
 use super::methods::*;
+use crate::limits::Limits;
 use crate::rpc::codec::{
     base::{BaseInboundCodec, BaseOutboundCodec},
     snappy::{SnappyInboundCodec, SnappyOutboundCodec},
+    worker::{CodecWorkers, WorkerFramed},
     InboundCodec, OutboundCodec,
 };
+use crate::types::BufferPool;
 use futures::future::Ready;
 use futures::prelude::*;
 use futures::prelude::{AsyncRead, AsyncWrite};
 use libp2p::core::{InboundUpgrade, OutboundUpgrade, ProtocolName, UpgradeInfo};
+use std::collections::HashMap;
 use std::io;
 use std::marker::PhantomData;
 use std::pin::Pin;
-use std::time::Duration;
+use std::str::FromStr;
+use std::sync::Arc;
 use tokio_io_timeout::TimeoutStream;
-use tokio_util::{
-    codec::Framed,
-    compat::{Compat, FuturesAsyncReadCompatExt},
-};
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt};
 
-/// The maximum bytes that can be sent across the RPC.
-const MAX_RPC_SIZE: usize = 1_048_576; // 1M
-/// The protocol prefix the RPC protocol id.
-const PROTOCOL_PREFIX: &str = "/eth2/beacon_chain/req";
-/// Time allowed for the first byte of a request to arrive before we time out (Time To First Byte).
-const TTFB_TIMEOUT: u64 = 5;
-/// The number of seconds to wait for the first bytes of a request once a protocol has been
-/// established before the stream is terminated.
-const REQUEST_TIMEOUT: u64 = 15;
+/// The default prefix of the RPC protocol ids.
+pub const DEFAULT_PROTOCOL_PREFIX: &str = "/eth2/beacon_chain/req";
 
 /// Protocol names to be used.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Protocol {
     /// The Status protocol name.
     Status,
@@ -41,17 +36,49 @@ pub enum Protocol {
     Ping,
     /// The `MetaData` protocol name.
     MetaData,
+    /// The `Topics` protocol name.
+    Topics,
+    /// The `Rendezvous` protocol name.
+    Rendezvous,
+    /// A protocol registered by the application.
+    Custom(CustomProtocol),
 }
 
 /// RPC Versions
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Version {
-    /// Version 1 of RPC
-    V1,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Version(u8);
+
+impl Version {
+    /// Version 1 of RPC, whose payloads are sent as given.
+    pub const V1: Version = Version(1);
+
+    /// A version by its number, which must not be zero.
+    pub fn new(number: u8) -> Option<Self> {
+        if number == 0 {
+            None
+        } else {
+            Some(Version(number))
+        }
+    }
+
+    /// The number of the version in protocol ids.
+    pub fn number(self) -> u8 {
+        self.0
+    }
 }
 
+/// All the RPC protocols.
+const PROTOCOLS: [Protocol; 6] = [
+    Protocol::Status,
+    Protocol::Goodbye,
+    Protocol::Ping,
+    Protocol::MetaData,
+    Protocol::Topics,
+    Protocol::Rendezvous,
+];
+
 /// RPC Encondings supported.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Encoding {
     Snappy,
 }
@@ -63,11 +90,201 @@ impl std::fmt::Display for Protocol {
             Protocol::Goodbye => "goodbye",
             Protocol::Ping => "ping",
             Protocol::MetaData => "metadata",
+            Protocol::Topics => "topics",
+            Protocol::Rendezvous => "rendezvous",
+            Protocol::Custom(custom) => custom.name(),
         };
         f.write_str(repr)
     }
 }
 
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        PROTOCOLS
+            .iter()
+            .find(|protocol| protocol.to_string() == s)
+            .copied()
+            .ok_or_else(|| format!("Unknown RPC protocol: {}", s))
+    }
+}
+
+impl Protocol {
+    /// The encoding of the protocol's messages.
+    pub fn encoding(self) -> Encoding {
+        match self {
+            Protocol::Custom(custom) => custom.encoding,
+            _ => Encoding::Snappy,
+        }
+    }
+
+    /// The maximum size of a request, `default` unless the protocol sets its own.
+    pub fn max_request_size(self, default: usize) -> usize {
+        match self {
+            Protocol::Custom(custom) => custom.max_request_size,
+            _ => default,
+        }
+    }
+
+    /// The maximum size of a response chunk, `default` unless the protocol sets its own.
+    pub fn max_response_size(self, default: usize) -> usize {
+        match self {
+            Protocol::Custom(custom) => custom.max_response_size,
+            _ => default,
+        }
+    }
+
+    /// Returns true if a request may be answered with several chunks, ended by closing the
+    /// stream.
+    pub fn is_chunked(self) -> bool {
+        match self {
+            Protocol::Custom(custom) => custom.max_chunks > 1,
+            _ => false,
+        }
+    }
+}
+
+/// The most bytes in the name of a protocol registered by the application.
+pub const MAX_PROTOCOL_NAME_LENGTH: usize = 32;
+
+/// A request/response protocol defined by the application. Its payloads are opaque to the
+/// network and flow to and from the application like those of the built in protocols, under the
+/// protocol id `<prefix>/<name>/<version>/<encoding>`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CustomProtocol {
+    /// The name, held inline so that `Protocol` stays `Copy`. See `name`.
+    name: [u8; MAX_PROTOCOL_NAME_LENGTH],
+    name_length: u8,
+    /// The maximum size of a request, in bytes.
+    pub max_request_size: usize,
+    /// The maximum size of a response chunk, in bytes.
+    pub max_response_size: usize,
+    /// The most chunks a request is answered with, 1 for a single response.
+    pub max_chunks: usize,
+    /// The encoding messages are framed with.
+    pub encoding: Encoding,
+}
+
+impl CustomProtocol {
+    /// The name the protocol was registered with.
+    pub fn name(&self) -> &str {
+        // registered names are checked to be valid UTF-8
+        std::str::from_utf8(&self.name[..self.name_length as usize]).unwrap_or_default()
+    }
+}
+
+impl std::fmt::Debug for CustomProtocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CustomProtocol")
+            .field("name", &self.name())
+            .field("max_request_size", &self.max_request_size)
+            .field("max_response_size", &self.max_response_size)
+            .field("max_chunks", &self.max_chunks)
+            .field("encoding", &self.encoding)
+            .finish()
+    }
+}
+
+/// The protocols registered by the application, served alongside the built in ones.
+#[derive(Debug, Clone, Default)]
+pub struct CustomProtocols {
+    protocols: Vec<Protocol>,
+}
+
+impl CustomProtocols {
+    /// Registers the protocol `name`, whose requests and responses may be at most
+    /// `max_request_size` and `max_response_size` bytes. The name must not be taken by another
+    /// protocol.
+    pub fn register(
+        &mut self,
+        name: &str,
+        max_request_size: usize,
+        max_response_size: usize,
+        encoding: Encoding,
+    ) -> Result<Protocol, String> {
+        self.register_chunked(name, max_request_size, max_response_size, 1, encoding)
+    }
+
+    /// Registers the protocol `name` like `register`, with requests answered by up to
+    /// `max_chunks` response chunks of at most `max_response_size` bytes each.
+    pub fn register_chunked(
+        &mut self,
+        name: &str,
+        max_request_size: usize,
+        max_response_size: usize,
+        max_chunks: usize,
+        encoding: Encoding,
+    ) -> Result<Protocol, String> {
+        if max_chunks == 0 {
+            return Err(format!("RPC protocol {} must allow a response", name));
+        }
+        if name.is_empty()
+            || name.len() > MAX_PROTOCOL_NAME_LENGTH
+            || name.contains(|c: char| c == '/' || c.is_whitespace())
+        {
+            return Err(format!("Invalid RPC protocol name: {}", name));
+        }
+        if name.parse::<Protocol>().is_ok() || self.get(name).is_some() {
+            return Err(format!("RPC protocol {} is already registered", name));
+        }
+        let mut name_bytes = [0; MAX_PROTOCOL_NAME_LENGTH];
+        name_bytes[..name.len()].copy_from_slice(name.as_bytes());
+        let protocol = Protocol::Custom(CustomProtocol {
+            name: name_bytes,
+            name_length: name.len() as u8,
+            max_request_size,
+            max_response_size,
+            max_chunks,
+            encoding,
+        });
+        self.protocols.push(protocol);
+        Ok(protocol)
+    }
+
+    /// Registers a protocol from a `name=max_request_size:max_response_size[:max_chunks]`
+    /// string, for example `blocks_by_range=64:1048576:1024`. Messages are encoded with
+    /// ssz_snappy.
+    pub fn register_from_str(&mut self, s: &str) -> Result<Protocol, String> {
+        let invalid = || format!("Invalid RPC protocol: {}", s);
+        let mut parts = s.splitn(2, '=');
+        let name = parts.next().unwrap_or_default();
+        let limits = parts
+            .next()
+            .ok_or_else(invalid)?
+            .split(':')
+            .map(str::parse)
+            .collect::<Result<Vec<usize>, _>>()
+            .map_err(|_| invalid())?;
+        match limits[..] {
+            [max_request_size, max_response_size] => {
+                self.register(name, max_request_size, max_response_size, Encoding::Snappy)
+            }
+            [max_request_size, max_response_size, max_chunks] => self.register_chunked(
+                name,
+                max_request_size,
+                max_response_size,
+                max_chunks,
+                Encoding::Snappy,
+            ),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// The registered protocol `name`.
+    pub fn get(&self, name: &str) -> Option<Protocol> {
+        self.protocols
+            .iter()
+            .find(|protocol| protocol.to_string() == name)
+            .copied()
+    }
+
+    /// The registered protocols, in the order they were registered.
+    pub fn iter(&self) -> impl Iterator<Item = Protocol> + '_ {
+        self.protocols.iter().copied()
+    }
+}
+
 impl std::fmt::Display for Encoding {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let repr = match self {
@@ -79,15 +296,191 @@ impl std::fmt::Display for Encoding {
 
 impl std::fmt::Display for Version {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let repr = match self {
-            Version::V1 => "1",
-        };
-        f.write_str(repr)
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse()
+            .ok()
+            .and_then(Version::new)
+            .ok_or_else(|| format!("Unknown RPC version: {}", s))
+    }
+}
+
+/// Converts payloads between `Version::V1` and a later version of the RPC.
+pub trait VersionCodec: Send + Sync {
+    /// Encodes the payload of a `protocol` request or response sent in this version.
+    fn encode(&self, protocol: Protocol, payload: Vec<u8>) -> Result<Vec<u8>, String>;
+
+    /// Reverses `encode` on the payload of a `protocol` request or response received in this
+    /// version.
+    fn decode(&self, protocol: Protocol, payload: Vec<u8>) -> Result<Vec<u8>, String>;
+}
+
+/// The codecs registered for each RPC version after `Version::V1`.
+#[derive(Clone, Default)]
+pub struct VersionCodecs {
+    codecs: HashMap<Version, Arc<dyn VersionCodec>>,
+}
+
+impl VersionCodecs {
+    /// The codec of a version, if it has one.
+    pub fn get(&self, version: Version) -> Option<Arc<dyn VersionCodec>> {
+        self.codecs.get(&version).cloned()
+    }
+}
+
+impl std::fmt::Debug for VersionCodecs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.codecs.keys()).finish()
+    }
+}
+
+/// The versions of each RPC protocol the node supports, most preferred first. Requests offer the
+/// versions in this order and the peer picks the first one it supports. By default only
+/// `Version::V1` is supported.
+///
+/// Every later version needs a codec registered with `register`, which converts the payloads sent
+/// and received in that version. The application is also given the negotiated version along with
+/// each request and response.
+#[derive(Debug, Clone)]
+pub struct ProtocolVersions {
+    versions: HashMap<Protocol, Vec<Version>>,
+    codecs: VersionCodecs,
+}
+
+impl Default for ProtocolVersions {
+    fn default() -> Self {
+        ProtocolVersions {
+            versions: PROTOCOLS
+                .iter()
+                .map(|protocol| (*protocol, vec![Version::V1]))
+                .collect(),
+            codecs: VersionCodecs::default(),
+        }
+    }
+}
+
+impl ProtocolVersions {
+    /// Registers the codec of a version after `Version::V1`, replacing any registered before.
+    pub fn register(
+        &mut self,
+        version: Version,
+        codec: Arc<dyn VersionCodec>,
+    ) -> Result<(), String> {
+        if version == Version::V1 {
+            return Err("RPC version 1 sends payloads as given and takes no codec".into());
+        }
+        self.codecs.codecs.insert(version, codec);
+        Ok(())
+    }
+
+    /// The codecs registered for each version.
+    pub fn codecs(&self) -> &VersionCodecs {
+        &self.codecs
+    }
+
+    /// Checks that every version supported after `Version::V1` has a codec registered.
+    pub fn check(&self) -> Result<(), String> {
+        for (protocol, versions) in &self.versions {
+            for version in versions {
+                if *version != Version::V1 && self.codecs.get(*version).is_none() {
+                    return Err(format!(
+                        "No codec registered for version {} of RPC protocol {}",
+                        version, protocol
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the versions of a protocol, most preferred first. At least one version is required.
+    pub fn set(&mut self, protocol: Protocol, versions: Vec<Version>) -> Result<(), String> {
+        if versions.is_empty() {
+            return Err(format!("No versions given for RPC protocol {}", protocol));
+        }
+        self.versions.insert(protocol, versions);
+        Ok(())
+    }
+
+    /// Sets the versions of a protocol from a `protocol=version,version` string, for example
+    /// `status=2,1`.
+    pub fn set_from_str(&mut self, s: &str) -> Result<(), String> {
+        let mut parts = s.splitn(2, '=');
+        let protocol = parts.next().unwrap_or_default().parse()?;
+        let versions = parts
+            .next()
+            .ok_or_else(|| format!("Invalid RPC versions: {}", s))?
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<Vec<Version>, _>>()?;
+        self.set(protocol, versions)
+    }
+
+    /// Keeps only the most preferred version of a protocol, so that the versions advertised do not
+    /// tell which release of the client is running.
+    pub fn keep_preferred(&mut self, protocol: Protocol) {
+        if let Some(versions) = self.versions.get_mut(&protocol) {
+            versions.truncate(1);
+        }
+    }
+
+    /// Stops supporting a protocol. It is neither advertised to peers nor requested from them.
+    pub fn remove(&mut self, protocol: Protocol) {
+        self.versions.remove(&protocol);
+    }
+
+    /// The versions of a protocol, most preferred first. Custom protocols are served in
+    /// `Version::V1` unless set otherwise.
+    pub fn get(&self, protocol: Protocol) -> &[Version] {
+        match self.versions.get(&protocol) {
+            Some(versions) => versions,
+            None if matches!(protocol, Protocol::Custom(_)) => &[Version::V1],
+            None => &[],
+        }
     }
+
+    /// The protocol ids of a protocol under `prefix`, most preferred first.
+    fn protocol_ids<'a>(
+        &'a self,
+        prefix: &'a str,
+        protocol: Protocol,
+    ) -> impl Iterator<Item = ProtocolId> + 'a {
+        self.get(protocol)
+            .iter()
+            .map(move |version| ProtocolId::new(prefix, protocol, *version, protocol.encoding()))
+    }
+}
+
+/// Checks an RPC protocol prefix, which must start with a `/` and not end with one, as in
+/// `/eth2/beacon_chain/req`.
+pub fn validate_protocol_prefix(prefix: &str) -> Result<(), String> {
+    if !prefix.starts_with('/') || prefix.ends_with('/') || prefix.contains(char::is_whitespace) {
+        return Err(format!("Invalid RPC protocol prefix: {}", prefix));
+    }
+    Ok(())
 }
 
 #[derive(Debug, Clone)]
-pub struct RPCProtocol;
+pub struct RPCProtocol {
+    /// The versions accepted for each protocol.
+    pub versions: ProtocolVersions,
+    /// The prefix of the protocol ids.
+    pub prefix: String,
+    /// The protocols registered by the application.
+    pub custom: CustomProtocols,
+    /// The buffers requests are decoded into.
+    pub pool: Arc<BufferPool>,
+    /// The threads requests are decoded and responses encoded on.
+    pub workers: Arc<CodecWorkers>,
+    /// The maximum size of requests and the time they have to arrive.
+    pub limits: Arc<Limits>,
+}
 
 impl UpgradeInfo for RPCProtocol {
     type Info = ProtocolId;
@@ -95,17 +488,17 @@ impl UpgradeInfo for RPCProtocol {
 
     /// The list of supported RPC protocols.
     fn protocol_info(&self) -> Self::InfoIter {
-        vec![
-            ProtocolId::new(Protocol::Status, Version::V1, Encoding::Snappy),
-            ProtocolId::new(Protocol::Goodbye, Version::V1, Encoding::Snappy),
-            ProtocolId::new(Protocol::Ping, Version::V1, Encoding::Snappy),
-            ProtocolId::new(Protocol::MetaData, Version::V1, Encoding::Snappy),
-        ]
+        PROTOCOLS
+            .iter()
+            .copied()
+            .chain(self.custom.iter())
+            .flat_map(|protocol| self.versions.protocol_ids(&self.prefix, protocol))
+            .collect()
     }
 }
 
 /// Tracks the types in a protocol id.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct ProtocolId {
     /// The RPC message type/name.
     pub message_name: Protocol,
@@ -122,11 +515,8 @@ pub struct ProtocolId {
 
 /// An RPC protocol ID.
 impl ProtocolId {
-    pub fn new(message_name: Protocol, version: Version, encoding: Encoding) -> Self {
-        let protocol_id = format!(
-            "{}/{}/{}/{}",
-            PROTOCOL_PREFIX, message_name, version, encoding
-        );
+    pub fn new(prefix: &str, message_name: Protocol, version: Version, encoding: Encoding) -> Self {
+        let protocol_id = format!("{}/{}/{}/{}", prefix, message_name, version, encoding);
 
         ProtocolId {
             message_name,
@@ -135,6 +525,17 @@ impl ProtocolId {
             protocol_id,
         }
     }
+
+    /// The full protocol id, as negotiated with peers.
+    pub fn as_str(&self) -> &str {
+        &self.protocol_id
+    }
+}
+
+impl std::fmt::Display for ProtocolId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.protocol_id)
+    }
 }
 
 impl ProtocolName for ProtocolId {
@@ -148,11 +549,11 @@ impl ProtocolName for ProtocolId {
 // The inbound protocol reads the request, decodes it and returns the stream to the protocol
 // handler to respond to once ready.
 
-pub type InboundOutput<TSocket> = (RPCRequest, InboundFramed<TSocket>);
-pub type InboundFramed<TSocket> = Framed<TimeoutStream<Compat<TSocket>>, InboundCodec>;
+pub type InboundOutput<TSocket> = (RPCRequest, ProtocolId, InboundFramed<TSocket>);
+pub type InboundFramed<TSocket> = WorkerFramed<TimeoutStream<Compat<TSocket>>, InboundCodec>;
 type FnAndThen<TSocket> = fn(
     (Option<Result<RPCRequest, RPCError>>, InboundFramed<TSocket>),
-) -> Ready<Result<InboundOutput<TSocket>, RPCError>>;
+) -> Ready<Result<(RPCRequest, InboundFramed<TSocket>), RPCError>>;
 type FnMapErr = fn(tokio::time::Elapsed) -> RPCError;
 
 impl<TSocket> InboundUpgrade<TSocket> for RPCProtocol
@@ -165,26 +566,31 @@ where
 
     fn upgrade_inbound(self, socket: TSocket, protocol: ProtocolId) -> Self::Future {
         let protocol_name = protocol.message_name;
+        let protocol_id = protocol.clone();
+        let request_timeout = self.limits.rpc_request_timeout;
         // convert the socket to tokio compatible socket
         let socket = socket.compat();
+        let version_codec = self.versions.codecs().get(protocol.version);
         let codec = match protocol.encoding {
             Encoding::Snappy => {
-                let ssz_snappy_codec =
-                    BaseInboundCodec::new(SnappyInboundCodec::new(protocol, MAX_RPC_SIZE));
+                let ssz_snappy_codec = BaseInboundCodec::new(
+                    SnappyInboundCodec::new(protocol, self.limits.max_rpc_size, self.pool)
+                        .with_version_codec(version_codec),
+                );
                 InboundCodec::Snappy(ssz_snappy_codec)
             }
         };
         let mut timed_socket = TimeoutStream::new(socket);
-        timed_socket.set_read_timeout(Some(Duration::from_secs(TTFB_TIMEOUT)));
+        timed_socket.set_read_timeout(Some(self.limits.rpc_ttfb_timeout));
 
-        let socket = Framed::new(timed_socket, codec);
+        let socket = WorkerFramed::new(timed_socket, codec, self.workers);
 
         // MetaData requests should be empty, return the stream
-        Box::pin(match protocol_name {
+        let request = match protocol_name {
             Protocol::MetaData => future::Either::Left(future::ok((RPCRequest::MetaData, socket))),
 
             _ => future::Either::Right(
-                tokio::time::timeout(Duration::from_secs(REQUEST_TIMEOUT), socket.into_future())
+                tokio::time::timeout(request_timeout, socket.into_future())
                     .map_err(RPCError::from as FnMapErr)
                     .and_then({
                         |(req, stream)| match req {
@@ -193,7 +599,8 @@ where
                         }
                     } as FnAndThen<TSocket>),
             ),
-        })
+        };
+        Box::pin(request.map_ok(move |(request, stream)| (request, protocol_id, stream)))
     }
 }
 
@@ -208,46 +615,12 @@ pub enum RPCRequest {
     Goodbye(Vec<u8>),
     Ping(Vec<u8>),
     MetaData,
+    Topics(Vec<u8>),
+    Rendezvous(Vec<u8>),
+    Custom(CustomProtocol, Vec<u8>),
 }
 
-impl UpgradeInfo for RPCRequest {
-    type Info = ProtocolId;
-    type InfoIter = Vec<Self::Info>;
-
-    // add further protocols as we support more encodings/versions
-    fn protocol_info(&self) -> Self::InfoIter {
-        self.supported_protocols()
-    }
-}
-
-/// Implements the encoding per supported protocol for `RPCRequest`.
 impl RPCRequest {
-    pub fn supported_protocols(&self) -> Vec<ProtocolId> {
-        match self {
-            // add more protocols when versions/encodings are supported
-            RPCRequest::Status(_) => vec![ProtocolId::new(
-                Protocol::Status,
-                Version::V1,
-                Encoding::Snappy,
-            )],
-            RPCRequest::Goodbye(_) => vec![ProtocolId::new(
-                Protocol::Goodbye,
-                Version::V1,
-                Encoding::Snappy,
-            )],
-            RPCRequest::Ping(_) => vec![ProtocolId::new(
-                Protocol::Ping,
-                Version::V1,
-                Encoding::Snappy,
-            )],
-            RPCRequest::MetaData => vec![ProtocolId::new(
-                Protocol::MetaData,
-                Version::V1,
-                Encoding::Snappy,
-            )],
-        }
-    }
-
     /* These functions are used in the handler for stream management */
 
     /// Number of responses expected for this request.
@@ -257,6 +630,9 @@ impl RPCRequest {
             RPCRequest::Goodbye(_) => 0,
             RPCRequest::Ping(_) => 1,
             RPCRequest::MetaData => 1,
+            RPCRequest::Topics(_) => 1,
+            RPCRequest::Rendezvous(_) => 1,
+            RPCRequest::Custom(custom, _) => custom.max_chunks,
         }
     }
 
@@ -267,6 +643,9 @@ impl RPCRequest {
             RPCRequest::Goodbye(_) => Protocol::Goodbye,
             RPCRequest::Ping(_) => Protocol::Ping,
             RPCRequest::MetaData => Protocol::MetaData,
+            RPCRequest::Topics(_) => Protocol::Topics,
+            RPCRequest::Rendezvous(_) => Protocol::Rendezvous,
+            RPCRequest::Custom(custom, _) => Protocol::Custom(*custom),
         }
     }
 }
@@ -275,33 +654,66 @@ impl RPCRequest {
 
 /* Outbound upgrades */
 
-pub type OutboundFramed<TSocket> = Framed<Compat<TSocket>, OutboundCodec>;
+/// A request offered to the peer in each of the versions we support for its protocol.
+#[derive(Debug, Clone)]
+pub struct OutboundRequest {
+    pub request: RPCRequest,
+    pub versions: Vec<Version>,
+    /// The codecs of the versions after `Version::V1`.
+    pub codecs: VersionCodecs,
+    pub prefix: String,
+    /// The buffers responses are decoded into.
+    pub pool: Arc<BufferPool>,
+    /// The threads the request is encoded and responses decoded on.
+    pub workers: Arc<CodecWorkers>,
+    /// The maximum size of responses.
+    pub limits: Arc<Limits>,
+}
 
-impl<TSocket> OutboundUpgrade<TSocket> for RPCRequest
+impl UpgradeInfo for OutboundRequest {
+    type Info = ProtocolId;
+    type InfoIter = Vec<Self::Info>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        let protocol = self.request.protocol();
+        self.versions
+            .iter()
+            .map(|version| ProtocolId::new(&self.prefix, protocol, *version, protocol.encoding()))
+            .collect()
+    }
+}
+
+pub type OutboundFramed<TSocket> = WorkerFramed<Compat<TSocket>, OutboundCodec>;
+
+impl<TSocket> OutboundUpgrade<TSocket> for OutboundRequest
 where
     TSocket: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
-    type Output = OutboundFramed<TSocket>;
+    type Output = (OutboundFramed<TSocket>, ProtocolId);
     type Error = RPCError;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Output, Self::Error>> + Send>>;
 
     fn upgrade_outbound(self, socket: TSocket, protocol: Self::Info) -> Self::Future {
+        let protocol_id = protocol.clone();
         // convert to a tokio compatible socket
         let socket = socket.compat();
+        let version_codec = self.codecs.get(protocol.version);
         let codec = match protocol.encoding {
             Encoding::Snappy => {
-                let ssz_snappy_codec =
-                    BaseOutboundCodec::new(SnappyOutboundCodec::new(protocol, MAX_RPC_SIZE));
+                let ssz_snappy_codec = BaseOutboundCodec::new(
+                    SnappyOutboundCodec::new(protocol, self.limits.max_rpc_size, self.pool)
+                        .with_version_codec(version_codec),
+                );
                 OutboundCodec::Snappy(ssz_snappy_codec)
             }
         };
 
-        let mut socket = Framed::new(socket, codec);
+        let mut socket = WorkerFramed::new(socket, codec, self.workers);
 
-        let future = async {
-            socket.send(self).await?;
+        let future = async move {
+            socket.send(self.request).await?;
             socket.close().await?;
-            Ok(socket)
+            Ok((socket, protocol_id))
         };
         Box::pin(future)
     }
@@ -392,6 +804,174 @@ impl std::fmt::Display for RPCRequest {
             RPCRequest::Goodbye(reason) => write!(f, "Goodbye: {:?}", reason),
             RPCRequest::Ping(ping) => write!(f, "Ping: {:?}", ping),
             RPCRequest::MetaData => write!(f, "MetaData request"),
+            RPCRequest::Topics(topics) => write!(f, "Topics: {:?}", topics),
+            RPCRequest::Rendezvous(message) => write!(f, "Rendezvous: {:?}", message),
+            RPCRequest::Custom(custom, data) => write!(f, "{}: {:?}", custom.name(), data),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends payloads reversed.
+    struct Reversed;
+
+    impl VersionCodec for Reversed {
+        fn encode(&self, _protocol: Protocol, mut payload: Vec<u8>) -> Result<Vec<u8>, String> {
+            payload.reverse();
+            Ok(payload)
         }
+
+        fn decode(&self, protocol: Protocol, payload: Vec<u8>) -> Result<Vec<u8>, String> {
+            self.encode(protocol, payload)
+        }
+    }
+
+    #[test]
+    fn test_protocol_versions() {
+        let v2 = Version::new(2).unwrap();
+        let mut versions = ProtocolVersions::default();
+        assert_eq!(versions.get(Protocol::Status), &[Version::V1]);
+        assert!(versions.check().is_ok());
+
+        versions.set_from_str("status=2,1").unwrap();
+        assert_eq!(versions.get(Protocol::Status), &[v2, Version::V1]);
+        assert_eq!(versions.get(Protocol::Ping), &[Version::V1]);
+
+        // later versions need a codec
+        assert!(versions.check().is_err());
+        assert!(versions.register(Version::V1, Arc::new(Reversed)).is_err());
+        versions.register(v2, Arc::new(Reversed)).unwrap();
+        assert!(versions.check().is_ok());
+        assert!(versions.codecs().get(v2).is_some());
+        assert!(versions.codecs().get(Version::V1).is_none());
+        let protocol_ids: Vec<String> = RPCProtocol {
+            versions,
+            prefix: DEFAULT_PROTOCOL_PREFIX.into(),
+            custom: CustomProtocols::default(),
+            pool: Arc::default(),
+            workers: Arc::default(),
+            limits: Arc::default(),
+        }
+        .protocol_info()
+        .iter()
+        .map(|id| String::from_utf8_lossy(id.protocol_name()).into_owned())
+        .collect();
+        assert_eq!(
+            protocol_ids[..2],
+            [
+                "/eth2/beacon_chain/req/status/2/ssz_snappy",
+                "/eth2/beacon_chain/req/status/1/ssz_snappy",
+            ]
+        );
+        let protocol_id = ProtocolId::new("/other/req", Protocol::Ping, v2, Encoding::Snappy);
+        assert_eq!(protocol_id.as_str(), "/other/req/ping/2/ssz_snappy");
+        assert_eq!(protocol_id.to_string(), protocol_id.as_str());
+
+        let mut versions = ProtocolVersions::default();
+        assert!(versions.set_from_str("status=").is_err());
+        assert!(versions.set_from_str("status").is_err());
+        assert!(versions.set_from_str("unknown=1").is_err());
+        assert!(versions.set_from_str("ping=0").is_err());
+        assert!(versions.set_from_str("ping=two").is_err());
+
+        versions.set_from_str("status=2,1").unwrap();
+        versions.keep_preferred(Protocol::Status);
+        assert_eq!(versions.get(Protocol::Status), &[v2]);
+
+        versions.remove(Protocol::Topics);
+        assert!(versions.get(Protocol::Topics).is_empty());
+        assert!(RPCProtocol {
+            versions,
+            prefix: DEFAULT_PROTOCOL_PREFIX.into(),
+            custom: CustomProtocols::default(),
+            pool: Arc::default(),
+            workers: Arc::default(),
+            limits: Arc::default(),
+        }
+        .protocol_info()
+        .iter()
+        .all(|id| id.message_name != Protocol::Topics));
+    }
+
+    #[test]
+    fn test_custom_protocols() {
+        let mut custom = CustomProtocols::default();
+        let blocks = custom
+            .register("blocks_by_root", 1024, 1 << 20, Encoding::Snappy)
+            .unwrap();
+        assert_eq!(custom.get("blocks_by_root"), Some(blocks));
+        assert_eq!(blocks.max_request_size(10), 1024);
+        assert_eq!(blocks.max_response_size(10), 1 << 20);
+        assert_eq!(Protocol::Status.max_request_size(10), 10);
+        assert!(custom
+            .register("blocks_by_root", 1, 1, Encoding::Snappy)
+            .is_err());
+        assert!(custom.register("status", 1, 1, Encoding::Snappy).is_err());
+        assert!(custom.register("a/b", 1, 1, Encoding::Snappy).is_err());
+        assert!(custom.register("", 1, 1, Encoding::Snappy).is_err());
+        let long = "a".repeat(MAX_PROTOCOL_NAME_LENGTH + 1);
+        assert!(custom.register(&long, 1, 1, Encoding::Snappy).is_err());
+        let longest = CustomProtocols::default()
+            .register(&long[1..], 1, 1, Encoding::Snappy)
+            .unwrap();
+        assert_eq!(longest.to_string(), long[1..]);
+        let light = custom.register_from_str("light_client=32:512").unwrap();
+        assert_eq!(light.max_response_size(0), 512);
+        assert!(!light.is_chunked());
+        assert!(custom.register_from_str("light=32").is_err());
+        assert!(custom.register_from_str("light=a:1").is_err());
+        assert!(custom.register_from_str("light=1:1:0").is_err());
+        assert!(custom.register_from_str("light=1:1:1:1").is_err());
+
+        let protocol_ids: Vec<String> = RPCProtocol {
+            versions: ProtocolVersions::default(),
+            prefix: DEFAULT_PROTOCOL_PREFIX.into(),
+            custom,
+            pool: Arc::default(),
+            workers: Arc::default(),
+            limits: Arc::default(),
+        }
+        .protocol_info()
+        .iter()
+        .map(ProtocolId::to_string)
+        .collect();
+        assert_eq!(
+            protocol_ids[protocol_ids.len() - 2..],
+            [
+                "/eth2/beacon_chain/req/blocks_by_root/1/ssz_snappy",
+                "/eth2/beacon_chain/req/light_client/1/ssz_snappy",
+            ]
+        );
+        assert_eq!(
+            RPCRequest::Custom(custom_spec(blocks), vec![]).protocol(),
+            blocks
+        );
+
+        let mut custom = CustomProtocols::default();
+        let range = custom
+            .register_from_str("blocks_by_range=64:1048576:1024")
+            .unwrap();
+        assert!(range.is_chunked());
+        let request = RPCRequest::Custom(custom_spec(range), vec![]);
+        assert_eq!(request.expected_responses(), 1024);
+    }
+
+    fn custom_spec(protocol: Protocol) -> CustomProtocol {
+        match protocol {
+            Protocol::Custom(custom) => custom,
+            _ => panic!("not a custom protocol"),
+        }
+    }
+
+    #[test]
+    fn test_validate_protocol_prefix() {
+        assert!(validate_protocol_prefix(DEFAULT_PROTOCOL_PREFIX).is_ok());
+        assert!(validate_protocol_prefix("/other").is_ok());
+        assert!(validate_protocol_prefix("other/req").is_err());
+        assert!(validate_protocol_prefix("/other/req/").is_err());
+        assert!(validate_protocol_prefix("/other req").is_err());
     }
 }
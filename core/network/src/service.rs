@@ -1,10 +1,15 @@
 use crate::behaviour::{Behaviour, BehaviourEvent, PeerRequestId, Request, Response};
 use crate::discovery::enr;
+use crate::keys::{
+    configured_keypair, generate_keypair, key_type, load_keypair_file, save_keypair_file,
+    NETWORK_KEY_FILENAME,
+};
 use crate::multiaddr::Protocol;
 use crate::rpc::{RPCResponseErrorCode, RequestId};
+use crate::tls::load_ws_tls;
 use crate::types::{error, GossipKind};
 use crate::EnrExt;
-use crate::{Enr, EnrForkId, NetworkConfig, NetworkGlobals, TaskExecutor};
+use crate::{Enr, EnrForkId, NetworkConfig, NetworkGlobals, NetworkGlobalsParams, TaskExecutor};
 use futures::prelude::*;
 use libp2p::core::{
     identity::Keypair,
@@ -20,20 +25,17 @@ use libp2p::{
     PeerId, Swarm, Transport,
 };
 use slog::{crit, debug, info, o, trace, warn};
-use std::fs::File;
-use std::io::prelude::*;
+use std::collections::{HashMap, VecDeque};
 use std::io::{Error, ErrorKind};
 use std::pin::Pin;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::time::DelayQueue;
 
-pub const NETWORK_KEY_FILENAME: &str = "key";
 /// The time in milliseconds to wait before banning a peer. This allows for any Goodbye messages to be
 /// flushed and protocols to be negotiated.
 const BAN_PEER_WAIT_TIMEOUT: u64 = 200;
-/// The maximum simultaneous libp2p connections per peer.
-const MAX_CONNECTIONS_PER_PEER: usize = 1;
 
 /// The types of events than can be obtained from polling the libp2p service.
 ///
@@ -58,6 +60,15 @@ pub enum Libp2pEvent {
         /// Whether the peer was a dialer or a listener.
         endpoint: ConnectedPoint,
     },
+    /// A dial requested with `Service::dial` finished.
+    DialResult {
+        /// The token the dial was requested with.
+        token: u64,
+        /// The dialed address.
+        address: Multiaddr,
+        /// The peer connected to, or why the dial failed.
+        result: Result<PeerId, String>,
+    },
 }
 
 /// The configuration and state of the libp2p components for the beacon node.
@@ -78,6 +89,12 @@ pub struct Service {
     /// A list of timeouts after which peers become unbanned.
     peer_ban_timeout: DelayQueue<PeerId>,
 
+    /// The tokens of the dials requested with `dial`, by dialed address.
+    pending_dials: HashMap<Multiaddr, Vec<u64>>,
+
+    /// Results of requested dials waiting to be returned by `next_event`.
+    dial_results: VecDeque<Libp2pEvent>,
+
     /// The libp2p logger handle.
     pub log: slog::Logger,
 }
@@ -93,23 +110,28 @@ impl Service {
     ) -> error::Result<(Arc<NetworkGlobals>, Self)> {
         let log = log.new(o!("service"=> "libp2p"));
         trace!(log, "Libp2p Service starting");
+        let limits = config.limits();
+        limits.validate()?;
+        debug!(log, "Network limits"; "limits" => limits.to_string());
         // initialise the node's ID
-        let local_keypair = load_private_key(config, &log);
+        let local_keypair = load_private_key(config, &log)?;
 
         // Create an ENR or load from disk if appropriate
         let enr = enr::build_or_load_enr(local_keypair.clone(), config, enr_fork_id.clone(), &log)?;
 
         let local_peer_id = enr.peer_id();
         // set up a collection of variables accessible outside of the network crate
-        let network_globals = Arc::new(NetworkGlobals::new(
-            enr.clone(),
-            enr_fork_id.clone(),
+        let params = NetworkGlobalsParams {
+            enr: enr.clone(),
+            fork_id: enr_fork_id.clone(),
+            secondary_fork_id: config.secondary_fork_id.clone(),
             meta_data,
             ping_data,
-            config.libp2p_port,
-            config.discovery_port,
-            &log,
-        ));
+            tcp_port: config.libp2p_port,
+            udp_port: config.discovery_port,
+            buffer_pool: config.buffer_pool.clone(),
+        };
+        let network_globals = Arc::new(NetworkGlobals::new(params, &log));
 
         info!(log, "Libp2p Service"; "peer_id" => format!("{:?}", enr.peer_id()));
         let discovery_string = if config.disable_discovery {
@@ -119,12 +141,25 @@ impl Service {
         };
         debug!(log, "Attempting to open listening ports"; "address" => format!("{}", config.listen_address), "tcp_port" => config.libp2p_port, "udp_port" => discovery_string);
 
+        let ws_tls = match (&config.ws_tls_cert, &config.ws_tls_key) {
+            (Some(cert), Some(key)) => Some(load_ws_tls(cert, key)?),
+            (None, None) => None,
+            _ => return Err("Secure WebSockets need both a TLS certificate and key".into()),
+        };
+        let ws_secure = ws_tls.is_some();
+
         let mut swarm = {
             // Set up the transport - tcp/ws with noise and yamux/mplex
-            let transport = build_transport(local_keypair.clone())
+            let transport = build_transport(local_keypair.clone(), ws_tls)
                 .map_err(|e| format!("Failed to build transport: {:?}", e))?;
             // Mothra network behaviour
-            let behaviour = Behaviour::new(&local_keypair, config, network_globals.clone(), &log)?;
+            let behaviour = Behaviour::new(
+                &local_keypair,
+                config,
+                network_globals.clone(),
+                executor.clone(),
+                &log,
+            )?;
 
             // use the executor for libp2p
             struct Executor(TaskExecutor);
@@ -134,7 +169,7 @@ impl Service {
                 }
             }
             SwarmBuilder::new(transport, behaviour, local_peer_id.clone())
-                .peer_connection_limit(MAX_CONNECTIONS_PER_PEER)
+                .peer_connection_limit(config.limits.max_connections_per_peer)
                 .executor(Box::new(Executor(executor)))
                 .build()
         };
@@ -145,26 +180,61 @@ impl Service {
             m.push(Protocol::Tcp(config.libp2p_port));
             m
         };
+        #[cfg(feature = "simulation")]
+        let listen_multiaddr = match config.memory_port {
+            Some(port) => Multiaddr::empty().with(Protocol::Memory(port)),
+            None => listen_multiaddr,
+        };
 
-        match Swarm::listen_on(&mut swarm, listen_multiaddr.clone()) {
-            Ok(_) => {
-                let mut log_address = listen_multiaddr;
-                log_address.push(Protocol::P2p(local_peer_id.clone().into()));
-                info!(log, "Listening established"; "address" => format!("{}", log_address));
-            }
-            Err(err) => {
-                crit!(
-                    log,
-                    "Unable to listen on libp2p address";
-                    "error" => format!("{:?}", err),
-                    "listen_multiaddr" => format!("{}", listen_multiaddr),
-                );
-                return Err("Libp2p was unable to listen on the given listen address.".into());
+        // browser peers connect over WebSockets, on a port of their own
+        let mut listen_multiaddrs = vec![listen_multiaddr];
+        if let Some(ws_port) = config.ws_port {
+            let mut m = Multiaddr::from(config.listen_address);
+            m.push(Protocol::Tcp(ws_port));
+            m.push(if ws_secure {
+                Protocol::Wss("/".into())
+            } else {
+                Protocol::Ws("/".into())
+            });
+            listen_multiaddrs.push(m);
+        }
+
+        if config.outbound_only {
+            info!(log, "Outbound-only mode, not listening for connections");
+        } else {
+            for listen_multiaddr in listen_multiaddrs {
+                match Swarm::listen_on(&mut swarm, listen_multiaddr.clone()) {
+                    Ok(_) => {
+                        let mut log_address = listen_multiaddr;
+                        log_address.push(Protocol::P2p(local_peer_id.clone().into()));
+                        info!(log, "Listening established"; "address" => format!("{}", log_address));
+                    }
+                    Err(err) => {
+                        crit!(
+                            log,
+                            "Unable to listen on libp2p address";
+                            "error" => format!("{:?}", err),
+                            "listen_multiaddr" => format!("{}", listen_multiaddr),
+                        );
+                        return Err(
+                            "Libp2p was unable to listen on the given listen address.".into()
+                        );
+                    }
+                }
             }
-        };
+        }
 
         // helper closure for dialing peers
         let mut dial_addr = |multiaddr: &Multiaddr| {
+            if let Some(Protocol::P2p(hash)) = multiaddr.iter().last() {
+                if PeerId::from_multihash(hash).ok().as_ref() == Some(&local_peer_id) {
+                    warn!(log, "Refusing to dial our own address"; "address" => format!("{}", multiaddr));
+                    network_globals
+                        .self_dials_rejected
+                        .fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+            }
             match Swarm::dial_addr(&mut swarm, multiaddr.clone()) {
                 Ok(()) => debug!(log, "Dialing libp2p peer"; "address" => format!("{}", multiaddr)),
                 Err(err) => debug!(
@@ -184,6 +254,10 @@ impl Service {
         boot_nodes.dedup();
 
         for bootnode_enr in boot_nodes {
+            if bootnode_enr.peer_id() == local_peer_id {
+                // discovery has already warned about it
+                continue;
+            }
             for multiaddr in &bootnode_enr.multiaddr() {
                 // ignore udp multiaddr if it exists
                 let components = multiaddr.iter().collect::<Vec<_>>();
@@ -207,6 +281,8 @@ impl Service {
             network_globals: network_globals.clone(),
             peers_to_ban: DelayQueue::new(),
             peer_ban_timeout: DelayQueue::new(),
+            pending_dials: HashMap::new(),
+            dial_results: VecDeque::new(),
             log,
         };
 
@@ -223,6 +299,35 @@ impl Service {
         self.peer_ban_timeout.insert(peer_id, timeout);
     }
 
+    /// Dials an address for the application. The outcome is returned by `next_event` as a
+    /// `Libp2pEvent::DialResult` tagged with `token`, unless the dial could not be started, in
+    /// which case the error is returned at once.
+    pub fn dial(&mut self, address: Multiaddr, token: u64) -> Result<(), String> {
+        if let Some(Protocol::P2p(hash)) = address.iter().last() {
+            if PeerId::from_multihash(hash).ok().as_ref() == Some(&self.local_peer_id) {
+                self.network_globals
+                    .self_dials_rejected
+                    .fetch_add(1, Ordering::Relaxed);
+                return Err("Refusing to dial our own address".into());
+            }
+        }
+        Swarm::dial_addr(&mut self.swarm, address.clone()).map_err(|e| format!("{:?}", e))?;
+        debug!(self.log, "Dialing address"; "address" => address.to_string(), "token" => token);
+        self.pending_dials.entry(address).or_default().push(token);
+        Ok(())
+    }
+
+    /// Queues the results of the requested dials of an address.
+    fn dial_finished(&mut self, address: &Multiaddr, result: Result<PeerId, String>) {
+        for token in self.pending_dials.remove(address).unwrap_or_default() {
+            self.dial_results.push_back(Libp2pEvent::DialResult {
+                token,
+                address: address.clone(),
+                result: result.clone(),
+            });
+        }
+    }
+
     /// Sends a request to a peer, with a given Id.
     pub fn send_request(&mut self, peer_id: PeerId, request_id: RequestId, request: Request) {
         self.swarm.send_request(peer_id, request_id, request);
@@ -246,6 +351,9 @@ impl Service {
 
     pub async fn next_event(&mut self) -> Libp2pEvent {
         loop {
+            if let Some(event) = self.dial_results.pop_front() {
+                return event;
+            }
             tokio::select! {
                 event = self.swarm.next_event() => {
                     match event {
@@ -258,6 +366,9 @@ impl Service {
                             num_established,
                         } => {
                             debug!(self.log, "Connection established"; "peer_id" => peer_id.to_string(), "connections" => num_established.get());
+                            if let ConnectedPoint::Dialer { address } = &endpoint {
+                                self.dial_finished(address, Ok(peer_id.clone()));
+                            }
                             // if this is the first connection inform the network layer a new connection
                             // has been established and update the db
                             if num_established.get() == 1 {
@@ -309,11 +420,11 @@ impl Service {
                         } => {
                             debug!(self.log, "Failed incoming connection"; "our_addr" => local_addr.to_string(), "from" => send_back_addr.to_string(), "error" => error.to_string())
                         }
-                        SwarmEvent::BannedPeer {
-                            peer_id,
-                            endpoint: _,
-                        } => {
-                            debug!(self.log, "Attempted to dial a banned peer"; "peer_id" => peer_id.to_string())
+                        SwarmEvent::BannedPeer { peer_id, endpoint } => {
+                            debug!(self.log, "Attempted to dial a banned peer"; "peer_id" => peer_id.to_string());
+                            if let ConnectedPoint::Dialer { address } = &endpoint {
+                                self.dial_finished(address, Err(format!("Peer {} is banned", peer_id)));
+                            }
                         }
                         SwarmEvent::UnreachableAddr {
                             peer_id,
@@ -322,10 +433,15 @@ impl Service {
                             attempts_remaining,
                         } => {
                             debug!(self.log, "Failed to dial address"; "peer_id" => peer_id.to_string(), "address" => address.to_string(), "error" => error.to_string(), "attempts_remaining" => attempts_remaining);
-                            self.swarm.peer_manager().notify_disconnect(&peer_id);
+                            self.dial_finished(&address, Err(error.to_string()));
+                            self.swarm
+                                .peer_manager()
+                                .address_unreachable(Some(&peer_id), &address);
                         }
                         SwarmEvent::UnknownPeerUnreachableAddr { address, error } => {
                             debug!(self.log, "Peer not known at dialed address"; "address" => address.to_string(), "error" => error.to_string());
+                            self.dial_finished(&address, Err(error.to_string()));
+                            self.swarm.peer_manager().address_unreachable(None, &address);
                         }
                         SwarmEvent::ExpiredListenAddr(multiaddr) => {
                             debug!(self.log, "Listen address expired"; "multiaddr" => multiaddr.to_string())
@@ -351,8 +467,8 @@ impl Service {
                     self.swarm.peer_banned(peer_id);
                 }
                 Some(Ok(peer_to_unban)) = self.peer_ban_timeout.next() => {
-                    debug!(self.log, "Peer has been unbanned"; "peer" => format!("{:?}", peer_to_unban));
                     let unban_peer = peer_to_unban.into_inner();
+                    debug!(self.log, "Peer has been unbanned"; "peer_id" => unban_peer.to_string());
                     self.swarm.peer_unbanned(&unban_peer);
                     Swarm::unban_peer_id(&mut self.swarm, unban_peer);
                 }
@@ -362,18 +478,23 @@ impl Service {
 }
 
 /// The implementation supports TCP/IP, WebSockets over TCP/IP, noise as the encryption layer, and
-/// yamux or mplex as the multiplexing layer.
-
+/// yamux or mplex as the multiplexing layer. With `ws_tls`, WebSockets are also accepted over TLS.
 fn build_transport(
     local_private_key: Keypair,
+    ws_tls: Option<libp2p::websocket::tls::Config>,
 ) -> Result<Boxed<(PeerId, StreamMuxerBox), Error>, Error> {
     let transport = libp2p::tcp::TokioTcpConfig::new().nodelay(true);
     let transport = libp2p::dns::DnsConfig::new(transport)?;
-    #[cfg(feature = "libp2p-websocket")]
     let transport = {
-        let trans_clone = transport.clone();
-        transport.or_transport(libp2p::websocket::WsConfig::new(trans_clone))
+        let mut ws = libp2p::websocket::WsConfig::new(transport.clone());
+        if let Some(tls_config) = ws_tls {
+            ws.set_tls_config(tls_config);
+        }
+        transport.or_transport(ws)
     };
+    // simulated nodes connect to each other in memory
+    #[cfg(feature = "simulation")]
+    let transport = transport.or_transport(libp2p::core::transport::MemoryTransport::default());
     // Authentication
     let transport = transport
         .and_then(move |stream, endpoint| {
@@ -418,76 +539,46 @@ fn build_transport(
     Ok(transport)
 }
 
-// Useful helper functions for debugging. Currently not used in the client.
-#[allow(dead_code)]
-fn keypair_from_hex(hex_bytes: &str) -> error::Result<Keypair> {
-    let hex_bytes = if hex_bytes.starts_with("0x") {
-        hex_bytes[2..].to_string()
-    } else {
-        hex_bytes.to_string()
-    };
-
-    hex::decode(&hex_bytes)
-        .map_err(|e| format!("Failed to parse p2p secret key bytes: {:?}", e).into())
-        .and_then(keypair_from_bytes)
-}
-
-#[allow(dead_code)]
-fn keypair_from_bytes(mut bytes: Vec<u8>) -> error::Result<Keypair> {
-    libp2p::core::identity::secp256k1::SecretKey::from_bytes(&mut bytes)
-        .map(|secret| {
-            let keypair: libp2p::core::identity::secp256k1::Keypair = secret.into();
-            Keypair::Secp256k1(keypair)
-        })
-        .map_err(|e| format!("Unable to parse p2p secret key: {:?}", e).into())
-}
-
-/// Loads a private key from disk. If this fails, a new key is
-/// generated and is then saved to disk.
+/// Loads the node's private key. A key given in the config takes precedence, and is refused if a
+/// different key is stored on disk, otherwise the key is loaded from disk. If this fails, a new key of the configured `key_type` is generated and is then
+/// saved to disk.
 ///
-/// Currently only secp256k1 keys are allowed, as these are the only keys supported by discv5.
-fn load_private_key(config: &NetworkConfig, log: &slog::Logger) -> Keypair {
-    // TODO: Currently using secp256k1 keypairs - currently required for discv5
+/// Both secp256k1 and ed25519 keys are supported by libp2p and discv5.
+fn load_private_key(config: &NetworkConfig, log: &slog::Logger) -> error::Result<Keypair> {
+    if let Some(secret_key_hex) = &config.secret_key_hex {
+        debug!(log, "Using network key from the config"; "key_type" => config.key_type.to_string());
+        return configured_keypair(&config.network_dir, secret_key_hex, config.key_type);
+    }
+
     // check for key from disk
     let network_key_f = config.network_dir.join(NETWORK_KEY_FILENAME);
-    if let Ok(mut network_key_file) = File::open(network_key_f.clone()) {
-        let mut key_bytes: Vec<u8> = Vec::with_capacity(36);
-        match network_key_file.read_to_end(&mut key_bytes) {
-            Err(_) => debug!(log, "Could not read network key file"),
-            Ok(_) => {
-                // only accept secp256k1 keys for now
-                if let Ok(secret_key) =
-                    libp2p::core::identity::secp256k1::SecretKey::from_bytes(&mut key_bytes)
-                {
-                    let kp: libp2p::core::identity::secp256k1::Keypair = secret_key.into();
-                    debug!(log, "Loaded network key from disk.");
-                    return Keypair::Secp256k1(kp);
-                } else {
-                    debug!(log, "Network key file is not a valid secp256k1 key");
+    if network_key_f.exists() {
+        match load_keypair_file(&network_key_f) {
+            Ok(keypair) => {
+                if key_type(&keypair) != Some(config.key_type) {
+                    // never silently replace the identity of an existing node
+                    warn!(log, "Network key on disk does not match the configured key type, using the key on disk";
+                        "configured" => config.key_type.to_string(),
+                        "file" => format!("{:?}", network_key_f));
                 }
+                debug!(log, "Loaded network key from disk.");
+                return Ok(keypair);
             }
+            Err(e) => debug!(log, "Network key file is not a valid key"; "error" => e.to_string()),
         }
     }
 
     // if a key could not be loaded from disk, generate a new one and save it
-    let local_private_key = Keypair::generate_secp256k1();
-    if let Keypair::Secp256k1(key) = local_private_key.clone() {
-        let _ = std::fs::create_dir_all(&config.network_dir);
-        match File::create(network_key_f.clone())
-            .and_then(|mut f| f.write_all(&key.secret().to_bytes()))
-        {
-            Ok(_) => {
-                debug!(log, "New network key generated and written to disk");
-            }
-            Err(e) => {
-                warn!(
-                    log,
-                    "Could not write node key to file: {:?}. error: {}", network_key_f, e
-                );
-            }
+    let local_private_key = generate_keypair(config.key_type);
+    match save_keypair_file(&network_key_f, &local_private_key) {
+        Ok(_) => {
+            debug!(log, "New network key generated and written to disk"; "key_type" => config.key_type.to_string());
+        }
+        Err(e) => {
+            warn!(log, "Could not write node key to file"; "error" => e.to_string());
         }
     }
-    local_private_key
+    Ok(local_private_key)
 }
 
 /// Generate authenticated XX Noise config from identity keys
@@ -0,0 +1,1213 @@
+//! Deterministic multi-node tests on virtual time, with the `simulation` feature.
+//!
+//! A simulation runs several nodes in one process on a current-thread runtime. The nodes connect
+//! over libp2p's in-memory transport rather than TCP, and the peer manager, scoring and gossipsub
+//! run as they do over the network. Time is tokio's paused clock, so the pings and timeouts of an
+//! hour run in as long as the work they trigger takes. The heartbeat of gossipsub is the
+//! exception: it runs on the system's clock, see `clock`.
+//!
+//! Nodes dial the peers they are configured with, and discover the others through discv5, which
+//! runs over UDP on the loopback interface, at `DISCOVERY_BASE_PORT` plus the node's index. A
+//! node's ENR gives its in-memory port as TCP port, and the peer manager of a simulated node dials
+//! a discovered peer at that port of the in-memory transport. A node that is not configured with
+//! peers joins by being given the ENR of another node with `Behaviour::add_enr`.
+//!
+//! Node keys are derived from the node's index, so peer ids are the same on every run. Scheduling
+//! on a current-thread runtime is deterministic too. The random choices of gossipsub, whose random
+//! number generator cannot be seeded, remain; `deterministic_gossip` makes the mesh take in every
+//! peer, so that they no longer change the outcome of a small simulation.
+//!
+//! Each node is a `SimulatedNode` playing a `Persona`. The persona configures the node, answers
+//! the requests it receives and sends the messages it publishes, so a misbehaving persona sends
+//! oversized responses, floods its peers or never responds, and the penalties of the peer manager
+//! and the rate limiters of honest nodes can be tested against it.
+//!
+//! ```ignore
+//! let mut runtime = tokio::runtime::Builder::new().basic_scheduler().enable_all().build()?;
+//! runtime.block_on(async {
+//!     let clock = VirtualClock::start();
+//!     let config = simulation::node_config(0, dir.path().join("0"), &[1, 2]);
+//!     let node = SimulatedNode::start(Persona::Honest, config, vec![], executor, &log)?;
+//!     // start the other nodes, then run them for an hour of virtual time
+//!     let step = Duration::from_millis(100);
+//!     clock.run_nodes(&mut nodes, Duration::from_secs(3600), step).await;
+//! });
+//! ```
+use crate::error;
+use crate::rpc::RequestId;
+use crate::{
+    BehaviourEvent, EnrForkId, GossipTopic, Libp2pEvent, Multiaddr, NetworkConfig, NetworkGlobals,
+    PeerId, Request, Response, Service, TaskExecutor,
+};
+use futures::FutureExt;
+use libp2p::multiaddr::Protocol;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The UDP port of the discovery of the first node, on the loopback interface. The node of index
+/// `i` uses the port `DISCOVERY_BASE_PORT + i`.
+pub const DISCOVERY_BASE_PORT: u16 = 40000;
+
+/// The address at which the node of `index` listens, on the in-memory transport.
+pub fn memory_address(index: u64) -> Multiaddr {
+    // port 0 asks the transport to pick a port, so indices start at 1
+    Multiaddr::empty().with(Protocol::Memory(index + 1))
+}
+
+/// The in-memory address of a simulated node, given the loopback TCP address of its ENR.
+pub fn memory_address_of(address: &Multiaddr) -> Option<Multiaddr> {
+    let mut protocols = address.iter();
+    match (protocols.next(), protocols.next()) {
+        (Some(Protocol::Ip4(ip)), Some(Protocol::Tcp(port))) if ip.is_loopback() && port > 0 => {
+            Some(Multiaddr::empty().with(Protocol::Memory(u64::from(port))))
+        }
+        _ => None,
+    }
+}
+
+/// The configuration of the node of `index` in a simulation, storing its files in `network_dir`
+/// and connecting to the nodes of `peers`.
+pub fn node_config(index: u64, network_dir: PathBuf, peers: &[u64]) -> NetworkConfig {
+    let mut config = NetworkConfig::default();
+    config.network_dir = network_dir;
+    config.memory_port = Some(index + 1);
+    config.libp2p_nodes = peers.iter().map(|peer| memory_address(*peer)).collect();
+    // discovery runs on the loopback interface, and the ENR's TCP port is the in-memory one
+    let loopback = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    config.listen_address = loopback;
+    config.discovery_port = DISCOVERY_BASE_PORT + index as u16;
+    config.libp2p_port = index as u16 + 1;
+    config.enr_address = Some(loopback);
+    config.enr_udp_port = Some(config.discovery_port);
+    config.enr_tcp_port = Some(config.libp2p_port);
+    // a valid secp256k1 and ed25519 secret key, unique to the node
+    let mut secret = [0u8; 32];
+    secret[24..].copy_from_slice(&(index + 1).to_be_bytes());
+    config.secret_key_hex = Some(hex::encode(secret));
+    config
+}
+
+/// Changes the configuration of a node so that the mesh of each topic takes in every subscribed
+/// peer of a simulation of up to `nodes` nodes, and no peer is left to gossip to. The random peer
+/// selections of gossipsub then pick every candidate, whatever their order.
+pub fn deterministic_gossip(config: &mut NetworkConfig, nodes: usize) {
+    let peers = nodes.saturating_sub(1).max(1);
+    config.gs_config.mesh_n_low = 1;
+    config.gs_config.mesh_n = peers;
+    config.gs_config.mesh_n_high = peers;
+    config.gs_config.gossip_lazy = 0;
+    config.max_peers = config.max_peers.max(peers);
+}
+
+/// How a node of a simulation behaves towards its peers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Persona {
+    /// Behaves as any other node, answering requests with the data they carry.
+    Honest,
+    /// Publishes messages and sends responses padded to `size` bytes, however large the limits of
+    /// its peers.
+    Oversized { size: usize },
+    /// Accepts RPC requests and never responds to them, leaving them to time out.
+    Unresponsive,
+    /// Gossips the ids of every message it has seen to as many peers as it can, ten times as often
+    /// as other nodes.
+    IhaveSpammer,
+    /// Advertises an ENR with an address no node listens on and a fork id no node shares.
+    BogusEnr,
+    /// Publishes every message `copies` times, as distinct messages, flooding its peers beyond
+    /// their rate limits.
+    Flooder { copies: usize },
+}
+
+impl Persona {
+    /// Changes the configuration of a node, as made by `node_config`, to play this persona.
+    pub fn configure(&self, config: &mut NetworkConfig) {
+        match self {
+            Persona::Honest | Persona::Unresponsive | Persona::Flooder { .. } => {}
+            Persona::Oversized { size } => {
+                // the persona's own limits must let its messages out
+                config.gs_config.max_transmit_size =
+                    config.gs_config.max_transmit_size.max(2 * size);
+                config.limits.max_rpc_size = config.limits.max_rpc_size.max(*size);
+            }
+            Persona::IhaveSpammer => {
+                config.gs_config.heartbeat_interval /= 10;
+                config.gs_config.history_gossip = config.gs_config.history_length;
+                config.gs_config.gossip_lazy = config.max_peers;
+            }
+            Persona::BogusEnr => {
+                // TEST-NET-1, which is never routed
+                config.enr_address = Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+                config.enr_tcp_port = Some(1);
+                config.enr_udp_port = Some(1);
+            }
+        }
+    }
+
+    /// The fork id the persona's node is started with, in a network on `fork_id`.
+    pub fn enr_fork_id(&self, fork_id: EnrForkId) -> EnrForkId {
+        match self {
+            Persona::BogusEnr if fork_id.is_empty() => vec![0xff; 4],
+            Persona::BogusEnr => fork_id.iter().map(|byte| !byte).collect(),
+            _ => fork_id,
+        }
+    }
+
+    /// The data the persona publishes or responds with in place of `data`.
+    pub fn message(&self, mut data: Vec<u8>) -> Vec<u8> {
+        if let Persona::Oversized { size } = self {
+            data.resize(data.len().max(*size), 0);
+        }
+        data
+    }
+
+    /// The messages the persona publishes in place of `data`.
+    pub fn messages(&self, data: Vec<u8>) -> Vec<Vec<u8>> {
+        match self {
+            Persona::Flooder { copies } => (0..*copies as u64)
+                .map(|copy| {
+                    // each copy differs, so that gossipsub does not drop it as a duplicate
+                    let mut message = data.clone();
+                    message.extend_from_slice(&copy.to_be_bytes());
+                    message
+                })
+                .collect(),
+            _ => vec![self.message(data)],
+        }
+    }
+
+    /// Handles an event of the persona's node as the persona would, returning false if the event
+    /// is left to the test. The node sends its local status, if any, in the Status requests the
+    /// peer manager asks for, that of the peer's fork once the peer's status is known. It answers
+    /// the requests it receives with the data they carry, unless it is unresponsive.
+    pub fn handle(
+        &self,
+        service: &mut Service,
+        network_globals: &NetworkGlobals,
+        event: &Libp2pEvent,
+    ) -> bool {
+        let event = match event {
+            Libp2pEvent::Behaviour(event) => event,
+            _ => return false,
+        };
+        match event {
+            BehaviourEvent::RequestReceived {
+                peer_id,
+                id,
+                request,
+                ..
+            } => {
+                let response = match request {
+                    Request::Status(data) => Response::Status(self.message(data.clone())),
+                    Request::Custom(_, data) => Response::Custom(self.message(data.clone())),
+                    Request::Goodbye(_) => return true,
+                };
+                if *self != Persona::Unresponsive {
+                    service.send_response(peer_id.clone(), *id, response);
+                }
+                true
+            }
+            BehaviourEvent::StatusPeer(peer_id) => {
+                let fork_digest = network_globals
+                    .peers
+                    .read()
+                    .peer_info(peer_id)
+                    .and_then(|info| info.status.as_ref())
+                    .map(|status| status.fork_digest);
+                let status = match fork_digest {
+                    Some(fork_digest) => network_globals.local_status_on(&fork_digest),
+                    None => network_globals.local_status(),
+                };
+                let status = status.map(|status| status.encode());
+                let request = Request::Status(self.message(status.unwrap_or_default()));
+                service.send_request(peer_id.clone(), RequestId::Router, request);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A node of a simulation, playing a persona.
+pub struct SimulatedNode {
+    pub persona: Persona,
+    pub network_globals: Arc<NetworkGlobals>,
+    pub service: Service,
+}
+
+impl SimulatedNode {
+    /// Starts a node playing `persona` with `config`, as made by `node_config`, in a network on
+    /// `fork_id`.
+    pub fn start(
+        persona: Persona,
+        mut config: NetworkConfig,
+        fork_id: EnrForkId,
+        executor: TaskExecutor,
+        log: &slog::Logger,
+    ) -> error::Result<Self> {
+        persona.configure(&mut config);
+        let enr_fork_id = persona.enr_fork_id(fork_id);
+        let (network_globals, service) =
+            Service::new(executor, &config, enr_fork_id, vec![], vec![], log)?;
+        Ok(SimulatedNode {
+            persona,
+            network_globals,
+            service,
+        })
+    }
+
+    pub fn peer_id(&self) -> &PeerId {
+        &self.service.local_peer_id
+    }
+
+    /// Subscribes the node to the topic kind `topic`.
+    pub fn subscribe(&mut self, topic: &str) -> Result<bool, String> {
+        self.service.swarm.subscribe_kind(topic.into())
+    }
+
+    /// Publishes `data` on `topic`, as the persona would.
+    pub fn publish(&mut self, topic: &str, data: Vec<u8>) {
+        for message in self.persona.messages(data) {
+            let topic = GossipTopic::new(topic.into());
+            self.service.swarm.publish(topic, message, None);
+        }
+    }
+
+    /// Handles the events the node has ready, returning those the persona left to the test.
+    pub fn poll(&mut self) -> Vec<Libp2pEvent> {
+        let mut events = Vec::new();
+        while let Some(event) = self.service.next_event().now_or_never() {
+            if !self
+                .persona
+                .handle(&mut self.service, &self.network_globals, &event)
+            {
+                events.push(event);
+            }
+        }
+        events
+    }
+}
+
+/// Tokio's clock, paused for the duration of a simulation.
+pub struct VirtualClock(());
+
+impl VirtualClock {
+    /// Pauses the clock of the current runtime, which must be a current-thread runtime. Time then
+    /// only moves when the clock is advanced.
+    pub fn start() -> Self {
+        tokio::time::pause();
+        VirtualClock(())
+    }
+
+    /// Moves the clock forward by `duration`, firing the timers due in the meantime.
+    pub async fn advance(&self, duration: Duration) {
+        tokio::time::advance(duration).await;
+    }
+
+    /// Moves the clock forward by `duration` in steps of `step`, letting the nodes handle the
+    /// events of each step before the next one.
+    pub async fn run_for(&self, duration: Duration, step: Duration) {
+        let mut elapsed = Duration::from_secs(0);
+        while elapsed < duration {
+            let step = step.min(duration - elapsed);
+            tokio::time::advance(step).await;
+            tokio::task::yield_now().await;
+            elapsed += step;
+        }
+    }
+
+    /// Moves the clock forward as in `run_for`, letting each node handle its events before every
+    /// step. The events the personas leave to the test are dropped.
+    pub async fn run_nodes(&self, nodes: &mut [SimulatedNode], duration: Duration, step: Duration) {
+        let mut elapsed = Duration::from_secs(0);
+        while elapsed < duration {
+            for node in nodes.iter_mut() {
+                node.poll();
+            }
+            tokio::task::yield_now().await;
+            let step = step.min(duration - elapsed);
+            tokio::time::advance(step).await;
+            elapsed += step;
+        }
+    }
+}
+
+impl Drop for VirtualClock {
+    fn drop(&mut self) {
+        tokio::time::resume();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock;
+    use crate::config::PRIVATE_AGENT_VERSION;
+    use crate::keys::keypair_from_hex;
+    use crate::rpc::{Encoding, Protocol, StatusMessage, Version, VersionCodec};
+    use crate::IdentityVerifier;
+    use libp2p::identify::IdentifyInfo;
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    /// Starts a node for each of `personas`, each connecting to the first one and configured
+    /// with `configure`.
+    fn start_nodes(
+        executor: &TaskExecutor,
+        dir: &Path,
+        personas: &[Persona],
+        configure: impl Fn(&mut NetworkConfig),
+    ) -> Vec<SimulatedNode> {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let mut nodes = Vec::new();
+        for (index, persona) in personas.iter().enumerate() {
+            let peers = if index == 0 { vec![] } else { vec![0] };
+            let index = index as u64;
+            let mut config = node_config(index, dir.join(index.to_string()), &peers);
+            deterministic_gossip(&mut config, personas.len());
+            configure(&mut config);
+            let node = SimulatedNode::start(*persona, config, vec![], executor.clone(), &log);
+            nodes.push(node.unwrap());
+        }
+        nodes
+    }
+
+    /// Runs the nodes as `VirtualClock::run_nodes` does, returning the events the personas left to
+    /// the test with the index of their node.
+    async fn run_collecting(
+        clock: &VirtualClock,
+        nodes: &mut [SimulatedNode],
+        duration: Duration,
+        step: Duration,
+    ) -> Vec<(usize, Libp2pEvent)> {
+        let mut events = Vec::new();
+        let mut elapsed = Duration::from_secs(0);
+        while elapsed < duration {
+            for (index, node) in nodes.iter_mut().enumerate() {
+                events.extend(node.poll().into_iter().map(|event| (index, event)));
+            }
+            tokio::task::yield_now().await;
+            let step = step.min(duration - elapsed);
+            clock.advance(step).await;
+            elapsed += step;
+        }
+        events
+    }
+
+    fn executor(exit: exit_future::Exit) -> TaskExecutor {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        TaskExecutor::new(tokio::runtime::Handle::current(), exit, log)
+    }
+
+    #[tokio::test]
+    async fn test_virtual_time() {
+        let clock = VirtualClock::start();
+        let start = clock::now();
+        let mut interval = tokio::time::interval(Duration::from_secs(30));
+        interval.tick().await;
+
+        clock.advance(Duration::from_secs(3600)).await;
+        assert_eq!(clock::now() - start, Duration::from_secs(3600));
+        // the interval fell behind and fires at once
+        interval.tick().await;
+        assert_eq!(clock::now() - start, Duration::from_secs(3600));
+    }
+
+    #[tokio::test]
+    async fn test_nodes_on_virtual_time() {
+        let clock = VirtualClock::start();
+        let dir = TempDir::new("simulation").unwrap();
+        let (_signal, exit) = exit_future::signal();
+        let personas = [Persona::Honest, Persona::Honest];
+        let mut nodes = start_nodes(&executor(exit), dir.path(), &personas, |_| {});
+
+        // an hour of virtual time, over which the nodes keep pinging each other
+        let (hour, step) = (Duration::from_secs(3600), Duration::from_secs(10));
+        clock.run_nodes(&mut nodes, hour, step).await;
+        for node in &nodes {
+            assert_eq!(node.network_globals.connected_peers(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ping_and_identify_are_reported() {
+        let clock = VirtualClock::start();
+        let dir = TempDir::new("simulation").unwrap();
+        let (_signal, exit) = exit_future::signal();
+        let personas = [Persona::Honest, Persona::Honest];
+        let mut nodes = start_nodes(&executor(exit), dir.path(), &personas, |_| {});
+        let step = Duration::from_millis(100);
+        let events = run_collecting(&clock, &mut nodes, Duration::from_secs(10), step).await;
+        let remote = nodes[0].peer_id().clone();
+        assert!(events.iter().any(|(index, event)| match event {
+            Libp2pEvent::Behaviour(BehaviourEvent::PeerIdentified {
+                peer_id,
+                protocol_version,
+                ..
+            }) => *index == 1 && *peer_id == remote && protocol_version.starts_with("mothra/"),
+            _ => false,
+        }));
+
+        // pings sent by the application are answered with a pong it is told about
+        nodes[1]
+            .service
+            .swarm
+            .ping(RequestId::Router, remote.clone());
+        let events = run_collecting(&clock, &mut nodes, Duration::from_secs(1), step).await;
+        assert!(events.iter().any(|(index, event)| match event {
+            Libp2pEvent::Behaviour(BehaviourEvent::PongReceived { peer_id, id, .. }) =>
+                *index == 1 && *peer_id == remote && matches!(id, RequestId::Router),
+            _ => false,
+        }));
+    }
+
+    #[tokio::test]
+    async fn test_private_mode_hides_the_agent_version() {
+        let clock = VirtualClock::start();
+        let dir = TempDir::new("simulation").unwrap();
+        let (_signal, exit) = exit_future::signal();
+        let personas = [Persona::Honest, Persona::Honest];
+        let mut nodes = start_nodes(&executor(exit), dir.path(), &personas, |config| {
+            config.private = true;
+        });
+        // the application cannot reveal the agent version either
+        nodes[0]
+            .service
+            .swarm
+            .update_identify(Some("mothra/v1.0.0".into()), None);
+        let step = Duration::from_millis(100);
+        let events = run_collecting(&clock, &mut nodes, Duration::from_secs(10), step).await;
+        let remote = nodes[0].peer_id().clone();
+        let agent_version = events.iter().find_map(|(index, event)| match event {
+            Libp2pEvent::Behaviour(BehaviourEvent::PeerIdentified {
+                peer_id,
+                agent_version,
+                ..
+            }) if *index == 1 && *peer_id == remote => Some(agent_version.clone()),
+            _ => None,
+        });
+        assert_eq!(agent_version.as_deref(), Some(PRIVATE_AGENT_VERSION));
+    }
+
+    #[tokio::test]
+    async fn test_peers_on_another_chain_are_disconnected() {
+        let clock = VirtualClock::start();
+        let dir = TempDir::new("simulation").unwrap();
+        let (_signal, exit) = exit_future::signal();
+        let personas = [Persona::Honest, Persona::Honest];
+        let mut nodes = start_nodes(&executor(exit), dir.path(), &personas, |config| {
+            config.typed_status = true;
+            config.auto_status = true;
+        });
+        // both nodes finalized the same epoch, at different roots
+        for (node, root) in nodes.iter().zip(&[1, 2]) {
+            node.network_globals.set_local_status(StatusMessage {
+                finalized_root: [*root; 32],
+                finalized_epoch: 10,
+                ..StatusMessage::default()
+            });
+        }
+
+        // the peer manager has the nodes exchange their Status five minutes after connecting
+        let (duration, step) = (Duration::from_secs(310), Duration::from_secs(1));
+        clock.run_nodes(&mut nodes, duration, step).await;
+        assert!(nodes
+            .iter()
+            .any(|node| node.network_globals.irrelevant_peers() > 0));
+        for node in &nodes {
+            assert_eq!(node.network_globals.connected_peers(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bridge_serves_two_forks() {
+        let clock = VirtualClock::start();
+        let dir = TempDir::new("simulation").unwrap();
+        let (_signal, exit) = exit_future::signal();
+        let executor = executor(exit);
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let status = |fork: u8, head_slot| StatusMessage {
+            fork_digest: [fork, 0, 0, 0],
+            head_slot,
+            ..StatusMessage::default()
+        };
+        // node 0 is on the old fork and node 2 on the new one, each connecting to node 1, which
+        // serves both and bridges them
+        let forks = [1, 1, 2];
+        let mut nodes = Vec::new();
+        for (index, fork) in forks.iter().enumerate() {
+            let peers = if index == 1 { vec![] } else { vec![1] };
+            let mut config = node_config(index as u64, dir.path().join(index.to_string()), &peers);
+            deterministic_gossip(&mut config, forks.len());
+            if index == 1 {
+                config.secondary_fork_id = Some(vec![2, 0, 0, 0]);
+                config.bridge_forks = true;
+                config.typed_status = true;
+                config.auto_status = true;
+            }
+            let fork_id = vec![*fork, 0, 0, 0];
+            let node =
+                SimulatedNode::start(Persona::Honest, config, fork_id, executor.clone(), &log);
+            let mut node = node.unwrap();
+            node.network_globals.set_local_status(status(*fork, 0));
+            assert_eq!(node.subscribe("/mothra/{fork}/blocks"), Ok(true));
+            nodes.push(node);
+        }
+        // the bridge is ahead on both forks
+        nodes[1].network_globals.set_local_status(status(1, 10));
+        nodes[1].network_globals.set_local_status(status(2, 20));
+        let step = Duration::from_millis(100);
+        clock
+            .run_nodes(&mut nodes, Duration::from_secs(10), step)
+            .await;
+
+        // a message published on the old fork reaches the new one through the bridge
+        nodes[0].publish("/mothra/{fork}/blocks", vec![1, 2, 3]);
+        let events = run_collecting(&clock, &mut nodes, Duration::from_secs(1), step).await;
+        assert!(events.iter().any(|(index, event)| match event {
+            Libp2pEvent::Behaviour(BehaviourEvent::PubsubMessage {
+                topics, message, ..
+            }) =>
+                *index == 2
+                    && topics[0].as_str() == "/mothra/02000000/blocks"
+                    && *message == [1, 2, 3],
+            _ => false,
+        }));
+        assert_eq!(nodes[1].network_globals.bridged_messages(), 1);
+
+        // the peer manager has the nodes exchange their Status five minutes after connecting, and
+        // the bridge answers each with the status of its fork
+        let (duration, step) = (Duration::from_secs(310), Duration::from_secs(1));
+        let events = run_collecting(&clock, &mut nodes, duration, step).await;
+        let statuses: Vec<(usize, StatusMessage)> = events
+            .into_iter()
+            .filter_map(|(index, event)| match event {
+                Libp2pEvent::Behaviour(BehaviourEvent::ResponseReceived {
+                    response: Response::Status(data),
+                    ..
+                }) if index != 1 => StatusMessage::decode(&data).map(|status| (index, status)),
+                _ => None,
+            })
+            .collect();
+        assert!(statuses.contains(&(0, status(1, 10))));
+        assert!(statuses.contains(&(2, status(2, 20))));
+        let bridge = &nodes[1].network_globals;
+        assert_eq!(bridge.irrelevant_peers(), 0);
+        for index in &[0, 2] {
+            assert!(bridge.peers.read().is_connected(nodes[*index].peer_id()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_outbound_only_node_is_not_reachable() {
+        let clock = VirtualClock::start();
+        let dir = TempDir::new("simulation").unwrap();
+        let (_signal, exit) = exit_future::signal();
+        let executor = executor(exit);
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let mut nodes = Vec::new();
+        for index in 0..2 {
+            let peers = if index == 0 { vec![] } else { vec![0] };
+            let mut config = node_config(index, dir.path().join(index.to_string()), &peers);
+            deterministic_gossip(&mut config, 2);
+            config.outbound_only = index == 1;
+            let node =
+                SimulatedNode::start(Persona::Honest, config, vec![], executor.clone(), &log);
+            nodes.push(node.unwrap());
+        }
+        let step = Duration::from_millis(100);
+        clock
+            .run_nodes(&mut nodes, Duration::from_secs(10), step)
+            .await;
+
+        // the node dials out, but has no listener and advertises no address to be found at
+        let outbound = &nodes[1];
+        assert_eq!(outbound.network_globals.connected_peers(), 1);
+        assert_eq!(libp2p::Swarm::listeners(&outbound.service.swarm).count(), 0);
+        assert!(outbound.network_globals.listen_multiaddrs().is_empty());
+        let enr = outbound.network_globals.local_enr();
+        assert_eq!((enr.ip(), enr.tcp(), enr.udp()), (None, None, None));
+    }
+
+    #[tokio::test]
+    async fn test_own_peer_id_and_enr_are_ignored() {
+        let clock = VirtualClock::start();
+        let dir = TempDir::new("simulation").unwrap();
+        let (_signal, exit) = exit_future::signal();
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let mut config = node_config(0, dir.path().join("0"), &[]);
+        let secret_key_hex = config.secret_key_hex.clone().unwrap();
+        let keypair = keypair_from_hex(&secret_key_hex, config.key_type).unwrap();
+        let own_address =
+            memory_address(0).with(Protocol::P2p(keypair.public().into_peer_id().into()));
+        // the node is given its own address as a static peer
+        config.libp2p_nodes.push(own_address.clone());
+        let node = SimulatedNode::start(Persona::Honest, config, vec![], executor(exit), &log);
+        let mut nodes = vec![node.unwrap()];
+        let globals = nodes[0].network_globals.clone();
+        assert_eq!(globals.self_dials_rejected(), 1);
+
+        assert!(nodes[0].service.dial(own_address, 1).is_err());
+        nodes[0].service.swarm.add_enr(globals.local_enr());
+        assert_eq!(globals.self_dials_rejected(), 3);
+        let step = Duration::from_millis(100);
+        clock
+            .run_nodes(&mut nodes, Duration::from_secs(10), step)
+            .await;
+        assert_eq!(globals.connected_peers(), 0);
+        assert!(!globals.peers.read().is_connected(&globals.local_peer_id()));
+    }
+
+    #[tokio::test]
+    async fn test_incompatible_protocols_are_disconnected() {
+        let clock = VirtualClock::start();
+        let dir = TempDir::new("simulation").unwrap();
+        let (_signal, exit) = exit_future::signal();
+        let executor = executor(exit);
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        // node 0 requires the mothra protocol, which node 1 does not run and node 2 does
+        let mut nodes = Vec::new();
+        for index in 0..3 {
+            let peers = if index == 0 { vec![] } else { vec![0] };
+            let mut config = node_config(index, dir.path().join(index.to_string()), &peers);
+            deterministic_gossip(&mut config, 3);
+            match index {
+                0 => config.required_protocol_prefix = Some("mothra/".into()),
+                1 => config.protocol_version = "other/libp2p".into(),
+                _ => config.protocol_version = "mothra/libp2p".into(),
+            }
+            let node =
+                SimulatedNode::start(Persona::Honest, config, vec![], executor.clone(), &log);
+            nodes.push(node.unwrap());
+        }
+        let step = Duration::from_millis(100);
+        clock
+            .run_nodes(&mut nodes, Duration::from_secs(10), step)
+            .await;
+
+        let globals = &nodes[0].network_globals;
+        assert_eq!(globals.incompatible_protocols(), 1);
+        let peers = globals.peers.read();
+        let (incompatible, compatible) = (nodes[1].peer_id(), nodes[2].peer_id());
+        assert!(!peers.is_connected(incompatible));
+        assert!(peers.is_connected(compatible));
+        // an unknown peer has the reputation every peer starts with
+        assert!(peers.reputation(incompatible) < peers.reputation(&PeerId::random()));
+    }
+
+    #[tokio::test]
+    async fn test_topic_ready_once_meshed() {
+        let clock = VirtualClock::start();
+        let dir = TempDir::new("simulation").unwrap();
+        let (_signal, exit) = exit_future::signal();
+        let personas = [Persona::Honest, Persona::Honest];
+        let mut nodes = start_nodes(&executor(exit), dir.path(), &personas, |_| {});
+        for node in &mut nodes {
+            assert_eq!(node.subscribe("/mothra/blocks"), Ok(true));
+            assert!(!node.network_globals.topic_ready("/mothra/blocks"));
+        }
+        // node 1 alone joins another topic, whose mesh stays empty
+        assert_eq!(nodes[1].subscribe("/mothra/lonely"), Ok(true));
+        let step = Duration::from_millis(100);
+        let events = run_collecting(&clock, &mut nodes, Duration::from_secs(10), step).await;
+
+        // each node reports the topic once
+        let mut ready: Vec<(usize, String)> = events
+            .into_iter()
+            .filter_map(|(index, event)| match event {
+                Libp2pEvent::Behaviour(BehaviourEvent::TopicReady(topic)) => {
+                    Some((index, topic.to_string()))
+                }
+                _ => None,
+            })
+            .collect();
+        ready.sort();
+        let blocks = "/mothra/blocks".to_string();
+        assert_eq!(ready, vec![(0, blocks.clone()), (1, blocks)]);
+        let globals = nodes[1].network_globals.clone();
+        assert!(globals.topic_ready("/mothra/blocks"));
+        assert!(!globals.topic_ready("/mothra/lonely"));
+
+        // leaving the topic resets it
+        nodes[1]
+            .service
+            .swarm
+            .unsubscribe_kind("/mothra/blocks".into());
+        assert!(!globals.topic_ready("/mothra/blocks"));
+    }
+
+    #[tokio::test]
+    async fn test_dial_results_carry_their_token() {
+        let clock = VirtualClock::start();
+        let dir = TempDir::new("simulation").unwrap();
+        let (_signal, exit) = exit_future::signal();
+        let executor = executor(exit);
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        // the nodes know nothing of each other until the application dials
+        let mut nodes = Vec::new();
+        for index in 0..2 {
+            let config = node_config(index, dir.path().join(index.to_string()), &[]);
+            let node =
+                SimulatedNode::start(Persona::Honest, config, vec![], executor.clone(), &log);
+            nodes.push(node.unwrap());
+        }
+
+        // nothing listens at the address of an unused index, which fails at once
+        assert!(nodes[1].service.dial(memory_address(1000), 3).is_err());
+        nodes[1].service.dial(memory_address(0), 1).unwrap();
+        nodes[1].service.dial(memory_address(0), 2).unwrap();
+        let step = Duration::from_millis(100);
+        let events = run_collecting(&clock, &mut nodes, Duration::from_secs(5), step).await;
+        let mut results: Vec<(u64, Result<PeerId, String>)> = events
+            .into_iter()
+            .filter_map(|(index, event)| match event {
+                Libp2pEvent::DialResult { token, result, .. } if index == 1 => {
+                    Some((token, result))
+                }
+                _ => None,
+            })
+            .collect();
+        results.sort_by_key(|(token, _)| *token);
+        let remote = nodes[0].peer_id().clone();
+        assert_eq!(results, vec![(1, Ok(remote.clone())), (2, Ok(remote))]);
+    }
+
+    /// Sends payloads reversed.
+    struct Reversed;
+
+    impl VersionCodec for Reversed {
+        fn encode(&self, _protocol: Protocol, mut payload: Vec<u8>) -> Result<Vec<u8>, String> {
+            payload.reverse();
+            Ok(payload)
+        }
+
+        fn decode(&self, protocol: Protocol, payload: Vec<u8>) -> Result<Vec<u8>, String> {
+            self.encode(protocol, payload)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_negotiated_protocol_is_reported() {
+        let clock = VirtualClock::start();
+        let dir = TempDir::new("simulation").unwrap();
+        let (_signal, exit) = exit_future::signal();
+        let executor = executor(exit);
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let v2 = Version::new(2).unwrap();
+        // nodes 0 and 1 prefer the second version of Status, which node 2 does not support
+        let mut nodes = Vec::new();
+        for index in 0..3 {
+            let peers = if index == 0 { vec![] } else { vec![0] };
+            let mut config = node_config(index, dir.path().join(index.to_string()), &peers);
+            deterministic_gossip(&mut config, 3);
+            if index < 2 {
+                config
+                    .rpc_versions
+                    .set(Protocol::Status, vec![v2, Version::V1])
+                    .unwrap();
+                config
+                    .rpc_versions
+                    .register(v2, Arc::new(Reversed))
+                    .unwrap();
+            }
+            let node =
+                SimulatedNode::start(Persona::Honest, config, vec![], executor.clone(), &log);
+            nodes.push(node.unwrap());
+        }
+        let step = Duration::from_millis(100);
+        clock
+            .run_nodes(&mut nodes, Duration::from_secs(5), step)
+            .await;
+
+        let remote = nodes[0].peer_id().clone();
+        for node in &mut nodes[1..] {
+            let request = Request::Status(vec![1, 2, 3]);
+            node.service
+                .send_request(remote.clone(), RequestId::Router, request);
+        }
+        let events = run_collecting(&clock, &mut nodes, Duration::from_secs(1), step).await;
+        let mut responses: Vec<(usize, Version, &Response)> = events
+            .iter()
+            .filter_map(|(index, event)| match event {
+                Libp2pEvent::Behaviour(BehaviourEvent::ResponseReceived {
+                    response,
+                    protocol_id,
+                    ..
+                }) => {
+                    assert_eq!(protocol_id.message_name, Protocol::Status);
+                    assert_eq!(protocol_id.encoding, Encoding::Snappy);
+                    Some((*index, protocol_id.version, response))
+                }
+                _ => None,
+            })
+            .collect();
+        responses.sort_by_key(|(index, _, _)| *index);
+        // the payload is decoded with the codec of the negotiated version
+        let status = Response::Status(vec![1, 2, 3]);
+        assert_eq!(responses, vec![(1, v2, &status), (2, Version::V1, &status)]);
+    }
+
+    /// Denies the peers whose agent version starts with the given prefix.
+    #[derive(Debug)]
+    struct DenyAgent(&'static str);
+
+    impl IdentityVerifier for DenyAgent {
+        fn verify_identify(&self, _peer_id: &PeerId, info: &IdentifyInfo) -> bool {
+            !info.agent_version.starts_with(self.0)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_denied_identities_are_banned() {
+        let clock = VirtualClock::start();
+        let dir = TempDir::new("simulation").unwrap();
+        let (_signal, exit) = exit_future::signal();
+        let executor = executor(exit);
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        // node 0 denies node 1 and allows node 2
+        let mut nodes = Vec::new();
+        for index in 0..3 {
+            let peers = if index == 0 { vec![] } else { vec![0] };
+            let mut config = node_config(index, dir.path().join(index.to_string()), &peers);
+            deterministic_gossip(&mut config, 3);
+            match index {
+                0 => config.identity_verifier = Some(Arc::new(DenyAgent("denied/"))),
+                1 => config.agent_version = "denied/v1".into(),
+                _ => config.agent_version = "allowed/v1".into(),
+            }
+            let node =
+                SimulatedNode::start(Persona::Honest, config, vec![], executor.clone(), &log);
+            nodes.push(node.unwrap());
+        }
+        let step = Duration::from_millis(100);
+        clock
+            .run_nodes(&mut nodes, Duration::from_secs(10), step)
+            .await;
+
+        let globals = &nodes[0].network_globals;
+        assert!(globals.rejected_identities() >= 1);
+        let peers = globals.peers.read();
+        let (denied, allowed) = (nodes[1].peer_id(), nodes[2].peer_id());
+        assert!(!peers.is_connected(denied));
+        assert!(peers.peer_banned(denied));
+        assert!(peers.is_connected(allowed));
+        assert!(!peers.peer_banned(allowed));
+    }
+
+    #[tokio::test]
+    async fn test_identify_is_updated() {
+        let clock = VirtualClock::start();
+        let dir = TempDir::new("simulation").unwrap();
+        let (_signal, exit) = exit_future::signal();
+        let executor = executor(exit);
+        let personas = [Persona::Honest, Persona::Honest];
+        let mut nodes = start_nodes(&executor, dir.path(), &personas, |_| {});
+        let step = Duration::from_millis(100);
+        clock
+            .run_nodes(&mut nodes, Duration::from_secs(10), step)
+            .await;
+
+        // the update swaps identify while node 1 is connected
+        nodes[0]
+            .service
+            .swarm
+            .update_identify(Some("updated/v2".into()), None);
+        clock
+            .run_nodes(&mut nodes, Duration::from_secs(1), step)
+            .await;
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let mut config = node_config(2, dir.path().join("2"), &[0]);
+        deterministic_gossip(&mut config, 3);
+        let node = SimulatedNode::start(Persona::Honest, config, vec![], executor, &log);
+        nodes.push(node.unwrap());
+        clock
+            .run_nodes(&mut nodes, Duration::from_secs(10), step)
+            .await;
+
+        // a new peer identifies the node with the new agent version, and the connection open
+        // during the swap is kept
+        let updated = nodes[0].peer_id().clone();
+        let peers = nodes[2].network_globals.peers.read();
+        let agent = peers
+            .peer_info(&updated)
+            .and_then(|info| info.client.agent_string.clone());
+        assert_eq!(agent.as_deref(), Some("updated/v2"));
+        assert!(nodes[1].network_globals.peers.read().is_connected(&updated));
+    }
+
+    #[tokio::test]
+    async fn test_unsubscribed_publish_is_rejected() {
+        let clock = VirtualClock::start();
+        let dir = TempDir::new("simulation").unwrap();
+        let (_signal, exit) = exit_future::signal();
+        let executor = executor(exit);
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let mut nodes = Vec::new();
+        for index in 0..2 {
+            let peers = if index == 0 { vec![] } else { vec![0] };
+            let mut config = node_config(index, dir.path().join(index.to_string()), &peers);
+            deterministic_gossip(&mut config, 2);
+            config.reject_unsubscribed_publish = true;
+            let fork_id = vec![1, 0, 0, 0];
+            let node =
+                SimulatedNode::start(Persona::Honest, config, fork_id, executor.clone(), &log);
+            let mut node = node.unwrap();
+            assert_eq!(node.subscribe("/mothra/blocks"), Ok(true));
+            assert_eq!(node.subscribe("/mothra/{fork}/blocks"), Ok(true));
+            nodes.push(node);
+        }
+        let step = Duration::from_millis(100);
+        clock
+            .run_nodes(&mut nodes, Duration::from_secs(10), step)
+            .await;
+
+        // a topic we are subscribed to, and a fork-scoped one joined on the current fork
+        nodes[0].publish("/mothra/blocks", vec![1]);
+        nodes[0].publish("/mothra/{fork}/blocks", vec![2]);
+        nodes[0].publish("/mothra/other", vec![3]);
+        let events = run_collecting(&clock, &mut nodes, Duration::from_secs(1), step).await;
+        let failed: Vec<&str> = events
+            .iter()
+            .filter_map(|(_, event)| match event {
+                Libp2pEvent::Behaviour(BehaviourEvent::PublishFailed { topic, .. }) => {
+                    Some(topic.as_str())
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(failed, vec!["/mothra/other"]);
+        let received: Vec<(&str, &[u8])> = events
+            .iter()
+            .filter_map(|(index, event)| match event {
+                Libp2pEvent::Behaviour(BehaviourEvent::PubsubMessage {
+                    topics, message, ..
+                }) if *index == 1 => Some((topics[0].as_str(), message.as_slice())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            received,
+            vec![
+                ("/mothra/blocks", &[1][..]),
+                ("/mothra/01000000/blocks", &[2][..])
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_no_graft_peers_stay_out_of_the_mesh() {
+        let clock = VirtualClock::start();
+        let dir = TempDir::new("simulation").unwrap();
+        let (_signal, exit) = exit_future::signal();
+        let executor = executor(exit);
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let peer_id = |index| {
+            let config = node_config(index, dir.path().join(index.to_string()), &[]);
+            let keypair = keypair_from_hex(&config.secret_key_hex.unwrap(), config.key_type);
+            keypair.unwrap().public().into_peer_id()
+        };
+        // node 0 keeps node 2 out of its mesh
+        let no_graft_peer = peer_id(2);
+        let mut nodes = Vec::new();
+        for index in 0..3 {
+            let peers = if index == 0 { vec![] } else { vec![0] };
+            let mut config = node_config(index, dir.path().join(index.to_string()), &peers);
+            deterministic_gossip(&mut config, 3);
+            if index == 0 {
+                config.no_graft_peers = vec![no_graft_peer.clone()];
+            }
+            let node =
+                SimulatedNode::start(Persona::Honest, config, vec![], executor.clone(), &log);
+            let mut node = node.unwrap();
+            assert_eq!(node.subscribe("/mothra/blocks"), Ok(true));
+            nodes.push(node);
+        }
+        let step = Duration::from_millis(100);
+        clock
+            .run_nodes(&mut nodes, Duration::from_secs(10), step)
+            .await;
+
+        // node 1 is grafted, while node 2 stays connected but outside the mesh
+        let globals = &nodes[0].network_globals;
+        assert!(globals.peers.read().is_connected(&no_graft_peer));
+        assert_eq!(globals.mesh_grafts(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_responses_are_penalized() {
+        let clock = VirtualClock::start();
+        let dir = TempDir::new("simulation").unwrap();
+        let (_signal, exit) = exit_future::signal();
+        // twice the largest response the honest node accepts
+        let oversized = Persona::Oversized { size: 2 << 20 };
+        let personas = [Persona::Honest, oversized];
+        let mut nodes = start_nodes(&executor(exit), dir.path(), &personas, |_| {});
+
+        // the peer manager has the nodes exchange their Status five minutes after connecting
+        let (duration, step) = (Duration::from_secs(310), Duration::from_secs(1));
+        clock.run_nodes(&mut nodes, duration, step).await;
+        let persona = nodes[1].peer_id().clone();
+        let peers = nodes[0].network_globals.peers.read();
+        // an unknown peer has the reputation every peer starts with
+        assert!(peers.reputation(&persona) < peers.reputation(&PeerId::random()));
+    }
+
+    #[tokio::test]
+    async fn test_stale_substreams_are_force_closed() {
+        let clock = VirtualClock::start();
+        let dir = TempDir::new("simulation").unwrap();
+        let (_signal, exit) = exit_future::signal();
+        let personas = [Persona::Unresponsive, Persona::Honest];
+        let mut nodes = start_nodes(&executor(exit), dir.path(), &personas, |config| {
+            // the watchdog closes the substreams before the request times out
+            config.rpc_substream_max_age = Some(Duration::from_secs(1));
+            config.limits.rpc_ttfb_timeout = Duration::from_secs(60);
+            config.limits.rpc_response_timeout = Duration::from_secs(60);
+        });
+        let step = Duration::from_millis(100);
+        clock
+            .run_nodes(&mut nodes, Duration::from_secs(5), step)
+            .await;
+
+        // the request is never answered, and its substreams are counted on both sides
+        let (persona, honest) = (nodes[0].peer_id().clone(), nodes[1].peer_id().clone());
+        let request = Request::Status(vec![1, 2, 3]);
+        nodes[1]
+            .service
+            .send_request(persona.clone(), RequestId::Router, request);
+        clock
+            .run_nodes(&mut nodes, Duration::from_secs(1), step)
+            .await;
+        let counts = |node: &SimulatedNode, peer_id: &PeerId| {
+            let substreams = node.network_globals.rpc_substreams();
+            substreams.get(peer_id).copied().unwrap_or_default()
+        };
+        assert!(counts(&nodes[1], &persona).outbound >= 1);
+        assert!(counts(&nodes[0], &honest).inbound >= 1);
+
+        clock
+            .run_nodes(&mut nodes, Duration::from_secs(15), step)
+            .await;
+        for node in &nodes {
+            assert!(node.network_globals.force_closed_substreams() >= 1);
+        }
+        assert_eq!(counts(&nodes[1], &persona).outbound, 0);
+        assert_eq!(counts(&nodes[0], &honest).inbound, 0);
+    }
+
+    #[tokio::test]
+    async fn test_floods_are_rate_limited() {
+        let clock = VirtualClock::start();
+        let dir = TempDir::new("simulation").unwrap();
+        let (_signal, exit) = exit_future::signal();
+        let personas = [Persona::Honest, Persona::Flooder { copies: 20 }];
+        let mut nodes = start_nodes(&executor(exit), dir.path(), &personas, |config| {
+            config.peer_topic_rate_limit = Some(1);
+        });
+        assert_eq!(nodes[0].subscribe("flood"), Ok(true));
+        let step = Duration::from_millis(100);
+        clock
+            .run_nodes(&mut nodes, Duration::from_secs(10), step)
+            .await;
+
+        // the copies arrive within a second, when the honest node accepts a single message
+        nodes[1].publish("flood", vec![1, 2, 3]);
+        let step = Duration::from_millis(10);
+        clock
+            .run_nodes(&mut nodes, Duration::from_secs(1), step)
+            .await;
+        let honest = &nodes[0].network_globals;
+        assert!(honest.rate_limited_messages() > 0);
+        // the flooder is not penalized, as it might only have relayed the copies
+        let persona = nodes[1].peer_id();
+        let peers = honest.peers.read();
+        assert!(peers.reputation(persona) >= peers.reputation(&PeerId::random()));
+    }
+
+    #[cfg(feature = "kad")]
+    #[tokio::test]
+    async fn test_records_are_put_and_got() {
+        use futures::channel::oneshot;
+
+        let clock = VirtualClock::start();
+        let dir = TempDir::new("simulation").unwrap();
+        let (_signal, exit) = exit_future::signal();
+        let personas = [Persona::Honest, Persona::Honest];
+        let mut nodes = start_nodes(&executor(exit), dir.path(), &personas, |_| {});
+        // the nodes add each other to their routing tables once identified
+        let step = Duration::from_millis(100);
+        clock
+            .run_nodes(&mut nodes, Duration::from_secs(10), step)
+            .await;
+
+        let (reply, mut stored) = oneshot::channel();
+        nodes[1]
+            .service
+            .swarm
+            .put_record(b"key".to_vec(), b"value".to_vec(), reply);
+        clock
+            .run_nodes(&mut nodes, Duration::from_secs(10), step)
+            .await;
+        assert_eq!(stored.try_recv(), Ok(Some(Ok(()))));
+
+        let (reply, mut found) = oneshot::channel();
+        nodes[0].service.swarm.get_record(b"key".to_vec(), reply);
+        clock
+            .run_nodes(&mut nodes, Duration::from_secs(10), step)
+            .await;
+        assert_eq!(found.try_recv(), Ok(Some(Ok(vec![b"value".to_vec()]))));
+    }
+
+    #[test]
+    fn test_node_config() {
+        let config = node_config(0, PathBuf::from("/tmp/mothra-0"), &[1, 2]);
+        assert_eq!(config.memory_port, Some(1));
+        assert!(!config.disable_discovery);
+        assert_eq!(config.enr_udp_port, Some(DISCOVERY_BASE_PORT));
+        // a peer discovered through its ENR is dialed in memory
+        let enr_address: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+        assert_eq!(memory_address_of(&enr_address), Some(memory_address(0)));
+        let remote: Multiaddr = "/ip4/10.0.0.1/tcp/1".parse().unwrap();
+        assert_eq!(memory_address_of(&remote), None);
+        assert_eq!(
+            config.libp2p_nodes,
+            vec![
+                "/memory/2".parse::<Multiaddr>().unwrap(),
+                "/memory/3".parse().unwrap()
+            ]
+        );
+        assert_ne!(
+            node_config(1, PathBuf::from("/tmp/mothra-1"), &[]).secret_key_hex,
+            config.secret_key_hex
+        );
+    }
+
+    #[test]
+    fn test_personas() {
+        let honest = node_config(0, PathBuf::from("/tmp/mothra-0"), &[]);
+        let mut config = honest.clone();
+        Persona::Oversized { size: 4 << 20 }.configure(&mut config);
+        assert_eq!(config.gs_config.max_transmit_size, 8 << 20);
+        assert_eq!(config.limits.max_rpc_size, 4 << 20);
+        assert_eq!(Persona::Oversized { size: 8 }.message(vec![1]).len(), 8);
+        assert_eq!(Persona::Honest.message(vec![1]), vec![1]);
+        let copies = Persona::Flooder { copies: 3 }.messages(vec![1]);
+        assert_eq!(copies.len(), 3);
+        assert_ne!(copies[0], copies[1]);
+        assert_eq!(Persona::Honest.messages(vec![1]), vec![vec![1]]);
+
+        let mut config = honest.clone();
+        Persona::IhaveSpammer.configure(&mut config);
+        assert!(config.gs_config.heartbeat_interval < honest.gs_config.heartbeat_interval);
+        assert_eq!(config.gs_config.gossip_lazy, honest.max_peers);
+
+        let mut config = honest.clone();
+        Persona::BogusEnr.configure(&mut config);
+        assert!(config.enr_address.is_some());
+        assert_ne!(Persona::BogusEnr.enr_fork_id(vec![0, 1]), vec![0, 1]);
+        assert!(!Persona::BogusEnr.enr_fork_id(vec![]).is_empty());
+        assert_eq!(Persona::Unresponsive.enr_fork_id(vec![0, 1]), vec![0, 1]);
+    }
+}
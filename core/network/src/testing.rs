@@ -0,0 +1,63 @@
+//! Fixtures shared by the tests of this crate and of the crates built on it.
+use crate::discv5::enr::{CombinedKey, EnrBuilder};
+use crate::{BufferPool, CombinedKeyExt, Keypair, NetworkGlobals, NetworkGlobalsParams};
+use parking_lot::Mutex;
+use slog::{Drain, Key, OwnedKVList, Record, Serializer, KV};
+use std::fmt;
+use std::sync::Arc;
+
+/// The globals of a network service that was never started, with a random ENR.
+pub fn network_globals() -> Arc<NetworkGlobals> {
+    let keypair = Keypair::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .build(&CombinedKey::from_libp2p(&keypair).unwrap())
+        .unwrap();
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let params = NetworkGlobalsParams {
+        enr,
+        fork_id: vec![],
+        secondary_fork_id: None,
+        meta_data: vec![],
+        ping_data: vec![],
+        tcp_port: 0,
+        udp_port: 0,
+        buffer_pool: Arc::new(BufferPool::default()),
+    };
+    Arc::new(NetworkGlobals::new(params, &log))
+}
+
+/// A drain recording the keys and values of every line logged, those of the line's logger
+/// included.
+#[derive(Clone, Default)]
+pub struct Collect(pub Arc<Mutex<Vec<(String, String)>>>);
+
+impl Collect {
+    /// Returns the values logged under `key`, in the order they were logged.
+    pub fn values(&self, key: &str) -> Vec<String> {
+        self.0
+            .lock()
+            .iter()
+            .filter(|(k, _)| k == key)
+            .map(|(_, value)| value.clone())
+            .collect()
+    }
+}
+
+impl Serializer for Collect {
+    fn emit_arguments(&mut self, key: Key, val: &fmt::Arguments) -> slog::Result {
+        self.0.lock().push((key.to_string(), val.to_string()));
+        Ok(())
+    }
+}
+
+impl Drain for Collect {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<(), slog::Never> {
+        let mut serializer = self.clone();
+        let _ = record.kv().serialize(record, &mut serializer);
+        let _ = values.serialize(record, &mut serializer);
+        Ok(())
+    }
+}
@@ -0,0 +1,254 @@
+//! Bridges that re-publish the gossip received on one topic to another, for migrating a network
+//! to new topics or consolidating several namespaces into one.
+//!
+//! The messages of a topic that is bridged to carry an envelope: a version byte telling whether
+//! the message was bridged, followed for a bridged message by the id of the message it was bridged
+//! from. The envelope is only used on these target topics, so every node publishing or receiving
+//! on one must know it is a target: a node bridging to the topic knows from its bridges, any other
+//! marks it with `TopicBridges::add_target`. Messages on other topics are left untouched.
+//!
+//! Bridged messages are never bridged again, so bridges between the same topics in opposite
+//! directions, on this node or any other, cannot loop. Receivers open the envelope before the
+//! message reaches the application, and deliver a message bridged by several nodes only once.
+//! Messages are not signed, so any peer can claim any origin; a copy is therefore recognised by
+//! its origin together with its data, and a forged copy cannot suppress the real one.
+use crate::types::BufferPool;
+use libp2p::gossipsub::MessageId;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+
+/// The envelope version of a message published on the target topic itself.
+const ENVELOPE_PLAIN: u8 = 0;
+
+/// The envelope version of a bridged message, followed by the length of the origin message id and
+/// the id itself.
+const ENVELOPE_BRIDGED: u8 = 1;
+
+/// Identifies the copies of a bridged message: the digest of its origin and data.
+pub type BridgedCopy = [u8; 32];
+
+/// Transforms the data of a message as it is bridged. A message that fails is not bridged.
+pub type BridgeTransform = Arc<dyn Fn(&str, Vec<u8>) -> Result<Vec<u8>, String> + Send + Sync>;
+
+/// The topic a bridge publishes to and the transform applied on the way.
+#[derive(Clone)]
+pub struct Bridge {
+    pub to: String,
+    pub transform: Option<BridgeTransform>,
+}
+
+/// The bridges out of each topic, and the topics whose messages carry the bridge envelope.
+#[derive(Clone, Default)]
+pub struct TopicBridges {
+    bridges: HashMap<String, Vec<Bridge>>,
+    targets: HashSet<String>,
+}
+
+impl TopicBridges {
+    /// Re-publishes the messages received on `from` to `to`, passing their data and the topic they
+    /// were received on through `transform` if given. The node must be subscribed to `from`, and
+    /// `to` becomes a target topic.
+    pub fn register(
+        &mut self,
+        from: impl Into<String>,
+        to: impl Into<String>,
+        transform: Option<BridgeTransform>,
+    ) {
+        let to = to.into();
+        self.targets.insert(to.clone());
+        self.bridges
+            .entry(from.into())
+            .or_default()
+            .push(Bridge { to, transform });
+    }
+
+    /// Marks `topic` as a target of bridges on other nodes, so that its messages are published and
+    /// received in the bridge envelope.
+    pub fn add_target(&mut self, topic: impl Into<String>) {
+        self.targets.insert(topic.into());
+    }
+
+    /// Returns true if no bridges or targets are registered.
+    pub fn is_empty(&self) -> bool {
+        self.bridges.is_empty() && self.targets.is_empty()
+    }
+
+    /// Returns true if the messages of `topic` carry the bridge envelope.
+    pub fn is_target(&self, topic: &str) -> bool {
+        self.targets.contains(topic)
+    }
+
+    /// The bridges a message received on `topic` is re-published through. A message that was
+    /// bridged itself, from `origin`, is not bridged again.
+    pub fn from_topic(&self, topic: &str, origin: Option<&MessageId>) -> &[Bridge] {
+        match origin {
+            Some(_) => &[],
+            None => self.bridges.get(topic).map_or(&[], Vec::as_slice),
+        }
+    }
+}
+
+impl fmt::Debug for TopicBridges {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bridges: HashMap<&String, Vec<&str>> = self
+            .bridges
+            .iter()
+            .map(|(from, bridges)| {
+                (
+                    from,
+                    bridges.iter().map(|bridge| bridge.to.as_str()).collect(),
+                )
+            })
+            .collect();
+        f.debug_struct("TopicBridges")
+            .field("bridges", &bridges)
+            .field("targets", &self.targets)
+            .finish()
+    }
+}
+
+/// Puts the data of a message published on a target topic in the bridge envelope, with the id of
+/// the message it was bridged from if it was bridged. Ids longer than 255 bytes are truncated,
+/// which only weakens the deduplication of their copies.
+pub fn seal_bridged(origin: Option<&MessageId>, data: Vec<u8>) -> Vec<u8> {
+    let id = origin.map_or(&[][..], |origin| origin.0.as_bytes());
+    let id = &id[..id.len().min(std::u8::MAX as usize)];
+    let mut sealed = Vec::with_capacity(2 + id.len() + data.len());
+    match origin {
+        Some(_) => {
+            sealed.push(ENVELOPE_BRIDGED);
+            sealed.push(id.len() as u8);
+            sealed.extend_from_slice(id);
+        }
+        None => sealed.push(ENVELOPE_PLAIN),
+    }
+    sealed.extend_from_slice(&data);
+    sealed
+}
+
+/// Opens the bridge envelope of a message received on a target topic, returning the id of the
+/// message it was bridged from, if it was bridged, and the data published. The data is copied to
+/// a buffer taken from `pool`, and the envelope given back to it.
+pub fn open_bridged(
+    data: Vec<u8>,
+    pool: &BufferPool,
+) -> Result<(Option<MessageId>, Vec<u8>), String> {
+    let opened = match data.first() {
+        Some(&ENVELOPE_PLAIN) => Ok((None, 1)),
+        Some(&ENVELOPE_BRIDGED) => match data.get(1) {
+            Some(&len) if data.len() >= 2 + len as usize => {
+                let origin = String::from_utf8_lossy(&data[2..2 + len as usize]).into_owned();
+                Ok((Some(MessageId(origin)), 2 + len as usize))
+            }
+            _ => Err("Bridge envelope cut short".to_string()),
+        },
+        Some(version) => Err(format!("Unknown bridge envelope version {}", version)),
+        None => Err("Empty bridge envelope".into()),
+    };
+    let opened = opened.map(|(origin, start)| {
+        let mut published = pool.take(data.len() - start);
+        published.copy_from_slice(&data[start..]);
+        (origin, published)
+    });
+    pool.give(data);
+    opened
+}
+
+/// Identifies the copies of the message bridged from `origin` with `data`, the data after any
+/// inbound transforms.
+pub fn bridged_copy(origin: &MessageId, data: &[u8]) -> BridgedCopy {
+    let mut hasher = Sha256::new();
+    hasher.input(&(origin.0.len() as u64).to_le_bytes());
+    hasher.input(origin.0.as_bytes());
+    hasher.input(data);
+    let mut copy = [0; 32];
+    copy.copy_from_slice(&hasher.result());
+    copy
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envelope_round_trip() {
+        let pool = BufferPool::default();
+        let origin = MessageId("origin".into());
+        let sealed = seal_bridged(Some(&origin), vec![1, 2, 3]);
+        assert_eq!(
+            open_bridged(sealed, &pool),
+            Ok((Some(origin), vec![1, 2, 3]))
+        );
+        let sealed = seal_bridged(None, vec![0xff, b'M']);
+        assert_eq!(open_bridged(sealed, &pool), Ok((None, vec![0xff, b'M'])));
+        // the envelopes were given back, and the second reused the first's buffer
+        assert_eq!(pool.stats().returned, 2);
+        assert_eq!(pool.stats().reused, 1);
+
+        // data that is not in the envelope is rejected rather than guessed at
+        assert!(open_bridged(vec![], &pool).is_err());
+        assert!(open_bridged(vec![7, 1, 2], &pool).is_err());
+        assert!(open_bridged(vec![ENVELOPE_BRIDGED, 10, 1], &pool).is_err());
+        assert!(open_bridged(vec![ENVELOPE_BRIDGED], &pool).is_err());
+    }
+
+    #[test]
+    fn test_forged_origin_is_another_copy() {
+        let origin = MessageId("origin".into());
+        assert_eq!(
+            bridged_copy(&origin, &[1, 2]),
+            bridged_copy(&origin, &[1, 2])
+        );
+        assert_ne!(bridged_copy(&origin, &[1, 2]), bridged_copy(&origin, &[6]));
+        assert_ne!(
+            bridged_copy(&origin, &[1, 2]),
+            bridged_copy(&MessageId("other".into()), &[1, 2])
+        );
+    }
+
+    #[test]
+    fn test_opposite_bridges_do_not_loop() {
+        let mut bridges = TopicBridges::default();
+        bridges.register("a", "b", None);
+        bridges.register("b", "a", None);
+        assert!(bridges.is_target("a") && bridges.is_target("b"));
+
+        // a message published on a is bridged to b
+        let id = MessageId("id".into());
+        let out = bridges.from_topic("a", None);
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].to, "b");
+        let sealed = seal_bridged(Some(&id), vec![1]);
+
+        // the copy received on b carries its origin, so it is not bridged back to a
+        let (origin, data) = open_bridged(sealed, &BufferPool::default()).unwrap();
+        assert_eq!(data, vec![1]);
+        assert!(bridges.from_topic("b", origin.as_ref()).is_empty());
+        // while a message published on b itself is
+        assert_eq!(bridges.from_topic("b", None).len(), 1);
+    }
+
+    #[test]
+    fn test_bridges_by_topic() {
+        let mut bridges = TopicBridges::default();
+        bridges.register("a", "b", None);
+        bridges.register(
+            "a",
+            "c",
+            Some(Arc::new(|_topic: &str, mut data: Vec<u8>| {
+                data.reverse();
+                Ok(data)
+            })),
+        );
+        let out = bridges.from_topic("a", None);
+        assert_eq!(out.len(), 2);
+        assert_eq!(
+            (out[1].transform.as_ref().unwrap())("a", vec![1, 2]),
+            Ok(vec![2, 1])
+        );
+        assert!(bridges.from_topic("b", None).is_empty());
+        assert!(bridges.is_target("c") && !bridges.is_target("a"));
+    }
+}
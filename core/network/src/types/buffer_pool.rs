@@ -0,0 +1,189 @@
+//! A pool of byte buffers for the payloads of inbound messages.
+//!
+//! At high message rates allocating and freeing a buffer per message dominates the time spent
+//! outside of the application. The RPC codec therefore decodes into buffers taken from the pool,
+//! and those buffers travel in the requests and responses through the behaviour to the
+//! `Subscriber`, which may give them back once it is done with the data. Gossip is decoded by
+//! gossipsub into buffers of its own, but they too can be given back and are then reused by the
+//! codec, and the payloads of bridged gossip are taken from the pool when their envelope is
+//! opened.
+//!
+//! The pool holds at most `capacity` buffers, and no buffer larger than `max_buffer_size`, so a
+//! burst of large messages does not keep their memory once it has passed.
+use parking_lot::Mutex;
+use serde_derive::Serialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The default number of buffers kept for reuse.
+pub const DEFAULT_BUFFER_POOL_SIZE: usize = 256;
+
+/// The default capacity in bytes above which a buffer is freed rather than kept.
+pub const DEFAULT_MAX_POOLED_BUFFER_SIZE: usize = 65_536;
+
+/// Byte buffers kept for reuse.
+#[derive(Debug)]
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    capacity: usize,
+    max_buffer_size: usize,
+    /// The buffers taken from the pool.
+    taken: AtomicUsize,
+    /// The buffers taken that were reused rather than allocated.
+    reused: AtomicUsize,
+    /// The buffers given back and kept.
+    returned: AtomicUsize,
+    /// The buffers given back and freed, because the pool was full or they were too large.
+    discarded: AtomicUsize,
+}
+
+/// The use of a `BufferPool` since it was created.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct BufferPoolStats {
+    pub taken: usize,
+    pub reused: usize,
+    pub returned: usize,
+    pub discarded: usize,
+    /// The buffers currently kept.
+    pub pooled: usize,
+    /// The capacity in bytes of the buffers currently kept.
+    pub pooled_bytes: usize,
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        BufferPool::new(DEFAULT_BUFFER_POOL_SIZE, DEFAULT_MAX_POOLED_BUFFER_SIZE)
+    }
+}
+
+impl BufferPool {
+    /// Creates a pool keeping up to `capacity` buffers of at most `max_buffer_size` bytes. With no
+    /// capacity, every buffer is allocated.
+    pub fn new(capacity: usize, max_buffer_size: usize) -> Self {
+        BufferPool {
+            buffers: Mutex::new(Vec::new()),
+            capacity,
+            max_buffer_size,
+            taken: AtomicUsize::new(0),
+            reused: AtomicUsize::new(0),
+            returned: AtomicUsize::new(0),
+            discarded: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns a buffer of `len` zeroed bytes, reusing the smallest kept buffer large enough if
+    /// there is one, so that small messages do not take the buffers large ones need.
+    pub fn take(&self, len: usize) -> Vec<u8> {
+        self.taken.fetch_add(1, Ordering::Relaxed);
+        let reused = {
+            let mut buffers = self.buffers.lock();
+            // of buffers of the same size, the most recently returned are the most likely to be in
+            // the cache
+            let index = buffers
+                .iter()
+                .enumerate()
+                .rev()
+                .filter(|(_, buffer)| buffer.capacity() >= len)
+                .min_by_key(|(_, buffer)| buffer.capacity())
+                .map(|(index, _)| index);
+            index.map(|index| buffers.swap_remove(index))
+        };
+        match reused {
+            Some(mut buffer) => {
+                self.reused.fetch_add(1, Ordering::Relaxed);
+                buffer.clear();
+                buffer.resize(len, 0);
+                buffer
+            }
+            None => vec![0; len],
+        }
+    }
+
+    /// Gives a buffer back for reuse. Its contents are discarded.
+    pub fn give(&self, buffer: Vec<u8>) {
+        if buffer.capacity() == 0 || buffer.capacity() > self.max_buffer_size {
+            self.discarded.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let mut buffers = self.buffers.lock();
+        if buffers.len() < self.capacity {
+            buffers.push(buffer);
+            self.returned.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.discarded.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Reads the use of the pool.
+    pub fn stats(&self) -> BufferPoolStats {
+        let buffers = self.buffers.lock();
+        BufferPoolStats {
+            taken: self.taken.load(Ordering::Relaxed),
+            reused: self.reused.load(Ordering::Relaxed),
+            returned: self.returned.load(Ordering::Relaxed),
+            discarded: self.discarded.load(Ordering::Relaxed),
+            pooled: buffers.len(),
+            pooled_bytes: buffers.iter().map(Vec::capacity).sum(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reuse_buffers() {
+        let pool = BufferPool::new(2, 1024);
+        let mut buffer = pool.take(100);
+        assert_eq!(buffer, vec![0; 100]);
+        buffer[0] = 1;
+        let capacity = buffer.capacity();
+        pool.give(buffer);
+
+        // a smaller buffer reuses the returned one, zeroed
+        let buffer = pool.take(10);
+        assert_eq!(buffer, vec![0; 10]);
+        assert_eq!(buffer.capacity(), capacity);
+        // a larger one is allocated
+        let large = pool.take(200);
+        pool.give(buffer);
+        pool.give(large);
+        // the pool is full and the buffer too large
+        pool.give(vec![0; 10]);
+        pool.give(vec![0; 2048]);
+
+        let stats = pool.stats();
+        assert_eq!(
+            stats,
+            BufferPoolStats {
+                taken: 3,
+                reused: 1,
+                returned: 3,
+                discarded: 2,
+                pooled: 2,
+                pooled_bytes: stats.pooled_bytes,
+            }
+        );
+        assert!(stats.pooled_bytes >= 300);
+    }
+
+    #[test]
+    fn test_best_fit() {
+        let pool = BufferPool::new(3, 4096);
+        pool.give(Vec::with_capacity(4096));
+        pool.give(Vec::with_capacity(100));
+        pool.give(Vec::with_capacity(1024));
+        assert_eq!(pool.take(50).capacity(), 100);
+        assert_eq!(pool.take(500).capacity(), 1024);
+        assert_eq!(pool.take(2000).capacity(), 4096);
+        assert_eq!(pool.stats().reused, 3);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let pool = BufferPool::new(0, 1024);
+        pool.give(vec![0; 10]);
+        assert_eq!(pool.take(10), vec![0; 10]);
+        assert_eq!(pool.stats().reused, 0);
+    }
+}
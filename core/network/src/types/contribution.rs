@@ -0,0 +1,66 @@
+//! How much of the gossip a peer forwards to us is new.
+//!
+//! A message counts as first delivered for the peer it arrives from first, and as a duplicate for
+//! every peer that forwards it afterwards. Peers that only ever forward duplicates add load without
+//! helping propagation.
+use serde_derive::Serialize;
+
+/// The gossip messages received from a peer, split by whether the peer delivered them first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct GossipContribution {
+    /// Messages the peer was the first to deliver.
+    pub first_delivered: u64,
+    /// The total size of the messages the peer was the first to deliver.
+    pub first_delivered_bytes: u64,
+    /// Messages the peer delivered after another peer had.
+    pub duplicates: u64,
+    /// The total size of the duplicate messages.
+    pub duplicate_bytes: u64,
+}
+
+impl GossipContribution {
+    /// Counts a message of `bytes` bytes received from the peer.
+    pub fn record(&mut self, first: bool, bytes: usize) {
+        if first {
+            self.first_delivered += 1;
+            self.first_delivered_bytes = self.first_delivered_bytes.saturating_add(bytes as u64);
+        } else {
+            self.duplicates += 1;
+            self.duplicate_bytes = self.duplicate_bytes.saturating_add(bytes as u64);
+        }
+    }
+
+    /// The number of messages received from the peer.
+    pub fn messages(&self) -> u64 {
+        self.first_delivered + self.duplicates
+    }
+
+    /// The fraction of the peer's messages it delivered first, from 0 to 1. `None` until the peer
+    /// has sent a message.
+    pub fn usefulness(&self) -> Option<f64> {
+        match self.messages() {
+            0 => None,
+            messages => Some(self.first_delivered as f64 / messages as f64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usefulness() {
+        let mut contribution = GossipContribution::default();
+        assert_eq!(contribution.usefulness(), None);
+
+        contribution.record(true, 100);
+        contribution.record(false, 100);
+        contribution.record(false, 50);
+        contribution.record(false, 50);
+        assert_eq!(contribution.messages(), 4);
+        assert_eq!(contribution.first_delivered_bytes, 100);
+        assert_eq!(contribution.duplicate_bytes, 200);
+        assert_eq!(contribution.usefulness(), Some(0.25));
+    }
+}
@@ -0,0 +1,155 @@
+//! Counts of the gossipsub control messages exchanged with peers.
+use crate::PeerId;
+use libp2p::gossipsub::protocol::GossipsubControlAction;
+use libp2p::gossipsub::MessageId;
+use serde_derive::Serialize;
+use std::collections::{HashMap, HashSet};
+
+/// The number of control messages of each kind.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct ControlCounts {
+    pub graft: usize,
+    pub prune: usize,
+    pub ihave: usize,
+    pub iwant: usize,
+}
+
+impl ControlCounts {
+    /// Counts a control message.
+    pub fn add(&mut self, action: &GossipsubControlAction) {
+        match action {
+            GossipsubControlAction::Graft { .. } => self.graft += 1,
+            GossipsubControlAction::Prune { .. } => self.prune += 1,
+            GossipsubControlAction::IHave { .. } => self.ihave += 1,
+            GossipsubControlAction::IWant { .. } => self.iwant += 1,
+        }
+    }
+}
+
+/// The control messages sent and received, by kind.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct ControlStats {
+    pub sent: ControlCounts,
+    pub received: ControlCounts,
+}
+
+impl ControlStats {
+    /// Counts a control message sent to a peer if `outbound`, or received from one otherwise.
+    pub fn record(&mut self, action: &GossipsubControlAction, outbound: bool) {
+        if outbound {
+            self.sent.add(action);
+        } else {
+            self.received.add(action);
+        }
+    }
+}
+
+/// The messages peers asked for with IWANT and have not been sent yet.
+///
+/// Gossipsub answers an IWANT with the requested messages still in its message cache, as soon as
+/// it is received. A requested message that is sent to the peer is an mcache hit; one that is
+/// not was already dropped from the cache, and is a miss.
+#[derive(Debug, Default)]
+pub struct PendingIwants {
+    peers: HashMap<PeerId, HashSet<MessageId>>,
+}
+
+impl PendingIwants {
+    /// Records the messages a peer asked for, returning how many. The unanswered messages of its
+    /// previous IWANTs are forgotten, gossipsub did not have them.
+    pub fn requested(&mut self, peer_id: &PeerId, message_ids: &[MessageId]) -> usize {
+        if message_ids.is_empty() {
+            self.peers.remove(peer_id);
+        } else {
+            let pending = message_ids.iter().cloned().collect();
+            self.peers.insert(peer_id.clone(), pending);
+        }
+        message_ids.len()
+    }
+
+    /// Returns true if a peer asked for messages it has not been sent yet.
+    pub fn is_waiting(&self, peer_id: &PeerId) -> bool {
+        self.peers.contains_key(peer_id)
+    }
+
+    /// Returns true if a message sent to a peer answers one of its IWANTs.
+    pub fn served(&mut self, peer_id: &PeerId, message_id: &MessageId) -> bool {
+        let pending = match self.peers.get_mut(peer_id) {
+            Some(pending) => pending,
+            None => return false,
+        };
+        let served = pending.remove(message_id);
+        if pending.is_empty() {
+            self.peers.remove(peer_id);
+        }
+        served
+    }
+
+    /// Forgets the IWANTs of a disconnected peer.
+    pub fn remove_peer(&mut self, peer_id: &PeerId) {
+        self.peers.remove(peer_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TopicHash;
+
+    #[test]
+    fn test_record() {
+        let topic_hash = TopicHash::from_raw("topic");
+        let mut stats = ControlStats::default();
+        stats.record(
+            &GossipsubControlAction::Graft {
+                topic_hash: topic_hash.clone(),
+            },
+            true,
+        );
+        stats.record(&GossipsubControlAction::Prune { topic_hash }, false);
+        stats.record(
+            &GossipsubControlAction::IWant {
+                message_ids: Vec::new(),
+            },
+            false,
+        );
+
+        assert_eq!(
+            stats.sent,
+            ControlCounts {
+                graft: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            stats.received,
+            ControlCounts {
+                prune: 1,
+                iwant: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_pending_iwants() {
+        let peer_id = PeerId::random();
+        let ids: Vec<MessageId> = (0..3).map(|i| MessageId(i.to_string())).collect();
+        let mut pending = PendingIwants::default();
+        assert_eq!(pending.requested(&peer_id, &ids), 3);
+        assert!(pending.served(&peer_id, &ids[0]));
+        assert!(!pending.served(&peer_id, &ids[0]));
+        assert!(pending.is_waiting(&peer_id));
+        assert!(!pending.served(&PeerId::random(), &ids[1]));
+
+        // a new IWANT replaces the unanswered ones
+        pending.requested(&peer_id, &ids[1..2]);
+        assert!(!pending.served(&peer_id, &ids[2]));
+        assert!(pending.served(&peer_id, &ids[1]));
+        assert!(!pending.is_waiting(&peer_id));
+
+        pending.requested(&peer_id, &ids);
+        pending.remove_peer(&peer_id);
+        assert!(!pending.served(&peer_id, &ids[2]));
+    }
+}
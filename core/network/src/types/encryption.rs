@@ -0,0 +1,446 @@
+//! Encryption of the messages of private topics with keys shared by their members.
+//!
+//! Each message is sealed with a fresh random nonce, which is sent in front of the ciphertext.
+//! The topic is authenticated as associated data, so a message cannot be replayed on another
+//! topic sharing the key. Random 96-bit nonces stay safe for about 2^32 messages per key, well
+//! beyond what a topic publishes before its key is replaced.
+//!
+//! Keys are rotated by generation, in two phases. A key is first added: messages sealed with it
+//! are accepted, but messages are still published under the current key. Once every member has
+//! added it, the key is activated and messages are published under it. Each key it replaces is
+//! still accepted for a grace period from then on. No message is lost as long as no member
+//! activates a key before every member added it, and every member activates it within the grace
+//! period. `TopicEncryption::rotate_key` does both at once, and loses the messages a member
+//! publishes under the new key until the others have it.
+use super::transform::MessageTransform;
+use crate::clock;
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::ChaCha20Poly1305;
+use parking_lot::RwLock;
+use rand::RngCore;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The length of a topic key, in bytes.
+pub const TOPIC_KEY_LENGTH: usize = 32;
+
+/// The length of the nonce in front of each encrypted message, in bytes.
+const NONCE_LENGTH: usize = 12;
+
+/// The authenticated cipher a topic is encrypted with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TopicCipher {
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl FromStr for TopicCipher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aes-256-gcm" => Ok(TopicCipher::Aes256Gcm),
+            "chacha20-poly1305" => Ok(TopicCipher::ChaCha20Poly1305),
+            _ => Err(format!("Invalid topic cipher: {}", s)),
+        }
+    }
+}
+
+/// The key of an encrypted topic.
+#[derive(Clone, PartialEq)]
+pub struct TopicKey {
+    pub cipher: TopicCipher,
+    pub key: [u8; TOPIC_KEY_LENGTH],
+}
+
+impl TopicKey {
+    /// Seals `data` published on `topic`.
+    fn encrypt(&self, topic: &str, data: &[u8]) -> Result<Vec<u8>, String> {
+        let mut nonce = [0u8; NONCE_LENGTH];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let payload = Payload {
+            msg: data,
+            aad: topic.as_bytes(),
+        };
+        let key = GenericArray::from_slice(&self.key);
+        let nonce_array = GenericArray::from_slice(&nonce);
+        let ciphertext = match self.cipher {
+            TopicCipher::Aes256Gcm => Aes256Gcm::new(key).encrypt(nonce_array, payload),
+            TopicCipher::ChaCha20Poly1305 => {
+                ChaCha20Poly1305::new(key).encrypt(nonce_array, payload)
+            }
+        }
+        .map_err(|_| "Could not encrypt the message".to_string())?;
+
+        let mut message = Vec::with_capacity(NONCE_LENGTH + ciphertext.len());
+        message.extend_from_slice(&nonce);
+        message.extend_from_slice(&ciphertext);
+        Ok(message)
+    }
+
+    /// Opens a message received on `topic`.
+    fn decrypt(&self, topic: &str, message: &[u8]) -> Result<Vec<u8>, String> {
+        if message.len() < NONCE_LENGTH {
+            return Err("Encrypted message too short".to_string());
+        }
+        let (nonce, ciphertext) = message.split_at(NONCE_LENGTH);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: topic.as_bytes(),
+        };
+        let key = GenericArray::from_slice(&self.key);
+        let nonce = GenericArray::from_slice(nonce);
+        match self.cipher {
+            TopicCipher::Aes256Gcm => Aes256Gcm::new(key).decrypt(nonce, payload),
+            TopicCipher::ChaCha20Poly1305 => ChaCha20Poly1305::new(key).decrypt(nonce, payload),
+        }
+        .map_err(|_| "Could not decrypt the message".to_string())
+    }
+}
+
+impl FromStr for TopicKey {
+    type Err = String;
+
+    /// Parses a key given as `<cipher>:<hex key>`, e.g. `chacha20-poly1305:0x01..`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let cipher = parts.next().unwrap_or_default().parse()?;
+        let bytes = parts
+            .next()
+            .and_then(|key| hex::decode(key.trim_start_matches("0x")).ok())
+            .filter(|bytes| bytes.len() == TOPIC_KEY_LENGTH)
+            .ok_or_else(|| format!("Invalid topic key, expected {} hex bytes", TOPIC_KEY_LENGTH))?;
+        let mut key = [0u8; TOPIC_KEY_LENGTH];
+        key.copy_from_slice(&bytes);
+        Ok(TopicKey { cipher, key })
+    }
+}
+
+impl fmt::Debug for TopicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // the key itself is kept out of logs
+        f.debug_struct("TopicKey")
+            .field("cipher", &self.cipher)
+            .finish()
+    }
+}
+
+/// A key of an encrypted topic, with the time until which it is accepted once replaced.
+struct AcceptedKey {
+    key: TopicKey,
+    until: Option<Instant>,
+}
+
+/// The keys of an encrypted topic.
+struct TopicKeys {
+    /// The generation of the key messages are published under, counting from the first key.
+    current: u64,
+    /// The keys messages are accepted under, by generation: the current key, the keys added but
+    /// not yet activated and the replaced keys still within their grace period.
+    accepted: BTreeMap<u64, AcceptedKey>,
+    /// The number of messages decrypted under each key generation.
+    decrypted: BTreeMap<u64, usize>,
+}
+
+impl TopicKeys {
+    fn new(generation: u64, key: TopicKey) -> Self {
+        let mut accepted = BTreeMap::new();
+        accepted.insert(generation, AcceptedKey { key, until: None });
+        TopicKeys {
+            current: generation,
+            accepted,
+            decrypted: BTreeMap::new(),
+        }
+    }
+
+    /// The generation the next key added gets.
+    fn next_generation(&self) -> u64 {
+        self.accepted.keys().next_back().map_or(0, |last| last + 1)
+    }
+
+    /// Accepts messages under `key`, returning its generation.
+    fn add(&mut self, key: TopicKey) -> u64 {
+        if let Some((generation, _)) = self.accepted.iter().find(|(_, k)| k.key == key) {
+            return *generation;
+        }
+        let generation = self.next_generation();
+        self.accepted
+            .insert(generation, AcceptedKey { key, until: None });
+        generation
+    }
+
+    /// Publishes under the key of `generation`, still accepting each key it replaces for `grace`.
+    /// A key replaced before keeps its own grace period.
+    fn activate(&mut self, generation: u64, grace: Duration, now: Instant) {
+        if generation == self.current {
+            return;
+        }
+        let current = self.current;
+        for (replaced, accepted) in self.accepted.iter_mut() {
+            let is_replaced = *replaced == current || *replaced < generation;
+            if is_replaced && accepted.until.is_none() {
+                accepted.until = Some(now + grace);
+            }
+        }
+        if let Some(activated) = self.accepted.get_mut(&generation) {
+            activated.until = None;
+        }
+        self.current = generation;
+    }
+
+    /// Stops accepting the replaced keys whose grace period is over.
+    fn expire(&mut self, now: Instant) {
+        self.accepted
+            .retain(|_, accepted| accepted.until.map_or(true, |until| until > now));
+    }
+}
+
+/// The keys of the encrypted topics. Clones share the keys, so a key set at runtime applies to
+/// the transform registered for its topic.
+#[derive(Clone, Default)]
+pub struct TopicEncryption {
+    keys: Arc<RwLock<HashMap<String, TopicKeys>>>,
+}
+
+impl TopicEncryption {
+    /// Sets the key of a topic, replacing any previous key at once. Returns true if the topic was
+    /// not encrypted before.
+    pub fn set_key(&self, topic: String, key: TopicKey) -> bool {
+        let mut keys = self.keys.write();
+        match keys.get_mut(&topic) {
+            Some(topic_keys) => {
+                let decrypted = std::mem::take(&mut topic_keys.decrypted);
+                *topic_keys = TopicKeys::new(topic_keys.next_generation(), key);
+                topic_keys.decrypted = decrypted;
+                false
+            }
+            None => {
+                keys.insert(topic, TopicKeys::new(0, key));
+                true
+            }
+        }
+    }
+
+    /// Accepts messages under a new key of an encrypted topic, the first phase of a rotation.
+    /// Messages are still published under the current key. Returns the generation of the key, or
+    /// `None` if the topic is not encrypted.
+    pub fn add_key(&self, topic: &str, key: TopicKey) -> Option<u64> {
+        let mut keys = self.keys.write();
+        Some(keys.get_mut(topic)?.add(key))
+    }
+
+    /// Publishes the messages of an encrypted topic under a key added with `add_key`, the second
+    /// phase of a rotation. The keys it replaces are still accepted for `grace`. Returns the
+    /// generation of the key, or `None` if the topic is not encrypted or the key was not added.
+    pub fn activate_key(&self, topic: &str, key: &TopicKey, grace: Duration) -> Option<u64> {
+        let mut keys = self.keys.write();
+        let topic_keys = keys.get_mut(topic)?;
+        let now = clock::now();
+        topic_keys.expire(now);
+        let generation = topic_keys
+            .accepted
+            .iter()
+            .find(|(_, accepted)| accepted.key == *key)
+            .map(|(generation, _)| *generation)?;
+        topic_keys.activate(generation, grace, now);
+        Some(generation)
+    }
+
+    /// Adds and activates a key of an encrypted topic at once, still accepting the keys it
+    /// replaces for `grace`. Members that have not added the key yet cannot read the messages
+    /// published under it; use `add_key` and `activate_key` to avoid that. Returns the generation
+    /// of the new key, or `None` if the topic is not encrypted.
+    pub fn rotate_key(&self, topic: &str, key: TopicKey, grace: Duration) -> Option<u64> {
+        let mut keys = self.keys.write();
+        let topic_keys = keys.get_mut(topic)?;
+        let generation = topic_keys.add(key);
+        topic_keys.activate(generation, grace, clock::now());
+        Some(generation)
+    }
+
+    /// The number of messages decrypted under each key generation, by topic.
+    pub fn decrypted_by_generation(&self) -> HashMap<String, BTreeMap<u64, usize>> {
+        self.keys
+            .read()
+            .iter()
+            .map(|(topic, topic_keys)| (topic.clone(), topic_keys.decrypted.clone()))
+            .collect()
+    }
+}
+
+impl MessageTransform for TopicEncryption {
+    fn outbound(&self, topic: &str, data: Vec<u8>) -> Result<Vec<u8>, String> {
+        match self.keys.read().get(topic) {
+            Some(topic_keys) => topic_keys.accepted[&topic_keys.current]
+                .key
+                .encrypt(topic, &data),
+            None => Err(format!("No key for encrypted topic {}", topic)),
+        }
+    }
+
+    fn inbound(&self, topic: &str, data: Vec<u8>) -> Result<Vec<u8>, String> {
+        let mut keys = self.keys.write();
+        let topic_keys = keys
+            .get_mut(topic)
+            .ok_or_else(|| format!("No key for encrypted topic {}", topic))?;
+        topic_keys.expire(clock::now());
+
+        // the current key is tried first, then the others from the newest
+        let current = topic_keys.current;
+        let generations = std::iter::once(current).chain(
+            topic_keys
+                .accepted
+                .keys()
+                .rev()
+                .copied()
+                .filter(|generation| *generation != current),
+        );
+        let mut opened = None;
+        for generation in generations {
+            if let Ok(data) = topic_keys.accepted[&generation].key.decrypt(topic, &data) {
+                opened = Some((data, generation));
+                break;
+            }
+        }
+        let (data, generation) =
+            opened.ok_or_else(|| "Could not decrypt the message".to_string())?;
+        *topic_keys.decrypted.entry(generation).or_default() += 1;
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_and_decrypt() {
+        for cipher in &["aes-256-gcm", "chacha20-poly1305"] {
+            let key: TopicKey = format!("{}:0x{}", cipher, "01".repeat(TOPIC_KEY_LENGTH))
+                .parse()
+                .unwrap();
+            let encryption = TopicEncryption::default();
+            assert!(encryption.set_key("topic".into(), key.clone()));
+            assert!(encryption.set_key("other".into(), key));
+
+            let message = encryption.outbound("topic", b"hello".to_vec()).unwrap();
+            assert_ne!(&message[NONCE_LENGTH..], b"hello");
+            assert_ne!(
+                encryption.outbound("topic", b"hello".to_vec()).unwrap(),
+                message
+            );
+            assert_eq!(
+                encryption.inbound("topic", message.clone()).unwrap(),
+                b"hello".to_vec()
+            );
+
+            // the topic is authenticated
+            assert!(encryption.inbound("other", message.clone()).is_err());
+            // so is the ciphertext
+            let mut tampered = message;
+            *tampered.last_mut().unwrap() ^= 1;
+            assert!(encryption.inbound("topic", tampered).is_err());
+            assert!(encryption.inbound("topic", vec![0; 4]).is_err());
+            assert!(encryption.outbound("unknown", vec![0]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_rotate_key() {
+        let key = |byte: u8| TopicKey {
+            cipher: TopicCipher::Aes256Gcm,
+            key: [byte; TOPIC_KEY_LENGTH],
+        };
+        let encryption = TopicEncryption::default();
+        assert_eq!(
+            encryption.rotate_key("topic", key(0), Duration::from_secs(60)),
+            None
+        );
+        encryption.set_key("topic".into(), key(0));
+        let first = encryption.outbound("topic", b"first".to_vec()).unwrap();
+
+        // messages under the replaced key are accepted during the grace window
+        assert_eq!(
+            encryption.rotate_key("topic", key(1), Duration::from_secs(60)),
+            Some(1)
+        );
+        let second = encryption.outbound("topic", b"second".to_vec()).unwrap();
+        assert!(encryption.inbound("topic", first.clone()).is_ok());
+        assert!(encryption.inbound("topic", second.clone()).is_ok());
+        assert!(encryption.inbound("topic", second.clone()).is_ok());
+
+        let mut decrypted = BTreeMap::new();
+        decrypted.insert(0, 1);
+        decrypted.insert(1, 2);
+        assert_eq!(encryption.decrypted_by_generation()["topic"], decrypted);
+
+        // and rejected once it is over, while a key replaced earlier keeps its own grace window
+        assert_eq!(
+            encryption.rotate_key("topic", key(2), Duration::from_secs(0)),
+            Some(2)
+        );
+        assert!(encryption.inbound("topic", second).is_err());
+        assert!(encryption.inbound("topic", first).is_ok());
+    }
+
+    #[test]
+    fn test_two_phase_rotation() {
+        let key = |byte: u8| TopicKey {
+            cipher: TopicCipher::ChaCha20Poly1305,
+            key: [byte; TOPIC_KEY_LENGTH],
+        };
+        let grace = Duration::from_secs(60);
+        // two members of a topic, the second one adding the new key later
+        let early = TopicEncryption::default();
+        let late = TopicEncryption::default();
+        early.set_key("topic".into(), key(0));
+        late.set_key("topic".into(), key(0));
+        assert_eq!(early.add_key("other", key(1)), None);
+        assert_eq!(early.activate_key("topic", &key(1), grace), None);
+
+        // an added key is accepted, but messages are still published under the current one
+        assert_eq!(early.add_key("topic", key(1)), Some(1));
+        assert_eq!(early.add_key("topic", key(1)), Some(1));
+        let message = early.outbound("topic", b"before".to_vec()).unwrap();
+        assert!(late.inbound("topic", message).is_ok());
+
+        assert_eq!(late.add_key("topic", key(1)), Some(1));
+        assert_eq!(early.activate_key("topic", &key(1), grace), Some(1));
+        let after = early.outbound("topic", b"after".to_vec()).unwrap();
+        assert!(late.inbound("topic", after.clone()).is_ok());
+        // the member that did not activate the key yet is still read under the replaced key
+        let message = late.outbound("topic", b"old key".to_vec()).unwrap();
+        assert!(early.inbound("topic", message.clone()).is_ok());
+
+        // a key replaced without a grace window is dropped at once, while the key replaced
+        // before keeps its own
+        assert_eq!(
+            early.rotate_key("topic", key(2), Duration::from_secs(0)),
+            Some(2)
+        );
+        assert!(early.inbound("topic", after).is_err());
+        assert!(early.inbound("topic", message).is_ok());
+    }
+
+    #[test]
+    fn test_parse_key() {
+        assert!("aes-256-gcm:0x01".parse::<TopicKey>().is_err());
+        assert!(format!("rot13:{}", "01".repeat(TOPIC_KEY_LENGTH))
+            .parse::<TopicKey>()
+            .is_err());
+        assert_eq!(
+            format!("chacha20-poly1305:{}", "ff".repeat(TOPIC_KEY_LENGTH))
+                .parse::<TopicKey>()
+                .unwrap(),
+            TopicKey {
+                cipher: TopicCipher::ChaCha20Poly1305,
+                key: [255; TOPIC_KEY_LENGTH],
+            }
+        );
+    }
+}
@@ -0,0 +1,175 @@
+//! The forks a node serves.
+//!
+//! A node normally serves only its current fork. During a fork transition it can serve a second
+//! one alongside it, the upcoming fork or the one being left, so that bridge nodes can relay
+//! between the networks on either side. Fork-scoped topics are then joined once per fork, and the
+//! second fork id is advertised in the ENR next to the current one. Status requests are answered
+//! with the status of the fork the requesting peer is on.
+//!
+//! With `NetworkConfig::bridge_forks` the node relays the gossip of fork-scoped topics between
+//! the two forks. A relayed message is a new gossip message on the other fork, so the copies
+//! relayed by several bridge nodes, or relayed back, are recognised by their topic and data: data
+//! relayed or received from another fork recently is not relayed again, which keeps bridges from
+//! looping. Nodes receiving on the other fork may still be delivered the copies of several
+//! bridges, unless gossipsub's message ids are derived from the data.
+use crate::types::{EnrForkId, GossipTopic};
+use lru::LruCache;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+
+/// The current fork and, during a transition, the second fork served alongside it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ActiveForks {
+    current: EnrForkId,
+    secondary: Option<EnrForkId>,
+}
+
+impl ActiveForks {
+    /// A secondary fork equal to the current one is ignored.
+    pub fn new(current: EnrForkId, secondary: Option<EnrForkId>) -> Self {
+        let secondary = secondary.filter(|secondary| *secondary != current);
+        ActiveForks { current, secondary }
+    }
+
+    pub fn current(&self) -> &EnrForkId {
+        &self.current
+    }
+
+    pub fn secondary(&self) -> Option<&EnrForkId> {
+        self.secondary.as_ref()
+    }
+
+    /// The ids of the forks served, the current one first.
+    pub fn ids(&self) -> impl Iterator<Item = &EnrForkId> {
+        std::iter::once(&self.current).chain(self.secondary.iter())
+    }
+
+    /// Whether the given fork is served.
+    pub fn contains(&self, fork_id: &[u8]) -> bool {
+        self.ids().any(|id| id.as_slice() == fork_id)
+    }
+
+    /// The topics joined for a topic: one per fork served if it is fork-scoped, otherwise the
+    /// topic itself.
+    pub fn topics(&self, topic: &GossipTopic) -> Vec<GossipTopic> {
+        if topic.is_fork_scoped() {
+            self.ids().map(|fork_id| topic.for_fork(fork_id)).collect()
+        } else {
+            vec![topic.clone()]
+        }
+    }
+}
+
+/// Relays the gossip of fork-scoped topics between the forks served.
+pub struct ForkRelay {
+    /// The digests of the topics and data of the messages relayed or received from another fork.
+    seen: LruCache<[u8; 32], ()>,
+}
+
+impl ForkRelay {
+    /// Remembers the last `capacity` messages relayed.
+    pub fn new(capacity: usize) -> Self {
+        ForkRelay {
+            seen: LruCache::new(capacity),
+        }
+    }
+
+    /// The number of messages remembered.
+    pub fn seen(&self) -> usize {
+        self.seen.len()
+    }
+
+    /// The topics a message received on `topic` is relayed to: the topics of the other forks
+    /// served for the fork-scoped topic `topic` belongs to, among those in `scoped`. None are
+    /// returned for data relayed or received on another fork recently.
+    pub fn targets(
+        &mut self,
+        forks: &ActiveForks,
+        scoped: &HashSet<GossipTopic>,
+        topic: &str,
+        data: &[u8],
+    ) -> Vec<GossipTopic> {
+        let (scoped, targets) = match scoped.iter().find_map(|scoped| {
+            let topics = forks.topics(scoped);
+            if topics.iter().any(|joined| joined.as_str() == topic) {
+                Some((scoped, topics))
+            } else {
+                None
+            }
+        }) {
+            Some(found) => found,
+            None => return vec![],
+        };
+        let mut hasher = Sha256::new();
+        hasher.input(&(scoped.as_str().len() as u64).to_le_bytes());
+        hasher.input(scoped.as_str().as_bytes());
+        hasher.input(data);
+        let mut digest = [0; 32];
+        digest.copy_from_slice(&hasher.result());
+        if self.seen.put(digest, ()).is_some() {
+            return vec![];
+        }
+        targets
+            .into_iter()
+            .filter(|target| target.as_str() != topic)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topics_per_fork() {
+        let topic = GossipTopic::new("/mothra/{fork}/blocks".into());
+        let shared = GossipTopic::new("/mothra/blocks".into());
+
+        let forks = ActiveForks::new(vec![1], Some(vec![1]));
+        assert_eq!(forks.secondary(), None);
+        assert_eq!(forks.topics(&topic).len(), 1);
+
+        let forks = ActiveForks::new(vec![1], Some(vec![2]));
+        assert!(forks.contains(&[2]) && !forks.contains(&[3]));
+        assert_eq!(
+            forks.topics(&topic),
+            vec![
+                GossipTopic::new("/mothra/01/blocks".into()),
+                GossipTopic::new("/mothra/02/blocks".into())
+            ]
+        );
+        assert_eq!(forks.topics(&shared), vec![shared]);
+    }
+
+    #[test]
+    fn test_relay_between_forks() {
+        let topic = GossipTopic::new("/mothra/{fork}/blocks".into());
+        let scoped: HashSet<GossipTopic> = vec![topic].into_iter().collect();
+        let forks = ActiveForks::new(vec![1], Some(vec![2]));
+        let mut relay = ForkRelay::new(8);
+
+        assert_eq!(
+            relay.targets(&forks, &scoped, "/mothra/01/blocks", &[1]),
+            vec![GossipTopic::new("/mothra/02/blocks".into())]
+        );
+        // the copy relayed back, or by another bridge, is not relayed again
+        assert!(relay
+            .targets(&forks, &scoped, "/mothra/02/blocks", &[1])
+            .is_empty());
+        assert_eq!(
+            relay.targets(&forks, &scoped, "/mothra/02/blocks", &[2]),
+            vec![GossipTopic::new("/mothra/01/blocks".into())]
+        );
+        // topics that are not fork-scoped, or of forks not served, are not relayed
+        assert!(relay
+            .targets(&forks, &scoped, "/mothra/blocks", &[3])
+            .is_empty());
+        assert!(relay
+            .targets(&forks, &scoped, "/mothra/03/blocks", &[3])
+            .is_empty());
+        let single = ActiveForks::new(vec![1], None);
+        assert!(relay
+            .targets(&single, &scoped, "/mothra/01/blocks", &[4])
+            .is_empty());
+    }
+}
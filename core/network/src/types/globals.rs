@@ -1,21 +1,39 @@
 //! A collection of variables that are accessible outside of the network thread itself.
 use crate::rpc::methods::MetaData;
+use crate::rpc::StatusMessage;
+use crate::rpc::SubstreamCounts;
+use crate::types::{
+    BufferPool, BufferPoolStats, ControlStats, GossipContribution, Histogram, MemoryUsage,
+    MemoryUsageSnapshot, QueueDepths, QueueDepthsSnapshot, SizeHistogram, Stats, StatsSnapshot,
+    SubnetId, TopicEncryption,
+};
 use crate::Client;
 use crate::EnrExt;
-use crate::{Enr, EnrForkId, GossipTopic, Multiaddr, PeerDB, PeerId};
+use crate::{
+    Enr, EnrForkId, GossipTopic, Multiaddr, PeerDB, PeerId, PeerQuery, PeerQueryResult,
+    PeerScoreSnapshot, SubnetPeerCount, TopicHash,
+};
 use parking_lot::RwLock;
-use std::collections::HashSet;
-use std::sync::atomic::{AtomicU16, Ordering};
+use rand::seq::SliceRandom;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 pub struct NetworkGlobals {
     /// The current local ENR.
     pub local_enr: RwLock<Enr>,
     /// The current local fork_id
     pub fork_id: RwLock<EnrForkId>,
+    /// The second fork served alongside the current one during a fork transition.
+    pub secondary_fork_id: RwLock<Option<EnrForkId>>,
     /// The current node's meta-data.
     pub meta_data: RwLock<Vec<u8>>,
     /// The current node's ping-data.
     pub ping_data: RwLock<Vec<u8>>,
+    /// The statuses the behaviour answers Status requests with, one per fork served, if
+    /// `NetworkConfig::auto_status` is set. Kept up to date by the application.
+    pub local_statuses: RwLock<Vec<StatusMessage>>,
     /// The local peer_id.
     pub peer_id: RwLock<PeerId>,
     /// Listening multiaddrs.
@@ -28,29 +46,182 @@ pub struct NetworkGlobals {
     pub peers: RwLock<PeerDB>,
     /// The current gossipsub topic subscriptions.
     pub gossipsub_subscriptions: RwLock<HashSet<GossipTopic>>,
+    /// The connected peers subscribed to each topic, as announced to gossipsub.
+    pub topic_peers: RwLock<HashMap<TopicHash, HashSet<PeerId>>>,
+    /// The subscribed topics whose mesh has reached a healthy size since we subscribed.
+    pub ready_topics: RwLock<HashSet<String>>,
+    /// The number of connections closed because they duplicated an existing connection to a peer.
+    pub duplicate_connections_closed: AtomicUsize,
+    /// The number of gossip messages dropped because the application could not keep up.
+    pub dropped_gossip_messages: AtomicUsize,
+    /// The number of RPC messages dropped because the application could not keep up.
+    pub dropped_rpc_messages: AtomicUsize,
+    /// The number of messages not mirrored because the mirror could not keep up or be reached.
+    pub dropped_mirror_messages: AtomicUsize,
+    /// The number of gossip messages not delivered because the application had already seen
+    /// their message key.
+    pub duplicate_messages_suppressed: AtomicUsize,
+    /// The number of peers disconnected because their agent string was rejected.
+    pub rejected_agents: AtomicUsize,
+    /// The number of peers disconnected because their protocol version lacks the required prefix.
+    pub incompatible_protocols: AtomicUsize,
+    /// The number of peers disconnected because their status is for a fork we do not serve.
+    pub irrelevant_peers: AtomicUsize,
+    /// The number of typed statuses received that could not be decoded.
+    pub malformed_statuses: AtomicUsize,
+    /// The number of peers denied by the identity verifier, when discovered or identified.
+    pub rejected_identities: AtomicUsize,
+    /// The number of gossip messages dropped because the peer they were received from is not
+    /// allowed on the topic.
+    pub rejected_publisher_messages: AtomicUsize,
+    /// The number of gossip messages dropped because a topic transform failed on them.
+    pub transform_failures: AtomicUsize,
+    /// The number of gossip messages dropped because their peer sent them over its rate on their
+    /// topic.
+    pub rate_limited_messages: AtomicUsize,
+    /// The number of gossip messages dropped because the node received more than its inbound
+    /// rate.
+    pub shed_messages: AtomicUsize,
+    /// The number of published messages dropped unpublished, at their deadline or to make room in
+    /// the publish buffer.
+    pub expired_publishes: AtomicUsize,
+    /// The number of messages re-published by the topic bridges.
+    pub bridged_messages: AtomicUsize,
+    /// The number of bridged messages not delivered because a copy bridged by another node was.
+    pub duplicate_bridged_messages: AtomicUsize,
+    /// The peers whose gossip is dropped on arrival, without disconnecting them. Peers are unmuted
+    /// when they disconnect or are banned.
+    pub muted_peers: RwLock<HashSet<PeerId>>,
+    /// The number of gossip messages dropped because they came from a muted peer.
+    pub muted_messages: AtomicUsize,
+    /// The number of received messages whose forwarding was dropped because too many were waiting
+    /// for the forwarding budget of their topic class.
+    pub dropped_forwards: AtomicUsize,
+    /// The number of received messages dropped for exceeding the control topic size limit.
+    pub oversized_control_messages: AtomicUsize,
+    /// The number of messages peers asked for with IWANT.
+    pub iwant_requested_messages: AtomicUsize,
+    /// The number of messages asked for with IWANT that were sent from gossipsub's mcache.
+    pub iwant_served_messages: AtomicUsize,
+    /// The number of peers added to our gossipsub meshes, by either side.
+    pub mesh_grafts: AtomicUsize,
+    /// The number of peers removed from our gossipsub meshes, by either side.
+    pub mesh_prunes: AtomicUsize,
+    /// The number of GRAFTs sent to better reputed peers because a mesh was poorly reputed.
+    pub opportunistic_grafts: AtomicUsize,
+    /// The RPC substreams open to each connected peer.
+    pub rpc_substreams: RwLock<HashMap<PeerId, SubstreamCounts>>,
+    /// The number of RPC substreams force closed because they were open for too long.
+    pub force_closed_substreams: AtomicUsize,
+    /// The propagation latency of gossip, per topic, for messages whose publish time is known.
+    pub gossip_latency: RwLock<HashMap<String, Histogram>>,
+    /// The number of times our own peer id or ENR was dropped from discovery results, boot nodes or
+    /// dials.
+    pub self_dials_rejected: AtomicUsize,
+    /// The number of inbound peers disconnected to make room for a more valuable peer.
+    pub preempted_peers: AtomicUsize,
+    /// The connected peers of the subnets with a peer quota, as of the last heartbeat.
+    pub subnet_peers: RwLock<HashMap<SubnetId, SubnetPeerCount>>,
+    /// The gossipsub control messages exchanged with all peers.
+    pub gossip_control: RwLock<ControlStats>,
+    /// The sizes of the gossip messages received, duplicates included.
+    pub gossip_message_sizes: RwLock<SizeHistogram>,
+    /// Counters of messages, bytes, peers and errors.
+    pub stats: Stats,
+    /// The depth of the internal queues.
+    pub queue_depths: QueueDepths,
+    /// The estimated memory held by the caches and queues.
+    pub memory_usage: MemoryUsage,
+    /// The buffers inbound message payloads are decoded into.
+    pub buffer_pool: Arc<BufferPool>,
+    /// The keys of the encrypted topics.
+    pub topic_encryption: TopicEncryption,
+}
+
+/// The initial values of the `NetworkGlobals`.
+pub struct NetworkGlobalsParams {
+    /// The local ENR.
+    pub enr: Enr,
+    /// The fork the node serves.
+    pub fork_id: EnrForkId,
+    /// The second fork served alongside `fork_id` during a fork transition.
+    pub secondary_fork_id: Option<EnrForkId>,
+    pub meta_data: Vec<u8>,
+    pub ping_data: Vec<u8>,
+    /// The TCP port libp2p listens on.
+    pub tcp_port: u16,
+    /// The UDP port discovery listens on.
+    pub udp_port: u16,
+    /// The buffers inbound payloads are decoded into.
+    pub buffer_pool: Arc<BufferPool>,
 }
 
 impl NetworkGlobals {
-    pub fn new(
-        enr: Enr,
-        fork_id: EnrForkId,
-        meta_data: Vec<u8>,
-        ping_data: Vec<u8>,
-        tcp_port: u16,
-        udp_port: u16,
-        log: &slog::Logger,
-    ) -> Self {
+    pub fn new(params: NetworkGlobalsParams, log: &slog::Logger) -> Self {
+        let NetworkGlobalsParams {
+            enr,
+            fork_id,
+            secondary_fork_id,
+            meta_data,
+            ping_data,
+            tcp_port,
+            udp_port,
+            buffer_pool,
+        } = params;
         NetworkGlobals {
             local_enr: RwLock::new(enr.clone()),
-            fork_id: RwLock::new(fork_id.clone()),
+            fork_id: RwLock::new(fork_id),
+            secondary_fork_id: RwLock::new(secondary_fork_id),
             meta_data: RwLock::new(meta_data),
             ping_data: RwLock::new(ping_data),
+            local_statuses: RwLock::new(Vec::new()),
             peer_id: RwLock::new(enr.peer_id()),
             listen_multiaddrs: RwLock::new(Vec::new()),
             listen_port_tcp: AtomicU16::new(tcp_port),
             listen_port_udp: AtomicU16::new(udp_port),
             peers: RwLock::new(PeerDB::new(log)),
             gossipsub_subscriptions: RwLock::new(HashSet::new()),
+            topic_peers: RwLock::new(HashMap::new()),
+            ready_topics: RwLock::new(HashSet::new()),
+            duplicate_connections_closed: AtomicUsize::new(0),
+            dropped_gossip_messages: AtomicUsize::new(0),
+            dropped_rpc_messages: AtomicUsize::new(0),
+            dropped_mirror_messages: AtomicUsize::new(0),
+            duplicate_messages_suppressed: AtomicUsize::new(0),
+            rejected_agents: AtomicUsize::new(0),
+            incompatible_protocols: AtomicUsize::new(0),
+            irrelevant_peers: AtomicUsize::new(0),
+            malformed_statuses: AtomicUsize::new(0),
+            rejected_identities: AtomicUsize::new(0),
+            rejected_publisher_messages: AtomicUsize::new(0),
+            transform_failures: AtomicUsize::new(0),
+            rate_limited_messages: AtomicUsize::new(0),
+            shed_messages: AtomicUsize::new(0),
+            expired_publishes: AtomicUsize::new(0),
+            bridged_messages: AtomicUsize::new(0),
+            duplicate_bridged_messages: AtomicUsize::new(0),
+            muted_peers: RwLock::new(HashSet::new()),
+            muted_messages: AtomicUsize::new(0),
+            dropped_forwards: AtomicUsize::new(0),
+            oversized_control_messages: AtomicUsize::new(0),
+            iwant_requested_messages: AtomicUsize::new(0),
+            iwant_served_messages: AtomicUsize::new(0),
+            mesh_grafts: AtomicUsize::new(0),
+            mesh_prunes: AtomicUsize::new(0),
+            opportunistic_grafts: AtomicUsize::new(0),
+            rpc_substreams: RwLock::new(HashMap::new()),
+            force_closed_substreams: AtomicUsize::new(0),
+            gossip_latency: RwLock::new(HashMap::new()),
+            self_dials_rejected: AtomicUsize::new(0),
+            preempted_peers: AtomicUsize::new(0),
+            subnet_peers: RwLock::new(HashMap::new()),
+            gossip_control: RwLock::new(ControlStats::default()),
+            gossip_message_sizes: RwLock::new(SizeHistogram::default()),
+            stats: Stats::default(),
+            queue_depths: QueueDepths::default(),
+            memory_usage: MemoryUsage::default(),
+            buffer_pool,
+            topic_encryption: TopicEncryption::default(),
         }
     }
 
@@ -66,6 +237,43 @@ impl NetworkGlobals {
         self.fork_id.read().clone()
     }
 
+    /// Returns the second fork served during a fork transition, if any.
+    pub fn local_secondary_fork_id(&self) -> Option<EnrForkId> {
+        self.secondary_fork_id.read().clone()
+    }
+
+    /// Returns the status Status requests on the current fork are answered with, if the
+    /// application has set one.
+    pub fn local_status(&self) -> Option<StatusMessage> {
+        self.local_status_on(&self.local_fork_id())
+    }
+
+    /// Returns the status set for the fork of `fork_id`, or the last one set if none is for it.
+    pub fn local_status_on(&self, fork_id: &[u8]) -> Option<StatusMessage> {
+        let statuses = self.local_statuses.read();
+        statuses
+            .iter()
+            .rev()
+            .find(|status| status.is_on_fork(fork_id))
+            .or_else(|| statuses.last())
+            .cloned()
+    }
+
+    /// Sets the status Status requests on its fork are answered with, when they are answered by
+    /// the behaviour. It replaces the status set for the same fork, and those set for forks no
+    /// longer served are dropped.
+    pub fn set_local_status(&self, status: StatusMessage) {
+        let forks: Vec<EnrForkId> = std::iter::once(self.local_fork_id())
+            .chain(self.local_secondary_fork_id())
+            .collect();
+        let mut statuses = self.local_statuses.write();
+        statuses.retain(|local| {
+            local.fork_digest != status.fork_digest
+                && forks.iter().any(|fork_id| local.is_on_fork(fork_id))
+        });
+        statuses.push(status);
+    }
+
     /// Returns the local libp2p PeerID.
     pub fn local_peer_id(&self) -> PeerId {
         self.peer_id.read().clone()
@@ -96,6 +304,229 @@ impl NetworkGlobals {
         self.peers.read().connected_or_dialing_peers().count()
     }
 
+    /// Returns the number of peers in the peer database.
+    pub fn known_peers(&self) -> usize {
+        self.peers.read().len()
+    }
+
+    /// Returns the number of duplicate connections closed after simultaneous dials.
+    pub fn duplicate_connections_closed(&self) -> usize {
+        self.duplicate_connections_closed.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of gossip messages dropped before reaching the application.
+    pub fn dropped_gossip_messages(&self) -> usize {
+        self.dropped_gossip_messages.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of RPC messages dropped before reaching the application.
+    pub fn dropped_rpc_messages(&self) -> usize {
+        self.dropped_rpc_messages.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of messages not mirrored because the mirror could not keep up or be
+    /// reached.
+    pub fn dropped_mirror_messages(&self) -> usize {
+        self.dropped_mirror_messages.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of gossip messages suppressed as duplicates of a seen message key.
+    pub fn duplicate_messages_suppressed(&self) -> usize {
+        self.duplicate_messages_suppressed.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of peers disconnected because their agent string was rejected.
+    pub fn rejected_agents(&self) -> usize {
+        self.rejected_agents.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of peers disconnected for running an incompatible protocol version.
+    pub fn incompatible_protocols(&self) -> usize {
+        self.incompatible_protocols.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of peers disconnected for sending the status of another fork.
+    pub fn irrelevant_peers(&self) -> usize {
+        self.irrelevant_peers.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of typed statuses received that could not be decoded.
+    pub fn malformed_statuses(&self) -> usize {
+        self.malformed_statuses.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of peers denied by the identity verifier.
+    pub fn rejected_identities(&self) -> usize {
+        self.rejected_identities.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of gossip messages dropped for coming from a peer not allowed on their
+    /// topic.
+    pub fn rejected_publisher_messages(&self) -> usize {
+        self.rejected_publisher_messages.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of messages, published or received, dropped because a topic transform
+    /// failed on them.
+    pub fn transform_failures(&self) -> usize {
+        self.transform_failures.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of gossip messages dropped for exceeding their peer's rate on their
+    /// topic.
+    pub fn rate_limited_messages(&self) -> usize {
+        self.rate_limited_messages.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of gossip messages dropped to keep the node within its inbound rate.
+    pub fn shed_messages(&self) -> usize {
+        self.shed_messages.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of published messages dropped without being published.
+    pub fn expired_publishes(&self) -> usize {
+        self.expired_publishes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of messages re-published by the topic bridges.
+    pub fn bridged_messages(&self) -> usize {
+        self.bridged_messages.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of bridged messages not delivered, because they were copies of a
+    /// message already bridged.
+    pub fn duplicate_bridged_messages(&self) -> usize {
+        self.duplicate_bridged_messages.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if the gossip of `peer_id` is dropped on arrival.
+    pub fn is_muted(&self, peer_id: &PeerId) -> bool {
+        self.muted_peers.read().contains(peer_id)
+    }
+
+    /// Drops the gossip of `peer_id` on arrival until it is unmuted or disconnects.
+    pub fn mute(&self, peer_id: PeerId) {
+        self.muted_peers.write().insert(peer_id);
+    }
+
+    /// Accepts the gossip of `peer_id` again.
+    pub fn unmute(&self, peer_id: &PeerId) {
+        self.muted_peers.write().remove(peer_id);
+    }
+
+    /// Drops the messages received from `peer_id` if it is muted, counting them in
+    /// `muted_messages`.
+    pub fn drop_muted<T>(&self, peer_id: &PeerId, messages: &mut Vec<T>) {
+        if !messages.is_empty() && self.is_muted(peer_id) {
+            self.muted_messages
+                .fetch_add(messages.len(), Ordering::Relaxed);
+            messages.clear();
+        }
+    }
+
+    /// Returns the number of gossip messages dropped because they came from a muted peer.
+    pub fn muted_messages(&self) -> usize {
+        self.muted_messages.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of received messages not forwarded because their topic class was over
+    /// its forwarding budget for too long.
+    pub fn dropped_forwards(&self) -> usize {
+        self.dropped_forwards.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of received messages dropped for exceeding the control topic size limit.
+    pub fn oversized_control_messages(&self) -> usize {
+        self.oversized_control_messages.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of messages peers asked for with IWANT.
+    pub fn iwant_requested_messages(&self) -> usize {
+        self.iwant_requested_messages.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of messages asked for with IWANT that were still in gossipsub's mcache
+    /// and sent.
+    pub fn iwant_served_messages(&self) -> usize {
+        self.iwant_served_messages.load(Ordering::Relaxed)
+    }
+
+    /// Returns the share of the messages asked for with IWANT that were still in gossipsub's
+    /// mcache, or `None` before any was asked for. A low rate suggests a longer mcache history.
+    pub fn mcache_hit_rate(&self) -> Option<f64> {
+        let requested = self.iwant_requested_messages();
+        if requested == 0 {
+            return None;
+        }
+        Some(self.iwant_served_messages() as f64 / requested as f64)
+    }
+
+    /// Returns the number of peers added to our gossipsub meshes.
+    pub fn mesh_grafts(&self) -> usize {
+        self.mesh_grafts.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of peers removed from our gossipsub meshes.
+    pub fn mesh_prunes(&self) -> usize {
+        self.mesh_prunes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of GRAFTs sent opportunistically.
+    pub fn opportunistic_grafts(&self) -> usize {
+        self.opportunistic_grafts.load(Ordering::Relaxed)
+    }
+
+    /// Returns the RPC substreams open to each connected peer.
+    pub fn rpc_substreams(&self) -> HashMap<PeerId, SubstreamCounts> {
+        self.rpc_substreams.read().clone()
+    }
+
+    /// Returns the number of RPC substreams open over all peers.
+    pub fn open_rpc_substreams(&self) -> SubstreamCounts {
+        self.rpc_substreams.read().values().copied().sum()
+    }
+
+    /// Returns the number of RPC substreams force closed for exceeding the maximum age.
+    pub fn force_closed_substreams(&self) -> usize {
+        self.force_closed_substreams.load(Ordering::Relaxed)
+    }
+
+    /// Records the propagation latency of a gossip message on `topic`.
+    pub fn observe_gossip_latency(&self, topic: &str, latency: Duration) {
+        self.gossip_latency
+            .write()
+            .entry(topic.to_string())
+            .or_default()
+            .observe(latency);
+    }
+
+    /// Returns the gossip propagation latency histograms, per topic.
+    pub fn gossip_latency(&self) -> HashMap<String, Histogram> {
+        self.gossip_latency.read().clone()
+    }
+
+    /// Returns the number of times our own peer id or ENR was dropped instead of being dialed.
+    pub fn self_dials_rejected(&self) -> usize {
+        self.self_dials_rejected.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of inbound peers disconnected to admit a more valuable peer.
+    pub fn preempted_peers(&self) -> usize {
+        self.preempted_peers.load(Ordering::Relaxed)
+    }
+
+    /// Returns the connected peers of each subnet with a peer quota, and whether the quota could
+    /// be met.
+    pub fn subnet_peer_counts(&self) -> HashMap<SubnetId, SubnetPeerCount> {
+        self.subnet_peers.read().clone()
+    }
+
+    /// Returns the number of gossipsub control messages sent and received, by kind. The counts
+    /// per peer are in the peer's `PeerInfo`.
+    pub fn gossip_control(&self) -> ControlStats {
+        *self.gossip_control.read()
+    }
+
     /// Returns a `Client` type if one is known for the `PeerId`.
     pub fn client(&self, peer_id: &PeerId) -> Client {
         self.peers
@@ -104,4 +535,171 @@ impl NetworkGlobals {
             .map(|info| info.client.clone())
             .unwrap_or_default()
     }
+
+    /// Returns the number of connected peers per client name and version.
+    pub fn client_counts(&self) -> BTreeMap<String, BTreeMap<String, usize>> {
+        self.peers.read().client_counts()
+    }
+
+    /// Returns the current values of the activity counters.
+    pub fn stats(&self) -> StatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    /// Returns the depth of the internal queues, to tell where messages build up under load.
+    pub fn queue_depths(&self) -> QueueDepthsSnapshot {
+        self.queue_depths.snapshot()
+    }
+
+    /// Returns the estimated bytes held by the caches, the peer database, the buffer pool and the
+    /// queues. The peer database and the pool are measured now, the others as of the last time
+    /// their owner was polled.
+    pub fn memory_usage(&self) -> MemoryUsageSnapshot {
+        let peer_db = self.peers.read().approximate_size();
+        let buffer_pool = self.buffer_pool.stats().pooled_bytes;
+        self.memory_usage.snapshot(peer_db, buffer_pool)
+    }
+
+    /// Returns the use of the buffer pool.
+    pub fn buffer_pool_stats(&self) -> BufferPoolStats {
+        self.buffer_pool.stats()
+    }
+
+    /// Returns the number of messages decrypted under each key generation, by encrypted topic.
+    pub fn topic_key_generations(&self) -> HashMap<String, BTreeMap<u64, usize>> {
+        self.topic_encryption.decrypted_by_generation()
+    }
+
+    /// Returns the sizes of the gossip messages received from all peers.
+    pub fn gossip_message_sizes(&self) -> SizeHistogram {
+        self.gossip_message_sizes.read().clone()
+    }
+
+    /// Returns the gossip messages each connected peer delivered first or duplicated. The
+    /// fraction delivered first is the peer's `GossipContribution::usefulness`.
+    pub fn gossip_contributions(&self) -> HashMap<PeerId, GossipContribution> {
+        self.peers
+            .read()
+            .connected_peers()
+            .map(|(peer_id, info)| (peer_id.clone(), info.gossip_contribution))
+            .collect()
+    }
+
+    /// Returns true if we are subscribed to `topic` and its mesh has reached a healthy size, so
+    /// that messages published on it are likely to propagate. A topic stays ready while
+    /// subscribed, even if its mesh shrinks again.
+    pub fn topic_ready(&self, topic: &str) -> bool {
+        self.ready_topics.read().contains(topic)
+    }
+
+    /// Returns the connected peers that told us they are subscribed to `topic`, whether or not
+    /// they are in our mesh for it.
+    pub fn peers_on_topic(&self, topic: &str) -> Vec<PeerId> {
+        self.peers.read().peers_on_topic(topic).cloned().collect()
+    }
+
+    /// Returns up to `n` connected peers chosen uniformly at random, in random order. With a
+    /// `topic`, only the peers that announced a gossipsub subscription to it are sampled.
+    pub fn random_peers(&self, n: usize, topic: Option<&str>) -> Vec<PeerId> {
+        let mut peers: Vec<PeerId> = match topic {
+            Some(topic) => self
+                .topic_peers
+                .read()
+                .get(&TopicHash::from_raw(topic))
+                .map(|peers| peers.iter().cloned().collect())
+                .unwrap_or_default(),
+            None => self.peers.read().connected_peer_ids().cloned().collect(),
+        };
+        peers.shuffle(&mut rand::thread_rng());
+        peers.truncate(n);
+        peers
+    }
+
+    /// Returns a page of the known peers matching `query`.
+    pub fn query_peers(&self, query: &PeerQuery) -> PeerQueryResult {
+        self.peers.read().query(query)
+    }
+
+    /// Returns the reputation of every known peer, broken down by the source of each change.
+    pub fn peer_scores(&self) -> Vec<PeerScoreSnapshot> {
+        self.peers.read().score_snapshot()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rpc::StatusMessage;
+    use crate::testing::network_globals;
+    use crate::{PeerId, TopicHash};
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_muted_messages_are_dropped() {
+        let globals = network_globals();
+        let muted = PeerId::random();
+        let other = PeerId::random();
+        globals.mute(muted.clone());
+
+        let mut messages = vec![1, 2];
+        globals.drop_muted(&other, &mut messages);
+        assert_eq!(messages, vec![1, 2]);
+        globals.drop_muted(&muted, &mut messages);
+        assert!(messages.is_empty());
+        assert_eq!(globals.muted_messages(), 2);
+
+        globals.unmute(&muted);
+        let mut messages = vec![3];
+        globals.drop_muted(&muted, &mut messages);
+        assert_eq!(messages, vec![3]);
+        assert_eq!(globals.muted_messages(), 2);
+    }
+
+    #[test]
+    fn test_random_peers_on_topic() {
+        let globals = network_globals();
+        let topic = "/mothra/blocks/ssz";
+        let subscribed: HashSet<PeerId> = (0..4).map(|_| PeerId::random()).collect();
+        globals
+            .topic_peers
+            .write()
+            .insert(TopicHash::from_raw(topic), subscribed.clone());
+
+        let sampled = globals.random_peers(2, Some(topic));
+        assert_eq!(sampled.len(), 2);
+        assert!(sampled.iter().all(|peer_id| subscribed.contains(peer_id)));
+        let sampled: HashSet<PeerId> = globals.random_peers(10, Some(topic)).into_iter().collect();
+        assert_eq!(sampled, subscribed);
+        // peers that announced no gossipsub subscription are never sampled
+        assert!(globals
+            .random_peers(10, Some("/mothra/other/ssz"))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_local_status_per_fork() {
+        let globals = network_globals();
+        *globals.fork_id.write() = vec![1, 0, 0, 0];
+        *globals.secondary_fork_id.write() = Some(vec![2, 0, 0, 0]);
+        let status = |fork: u8, head_slot| StatusMessage {
+            fork_digest: [fork, 0, 0, 0],
+            head_slot,
+            ..StatusMessage::default()
+        };
+        assert_eq!(globals.local_status(), None);
+
+        globals.set_local_status(status(2, 1));
+        // a status for another fork is the fallback
+        assert_eq!(globals.local_status(), Some(status(2, 1)));
+        globals.set_local_status(status(1, 1));
+        globals.set_local_status(status(1, 2));
+        assert_eq!(globals.local_status(), Some(status(1, 2)));
+        assert_eq!(globals.local_status_on(&[2, 0, 0, 0]), Some(status(2, 1)));
+        assert_eq!(globals.local_statuses.read().len(), 2);
+
+        // the status of a fork no longer served is dropped with the next one set
+        *globals.secondary_fork_id.write() = None;
+        globals.set_local_status(status(1, 3));
+        assert_eq!(globals.local_status_on(&[2, 0, 0, 0]), Some(status(1, 3)));
+        assert_eq!(globals.local_statuses.read().len(), 1);
+    }
 }
@@ -0,0 +1,135 @@
+//! Fixed-bucket histograms of durations and sizes.
+use serde_derive::Serialize;
+use std::time::Duration;
+
+/// The upper bounds, in milliseconds, of the histogram buckets. Larger values fall in a final,
+/// unbounded bucket.
+pub const LATENCY_BUCKETS_MS: [u64; 10] = [10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000];
+
+/// The upper bounds, in bytes, of the size histogram buckets. Larger sizes fall in a final,
+/// unbounded bucket.
+pub const SIZE_BUCKETS_BYTES: [u64; 8] = [
+    256, 1_024, 4_096, 16_384, 65_536, 262_144, 1_048_576, 4_194_304,
+];
+
+/// Counts observed durations per bucket of `LATENCY_BUCKETS_MS`.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Histogram {
+    /// The number of observations per bucket. The last entry counts the observations above the
+    /// largest bound.
+    pub buckets: [u64; LATENCY_BUCKETS_MS.len() + 1],
+    /// The number of observations.
+    pub count: u64,
+    /// The sum of the observed durations, in milliseconds.
+    pub sum_ms: u64,
+}
+
+impl Histogram {
+    /// Records a duration.
+    pub fn observe(&mut self, duration: Duration) {
+        let ms = duration.as_millis() as u64;
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|bound| ms <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum_ms = self.sum_ms.saturating_add(ms);
+    }
+
+    /// The mean of the observed durations.
+    pub fn mean(&self) -> Option<Duration> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(Duration::from_millis(self.sum_ms / self.count))
+        }
+    }
+
+    /// An upper bound of the `fraction` (0 to 1) percentile of the observed durations: the bound
+    /// of the bucket it falls in. `None` if nothing was observed or the percentile is above the
+    /// largest bound.
+    pub fn percentile(&self, fraction: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        // the rank of the observation at the percentile, counting from 1
+        let rank = ((fraction.max(0.0).min(1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return LATENCY_BUCKETS_MS
+                    .get(bucket)
+                    .map(|bound| Duration::from_millis(*bound));
+            }
+        }
+        None
+    }
+}
+
+/// Counts observed sizes per bucket of `SIZE_BUCKETS_BYTES`.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct SizeHistogram {
+    /// The number of observations per bucket. The last entry counts the observations above the
+    /// largest bound.
+    pub buckets: [u64; SIZE_BUCKETS_BYTES.len() + 1],
+    /// The number of observations.
+    pub count: u64,
+    /// The sum of the observed sizes, in bytes.
+    pub sum_bytes: u64,
+}
+
+impl SizeHistogram {
+    /// Records a size in bytes.
+    pub fn observe(&mut self, bytes: usize) {
+        let bytes = bytes as u64;
+        let bucket = SIZE_BUCKETS_BYTES
+            .iter()
+            .position(|bound| bytes <= *bound)
+            .unwrap_or(SIZE_BUCKETS_BYTES.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum_bytes = self.sum_bytes.saturating_add(bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_buckets() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.mean(), None);
+        assert_eq!(histogram.percentile(0.5), None);
+        // a bound belongs to its own bucket
+        for ms in &[0, 10, 11, 250, 10_000, 10_001] {
+            histogram.observe(Duration::from_millis(*ms));
+        }
+        assert_eq!(histogram.buckets, [2, 1, 0, 0, 1, 0, 0, 0, 0, 1, 1]);
+        assert_eq!(histogram.count, 6);
+        assert_eq!(histogram.mean(), Some(Duration::from_millis(3_378)));
+
+        assert_eq!(histogram.percentile(0.0), Some(Duration::from_millis(10)));
+        assert_eq!(histogram.percentile(0.5), Some(Duration::from_millis(25)));
+        assert_eq!(histogram.percentile(0.6), Some(Duration::from_millis(250)));
+        assert_eq!(
+            histogram.percentile(0.8),
+            Some(Duration::from_millis(10_000))
+        );
+        // above the largest bound
+        assert_eq!(histogram.percentile(1.0), None);
+    }
+
+    #[test]
+    fn test_size_buckets() {
+        let mut histogram = SizeHistogram::default();
+        for bytes in &[0, 256, 257, 4_194_304, 4_194_305] {
+            histogram.observe(*bytes);
+        }
+        assert_eq!(histogram.buckets, [2, 1, 0, 0, 0, 0, 0, 1, 1]);
+        assert_eq!(histogram.count, 5);
+        assert_eq!(histogram.sum_bytes, 8_389_122);
+    }
+}
@@ -0,0 +1,164 @@
+//! Approximate memory held by the caches and queues that grow with traffic and peers, for planning
+//! the capacity of a node and spotting leaks.
+//!
+//! The figures are estimates: an entry counts its own size, the data it owns on the heap and a
+//! fixed overhead for the collection holding it. Allocator overhead and spare capacity are not
+//! counted, so the totals are lower bounds that are mostly useful for how they change over time.
+use serde_derive::Serialize;
+use std::collections::VecDeque;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// The bookkeeping of an entry of an `LruCache`: its list pointers and its slot in the map.
+pub const LRU_ENTRY_OVERHEAD: usize = 4 * size_of::<usize>();
+
+/// The estimated bytes held by each cache and queue, as of the last time its owner was polled.
+/// Use `snapshot` to read them together.
+#[derive(Debug, Default)]
+pub struct MemoryUsage {
+    /// The ids of the gossip messages received and published directly.
+    pub seen_message_ids: AtomicUsize,
+    /// The application level keys of the gossip messages delivered, if deduplicated.
+    pub seen_message_keys: AtomicUsize,
+    /// The messages gossipsub keeps to answer requests for them.
+    pub mcache: AtomicUsize,
+    /// The events and messages waiting in the behaviour.
+    pub behaviour_queues: AtomicUsize,
+    /// The messages waiting between the application and the network service.
+    pub service_queues: AtomicUsize,
+}
+
+/// The estimated bytes held by each cache and queue at one point in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct MemoryUsageSnapshot {
+    pub seen_message_ids: usize,
+    pub seen_message_keys: usize,
+    /// The peers in the peer database and what is known of them.
+    pub peer_db: usize,
+    /// The buffers kept for reuse by the buffer pool.
+    pub buffer_pool: usize,
+    pub mcache: usize,
+    pub behaviour_queues: usize,
+    pub service_queues: usize,
+}
+
+impl MemoryUsageSnapshot {
+    /// Returns the bytes held by all the caches and queues.
+    pub fn total(&self) -> usize {
+        self.seen_message_ids
+            + self.seen_message_keys
+            + self.peer_db
+            + self.buffer_pool
+            + self.mcache
+            + self.behaviour_queues
+            + self.service_queues
+    }
+}
+
+impl MemoryUsage {
+    /// Records the current estimate of a cache or queue.
+    pub fn set(usage: &AtomicUsize, bytes: usize) {
+        usage.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Reads every estimate. The peer database and the buffer pool are measured by the caller,
+    /// when it is read.
+    pub fn snapshot(&self, peer_db: usize, buffer_pool: usize) -> MemoryUsageSnapshot {
+        MemoryUsageSnapshot {
+            seen_message_ids: self.seen_message_ids.load(Ordering::Relaxed),
+            seen_message_keys: self.seen_message_keys.load(Ordering::Relaxed),
+            peer_db,
+            buffer_pool,
+            mcache: self.mcache.load(Ordering::Relaxed),
+            behaviour_queues: self.behaviour_queues.load(Ordering::Relaxed),
+            service_queues: self.service_queues.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The mean size of the heap data of the entries put in a collection, for estimating it from its
+/// length without visiting every entry.
+#[derive(Debug, Default)]
+pub struct MeanSize {
+    total: u64,
+    count: u64,
+}
+
+impl MeanSize {
+    pub fn add(&mut self, bytes: usize) {
+        self.total += bytes as u64;
+        self.count += 1;
+    }
+
+    /// Returns the estimated bytes of `len` entries, each taking `entry_size` bytes besides its
+    /// heap data.
+    pub fn estimate(&self, len: usize, entry_size: usize) -> usize {
+        let mean = self.total.checked_div(self.count).unwrap_or_default() as usize;
+        len * (entry_size + mean)
+    }
+}
+
+/// Estimates the size of gossipsub's message cache, which is not visible outside of it, from the
+/// messages received and published within its history.
+pub struct McacheEstimate {
+    /// How long gossipsub keeps a message: its history length in heartbeats.
+    history: Duration,
+    /// When each message in the cache was added and its size.
+    messages: VecDeque<(Instant, usize)>,
+    bytes: usize,
+}
+
+impl McacheEstimate {
+    pub fn new(history: Duration) -> Self {
+        McacheEstimate {
+            history,
+            messages: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    /// Counts a message of `bytes` bytes, including its id and topics, added to the cache.
+    pub fn add(&mut self, now: Instant, bytes: usize) {
+        self.messages.push_back((now, bytes));
+        self.bytes += bytes;
+    }
+
+    /// Returns the bytes of the messages still in the cache at `now`.
+    pub fn bytes(&mut self, now: Instant) -> usize {
+        while let Some((added, bytes)) = self.messages.front().copied() {
+            if now.saturating_duration_since(added) < self.history {
+                break;
+            }
+            self.messages.pop_front();
+            self.bytes -= bytes;
+        }
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mean_size() {
+        let mut sizes = MeanSize::default();
+        assert_eq!(sizes.estimate(10, 8), 80);
+        sizes.add(10);
+        sizes.add(20);
+        assert_eq!(sizes.estimate(10, 8), 230);
+    }
+
+    #[test]
+    fn test_mcache_expires_messages() {
+        let history = Duration::from_secs(5);
+        let mut mcache = McacheEstimate::new(history);
+        let now = Instant::now();
+        mcache.add(now, 100);
+        mcache.add(now + Duration::from_secs(2), 50);
+        assert_eq!(mcache.bytes(now + Duration::from_secs(4)), 150);
+        assert_eq!(mcache.bytes(now + history), 50);
+        assert_eq!(mcache.bytes(now + 2 * history), 0);
+    }
+}
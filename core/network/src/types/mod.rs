@@ -1,9 +1,40 @@
+mod bridge;
+mod buffer_pool;
+mod contribution;
+mod control;
+mod encryption;
 pub mod error;
+mod forks;
 mod globals;
+mod histogram;
+mod memory;
+mod ping;
+mod queues;
+mod stats;
 mod topics;
+mod transform;
 
-pub use globals::NetworkGlobals;
-pub use topics::GossipTopic;
+pub use bridge::{
+    bridged_copy, open_bridged, seal_bridged, Bridge, BridgeTransform, BridgedCopy, TopicBridges,
+};
+pub use buffer_pool::{
+    BufferPool, BufferPoolStats, DEFAULT_BUFFER_POOL_SIZE, DEFAULT_MAX_POOLED_BUFFER_SIZE,
+};
+pub use contribution::GossipContribution;
+pub use control::{ControlCounts, ControlStats, PendingIwants};
+pub use encryption::{TopicCipher, TopicEncryption, TopicKey, TOPIC_KEY_LENGTH};
+pub use forks::{ActiveForks, ForkRelay};
+pub use globals::{NetworkGlobals, NetworkGlobalsParams};
+pub use histogram::{Histogram, SizeHistogram, LATENCY_BUCKETS_MS, SIZE_BUCKETS_BYTES};
+pub use memory::{McacheEstimate, MeanSize, MemoryUsage, MemoryUsageSnapshot, LRU_ENTRY_OVERHEAD};
+pub use ping::{PingHandler, PingPayloadFn, PingReceivedFn};
+pub use queues::{QueueDepths, QueueDepthsSnapshot};
+pub use stats::{Stats, StatsSnapshot};
+pub use topics::{
+    DecodedTopic, GossipTopic, TopicValidator, TopicValidatorFn, UnsubscribedTopicPolicy,
+    FORK_PLACEHOLDER,
+};
+pub use transform::{MessageTransform, MessageTransforms};
 
 #[allow(type_alias_bounds)]
 pub type EnrBitfield = Vec<u8>;
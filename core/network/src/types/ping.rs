@@ -0,0 +1,79 @@
+//! Application defined payloads for the Ping and Pong messages.
+//!
+//! By default every Ping and Pong carries the `ping_data` given when the network starts. An
+//! application whose ping carries state that changes, such as the sequence number of its
+//! metadata, registers a `PingHandler` instead: the payload is then produced for each message
+//! sent, and the payload of each message received is interpreted by the application. A sequence
+//! number found in a received payload is handed to the peer manager, which requests the peer's
+//! metadata when it is newer than the one known or already requested. The metadata received is
+//! recorded under the sequence number it was requested for, so a peer repeating the same sequence
+//! number is not asked for its metadata again.
+use crate::PeerId;
+use std::fmt;
+use std::sync::Arc;
+
+/// Produces the payload of a Ping sent to a peer, or of the Pong answering its Ping.
+pub type PingPayloadFn = Arc<dyn Fn(&PeerId) -> Vec<u8> + Send + Sync>;
+
+/// Interprets the payload of a Ping or Pong received from a peer, returning the metadata
+/// sequence number it carries, if any.
+pub type PingReceivedFn = Arc<dyn Fn(&PeerId, &[u8]) -> Option<u64> + Send + Sync>;
+
+/// The application's handling of Ping and Pong payloads.
+#[derive(Clone)]
+pub struct PingHandler {
+    payload: PingPayloadFn,
+    received: PingReceivedFn,
+}
+
+impl PingHandler {
+    /// Creates a handler producing payloads with `payload` and reading the metadata sequence
+    /// number of received payloads with `received`.
+    pub fn new(payload: PingPayloadFn, received: PingReceivedFn) -> Self {
+        PingHandler { payload, received }
+    }
+
+    /// The payload to send to `peer_id`.
+    pub fn payload(&self, peer_id: &PeerId) -> Vec<u8> {
+        (self.payload)(peer_id)
+    }
+
+    /// Interprets the payload received from `peer_id`.
+    pub fn received(&self, peer_id: &PeerId, payload: &[u8]) -> Option<u64> {
+        (self.received)(peer_id, payload)
+    }
+}
+
+impl fmt::Debug for PingHandler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("PingHandler")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[test]
+    fn test_payload_follows_state() {
+        let seq = Arc::new(AtomicU64::new(1));
+        let local_seq = seq.clone();
+        let handler = PingHandler::new(
+            Arc::new(move |_peer_id: &PeerId| {
+                local_seq.load(Ordering::Relaxed).to_le_bytes().to_vec()
+            }),
+            Arc::new(|_peer_id: &PeerId, payload: &[u8]| {
+                let mut seq = [0; 8];
+                seq.copy_from_slice(payload.get(..8)?);
+                Some(u64::from_le_bytes(seq))
+            }),
+        );
+        let peer_id = PeerId::random();
+        assert_eq!(handler.payload(&peer_id), 1u64.to_le_bytes().to_vec());
+        seq.store(7, Ordering::Relaxed);
+        let payload = handler.payload(&peer_id);
+        assert_eq!(handler.received(&peer_id, &payload), Some(7));
+        assert_eq!(handler.received(&peer_id, &[1, 2]), None);
+    }
+}
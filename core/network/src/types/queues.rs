@@ -0,0 +1,48 @@
+//! The depth of the queues between the parts of the network service, for finding where messages
+//! build up under load.
+use serde_derive::Serialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The number of items waiting in each internal queue, as of the last time its owner was polled.
+/// Use `snapshot` to read them together.
+#[derive(Debug, Default)]
+pub struct QueueDepths {
+    /// Events of the behaviour waiting to be handed to the swarm.
+    pub behaviour_events: AtomicUsize,
+    /// Peers waiting for their handlers to be told to disconnect.
+    pub peers_to_dc: AtomicUsize,
+    /// RPC requests, responses and errors waiting in the RPC behaviour.
+    pub rpc_events: AtomicUsize,
+    /// Messages from the application waiting to be handled by the network service.
+    pub network_messages: AtomicUsize,
+    /// Received messages waiting to be handed to the application.
+    pub deliveries: AtomicUsize,
+}
+
+/// The depths of the internal queues at one point in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct QueueDepthsSnapshot {
+    pub behaviour_events: usize,
+    pub peers_to_dc: usize,
+    pub rpc_events: usize,
+    pub network_messages: usize,
+    pub deliveries: usize,
+}
+
+impl QueueDepths {
+    /// Records the current depth of a queue.
+    pub fn set(depth: &AtomicUsize, len: usize) {
+        depth.store(len, Ordering::Relaxed);
+    }
+
+    /// Reads every depth.
+    pub fn snapshot(&self) -> QueueDepthsSnapshot {
+        QueueDepthsSnapshot {
+            behaviour_events: self.behaviour_events.load(Ordering::Relaxed),
+            peers_to_dc: self.peers_to_dc.load(Ordering::Relaxed),
+            rpc_events: self.rpc_events.load(Ordering::Relaxed),
+            network_messages: self.network_messages.load(Ordering::Relaxed),
+            deliveries: self.deliveries.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! Counters of network activity, readable by the application without a metrics server.
+use serde_derive::Serialize;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// Counters updated by the behaviour as the node runs. Use `snapshot` to read them together.
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// Gossip messages received and passed on to the application.
+    pub gossip_received: AtomicUsize,
+    /// The total size of the gossip messages received.
+    pub gossip_received_bytes: AtomicU64,
+    /// Gossip messages published by this node, through the mesh or directly to peers.
+    pub gossip_published: AtomicUsize,
+    /// The total size of the gossip messages published.
+    pub gossip_published_bytes: AtomicU64,
+    /// RPC requests sent to peers.
+    pub rpc_requests_sent: AtomicUsize,
+    /// RPC requests received from peers.
+    pub rpc_requests_received: AtomicUsize,
+    /// RPC responses sent to peers.
+    pub rpc_responses_sent: AtomicUsize,
+    /// RPC responses received from peers.
+    pub rpc_responses_received: AtomicUsize,
+    /// RPC requests that failed, sent or received.
+    pub rpc_errors: AtomicUsize,
+    /// Peers that connected.
+    pub peers_connected: AtomicUsize,
+    /// Peers that disconnected.
+    pub peers_disconnected: AtomicUsize,
+    /// Dials to peers that failed.
+    pub dial_failures: AtomicUsize,
+}
+
+/// The values of the `Stats` counters at one point in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct StatsSnapshot {
+    pub gossip_received: usize,
+    pub gossip_received_bytes: u64,
+    pub gossip_published: usize,
+    pub gossip_published_bytes: u64,
+    pub rpc_requests_sent: usize,
+    pub rpc_requests_received: usize,
+    pub rpc_responses_sent: usize,
+    pub rpc_responses_received: usize,
+    pub rpc_errors: usize,
+    pub peers_connected: usize,
+    pub peers_disconnected: usize,
+    pub dial_failures: usize,
+}
+
+impl Stats {
+    /// Adds one to a counter.
+    pub fn increment(counter: &AtomicUsize) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a gossip message of `bytes` bytes received.
+    pub fn gossip_received(&self, bytes: usize) {
+        Stats::increment(&self.gossip_received);
+        self.gossip_received_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a gossip message of `bytes` bytes published.
+    pub fn gossip_published(&self, bytes: usize) {
+        Stats::increment(&self.gossip_published);
+        self.gossip_published_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Reads every counter.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            gossip_received: self.gossip_received.load(Ordering::Relaxed),
+            gossip_received_bytes: self.gossip_received_bytes.load(Ordering::Relaxed),
+            gossip_published: self.gossip_published.load(Ordering::Relaxed),
+            gossip_published_bytes: self.gossip_published_bytes.load(Ordering::Relaxed),
+            rpc_requests_sent: self.rpc_requests_sent.load(Ordering::Relaxed),
+            rpc_requests_received: self.rpc_requests_received.load(Ordering::Relaxed),
+            rpc_responses_sent: self.rpc_responses_sent.load(Ordering::Relaxed),
+            rpc_responses_received: self.rpc_responses_received.load(Ordering::Relaxed),
+            rpc_errors: self.rpc_errors.load(Ordering::Relaxed),
+            peers_connected: self.peers_connected.load(Ordering::Relaxed),
+            peers_disconnected: self.peers_disconnected.load(Ordering::Relaxed),
+            dial_failures: self.dial_failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        let stats = Stats::default();
+        stats.gossip_received(100);
+        stats.gossip_received(20);
+        stats.gossip_published(7);
+        Stats::increment(&stats.rpc_errors);
+
+        assert_eq!(
+            stats.snapshot(),
+            StatsSnapshot {
+                gossip_received: 2,
+                gossip_received_bytes: 120,
+                gossip_published: 1,
+                gossip_published_bytes: 7,
+                rpc_errors: 1,
+                ..Default::default()
+            }
+        );
+    }
+}
@@ -1,15 +1,207 @@
-use libp2p::gossipsub::Topic;
+use libp2p::gossipsub::{Topic, TopicHash};
+use regex::Regex;
 use serde_derive::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
 
+/// Stands for the fork id in a fork-scoped topic, for example `/eth2/{fork}/beacon_block`. The node
+/// joins the topic once for each fork it serves, with the placeholder replaced by the hex encoded
+/// fork id.
+pub const FORK_PLACEHOLDER: &str = "{fork}";
+
+/// A gossipsub topic mothra can subscribe and publish to.
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub struct GossipTopic {
     topic: String,
 }
 
+/// A topic received on the wire. Topics that are not valid `GossipTopic`s, such as those of
+/// other networks, are kept verbatim so they can still be reported.
+#[derive(Clone, Debug, Serialize, PartialEq, Eq, Hash)]
+pub enum DecodedTopic {
+    Known(GossipTopic),
+    Unknown(String),
+}
+
 impl GossipTopic {
     pub fn new(topic: String) -> Self {
         GossipTopic { topic }
     }
+
+    /// The hash gossipsub identifies the topic by. Topics are not hashed by mothra's gossipsub
+    /// configuration.
+    pub fn hash(&self) -> TopicHash {
+        Topic::new(self.topic.clone()).no_hash()
+    }
+
+    /// The topic string.
+    pub fn as_str(&self) -> &str {
+        &self.topic
+    }
+
+    /// Whether the topic contains the fork placeholder, and so stands for one topic per fork.
+    pub fn is_fork_scoped(&self) -> bool {
+        self.topic.contains(FORK_PLACEHOLDER)
+    }
+
+    /// The topic of the given fork, with the fork placeholder replaced by the hex encoded fork id.
+    /// Topics that are not fork-scoped are returned unchanged.
+    pub fn for_fork(&self, fork_id: &[u8]) -> GossipTopic {
+        GossipTopic::new(self.topic.replace(FORK_PLACEHOLDER, &hex::encode(fork_id)))
+    }
+}
+
+/// Parses a topic, rejecting empty topics and topics containing whitespace or control
+/// characters.
+impl FromStr for GossipTopic {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("Empty topic".into());
+        }
+        if s.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return Err(format!("Invalid character in topic: {:?}", s));
+        }
+        Ok(GossipTopic::new(s.to_string()))
+    }
+}
+
+impl fmt::Display for GossipTopic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.topic)
+    }
+}
+
+impl DecodedTopic {
+    /// The topic string exactly as it was received.
+    pub fn as_str(&self) -> &str {
+        match self {
+            DecodedTopic::Known(topic) => topic.as_str(),
+            DecodedTopic::Unknown(topic) => topic,
+        }
+    }
+
+    /// Returns the topic if it is a valid `GossipTopic`.
+    pub fn known(&self) -> Option<&GossipTopic> {
+        match self {
+            DecodedTopic::Known(topic) => Some(topic),
+            DecodedTopic::Unknown(_) => None,
+        }
+    }
+}
+
+impl From<&TopicHash> for DecodedTopic {
+    fn from(hash: &TopicHash) -> Self {
+        // topics are not hashed, so the hash is the topic itself
+        match hash.as_str().parse() {
+            Ok(topic) => DecodedTopic::Known(topic),
+            Err(_) => DecodedTopic::Unknown(hash.as_str().to_string()),
+        }
+    }
+}
+
+impl fmt::Display for DecodedTopic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// What to do with a gossipsub message that arrives on a topic we are not subscribed to.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum UnsubscribedTopicPolicy {
+    /// Deliver the message to the application like any other message.
+    Deliver,
+    /// Silently drop the message, or the unsubscribed topics of a message sent to several topics.
+    Drop,
+    /// Drop the unsubscribed topics as with `Drop`, and lower the reputation of the peer that
+    /// forwarded the message.
+    Penalize,
+}
+
+impl UnsubscribedTopicPolicy {
+    /// Removes the topics of a received message we are not subscribed to, unless they are
+    /// delivered, and returns them. The message is delivered on the topics left, if any.
+    pub fn filter(
+        self,
+        topics: &mut Vec<TopicHash>,
+        subscribed: &HashSet<TopicHash>,
+    ) -> Vec<TopicHash> {
+        if self == UnsubscribedTopicPolicy::Deliver {
+            return Vec::new();
+        }
+        let (kept, unsubscribed) = topics
+            .drain(..)
+            .partition(|topic| subscribed.contains(topic));
+        *topics = kept;
+        unsubscribed
+    }
+}
+
+impl Default for UnsubscribedTopicPolicy {
+    fn default() -> Self {
+        UnsubscribedTopicPolicy::Deliver
+    }
+}
+
+impl FromStr for UnsubscribedTopicPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "deliver" => Ok(UnsubscribedTopicPolicy::Deliver),
+            "drop" => Ok(UnsubscribedTopicPolicy::Drop),
+            "penalize" => Ok(UnsubscribedTopicPolicy::Penalize),
+            _ => Err(format!("Unknown unsubscribed topic policy: {}", s)),
+        }
+    }
+}
+
+/// Checks a topic name, returning why it is rejected.
+pub type TopicValidatorFn = Arc<dyn Fn(&str) -> Result<(), String> + Send + Sync>;
+
+/// The application's rule for topic names. It is applied to the topics subscribed and published
+/// to, once the fork is filled in, and to the topics peers announce, so that a misnamed topic is
+/// rejected up front rather than joined or published to.
+#[derive(Clone)]
+pub struct TopicValidator {
+    validate: TopicValidatorFn,
+}
+
+impl TopicValidator {
+    pub fn new(validate: TopicValidatorFn) -> Self {
+        TopicValidator { validate }
+    }
+
+    /// Accepts the topics matching `pattern` in full.
+    pub fn pattern(pattern: &str) -> Result<Self, String> {
+        let regex = Regex::new(&format!("^(?:{})$", pattern))
+            .map_err(|e| format!("Invalid topic pattern: {}", e))?;
+        let pattern = pattern.to_string();
+        Ok(TopicValidator::new(Arc::new(move |topic: &str| {
+            if regex.is_match(topic) {
+                Ok(())
+            } else {
+                Err(format!(
+                    "Topic {} does not match the pattern {}",
+                    topic, pattern
+                ))
+            }
+        })))
+    }
+
+    /// Checks the topic name.
+    pub fn validate(&self, topic: &str) -> Result<(), String> {
+        (self.validate)(topic)
+    }
+}
+
+impl fmt::Debug for TopicValidator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("TopicValidator")
+    }
 }
 
 impl Into<Topic> for GossipTopic {
@@ -23,3 +215,90 @@ impl Into<String> for GossipTopic {
         self.topic
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_is_lossless() {
+        let known = TopicHash::from_raw("/mothra/blocks/ssz");
+        let decoded = DecodedTopic::from(&known);
+        assert_eq!(
+            decoded.known(),
+            Some(&GossipTopic::new("/mothra/blocks/ssz".into()))
+        );
+        assert_eq!(decoded.as_str(), known.as_str());
+
+        for raw in &["", "foreign topic", "tab\there"] {
+            let decoded = DecodedTopic::from(&TopicHash::from_raw(*raw));
+            assert_eq!(decoded, DecodedTopic::Unknown(raw.to_string()));
+            assert_eq!(decoded.to_string(), *raw);
+        }
+    }
+
+    #[test]
+    fn test_unsubscribed_topic_policy() {
+        let hash = |topic: &str| TopicHash::from_raw(topic);
+        let subscribed: HashSet<TopicHash> = vec![hash("/mothra/blocks")].into_iter().collect();
+        let message = || vec![hash("/mothra/blocks"), hash("/mothra/other")];
+
+        let mut topics = message();
+        assert!(UnsubscribedTopicPolicy::Deliver
+            .filter(&mut topics, &subscribed)
+            .is_empty());
+        assert_eq!(topics, message());
+
+        // both policies keep the subscribed topics and only report the others
+        for policy in &[
+            UnsubscribedTopicPolicy::Drop,
+            UnsubscribedTopicPolicy::Penalize,
+        ] {
+            let mut topics = message();
+            let unsubscribed = policy.filter(&mut topics, &subscribed);
+            assert_eq!(topics, vec![hash("/mothra/blocks")]);
+            assert_eq!(unsubscribed, vec![hash("/mothra/other")]);
+
+            let mut topics = vec![hash("/mothra/other")];
+            assert_eq!(policy.filter(&mut topics, &subscribed).len(), 1);
+            assert!(topics.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_for_fork() {
+        let topic: GossipTopic = "/mothra/{fork}/blocks".parse().unwrap();
+        assert!(topic.is_fork_scoped());
+        assert_eq!(
+            topic.for_fork(&[0xab, 0x01]).as_str(),
+            "/mothra/ab01/blocks"
+        );
+        assert!(!topic.for_fork(&[0xab]).is_fork_scoped());
+
+        let shared = GossipTopic::new("/mothra/blocks".into());
+        assert_eq!(shared.for_fork(&[0xab]), shared);
+    }
+
+    #[test]
+    fn test_topic_validator() {
+        let validator = TopicValidator::pattern("/mothra/[0-9a-f]+/[a-z_]+").unwrap();
+        assert!(validator.validate("/mothra/ab01/blocks").is_ok());
+        // the whole topic must match
+        assert!(validator.validate("/mothra/ab01/blocks/ssz").is_err());
+        assert!(validator.validate("/mothra/ab01/blcoks!").is_err());
+        assert!(TopicValidator::pattern("(").is_err());
+
+        let validator = TopicValidator::new(Arc::new(|topic: &str| {
+            if topic.len() <= 16 {
+                Ok(())
+            } else {
+                Err("Topic too long".into())
+            }
+        }));
+        assert!(validator.validate("/mothra/blocks").is_ok());
+        assert_eq!(
+            validator.validate("/mothra/attestations"),
+            Err("Topic too long".into())
+        );
+    }
+}
@@ -0,0 +1,111 @@
+//! Transformations applied to the data of gossip messages on specific topics.
+//!
+//! Applications register transforms per topic, for instance to compress, encrypt or sign the
+//! messages of that topic. On publish the transforms of the topic run in the order they were
+//! registered; on receive they are reversed in the opposite order, before the message reaches the
+//! application. Messages that cannot be reversed are dropped.
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// A reversible transformation of message data.
+pub trait MessageTransform: Send + Sync {
+    /// Transforms the data of a message published on `topic`.
+    fn outbound(&self, topic: &str, data: Vec<u8>) -> Result<Vec<u8>, String>;
+
+    /// Reverses `outbound` on the data of a message received on `topic`.
+    fn inbound(&self, topic: &str, data: Vec<u8>) -> Result<Vec<u8>, String>;
+}
+
+/// The transforms registered for each topic.
+#[derive(Clone, Default)]
+pub struct MessageTransforms {
+    transforms: HashMap<String, Vec<Arc<dyn MessageTransform>>>,
+}
+
+impl MessageTransforms {
+    /// Adds a transform to the messages of `topic`, after those already registered for it.
+    pub fn register(&mut self, topic: impl Into<String>, transform: Arc<dyn MessageTransform>) {
+        self.transforms
+            .entry(topic.into())
+            .or_default()
+            .push(transform);
+    }
+
+    /// Returns true if no transforms are registered.
+    pub fn is_empty(&self) -> bool {
+        self.transforms.is_empty()
+    }
+
+    /// Applies the transforms of `topic` to a message being published.
+    pub fn outbound(&self, topic: &str, data: Vec<u8>) -> Result<Vec<u8>, String> {
+        match self.transforms.get(topic) {
+            Some(transforms) => transforms
+                .iter()
+                .try_fold(data, |data, transform| transform.outbound(topic, data)),
+            None => Ok(data),
+        }
+    }
+
+    /// Reverses the transforms of `topic` on a received message.
+    pub fn inbound(&self, topic: &str, data: Vec<u8>) -> Result<Vec<u8>, String> {
+        match self.transforms.get(topic) {
+            Some(transforms) => transforms
+                .iter()
+                .rev()
+                .try_fold(data, |data, transform| transform.inbound(topic, data)),
+            None => Ok(data),
+        }
+    }
+}
+
+impl fmt::Debug for MessageTransforms {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map()
+            .entries(
+                self.transforms
+                    .iter()
+                    .map(|(topic, transforms)| (topic, transforms.len())),
+            )
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Appends a byte on publish and checks and strips it on receive.
+    struct Suffix(u8);
+
+    impl MessageTransform for Suffix {
+        fn outbound(&self, _topic: &str, mut data: Vec<u8>) -> Result<Vec<u8>, String> {
+            data.push(self.0);
+            Ok(data)
+        }
+
+        fn inbound(&self, _topic: &str, mut data: Vec<u8>) -> Result<Vec<u8>, String> {
+            match data.pop() {
+                Some(byte) if byte == self.0 => Ok(data),
+                _ => Err(format!("Missing suffix {}", self.0)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_transforms_are_reversed() {
+        let mut transforms = MessageTransforms::default();
+        transforms.register("topic", Arc::new(Suffix(1)));
+        transforms.register("topic", Arc::new(Suffix(2)));
+
+        let published = transforms.outbound("topic", vec![0]).unwrap();
+        assert_eq!(published, vec![0, 1, 2]);
+        assert_eq!(transforms.inbound("topic", published).unwrap(), vec![0]);
+
+        // other topics are left alone
+        assert_eq!(transforms.outbound("other", vec![0]).unwrap(), vec![0]);
+        assert_eq!(transforms.inbound("other", vec![0]).unwrap(), vec![0]);
+
+        assert!(transforms.inbound("topic", vec![0, 2, 1]).is_err());
+    }
+}
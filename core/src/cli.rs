@@ -1,4 +1,11 @@
-use clap::{App, AppSettings, Arg, ArgMatches};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+/// The exit code returned when a subcommand succeeds.
+pub const EXIT_SUCCESS: i32 = 0;
+/// The exit code returned when a subcommand fails.
+pub const EXIT_FAILURE: i32 = 1;
+/// The exit code returned when the subcommand arguments are invalid.
+pub const EXIT_INVALID_ARGS: i32 = 2;
 
 pub fn cli_app<'a, 'b>() -> App<'a, 'b> {
     App::new("mothra")
@@ -41,6 +48,29 @@ pub fn cli_app<'a, 'b>() -> App<'a, 'b> {
             .help("The discovery UDP port.")
             .takes_value(true),
     )
+    .arg(
+        Arg::with_name("ws-port")
+            .long("ws-port")
+            .value_name("PORT")
+            .help("The TCP port to accept WebSocket connections on, such as those of browser peers. Secure WebSockets are accepted instead when --ws-tls-cert and --ws-tls-key are given.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("ws-tls-cert")
+            .long("ws-tls-cert")
+            .value_name("PATH")
+            .help("The PEM or DER encoded TLS certificate chain of the secure WebSocket listener.")
+            .requires("ws-tls-key")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("ws-tls-key")
+            .long("ws-tls-key")
+            .value_name("PATH")
+            .help("The PEM or DER encoded TLS private key of the secure WebSocket listener.")
+            .requires("ws-tls-cert")
+            .takes_value(true),
+    )
     .arg(
         Arg::with_name("maxpeers")
             .long("maxpeers")
@@ -48,6 +78,97 @@ pub fn cli_app<'a, 'b>() -> App<'a, 'b> {
             .default_value("50")
             .takes_value(true),
     )
+    .arg(
+        Arg::with_name("agent-allow")
+            .long("agent-allow")
+            .value_name("REGEX")
+            .help("Only stay connected to peers whose identify agent string matches. May be repeated.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+    )
+    .arg(
+        Arg::with_name("agent-deny")
+            .long("agent-deny")
+            .value_name("REGEX")
+            .help("Disconnect and penalize peers whose identify agent string matches. May be repeated.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+    )
+    .arg(
+        Arg::with_name("strict-protocol")
+            .long("strict-protocol")
+            .value_name("PREFIX")
+            .help("Disconnect and penalize peers whose identify protocol version does not start with PREFIX, by default the part of our protocol version up to its last /, e.g. mothra/.")
+            .takes_value(true)
+            .min_values(0)
+            .max_values(1),
+    )
+    .arg(
+        Arg::with_name("max-disconnected-peers")
+            .long("max-disconnected-peers")
+            .value_name("COUNT")
+            .help("The maximum number of disconnected peers to remember.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("max-pending-dials")
+            .long("max-pending-dials")
+            .value_name("COUNT")
+            .help("The maximum number of discovered peers dialed at the same time.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("warm-dial-peers")
+            .long("warm-dial-peers")
+            .value_name("COUNT")
+            .help("The number of best peers from the last run to dial on startup. 0 disables.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("dial-stagger")
+            .long("dial-stagger")
+            .value_name("MILLIS")
+            .help("The delay before dialing the next address of a peer known at several addresses. By default, and with 0, they are dialed one after the other.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("priority-subnets")
+            .long("priority-subnets")
+            .value_name("SUBNETS")
+            .help("One or more comma-delimited subnet ids. Discovered peers on these subnets are dialed first.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("subnet-peer-quotas")
+            .long("subnet-peer-quotas")
+            .value_name("QUOTAS")
+            .help("One or more comma-delimited SUBNET=COUNT pairs. Peers on each subnet are searched for and dialed while fewer than COUNT are connected.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("peer-preemption-interval")
+            .long("peer-preemption-interval")
+            .value_name("SECONDS")
+            .help("The minimum time between disconnecting inbound peers to admit trusted or priority subnet peers at the peer limit. Pre-emption is off by default, and 0 disables it.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("score-half-life")
+            .long("score-half-life")
+            .value_name("SECONDS")
+            .help("The time for a peer's reputation changes to decay by half. By default, or with 0, reputation changes do not decay.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("key-type")
+            .long("key-type")
+            .value_name("TYPE")
+            .help("The type of network key to generate if none exists in the datadir.")
+            .takes_value(true)
+            .possible_values(&["secp256k1", "ed25519"]),
+    )
     .arg(
         Arg::with_name("boot-nodes")
             .long("boot-nodes")
@@ -56,6 +177,13 @@ pub fn cli_app<'a, 'b>() -> App<'a, 'b> {
             .help("One or more comma-delimited base64-encoded ENR's to bootstrap the p2p network.")
             .takes_value(true),
     )
+    .arg(
+        Arg::with_name("dht-import")
+            .long("dht-import")
+            .value_name("FILE")
+            .help("A file of ENRs exported from the routing table of another node, e.g. with the dht-export admin command, to add to the routing table on startup.")
+            .takes_value(true),
+    )
     .arg(
         Arg::with_name("disable-enr-auto-update")
             .long("disable-enr-auto-update")
@@ -63,11 +191,127 @@ pub fn cli_app<'a, 'b>() -> App<'a, 'b> {
             .help("This fixes the ENR's IP/PORT to whatever is specified at startup.")
             .takes_value(false),
     )
+    .arg(
+        Arg::with_name("outbound-only")
+            .long("outbound-only")
+            .help("Only dial out to peers. No ports are opened for incoming connections and the ENR advertises no address, so other nodes can neither discover nor dial this node.")
+            .takes_value(false),
+    )
+    .arg(
+        Arg::with_name("private")
+            .long("private")
+            .help("Hides the client name and version from peers, in identify, the ENR and the Status versions served.")
+            .takes_value(false),
+    )
     .arg(
         Arg::with_name("topics")
             .long("topics")
             .value_name("STRING")
-            .help("One or more comma-delimited gossipsub topics to subscribe to.")
+            .help("One or more comma-delimited gossipsub topics to subscribe to. {fork} in a topic is replaced by the hex encoded fork id, and such topics are joined once per fork served.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("bridge")
+            .long("bridge")
+            .value_name("FROM=TO")
+            .help("Re-publishes the messages received on topic FROM to topic TO, which the node subscribes to FROM for. Messages on TO carry an envelope marking bridged messages, which are never bridged again, so bridges cannot loop. Every node on TO must know it is bridged to, see --bridge-target. Can be given several times.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+    )
+    .arg(
+        Arg::with_name("bridge-target")
+            .long("bridge-target")
+            .value_name("TOPIC")
+            .help("Marks a topic other nodes bridge messages to, so that its messages are published and received in the bridge envelope. Not needed on the bridging nodes themselves. Can be given several times.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+    )
+    .arg(
+        Arg::with_name("capability-field")
+            .long("capability-field")
+            .value_name("NAME=OFFSET:LENGTH")
+            .help("Reads LENGTH bytes at OFFSET in the metadata of peers as capability flags, numbered after those of earlier fields. The fields hold at most 1024 bytes in all. Metadata is requested from peers as they connect. Can be given several times.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+    )
+    .arg(
+        Arg::with_name("secondary-fork-id")
+            .long("secondary-fork-id")
+            .value_name("HEX")
+            .help("A second fork id to serve alongside the current one during a fork transition. Topics containing {fork} are joined for both forks and the ENR advertises both, so the node can bridge the two networks.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("bridge-forks")
+            .long("bridge-forks")
+            .help("Relays the gossip of topics containing {fork} between the current and the secondary fork, and publishes on both.")
+            .takes_value(false),
+    )
+    .arg(
+        Arg::with_name("control-topics")
+            .long("control-topics")
+            .value_name("STRING")
+            .help("One or more comma-delimited topics of small, urgent messages, forwarded apart from the other topics.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("control-max-size")
+            .long("control-max-size")
+            .value_name("BYTES")
+            .help("The largest message accepted or published on a control topic.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("control-forward-budget")
+            .long("control-forward-budget")
+            .value_name("MESSAGES")
+            .help("The number of messages on control topics forwarded per second. Unlimited by default.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("data-forward-budget")
+            .long("data-forward-budget")
+            .value_name("MESSAGES")
+            .help("The number of messages on the other topics forwarded per second. Unlimited by default.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("unsubscribed-topics")
+            .long("unsubscribed-topics")
+            .value_name("POLICY")
+            .help("How to handle gossip on topics we are not subscribed to: deliver it, drop it, or drop it and penalize the sender.")
+            .takes_value(true)
+            .possible_values(&["deliver", "drop", "penalize"]),
+    )
+    .arg(
+        Arg::with_name("reject-unsubscribed-publish")
+            .long("reject-unsubscribed-publish")
+            .help("Fails the publish of messages on topics we are not subscribed to, reporting an error to the application, rather than sending them to the topic's fanout.")
+            .takes_value(false),
+    )
+    .arg(
+        Arg::with_name("topic-pattern")
+            .long("topic-pattern")
+            .value_name("REGEX")
+            .help("A regular expression the whole name of every topic must match, with the fork filled in. Subscribing or publishing to other topics fails, and peers' subscriptions to them are ignored.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("keep-alive-policy")
+            .long("keep-alive-policy")
+            .value_name("POLICY")
+            .help("When connections are closed for being idle: never, or after the idle timeout without RPC or gossip traffic. Connections to mesh peers are never idle.")
+            .takes_value(true)
+            .possible_values(&["always", "idle"]),
+    )
+    .arg(
+        Arg::with_name("keep-alive-idle-timeout")
+            .long("keep-alive-idle-timeout")
+            .value_name("SECONDS")
+            .help("How long a connection may go without traffic under the idle keep alive policy.")
             .takes_value(true),
     )
         .arg(
@@ -77,6 +321,360 @@ pub fn cli_app<'a, 'b>() -> App<'a, 'b> {
             .help("One or more comma-delimited multiaddrs to manually connect to a libp2p peer without an ENR.")
             .takes_value(true),
         )
+    .arg(
+        Arg::with_name("no-graft-peers")
+            .long("no-graft-peers")
+            .value_name("PEER_ID")
+            .help("One or more comma-delimited peer ids that are kept out of the gossipsub mesh. They can still be reached over RPC.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("topic-publishers")
+            .long("topic-publishers")
+            .value_name("TOPIC=PEER_IDS")
+            .help("Only accepts messages on a topic received directly from the given comma-delimited peer ids, e.g. /mothra/control=16Uiu2...,16Uiu2... Messages are not signed, so list the peers trusted to relay the topic along with its publishers. Can be given once per topic.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+    )
+    .arg(
+        Arg::with_name("peer-topic-rate-limit")
+            .long("peer-topic-rate-limit")
+            .value_name("MESSAGES")
+            .help("The number of messages per second each peer may send on a topic. Messages over the rate are dropped, without penalizing the peer. At least 1, unlimited by default.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("topic-rate-limit")
+            .long("topic-rate-limit")
+            .value_name("TOPIC=MESSAGES")
+            .help("The number of messages per second each peer may send on a topic, overriding --peer-topic-rate-limit, e.g. /mothra/blocks=10. Can be given once per topic.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+    )
+    .arg(
+        Arg::with_name("inbound-rate-limit")
+            .long("inbound-rate-limit")
+            .value_name("MESSAGES")
+            .help("The number of gossip messages per second the node accepts from all peers. Over it, messages on the lowest priority topics are dropped first. Unlimited by default.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("topic-priority")
+            .long("topic-priority")
+            .value_name("TOPIC=PRIORITY")
+            .help("The priority of a topic under --inbound-rate-limit, from 0, the default, to 255, e.g. /mothra/blocks=2. Can be given once per topic.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+    )
+    .arg(
+        Arg::with_name("topic-key")
+            .long("topic-key")
+            .value_name("TOPIC=file:PATH|env:VAR")
+            .help("Encrypts the messages of a topic with a 32 byte hex key shared by its members, read as CIPHER:KEY from a file or an environment variable rather than given on the command line. The cipher is aes-256-gcm or chacha20-poly1305. Can be given once per topic.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+    )
+    .arg(
+        Arg::with_name("topic-key-grace")
+            .long("topic-key-grace")
+            .value_name("SECONDS")
+            .help("The time messages encrypted under a topic key are still accepted after the key is replaced.")
+            .default_value("60")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("gossip-prune-backoff")
+            .long("gossip-prune-backoff")
+            .value_name("SECONDS")
+            .help("The time after a peer is pruned from a gossipsub mesh before it can be grafted on that topic again. 0 disables the backoff.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("gossip-unsubscribe-backoff")
+            .long("gossip-unsubscribe-backoff")
+            .value_name("SECONDS")
+            .help("The time after leaving a gossipsub topic before its peers can be grafted on it again. 0 disables the backoff.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("gossip-graft-flood-threshold")
+            .long("gossip-graft-flood-threshold")
+            .value_name("SECONDS")
+            .help("Peers grafting this soon after being pruned are penalized. 0 disables the penalty.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("gossip-history-length")
+            .long("gossip-history-length")
+            .value_name("HEARTBEATS")
+            .help("The number of heartbeats gossipsub keeps messages in its message cache, to answer IWANTs. A longer history trades memory for a higher mcache hit rate. Defaults to 5.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("gossip-history-gossip")
+            .long("gossip-history-gossip")
+            .value_name("HEARTBEATS")
+            .help("The number of recent heartbeats whose messages are advertised with IHAVE. At most the history length. Defaults to 3.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("opportunistic-graft-interval")
+            .long("opportunistic-graft-interval")
+            .value_name("SECONDS")
+            .help("How often gossipsub meshes are checked for poorly reputed peers, sending a GRAFT to better reputed ones so they add this node to their meshes. 0, the default, disables opportunistic grafting.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("opportunistic-graft-peers")
+            .long("opportunistic-graft-peers")
+            .value_name("NUM_PEERS")
+            .help("The number of peers sent a GRAFT for a poorly reputed mesh at a time.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("max-identify-addresses")
+            .long("max-identify-addresses")
+            .value_name("COUNT")
+            .help("The maximum number of listen addresses stored for a peer from identify.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("allow-private-addresses")
+            .long("allow-private-addresses")
+            .help("Keeps private range addresses advertised by peers even when this node is public. Useful for lab networks.")
+            .takes_value(false),
+    )
+    .arg(
+        Arg::with_name("log-gossip-control")
+            .long("log-gossip-control")
+            .help("Logs every gossipsub GRAFT, PRUNE, IHAVE and IWANT sent or received, at debug level.")
+            .takes_value(false),
+    )
+    .arg(
+        Arg::with_name("topic-exchange")
+            .long("topic-exchange")
+            .help("Exchanges subscribed topics with peers over RPC when they connect.")
+            .takes_value(false),
+    )
+    .arg(
+        Arg::with_name("typed-status")
+            .long("typed-status")
+            .help("Expects peers to send SSZ encoded Status messages with a fork digest, penalizing malformed statuses and disconnecting peers on a fork this node does not serve or whose finalized checkpoint conflicts with the local status.")
+            .takes_value(false),
+    )
+    .arg(
+        Arg::with_name("auto-status")
+            .long("auto-status")
+            .help("Answers Status requests with the status the application keeps in the network globals, rather than handing them to the application, which is still given the statuses received. Requests arriving before a status is set are handed to the application.")
+            .takes_value(false),
+    )
+    .arg(
+        Arg::with_name("min-gossip-usefulness")
+            .long("min-gossip-usefulness")
+            .value_name("FRACTION")
+            .help("Penalizes peers that deliver less than this fraction (0 to 1) of their gossip messages before any other peer.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("rpc-protocol-prefix")
+            .long("rpc-protocol-prefix")
+            .value_name("PREFIX")
+            .help("The prefix of the RPC protocol ids, e.g. /myproject/req. Only peers using the same prefix exchange RPCs.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("rpc-protocol")
+            .long("rpc-protocol")
+            .value_name("NAME=MAX_REQUEST:MAX_RESPONSE[:MAX_CHUNKS]")
+            .help("An application defined RPC protocol to serve, with the maximum sizes of its requests and responses in bytes, e.g. blocks_by_root=1024:1048576, and optionally the most chunks a response may have, e.g. blocks_by_range=64:1048576:1024. Names are at most 32 bytes. Can be given multiple times. Its requests and responses are delivered to receive_rpc under the protocol name.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+    )
+    .arg(
+        Arg::with_name("rpc-substream-max-age")
+            .long("rpc-substream-max-age")
+            .value_name("SECONDS")
+            .help("RPC substreams open for longer than this are logged and force closed. 0 never closes them.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("rpc-codec-workers")
+            .long("rpc-codec-workers")
+            .value_name("COUNT")
+            .help("The threads large RPC payloads are compressed and decompressed on, so they do not hold up the connection. 0 codes them on the connection's task. Defaults to 2.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("limit")
+            .long("limit")
+            .value_name("NAME=VALUE")
+            .help("Sets a cache size, queue cap or timeout of the network, e.g. queued_dials=512 or rpc_response_timeout=20. Timeouts are in seconds, or in milliseconds with an ms suffix, e.g. rpc_ttfb_timeout=500ms. Can be given once per limit. The limits in effect are logged at debug level on startup.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+    )
+    .arg(
+        Arg::with_name("static-peers")
+            .long("static-peers")
+            .value_name("MULTIADDR")
+            .help("One or more comma-delimited multiaddrs, ending with /p2p/<peer id>, of peers to stay connected to. They are redialed whenever they disconnect.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("rendezvous-point")
+            .long("rendezvous-point")
+            .value_name("MULTIADDR")
+            .help("One or more comma-delimited multiaddrs, ending with /p2p/<peer id>, of rendezvous points to register at and find peers through. Unlike discv5 this only needs TCP. They are kept connected like static peers. This is mothra's own rendezvous protocol, not libp2p rendezvous, so the points must be mothra nodes.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("rendezvous-namespaces")
+            .long("rendezvous-namespaces")
+            .value_name("NAMESPACES")
+            .help("One or more comma-delimited namespaces to register under at the rendezvous points. Defaults to mothra.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("rendezvous-server")
+            .long("rendezvous-server")
+            .help("Serves as a rendezvous point, keeping the registrations of other nodes. Only the addresses on hosts a node is connected from are registered.")
+            .takes_value(false),
+    )
+    .arg(
+        Arg::with_name("disable-discovery")
+            .long("disable-discovery")
+            .help("Disables discv5. Only boot nodes, libp2p addresses and static peers are connected to.")
+            .takes_value(false),
+    )
+    .arg(
+        Arg::with_name("delivery-workers")
+            .long("delivery-workers")
+            .value_name("COUNT")
+            .help(
+                "The number of threads handing received messages to the application. With 0 \
+                 they are handed over on the network's runtime.",
+            )
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("delivery-ordering")
+            .long("delivery-ordering")
+            .value_name("KEY")
+            .help("Keep messages from the same peer, or on the same topic, in order when there are several delivery workers.")
+            .takes_value(true)
+            .possible_values(&["peer", "topic"]),
+    )
+    .arg(
+        Arg::with_name("delivery-queue-size")
+            .long("delivery-queue-size")
+            .value_name("COUNT")
+            .help("The number of received messages that can wait for the application, per delivery worker.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("delivery-overflow")
+            .long("delivery-overflow")
+            .value_name("POLICY")
+            .help("What to do with received messages when the application falls behind.")
+            .takes_value(true)
+            .possible_values(&["drop-oldest", "drop-newest", "block"]),
+    )
+    .arg(
+        Arg::with_name("slow-consumer-threshold")
+            .long("slow-consumer-threshold")
+            .value_name("MILLIS")
+            .help("How long a callback of the application may run before it counts as slow. The application is warned when most of its callbacks are. 0 disables the check. Defaults to 100.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("ordered-topics")
+            .long("ordered-topics")
+            .value_name("STRING")
+            .help("One or more comma-delimited topics whose messages are delivered in the order of the sequence numbers the application extracts from them.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("reorder-window")
+            .long("reorder-window")
+            .value_name("COUNT")
+            .help("The number of early messages held back per stream on ordered topics.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("message-key-cache-size")
+            .long("message-key-cache-size")
+            .value_name("COUNT")
+            .help("The number of application message keys remembered to suppress duplicate gossip. 0 disables it.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("buffer-pool-size")
+            .long("buffer-pool-size")
+            .value_name("COUNT")
+            .help("The number of buffers kept for reuse by the decoding of inbound messages. 0 disables the pool.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("discovered-peer-interval")
+            .long("discovered-peer-interval")
+            .value_name("SECONDS")
+            .help("The time before a peer that discovery keeps finding is reported to the application again.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("shutdown-grace-period")
+            .long("shutdown-grace-period")
+            .value_name("SECONDS")
+            .help("How long to keep answering requests and forwarding messages on shutdown before the connections close.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("request-retries")
+            .long("request-retries")
+            .value_name("COUNT")
+            .help("The number of times a request sent to several peers at once is sent to a peer again after failing.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("request-timeout")
+            .long("request-timeout")
+            .value_name("SECONDS")
+            .help("How long the peers of a request sent to several peers at once have to respond.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("admin-socket")
+            .long("admin-socket")
+            .value_name("PATH")
+            .help("Serves control commands on a unix socket at this path, accessible to the user running the node only. Only available on unix platforms.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("admin-token")
+            .long("admin-token")
+            .value_name("TOKEN")
+            .help("Requires connections to the admin socket to authenticate with this token before running commands that change the node.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("mirror")
+            .long("mirror")
+            .value_name("udp:ADDRESS:PORT")
+            .help("Mirrors the gossip and RPC messages received to a UDP address, one line of text per message, for shadow-testing another version of the application. Messages the mirror cannot keep up with, and those of more than about 32KB, which do not fit a datagram once hex encoded, are dropped without affecting the node.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("mirror-queue-size")
+            .long("mirror-queue-size")
+            .value_name("COUNT")
+            .help("The number of messages that can wait for the mirror. Defaults to 4096.")
+            .takes_value(true),
+    )
     .arg(
         Arg::with_name("debug-level")
             .long("debug-level")
@@ -86,4 +684,154 @@ pub fn cli_app<'a, 'b>() -> App<'a, 'b> {
             .possible_values(&["info", "debug", "trace", "warn", "error", "crit"])
             .default_value("info"),
     )
+    .arg(
+        Arg::with_name("log-unsafe")
+            .long("log-unsafe")
+            .help("Logs message payloads and peer addresses as they are, rather than their lengths and hashes. For debugging only.")
+            .takes_value(false),
+    )
+    .arg(
+        Arg::with_name("log-anonymize-ips")
+            .long("log-anonymize-ips")
+            .help("Logs hashes of peer addresses instead of the addresses. Ignored with --log-unsafe.")
+            .takes_value(false),
+    )
+    .subcommand(
+        SubCommand::with_name("ping")
+            .about("Dials a peer, identifies it and measures the round trip time of an RPC Ping.")
+            .arg(
+                Arg::with_name("target")
+                    .value_name("MULTIADDR|ENR")
+                    .help("The multiaddr or base64-encoded ENR of the peer to ping.")
+                    .required(true)
+                    .index(1),
+            )
+            .arg(
+                Arg::with_name("timeout")
+                    .long("timeout")
+                    .value_name("SECONDS")
+                    .help("The number of seconds to wait for the peer to respond.")
+                    .default_value("10")
+                    .takes_value(true),
+            ),
+    )
+    .subcommand(
+        SubCommand::with_name("enr")
+            .about("Inspect and generate ENRs.")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("decode")
+                    .about("Prints the fields of an ENR.")
+                    .arg(
+                        Arg::with_name("enr")
+                            .value_name("ENR")
+                            .help("The base64-encoded ENR to decode.")
+                            .required(true)
+                            .index(1),
+                    ),
+            )
+            .subcommand(
+                enr_update_args(
+                    SubCommand::with_name("generate")
+                        .about("Generates a new ENR signed with the node's key."),
+                ),
+            )
+            .subcommand(
+                enr_update_args(
+                    SubCommand::with_name("resign")
+                        .about("Updates the fields of an ENR and signs it with the node's key.")
+                        .arg(
+                            Arg::with_name("enr")
+                                .value_name("ENR")
+                                .help("The base64-encoded ENR to update.")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(
+                            Arg::with_name("field")
+                                .long("field")
+                                .value_name("KEY=HEX")
+                                .help("A key and hex encoded value to set on the ENR. May be repeated.")
+                                .takes_value(true)
+                                .multiple(true)
+                                .number_of_values(1),
+                        ),
+                ),
+            ),
+    )
+    .subcommand(
+        SubCommand::with_name("generate-key")
+            .about("Generates a new network key in the datadir.")
+            .arg(key_type_arg())
+            .arg(force_arg()),
+    )
+    .subcommand(
+        SubCommand::with_name("import-key")
+            .about("Imports a hex encoded secret key as the network key in the datadir.")
+            .arg(
+                Arg::with_name("secret-key")
+                    .value_name("HEX")
+                    .help("The hex encoded secret key to import.")
+                    .required(true)
+                    .index(1),
+            )
+            .arg(key_type_arg())
+            .arg(force_arg()),
+    )
+    .subcommand(
+        SubCommand::with_name("export-peer-id")
+            .about("Prints the peer id of the network key in the datadir."),
+    )
+}
+
+/// The `--force` argument of the subcommands that replace the network key.
+fn force_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("force")
+        .long("force")
+        .help("Replace an existing network key. This changes the node's identity.")
+        .takes_value(false)
+}
+
+/// The `--key-type` argument shared by the subcommands that parse or generate keys.
+fn key_type_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("key-type")
+        .long("key-type")
+        .value_name("TYPE")
+        .help("The type of the key.")
+        .takes_value(true)
+        .possible_values(&["secp256k1", "ed25519"])
+        .default_value("secp256k1")
+}
+
+/// Adds the arguments shared by the ENR subcommands that sign a record.
+fn enr_update_args<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app.arg(
+        Arg::with_name("secret-key")
+            .long("secret-key")
+            .value_name("HEX")
+            .help("The hex encoded secret key to sign with. Defaults to the key in the datadir.")
+            .takes_value(true),
+    )
+    .arg(key_type_arg())
+    .arg(
+        Arg::with_name("ip")
+            .long("ip")
+            .value_name("ADDRESS")
+            .help("The IP address to set on the ENR.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("udp-port")
+            .long("udp-port")
+            .value_name("PORT")
+            .help("The UDP port to set on the ENR.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("tcp-port")
+            .long("tcp-port")
+            .value_name("PORT")
+            .help("The TCP port to set on the ENR.")
+            .takes_value(true),
+    )
 }
@@ -1,9 +1,37 @@
+use crate::delivery::{DeliveryOrdering, OverflowPolicy, DEFAULT_QUEUE_SIZE, DEFAULT_WORKERS};
+use crate::log_level::{parse_level, LogLevel};
+use crate::log_policy::LogPolicy;
+use crate::mirror::{MirrorSink, DEFAULT_MIRROR_QUEUE_SIZE};
+use crate::reorder::DEFAULT_REORDER_WINDOW;
+use crate::slow_consumer::DEFAULT_SLOW_CONSUMER_THRESHOLD;
 use clap::ArgMatches;
-use network::{unused_port, Enr, Multiaddr, NetworkConfig, DEFAULT_CLIENT_NAME};
+use network::types::DEFAULT_MAX_POOLED_BUFFER_SIZE;
+use network::{
+    unused_port, BufferPool, Enr, Multiaddr, NetworkConfig, PeerId, TopicKey, TopicValidator,
+    DEFAULT_CLIENT_NAME,
+};
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 pub const DEFAULT_DEBUG_LEVEL: &str = "info";
 
+/// The default number of message keys remembered to suppress duplicate gossip.
+pub const DEFAULT_MESSAGE_KEY_CACHE_SIZE: usize = 8192;
+
+/// The default time in seconds before a discovered peer is notified again.
+pub const DEFAULT_DISCOVERED_PEER_INTERVAL: u64 = 600;
+
+/// The default time in seconds given to the work queued for peers when shutting down.
+pub const DEFAULT_SHUTDOWN_GRACE_PERIOD: u64 = 5;
+
+/// The default number of times a failed request of a fan-out is sent again.
+pub const DEFAULT_REQUEST_RETRIES: usize = 1;
+
+/// The default time in seconds the peers of a fan-out have to respond.
+pub const DEFAULT_REQUEST_TIMEOUT: u64 = 10;
+
 /// Mothra configuration
 pub struct Config {
     /// The client name
@@ -15,8 +43,73 @@ pub struct Config {
     /// The log debug level
     pub debug_level: String,
 
+    /// The log level, changeable at runtime. It starts at `debug_level`.
+    pub log_level: LogLevel,
+
+    /// What the network redacts from its logs. By default message payloads are logged as their
+    /// length and a hash.
+    pub log_policy: LogPolicy,
+
     /// Network configuration
     pub network_config: NetworkConfig,
+
+    /// The number of threads handing received messages to the `Subscriber`. With none, messages
+    /// are handed over by a task on the runtime, which suits current-thread runtimes.
+    pub delivery_workers: usize,
+
+    /// Which received messages are kept in order when there are several delivery workers.
+    pub delivery_ordering: DeliveryOrdering,
+
+    /// The number of received messages that can wait to be handed to the `Subscriber`, per
+    /// delivery worker.
+    pub delivery_queue_size: usize,
+
+    /// What to do with received messages once the delivery queue is full.
+    pub delivery_overflow: OverflowPolicy,
+
+    /// How long a `Subscriber` callback may run before it counts as slow. The application is
+    /// warned when most of its callbacks are. `None` does not time the callbacks.
+    pub slow_consumer_threshold: Option<Duration>,
+
+    /// Topics whose messages are delivered in the order given by `Subscriber::message_sequence`.
+    pub ordered_topics: Vec<String>,
+
+    /// The number of early messages held back per topic and stream on ordered topics.
+    pub reorder_window: usize,
+
+    /// The number of message keys remembered to suppress duplicate gossip. 0 disables the
+    /// suppression. See `Subscriber::message_key`.
+    pub message_key_cache_size: usize,
+
+    /// The time before `Subscriber::discovered_peer` is called again for a peer that discovery
+    /// keeps finding.
+    pub discovered_peer_interval: Duration,
+
+    /// How long the network keeps running after the exit signal to answer the requests already
+    /// delivered, propagate messages and say goodbye to its peers.
+    pub shutdown_grace_period: Duration,
+
+    /// The number of times a request sent with `Mothra::broadcast_request` is sent to a peer
+    /// again after failing, for example because the stream timed out.
+    pub request_retries: usize,
+
+    /// How long the peers of `Mothra::broadcast_request` have to respond, retries included.
+    pub request_timeout: Duration,
+
+    /// The path of a unix socket to serve control commands on. See `crate::control`.
+    pub admin_socket: Option<PathBuf>,
+
+    /// The token control connections authenticate with before running commands that change the
+    /// node. `None` leaves access to the permissions of the socket. See `crate::control`.
+    pub admin_token: Option<String>,
+
+    /// A second consumer the messages received from the network are mirrored to, without
+    /// affecting their delivery to the `Subscriber`. A UDP mirror drops messages of more than
+    /// about 32KB, which do not fit a datagram once hex encoded. See `crate::mirror`.
+    pub mirror: Option<MirrorSink>,
+
+    /// The number of messages that can wait for the mirror before further ones are dropped.
+    pub mirror_queue_size: usize,
 }
 
 impl Default for Config {
@@ -27,7 +120,25 @@ impl Default for Config {
             client_name: DEFAULT_CLIENT_NAME.into(),
             client_version: format!("v{}", env!("CARGO_PKG_VERSION")),
             debug_level: DEFAULT_DEBUG_LEVEL.into(),
+            log_level: LogLevel::default(),
+            log_policy: LogPolicy::default(),
             network_config,
+            delivery_workers: DEFAULT_WORKERS,
+            delivery_ordering: DeliveryOrdering::default(),
+            delivery_queue_size: DEFAULT_QUEUE_SIZE,
+            delivery_overflow: OverflowPolicy::default(),
+            slow_consumer_threshold: Some(Duration::from_millis(DEFAULT_SLOW_CONSUMER_THRESHOLD)),
+            ordered_topics: vec![],
+            reorder_window: DEFAULT_REORDER_WINDOW,
+            message_key_cache_size: DEFAULT_MESSAGE_KEY_CACHE_SIZE,
+            discovered_peer_interval: Duration::from_secs(DEFAULT_DISCOVERED_PEER_INTERVAL),
+            shutdown_grace_period: Duration::from_secs(DEFAULT_SHUTDOWN_GRACE_PERIOD),
+            request_retries: DEFAULT_REQUEST_RETRIES,
+            request_timeout: Duration::from_secs(DEFAULT_REQUEST_TIMEOUT),
+            admin_socket: None,
+            admin_token: None,
+            mirror: None,
+            mirror_queue_size: DEFAULT_MIRROR_QUEUE_SIZE,
         }
     }
 }
@@ -39,6 +150,8 @@ impl Config {
         protocol_version: Option<String>,
     ) -> Self {
         let mut config = Config::default();
+        let named = client_name.is_some();
+        let client_given = named || client_version.is_some();
         // update self.client_name if client_name is not None
         if let Some(x) = client_name {
             config.client_name = x;
@@ -47,9 +160,26 @@ impl Config {
         if let Some(x) = client_version {
             config.client_version = x;
         }
-        // update self.network_config.protocol_version if protocol_version is not None
+        // identify advertises the client given rather than mothra, keeping the platform of the
+        // default agent version
+        if client_given {
+            let platform = config
+                .network_config
+                .agent_version
+                .rsplit('/')
+                .next()
+                .unwrap_or_default()
+                .to_string();
+            config.network_config.agent_version = format!(
+                "{}/{}/{}",
+                config.client_name, config.client_version, platform
+            );
+        }
+        // the protocol version given wins over the one derived from the client name
         if let Some(x) = protocol_version {
             config.network_config.protocol_version = x;
+        } else if named {
+            config.network_config.protocol_version = format!("{}/libp2p", config.client_name);
         }
         config
     }
@@ -73,6 +203,83 @@ impl Config {
                 .map_err(|_| format!("Invalid number of max peers: {}", max_peers_str))?;
         }
 
+        if let Some(patterns) = args.values_of("agent-allow") {
+            self.network_config.agent_allow = patterns.map(String::from).collect();
+        }
+
+        if let Some(patterns) = args.values_of("agent-deny") {
+            self.network_config.agent_deny = patterns.map(String::from).collect();
+        }
+
+        if args.is_present("strict-protocol") {
+            let prefix = match args.value_of("strict-protocol") {
+                Some(prefix) => prefix.to_string(),
+                None => {
+                    let version = &self.network_config.protocol_version;
+                    match version.rfind('/') {
+                        Some(i) => version[..=i].to_string(),
+                        None => return Err(format!("No prefix in protocol version: {}", version)),
+                    }
+                }
+            };
+            self.network_config.required_protocol_prefix = Some(prefix);
+        }
+
+        if let Some(max_dc_str) = args.value_of("max-disconnected-peers") {
+            self.network_config.max_disconnected_peers = max_dc_str
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid number of disconnected peers: {}", max_dc_str))?;
+        }
+
+        if let Some(dials_str) = args.value_of("max-pending-dials") {
+            self.network_config.max_pending_dials = dials_str
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid number of pending dials: {}", dials_str))?;
+        }
+
+        if let Some(warm_str) = args.value_of("warm-dial-peers") {
+            self.network_config.warm_dial_peers = warm_str
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid number of warm dial peers: {}", warm_str))?;
+        }
+
+        if let Some(stagger_str) = args.value_of("dial-stagger") {
+            let millis = stagger_str
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid dial stagger: {}", stagger_str))?;
+            self.network_config.dial_stagger = if millis == 0 {
+                None
+            } else {
+                Some(Duration::from_millis(millis))
+            };
+        }
+
+        if let Some(subnets_str) = args.value_of("priority-subnets") {
+            self.network_config.priority_subnets = subnets_str
+                .split(',')
+                .map(|subnet| {
+                    subnet
+                        .parse()
+                        .map_err(|_| format!("Invalid subnet id: {}", subnet))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+        }
+
+        if let Some(quotas_str) = args.value_of("subnet-peer-quotas") {
+            self.network_config.subnet_peer_quotas = quotas_str
+                .split(',')
+                .map(|quota| {
+                    let mut parts = quota.splitn(2, '=');
+                    let subnet_id = parts.next().and_then(|subnet| subnet.parse().ok());
+                    let count = parts.next().and_then(|count| count.parse().ok());
+                    match (subnet_id, count) {
+                        (Some(subnet_id), Some(count)) => Ok((subnet_id, count)),
+                        _ => Err(format!("Invalid subnet peer quota: {}", quota)),
+                    }
+                })
+                .collect::<Result<HashMap<_, _>, _>>()?;
+        }
+
         if let Some(port_str) = args.value_of("port") {
             let port = port_str
                 .parse::<u16>()
@@ -90,6 +297,48 @@ impl Config {
             self.network_config.enr_udp_port = Some(self.network_config.discovery_port);
         }
 
+        if let Some(ws_port_str) = args.value_of("ws-port") {
+            self.network_config.ws_port = Some(
+                ws_port_str
+                    .parse::<u16>()
+                    .map_err(|_| format!("Invalid WebSocket port: {}", ws_port_str))?,
+            );
+        }
+
+        if let Some(cert_path) = args.value_of("ws-tls-cert") {
+            self.network_config.ws_tls_cert = Some(PathBuf::from(cert_path));
+        }
+
+        if let Some(key_path) = args.value_of("ws-tls-key") {
+            self.network_config.ws_tls_key = Some(PathBuf::from(key_path));
+        }
+
+        if let Some(interval_str) = args.value_of("peer-preemption-interval") {
+            let secs = interval_str
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid peer pre-emption interval: {}", interval_str))?;
+            self.network_config.peer_preemption_interval = if secs == 0 {
+                None
+            } else {
+                Some(Duration::from_secs(secs))
+            };
+        }
+
+        if let Some(half_life_str) = args.value_of("score-half-life") {
+            let secs = half_life_str
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid score half-life: {}", half_life_str))?;
+            self.network_config.score_half_life = if secs == 0 {
+                None
+            } else {
+                Some(Duration::from_secs(secs))
+            };
+        }
+
+        if let Some(key_type_str) = args.value_of("key-type") {
+            self.network_config.key_type = key_type_str.parse()?;
+        }
+
         if let Some(boot_enr_str) = args.value_of("boot-nodes") {
             self.network_config.boot_nodes = boot_enr_str
                 .split(',')
@@ -97,6 +346,10 @@ impl Config {
                 .collect::<Result<Vec<Enr>, _>>()?;
         }
 
+        if let Some(dht_file) = args.value_of("dht-import") {
+            self.network_config.dht_import_file = Some(PathBuf::from(dht_file));
+        }
+
         if let Some(libp2p_addresses_str) = args.value_of("libp2p-addresses") {
             self.network_config.libp2p_nodes = libp2p_addresses_str
                 .split(',')
@@ -108,6 +361,265 @@ impl Config {
                 .collect::<Result<Vec<Multiaddr>, _>>()?;
         }
 
+        if let Some(static_peers_str) = args.value_of("static-peers") {
+            self.network_config.static_peers = static_peers_str
+                .split(',')
+                .map(|multiaddr| {
+                    multiaddr
+                        .parse()
+                        .map_err(|_| format!("Invalid Multiaddr: {}", multiaddr))
+                })
+                .collect::<Result<Vec<Multiaddr>, _>>()?;
+        }
+
+        if let Some(points_str) = args.value_of("rendezvous-point") {
+            self.network_config.rendezvous_points = points_str
+                .split(',')
+                .map(|multiaddr| {
+                    multiaddr
+                        .parse()
+                        .map_err(|_| format!("Invalid Multiaddr: {}", multiaddr))
+                })
+                .collect::<Result<Vec<Multiaddr>, _>>()?;
+        }
+
+        if let Some(namespaces_str) = args.value_of("rendezvous-namespaces") {
+            self.network_config.rendezvous_namespaces =
+                namespaces_str.split(',').map(|s| s.into()).collect();
+        }
+
+        if args.is_present("rendezvous-server") {
+            self.network_config.rendezvous_server = true;
+        }
+
+        if let Some(peers_str) = args.value_of("no-graft-peers") {
+            self.network_config.no_graft_peers = peers_str
+                .split(',')
+                .map(|peer_id| {
+                    peer_id
+                        .parse()
+                        .map_err(|_| format!("Invalid peer id: {}", peer_id))
+                })
+                .collect::<Result<Vec<PeerId>, _>>()?;
+        }
+
+        if let Some(publishers) = args.values_of("topic-publishers") {
+            for publishers_str in publishers {
+                let mut parts = publishers_str.splitn(2, '=');
+                let topic = parts.next().unwrap_or_default().to_string();
+                let peers = parts
+                    .next()
+                    .filter(|_| !topic.is_empty())
+                    .ok_or_else(|| format!("Invalid topic publishers: {}", publishers_str))?
+                    .split(',')
+                    .map(|peer_id| {
+                        peer_id
+                            .parse()
+                            .map_err(|_| format!("Invalid peer id: {}", peer_id))
+                    })
+                    .collect::<Result<HashSet<PeerId>, _>>()?;
+                self.network_config.topic_publishers.insert(topic, peers);
+            }
+        }
+
+        if let Some(rate_str) = args.value_of("peer-topic-rate-limit") {
+            self.network_config.peer_topic_rate_limit = Some(
+                rate_str
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|rate| *rate > 0)
+                    .ok_or_else(|| format!("Invalid peer topic rate limit: {}", rate_str))?,
+            );
+        }
+
+        if let Some(limits) = args.values_of("topic-rate-limit") {
+            for limit_str in limits {
+                let mut parts = limit_str.splitn(2, '=');
+                let topic = parts.next().unwrap_or_default().to_string();
+                let rate = parts
+                    .next()
+                    .filter(|_| !topic.is_empty())
+                    .and_then(|rate| rate.parse::<u32>().ok())
+                    .filter(|rate| *rate > 0)
+                    .ok_or_else(|| format!("Invalid topic rate limit: {}", limit_str))?;
+                self.network_config.topic_rate_limits.insert(topic, rate);
+            }
+        }
+
+        if let Some(rate_str) = args.value_of("inbound-rate-limit") {
+            self.network_config.inbound_message_rate_limit = Some(
+                rate_str
+                    .parse::<u32>()
+                    .map_err(|_| format!("Invalid inbound rate limit: {}", rate_str))?,
+            );
+        }
+
+        if let Some(priorities) = args.values_of("topic-priority") {
+            for priority_str in priorities {
+                let mut parts = priority_str.splitn(2, '=');
+                let topic = parts.next().unwrap_or_default().to_string();
+                let priority = parts
+                    .next()
+                    .filter(|_| !topic.is_empty())
+                    .and_then(|priority| priority.parse::<u8>().ok())
+                    .ok_or_else(|| format!("Invalid topic priority: {}", priority_str))?;
+                self.network_config.topic_priorities.insert(topic, priority);
+            }
+        }
+
+        if let Some(keys) = args.values_of("topic-key") {
+            for key_str in keys {
+                let mut parts = key_str.splitn(2, '=');
+                let topic = parts.next().unwrap_or_default().to_string();
+                let source = parts.next().filter(|_| !topic.is_empty()).ok_or_else(|| {
+                    "Invalid topic key, expected TOPIC=file:PATH or TOPIC=env:VAR".to_string()
+                })?;
+                let key = read_topic_key(source)?;
+                self.network_config.topic_keys.insert(topic, key);
+            }
+        }
+
+        if let Some(grace_str) = args.value_of("topic-key-grace") {
+            let secs = grace_str
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid topic key grace: {}", grace_str))?;
+            self.network_config.topic_key_grace = Duration::from_secs(secs);
+        }
+
+        if let Some(backoff_str) = args.value_of("gossip-prune-backoff") {
+            let secs = backoff_str
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid gossip prune backoff: {}", backoff_str))?;
+            self.network_config.gossip_prune_backoff = Duration::from_secs(secs);
+        }
+
+        if let Some(backoff_str) = args.value_of("gossip-unsubscribe-backoff") {
+            let secs = backoff_str
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid gossip unsubscribe backoff: {}", backoff_str))?;
+            self.network_config.gossip_unsubscribe_backoff = Duration::from_secs(secs);
+        }
+
+        if let Some(threshold_str) = args.value_of("gossip-graft-flood-threshold") {
+            let secs = threshold_str
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid gossip graft flood threshold: {}", threshold_str))?;
+            self.network_config.gossip_graft_flood_threshold = Duration::from_secs(secs);
+        }
+
+        let gs_config = &mut self.network_config.gs_config;
+        if let Some(length_str) = args.value_of("gossip-history-length") {
+            gs_config.history_length = length_str
+                .parse::<usize>()
+                .ok()
+                .filter(|length| *length > 0)
+                .ok_or_else(|| format!("Invalid gossip history length: {}", length_str))?;
+        }
+        if let Some(window_str) = args.value_of("gossip-history-gossip") {
+            gs_config.history_gossip = window_str
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid gossip history window: {}", window_str))?;
+        }
+        // messages are only gossiped while gossipsub still caches them
+        if gs_config.history_gossip > gs_config.history_length {
+            return Err(format!(
+                "The gossip history window of {} heartbeats exceeds the history length of {}",
+                gs_config.history_gossip, gs_config.history_length
+            ));
+        }
+
+        if let Some(interval_str) = args.value_of("opportunistic-graft-interval") {
+            let secs = interval_str
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid opportunistic graft interval: {}", interval_str))?;
+            self.network_config.opportunistic_graft_interval = if secs == 0 {
+                None
+            } else {
+                Some(Duration::from_secs(secs))
+            };
+        }
+
+        if let Some(peers_str) = args.value_of("opportunistic-graft-peers") {
+            self.network_config.opportunistic_graft_peers = peers_str
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid opportunistic graft peers: {}", peers_str))?;
+        }
+
+        if let Some(max_str) = args.value_of("max-identify-addresses") {
+            self.network_config.limits.max_identify_addresses = max_str
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid number of identify addresses: {}", max_str))?;
+        }
+
+        if args.is_present("allow-private-addresses") {
+            self.network_config.allow_private_addresses = true;
+        }
+
+        if args.is_present("log-gossip-control") {
+            self.network_config.log_gossip_control = true;
+        }
+
+        if args.is_present("topic-exchange") {
+            self.network_config.topic_exchange = true;
+        }
+
+        if args.is_present("typed-status") {
+            self.network_config.typed_status = true;
+        }
+
+        if args.is_present("auto-status") {
+            self.network_config.auto_status = true;
+        }
+
+        if let Some(usefulness_str) = args.value_of("min-gossip-usefulness") {
+            let usefulness = usefulness_str
+                .parse::<f64>()
+                .ok()
+                .filter(|usefulness| *usefulness >= 0.0 && *usefulness <= 1.0)
+                .ok_or_else(|| format!("Invalid minimum gossip usefulness: {}", usefulness_str))?;
+            self.network_config.min_gossip_usefulness = Some(usefulness);
+        }
+
+        if let Some(prefix) = args.value_of("rpc-protocol-prefix") {
+            network::rpc::validate_protocol_prefix(prefix)?;
+            self.network_config.rpc_protocol_prefix = prefix.to_string();
+        }
+
+        if let Some(protocols) = args.values_of("rpc-protocol") {
+            for protocol_str in protocols {
+                self.network_config
+                    .custom_protocols
+                    .register_from_str(protocol_str)?;
+            }
+        }
+
+        if let Some(age_str) = args.value_of("rpc-substream-max-age") {
+            let secs = age_str
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid RPC substream max age: {}", age_str))?;
+            self.network_config.rpc_substream_max_age = if secs == 0 {
+                None
+            } else {
+                Some(Duration::from_secs(secs))
+            };
+        }
+
+        if let Some(workers_str) = args.value_of("rpc-codec-workers") {
+            self.network_config.rpc_codec_workers = workers_str
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid RPC codec workers: {}", workers_str))?;
+        }
+
+        if let Some(limits) = args.values_of("limit") {
+            for limit in limits {
+                self.network_config.limits.set_from_str(limit)?;
+            }
+        }
+
+        if args.is_present("disable-discovery") {
+            self.network_config.disable_discovery = true;
+        }
+
         if let Some(enr_address_str) = args.value_of("enr-address") {
             self.network_config.enr_address = Some(
                 enr_address_str
@@ -136,14 +648,236 @@ impl Config {
             self.network_config.discv5_config.enr_update = false;
         }
 
+        if args.is_present("outbound-only") {
+            self.network_config.outbound_only = true;
+        }
+
+        if args.is_present("private") {
+            self.network_config.private = true;
+        }
+
         if let Some(topics_str) = args.value_of("topics") {
             self.network_config.topics = topics_str.split(',').map(|s| s.into()).collect();
         }
 
+        if let Some(fork_str) = args.value_of("secondary-fork-id") {
+            let fork_id = hex::decode(fork_str.trim_start_matches("0x"))
+                .map_err(|_| format!("Invalid secondary fork id: {}", fork_str))?;
+            self.network_config.secondary_fork_id = Some(fork_id);
+        }
+
+        if args.is_present("bridge-forks") {
+            self.network_config.bridge_forks = true;
+        }
+
+        if let Some(bridges) = args.values_of("bridge") {
+            for bridge_str in bridges {
+                let mut parts = bridge_str.splitn(2, '=');
+                let from = parts.next().unwrap_or_default().to_string();
+                let to = parts
+                    .next()
+                    .filter(|to| !from.is_empty() && !to.is_empty())
+                    .ok_or_else(|| format!("Invalid bridge, expected FROM=TO: {}", bridge_str))?;
+                if !self.network_config.topics.contains(&from) {
+                    self.network_config.topics.push(from.clone());
+                }
+                self.network_config.topic_bridges.register(from, to, None);
+            }
+        }
+
+        if let Some(targets) = args.values_of("bridge-target") {
+            for target in targets {
+                self.network_config.topic_bridges.add_target(target);
+            }
+        }
+
+        if let Some(fields) = args.values_of("capability-field") {
+            for field_str in fields {
+                let invalid = || {
+                    format!(
+                        "Invalid capability field, expected NAME=OFFSET:LENGTH: {}",
+                        field_str
+                    )
+                };
+                let mut parts = field_str.splitn(2, '=');
+                let name = parts.next().unwrap_or_default();
+                let mut range = parts.next().ok_or_else(invalid)?.splitn(2, ':');
+                let mut next = || range.next().and_then(|s| s.parse::<usize>().ok());
+                let offset = next().ok_or_else(invalid)?;
+                let length = next().ok_or_else(invalid)?;
+                if name.is_empty() || length == 0 {
+                    return Err(invalid());
+                }
+                self.network_config
+                    .capability_fields
+                    .register(name, offset, length)?;
+            }
+        }
+
+        if let Some(topics_str) = args.value_of("control-topics") {
+            self.network_config.control_topics = topics_str.split(',').map(|s| s.into()).collect();
+        }
+
+        if let Some(size_str) = args.value_of("control-max-size") {
+            self.network_config.control_max_message_size = size_str
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid control max size: {}", size_str))?;
+        }
+
+        if let Some(budget_str) = args.value_of("control-forward-budget") {
+            self.network_config.control_forward_budget = Some(
+                budget_str
+                    .parse::<usize>()
+                    .map_err(|_| format!("Invalid control forward budget: {}", budget_str))?,
+            );
+        }
+
+        if let Some(budget_str) = args.value_of("data-forward-budget") {
+            self.network_config.data_forward_budget = Some(
+                budget_str
+                    .parse::<usize>()
+                    .map_err(|_| format!("Invalid data forward budget: {}", budget_str))?,
+            );
+        }
+
+        if let Some(policy_str) = args.value_of("unsubscribed-topics") {
+            self.network_config.unsubscribed_topic_policy = policy_str.parse()?;
+        }
+
+        if args.is_present("reject-unsubscribed-publish") {
+            self.network_config.reject_unsubscribed_publish = true;
+        }
+
+        if let Some(pattern) = args.value_of("topic-pattern") {
+            self.network_config.topic_validator = Some(TopicValidator::pattern(pattern)?);
+        }
+
+        if let Some(policy_str) = args.value_of("keep-alive-policy") {
+            self.network_config.keep_alive_policy = policy_str.parse()?;
+        }
+
+        if let Some(timeout_str) = args.value_of("keep-alive-idle-timeout") {
+            let secs = timeout_str
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid keep alive idle timeout: {}", timeout_str))?;
+            self.network_config.keep_alive_idle_timeout = Duration::from_secs(secs);
+        }
+
+        if let Some(workers_str) = args.value_of("delivery-workers") {
+            self.delivery_workers = workers_str
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid number of delivery workers: {}", workers_str))?;
+        }
+
+        if let Some(ordering_str) = args.value_of("delivery-ordering") {
+            self.delivery_ordering = ordering_str.parse()?;
+        }
+
+        if let Some(queue_size_str) = args.value_of("delivery-queue-size") {
+            self.delivery_queue_size = queue_size_str
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid delivery queue size: {}", queue_size_str))?;
+        }
+
+        if let Some(overflow_str) = args.value_of("delivery-overflow") {
+            self.delivery_overflow = overflow_str.parse()?;
+        }
+
+        if let Some(threshold_str) = args.value_of("slow-consumer-threshold") {
+            let millis = threshold_str
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid slow consumer threshold: {}", threshold_str))?;
+            self.slow_consumer_threshold = match millis {
+                0 => None,
+                millis => Some(Duration::from_millis(millis)),
+            };
+        }
+
+        if let Some(topics_str) = args.value_of("ordered-topics") {
+            self.ordered_topics = topics_str.split(',').map(|s| s.into()).collect();
+        }
+
+        if let Some(window_str) = args.value_of("reorder-window") {
+            self.reorder_window = window_str
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid reorder window: {}", window_str))?;
+        }
+
+        if let Some(cache_size_str) = args.value_of("message-key-cache-size") {
+            self.message_key_cache_size = cache_size_str
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid message key cache size: {}", cache_size_str))?;
+        }
+
+        if let Some(pool_size_str) = args.value_of("buffer-pool-size") {
+            let pool_size = pool_size_str
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid buffer pool size: {}", pool_size_str))?;
+            self.network_config.buffer_pool =
+                Arc::new(BufferPool::new(pool_size, DEFAULT_MAX_POOLED_BUFFER_SIZE));
+        }
+
+        if let Some(interval_str) = args.value_of("discovered-peer-interval") {
+            let secs = interval_str
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid discovered peer interval: {}", interval_str))?;
+            self.discovered_peer_interval = Duration::from_secs(secs);
+        }
+
+        if let Some(grace_str) = args.value_of("shutdown-grace-period") {
+            let secs = grace_str
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid shutdown grace period: {}", grace_str))?;
+            self.shutdown_grace_period = Duration::from_secs(secs);
+        }
+
+        if let Some(retries_str) = args.value_of("request-retries") {
+            self.request_retries = retries_str
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid request retries: {}", retries_str))?;
+        }
+
+        if let Some(timeout_str) = args.value_of("request-timeout") {
+            let secs = timeout_str
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid request timeout: {}", timeout_str))?;
+            self.request_timeout = Duration::from_secs(secs);
+        }
+
+        if let Some(socket_str) = args.value_of("admin-socket") {
+            self.admin_socket = Some(PathBuf::from(socket_str));
+        }
+
+        if let Some(token) = args.value_of("admin-token") {
+            if token.is_empty() {
+                return Err("The admin token must not be empty".into());
+            }
+            self.admin_token = Some(token.to_string());
+        }
+
+        if let Some(mirror_str) = args.value_of("mirror") {
+            self.mirror = Some(mirror_str.parse()?);
+        }
+
+        if let Some(size_str) = args.value_of("mirror-queue-size") {
+            self.mirror_queue_size = size_str
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid mirror queue size: {}", size_str))?;
+        }
+
         if let Some(debug_level_str) = args.value_of("debug-level") {
             self.debug_level = debug_level_str
                 .parse()
                 .map_err(|_| format!("Invalid debug-level: {:?}", debug_level_str))?;
+            self.log_level.set(parse_level(debug_level_str)?);
+        }
+
+        if args.is_present("log-unsafe") {
+            self.log_policy.unsafe_logging = true;
+        }
+
+        if args.is_present("log-anonymize-ips") {
+            self.log_policy.anonymize_ips = true;
         }
 
         if args.is_present("auto-ports") {
@@ -163,3 +897,18 @@ impl Config {
         Ok(())
     }
 }
+
+/// Reads a topic key, as `CIPHER:KEY`, from the file or environment variable `source` names:
+/// `file:PATH` or `env:VAR`. Keys are not taken from the command line itself, where other users
+/// of the host and the shell history would see them.
+fn read_topic_key(source: &str) -> Result<TopicKey, String> {
+    let mut parts = source.splitn(2, ':');
+    let key_str = match (parts.next(), parts.next()) {
+        (Some("file"), Some(path)) => std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read the topic key file {}: {}", path, e))?,
+        (Some("env"), Some(var)) => std::env::var(var)
+            .map_err(|e| format!("Could not read the topic key variable {}: {}", var, e))?,
+        _ => return Err("Topic keys are read from a file or an environment variable, as TOPIC=file:PATH or TOPIC=env:VAR".into()),
+    };
+    key_str.trim().parse()
+}
@@ -0,0 +1,700 @@
+//! A control endpoint on a unix domain socket, for local tooling that must not open a TCP port.
+//!
+//! Each line written to the socket is a command. The reply is zero or more lines of output
+//! followed by `ok`, or a single `error: <reason>` line. Access is controlled by the permissions
+//! of the socket file, which is only readable and writable by the user running the node. The
+//! socket is bound in a directory only that user can enter, and moved into place once its
+//! permissions are restricted, so no one else can connect in between. It is removed when the
+//! node shuts down. Unix domain sockets are only available on unix platforms.
+//!
+//! When an admin token is configured, a connection must authenticate with `auth <token>` before
+//! it may run commands that change the node. Until then only `info`, `peers`, `dht`, `queues`,
+//! `memory` and `watch` are answered, so monitoring tools can be given the socket without the
+//! token.
+//!
+//! The commands are:
+//! - `auth <token>`: authenticates the connection with the admin token.
+//! - `info`: the local peer id, ENR, listen addresses and number of connected peers.
+//! - `peers`: one line per connected peer with its reputation and client.
+//! - `dht`: the number of running and queued discovery queries, the number of entries in each
+//!   non-empty routing table bucket, and one line per routing table ENR.
+//! - `dht-export <path>`: writes the routing table ENRs to a file, for `--dht-import`.
+//! - `queues`: the number of items waiting in each internal queue of the network service.
+//! - `memory`: the estimated bytes held by the caches, the peer database and the queues.
+//! - `watch`: streams the events of the node, one `NodeEvent` per line, until the connection
+//!   closes. Commands sent afterwards are ignored. A watcher that falls behind is sent
+//!   `lagged <n>` in place of the `n` events it missed.
+//! - `subscribe <topic>`: subscribes to a gossipsub topic.
+//! - `unsubscribe <topic>`: unsubscribes from a gossipsub topic.
+//! - `publish <topic> <hex data>`: publishes a message.
+//! - `pause-forwarding <topic>`: stops forwarding the messages received on a topic.
+//! - `resume-forwarding <topic>`: forwards the messages received on a topic again.
+//! - `dial <multiaddr>`: dials an address, printing the token its result is reported with.
+//! - `disconnect <peer id>`: disconnects and temporarily bans a peer.
+//! - `ban <peer id>`: bans a peer as for a fatal fault, disconnecting it.
+//! - `mute <peer id>`: drops the gossip of a peer without disconnecting it.
+//! - `unmute <peer id>`: accepts the gossip of a muted peer again.
+//! - `log-level <level>`: changes the log level, one of the `--debug-level` values.
+use crate::log_level::parse_level;
+use crate::mothra::{dht_snapshot, next_dial_token, NetworkMessage, NodeEvent};
+use network::{GossipTopic, Multiaddr, NetworkGlobals, PeerAction, PeerId, TaskExecutor};
+use slog::{debug, info, warn, Level};
+use std::fs::{self, DirBuilder, Permissions};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{broadcast, mpsc};
+
+/// A command received on the control socket.
+#[derive(Debug, PartialEq)]
+enum Command {
+    Auth(String),
+    Info,
+    Peers,
+    Dht,
+    DhtExport(PathBuf),
+    Queues,
+    Memory,
+    Watch,
+    Subscribe(String),
+    Unsubscribe(String),
+    Publish { topic: String, data: Vec<u8> },
+    PauseForwarding(String),
+    ResumeForwarding(String),
+    Dial(Multiaddr),
+    Disconnect(PeerId),
+    Ban(PeerId),
+    Mute(PeerId),
+    Unmute(PeerId),
+    LogLevel(Level),
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut words = line.split_whitespace();
+        let command = words.next().ok_or_else(|| "Empty command".to_string())?;
+        let mut arg = |name: &str| {
+            words
+                .next()
+                .ok_or_else(|| format!("Missing {} for {}", name, command))
+        };
+        let parsed = match command {
+            "auth" => Command::Auth(arg("token")?.to_string()),
+            "info" => Command::Info,
+            "peers" => Command::Peers,
+            "dht" => Command::Dht,
+            "dht-export" => Command::DhtExport(PathBuf::from(arg("path")?)),
+            "queues" => Command::Queues,
+            "memory" => Command::Memory,
+            "watch" => Command::Watch,
+            "subscribe" => Command::Subscribe(arg("topic")?.to_string()),
+            "unsubscribe" => Command::Unsubscribe(arg("topic")?.to_string()),
+            "publish" => {
+                let topic = arg("topic")?.to_string();
+                let data = arg("data")?;
+                let data = hex::decode(data.trim_start_matches("0x"))
+                    .map_err(|_| format!("Invalid hex data: {}", data))?;
+                Command::Publish { topic, data }
+            }
+            "pause-forwarding" => Command::PauseForwarding(arg("topic")?.to_string()),
+            "resume-forwarding" => Command::ResumeForwarding(arg("topic")?.to_string()),
+            "dial" => {
+                let address = arg("address")?;
+                let address = Multiaddr::from_str(address)
+                    .map_err(|_| format!("Invalid address: {}", address))?;
+                Command::Dial(address)
+            }
+            "disconnect" | "ban" | "mute" | "unmute" => {
+                let peer_id = arg("peer id")?;
+                let peer_id = PeerId::from_str(peer_id)
+                    .map_err(|_| format!("Invalid peer id: {}", peer_id))?;
+                match command {
+                    "disconnect" => Command::Disconnect(peer_id),
+                    "ban" => Command::Ban(peer_id),
+                    "mute" => Command::Mute(peer_id),
+                    _ => Command::Unmute(peer_id),
+                }
+            }
+            "log-level" => Command::LogLevel(parse_level(arg("level")?)?),
+            _ => return Err(format!("Unknown command: {}", command)),
+        };
+        match words.next() {
+            Some(extra) => Err(format!("Unexpected argument: {}", extra)),
+            None => Ok(parsed),
+        }
+    }
+}
+
+impl Command {
+    /// Whether the command only reads the state of the node, and may run before the connection
+    /// authenticates.
+    fn is_read_only(&self) -> bool {
+        match self {
+            Command::Auth(_)
+            | Command::Info
+            | Command::Peers
+            | Command::Dht
+            | Command::Queues
+            | Command::Memory
+            | Command::Watch => true,
+            _ => false,
+        }
+    }
+}
+
+/// Compares a token to the admin token in a time that does not depend on where they differ.
+fn token_matches(admin_token: &str, token: &str) -> bool {
+    admin_token.len() == token.len()
+        && admin_token
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// The socket file, removed when dropped.
+struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Binds a socket at `path`, only accessible to the current user, replacing a socket left there
+/// by an earlier run. Any other file at `path` is left alone.
+fn bind(path: &Path) -> Result<(UnixListener, SocketFile), String> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            fs::remove_file(path).map_err(|e| format!("Could not remove the old socket: {}", e))?
+        }
+        Ok(_) => return Err("A file that is not a socket is in the way".into()),
+        Err(_) => {}
+    }
+    // the socket is bound in a private directory, where no one else can reach it before its
+    // permissions are restricted
+    let mut private_dir = path.as_os_str().to_owned();
+    private_dir.push(format!(".{}.tmp", std::process::id()));
+    let private_dir = PathBuf::from(private_dir);
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)
+        .map_err(|e| format!("Could not create {:?}: {}", private_dir, e))?;
+    let private_path = private_dir.join("socket");
+    let bound = UnixListener::bind(&private_path)
+        .map_err(|e| e.to_string())
+        .and_then(|listener| {
+            fs::set_permissions(&private_path, Permissions::from_mode(0o600))
+                .and_then(|_| fs::rename(&private_path, path))
+                .map_err(|e| format!("Could not restrict the socket: {}", e))?;
+            Ok((listener, SocketFile(path.to_path_buf())))
+        });
+    let _ = fs::remove_file(&private_path);
+    let _ = fs::remove_dir(&private_dir);
+    bound
+}
+
+/// Listens for control connections on the socket at `path`, replacing a socket already there.
+/// The socket is removed once the executor exits. With an `admin_token`, connections authenticate
+/// with it before changing the node.
+pub fn spawn(
+    path: PathBuf,
+    admin_token: Option<String>,
+    network_send: mpsc::UnboundedSender<NetworkMessage>,
+    node_events: broadcast::Sender<NodeEvent>,
+    network_globals: Arc<NetworkGlobals>,
+    executor: &TaskExecutor,
+    log: slog::Logger,
+) {
+    let task_executor = executor.clone();
+    let admin_token = admin_token.map(Arc::new);
+    executor.spawn(
+        async move {
+            // the socket file lives as long as this task, which ends when the executor exits
+            let (mut listener, _socket_file) = match bind(&path) {
+                Ok(bound) => bound,
+                Err(e) => {
+                    warn!(log, "Could not open the admin socket";
+                        "path" => format!("{:?}", path), "error" => e);
+                    return;
+                }
+            };
+            info!(log, "Admin socket open"; "path" => format!("{:?}", path));
+
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => task_executor.spawn(
+                        serve(
+                            stream,
+                            admin_token.clone(),
+                            network_send.clone(),
+                            node_events.clone(),
+                            network_globals.clone(),
+                            log.clone(),
+                        ),
+                        "admin_socket_connection",
+                    ),
+                    Err(e) => {
+                        warn!(log, "Could not accept an admin socket connection";
+                            "error" => e.to_string());
+                    }
+                }
+            }
+        },
+        "admin_socket",
+    );
+}
+
+/// Answers the commands of a single control connection until it closes.
+async fn serve(
+    stream: UnixStream,
+    admin_token: Option<Arc<String>>,
+    network_send: mpsc::UnboundedSender<NetworkMessage>,
+    node_events: broadcast::Sender<NodeEvent>,
+    network_globals: Arc<NetworkGlobals>,
+    log: slog::Logger,
+) {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+    let mut authenticated = admin_token.is_none();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match line.parse() {
+            Ok(Command::Auth(token)) => {
+                debug!(log, "Admin authentication received");
+                match &admin_token {
+                    Some(admin_token) if token_matches(admin_token, &token) => {
+                        authenticated = true;
+                        Ok(vec![])
+                    }
+                    Some(_) => {
+                        warn!(log, "Admin authentication failed");
+                        Err("Invalid token".to_string())
+                    }
+                    None => Err("No admin token is configured".to_string()),
+                }
+            }
+            Ok(Command::Watch) => {
+                debug!(log, "Admin connection watching events");
+                let events = node_events.subscribe();
+                if writer.write_all(b"ok\n").await.is_ok() {
+                    watch(events, lines, writer).await;
+                }
+                return;
+            }
+            Ok(command) if !authenticated && !command.is_read_only() => {
+                warn!(log, "Unauthenticated admin command refused"; "command" => &line);
+                Err("Not authenticated, send auth <token> first".to_string())
+            }
+            Ok(command) => {
+                debug!(log, "Admin command received"; "command" => &line);
+                execute(command, &network_send, &network_globals).await
+            }
+            Err(e) => Err(e),
+        };
+        let reply = match reply {
+            Ok(mut output) => {
+                output.push("ok".to_string());
+                output.join("\n")
+            }
+            Err(e) => format!("error: {}", e),
+        };
+        if writer
+            .write_all(format!("{}\n", reply).as_bytes())
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Writes each event to a watching connection until it closes or the node stops.
+async fn watch<R, W>(
+    mut events: broadcast::Receiver<NodeEvent>,
+    mut lines: tokio::io::Lines<R>,
+    mut writer: W,
+) where
+    R: tokio::io::AsyncBufRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    loop {
+        let line = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => event.to_string(),
+                Err(broadcast::RecvError::Lagged(missed)) => format!("lagged {}", missed),
+                Err(broadcast::RecvError::Closed) => return,
+            },
+            // commands are ignored, and the end of the connection ends the watch
+            line = lines.next_line() => match line {
+                Ok(Some(_)) => continue,
+                Ok(None) | Err(_) => return,
+            },
+        };
+        if writer
+            .write_all(format!("{}\n", line).as_bytes())
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Runs a command, returning its output lines.
+async fn execute(
+    command: Command,
+    network_send: &mpsc::UnboundedSender<NetworkMessage>,
+    network_globals: &NetworkGlobals,
+) -> Result<Vec<String>, String> {
+    let message = match command {
+        Command::Auth(_) | Command::Watch => {
+            return Err("Only accepted on a control connection".into())
+        }
+        Command::Info => {
+            let mut output = vec![
+                format!("peer_id {}", network_globals.local_peer_id()),
+                format!("enr {}", network_globals.local_enr().to_base64()),
+            ];
+            for address in network_globals.listen_multiaddrs() {
+                output.push(format!("listen {}", address));
+            }
+            output.push(format!("peers {}", network_globals.connected_peers()));
+            return Ok(output);
+        }
+        Command::Peers => {
+            return Ok(network_globals
+                .peers
+                .read()
+                .connected_peers()
+                .map(|(peer_id, info)| {
+                    format!(
+                        "{} {} {} {}",
+                        peer_id,
+                        info.reputation,
+                        info.client.kind.name(),
+                        info.client.version
+                    )
+                })
+                .collect());
+        }
+        Command::Dht => {
+            let snapshot = dht_snapshot(network_send.clone())
+                .await
+                .map_err(|_| "The network service has stopped".to_string())?;
+            return Ok(snapshot.to_string().lines().map(String::from).collect());
+        }
+        Command::Queues => {
+            let depths = network_globals.queue_depths();
+            return Ok(vec![
+                format!("behaviour_events {}", depths.behaviour_events),
+                format!("peers_to_dc {}", depths.peers_to_dc),
+                format!("rpc_events {}", depths.rpc_events),
+                format!("network_messages {}", depths.network_messages),
+                format!("deliveries {}", depths.deliveries),
+            ]);
+        }
+        Command::Memory => {
+            let usage = network_globals.memory_usage();
+            return Ok(vec![
+                format!("seen_message_ids {}", usage.seen_message_ids),
+                format!("seen_message_keys {}", usage.seen_message_keys),
+                format!("peer_db {}", usage.peer_db),
+                format!("buffer_pool {}", usage.buffer_pool),
+                format!("mcache {}", usage.mcache),
+                format!("behaviour_queues {}", usage.behaviour_queues),
+                format!("service_queues {}", usage.service_queues),
+                format!("total {}", usage.total()),
+            ]);
+        }
+        Command::DhtExport(path) => {
+            let snapshot = dht_snapshot(network_send.clone())
+                .await
+                .map_err(|_| "The network service has stopped".to_string())?;
+            snapshot
+                .export(&path)
+                .map_err(|e| format!("Could not write {:?}: {}", path, e))?;
+            return Ok(vec![format!("entries {}", snapshot.entries.len())]);
+        }
+        Command::Subscribe(topic) => NetworkMessage::Subscribe {
+            subscriptions: vec![GossipTopic::new(topic)],
+        },
+        Command::Unsubscribe(topic) => NetworkMessage::Unsubscribe {
+            topics: vec![GossipTopic::new(topic)],
+        },
+        Command::Publish { topic, data } => NetworkMessage::Publish {
+            topic: GossipTopic::new(topic),
+            message: data,
+            ttl: None,
+        },
+        Command::PauseForwarding(topic) => NetworkMessage::PauseForwarding { topic },
+        Command::ResumeForwarding(topic) => NetworkMessage::ResumeForwarding { topic },
+        Command::Dial(address) => {
+            let token = next_dial_token();
+            network_send
+                .send(NetworkMessage::Dial { address, token })
+                .map_err(|_| "The network service has stopped".to_string())?;
+            return Ok(vec![format!("token {}", token)]);
+        }
+        Command::Disconnect(peer_id) => NetworkMessage::Disconnect { peer_id },
+        Command::Ban(peer_id) => NetworkMessage::ReportPeer {
+            peer_id,
+            action: PeerAction::Fatal,
+        },
+        Command::Mute(peer_id) => NetworkMessage::MutePeer { peer_id },
+        Command::Unmute(peer_id) => NetworkMessage::UnmutePeer { peer_id },
+        Command::LogLevel(level) => NetworkMessage::SetLogLevel { level },
+    };
+    network_send
+        .send(message)
+        .map_err(|_| "The network service has stopped".to_string())?;
+    Ok(vec![])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::network_globals;
+    use futures::executor::block_on;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!("auth secret".parse(), Ok(Command::Auth("secret".into())));
+        assert_eq!("info".parse(), Ok(Command::Info));
+        assert_eq!(" peers ".parse(), Ok(Command::Peers));
+        assert_eq!("dht".parse(), Ok(Command::Dht));
+        assert_eq!("queues".parse(), Ok(Command::Queues));
+        assert_eq!("memory".parse(), Ok(Command::Memory));
+        assert_eq!("watch".parse(), Ok(Command::Watch));
+        assert_eq!(
+            "dht-export /tmp/dht.txt".parse(),
+            Ok(Command::DhtExport("/tmp/dht.txt".into()))
+        );
+        assert_eq!(
+            "subscribe /mothra/topic".parse(),
+            Ok(Command::Subscribe("/mothra/topic".into()))
+        );
+        assert_eq!(
+            "unsubscribe /mothra/topic".parse(),
+            Ok(Command::Unsubscribe("/mothra/topic".into()))
+        );
+        assert_eq!(
+            "dial /ip4/127.0.0.1/tcp/9000".parse(),
+            Ok(Command::Dial("/ip4/127.0.0.1/tcp/9000".parse().unwrap()))
+        );
+        assert_eq!(
+            "publish /mothra/topic 0x0102ff".parse(),
+            Ok(Command::Publish {
+                topic: "/mothra/topic".into(),
+                data: vec![1, 2, 255],
+            })
+        );
+        assert_eq!(
+            "pause-forwarding /mothra/topic".parse(),
+            Ok(Command::PauseForwarding("/mothra/topic".into()))
+        );
+        assert_eq!(
+            "resume-forwarding /mothra/topic".parse(),
+            Ok(Command::ResumeForwarding("/mothra/topic".into()))
+        );
+        let peer_id = PeerId::random();
+        assert_eq!(
+            format!("disconnect {}", peer_id).parse(),
+            Ok(Command::Disconnect(peer_id.clone()))
+        );
+        assert_eq!(
+            format!("ban {}", peer_id).parse(),
+            Ok(Command::Ban(peer_id.clone()))
+        );
+        assert_eq!(
+            format!("mute {}", peer_id).parse(),
+            Ok(Command::Mute(peer_id.clone()))
+        );
+        assert_eq!(
+            format!("unmute {}", peer_id).parse(),
+            Ok(Command::Unmute(peer_id))
+        );
+
+        assert_eq!(
+            "log-level debug".parse(),
+            Ok(Command::LogLevel(Level::Debug))
+        );
+
+        assert!("".parse::<Command>().is_err());
+        assert!("reboot".parse::<Command>().is_err());
+        assert!("subscribe".parse::<Command>().is_err());
+        assert!("dht-export".parse::<Command>().is_err());
+        assert!("publish /mothra/topic 0xzz".parse::<Command>().is_err());
+        assert!("disconnect not-a-peer".parse::<Command>().is_err());
+        assert!("dial not-an-address".parse::<Command>().is_err());
+        assert!("auth".parse::<Command>().is_err());
+        assert!("peers extra".parse::<Command>().is_err());
+        assert!("log-level verbose".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_execute() {
+        let globals = network_globals();
+        let (send, mut recv) = mpsc::unbounded_channel();
+        let output = block_on(execute(Command::Info, &send, &globals)).unwrap();
+        assert_eq!(output[0], format!("peer_id {}", globals.local_peer_id()));
+        assert_eq!(output.last(), Some(&"peers 0".to_string()));
+        assert_eq!(
+            block_on(execute(Command::Peers, &send, &globals)),
+            Ok(vec![])
+        );
+        let queues = block_on(execute(Command::Queues, &send, &globals)).unwrap();
+        assert_eq!(queues.len(), 5);
+
+        // the other commands are handed to the network service
+        let subscribe = Command::Subscribe("/mothra/topic".into());
+        assert_eq!(block_on(execute(subscribe, &send, &globals)), Ok(vec![]));
+        match recv.try_recv() {
+            Ok(NetworkMessage::Subscribe { subscriptions }) => {
+                assert_eq!(
+                    subscriptions,
+                    vec![GossipTopic::new("/mothra/topic".into())]
+                )
+            }
+            _ => panic!("expected a subscription"),
+        }
+        let dial = Command::Dial("/ip4/127.0.0.1/tcp/9000".parse().unwrap());
+        let output = block_on(execute(dial, &send, &globals)).unwrap();
+        match recv.try_recv() {
+            Ok(NetworkMessage::Dial { token, .. }) => {
+                assert_eq!(output, vec![format!("token {}", token)])
+            }
+            _ => panic!("expected a dial"),
+        }
+        let peer_id = PeerId::random();
+        let ban = Command::Ban(peer_id.clone());
+        assert_eq!(block_on(execute(ban, &send, &globals)), Ok(vec![]));
+        match recv.try_recv() {
+            Ok(NetworkMessage::ReportPeer {
+                peer_id: banned,
+                action,
+            }) => {
+                assert_eq!(banned, peer_id);
+                assert!(matches!(action, PeerAction::Fatal));
+            }
+            _ => panic!("expected a ban"),
+        }
+        drop(recv);
+        let mute = Command::Mute(PeerId::random());
+        assert!(block_on(execute(mute, &send, &globals)).is_err());
+    }
+
+    /// Sends a command on a control connection, returning the first line of the reply.
+    async fn command(client: &mut BufReader<UnixStream>, line: &str) -> String {
+        client
+            .get_mut()
+            .write_all(format!("{}\n", line).as_bytes())
+            .await
+            .unwrap();
+        let mut reply = String::new();
+        client.read_line(&mut reply).await.unwrap();
+        reply.trim_end().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_serve_requires_authentication() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let (send, mut recv) = mpsc::unbounded_channel();
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let admin_token = Some(Arc::new("secret".to_string()));
+        let (node_events, _) = broadcast::channel(8);
+        let globals = network_globals();
+        tokio::spawn(serve(server, admin_token, send, node_events, globals, log));
+        let mut client = BufReader::new(client);
+
+        // reading needs no token
+        assert_eq!(command(&mut client, "peers").await, "ok");
+        let reply = command(&mut client, "subscribe /mothra/topic").await;
+        assert!(reply.starts_with("error: Not authenticated"));
+        let reply = command(&mut client, "auth wrong").await;
+        assert_eq!(reply, "error: Invalid token");
+        assert!(recv.try_recv().is_err());
+
+        assert_eq!(command(&mut client, "auth secret").await, "ok");
+        let reply = command(&mut client, "unsubscribe /mothra/topic").await;
+        assert_eq!(reply, "ok");
+        match recv.try_recv() {
+            Ok(NetworkMessage::Unsubscribe { topics }) => {
+                assert_eq!(topics, vec![GossipTopic::new("/mothra/topic".into())])
+            }
+            _ => panic!("expected an unsubscription"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let (send, _recv) = mpsc::unbounded_channel();
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let (node_events, _) = broadcast::channel(8);
+        let globals = network_globals();
+        let watched = node_events.clone();
+        tokio::spawn(serve(server, None, send, watched, globals, log));
+        let mut client = BufReader::new(client);
+        assert_eq!(command(&mut client, "watch").await, "ok");
+
+        let peer_id = PeerId::random();
+        node_events
+            .send(NodeEvent::PeerConnected(peer_id.clone()))
+            .unwrap();
+        node_events
+            .send(NodeEvent::Gossip {
+                topic: "/mothra/topic".into(),
+                message_id: "id".into(),
+                peer_id: peer_id.clone(),
+            })
+            .unwrap();
+        // commands are no longer answered
+        let mut reply = String::new();
+        client.read_line(&mut reply).await.unwrap();
+        assert_eq!(reply, format!("peer-connected {}\n", peer_id));
+        let reply = command(&mut client, "peers").await;
+        assert_eq!(reply, format!("gossip /mothra/topic id {}", peer_id));
+    }
+
+    #[test]
+    fn test_token_matches() {
+        assert!(token_matches("secret", "secret"));
+        assert!(!token_matches("secret", "secreT"));
+        assert!(!token_matches("secret", "secret2"));
+        assert!(!token_matches("secret", ""));
+    }
+
+    #[tokio::test]
+    async fn test_bind() {
+        let dir = TempDir::new("control").unwrap();
+        let path = dir.path().join("admin.sock");
+        // a file that is not a socket is left alone
+        fs::write(&path, b"data").unwrap();
+        assert!(bind(&path).is_err());
+        fs::remove_file(&path).unwrap();
+
+        let (_listener, socket_file) = bind(&path).unwrap();
+        let metadata = fs::symlink_metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        // the private directory the socket was bound in is gone
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        UnixStream::connect(&path).await.unwrap();
+        drop(socket_file);
+        assert!(!path.exists());
+
+        // a socket left behind by an earlier run is replaced
+        let _stale = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let (_listener, _socket_file) = bind(&path).unwrap();
+        UnixStream::connect(&path).await.unwrap();
+    }
+}
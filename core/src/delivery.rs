@@ -0,0 +1,812 @@
+//! The queue between the network service and the `Subscriber`.
+//!
+//! Messages received from the network are queued here and handed to the `Subscriber` by a pool of
+//! worker threads, so a slow application no longer stalls the swarm. Each worker has its own
+//! bounded queue and the `OverflowPolicy` decides what happens once it is full.
+//!
+//! RPC messages are never dropped: a request the application never sees would keep its index
+//! until the peer gives up on it, and a response would leave the application waiting. A full
+//! queue takes them in beyond its capacity, dropping other messages in their place where the
+//! policy drops the oldest.
+//!
+//! # Ordering
+//!
+//! Messages are assigned to a worker by their `DeliveryOrdering` key, so messages with the same
+//! key are delivered one at a time, in the order they were received. Messages with different keys
+//! may be delivered concurrently and in any order. RPC messages are always keyed by peer, and
+//! gossip on ordered topics (see the `reorder` module) by topic. With a single worker every message
+//! is delivered in the order it was received.
+//!
+//! # Slow consumers
+//!
+//! Workers time their callbacks and how long messages waited for them, and report a `Subscriber`
+//! that is consistently slow. See the `slow_consumer` module.
+//!
+//! # Without worker threads
+//!
+//! With no workers, messages are delivered by a task on the network's runtime rather than by
+//! threads of their own. This suits current-thread runtimes, such as the event loop of a GUI
+//! application, as long as the `Subscriber` returns quickly.
+use crate::mirror::Mirror;
+use crate::mothra::{GossipMessage, NetworkErrorKind, Subscriber};
+use crate::slow_consumer::ConsumerMonitor;
+use futures::future;
+use network::{rpc::ProtocolId, NetworkGlobals, Overload, TaskExecutor};
+use parking_lot::Mutex;
+use slog::warn;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// The default number of messages that can wait for the `Subscriber`, per worker.
+pub const DEFAULT_QUEUE_SIZE: usize = 4096;
+
+/// The default number of threads calling the `Subscriber`.
+pub const DEFAULT_WORKERS: usize = 1;
+
+/// What to do with a message that arrives while the delivery queue is full.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverflowPolicy {
+    /// Drop the message that has been waiting the longest to make room, other than RPC messages.
+    DropOldest,
+    /// Drop the message that just arrived, unless it is an RPC message.
+    DropNewest,
+    /// Stop processing network events until the `Subscriber` catches up, or the network shuts
+    /// down. A message still waiting for room at shutdown is dropped.
+    Block,
+}
+
+impl Default for OverflowPolicy {
+    fn default() -> Self {
+        OverflowPolicy::DropOldest
+    }
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "drop-newest" => Ok(OverflowPolicy::DropNewest),
+            "block" => Ok(OverflowPolicy::Block),
+            _ => Err(format!("Unknown overflow policy: {}", s)),
+        }
+    }
+}
+
+/// Which messages are kept in order when delivered by several workers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeliveryOrdering {
+    /// Messages from the same peer are delivered in order.
+    PerPeer,
+    /// Gossip on the same topic is delivered in order. RPC messages are ordered per peer.
+    PerTopic,
+}
+
+impl Default for DeliveryOrdering {
+    fn default() -> Self {
+        DeliveryOrdering::PerPeer
+    }
+}
+
+impl FromStr for DeliveryOrdering {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "peer" => Ok(DeliveryOrdering::PerPeer),
+            "topic" => Ok(DeliveryOrdering::PerTopic),
+            _ => Err(format!("Unknown delivery ordering: {}", s)),
+        }
+    }
+}
+
+/// A message waiting to be handed to the `Subscriber`.
+#[derive(Debug)]
+pub enum Delivery {
+    Gossip(GossipMessage),
+    Rpc {
+        method: String,
+        req_resp: u8,
+        protocol: Option<ProtocolId>,
+        peer_id: String,
+        data: Vec<u8>,
+    },
+    RpcCancelled {
+        method: String,
+        peer_id: String,
+        index: u8,
+        cause: String,
+    },
+    DiscoveredEnr {
+        peer_id: String,
+        enr: String,
+    },
+    DiscoveredPeer {
+        peer_id: String,
+        new: bool,
+    },
+    PublishExpired {
+        topic: String,
+        data: Vec<u8>,
+    },
+    TopicReady {
+        topic: String,
+    },
+    Overloaded {
+        overload: Overload,
+    },
+    Error {
+        kind: NetworkErrorKind,
+        context: String,
+    },
+    DialResult {
+        token: u64,
+        result: Result<String, String>,
+    },
+    StatusAnswered {
+        peer_id: String,
+        status: Vec<u8>,
+    },
+}
+
+impl Delivery {
+    /// Returns true if the overflow policy may drop this message.
+    fn is_droppable(&self) -> bool {
+        !matches!(self, Delivery::Rpc { .. })
+    }
+
+    /// The key that determines which worker delivers this message.
+    fn ordering_key(&self, ordering: DeliveryOrdering, ordered_topics: &HashSet<String>) -> &str {
+        match (self, ordering) {
+            (Delivery::Gossip(message), DeliveryOrdering::PerTopic) => &message.topic,
+            (Delivery::Gossip(message), _) if ordered_topics.contains(&message.topic) => {
+                &message.topic
+            }
+            (Delivery::PublishExpired { topic, .. }, _) => topic,
+            (Delivery::TopicReady { topic }, _) => topic,
+            // errors are reported in the order they happened
+            (Delivery::Error { .. }, _) => "error",
+            (Delivery::DialResult { .. }, _) => "dial",
+            (Delivery::Overloaded { .. }, _) => "overload",
+            (Delivery::Gossip(message), _) => &message.peer_id,
+            (Delivery::Rpc { peer_id, .. }, _)
+            | (Delivery::RpcCancelled { peer_id, .. }, _)
+            | (Delivery::DiscoveredEnr { peer_id, .. }, _)
+            | (Delivery::DiscoveredPeer { peer_id, .. }, _)
+            | (Delivery::StatusAnswered { peer_id, .. }, _) => peer_id,
+        }
+    }
+
+    /// A copy of this message for the mirror, if it was received from the network.
+    pub(crate) fn mirrored(&self) -> Option<Delivery> {
+        match self {
+            Delivery::Gossip(message) => Some(Delivery::Gossip(message.clone())),
+            Delivery::Rpc {
+                method,
+                req_resp,
+                protocol,
+                peer_id,
+                data,
+            } => Some(Delivery::Rpc {
+                method: method.clone(),
+                req_resp: *req_resp,
+                protocol: protocol.clone(),
+                peer_id: peer_id.clone(),
+                data: data.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Calls the `Subscriber` callback for this message.
+    pub(crate) fn deliver(self, client: &dyn Subscriber) {
+        match self {
+            Delivery::Gossip(message) => client.receive_gossip(message),
+            Delivery::Rpc {
+                method,
+                req_resp,
+                protocol,
+                peer_id,
+                data,
+            } => client.receive_rpc(method, req_resp, protocol, peer_id, data),
+            Delivery::RpcCancelled {
+                method,
+                peer_id,
+                index,
+                cause,
+            } => client.rpc_cancelled(method, peer_id, index, cause),
+            Delivery::DiscoveredEnr { peer_id, enr } => client.discovered_enr(peer_id, enr),
+            Delivery::DiscoveredPeer { peer_id, new } => client.discovered_peer(peer_id, new),
+            Delivery::PublishExpired { topic, data } => client.publish_expired(topic, data),
+            Delivery::TopicReady { topic } => client.topic_ready(topic),
+            Delivery::Overloaded { overload } => client.overloaded(overload),
+            Delivery::Error { kind, context } => client.on_error(kind, context),
+            Delivery::DialResult { token, result } => client.dial_result(token, result),
+            Delivery::StatusAnswered { peer_id, status } => client.status_answered(peer_id, status),
+        }
+    }
+}
+
+struct Shared {
+    /// The messages waiting, with the time they were queued.
+    queue: Mutex<VecDeque<(Instant, Delivery)>>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    closed: AtomicBool,
+    /// Notified when a message is queued or the queue is closed.
+    pushed: Notify,
+    /// Notified when a message is taken off the queue or the queue is closed.
+    popped: Notify,
+    /// Holds the dropped message counters.
+    network_globals: Arc<NetworkGlobals>,
+    /// Fires when the network shuts down, ending the wait for room of `OverflowPolicy::Block`.
+    exit: exit_future::Exit,
+}
+
+/// A bounded queue of messages for the `Subscriber`.
+#[derive(Clone)]
+pub struct DeliveryQueue {
+    shared: Arc<Shared>,
+}
+
+impl DeliveryQueue {
+    /// Creates a queue holding up to `capacity` messages. Dropped messages are counted in
+    /// `network_globals`. A push waiting for room gives up once `exit` fires.
+    pub fn new(
+        capacity: usize,
+        policy: OverflowPolicy,
+        network_globals: Arc<NetworkGlobals>,
+        exit: exit_future::Exit,
+    ) -> Self {
+        DeliveryQueue {
+            shared: Arc::new(Shared {
+                queue: Mutex::new(VecDeque::new()),
+                capacity: capacity.max(1),
+                policy,
+                closed: AtomicBool::new(false),
+                pushed: Notify::new(),
+                popped: Notify::new(),
+                network_globals,
+                exit,
+            }),
+        }
+    }
+
+    /// Queues a message, applying the overflow policy if the queue is full. With
+    /// `OverflowPolicy::Block` this waits until there is room, the queue is closed or the network
+    /// shuts down.
+    pub async fn push(&self, delivery: Delivery) {
+        loop {
+            {
+                let mut queue = self.shared.queue.lock();
+                if queue.len() < self.shared.capacity {
+                    queue.push_back((Instant::now(), delivery));
+                    break;
+                }
+                match self.shared.policy {
+                    OverflowPolicy::DropNewest if delivery.is_droppable() => {
+                        self.count_dropped(&delivery);
+                        return;
+                    }
+                    OverflowPolicy::DropNewest => {
+                        queue.push_back((Instant::now(), delivery));
+                        break;
+                    }
+                    OverflowPolicy::DropOldest => {
+                        let oldest = queue.iter().position(|(_, queued)| queued.is_droppable());
+                        if let Some((_, oldest)) = oldest.and_then(|index| queue.remove(index)) {
+                            self.count_dropped(&oldest);
+                        }
+                        queue.push_back((Instant::now(), delivery));
+                        break;
+                    }
+                    OverflowPolicy::Block => {}
+                }
+            }
+            if self.shared.closed.load(Ordering::Acquire) {
+                return;
+            }
+            let mut exit = self.shared.exit.clone();
+            tokio::select! {
+                _ = self.shared.popped.notified() => {}
+                _ = &mut exit => {
+                    self.count_dropped(&delivery);
+                    return;
+                }
+            }
+        }
+        self.shared.pushed.notify();
+    }
+
+    /// Takes the next message off the queue, with the time it was queued, waiting for one to
+    /// arrive. Returns `None` once the queue is closed.
+    pub async fn pop(&self) -> Option<(Instant, Delivery)> {
+        loop {
+            if self.shared.closed.load(Ordering::Acquire) {
+                return None;
+            }
+            if let Some(queued) = self.shared.queue.lock().pop_front() {
+                self.shared.popped.notify();
+                return Some(queued);
+            }
+            self.shared.pushed.notified().await;
+        }
+    }
+
+    /// Waits until every queued message was taken off the queue, or the queue is closed.
+    pub async fn drained(&self) {
+        while !self.shared.closed.load(Ordering::Acquire) && !self.is_empty() {
+            self.shared.popped.notified().await;
+        }
+    }
+
+    /// Closes the queue. Messages still queued are discarded.
+    pub fn close(&self) {
+        self.shared.closed.store(true, Ordering::Release);
+        self.shared.pushed.notify();
+        self.shared.popped.notify();
+    }
+
+    /// The number of messages waiting to be delivered.
+    pub fn len(&self) -> usize {
+        self.shared.queue.lock().len()
+    }
+
+    /// Returns true if no messages are waiting to be delivered.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn count_dropped(&self, delivery: &Delivery) {
+        let globals = &self.shared.network_globals;
+        let counter = match delivery {
+            Delivery::Gossip(_) => &globals.dropped_gossip_messages,
+            Delivery::Rpc { .. }
+            | Delivery::RpcCancelled { .. }
+            | Delivery::StatusAnswered { .. } => &globals.dropped_rpc_messages,
+            // discovery results and our own messages are not messages from the network
+            Delivery::DiscoveredEnr { .. }
+            | Delivery::DiscoveredPeer { .. }
+            | Delivery::PublishExpired { .. }
+            | Delivery::TopicReady { .. }
+            | Delivery::Overloaded { .. }
+            | Delivery::Error { .. }
+            | Delivery::DialResult { .. } => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Hands the messages of one queue to the `Subscriber`, timing the callbacks.
+struct Worker {
+    client: Arc<dyn Subscriber>,
+    monitor: Option<ConsumerMonitor>,
+    log: slog::Logger,
+}
+
+impl Worker {
+    fn new(
+        index: usize,
+        client: Arc<dyn Subscriber>,
+        slow_threshold: Option<Duration>,
+        log: &slog::Logger,
+    ) -> Self {
+        Worker {
+            client,
+            monitor: slow_threshold
+                .map(|threshold| ConsumerMonitor::new(index, threshold, Instant::now())),
+            log: log.clone(),
+        }
+    }
+
+    /// Delivers a message queued at `queued`.
+    fn deliver(&mut self, queued: Instant, delivery: Delivery) {
+        let started = Instant::now();
+        delivery.deliver(self.client.as_ref());
+        let monitor = match self.monitor.as_mut() {
+            Some(monitor) => monitor,
+            None => return,
+        };
+        let now = Instant::now();
+        if let Some(stats) = monitor.record(started - queued, now - started, now) {
+            warn!(self.log, "The application is slow to handle the messages it receives";
+                "worker" => stats.worker,
+                "slow_callbacks" => stats.slow_callbacks,
+                "callbacks" => stats.callbacks,
+                "mean_callback_ms" => stats.mean_callback.as_millis() as u64,
+                "max_callback_ms" => stats.max_callback.as_millis() as u64,
+                "mean_wait_ms" => stats.mean_wait.as_millis() as u64);
+            self.client.slow_consumer(stats);
+        }
+    }
+}
+
+/// How messages are handed to the `Subscriber`.
+#[derive(Clone, Debug)]
+pub struct DispatcherConfig {
+    /// The number of threads calling the `Subscriber`. With none, messages are handed over by a
+    /// task on the runtime instead, which then must not be blocked by the `Subscriber`.
+    pub workers: usize,
+    /// The number of messages that can wait for the `Subscriber`, per worker.
+    pub queue_size: usize,
+    pub overflow: OverflowPolicy,
+    pub ordering: DeliveryOrdering,
+    /// Topics whose messages must stay in order regardless of `ordering`.
+    pub ordered_topics: Vec<String>,
+    /// Callbacks taking longer than this count as slow. `None` does not time the callbacks.
+    pub slow_threshold: Option<Duration>,
+}
+
+/// Distributes messages over the delivery workers.
+#[derive(Clone)]
+pub struct Dispatcher {
+    queues: Vec<DeliveryQueue>,
+    ordering: DeliveryOrdering,
+    /// Topics whose messages must stay in order regardless of `ordering`.
+    ordered_topics: Arc<HashSet<String>>,
+    /// Receives a copy of the messages from the network, if traffic is mirrored.
+    mirror: Option<Arc<Mirror>>,
+}
+
+impl Dispatcher {
+    /// Starts the workers that hand messages to `client`, as configured.
+    pub fn spawn(
+        config: DispatcherConfig,
+        client: Arc<dyn Subscriber>,
+        network_globals: Arc<NetworkGlobals>,
+        executor: &TaskExecutor,
+        log: &slog::Logger,
+    ) -> Self {
+        let DispatcherConfig {
+            workers,
+            queue_size,
+            overflow,
+            ordering,
+            ordered_topics,
+            slow_threshold,
+        } = config;
+        if workers == 0 {
+            let queue = DeliveryQueue::new(queue_size, overflow, network_globals, executor.exit());
+            let task_queue = queue.clone();
+            let mut worker = Worker::new(0, client, slow_threshold, log);
+            // the task runs until the queue is closed, so the queue can be drained at shutdown
+            executor.spawn_without_exit(
+                async move {
+                    while let Some((queued, delivery)) = task_queue.pop().await {
+                        worker.deliver(queued, delivery);
+                    }
+                },
+                "mothra-delivery",
+            );
+            return Dispatcher {
+                queues: vec![queue],
+                ordering,
+                ordered_topics: Arc::new(ordered_topics.into_iter().collect()),
+                mirror: None,
+            };
+        }
+
+        let queues = (0..workers)
+            .map(|index| {
+                let queue = DeliveryQueue::new(
+                    queue_size,
+                    overflow,
+                    network_globals.clone(),
+                    executor.exit(),
+                );
+                let worker_queue = queue.clone();
+                let mut worker = Worker::new(index, client.clone(), slow_threshold, log);
+                executor.spawn_blocking(
+                    move || {
+                        while let Some((queued, delivery)) =
+                            futures::executor::block_on(worker_queue.pop())
+                        {
+                            worker.deliver(queued, delivery);
+                        }
+                    },
+                    "mothra-delivery",
+                );
+                queue
+            })
+            .collect();
+        Dispatcher {
+            queues,
+            ordering,
+            ordered_topics: Arc::new(ordered_topics.into_iter().collect()),
+            mirror: None,
+        }
+    }
+
+    /// Hands a copy of every message from the network to `mirror` as well, without waiting for
+    /// it.
+    pub fn mirror_to(&mut self, mirror: Mirror) {
+        self.mirror = Some(Arc::new(mirror));
+    }
+
+    /// Queues a message on the worker responsible for its ordering key.
+    pub async fn push(&self, delivery: Delivery) {
+        if let Some(mirror) = &self.mirror {
+            mirror.push(&delivery);
+        }
+        let mut hasher = DefaultHasher::new();
+        delivery
+            .ordering_key(self.ordering, &self.ordered_topics)
+            .hash(&mut hasher);
+        let worker = (hasher.finish() % self.queues.len() as u64) as usize;
+        self.queues[worker].push(delivery).await
+    }
+
+    /// The number of messages waiting to be delivered, over all workers.
+    pub fn len(&self) -> usize {
+        self.queues.iter().map(DeliveryQueue::len).sum()
+    }
+
+    /// Returns true if no messages are waiting to be delivered.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Waits until the workers took every queued message.
+    pub async fn drain(&self) {
+        future::join_all(self.queues.iter().map(DeliveryQueue::drained)).await;
+    }
+
+    /// Stops the workers. Messages still queued are discarded.
+    pub fn close(&self) {
+        self.queues.iter().for_each(DeliveryQueue::close);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mothra::{GossipTiming, NetworkMessage};
+    use crate::test_utils::network_globals;
+    use futures::executor::block_on;
+    use futures::FutureExt;
+    use std::time::SystemTime;
+    use tokio::sync::mpsc;
+
+    /// Records the data of the gossip it receives.
+    struct Recorder(Arc<Mutex<Vec<u8>>>);
+
+    impl Subscriber for Recorder {
+        fn init(&mut self, _send: mpsc::UnboundedSender<NetworkMessage>, _fork_id: Vec<u8>) {}
+
+        fn discovered_peer(&self, _peer: String, _new: bool) {}
+
+        fn receive_gossip(&self, message: GossipMessage) {
+            self.0.lock().extend(message.data);
+        }
+
+        fn receive_rpc(
+            &self,
+            _method: String,
+            _req_resp: u8,
+            _protocol: Option<ProtocolId>,
+            _peer: String,
+            _data: Vec<u8>,
+        ) {
+        }
+    }
+
+    fn gossip(data: u8) -> Delivery {
+        gossip_on("peer", "topic", data)
+    }
+
+    fn gossip_on(peer_id: &str, topic: &str, data: u8) -> Delivery {
+        Delivery::Gossip(GossipMessage {
+            message_id: data.to_string(),
+            sequence_number: 0,
+            agent_string: String::new(),
+            peer_id: peer_id.into(),
+            topic: topic.into(),
+            topics: vec![],
+            data: vec![data],
+            timing: GossipTiming {
+                received_at: SystemTime::now(),
+                latency: None,
+            },
+        })
+    }
+
+    fn rpc(data: u8) -> Delivery {
+        Delivery::Rpc {
+            method: "Status".into(),
+            req_resp: 1,
+            protocol: None,
+            peer_id: "peer".into(),
+            data: vec![data],
+        }
+    }
+
+    /// The data of the next message queued.
+    fn pop_data(queue: &DeliveryQueue) -> Vec<u8> {
+        match block_on(queue.pop()) {
+            Some((_, Delivery::Gossip(message))) => message.data,
+            Some((_, Delivery::Rpc { data, .. })) => data,
+            other => panic!("unexpected delivery: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_ordering_keys() {
+        let none = HashSet::new();
+        let ordered: HashSet<String> = vec!["ordered".to_string()].into_iter().collect();
+        let message = gossip_on("a", "topic", 0);
+        assert_eq!(message.ordering_key(DeliveryOrdering::PerPeer, &none), "a");
+        assert_eq!(
+            message.ordering_key(DeliveryOrdering::PerTopic, &none),
+            "topic"
+        );
+        // ordered topics stay in order whatever the ordering
+        let message = gossip_on("a", "ordered", 0);
+        assert_eq!(
+            message.ordering_key(DeliveryOrdering::PerPeer, &ordered),
+            "ordered"
+        );
+        // RPC messages are always ordered per peer
+        for ordering in &[DeliveryOrdering::PerPeer, DeliveryOrdering::PerTopic] {
+            assert_eq!(rpc(0).ordering_key(*ordering, &ordered), "peer");
+        }
+    }
+
+    #[test]
+    fn test_workers_keep_peer_order() {
+        let mut runtime = tokio::runtime::Builder::new()
+            .threaded_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let (_signal, exit) = exit_future::signal();
+        let executor = TaskExecutor::new(runtime.handle().clone(), exit, log.clone());
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let config = DispatcherConfig {
+            workers: 4,
+            queue_size: 256,
+            overflow: OverflowPolicy::default(),
+            ordering: DeliveryOrdering::PerPeer,
+            ordered_topics: vec![],
+            slow_threshold: None,
+        };
+        let dispatcher = Dispatcher::spawn(
+            config,
+            Arc::new(Recorder(received.clone())),
+            network_globals(),
+            &executor,
+            &log,
+        );
+
+        // two peers, their messages interleaved, and delivered by any of the workers
+        runtime.block_on(async {
+            for data in 0..100 {
+                let peer_id = if data % 2 == 0 { "a" } else { "b" };
+                dispatcher.push(gossip_on(peer_id, "topic", data)).await;
+            }
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while received.lock().len() < 100 {
+                    tokio::time::delay_for(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .unwrap();
+        });
+        dispatcher.close();
+        let received = received.lock();
+        for parity in 0..2 {
+            let peer: Vec<u8> = received
+                .iter()
+                .cloned()
+                .filter(|data| data % 2 == parity)
+                .collect();
+            let sent: Vec<u8> = (0..100).filter(|data| data % 2 == parity).collect();
+            assert_eq!(peer, sent);
+        }
+    }
+
+    #[test]
+    fn test_drop_oldest_keeps_rpc() {
+        let (_signal, exit) = exit_future::signal();
+        let globals = network_globals();
+        let queue = DeliveryQueue::new(2, OverflowPolicy::DropOldest, globals.clone(), exit);
+        block_on(queue.push(rpc(0)));
+        block_on(queue.push(gossip(1)));
+        // the oldest gossip makes room, the request stays
+        block_on(queue.push(gossip(2)));
+        assert_eq!(globals.dropped_gossip_messages(), 1);
+        // with only requests left, a request is queued beyond the capacity
+        block_on(queue.push(rpc(3)));
+        block_on(queue.push(rpc(4)));
+        assert_eq!(queue.len(), 3);
+        assert_eq!(globals.dropped_rpc_messages(), 0);
+        assert_eq!(pop_data(&queue), vec![0]);
+        assert_eq!(pop_data(&queue), vec![3]);
+        assert_eq!(pop_data(&queue), vec![4]);
+    }
+
+    #[test]
+    fn test_drop_newest_keeps_rpc() {
+        let (_signal, exit) = exit_future::signal();
+        let globals = network_globals();
+        let queue = DeliveryQueue::new(1, OverflowPolicy::DropNewest, globals.clone(), exit);
+        block_on(queue.push(gossip(0)));
+        block_on(queue.push(gossip(1)));
+        assert_eq!(globals.dropped_gossip_messages(), 1);
+        block_on(queue.push(rpc(2)));
+        assert_eq!(queue.len(), 2);
+        assert_eq!(globals.dropped_rpc_messages(), 0);
+        assert_eq!(pop_data(&queue), vec![0]);
+        assert_eq!(pop_data(&queue), vec![2]);
+    }
+
+    #[test]
+    fn test_block_until_room_or_exit() {
+        let (signal, exit) = exit_future::signal();
+        let globals = network_globals();
+        let queue = DeliveryQueue::new(1, OverflowPolicy::Block, globals.clone(), exit);
+        block_on(queue.push(gossip(0)));
+        let mut push = Box::pin(queue.push(rpc(1)));
+        assert!(push.as_mut().now_or_never().is_none());
+        // taking a message off the queue makes room for the waiting one
+        assert_eq!(pop_data(&queue), vec![0]);
+        block_on(push);
+        assert_eq!(queue.len(), 1);
+
+        // the network shutting down ends the wait, dropping the message
+        let mut push = Box::pin(queue.push(gossip(2)));
+        assert!(push.as_mut().now_or_never().is_none());
+        signal.fire().unwrap();
+        block_on(push);
+        assert_eq!(globals.dropped_gossip_messages(), 1);
+        assert_eq!(pop_data(&queue), vec![1]);
+    }
+
+    #[test]
+    fn test_drain_without_workers_after_exit() {
+        let mut runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let (signal, exit) = exit_future::signal();
+        let executor = TaskExecutor::new(runtime.handle().clone(), exit, log.clone());
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let config = DispatcherConfig {
+            workers: 0,
+            queue_size: 8,
+            overflow: OverflowPolicy::default(),
+            ordering: DeliveryOrdering::default(),
+            ordered_topics: vec![],
+            slow_threshold: None,
+        };
+        let dispatcher = Dispatcher::spawn(
+            config,
+            Arc::new(Recorder(received.clone())),
+            network_globals(),
+            &executor,
+            &log,
+        );
+
+        // messages queued as the network shuts down are still delivered when draining
+        signal.fire().unwrap();
+        runtime.block_on(async {
+            for data in 0..3 {
+                dispatcher.push(gossip(data)).await;
+            }
+            tokio::time::timeout(Duration::from_secs(5), dispatcher.drain())
+                .await
+                .unwrap();
+        });
+        dispatcher.close();
+        assert_eq!(*received.lock(), vec![0, 1, 2]);
+    }
+}
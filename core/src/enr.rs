@@ -0,0 +1,117 @@
+//! Handlers for the `enr` subcommands, which decode, generate and re-sign ENRs.
+use crate::cli::{EXIT_FAILURE, EXIT_INVALID_ARGS, EXIT_SUCCESS};
+use crate::config::Config;
+use clap::ArgMatches;
+use network::keys::{keypair_from_hex, load_keypair_file, KeyType, NETWORK_KEY_FILENAME};
+use network::{decode_enr, generate_enr, resign_enr, Enr, EnrInfo, EnrUpdate, Keypair};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Runs the `enr` subcommand, printing the resulting ENR and returning the process exit code.
+pub fn run(config: &Config, args: &ArgMatches) -> i32 {
+    let result = match args.subcommand() {
+        ("decode", Some(sub_args)) => decode(sub_args),
+        ("generate", Some(sub_args)) => generate(config, sub_args),
+        ("resign", Some(sub_args)) => resign(config, sub_args),
+        _ => Err(Error::InvalidArgs("Unknown enr subcommand".into())),
+    };
+    match result {
+        Ok(info) => {
+            println!("{}", info);
+            EXIT_SUCCESS
+        }
+        Err(Error::InvalidArgs(e)) => {
+            eprintln!("{}", e);
+            EXIT_INVALID_ARGS
+        }
+        Err(Error::Failed(e)) => {
+            eprintln!("{}", e);
+            EXIT_FAILURE
+        }
+    }
+}
+
+/// Distinguishes bad input from failures to produce the ENR, as they map to different exit codes.
+enum Error {
+    InvalidArgs(String),
+    Failed(String),
+}
+
+fn decode(args: &ArgMatches) -> Result<EnrInfo, Error> {
+    let enr_str = args.value_of("enr").unwrap_or_default();
+    decode_enr(enr_str).map_err(Error::InvalidArgs)
+}
+
+fn generate(config: &Config, args: &ArgMatches) -> Result<EnrInfo, Error> {
+    let keypair = load_key(config, args)?;
+    let update = parse_update(args)?;
+    let enr = generate_enr(&keypair, update.ip, update.udp, update.tcp).map_err(Error::Failed)?;
+    Ok(EnrInfo::from(&enr))
+}
+
+fn resign(config: &Config, args: &ArgMatches) -> Result<EnrInfo, Error> {
+    let enr_str = args.value_of("enr").unwrap_or_default();
+    let enr = Enr::from_str(enr_str)
+        .map_err(|_| Error::InvalidArgs(format!("Invalid ENR: {}", enr_str)))?;
+    let keypair = load_key(config, args)?;
+    let update = parse_update(args)?;
+    let enr = resign_enr(&enr, &keypair, update).map_err(Error::Failed)?;
+    Ok(EnrInfo::from(&enr))
+}
+
+/// Loads the signing key from `--secret-key`, falling back to the key stored in the datadir.
+fn load_key(config: &Config, args: &ArgMatches) -> Result<Keypair, Error> {
+    match args.value_of("secret-key") {
+        Some(hex) => {
+            let key_type = args
+                .value_of("key-type")
+                .unwrap_or_default()
+                .parse::<KeyType>()
+                .map_err(Error::InvalidArgs)?;
+            keypair_from_hex(hex, key_type).map_err(|e| Error::InvalidArgs(e.to_string()))
+        }
+        None => {
+            let key_file = config.network_config.network_dir.join(NETWORK_KEY_FILENAME);
+            load_keypair_file(&key_file).map_err(|e| Error::Failed(e.to_string()))
+        }
+    }
+}
+
+/// Builds the ENR fields to set from the `--ip`, `--udp-port`, `--tcp-port` and `--field` arguments.
+fn parse_update(args: &ArgMatches) -> Result<EnrUpdate, Error> {
+    let mut update = EnrUpdate::default();
+    if let Some(ip_str) = args.value_of("ip") {
+        update.ip = Some(
+            ip_str
+                .parse::<IpAddr>()
+                .map_err(|_| Error::InvalidArgs(format!("Invalid ip address: {}", ip_str)))?,
+        );
+    }
+    if let Some(port_str) = args.value_of("udp-port") {
+        update.udp = Some(
+            port_str
+                .parse::<u16>()
+                .map_err(|_| Error::InvalidArgs(format!("Invalid udp port: {}", port_str)))?,
+        );
+    }
+    if let Some(port_str) = args.value_of("tcp-port") {
+        update.tcp = Some(
+            port_str
+                .parse::<u16>()
+                .map_err(|_| Error::InvalidArgs(format!("Invalid tcp port: {}", port_str)))?,
+        );
+    }
+    if let Some(fields) = args.values_of("field") {
+        for field in fields {
+            let mut split = field.splitn(2, '=');
+            let (key, value) = match (split.next(), split.next()) {
+                (Some(key), Some(value)) if !key.is_empty() => (key, value),
+                _ => return Err(Error::InvalidArgs(format!("Invalid field: {}", field))),
+            };
+            let value = hex::decode(value.trim_start_matches("0x"))
+                .map_err(|_| Error::InvalidArgs(format!("Invalid hex value for field: {}", key)))?;
+            update.fields.push((key.to_string(), value));
+        }
+    }
+    Ok(update)
+}
@@ -0,0 +1,385 @@
+//! Sends the same RPC request to several peers and collects their responses.
+//!
+//! A fan-out sends its request, a Status or a request of a custom protocol, to each of the peers
+//! selected for it, tagging every request with
+//! its own `RequestId::Sync` so that responses and failures can be traced back to the fan-out. A
+//! request that fails on the way is sent to the same peer again, up to `retries` times, while the
+//! peer stays connected. Requests still unanswered when the fan-out times out are reported as
+//! timed out. Each peer therefore yields exactly one result, and the stream of results ends once
+//! every peer has yielded one.
+//!
+//! The results of a fan-out can be collected with `quorum`, which waits until enough peers agree
+//! on a response and tells apart the peers that responded with something else.
+use crate::mothra::{NetworkError, NetworkErrorKind};
+use futures::{Stream, StreamExt};
+use network::{PeerId, Request};
+use std::collections::HashMap;
+use std::time::Instant;
+use tokio::sync::mpsc;
+
+/// The outcome of the request sent to one peer: the data it responded with or why it did not.
+pub type FanOutResult = (PeerId, Result<Vec<u8>, NetworkError>);
+
+/// A request of a fan-out, sent to a single peer.
+struct PendingRequest {
+    fanout: u64,
+    peer_id: PeerId,
+    retries_left: usize,
+    /// The chunks of the response received so far, for protocols responding with several.
+    chunks: Vec<u8>,
+}
+
+/// A request sent to several peers.
+struct FanOut {
+    request: Request,
+    /// The time at which the requests not answered yet time out.
+    deadline: Instant,
+    results: mpsc::UnboundedSender<FanOutResult>,
+}
+
+/// The fan-outs waiting for responses.
+#[derive(Default)]
+pub struct FanOuts {
+    next_fanout: u64,
+    next_request: usize,
+    fanouts: HashMap<u64, FanOut>,
+    /// The requests not answered yet, by the id they were sent with.
+    requests: HashMap<usize, PendingRequest>,
+}
+
+impl FanOuts {
+    /// Starts a fan-out of `request` to `peers`. Returns the requests to send, with their ids.
+    /// The results are sent to `results`, which is dropped once every peer has a result.
+    pub fn start(
+        &mut self,
+        peers: Vec<PeerId>,
+        request: Request,
+        retries: usize,
+        deadline: Instant,
+        results: mpsc::UnboundedSender<FanOutResult>,
+    ) -> Vec<(PeerId, usize)> {
+        if peers.is_empty() {
+            return Vec::new();
+        }
+        let fanout = self.next_fanout;
+        self.next_fanout = self.next_fanout.wrapping_add(1);
+        self.fanouts.insert(
+            fanout,
+            FanOut {
+                request,
+                deadline,
+                results,
+            },
+        );
+        peers
+            .into_iter()
+            .map(|peer_id| {
+                let id = self.next_request;
+                self.next_request = self.next_request.wrapping_add(1);
+                self.requests.insert(
+                    id,
+                    PendingRequest {
+                        fanout,
+                        peer_id: peer_id.clone(),
+                        retries_left: retries,
+                        chunks: Vec::new(),
+                    },
+                );
+                (peer_id, id)
+            })
+            .collect()
+    }
+
+    /// Returns the request sent with `id`, if it is still waiting for a response.
+    pub fn request(&self, id: usize) -> Option<&Request> {
+        let request = self.requests.get(&id)?;
+        self.fanouts
+            .get(&request.fanout)
+            .map(|fanout| &fanout.request)
+    }
+
+    /// Records the response to the request sent with `id`. Responses to requests that timed out
+    /// are ignored.
+    pub fn response(&mut self, id: usize, data: Vec<u8>) {
+        self.finish(id, Ok(data));
+    }
+
+    /// Records a chunk of the response to the request sent with `id`. The result of the peer is
+    /// the chunks put together, once `end_of_stream` is called.
+    pub fn chunk(&mut self, id: usize, data: &[u8]) {
+        if let Some(request) = self.requests.get_mut(&id) {
+            request.chunks.extend_from_slice(data);
+        }
+    }
+
+    /// Records the end of a response sent in chunks to the request sent with `id`.
+    pub fn end_of_stream(&mut self, id: usize) {
+        let data = match self.requests.get_mut(&id) {
+            Some(request) => std::mem::replace(&mut request.chunks, Vec::new()),
+            None => return,
+        };
+        self.finish(id, Ok(data));
+    }
+
+    /// Records that the request sent with `id` failed. Returns the peer to send the request to
+    /// again if the failure is `retriable` and the request has retries left.
+    pub fn failed(&mut self, id: usize, error: NetworkError, retriable: bool) -> Option<PeerId> {
+        let request = self.requests.get_mut(&id)?;
+        if retriable && request.retries_left > 0 {
+            request.retries_left -= 1;
+            // the request is sent again, and so is its response
+            request.chunks.clear();
+            return Some(request.peer_id.clone());
+        }
+        self.finish(id, Err(error));
+        None
+    }
+
+    /// Reports the requests of the fan-outs whose deadline has passed as timed out.
+    pub fn expire(&mut self, now: Instant) {
+        let expired: Vec<usize> = self
+            .requests
+            .iter()
+            .filter(|(_, request)| {
+                self.fanouts
+                    .get(&request.fanout)
+                    .map_or(true, |fanout| fanout.deadline <= now)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            let peer_id = self.requests[&id].peer_id.to_string();
+            self.finish(
+                id,
+                Err(NetworkError::new(NetworkErrorKind::Timeout, peer_id)),
+            );
+        }
+    }
+
+    /// Returns the earliest deadline of the fan-outs waiting for responses.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.fanouts.values().map(|fanout| fanout.deadline).min()
+    }
+
+    /// Sends the result of a request and forgets the request, and its fan-out once it has no
+    /// requests left.
+    fn finish(&mut self, id: usize, result: Result<Vec<u8>, NetworkError>) {
+        let request = match self.requests.remove(&id) {
+            Some(request) => request,
+            None => return,
+        };
+        if let Some(fanout) = self.fanouts.get(&request.fanout) {
+            // the receiver may have been dropped by an application no longer interested
+            let _ = fanout.results.send((request.peer_id, result));
+        }
+        if !self
+            .requests
+            .values()
+            .any(|pending| pending.fanout == request.fanout)
+        {
+            self.fanouts.remove(&request.fanout);
+        }
+    }
+}
+
+/// The responses of a fan-out, grouped by whether they match the response enough peers agreed
+/// on.
+#[derive(Debug, Default, PartialEq)]
+pub struct Quorum {
+    /// The response the quorum agreed on, as the first agreeing peer sent it. `None` if the
+    /// results ran out before enough peers agreed.
+    pub response: Option<Vec<u8>>,
+    /// The peers that sent the agreed response.
+    pub agreeing: Vec<PeerId>,
+    /// The peers that sent a response not matching the agreed one, before the quorum was reached.
+    pub disagreeing: Vec<PeerId>,
+    /// The peers that responded without a quorum being reached, so that none of them is known to
+    /// be right.
+    pub inconclusive: Vec<PeerId>,
+    /// The peers whose request failed or timed out.
+    pub failed: Vec<PeerId>,
+}
+
+/// Compares responses byte for byte, for `quorum`.
+pub fn same_bytes(a: &[u8], b: &[u8]) -> bool {
+    a == b
+}
+
+/// Collects `results` until `k` peers sent matching responses, as told by `equivalent`, or the
+/// results run out. The results of the peers that have not responded by then are not awaited.
+pub async fn quorum<S, F>(mut results: S, k: usize, equivalent: F) -> Quorum
+where
+    S: Stream<Item = FanOutResult> + Unpin,
+    F: Fn(&[u8], &[u8]) -> bool,
+{
+    // the responses received so far, each with the peers that sent a matching response
+    let mut groups: Vec<(Vec<u8>, Vec<PeerId>)> = Vec::new();
+    let mut failed = Vec::new();
+    while let Some((peer_id, result)) = results.next().await {
+        let data = match result {
+            Ok(data) => data,
+            Err(_) => {
+                failed.push(peer_id);
+                continue;
+            }
+        };
+        let index = match groups
+            .iter()
+            .position(|(response, _)| equivalent(response, &data))
+        {
+            Some(index) => index,
+            None => {
+                groups.push((data, Vec::new()));
+                groups.len() - 1
+            }
+        };
+        groups[index].1.push(peer_id);
+        if groups[index].1.len() >= k.max(1) {
+            let (response, agreeing) = groups.swap_remove(index);
+            return Quorum {
+                response: Some(response),
+                agreeing,
+                disagreeing: groups.into_iter().flat_map(|(_, peers)| peers).collect(),
+                inconclusive: Vec::new(),
+                failed,
+            };
+        }
+    }
+    Quorum {
+        inconclusive: groups.into_iter().flat_map(|(_, peers)| peers).collect(),
+        failed,
+        ..Quorum::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::stream;
+    use std::time::Duration;
+
+    fn error() -> NetworkError {
+        NetworkError::new(NetworkErrorKind::RequestFailed, "")
+    }
+
+    #[test]
+    fn test_results_and_retries() {
+        let (send, mut results) = mpsc::unbounded_channel();
+        let mut fanouts = FanOuts::default();
+        let peers = vec![PeerId::random(), PeerId::random(), PeerId::random()];
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let request = Request::Status(vec![1]);
+        let requests = fanouts.start(peers.clone(), request.clone(), 1, deadline, send);
+        assert_eq!(requests.len(), 3);
+        assert_eq!(fanouts.request(requests[0].1), Some(&request));
+
+        fanouts.response(requests[0].1, vec![2]);
+        assert_eq!(results.try_recv().unwrap(), (peers[0].clone(), Ok(vec![2])));
+        // a late response is ignored
+        fanouts.response(requests[0].1, vec![3]);
+        assert!(results.try_recv().is_err());
+
+        // the request is retried once, then fails
+        assert_eq!(
+            fanouts.failed(requests[1].1, error(), true),
+            Some(peers[1].clone())
+        );
+        assert!(results.try_recv().is_err());
+        assert_eq!(fanouts.failed(requests[1].1, error(), true), None);
+        assert_eq!(
+            results.try_recv().unwrap(),
+            (peers[1].clone(), Err(error()))
+        );
+
+        // a failure that is not retriable fails at once
+        assert_eq!(fanouts.failed(requests[2].1, error(), false), None);
+        assert_eq!(
+            results.try_recv().unwrap(),
+            (peers[2].clone(), Err(error()))
+        );
+        // every peer has a result, so the stream ends
+        assert_eq!(fanouts.next_deadline(), None);
+        assert!(results.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_expire() {
+        let (send, mut results) = mpsc::unbounded_channel();
+        let mut fanouts = FanOuts::default();
+        let peer_id = PeerId::random();
+        let now = Instant::now();
+        let request = Request::Status(vec![]);
+        let requests = fanouts.start(vec![peer_id.clone()], request.clone(), 0, now, send);
+        assert_eq!(fanouts.next_deadline(), Some(now));
+
+        fanouts.expire(now);
+        let (peer, result) = results.try_recv().unwrap();
+        assert_eq!(peer, peer_id);
+        assert_eq!(result.unwrap_err().kind, NetworkErrorKind::Timeout);
+        assert_eq!(fanouts.request(requests[0].1), None);
+        assert_eq!(fanouts.next_deadline(), None);
+
+        // a fan-out to no peers has nothing to wait for
+        let (send, _results) = mpsc::unbounded_channel();
+        assert!(fanouts.start(vec![], request, 0, now, send).is_empty());
+        assert_eq!(fanouts.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_chunked_responses() {
+        let (send, mut results) = mpsc::unbounded_channel();
+        let mut fanouts = FanOuts::default();
+        let peer_id = PeerId::random();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let request = Request::Custom("blocks".into(), vec![1]);
+        let requests = fanouts.start(vec![peer_id.clone()], request.clone(), 1, deadline, send);
+        let id = requests[0].1;
+        assert_eq!(fanouts.request(id), Some(&request));
+
+        // the chunks of a failed attempt are not kept for the retry
+        fanouts.chunk(id, &[1, 2]);
+        assert_eq!(fanouts.failed(id, error(), true), Some(peer_id.clone()));
+        fanouts.chunk(id, &[3]);
+        fanouts.chunk(id, &[4, 5]);
+        assert!(results.try_recv().is_err());
+        fanouts.end_of_stream(id);
+        assert_eq!(results.try_recv().unwrap(), (peer_id, Ok(vec![3, 4, 5])));
+        assert_eq!(fanouts.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_quorum() {
+        let peers: Vec<PeerId> = (0..5).map(|_| PeerId::random()).collect();
+        let results = vec![
+            (peers[0].clone(), Ok(vec![1, 0])),
+            (peers[1].clone(), Ok(vec![2])),
+            (peers[2].clone(), Err(error())),
+            (peers[3].clone(), Ok(vec![1, 1])),
+            (peers[4].clone(), Ok(vec![1, 0])),
+        ];
+
+        // byte equality never gets two matching responses before the last one
+        let outcome = block_on(quorum(stream::iter(results.clone()), 2, same_bytes));
+        assert_eq!(outcome.response, Some(vec![1, 0]));
+        assert_eq!(outcome.agreeing, vec![peers[0].clone(), peers[4].clone()]);
+        assert_eq!(
+            outcome.disagreeing,
+            vec![peers[3].clone(), peers[1].clone()]
+        );
+        assert_eq!(outcome.failed, vec![peers[2].clone()]);
+
+        // matching on the first byte only reaches the quorum earlier
+        let outcome = block_on(quorum(stream::iter(results.clone()), 2, |a, b| {
+            a[0] == b[0]
+        }));
+        assert_eq!(outcome.response, Some(vec![1, 0]));
+        assert_eq!(outcome.agreeing, vec![peers[0].clone(), peers[3].clone()]);
+        assert_eq!(outcome.disagreeing, vec![peers[1].clone()]);
+
+        let outcome = block_on(quorum(stream::iter(results), 3, same_bytes));
+        assert_eq!(outcome.response, None);
+        assert_eq!(outcome.inconclusive.len(), 4);
+        assert_eq!(outcome.failed, vec![peers[2].clone()]);
+    }
+}
@@ -0,0 +1,71 @@
+//! The messages sent to the network service, counted while they wait.
+//!
+//! An unbounded channel cannot tell how many messages it holds. Whenever a message is taken, the
+//! messages already waiting in the channel are moved to a backlog, which is read from first and
+//! whose length is the number of messages the network service has yet to handle.
+use std::collections::VecDeque;
+use tokio::sync::mpsc;
+
+pub struct Inbox<T> {
+    receiver: mpsc::UnboundedReceiver<T>,
+    /// The messages taken from the channel but not handled yet, oldest first.
+    backlog: VecDeque<T>,
+}
+
+impl<T> Inbox<T> {
+    pub fn new(receiver: mpsc::UnboundedReceiver<T>) -> Self {
+        Inbox {
+            receiver,
+            backlog: VecDeque::new(),
+        }
+    }
+
+    /// Takes the next message, waiting for one if none was sent. Returns `None` once every
+    /// sender is dropped and every message was taken.
+    pub async fn recv(&mut self) -> Option<T> {
+        let message = match self.backlog.pop_front() {
+            Some(message) => message,
+            None => self.receiver.recv().await?,
+        };
+        while let Ok(waiting) = self.receiver.try_recv() {
+            self.backlog.push_back(waiting);
+        }
+        Some(message)
+    }
+
+    /// The number of messages waiting, as of the last message taken.
+    pub fn len(&self) -> usize {
+        self.backlog.len()
+    }
+
+    /// Returns true if no messages were waiting when the last message was taken.
+    pub fn is_empty(&self) -> bool {
+        self.backlog.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_counts_waiting_messages() {
+        let (send, receiver) = mpsc::unbounded_channel();
+        let mut inbox = Inbox::new(receiver);
+        for i in 0..3 {
+            send.send(i).unwrap();
+        }
+        assert_eq!(block_on(inbox.recv()), Some(0));
+        assert_eq!(inbox.len(), 2);
+        send.send(3).unwrap();
+        assert_eq!(block_on(inbox.recv()), Some(1));
+        assert_eq!(inbox.len(), 2);
+
+        drop(send);
+        assert_eq!(block_on(inbox.recv()), Some(2));
+        assert_eq!(block_on(inbox.recv()), Some(3));
+        assert!(inbox.is_empty());
+        assert_eq!(block_on(inbox.recv()), None);
+    }
+}
@@ -0,0 +1,63 @@
+//! Handlers for the `generate-key`, `import-key` and `export-peer-id` subcommands, which manage the
+//! network key stored in the datadir.
+use crate::cli::{EXIT_FAILURE, EXIT_INVALID_ARGS, EXIT_SUCCESS};
+use crate::config::Config;
+use clap::ArgMatches;
+use network::keys::{self, KeyType};
+use network::PeerId;
+
+/// Runs the `generate-key` subcommand, printing the new peer id and returning the exit code.
+pub fn generate_key(config: &Config, args: &ArgMatches) -> i32 {
+    let key_type = match parse_key_type(args) {
+        Ok(key_type) => key_type,
+        Err(code) => return code,
+    };
+    report(keys::generate_key(
+        &config.network_config.network_dir,
+        key_type,
+        args.is_present("force"),
+    ))
+}
+
+/// Runs the `import-key` subcommand, printing the imported peer id and returning the exit code.
+pub fn import_key(config: &Config, args: &ArgMatches) -> i32 {
+    let key_type = match parse_key_type(args) {
+        Ok(key_type) => key_type,
+        Err(code) => return code,
+    };
+    let secret_key = args.value_of("secret-key").unwrap_or_default();
+    report(keys::import_key(
+        &config.network_config.network_dir,
+        secret_key,
+        key_type,
+        args.is_present("force"),
+    ))
+}
+
+/// Runs the `export-peer-id` subcommand, printing the peer id and returning the exit code.
+pub fn export_peer_id(config: &Config) -> i32 {
+    report(keys::export_peer_id(&config.network_config.network_dir))
+}
+
+fn parse_key_type(args: &ArgMatches) -> Result<KeyType, i32> {
+    args.value_of("key-type")
+        .unwrap_or_default()
+        .parse::<KeyType>()
+        .map_err(|e| {
+            eprintln!("{}", e);
+            EXIT_INVALID_ARGS
+        })
+}
+
+fn report(result: network::error::Result<PeerId>) -> i32 {
+    match result {
+        Ok(peer_id) => {
+            println!("{}", peer_id);
+            EXIT_SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            EXIT_FAILURE
+        }
+    }
+}
@@ -1,7 +1,36 @@
 pub mod cli;
 pub mod config;
+#[cfg(unix)]
+pub mod control;
+pub mod delivery;
+pub mod enr;
 pub mod error;
+pub mod fanout;
+mod inbox;
+pub mod keys;
+pub mod log_level;
+pub mod log_policy;
+pub mod mirror;
 mod mothra;
-pub use crate::mothra::{gossip, rpc_request, rpc_response, Mothra, NetworkMessage, Subscriber};
+pub mod ping;
+pub mod reorder;
+mod requests;
+pub mod slow_consumer;
+#[cfg(test)]
+mod test_utils;
+pub use crate::mothra::{
+    dht_snapshot, dial, gossip, rpc_end_of_stream, rpc_request, rpc_response, rpc_response_chunk,
+    GossipMessage, GossipTiming, MessageTopic, Mothra, NetworkError, NetworkErrorKind,
+    NetworkMessage, NodeEvent, Subscriber,
+};
+#[cfg(feature = "kad")]
+pub use crate::mothra::{get_providers, get_record, put_record, start_providing};
+pub use crate::slow_consumer::ConsumerStats;
 pub use cli::cli_app;
-pub use network::{rpc, NetworkGlobals, PeerId as MothraPeerId, Request, Response, TaskExecutor};
+pub use network::{
+    rpc, BridgeTransform, BufferPool, BufferPoolStats, DhtSnapshot, EnrPredicate, GossipTopic,
+    IdentifyInfo, IdentityVerifier, MemoryUsageSnapshot, MessageTransform, MessageTransforms,
+    NetworkGlobals, Overload, PeerAction, PeerId as MothraPeerId, PeerScoreSnapshot, PingHandler,
+    QueueDepthsSnapshot, Request, Response, Spawn, StatsSnapshot, SubnetPeerCount, TaskExecutor,
+    TopicBridges, TopicCipher, TopicHash, TopicKey, TopicValidator, FORK_PLACEHOLDER,
+};
@@ -0,0 +1,97 @@
+//! A log level that can be changed while the node runs.
+//!
+//! Applications filter their root drain through `RuntimeLevelFilter`, using the `LogLevel` of the
+//! `Config`, instead of a fixed `filter_level`. `NetworkMessage::SetLogLevel` then changes the
+//! level of every logger derived from that root, including those of the network service and its
+//! behaviours.
+use slog::{Drain, Level, OwnedKVList, Record};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The level below which records are dropped. Clones share the level.
+#[derive(Clone, Debug)]
+pub struct LogLevel(Arc<AtomicUsize>);
+
+impl LogLevel {
+    pub fn new(level: Level) -> Self {
+        LogLevel(Arc::new(AtomicUsize::new(level.as_usize())))
+    }
+
+    /// The current level.
+    pub fn get(&self) -> Level {
+        Level::from_usize(self.0.load(Ordering::Relaxed)).unwrap_or(Level::Info)
+    }
+
+    /// Changes the level of every logger filtered by this `LogLevel`.
+    pub fn set(&self, level: Level) {
+        self.0.store(level.as_usize(), Ordering::Relaxed);
+    }
+}
+
+impl Default for LogLevel {
+    fn default() -> Self {
+        LogLevel::new(Level::Info)
+    }
+}
+
+/// Parses a level as given to `--debug-level`.
+pub fn parse_level(s: &str) -> Result<Level, String> {
+    match s {
+        "info" => Ok(Level::Info),
+        "debug" => Ok(Level::Debug),
+        "trace" => Ok(Level::Trace),
+        "warn" => Ok(Level::Warning),
+        "error" => Ok(Level::Error),
+        "crit" => Ok(Level::Critical),
+        _ => Err(format!("Invalid log level: {}", s)),
+    }
+}
+
+/// Passes on the records at or above the current `LogLevel`.
+pub struct RuntimeLevelFilter<D> {
+    drain: D,
+    level: LogLevel,
+}
+
+impl<D> RuntimeLevelFilter<D> {
+    pub fn new(drain: D, level: LogLevel) -> Self {
+        RuntimeLevelFilter { drain, level }
+    }
+}
+
+impl<D: Drain> Drain for RuntimeLevelFilter<D> {
+    type Ok = Option<D::Ok>;
+    type Err = D::Err;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<Self::Ok, Self::Err> {
+        if record.level().is_at_least(self.level.get()) {
+            self.drain.log(record, values).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn is_enabled(&self, level: Level) -> bool {
+        level.is_at_least(self.level.get()) && self.drain.is_enabled(level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level_is_shared() {
+        let level = LogLevel::new(Level::Info);
+        let filter = RuntimeLevelFilter::new(slog::Discard, level.clone());
+        assert!(filter.is_enabled(Level::Warning));
+        assert!(!filter.is_enabled(Level::Debug));
+
+        level.set(parse_level("debug").unwrap());
+        assert_eq!(level.get(), Level::Debug);
+        assert!(filter.is_enabled(Level::Debug));
+        assert!(!filter.is_enabled(Level::Trace));
+
+        assert!(parse_level("verbose").is_err());
+    }
+}
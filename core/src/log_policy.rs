@@ -0,0 +1,225 @@
+//! What the logs may show of the messages exchanged and of the addresses of peers.
+//!
+//! At debug level the network logs the requests, responses and messages it handles, and the
+//! addresses of its peers, which some deployments must not write out. `Mothra::new` therefore
+//! logs through a `RedactingDrain`: the values logged under payload keys are replaced by their
+//! length and a hash, so repeated payloads can still be told apart, and with `anonymize_ips` so
+//! are the values of address keys. The hashes are keyed per node, so they cannot be compared
+//! across nodes or runs. `--log-unsafe` turns the policy off for debugging.
+//!
+//! Only the keys of a log line are redacted, not its message text nor the context of its logger,
+//! so payloads must be logged under one of the `PAYLOAD_KEYS` and addresses under one of the
+//! `ADDRESS_KEYS`.
+use slog::{BorrowedKV, Drain, Key, Level, OwnedKVList, Record, RecordStatic, Serializer, KV};
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hash, Hasher};
+
+/// The keys whose values are the contents of messages.
+pub const PAYLOAD_KEYS: &[&str] = &[
+    "data", "key", "message", "payload", "record", "request", "response", "value",
+];
+
+/// The keys whose values hold addresses of peers or of the node.
+pub const ADDRESS_KEYS: &[&str] = &[
+    "address",
+    "addresses",
+    "endpoint",
+    "enr",
+    "from",
+    "ip",
+    "listen_addresses",
+    "listen_multiaddr",
+    "multiaddr",
+    "observed_address",
+    "our_addr",
+    "tcp_socket",
+];
+
+/// What is redacted from the logs.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LogPolicy {
+    /// Logs payloads and addresses as they are.
+    pub unsafe_logging: bool,
+    /// Replaces addresses by hashes.
+    pub anonymize_ips: bool,
+}
+
+impl LogPolicy {
+    /// Wraps `log` so the records logged through the returned logger follow this policy.
+    pub fn apply(self, log: slog::Logger) -> slog::Logger {
+        if self.unsafe_logging {
+            return log;
+        }
+        slog::Logger::root(RedactingDrain::new(log, self), slog::o!())
+    }
+}
+
+/// Redacts the values of a record before passing it on.
+pub struct RedactingDrain<D> {
+    drain: D,
+    policy: LogPolicy,
+    hasher: RandomState,
+}
+
+impl<D> RedactingDrain<D> {
+    pub fn new(drain: D, policy: LogPolicy) -> Self {
+        RedactingDrain {
+            drain,
+            policy,
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Returns the redacted form of `value`, logged under `key`.
+    fn redact(&self, key: &str, value: String) -> String {
+        if PAYLOAD_KEYS.contains(&key) {
+            format!("<{} bytes, hash {:016x}>", value.len(), self.hash(&value))
+        } else if self.policy.anonymize_ips && ADDRESS_KEYS.contains(&key) {
+            format!("<address {:016x}>", self.hash(&value))
+        } else {
+            value
+        }
+    }
+
+    fn hash(&self, value: &str) -> u64 {
+        let mut hasher = self.hasher.build_hasher();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl<D: Drain<Ok = ()>> Drain for RedactingDrain<D> {
+    type Ok = D::Ok;
+    type Err = D::Err;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<Self::Ok, Self::Err> {
+        // the records filtered out downstream are not worth formatting
+        if !self.drain.is_enabled(record.level()) {
+            return Ok(());
+        }
+        let mut collected = Collected(Vec::new());
+        // serializing into a vector does not fail
+        let _ = record.kv().serialize(record, &mut collected);
+        let redacted = Redacted(
+            collected
+                .0
+                .into_iter()
+                .map(|(key, value)| (key, self.redact(key, value)))
+                .collect(),
+        );
+        let record_static = RecordStatic {
+            location: record.location(),
+            level: record.level(),
+            tag: record.tag(),
+        };
+        self.drain.log(
+            &Record::new(&record_static, record.msg(), BorrowedKV(&redacted)),
+            values,
+        )
+    }
+
+    fn is_enabled(&self, level: Level) -> bool {
+        self.drain.is_enabled(level)
+    }
+}
+
+/// The values of a record, formatted.
+struct Collected(Vec<(Key, String)>);
+
+impl Serializer for Collected {
+    fn emit_arguments(&mut self, key: Key, val: &fmt::Arguments) -> slog::Result {
+        self.0.push((key, val.to_string()));
+        Ok(())
+    }
+}
+
+/// The redacted values of a record, in the order they were logged.
+struct Redacted(Vec<(Key, String)>);
+
+impl KV for Redacted {
+    fn serialize(&self, _record: &Record, serializer: &mut dyn Serializer) -> slog::Result {
+        // in the order they were collected, which is the order slog serializes them in
+        for (key, value) in &self.0 {
+            serializer.emit_str(key, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use network::testing::Collect;
+    use slog::{debug, info, o, Logger};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// A value counting the times it was formatted.
+    #[derive(Clone, Default)]
+    struct Counted(Arc<AtomicUsize>);
+
+    impl slog::Value for Counted {
+        fn serialize(
+            &self,
+            _record: &Record,
+            key: Key,
+            serializer: &mut dyn Serializer,
+        ) -> slog::Result {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            serializer.emit_str(key, "secret")
+        }
+    }
+
+    /// Logs two lines under `policy`, returning the values logged under each key.
+    fn logged(policy: LogPolicy) -> impl Fn(&str) -> Vec<String> {
+        let collect = Collect::default();
+        let log = policy.apply(Logger::root(collect.clone().fuse(), o!()));
+        debug!(log, "Received message"; "data" => "secret", "address" => "/ip4/10.0.0.1/tcp/9000");
+        debug!(log, "Received message"; "data" => "secret", "topic" => "blocks");
+        move |key| collect.values(key)
+    }
+
+    #[test]
+    fn test_payloads_are_redacted() {
+        let values = logged(LogPolicy::default());
+        let data = values("data");
+        assert!(data[0].starts_with("<6 bytes, hash "));
+        // the same payload has the same hash
+        assert_eq!(data[0], data[1]);
+        assert_eq!(values("address"), vec!["/ip4/10.0.0.1/tcp/9000"]);
+        assert_eq!(values("topic"), vec!["blocks"]);
+    }
+
+    #[test]
+    fn test_addresses_are_anonymized() {
+        let values = logged(LogPolicy {
+            anonymize_ips: true,
+            ..LogPolicy::default()
+        });
+        assert!(values("address")[0].starts_with("<address "));
+    }
+
+    #[test]
+    fn test_disabled_levels_are_not_formatted() {
+        let collect = Collect::default();
+        let drain = collect.clone().filter_level(Level::Info).fuse();
+        let log = LogPolicy::default().apply(Logger::root(drain, o!()));
+        let formatted = Counted::default();
+        debug!(log, "Received message"; "data" => formatted.clone());
+        assert_eq!(formatted.0.load(Ordering::Relaxed), 0);
+        info!(log, "Received message"; "data" => formatted.clone());
+        assert_eq!(formatted.0.load(Ordering::Relaxed), 1);
+        assert_eq!(collect.values("data").len(), 1);
+    }
+
+    #[test]
+    fn test_unsafe_logging() {
+        let values = logged(LogPolicy {
+            unsafe_logging: true,
+            anonymize_ips: true,
+        });
+        assert_eq!(values("address"), vec!["/ip4/10.0.0.1/tcp/9000"]);
+        assert_eq!(values("data"), vec!["secret", "secret"]);
+    }
+}
@@ -0,0 +1,199 @@
+//! Mirrors the messages received from the network to a second consumer, for shadow-testing a new
+//! version of an application against live traffic.
+//!
+//! Every gossip and RPC message handed to the `Subscriber` is also handed to the mirror. The
+//! mirror has a bounded queue and a thread of its own, so a slow or failing mirror never holds up
+//! the `Subscriber`: messages arriving while its queue is full are dropped and counted in
+//! `NetworkGlobals::dropped_mirror_messages`. Discovery results and the node's own notifications
+//! are not mirrored.
+//!
+//! A mirror `Subscriber` is not initialised, so it has no channel to the network and cannot answer
+//! the requests it sees. A UDP mirror receives one datagram per message, a line of text:
+//! - `gossip <message id> <peer id> <topic> <hex data>`
+//! - `rpc <method> <req_resp> <protocol id or -> <peer id> <hex data>`
+//!
+//! Hex doubles the size of the data, and a datagram holds at most `MAX_DATAGRAM_SIZE` bytes, so
+//! messages of more than about 32KB cannot be mirrored over UDP. They are neither truncated nor
+//! split: they are dropped and counted in `NetworkGlobals::dropped_mirror_messages`. Mirror to a
+//! `Subscriber` to see every message.
+use crate::delivery::Delivery;
+use crate::mothra::Subscriber;
+use network::{NetworkGlobals, TaskExecutor};
+use slog::trace;
+use std::net::{SocketAddr, UdpSocket};
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::Arc;
+
+/// The default number of messages that can wait for the mirror.
+pub const DEFAULT_MIRROR_QUEUE_SIZE: usize = 4096;
+
+/// The largest UDP payload over IPv4, and the largest datagram mirrored.
+pub const MAX_DATAGRAM_SIZE: usize = 65_507;
+
+/// Where mirrored messages are sent.
+pub enum MirrorSink {
+    /// The `receive_gossip` and `receive_rpc` callbacks of a second `Subscriber`.
+    Subscriber(Arc<dyn Subscriber + Send>),
+    /// Datagrams sent to a UDP address, such as a shadow process on the same host.
+    Udp(SocketAddr),
+}
+
+/// Parses `udp:<address>:<port>`. A `Subscriber` can only be set in code.
+impl FromStr for MirrorSink {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.splitn(2, ':').collect::<Vec<_>>().as_slice() {
+            ["udp", address] => address
+                .parse()
+                .map(MirrorSink::Udp)
+                .map_err(|_| format!("Invalid mirror address: {}", address)),
+            _ => Err(format!(
+                "Unknown mirror, expected udp:<address>:<port>: {}",
+                s
+            )),
+        }
+    }
+}
+
+/// The queue of messages waiting for the mirror.
+pub struct Mirror {
+    send: SyncSender<Delivery>,
+    network_globals: Arc<NetworkGlobals>,
+}
+
+impl Mirror {
+    /// Starts the thread handing messages to `sink`, with a queue of `queue_size` messages. The
+    /// thread stops once the mirror is dropped.
+    pub fn spawn(
+        sink: MirrorSink,
+        queue_size: usize,
+        network_globals: Arc<NetworkGlobals>,
+        executor: &TaskExecutor,
+        log: &slog::Logger,
+    ) -> Result<Self, String> {
+        let (send, recv) = sync_channel::<Delivery>(queue_size.max(1));
+        let log = log.clone();
+        let globals = network_globals.clone();
+        match sink {
+            MirrorSink::Subscriber(client) => executor.spawn_blocking(
+                move || {
+                    while let Ok(delivery) = recv.recv() {
+                        delivery.deliver(client.as_ref());
+                    }
+                },
+                "mothra-mirror",
+            ),
+            MirrorSink::Udp(address) => {
+                let unspecified = if address.is_ipv4() {
+                    "0.0.0.0:0"
+                } else {
+                    "[::]:0"
+                };
+                let socket = UdpSocket::bind(unspecified)
+                    .map_err(|e| format!("Could not open the mirror socket: {}", e))?;
+                executor.spawn_blocking(
+                    move || {
+                        while let Ok(delivery) = recv.recv() {
+                            let datagram = match datagram(&delivery) {
+                                Some(datagram) => datagram,
+                                None => continue,
+                            };
+                            if datagram.len() > MAX_DATAGRAM_SIZE {
+                                trace!(log, "Message too large to mirror over UDP"; "size" => datagram.len());
+                                globals
+                                    .dropped_mirror_messages
+                                    .fetch_add(1, Ordering::Relaxed);
+                                continue;
+                            }
+                            // no one listening only affects the mirror
+                            if let Err(e) = socket.send_to(datagram.as_bytes(), address) {
+                                trace!(log, "Could not mirror a message"; "error" => e.to_string());
+                                globals
+                                    .dropped_mirror_messages
+                                    .fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                    },
+                    "mothra-mirror",
+                );
+            }
+        }
+        Ok(Mirror {
+            send,
+            network_globals,
+        })
+    }
+
+    /// Queues a copy of a message for the mirror, if it is one received from the network. Never
+    /// waits: the copy is dropped if the queue is full.
+    pub fn push(&self, delivery: &Delivery) {
+        if let Some(copy) = delivery.mirrored() {
+            if self.send.try_send(copy).is_err() {
+                self.network_globals
+                    .dropped_mirror_messages
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// The text a message is mirrored as over UDP.
+fn datagram(delivery: &Delivery) -> Option<String> {
+    match delivery {
+        Delivery::Gossip(message) => Some(format!(
+            "gossip {} {} {} {}",
+            message.message_id,
+            message.peer_id,
+            message.topic,
+            hex::encode(&message.data)
+        )),
+        Delivery::Rpc {
+            method,
+            req_resp,
+            protocol,
+            peer_id,
+            data,
+        } => Some(format!(
+            "rpc {} {} {} {} {}",
+            method,
+            req_resp,
+            protocol
+                .as_ref()
+                .map_or_else(|| "-".to_string(), ToString::to_string),
+            peer_id,
+            hex::encode(data)
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_format() {
+        match "udp:127.0.0.1:9100".parse::<MirrorSink>() {
+            Ok(MirrorSink::Udp(address)) => assert_eq!(address.port(), 9100),
+            _ => panic!("expected a UDP mirror"),
+        }
+        assert!("udp:localhost".parse::<MirrorSink>().is_err());
+        assert!("tcp:127.0.0.1:9100".parse::<MirrorSink>().is_err());
+
+        let rpc = Delivery::Rpc {
+            method: "Status".into(),
+            req_resp: 1,
+            protocol: None,
+            peer_id: "peer".into(),
+            data: vec![0xab],
+        };
+        assert_eq!(datagram(&rpc).unwrap(), "rpc Status 1 - peer ab");
+        let topic_ready = Delivery::TopicReady {
+            topic: "topic".into(),
+        };
+        assert!(datagram(&topic_ready).is_none());
+    }
+}
@@ -1,55 +1,332 @@
+#[cfg(unix)]
+use crate::control;
+use crate::delivery::{Delivery, Dispatcher, DispatcherConfig};
+use crate::fanout::{quorum, FanOutResult, FanOuts, Quorum};
+use crate::inbox::Inbox;
+use crate::log_level::LogLevel;
+use crate::mirror::Mirror;
+use crate::reorder::Reorderer;
+use crate::requests::RequestSlots;
+use crate::slow_consumer::ConsumerStats;
 use crate::{config::Config, error};
 use futures::prelude::*;
 use futures::Stream;
+#[cfg(feature = "kad")]
+use network::KadReply;
 use network::Service as LibP2PService;
 use network::{
-    rpc::{GoodbyeReason, RPCResponseErrorCode, RequestId, StatusMessage},
-    types::GossipKind,
-    BehaviourEvent, ConnectedPoint, GossipTopic, Libp2pEvent, MessageId, NetworkConfig,
-    NetworkGlobals, PeerId, PeerRequestId, Request, Response, Swarm, TaskExecutor,
+    rpc::{
+        GoodbyeReason, Protocol, ProtocolId, RPCError, RPCResponseErrorCode, RequestId,
+        StatusMessage,
+    },
+    service_enr_key,
+    types::{GossipKind, MeanSize, LRU_ENTRY_OVERHEAD},
+    BehaviourEvent, ConnectedPoint, DecodedTopic, DhtSnapshot, EnrExt, EnrPredicate, GossipTopic,
+    Libp2pEvent, MemoryUsage, MessageId, Multiaddr, NetworkConfig, NetworkGlobals, Overload,
+    PeerAction, PeerId, PeerRequestId, QueueDepths, Request, Response, ScoreSource, Swarm,
+    TaskExecutor, TopicHash, TopicKey,
 };
 
 use clap::ArgMatches;
+use lru::LruCache;
 use slog::{debug, info, o, trace, warn, Drain, Level, Logger};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::mpsc;
-use parking_lot::RwLock;
-use std::sync::atomic::{AtomicU8,Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{broadcast, mpsc, oneshot};
 
 /// The time in seconds that a peer will be banned and prevented from reconnecting.
 const BAN_PEER_TIMEOUT: u64 = 30;
 
-pub type DiscoveredPeerType = fn(peer: String);
+/// The number of discovered peers remembered to avoid reporting them repeatedly. Peers forgotten
+/// are reported as new when found again.
+const MAX_DISCOVERED_PEERS: usize = 4096;
+
+/// How long the Goodbyes and the messages queued for the client are each given to go out at
+/// shutdown, once the responses were sent or the shutdown grace period is over.
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The number of node events a watcher of the control socket may fall behind by before it misses
+/// some.
+const NODE_EVENT_CAPACITY: usize = 1024;
+
+/// The token of the next dial requested with `dial`.
+static NEXT_DIAL_TOKEN: AtomicU64 = AtomicU64::new(1);
+
+pub type DiscoveredPeerType = fn(peer: String, new: bool);
 pub type ReceiveGossipType = fn(message_id: String, peer_id: String, topic: String, data: Vec<u8>);
 pub type ReceiveRpcType = fn(method: String, req_resp: u8, peer: String, data: Vec<u8>);
 
-pub trait Subscriber {
+/// A topic a gossip message was sent on.
+#[derive(Clone, Debug)]
+pub struct MessageTopic {
+    /// The topic hash exactly as it was received.
+    pub hash: TopicHash,
+    /// The topic, if the hash decodes to a valid `GossipTopic`.
+    pub topic: Option<GossipTopic>,
+}
+
+impl From<&TopicHash> for MessageTopic {
+    fn from(hash: &TopicHash) -> Self {
+        MessageTopic {
+            hash: hash.clone(),
+            topic: DecodedTopic::from(hash).known().cloned(),
+        }
+    }
+}
+
+/// An event of the network service, streamed to the control connections that watch the node.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NodeEvent {
+    PeerConnected(PeerId),
+    PeerDisconnected(PeerId),
+    /// A gossip message was received and handed to the client.
+    Gossip {
+        topic: String,
+        message_id: String,
+        peer_id: PeerId,
+    },
+    /// A subscribed topic's mesh reached a healthy size.
+    TopicReady(String),
+}
+
+/// One line per event: its name followed by its fields, separated by spaces.
+impl std::fmt::Display for NodeEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            NodeEvent::PeerConnected(peer_id) => write!(f, "peer-connected {}", peer_id),
+            NodeEvent::PeerDisconnected(peer_id) => write!(f, "peer-disconnected {}", peer_id),
+            NodeEvent::Gossip {
+                topic,
+                message_id,
+                peer_id,
+            } => write!(f, "gossip {} {} {}", topic, message_id, peer_id),
+            NodeEvent::TopicReady(topic) => write!(f, "topic-ready {}", topic),
+        }
+    }
+}
+
+/// When a gossip message was received and, if its publish time is known, how long it took to
+/// reach us.
+#[derive(Clone, Copy, Debug)]
+pub struct GossipTiming {
+    /// The time the message was first seen.
+    pub received_at: SystemTime,
+    /// The time between publishing and receiving the message, from `Subscriber::publish_time`.
+    pub latency: Option<Duration>,
+}
+
+impl GossipTiming {
+    /// The timing of a message received at `received_at` and published at `published`, if known.
+    /// A publish time ahead of our clock counts as no latency.
+    fn new(received_at: SystemTime, published: Option<SystemTime>) -> Self {
+        GossipTiming {
+            received_at,
+            latency: published
+                .map(|published| received_at.duration_since(published).unwrap_or_default()),
+        }
+    }
+}
+
+/// A gossip message received from the network.
+#[derive(Clone, Debug)]
+pub struct GossipMessage {
+    pub message_id: String,
+    pub sequence_number: u64,
+    /// The agent version of the peer the message was received from.
+    pub agent_string: String,
+    /// The peer the message was received from, which is not necessarily its publisher.
+    pub peer_id: String,
+    /// The first topic the message was sent on.
+    pub topic: String,
+    /// Every topic the message was sent on.
+    pub topics: Vec<MessageTopic>,
+    pub data: Vec<u8>,
+    pub timing: GossipTiming,
+}
+
+/// The kinds of failures reported to `Subscriber::on_error`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NetworkErrorKind {
+    /// A message could not be published, because a transform of its topic failed on it, it was
+    /// too large for its topic, the topic validator rejected its topic, or
+    /// `reject_unsubscribed_publish` is set and we are not subscribed to its topic.
+    PublishFailed = 0,
+    /// An RPC request or response was addressed to a peer that is not connected.
+    UnknownPeer = 1,
+    /// A response was sent for a request that is no longer pending, because it was answered or
+    /// cancelled already.
+    UnknownRequest = 2,
+    /// An RPC request sent to a peer failed.
+    RequestFailed = 3,
+    /// A message could not be handed to the network service because it has stopped.
+    ChannelClosed = 4,
+    /// A peer id given by the application could not be parsed.
+    InvalidPeerId = 5,
+    /// An address given by the application could not be parsed.
+    InvalidAddress = 6,
+    /// A peer did not respond to a request sent with `Mothra::broadcast_request` in time.
+    Timeout = 7,
+    /// A DHT query failed, or found nothing.
+    DhtQueryFailed = 8,
+    /// A topic could not be subscribed to, because the topic validator rejected it.
+    InvalidTopic = 9,
+    /// A request received from a peer was refused with a server error without being delivered,
+    /// because as many requests as there are request slots were waiting for a response.
+    RequestRefused = 10,
+}
+
+/// A failure on the way to or inside the network service.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NetworkError {
+    pub kind: NetworkErrorKind,
+    /// What failed, for example the topic or the peer involved.
+    pub context: String,
+}
+
+impl NetworkError {
+    pub(crate) fn new(kind: NetworkErrorKind, context: impl Into<String>) -> Self {
+        NetworkError {
+            kind,
+            context: context.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.kind, self.context)
+    }
+}
+
+/// The callbacks through which the application receives network events.
+///
+/// The callbacks may be called from several threads at once if more than one delivery worker is
+/// configured. See the `delivery` module for the ordering guarantees.
+///
+/// The delivery workers share the subscriber as an `Arc<dyn Subscriber>` and each calls it from
+/// its own thread, hence the `Send + Sync` bound. Subscribers that were not `Sync` before the
+/// workers were introduced keep their mutable state behind a `Mutex` or in a channel.
+pub trait Subscriber: Send + Sync {
     fn init(&mut self, send: mpsc::UnboundedSender<NetworkMessage>, fork_id: Vec<u8>);
-    fn discovered_peer(&self, peer: String);
-    fn receive_gossip(&self, message_id: String, sequence_number: u64, agent_string: String, peer_id: String, topic: String, data: Vec<u8>);
-    fn receive_rpc(&self, method: String, req_resp: u8, peer: String, data: Vec<u8>);
+    /// Receives a peer found by discovery. `new` is false if the peer was reported before; a peer
+    /// that discovery keeps finding is only reported again once `Config::discovered_peer_interval`
+    /// has passed.
+    fn discovered_peer(&self, peer: String, new: bool);
+    /// Receives a gossip message.
+    fn receive_gossip(&self, message: GossipMessage);
+    /// Receives an RPC message. `protocol` is the protocol id negotiated for a received request,
+    /// whose `version` and `encoding` tell how the request was encoded, or `None` when no request
+    /// was received, for example when asked to send a status. `req_resp` is 1 for a request, 0
+    /// for a response or a chunk of one, and 2 with no data when a response of several chunks
+    /// has ended.
+    fn receive_rpc(
+        &self,
+        method: String,
+        req_resp: u8,
+        protocol: Option<ProtocolId>,
+        peer: String,
+        data: Vec<u8>,
+    );
+    /// Extracts an application level key from a gossip message. Messages on the same topic with
+    /// a key that was recently seen are not delivered again, even if their gossipsub message ids
+    /// differ. By default no key is extracted and only gossipsub's own deduplication applies.
+    fn message_key(&self, _topic: &str, _data: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+    /// Extracts the position of a gossip message on an ordered topic (see
+    /// `Config::ordered_topics`) from its payload: the stream it belongs to, for example its
+    /// publisher, and its sequence number within the stream. The messages of a stream are
+    /// delivered in sequence number order. By default no position is extracted and messages are
+    /// delivered as they arrive.
+    fn message_sequence(&self, _topic: &str, _data: &[u8]) -> Option<(Vec<u8>, u64)> {
+        None
+    }
+    /// Extracts the time a gossip message was published, if the payload embeds it. This is used
+    /// to estimate propagation latency. By default the publish time is unknown.
+    fn publish_time(&self, _topic: &str, _data: &[u8]) -> Option<SystemTime> {
+        None
+    }
+    /// Called when a received RPC request is cancelled before it was responded to, for example
+    /// because the peer is disconnecting or the response took too long. `index` is the one the
+    /// request was received with and a response to it is no longer sent, so the application can
+    /// abandon its processing. By default nothing is done.
+    fn rpc_cancelled(&self, _method: String, _peer: String, _index: u8, _cause: String) {}
+    /// Receives a peer found by a `NetworkMessage::DiscoverPeers` search, with its ENR in base64.
+    /// By default nothing is done.
+    fn discovered_enr(&self, _peer: String, _enr: String) {}
+    /// Receives a message that was published but dropped before any peer subscribed to its topic,
+    /// either because its TTL passed or to make room in the publish buffer. By default nothing is
+    /// done.
+    fn publish_expired(&self, _topic: String, _data: Vec<u8>) {}
+    /// Called when the mesh of a subscribed topic first reaches a healthy size, after which
+    /// messages published on it are likely to propagate. See `NetworkGlobals::topic_ready`. By
+    /// default nothing is done.
+    fn topic_ready(&self, _topic: String) {}
+    /// Called after each second in which more gossip was received than
+    /// `NetworkConfig::inbound_message_rate_limit`, with the number of messages dropped per
+    /// topic. The application may for example leave bulk topics until the traffic subsides. By
+    /// default nothing is done.
+    fn overloaded(&self, _overload: Overload) {}
+    /// Called when most callbacks of a delivery worker took longer than
+    /// `Config::slow_consumer_threshold` over the last interval, with the figures of the interval.
+    /// It is called on that worker, after a callback. By default nothing is done.
+    fn slow_consumer(&self, _stats: ConsumerStats) {}
+    /// Receives the outcome of a `NetworkMessage::Dial`, tagged with the token it was requested
+    /// with: the peer connected to, or why the dial failed. By default nothing is done.
+    fn dial_result(&self, _token: u64, _result: Result<String, String>) {}
+    /// Called when a message sent to the network service could not be handled, so the
+    /// application can react to failures that are otherwise only logged. `context` describes what
+    /// failed. By default nothing is done.
+    fn on_error(&self, _kind: NetworkErrorKind, _context: String) {}
+    /// Receives the status a peer sent in a Status request that was answered with the local
+    /// status, when `NetworkConfig::auto_status` is set and the request did not reach
+    /// `receive_rpc`. By default nothing is done.
+    fn status_answered(&self, _peer: String, _status: Vec<u8>) {}
 }
 
 /// Handles communication between calling code and the `libp2p_p2p` service.
 pub struct Mothra {
-    /// Reference to the client using Mothra
-    client: Box<dyn Subscriber + Send>,
+    /// Hands received messages to the client using Mothra
+    delivery: Dispatcher,
+    /// Holds back gossip on ordered topics until it can be delivered in order.
+    reorderer: Reorderer<Delivery>,
+    /// The client using Mothra, used to extract message keys.
+    client: Arc<dyn Subscriber + Send>,
+    /// The recently seen (topic, message key) pairs.
+    seen_message_keys: Option<LruCache<(String, Vec<u8>), ()>>,
+    /// The mean size of the topics and keys in `seen_message_keys`.
+    message_key_sizes: MeanSize,
+    /// When each discovered peer was last reported to the client.
+    discovered_peers: LruCache<PeerId, Instant>,
+    /// The time before a discovered peer is reported again.
+    discovered_peer_interval: Duration,
+    /// How long to finish the work queued for peers once the exit signal is received.
+    shutdown_grace_period: Duration,
     /// The underlying libp2p service that drives all the network interactions.
     libp2p: LibP2PService,
-    /// The network receiver channel
-    network_recv: mpsc::UnboundedReceiver<NetworkMessage>,
+    /// The network receiver channel, which counts the messages waiting in it.
+    network_recv: Inbox<NetworkMessage>,
     /// The network sender channel
     network_send: mpsc::UnboundedSender<NetworkMessage>,
+    /// Streams the events of the network service to the control connections that watch them.
+    node_events: broadcast::Sender<NodeEvent>,
     /// A collection of global variables, accessible outside of the network service.
     network_globals: Arc<NetworkGlobals>,
     /// Probability of message propagation.
     propagation_percentage: Option<u8>,
     // TODO: Make a struct that implements this functionality.
     // It should hold an array and a counter
-    /// rpc requests
-    requests: RwLock<[Option<PeerRequestId>; 256]>,
-    /// num active requests
-    num_requests:  AtomicU8,
+    /// The requests delivered to the client and not answered yet.
+    requests: RequestSlots<PeerId, PeerRequestId>,
+    /// The requests sent with `Mothra::broadcast_request` waiting for responses.
+    fanouts: FanOuts,
+    /// The number of times a failed request of a fan-out is sent to its peer again.
+    request_retries: usize,
+    /// How long the peers of a fan-out have to respond.
+    request_timeout: Duration,
+    /// The level of the application's root logger, if it is filtered with `RuntimeLevelFilter`.
+    log_level: LogLevel,
     /// The logger for the network service.
     log: slog::Logger,
 }
@@ -64,6 +341,8 @@ impl Mothra {
         mut client: Box<dyn Subscriber + Send>,
         log: slog::Logger,
     ) -> error::Result<(Arc<NetworkGlobals>, mpsc::UnboundedSender<NetworkMessage>)> {
+        // everything the network logs goes through the log policy
+        let log = config.log_policy.apply(log);
         // build the network channel
         let (network_send, network_recv) = mpsc::unbounded_channel::<NetworkMessage>();
         client.init(network_send.clone(), enr_fork_id.clone());
@@ -81,32 +360,334 @@ impl Mothra {
 
         let mut subscribed_topics: Vec<GossipKind> = vec![];
         for topic_kind in &config.network_config.topics {
-            if libp2p.swarm.subscribe_kind(topic_kind.clone()) {
-                subscribed_topics.push(topic_kind.clone());
-            } else {
-                warn!(log, "Could not subscribe to topic"; "topic" => format!("{}",topic_kind));
+            match libp2p.swarm.subscribe_kind(topic_kind.clone()) {
+                Ok(true) => subscribed_topics.push(topic_kind.clone()),
+                Ok(false) => {
+                    warn!(log, "Could not subscribe to topic"; "topic" => format!("{}",topic_kind))
+                }
+                // a configured topic the validator rejects is a configuration error
+                Err(e) => return Err(e.into()),
             }
         }
         info!(log, "Subscribed to topics"; "topics" => format!("{:?}", subscribed_topics));
 
+        // hand received messages to the client on worker threads, or on the runtime without any
+        let client: Arc<dyn Subscriber + Send> = Arc::from(client);
+        let dispatcher_config = DispatcherConfig {
+            workers: config.delivery_workers,
+            queue_size: config.delivery_queue_size,
+            overflow: config.delivery_overflow,
+            ordering: config.delivery_ordering,
+            ordered_topics: config.ordered_topics.clone(),
+            slow_threshold: config.slow_consumer_threshold,
+        };
+        let mut delivery = Dispatcher::spawn(
+            dispatcher_config,
+            client.clone(),
+            network_globals.clone(),
+            executor,
+            &log,
+        );
+        if let Some(sink) = config.mirror.take() {
+            let mirror = Mirror::spawn(
+                sink,
+                config.mirror_queue_size,
+                network_globals.clone(),
+                executor,
+                &log,
+            )?;
+            delivery.mirror_to(mirror);
+            info!(log, "Mirroring the messages received"; "queue_size" => config.mirror_queue_size);
+        }
+
+        let (node_events, _) = broadcast::channel(NODE_EVENT_CAPACITY);
+
         // create & spawn the network service
         let network_service = Mothra {
+            delivery,
+            reorderer: Reorderer::new(config.ordered_topics.clone(), config.reorder_window),
             client,
+            seen_message_keys: match config.message_key_cache_size {
+                0 => None,
+                size => Some(LruCache::new(size)),
+            },
+            message_key_sizes: MeanSize::default(),
+            discovered_peers: LruCache::new(MAX_DISCOVERED_PEERS),
+            discovered_peer_interval: config.discovered_peer_interval,
+            shutdown_grace_period: config.shutdown_grace_period,
             libp2p,
-            network_recv,
+            network_recv: Inbox::new(network_recv),
             network_send: network_send.clone(),
+            node_events: node_events.clone(),
             network_globals: network_globals.clone(),
             propagation_percentage: config.network_config.propagation_percentage,
-            requests: RwLock::new([None; 256]),
-            num_requests: AtomicU8::new(0),
+            requests: RequestSlots::new(),
+            fanouts: FanOuts::default(),
+            request_retries: config.request_retries,
+            request_timeout: config.request_timeout,
+            log_level: config.log_level.clone(),
             log: log.clone(),
         };
 
         spawn_mothra(network_service, executor)?;
 
+        if let Some(path) = config.admin_socket {
+            #[cfg(unix)]
+            control::spawn(
+                path,
+                config.admin_token,
+                network_send.clone(),
+                node_events,
+                network_globals.clone(),
+                executor,
+                log,
+            );
+            #[cfg(not(unix))]
+            warn!(log, "The admin socket needs unix domain sockets"; "path" => format!("{:?}", path));
+        }
+
         Ok((network_globals, network_send))
     }
 
+    /// Records the estimated memory of the seen message keys and of the queues around the network
+    /// service. The queued messages are counted without the payloads they own.
+    fn update_memory_usage(&self) {
+        let usage = &self.network_globals.memory_usage;
+        let keys = self.seen_message_keys.as_ref().map_or(0, LruCache::len);
+        let key_entry = std::mem::size_of::<(String, Vec<u8>)>() + LRU_ENTRY_OVERHEAD;
+        MemoryUsage::set(
+            &usage.seen_message_keys,
+            self.message_key_sizes.estimate(keys, key_entry),
+        );
+        MemoryUsage::set(
+            &usage.service_queues,
+            self.network_recv.len() * std::mem::size_of::<NetworkMessage>()
+                + self.delivery.len() * std::mem::size_of::<(Instant, Delivery)>(),
+        );
+    }
+
+    /// Returns true if the client's key for this message was recently seen on the topic.
+    fn is_duplicate(&mut self, topic: &str, data: &[u8]) -> bool {
+        let seen_message_keys = match self.seen_message_keys.as_mut() {
+            Some(seen_message_keys) => seen_message_keys,
+            None => return false,
+        };
+        let key = match self.client.message_key(topic, data) {
+            Some(key) => key,
+            None => return false,
+        };
+        self.message_key_sizes.add(topic.len() + key.len());
+        if seen_before(seen_message_keys, topic, key) {
+            self.network_globals
+                .duplicate_messages_suppressed
+                .fetch_add(1, Ordering::Relaxed);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns whether a discovered peer should be reported to the client and if it is reported
+    /// for the first time.
+    fn discovered_peer_report(&mut self, peer_id: &PeerId) -> Option<bool> {
+        report_discovered(
+            &mut self.discovered_peers,
+            self.discovered_peer_interval,
+            peer_id,
+            Instant::now(),
+        )
+    }
+
+    /// Sends the client's response to the request saved at `index`.
+    fn send_response(
+        &mut self,
+        peer_id: PeerId,
+        response: Response,
+        index: u8,
+    ) -> Result<(), NetworkError> {
+        // an index of another peer's request is refused, leaving that request open
+        match self.requests.get(index, &peer_id) {
+            Some(id) => {
+                // the request stays open until the last chunk of its response
+                if !matches!(response, Response::Chunk(_)) {
+                    self.requests.take(index, &peer_id);
+                }
+                if !self.network_globals.peers.read().is_connected(&peer_id) {
+                    warn!(self.log, "Response not sent to a disconnected peer"; "peer_id" => peer_id.to_string());
+                    return Err(NetworkError::new(
+                        NetworkErrorKind::UnknownPeer,
+                        peer_id.to_string(),
+                    ));
+                }
+                // send response to libp2p
+                self.libp2p.send_response(peer_id, id, response);
+                Ok(())
+            }
+            None => {
+                warn!(self.log, "Issue with match request/response ids");
+                Err(NetworkError::new(
+                    NetworkErrorKind::UnknownRequest,
+                    format!("No pending request with index {} from {}", index, peer_id),
+                ))
+            }
+        }
+    }
+
+    /// Sends the request of a fan-out to a peer, or fails the request if the peer is no longer
+    /// connected.
+    fn send_fanout_request(&mut self, peer_id: PeerId, id: usize) {
+        let request = match self.fanouts.request(id) {
+            Some(request) => request.clone(),
+            None => return,
+        };
+        if self.network_globals.peers.read().is_connected(&peer_id) {
+            self.libp2p
+                .send_request(peer_id, RequestId::Sync(id), request);
+        } else {
+            let error = NetworkError::new(NetworkErrorKind::UnknownPeer, peer_id.to_string());
+            self.fanouts.failed(id, error, false);
+        }
+    }
+
+    /// Hands a failure to the client.
+    async fn report_error(&self, error: NetworkError) {
+        self.delivery
+            .push(Delivery::Error {
+                kind: error.kind,
+                context: error.context,
+            })
+            .await;
+    }
+
+    /// Finishes the work queued for peers before closing the connections. For up to the shutdown
+    /// grace period, responses and propagations sent by the client are still handled, so peers
+    /// are not left waiting on requests the client was delivered. Once those are answered, or the
+    /// grace period is over, every peer is sent a Goodbye. Messages received in the meantime are
+    /// no longer delivered, but those already queued for the client are, within
+    /// `SHUTDOWN_FLUSH_TIMEOUT`.
+    async fn shutdown(&mut self) {
+        let mut grace_period = tokio::time::delay_for(self.shutdown_grace_period);
+        let mut goodbye_sent = false;
+        loop {
+            if !goodbye_sent && self.requests.is_empty() {
+                self.libp2p.swarm.peer_manager().disconnect_all();
+                goodbye_sent = true;
+            }
+            if goodbye_sent && self.network_globals.connected_peers() == 0 {
+                debug!(self.log, "All peers disconnected");
+                break;
+            }
+            tokio::select! {
+                _ = &mut grace_period => {
+                    debug!(self.log, "Shutdown grace period over";
+                        "unanswered_requests" => self.requests.len(),
+                        "connected_peers" => self.network_globals.connected_peers());
+                    if !goodbye_sent {
+                        self.libp2p.swarm.peer_manager().disconnect_all();
+                    }
+                    self.send_goodbyes().await;
+                    break;
+                }
+                Some(message) = self.network_recv.recv() => match message {
+                    NetworkMessage::SendResponse { peer_id, response, index } => {
+                        if let Err(e) = self.send_response(peer_id, response, index) {
+                            self.report_error(e).await;
+                        }
+                    }
+                    NetworkMessage::Propagate { propagation_source, message_id } => {
+                        self.libp2p.swarm.propagate_message(&propagation_source, message_id);
+                    }
+                    _ => {
+                        debug!(self.log, "Network message ignored during shutdown");
+                    }
+                },
+                _ = self.libp2p.next_event() => {}
+            }
+        }
+        if tokio::time::timeout(SHUTDOWN_FLUSH_TIMEOUT, self.delivery.drain())
+            .await
+            .is_err()
+        {
+            debug!(self.log, "Queued messages not delivered"; "messages" => self.delivery.len());
+        }
+    }
+
+    /// Drives the network until every peer is disconnected, for up to `SHUTDOWN_FLUSH_TIMEOUT`, so
+    /// the Goodbyes are sent.
+    async fn send_goodbyes(&mut self) {
+        let network_globals = self.network_globals.clone();
+        let libp2p = &mut self.libp2p;
+        let sent = tokio::time::timeout(SHUTDOWN_FLUSH_TIMEOUT, async move {
+            while network_globals.connected_peers() > 0 {
+                libp2p.next_event().await;
+            }
+        })
+        .await;
+        if sent.is_err() {
+            debug!(self.log, "Peers still connected at shutdown";
+                "connected_peers" => self.network_globals.connected_peers());
+        }
+    }
+
+    /// Timestamps a gossip message and records its propagation latency if the client can tell
+    /// when it was published.
+    fn gossip_timing(&self, topic: &str, data: &[u8]) -> GossipTiming {
+        let timing = GossipTiming::new(SystemTime::now(), self.client.publish_time(topic, data));
+        if let Some(latency) = timing.latency {
+            self.network_globals.observe_gossip_latency(topic, latency);
+        }
+        timing
+    }
+
+    /// Sends the same request to up to `fanout` connected peers picked at random and returns a
+    /// stream of the peers' results, in the order they arrive. `method` is `Status` or the name of
+    /// a protocol registered in `NetworkConfig::custom_protocols`; the chunks of a response sent
+    /// in several are put together into the peer's result. A request that fails is sent again up
+    /// to `Config::request_retries` times, and the peers that have not responded after
+    /// `Config::request_timeout` yield a `NetworkErrorKind::Timeout` error. The stream ends once
+    /// every peer has yielded a result, at once if no peer is connected or if the protocol is not
+    /// registered, which is also reported to `Subscriber::on_error`.
+    pub fn broadcast_request(
+        mut network_send: mpsc::UnboundedSender<NetworkMessage>,
+        method: String,
+        data: Vec<u8>,
+        fanout: usize,
+    ) -> Result<impl Stream<Item = FanOutResult> + Unpin, NetworkError> {
+        let (results, receiver) = mpsc::unbounded_channel();
+        let request = if method.eq_ignore_ascii_case("status") {
+            Request::Status(data)
+        } else {
+            Request::Custom(method.clone(), data)
+        };
+        network_send
+            .send(NetworkMessage::BroadcastRequest {
+                request,
+                fanout,
+                results,
+            })
+            .map_err(|_| NetworkError::new(NetworkErrorKind::ChannelClosed, method))?;
+        Ok(receiver)
+    }
+
+    /// Sends the same request to up to `fanout` peers like `broadcast_request`, and
+    /// resolves once `k` of them sent responses that are `equivalent`, such as
+    /// `fanout::same_bytes`. The peers whose response differs from the agreed one are listed so
+    /// the application can report them.
+    pub async fn broadcast_quorum<F>(
+        network_send: mpsc::UnboundedSender<NetworkMessage>,
+        method: String,
+        data: Vec<u8>,
+        fanout: usize,
+        k: usize,
+        equivalent: F,
+    ) -> Result<Quorum, NetworkError>
+    where
+        F: Fn(&[u8], &[u8]) -> bool,
+    {
+        let results = Self::broadcast_request(network_send, method, data, fanout)?;
+        Ok(quorum(results, k, equivalent).await)
+    }
+
     pub fn get_config(
         client_name: Option<String>,
         client_version: Option<String>,
@@ -126,40 +707,37 @@ fn spawn_mothra(mut mothra: Mothra, executor: &TaskExecutor) -> error::Result<()
     // spawn on the current executor
     executor.spawn_without_exit(async move {
         loop {
+            let depths = &mothra.network_globals.queue_depths;
+            QueueDepths::set(&depths.network_messages, mothra.network_recv.len());
+            QueueDepths::set(&depths.deliveries, mothra.delivery.len());
+            mothra.update_memory_usage();
             // build the futures to check simultaneously
             tokio::select! {
                 // handle network shutdown
                 _ = (&mut exit_rx) => {
                     // network thread is terminating
                     info!(mothra.log, "Mothra shutdown");
+                    mothra.shutdown().await;
+                    mothra.delivery.close();
                     return;
                 }
                 // handle a message sent to the network
                 Some(message) = mothra.network_recv.recv() => {
                     match message {
                         NetworkMessage::SendRequest{ peer_id, request, request_id } => {
-                            debug!(mothra.log, "SendRequest to peer: {:?} request type: {:?}", peer_id, request);
-                            mothra.libp2p.send_request(peer_id, request_id, request);
+                            debug!(mothra.log, "Sending request"; "peer_id" => peer_id.to_string(), "request" => format!("{:?}", request));
+                            if mothra.network_globals.peers.read().is_connected(&peer_id) {
+                                mothra.libp2p.send_request(peer_id, request_id, request);
+                            } else {
+                                warn!(mothra.log, "Request not sent to a disconnected peer"; "peer_id" => peer_id.to_string());
+                                mothra.report_error(NetworkError::new(NetworkErrorKind::UnknownPeer, peer_id.to_string())).await;
+                            }
                         }
                         NetworkMessage::SendResponse{ peer_id, response, index } => {
-                            debug!(mothra.log, "SendResponse to peer: {:?} response type: {:?}", peer_id, response);
-                            
-                            match mothra.requests.read()[index as usize] {
-                                Some(id) => {
-                                    // decrement the numrequests
-                                    mothra.num_requests.fetch_sub(1, Ordering::SeqCst);
-                                    // send response to libp2p
-                                    mothra.libp2p.send_response(peer_id, id, response);
-                                },
-                                None => {
-                                    warn!(mothra.log, "Issue with match request/response ids");
-                                }
+                            debug!(mothra.log, "Sending response"; "peer_id" => peer_id.to_string(), "response" => format!("{:?}", response));
+                            if let Err(e) = mothra.send_response(peer_id, response, index) {
+                                mothra.report_error(e).await;
                             }
-
-                            // zero out the old PeerRequestId
-                            mothra.requests.write()[index as usize] = None;
-                            
-
                         }
                         NetworkMessage::Propagate {
                             propagation_source,
@@ -174,13 +752,40 @@ fn spawn_mothra(mut mothra: Mothra, executor: &TaskExecutor) -> error::Result<()
                                     .swarm
                                     .propagate_message(&propagation_source, message_id);
                         }
-                        NetworkMessage::Publish { topic, message } => {
+                        NetworkMessage::Publish { topic, message, ttl } => {
                                 debug!(
                                     mothra.log,
                                     "Sending pubsub message";
                                     "topic" => format!("{:?}", topic)
                                 );
-                                mothra.libp2p.swarm.publish(topic, message);
+                                mothra.libp2p.swarm.publish(topic, message, ttl);
+                        }
+                        NetworkMessage::PublishToPeers { topic, message, peers } => {
+                                let sent = mothra
+                                    .libp2p
+                                    .swarm
+                                    .publish_to_peers(topic.clone(), message, &peers);
+                                debug!(
+                                    mothra.log,
+                                    "Sending pubsub message directly to peers";
+                                    "topic" => format!("{:?}", topic),
+                                    "requested" => peers.len(),
+                                    "sent" => sent
+                                );
+                        }
+                        NetworkMessage::MutePeer { peer_id } => {
+                            debug!(mothra.log, "Muting peer"; "peer_id" => peer_id.to_string());
+                            mothra.network_globals.mute(peer_id);
+                        }
+                        NetworkMessage::UnmutePeer { peer_id } => {
+                            debug!(mothra.log, "Unmuting peer"; "peer_id" => peer_id.to_string());
+                            mothra.network_globals.unmute(&peer_id);
+                        }
+                        NetworkMessage::PauseForwarding { topic } => {
+                            mothra.libp2p.swarm.pause_forwarding(&topic);
+                        }
+                        NetworkMessage::ResumeForwarding { topic } => {
+                            mothra.libp2p.swarm.resume_forwarding(&topic);
                         }
                         NetworkMessage::Disconnect { peer_id } => {
                             mothra.libp2p.disconnect_and_ban_peer(
@@ -188,68 +793,374 @@ fn spawn_mothra(mut mothra: Mothra, executor: &TaskExecutor) -> error::Result<()
                                 std::time::Duration::from_secs(BAN_PEER_TIMEOUT),
                             );
                         }
+                        NetworkMessage::ReportPeer { peer_id, action } => {
+                            debug!(mothra.log, "Peer reported by application"; "peer_id" => peer_id.to_string(), "action" => format!("{:?}", action));
+                            mothra.libp2p.swarm.peer_manager().report_peer(&peer_id, action, ScoreSource::Application);
+                        }
+                        NetworkMessage::UpdateIdentify { agent_version, protocol_version } => {
+                            mothra.libp2p.swarm.update_identify(agent_version, protocol_version);
+                        }
+                        NetworkMessage::UpdateEnr { fields } => {
+                            match mothra.libp2p.swarm.update_enr(fields) {
+                                Ok(enr) => info!(mothra.log, "Local ENR updated"; "seq" => enr.seq(), "enr" => enr.to_base64()),
+                                Err(e) => warn!(mothra.log, "Could not update the local ENR"; "error" => e),
+                            }
+                        }
+                        NetworkMessage::UpdateForks { current, secondary } => {
+                            match mothra.libp2p.swarm.update_forks(current, secondary) {
+                                Ok(enr) => info!(mothra.log, "Forks updated"; "current" => hex::encode(mothra.network_globals.local_fork_id()), "secondary" => mothra.network_globals.local_secondary_fork_id().map(hex::encode), "seq" => enr.seq()),
+                                Err(e) => warn!(mothra.log, "Could not update the forks"; "error" => e),
+                            }
+                        }
+                        NetworkMessage::RegisterService { service_key, record } => {
+                            match mothra.libp2p.swarm.update_enr(vec![(service_enr_key(&service_key), record)]) {
+                                Ok(enr) => info!(mothra.log, "Service registered in the local ENR"; "service" => service_key, "seq" => enr.seq()),
+                                Err(e) => warn!(mothra.log, "Could not register the service"; "service" => service_key, "error" => e),
+                            }
+                        }
+                        NetworkMessage::DiscoverPeers { predicate, target } => {
+                            debug!(mothra.log, "Discovering peers for the application"; "predicate" => format!("{:?}", predicate), "target" => target);
+                            mothra.libp2p.swarm.discover_matching_peers(predicate, target);
+                        }
+                        NetworkMessage::Dial { address, token } => {
+                            if let Err(e) = mothra.libp2p.dial(address.clone(), token) {
+                                debug!(mothra.log, "Could not dial address"; "address" => address.to_string(), "error" => &e);
+                                mothra.delivery.push(Delivery::DialResult { token, result: Err(e) }).await;
+                            }
+                        }
+                        NetworkMessage::BroadcastRequest { request, fanout, results } => {
+                            let unsupported = match &request {
+                                Request::Status(_) => None,
+                                Request::Custom(name, _) if mothra.libp2p.swarm.serves_protocol(name) => None,
+                                Request::Custom(name, _) => Some(name.clone()),
+                                Request::Goodbye(_) => Some("Goodbye".to_string()),
+                            };
+                            if let Some(method) = unsupported {
+                                // dropping `results` ends the stream without a result
+                                warn!(mothra.log, "Request cannot be broadcast"; "method" => &method);
+                                let context = format!("Cannot broadcast {} requests", method);
+                                mothra.report_error(NetworkError::new(NetworkErrorKind::RequestFailed, context)).await;
+                            } else {
+                                let peers = mothra.network_globals.random_peers(fanout, None);
+                                debug!(mothra.log, "Broadcasting request"; "fanout" => fanout, "peers" => peers.len());
+                                let deadline = Instant::now() + mothra.request_timeout;
+                                for (peer_id, id) in mothra.fanouts.start(peers, request, mothra.request_retries, deadline, results) {
+                                    mothra.send_fanout_request(peer_id, id);
+                                }
+                            }
+                        }
+                        NetworkMessage::GetDhtSnapshot { reply } => {
+                            let _ = reply.send(mothra.libp2p.swarm.dht_snapshot());
+                        }
+                        #[cfg(feature = "kad")]
+                        NetworkMessage::StartProviding { key, reply } => {
+                            debug!(mothra.log, "Providing a DHT key"; "key" => hex::encode(&key));
+                            mothra.libp2p.swarm.start_providing(key, reply);
+                        }
+                        #[cfg(feature = "kad")]
+                        NetworkMessage::GetProviders { key, reply } => {
+                            mothra.libp2p.swarm.get_providers(key, reply);
+                        }
+                        #[cfg(feature = "kad")]
+                        NetworkMessage::PutRecord { key, value, reply } => {
+                            debug!(mothra.log, "Storing a DHT record"; "key" => hex::encode(&key), "length" => value.len());
+                            mothra.libp2p.swarm.put_record(key, value, reply);
+                        }
+                        #[cfg(feature = "kad")]
+                        NetworkMessage::GetRecord { key, reply } => {
+                            mothra.libp2p.swarm.get_record(key, reply);
+                        }
+                        NetworkMessage::SetTopicKey { topic, key } => {
+                            debug!(mothra.log, "Setting topic key"; "topic" => &topic, "key" => format!("{:?}", key));
+                            mothra.libp2p.swarm.set_topic_key(topic, key);
+                        }
+                        NetworkMessage::RotateTopicKey { topic, key } => {
+                            match mothra.libp2p.swarm.rotate_topic_key(&topic, key) {
+                                Some(generation) => info!(mothra.log, "Rotated topic key"; "topic" => &topic, "generation" => generation),
+                                None => warn!(mothra.log, "Cannot rotate the key of a topic that is not encrypted"; "topic" => &topic),
+                            }
+                        }
+                        NetworkMessage::AddTopicKey { topic, key } => {
+                            match mothra.libp2p.swarm.add_topic_key(&topic, key) {
+                                Some(generation) => info!(mothra.log, "Added topic key"; "topic" => &topic, "generation" => generation),
+                                None => warn!(mothra.log, "Cannot add a key to a topic that is not encrypted"; "topic" => &topic),
+                            }
+                        }
+                        NetworkMessage::ActivateTopicKey { topic, key } => {
+                            match mothra.libp2p.swarm.activate_topic_key(&topic, &key) {
+                                Some(generation) => info!(mothra.log, "Activated topic key"; "topic" => &topic, "generation" => generation),
+                                None => warn!(mothra.log, "Cannot activate a topic key that was not added"; "topic" => &topic),
+                            }
+                        }
+                        NetworkMessage::SetLogLevel { level } => {
+                            mothra.log_level.set(level);
+                            info!(mothra.log, "Log level changed"; "level" => level.as_str());
+                        }
                         NetworkMessage::Subscribe { subscriptions } => {
                             let mut subscribed_topics: Vec<GossipKind> = vec![];
                             for topic_kind in subscriptions {
-                                if mothra.libp2p.swarm.subscribe_kind(topic_kind.clone().into()) {
-                                    subscribed_topics.push(topic_kind.clone().into());
-                                } else {
-                                    warn!(mothra.log, "Could not subscribe to topic"; "topic" => format!("{:?}",topic_kind));
+                                match mothra.libp2p.swarm.subscribe_kind(topic_kind.clone().into()) {
+                                    Ok(true) => subscribed_topics.push(topic_kind.clone().into()),
+                                    Ok(false) => warn!(mothra.log, "Could not subscribe to topic"; "topic" => format!("{:?}",topic_kind)),
+                                    Err(e) => {
+                                        warn!(mothra.log, "Invalid topic"; "topic" => topic_kind.as_str(), "error" => &e);
+                                        mothra.report_error(NetworkError::new(NetworkErrorKind::InvalidTopic, e)).await;
+                                    }
                                 }
                             }
                             info!(mothra.log, "Subscribed to topics"; "topics" => format!("{:?}", subscribed_topics));
                         }
+                        NetworkMessage::Unsubscribe { topics } => {
+                            for topic in topics {
+                                if mothra.libp2p.swarm.unsubscribe_kind(topic.clone().into()) {
+                                    info!(mothra.log, "Unsubscribed from topic"; "topic" => topic.as_str());
+                                } else {
+                                    warn!(mothra.log, "Not subscribed to topic"; "topic" => topic.as_str());
+                                }
+                            }
+                        }
                     }
                 } // end mothra.network_recv.recv()
+                // time out the fan-outs whose peers took too long to respond
+                _ = tokio::time::delay_until(mothra.fanouts.next_deadline().unwrap_or_else(Instant::now).into()), if mothra.fanouts.next_deadline().is_some() => {
+                    mothra.fanouts.expire(Instant::now());
+                }
                 libp2p_event = mothra.libp2p.next_event() => {
                     // poll the swarm
                     match libp2p_event {
                         Libp2pEvent::Behaviour(event) => match event {
-                            BehaviourEvent::RequestReceived{peer_id, id, request} => {
-                                debug!(mothra.log, "Mothra {:?} received from: {:?} id: {:?}", request, peer_id, id);
-                                let index = mothra.num_requests.load(Ordering::SeqCst);
-                                // Save the PeerRequestId
-                                mothra.requests.write()[index as usize] = Some(id);
-                                // incrememnt the variable that indicates the total number of indexes
-                                mothra.num_requests.fetch_add(1, Ordering::SeqCst);
-                                // Call out to bindings to encode and store the index of the PeerRequestId
-                                mothra.client.receive_rpc("Status".to_string(), 1, peer_id.to_string(), vec![index]);
+                            BehaviourEvent::RequestReceived{peer_id, id, request, protocol_id} => {
+                                debug!(mothra.log, "Request received"; "peer_id" => peer_id.to_string(), "id" => format!("{:?}", id), "protocol" => protocol_id.to_string(), "request" => format!("{:?}", request));
+                                // the payload of a custom request follows the index, a status is
+                                // not handed over
+                                let (method, payload) = match request {
+                                    Request::Custom(name, payload) => (name, payload),
+                                    _ => ("Status".to_string(), vec![]),
+                                };
+                                // the client answers with the index the PeerRequestId is saved at
+                                if let Some(index) = mothra.requests.insert(peer_id.clone(), id) {
+                                    let mut data = vec![index];
+                                    data.extend(payload);
+                                    mothra.delivery.push(Delivery::Rpc {
+                                        method,
+                                        req_resp: 1,
+                                        protocol: Some(protocol_id),
+                                        peer_id: peer_id.to_string(),
+                                        data,
+                                    }).await;
+                                } else {
+                                    // the peer is told at once rather than left to time out
+                                    warn!(mothra.log, "Too many unanswered requests, request not delivered"; "peer_id" => peer_id.to_string());
+                                    mothra.libp2p.respond_with_error(peer_id.clone(), id, RPCResponseErrorCode::ServerError, "Too many pending requests".into());
+                                    let context = format!("{} request from {} refused, every request slot is in use", method, peer_id);
+                                    mothra.report_error(NetworkError::new(NetworkErrorKind::RequestRefused, context)).await;
+                                }
                             }
-                            BehaviourEvent::ResponseReceived{peer_id, id, response} => {
-                                debug!(mothra.log, "{:?} received from: {:?}", peer_id, response);
+                            BehaviourEvent::RequestCancelled{peer_id, id, protocol, error} => {
+                                debug!(mothra.log, "Request cancelled"; "peer_id" => peer_id.to_string(), "id" => format!("{:?}", id), "error" => error.to_string());
+                                // free the slot of the PeerRequestId, a response can no longer be sent
+                                if let Some(index) = mothra.requests.remove(&peer_id, id) {
+                                    let method = match protocol {
+                                        Protocol::Custom(custom) => custom.name().to_string(),
+                                        _ => "Status".to_string(),
+                                    };
+                                    mothra.delivery.push(Delivery::RpcCancelled {
+                                        method,
+                                        peer_id: peer_id.to_string(),
+                                        index,
+                                        cause: error.to_string(),
+                                    }).await;
+                                }
+                            }
+                            BehaviourEvent::ResponseReceived{peer_id, id, response, protocol_id} => {
+                                debug!(mothra.log, "Response received"; "peer_id" => peer_id.to_string(), "protocol" => protocol_id.to_string(), "response" => format!("{:?}", response));
+                                match (id, response) {
+                                    (RequestId::Sync(id), Response::Status(data)) | (RequestId::Sync(id), Response::Custom(data)) => {
+                                        mothra.fanouts.response(id, data)
+                                    }
+                                    (RequestId::Sync(id), Response::Chunk(data)) => mothra.fanouts.chunk(id, &data),
+                                    (RequestId::Sync(id), Response::EndOfStream) => mothra.fanouts.end_of_stream(id),
+                                    (_, Response::Custom(data)) | (_, Response::Chunk(data)) => {
+                                        mothra.delivery.push(Delivery::Rpc {
+                                            method: protocol_id.message_name.to_string(),
+                                            req_resp: 0,
+                                            peer_id: peer_id.to_string(),
+                                            protocol: Some(protocol_id),
+                                            data,
+                                        }).await;
+                                    }
+                                    (_, Response::EndOfStream) => {
+                                        mothra.delivery.push(Delivery::Rpc {
+                                            method: protocol_id.message_name.to_string(),
+                                            req_resp: 2,
+                                            peer_id: peer_id.to_string(),
+                                            protocol: Some(protocol_id),
+                                            data: vec![],
+                                        }).await;
+                                    }
+                                    _ => {}
+                                }
                             }
                             BehaviourEvent::RPCFailed{id, peer_id, error} => {
-                                debug!(mothra.log, "RPC request to: {:?} failed. error: {:?}", peer_id, error);
+                                debug!(mothra.log, "RPC request failed"; "peer_id" => peer_id.to_string(), "error" => format!("{:?}", error));
+                                let context = format!("Request to {} failed: {}", peer_id, error);
+                                if let RequestId::Sync(id) = id {
+                                    // the peer answered, or cannot, so asking again is pointless
+                                    let retriable = !matches!(error, RPCError::ErrorResponse(..) | RPCError::UnsupportedProtocol);
+                                    let error = NetworkError::new(NetworkErrorKind::RequestFailed, context);
+                                    if let Some(peer_id) = mothra.fanouts.failed(id, error, retriable) {
+                                        mothra.send_fanout_request(peer_id, id);
+                                    }
+                                } else {
+                                    mothra.report_error(NetworkError::new(NetworkErrorKind::RequestFailed, context)).await;
+                                }
                             }
                             BehaviourEvent::StatusPeer(peer_id) => {
-                                debug!(mothra.log, "Status request received from: {:?}", peer_id);
-                                mothra.client.receive_rpc("Status".to_string(), 0, peer_id.to_string(), vec![])
+                                debug!(mothra.log, "Status request received"; "peer_id" => peer_id.to_string());
+                                mothra.delivery.push(Delivery::Rpc {
+                                    method: "Status".to_string(),
+                                    req_resp: 0,
+                                    protocol: None,
+                                    peer_id: peer_id.to_string(),
+                                    data: vec![],
+                                }).await;
                             }
                             BehaviourEvent::PubsubMessage {
                                 id,
                                 sequence_number,
                                 agent_string,
                                 source,
+                                publisher: _,
                                 topics,
                                 message
                             } => {
-                                debug!(mothra.log, "Gossip message received from: {:?} topic: {:?}", source, topics);
-                                mothra.client.receive_gossip(id.to_string(), sequence_number, agent_string, source.to_string(), topics[0].to_string(), message.clone());
+                                debug!(mothra.log, "Gossip message received"; "peer_id" => source.to_string(), "topics" => format!("{:?}", topics));
+                                let topic = match topics.first().map(DecodedTopic::from) {
+                                    Some(DecodedTopic::Known(topic)) => Some(topic.to_string()),
+                                    Some(DecodedTopic::Unknown(topic)) => {
+                                        debug!(mothra.log, "Gossip message received on unknown topic"; "message_id" => id.to_string(), "topic" => &topic);
+                                        Some(topic)
+                                    }
+                                    None => {
+                                        warn!(mothra.log, "Gossip message received without a topic"; "message_id" => id.to_string());
+                                        None
+                                    }
+                                };
+                                match topic {
+                                    None => {}
+                                    Some(topic) if mothra.is_duplicate(&topic, &message) => {
+                                        debug!(mothra.log, "Suppressed duplicate gossip message"; "message_id" => id.to_string(), "topic" => &topic);
+                                    }
+                                    Some(topic) => {
+                                        let timing = mothra.gossip_timing(&topic, &message);
+                                        let _ = mothra.node_events.send(NodeEvent::Gossip {
+                                            topic: topic.clone(),
+                                            message_id: id.to_string(),
+                                            peer_id: source.clone(),
+                                        });
+                                        let position = if mothra.reorderer.is_ordered(&topic) {
+                                            mothra.client.message_sequence(&topic, &message)
+                                        } else {
+                                            None
+                                        };
+                                        let delivery = Delivery::Gossip(GossipMessage {
+                                            message_id: id.to_string(),
+                                            sequence_number,
+                                            agent_string,
+                                            peer_id: source.to_string(),
+                                            topic: topic.clone(),
+                                            topics: topics.iter().map(MessageTopic::from).collect(),
+                                            data: message,
+                                            timing,
+                                        });
+                                        let ready = match position {
+                                            Some((stream, sequence)) => mothra.reorderer.push(&topic, stream, sequence, delivery),
+                                            None => vec![delivery],
+                                        };
+                                        for delivery in ready {
+                                            mothra.delivery.push(delivery).await;
+                                        }
+                                    }
+                                }
                             }
                             BehaviourEvent::PeerSubscribed(peer_id, topic) => {
                                 //debug!(mothra.log, "Subscribed to: {:?} for topic: {:?}", peer_id, topic);
                             },
+                            BehaviourEvent::PublishExpired { topic, message } => {
+                                debug!(mothra.log, "Published message expired before any peer subscribed"; "topic" => topic.as_str());
+                                mothra.delivery.push(Delivery::PublishExpired {
+                                    topic: topic.into(),
+                                    data: message,
+                                }).await;
+                            }
+                            BehaviourEvent::TopicReady(topic) => {
+                                info!(mothra.log, "Topic ready for publishing"; "topic" => topic.as_str());
+                                let _ = mothra.node_events.send(NodeEvent::TopicReady(topic.to_string()));
+                                mothra.delivery.push(Delivery::TopicReady { topic: topic.into() }).await;
+                            }
+                            BehaviourEvent::Overloaded(overload) => {
+                                mothra.delivery.push(Delivery::Overloaded { overload }).await;
+                            }
+                            BehaviourEvent::PublishFailed { topic, error } => {
+                                let context = format!("Could not publish on {}: {}", topic, error);
+                                mothra.report_error(NetworkError::new(NetworkErrorKind::PublishFailed, context)).await;
+                            }
+                            BehaviourEvent::StatusAnswered{peer_id, status} => {
+                                debug!(mothra.log, "Status request answered"; "peer_id" => peer_id.to_string(), "status" => hex::encode(&status));
+                                mothra.delivery.push(Delivery::StatusAnswered {
+                                    peer_id: peer_id.to_string(),
+                                    status,
+                                }).await;
+                            }
+                            BehaviourEvent::PongReceived{peer_id, ..} => {
+                                debug!(mothra.log, "Pong received"; "peer_id" => peer_id.to_string());
+                            }
+                            BehaviourEvent::PeerIdentified{..} => {}
+                            BehaviourEvent::PeersDiscovered(enrs) => {
+                                for enr in enrs {
+                                    let peer_id = enr.peer_id();
+                                    if let Some(new) = mothra.discovered_peer_report(&peer_id) {
+                                        mothra.delivery.push(Delivery::DiscoveredPeer {
+                                            peer_id: peer_id.to_string(),
+                                            new,
+                                        }).await;
+                                    }
+                                }
+                            }
+                            BehaviourEvent::MatchingPeersDiscovered(enrs) => {
+                                for enr in enrs {
+                                    mothra.delivery.push(Delivery::DiscoveredEnr {
+                                        peer_id: enr.peer_id().to_string(),
+                                        enr: enr.to_base64(),
+                                    }).await;
+                                }
+                            }
                         }
                         Libp2pEvent::NewListenAddr(multiaddr) => {
                             mothra.network_globals.listen_multiaddrs.write().push(multiaddr);
                         }
                         Libp2pEvent::PeerConnected{ peer_id, endpoint,} => {
                             debug!(mothra.log, "Peer Connected"; "peer_id" => peer_id.to_string(), "endpoint" => format!("{:?}", endpoint));
-                            mothra.client.receive_rpc("Status".to_string(), 0, peer_id.to_string(), vec![])
+                            let _ = mothra.node_events.send(NodeEvent::PeerConnected(peer_id.clone()));
+                            mothra.delivery.push(Delivery::Rpc {
+                                method: "Status".to_string(),
+                                req_resp: 0,
+                                protocol: None,
+                                peer_id: peer_id.to_string(),
+                                data: vec![],
+                            }).await;
 
                         }
                         Libp2pEvent::PeerDisconnected{ peer_id, endpoint,} => {
                             debug!(mothra.log, "Peer Disconnected";  "peer_id" => peer_id.to_string(), "endpoint" => format!("{:?}", endpoint));
+                            let _ = mothra.node_events.send(NodeEvent::PeerDisconnected(peer_id));
+                        }
+                        Libp2pEvent::DialResult { token, address, result } => {
+                            debug!(mothra.log, "Dial finished"; "token" => token, "address" => address.to_string(), "result" => format!("{:?}", result));
+                            mothra.delivery.push(Delivery::DialResult {
+                                token,
+                                result: result.map(|peer_id| peer_id.to_string()),
+                            }).await;
                         }
                     }
                 }
@@ -262,58 +1173,236 @@ fn spawn_mothra(mut mothra: Mothra, executor: &TaskExecutor) -> error::Result<()
 }
 
 // TODO: Consider removing these helper methods and simply using the network_send channel
+/// Sends a gossip message to the network service. Fails with `NetworkErrorKind::ChannelClosed` if
+/// the service has stopped.
 pub fn gossip(
     mut network_send: mpsc::UnboundedSender<NetworkMessage>,
     topic: String,
     data: Vec<u8>,
     log: slog::Logger,
-) {
+) -> Result<(), NetworkError> {
     network_send
         .send(NetworkMessage::Publish {
-            topic: GossipTopic::new(topic),
+            topic: GossipTopic::new(topic.clone()),
             message: data,
+            ttl: None,
+        })
+        .map_err(|_| {
+            warn!(log, "Could not send gossip message.");
+            NetworkError::new(NetworkErrorKind::ChannelClosed, topic)
         })
-        .unwrap_or_else(|_| warn!(log, "Could not send gossip message."));
 }
 
+/// Sends a request to a peer, given as a base58 peer id. `method` is `Status` or the name of a
+/// protocol registered in `NetworkConfig::custom_protocols`, whose response is passed to
+/// `Subscriber::receive_rpc` under the same name.
 pub fn rpc_request(
     mut network_send: mpsc::UnboundedSender<NetworkMessage>,
     method: String,
     peer: String,
     data: Vec<u8>,
     log: slog::Logger,
-) {
-    let request_id: RequestId = RequestId::Behaviour;
-    let request: Request = Request::Status(data);
-    let bytes = bs58::decode(peer.as_str()).into_vec().unwrap();
-    let peer_id = PeerId::from_bytes(bytes).map_err(|_| ()).unwrap();
+) -> Result<(), NetworkError> {
+    let (request_id, request) = if method.eq_ignore_ascii_case("status") {
+        (RequestId::Behaviour, Request::Status(data))
+    } else {
+        (RequestId::Router, Request::Custom(method, data))
+    };
+    let peer_id = parse_peer_id(&peer)?;
     network_send
         .send(NetworkMessage::SendRequest {
             peer_id,
             request,
             request_id,
         })
-        .unwrap_or_else(|_| warn!(log, "Could not send RPC request to the network service"));
+        .map_err(|_| {
+            warn!(log, "Could not send RPC request to the network service");
+            NetworkError::new(NetworkErrorKind::ChannelClosed, peer)
+        })
 }
 
+/// Sends a response to a peer, given as a base58 peer id. `method` is `Status` or the name of the
+/// custom protocol of the request being answered, and `index` the first byte of the request's data
+/// passed to `Subscriber::receive_rpc`.
 pub fn rpc_response(
     mut network_send: mpsc::UnboundedSender<NetworkMessage>,
     method: String,
     peer: String,
+    index: u8,
     data: Vec<u8>,
     log: slog::Logger,
-) {
-    let index = 0;
-    let response: Response = Response::Status(data);
-    let bytes = bs58::decode(peer.as_str()).into_vec().unwrap();
-    let peer_id = PeerId::from_bytes(bytes).map_err(|_| ()).unwrap();
+) -> Result<(), NetworkError> {
+    let response = if method.eq_ignore_ascii_case("status") {
+        Response::Status(data)
+    } else {
+        Response::Custom(data)
+    };
+    let peer_id = parse_peer_id(&peer)?;
     network_send
         .send(NetworkMessage::SendResponse {
             peer_id,
             response,
             index,
         })
-        .unwrap_or_else(|_| warn!(log, "Could not send RPC response to the network service"));
+        .map_err(|_| {
+            warn!(log, "Could not send RPC response to the network service");
+            NetworkError::new(NetworkErrorKind::ChannelClosed, peer)
+        })
+}
+
+/// Sends a chunk of a response to a peer, given as a base58 peer id, for a custom protocol
+/// answering with several chunks. `index` is the one the request was received with, as for
+/// `rpc_response`. The response is ended with `rpc_end_of_stream`.
+pub fn rpc_response_chunk(
+    mut network_send: mpsc::UnboundedSender<NetworkMessage>,
+    peer: String,
+    index: u8,
+    data: Vec<u8>,
+    log: slog::Logger,
+) -> Result<(), NetworkError> {
+    let peer_id = parse_peer_id(&peer)?;
+    network_send
+        .send(NetworkMessage::SendResponse {
+            peer_id,
+            response: Response::Chunk(data),
+            index,
+        })
+        .map_err(|_| {
+            warn!(
+                log,
+                "Could not send RPC response chunk to the network service"
+            );
+            NetworkError::new(NetworkErrorKind::ChannelClosed, peer)
+        })
+}
+
+/// Ends the response of several chunks to the request received with `index` from a peer, given
+/// as a base58 peer id.
+pub fn rpc_end_of_stream(
+    mut network_send: mpsc::UnboundedSender<NetworkMessage>,
+    peer: String,
+    index: u8,
+    log: slog::Logger,
+) -> Result<(), NetworkError> {
+    let peer_id = parse_peer_id(&peer)?;
+    network_send
+        .send(NetworkMessage::SendResponse {
+            peer_id,
+            response: Response::EndOfStream,
+            index,
+        })
+        .map_err(|_| {
+            warn!(log, "Could not end the RPC response stream");
+            NetworkError::new(NetworkErrorKind::ChannelClosed, peer)
+        })
+}
+
+/// Dials an address, such as `/ip4/1.2.3.4/tcp/9000/p2p/<peer id>`. Returns the token the outcome
+/// is passed to `Subscriber::dial_result` with.
+pub fn dial(
+    mut network_send: mpsc::UnboundedSender<NetworkMessage>,
+    address: String,
+    log: slog::Logger,
+) -> Result<u64, NetworkError> {
+    let multiaddr = address
+        .parse::<Multiaddr>()
+        .map_err(|_| NetworkError::new(NetworkErrorKind::InvalidAddress, address.clone()))?;
+    let token = next_dial_token();
+    network_send
+        .send(NetworkMessage::Dial {
+            address: multiaddr,
+            token,
+        })
+        .map_err(|_| {
+            warn!(log, "Could not send dial to the network service");
+            NetworkError::new(NetworkErrorKind::ChannelClosed, address)
+        })?;
+    Ok(token)
+}
+
+/// Picks the token of a new dial, unique in this process.
+pub(crate) fn next_dial_token() -> u64 {
+    NEXT_DIAL_TOKEN.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Returns the discovery routing table entries, their bucket occupancy and the pending discovery
+/// queries, as the network service sees them when it handles the request.
+pub async fn dht_snapshot(
+    mut network_send: mpsc::UnboundedSender<NetworkMessage>,
+) -> Result<DhtSnapshot, NetworkError> {
+    let (reply, snapshot) = oneshot::channel();
+    let closed = || NetworkError::new(NetworkErrorKind::ChannelClosed, "dht snapshot");
+    network_send
+        .send(NetworkMessage::GetDhtSnapshot { reply })
+        .map_err(|_| closed())?;
+    snapshot.await.map_err(|_| closed())
+}
+
+/// Sends the DHT query built by `query` to the network service and waits for its outcome.
+#[cfg(feature = "kad")]
+async fn kad_query<T>(
+    mut network_send: mpsc::UnboundedSender<NetworkMessage>,
+    query: impl FnOnce(KadReply<T>) -> NetworkMessage,
+    context: &str,
+) -> Result<T, NetworkError> {
+    let (reply, outcome) = futures::channel::oneshot::channel();
+    let closed = || NetworkError::new(NetworkErrorKind::ChannelClosed, context);
+    network_send.send(query(reply)).map_err(|_| closed())?;
+    outcome.await.map_err(|_| closed())?.map_err(|e| {
+        NetworkError::new(
+            NetworkErrorKind::DhtQueryFailed,
+            format!("{}: {}", context, e),
+        )
+    })
+}
+
+/// Announces in the DHT that the node provides `key`, so that `get_providers` finds it.
+#[cfg(feature = "kad")]
+pub async fn start_providing(
+    network_send: mpsc::UnboundedSender<NetworkMessage>,
+    key: Vec<u8>,
+) -> Result<(), NetworkError> {
+    let query = |reply| NetworkMessage::StartProviding { key, reply };
+    kad_query(network_send, query, "start providing").await
+}
+
+/// Returns the peers that announced in the DHT that they provide `key`.
+#[cfg(feature = "kad")]
+pub async fn get_providers(
+    network_send: mpsc::UnboundedSender<NetworkMessage>,
+    key: Vec<u8>,
+) -> Result<Vec<PeerId>, NetworkError> {
+    let query = |reply| NetworkMessage::GetProviders { key, reply };
+    kad_query(network_send, query, "get providers").await
+}
+
+/// Stores `value` under `key` in the DHT.
+#[cfg(feature = "kad")]
+pub async fn put_record(
+    network_send: mpsc::UnboundedSender<NetworkMessage>,
+    key: Vec<u8>,
+    value: Vec<u8>,
+) -> Result<(), NetworkError> {
+    let query = |reply| NetworkMessage::PutRecord { key, value, reply };
+    kad_query(network_send, query, "put record").await
+}
+
+/// Returns the values stored under `key` in the DHT, as the peers holding the key sent them.
+#[cfg(feature = "kad")]
+pub async fn get_record(
+    network_send: mpsc::UnboundedSender<NetworkMessage>,
+    key: Vec<u8>,
+) -> Result<Vec<Vec<u8>>, NetworkError> {
+    let query = |reply| NetworkMessage::GetRecord { key, reply };
+    kad_query(network_send, query, "get record").await
+}
+
+fn parse_peer_id(peer: &str) -> Result<PeerId, NetworkError> {
+    bs58::decode(peer)
+        .into_vec()
+        .ok()
+        .and_then(|bytes| PeerId::from_bytes(bytes).ok())
+        .ok_or_else(|| NetworkError::new(NetworkErrorKind::InvalidPeerId, peer))
 }
 
 /// Types of messages that the network service can receive.
@@ -321,6 +1410,8 @@ pub fn rpc_response(
 pub enum NetworkMessage {
     /// Subscribe to a list of topics.
     Subscribe { subscriptions: Vec<GossipTopic> },
+    /// Unsubscribe from a list of topics.
+    Unsubscribe { topics: Vec<GossipTopic> },
     /// Send an RPC request to the libp2p service.
     SendRequest {
         peer_id: PeerId,
@@ -333,16 +1424,411 @@ pub enum NetworkMessage {
         response: Response,
         index: u8,
     },
-    /// Publish a message.
+    /// Publish a message. If no peer is subscribed to the topic yet, the message is held until
+    /// one subscribes. A message still held after `ttl` is not published; it is passed to
+    /// `Subscriber::publish_expired` instead.
     Publish {
         topic: GossipTopic,
         message: Vec<u8>,
+        ttl: Option<Duration>,
+    },
+    /// Publish a message straight to the given peers instead of the mesh. Peers that are not
+    /// connected or not subscribed to the topic are skipped.
+    PublishToPeers {
+        topic: GossipTopic,
+        message: Vec<u8>,
+        peers: Vec<PeerId>,
     },
     /// Propagate a received gossipsub message.
     Propagate {
         propagation_source: PeerId,
         message_id: MessageId,
     },
+    /// Drops the gossip messages a peer sends before they are delivered or count towards its
+    /// score, while it stays connected and in our meshes. The peer is unmuted when it disconnects
+    /// or is banned. See `NetworkGlobals::is_muted`.
+    MutePeer { peer_id: PeerId },
+    /// Accepts the gossip of a muted peer again.
+    UnmutePeer { peer_id: PeerId },
+    /// Stops propagating the messages others publish on a topic, for example while overloaded,
+    /// so stale data is not forwarded. Messages on the topic are still received and the node
+    /// stays in the topic's mesh. `Propagate` has no effect on the topic until forwarding is
+    /// resumed.
+    PauseForwarding { topic: String },
+    /// Propagates the messages received on a topic again.
+    ResumeForwarding { topic: String },
     /// Disconnect and bans a peer id.
     Disconnect { peer_id: PeerId },
+    /// Adjusts the reputation of a peer for an action observed by the application.
+    ReportPeer { peer_id: PeerId, action: PeerAction },
+    /// Changes the agent and protocol versions advertised to peers. `None` keeps the current value.
+    UpdateIdentify {
+        agent_version: Option<String>,
+        protocol_version: Option<String>,
+    },
+    /// Sets fields of the local ENR, given as key and raw value, and republishes it through
+    /// discovery under a new sequence number. With no fields the ENR is left as it is. The fork
+    /// fields are refused, as they are set by `UpdateForks`. Connected peers stay connected.
+    UpdateEnr { fields: Vec<(String, Vec<u8>)> },
+    /// Changes the forks served. Topics containing `FORK_PLACEHOLDER` are joined for the current
+    /// fork and the secondary one, if given, and left for forks no longer served, and the ENR
+    /// advertises both forks. Publishing to such a topic publishes on the current fork.
+    UpdateForks {
+        current: Vec<u8>,
+        secondary: Option<Vec<u8>>,
+    },
+    /// Advertises a service record in the local ENR, replacing any record registered under the
+    /// same key. Other nodes index it in their `PeerDB`, where `peers_providing` finds it, and
+    /// can search for providers with `EnrPredicate::provides`. ENRs are limited to 300 bytes, so
+    /// records should be short.
+    RegisterService {
+        service_key: String,
+        record: Vec<u8>,
+    },
+    /// Searches for up to `target` peers whose ENR matches `predicate`. Each peer found is passed
+    /// to `Subscriber::discovered_enr`.
+    DiscoverPeers {
+        predicate: EnrPredicate,
+        target: usize,
+    },
+    /// Dials an address. The outcome is passed to `Subscriber::dial_result` with `token`, which
+    /// `dial` picks uniquely.
+    Dial { address: Multiaddr, token: u64 },
+    /// Sends a request to up to `fanout` connected peers picked at random, passing the result of
+    /// each peer to `results`. See `Mothra::broadcast_request`.
+    BroadcastRequest {
+        request: Request,
+        fanout: usize,
+        results: mpsc::UnboundedSender<FanOutResult>,
+    },
+    /// Sends the discovery routing table entries, their bucket occupancy and the pending
+    /// discovery queries to `reply`.
+    GetDhtSnapshot { reply: oneshot::Sender<DhtSnapshot> },
+    /// Announces in the Kademlia DHT that the node provides `key`, and keeps announcing it until
+    /// the node stops. Only with the `kad` feature, like the other DHT messages.
+    #[cfg(feature = "kad")]
+    StartProviding { key: Vec<u8>, reply: KadReply<()> },
+    /// Searches the DHT for the peers providing `key`.
+    #[cfg(feature = "kad")]
+    GetProviders {
+        key: Vec<u8>,
+        reply: KadReply<Vec<PeerId>>,
+    },
+    /// Stores `value` under `key` at the peers closest to the key in the DHT.
+    #[cfg(feature = "kad")]
+    PutRecord {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        reply: KadReply<()>,
+    },
+    /// Searches the DHT for the value stored under `key`.
+    #[cfg(feature = "kad")]
+    GetRecord {
+        key: Vec<u8>,
+        reply: KadReply<Vec<Vec<u8>>>,
+    },
+    /// Changes the log level of the node. This only has an effect if the application filters its
+    /// root logger with a `RuntimeLevelFilter` on `Config::log_level`.
+    SetLogLevel { level: Level },
+    /// Sets the key the messages of a topic are encrypted with, encrypting the topic from now on
+    /// if it was not before.
+    SetTopicKey { topic: String, key: TopicKey },
+    /// Replaces the key of an encrypted topic at once. Messages under the previous key are still
+    /// accepted for the topic key grace window, but members that do not have the new key yet
+    /// cannot read the messages published under it. `AddTopicKey` and `ActivateTopicKey` rotate
+    /// a key without that gap.
+    RotateTopicKey { topic: String, key: TopicKey },
+    /// Accepts messages under a new key of an encrypted topic, still publishing under the current
+    /// key. The first phase of a rotation, to be done by every member of the topic.
+    AddTopicKey { topic: String, key: TopicKey },
+    /// Publishes the messages of an encrypted topic under a key given to `AddTopicKey`. The
+    /// second phase of a rotation, once every member added the key. Messages under the replaced
+    /// keys are still accepted for the topic key grace window.
+    ActivateTopicKey { topic: String, key: TopicKey },
+}
+
+/// Records a message key as seen on `topic`, returning true if it was seen there recently. The
+/// same key on another topic is another message.
+fn seen_before(
+    seen_message_keys: &mut LruCache<(String, Vec<u8>), ()>,
+    topic: &str,
+    key: Vec<u8>,
+) -> bool {
+    seen_message_keys
+        .put((topic.to_string(), key), ())
+        .is_some()
+}
+
+/// Records a peer discovered at `now` as reported, unless it was reported less than `interval`
+/// ago. Returns None if the peer is not reported, and otherwise whether it is reported for the
+/// first time.
+fn report_discovered(
+    reported: &mut LruCache<PeerId, Instant>,
+    interval: Duration,
+    peer_id: &PeerId,
+    now: Instant,
+) -> Option<bool> {
+    let report = match reported.get(peer_id) {
+        None => Some(true),
+        Some(last) if now.duration_since(*last) >= interval => Some(false),
+        Some(_) => None,
+    };
+    if report.is_some() {
+        reported.put(peer_id.clone(), now);
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use network::unused_port;
+    use std::net::{IpAddr, Ipv4Addr};
+    use tempdir::TempDir;
+    use tokio::runtime::Builder;
+
+    const TOPIC: &str = "/mothra/current_thread";
+
+    /// Passes the data of received gossip to the test, if it listens.
+    struct TestClient(Option<mpsc::UnboundedSender<Vec<u8>>>);
+
+    impl Subscriber for TestClient {
+        fn init(&mut self, _send: mpsc::UnboundedSender<NetworkMessage>, _fork_id: Vec<u8>) {}
+
+        fn discovered_peer(&self, _peer: String, _new: bool) {}
+
+        fn receive_gossip(&self, message: GossipMessage) {
+            if let Some(received) = &self.0 {
+                let _ = received.send(message.data);
+            }
+        }
+
+        fn receive_rpc(
+            &self,
+            _method: String,
+            _req_resp: u8,
+            _protocol: Option<ProtocolId>,
+            _peer: String,
+            _data: Vec<u8>,
+        ) {
+        }
+    }
+
+    fn start_node(
+        dir: &TempDir,
+        static_peers: Vec<Multiaddr>,
+        client: TestClient,
+        executor: &TaskExecutor,
+        log: &Logger,
+    ) -> (Arc<NetworkGlobals>, mpsc::UnboundedSender<NetworkMessage>) {
+        let mut config = Config::default();
+        // deliver on the runtime rather than on worker threads
+        config.delivery_workers = 0;
+        config.network_config.network_dir = dir.path().into();
+        config.network_config.listen_address = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        config.network_config.libp2p_port = unused_port("tcp").unwrap();
+        config.network_config.discovery_port = unused_port("udp").unwrap();
+        config.network_config.disable_discovery = true;
+        config.network_config.static_peers = static_peers;
+        config.network_config.topics = vec![TOPIC.into()];
+        Mothra::new(
+            config,
+            vec![0; 32],
+            vec![0; 32],
+            vec![0; 32],
+            executor,
+            Box::new(client),
+            log.clone(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_current_thread_runtime() {
+        let mut runtime = Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+        let log = Logger::root(slog::Discard, o!());
+        let (exit_signal, exit) = exit_future::signal();
+        let executor = TaskExecutor::new(runtime.handle().clone(), exit, log.clone());
+        let dir_a = TempDir::new("mothra_node_a").unwrap();
+        let dir_b = TempDir::new("mothra_node_b").unwrap();
+
+        let received = runtime.block_on(async {
+            let (globals_a, send_a) = start_node(&dir_a, vec![], TestClient(None), &executor, &log);
+            let node_a = format!(
+                "/ip4/127.0.0.1/tcp/{}/p2p/{}",
+                globals_a.listen_port_tcp(),
+                globals_a.local_peer_id()
+            )
+            .parse()
+            .unwrap();
+            let (received_send, mut received_recv) = mpsc::unbounded_channel();
+            let _node_b = start_node(
+                &dir_b,
+                vec![node_a],
+                TestClient(Some(received_send)),
+                &executor,
+                &log,
+            );
+
+            // publish until node b has joined the mesh and received a message
+            let mut interval = tokio::time::interval(Duration::from_millis(500));
+            let mut count = 0u64;
+            tokio::time::timeout(Duration::from_secs(30), async {
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {
+                            count += 1;
+                            let data = count.to_be_bytes().to_vec();
+                            gossip(send_a.clone(), TOPIC.into(), data, log.clone()).unwrap();
+                        }
+                        data = received_recv.recv() => return data,
+                    }
+                }
+            })
+            .await
+        });
+
+        let _ = exit_signal.fire();
+        runtime.shutdown_timeout(Duration::from_millis(300));
+        assert_eq!(received.unwrap().map(|data| data.len()), Some(8));
+    }
+
+    #[test]
+    fn test_gossip_timing() {
+        let received_at = SystemTime::now();
+        let second = Duration::from_secs(1);
+        assert_eq!(GossipTiming::new(received_at, None).latency, None);
+        let timing = GossipTiming::new(received_at, Some(received_at - second));
+        assert_eq!(timing.received_at, received_at);
+        assert_eq!(timing.latency, Some(second));
+        // a publisher whose clock is ahead of ours
+        let timing = GossipTiming::new(received_at, Some(received_at + second));
+        assert_eq!(timing.latency, Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn test_message_topics() {
+        let hashes = vec![
+            TopicHash::from_raw("/mothra/known"),
+            TopicHash::from_raw("not a mothra topic"),
+        ];
+        let topics: Vec<MessageTopic> = hashes.iter().map(MessageTopic::from).collect();
+        // every hash is kept, in order, whether or not it decodes
+        let raw: Vec<TopicHash> = topics.iter().map(|topic| topic.hash.clone()).collect();
+        assert_eq!(raw, hashes);
+        assert_eq!(
+            topics[0].topic,
+            Some(GossipTopic::new("/mothra/known".into()))
+        );
+        assert_eq!(topics[1].topic, None);
+    }
+
+    #[test]
+    fn test_report_discovered() {
+        let mut reported = LruCache::new(2);
+        let interval = Duration::from_secs(60);
+        let start = Instant::now();
+        let peer = PeerId::random();
+        assert_eq!(
+            report_discovered(&mut reported, interval, &peer, start),
+            Some(true)
+        );
+        // found again within the interval
+        let later = start + Duration::from_secs(30);
+        assert_eq!(
+            report_discovered(&mut reported, interval, &peer, later),
+            None
+        );
+        // reported again once the interval has passed, counting from the last report
+        let later = start + interval;
+        assert_eq!(
+            report_discovered(&mut reported, interval, &peer, later),
+            Some(false)
+        );
+        let sooner = later + Duration::from_secs(30);
+        assert_eq!(
+            report_discovered(&mut reported, interval, &peer, sooner),
+            None
+        );
+
+        // a forgotten peer is new again
+        for _ in 0..2 {
+            let other = PeerId::random();
+            assert_eq!(
+                report_discovered(&mut reported, interval, &other, start),
+                Some(true)
+            );
+        }
+        assert_eq!(
+            report_discovered(&mut reported, interval, &peer, sooner),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn test_seen_message_keys() {
+        let mut seen = LruCache::new(2);
+        assert!(!seen_before(&mut seen, "/a", vec![1]));
+        assert!(seen_before(&mut seen, "/a", vec![1]));
+        // the same key on another topic is not a duplicate
+        assert!(!seen_before(&mut seen, "/b", vec![1]));
+        assert!(seen_before(&mut seen, "/b", vec![1]));
+
+        // the least recently seen key is evicted, and is then new again
+        assert!(!seen_before(&mut seen, "/a", vec![2]));
+        assert!(!seen_before(&mut seen, "/a", vec![1]));
+        assert!(seen_before(&mut seen, "/a", vec![2]));
+    }
+
+    #[test]
+    fn test_send_errors() {
+        let log = Logger::root(slog::Discard, o!());
+        let (send, recv) = mpsc::unbounded_channel();
+        let peer = PeerId::random().to_base58();
+
+        let error = rpc_request(
+            send.clone(),
+            "Status".into(),
+            "peer".into(),
+            vec![],
+            log.clone(),
+        )
+        .unwrap_err();
+        assert_eq!(error.kind, NetworkErrorKind::InvalidPeerId);
+        let sent = rpc_response(
+            send.clone(),
+            "Status".into(),
+            peer.clone(),
+            0,
+            vec![],
+            log.clone(),
+        );
+        assert!(sent.is_ok());
+        let error =
+            rpc_response_chunk(send.clone(), "peer".into(), 1, vec![1], log.clone()).unwrap_err();
+        assert_eq!(error.kind, NetworkErrorKind::InvalidPeerId);
+        assert!(rpc_response_chunk(send.clone(), peer.clone(), 1, vec![1], log.clone()).is_ok());
+        assert!(rpc_end_of_stream(send.clone(), peer.clone(), 1, log.clone()).is_ok());
+        let address = "/ip4/127.0.0.1/tcp/9000".to_string();
+        let first = dial(send.clone(), address.clone(), log.clone()).unwrap();
+        assert_ne!(dial(send.clone(), address, log.clone()).unwrap(), first);
+
+        // the network service has stopped
+        drop(recv);
+        let error = gossip(send.clone(), TOPIC.into(), vec![1], log.clone()).unwrap_err();
+        assert_eq!(
+            error,
+            NetworkError::new(NetworkErrorKind::ChannelClosed, TOPIC)
+        );
+        let error =
+            rpc_response(send.clone(), "Status".into(), peer, 0, vec![], log.clone()).unwrap_err();
+        assert_eq!(error.kind, NetworkErrorKind::ChannelClosed);
+
+        let error = dial(send, "not an address".into(), log).unwrap_err();
+        assert_eq!(error.kind, NetworkErrorKind::InvalidAddress);
+    }
 }
@@ -0,0 +1,238 @@
+//! A diagnostic mode that dials a single peer, identifies it and measures the round trip time of
+//! an RPC Ping before exiting.
+use crate::cli::{EXIT_FAILURE, EXIT_INVALID_ARGS, EXIT_SUCCESS};
+use crate::{config::Config, error};
+use clap::ArgMatches;
+use network::Service as LibP2PService;
+use network::{
+    multiaddr::Protocol, rpc::RequestId, unused_port, BehaviourEvent, ConnectedPoint, Enr, EnrExt,
+    Libp2pEvent, Multiaddr, PeerId, Swarm, TaskExecutor,
+};
+use slog::debug;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// The name of the directory, inside the system temp directory, that holds the ping node's key
+/// and ENR. This keeps the ping from overwriting the identity of a node sharing the datadir.
+const PING_NETWORK_DIR: &str = "mothra-ping";
+
+/// The information gathered about a peer by a successful ping.
+#[derive(Debug, Clone)]
+pub struct PingReport {
+    /// The peer that was pinged.
+    pub peer_id: PeerId,
+    /// The address that was dialed.
+    pub address: Multiaddr,
+    /// The time between sending the Ping and receiving the Pong.
+    pub rtt: Duration,
+    /// The agent version the peer advertised over identify.
+    pub agent_version: String,
+    /// The protocol version the peer advertised over identify.
+    pub protocol_version: String,
+    /// The protocols the peer supports.
+    pub protocols: Vec<String>,
+}
+
+impl fmt::Display for PingReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "peer_id: {}", self.peer_id)?;
+        writeln!(f, "address: {}", self.address)?;
+        writeln!(f, "rtt: {:?}", self.rtt)?;
+        writeln!(f, "agent_version: {}", self.agent_version)?;
+        writeln!(f, "protocol_version: {}", self.protocol_version)?;
+        write!(f, "protocols:")?;
+        for protocol in &self.protocols {
+            write!(f, "\n  {}", protocol)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs the `ping` subcommand, printing the outcome and returning the process exit code.
+pub async fn run(
+    config: Config,
+    args: &ArgMatches<'_>,
+    executor: &TaskExecutor,
+    log: &slog::Logger,
+) -> i32 {
+    let target = match args.value_of("target") {
+        Some(target) => target,
+        None => {
+            eprintln!("A multiaddr or ENR to ping is required");
+            return EXIT_INVALID_ARGS;
+        }
+    };
+    let timeout = match args.value_of("timeout").map(|t| t.parse::<u64>()) {
+        Some(Ok(secs)) => Duration::from_secs(secs),
+        Some(Err(_)) | None => {
+            eprintln!("Invalid timeout: {:?}", args.value_of("timeout"));
+            return EXIT_INVALID_ARGS;
+        }
+    };
+    let (address, peer_id) = match parse_target(target) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}", e);
+            return EXIT_INVALID_ARGS;
+        }
+    };
+
+    match ping(config, address, peer_id, timeout, executor, log).await {
+        Ok(report) => {
+            println!("{}", report);
+            EXIT_SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Ping to {} failed: {}", target, e);
+            EXIT_FAILURE
+        }
+    }
+}
+
+/// Splits a ping target into the address to dial and, if known, the expected peer id.
+///
+/// The target can either be a base64-encoded ENR, in which case its first TCP address is dialed,
+/// or a multiaddr optionally ending in a `/p2p/<peer_id>` component.
+pub fn parse_target(target: &str) -> error::Result<(Multiaddr, Option<PeerId>)> {
+    if target.starts_with("enr:") {
+        let enr: Enr = target
+            .parse()
+            .map_err(|_| format!("Invalid ENR: {}", target))?;
+        let address = enr
+            .multiaddr_tcp()
+            .into_iter()
+            .next()
+            .ok_or_else(|| format!("ENR does not contain a TCP address: {}", target))?;
+        Ok((address, Some(enr.peer_id())))
+    } else {
+        let address: Multiaddr = target
+            .parse()
+            .map_err(|_| format!("Invalid Multiaddr: {}", target))?;
+        let peer_id = address.iter().find_map(|protocol| match protocol {
+            Protocol::P2p(multihash) => PeerId::from_multihash(multihash).ok(),
+            _ => None,
+        });
+        Ok((address, peer_id))
+    }
+}
+
+/// Dials `address`, waits for the peer to be identified and answer a Ping, and reports the result.
+///
+/// A throwaway libp2p service is started for the duration of the ping. Discovery is disabled and
+/// no boot nodes, static peers or topics from `config` are used.
+pub async fn ping(
+    mut config: Config,
+    address: Multiaddr,
+    expected_peer: Option<PeerId>,
+    timeout: Duration,
+    executor: &TaskExecutor,
+    log: &slog::Logger,
+) -> error::Result<PingReport> {
+    let network_config = &mut config.network_config;
+    network_config.network_dir = std::env::temp_dir().join(PING_NETWORK_DIR);
+    network_config.disable_discovery = true;
+    network_config.boot_nodes = vec![];
+    network_config.libp2p_nodes = vec![];
+    network_config.topics = vec![];
+    network_config.libp2p_port =
+        unused_port("tcp").map_err(|e| format!("Failed to get port for libp2p: {}", e))?;
+    network_config.enr_tcp_port = Some(network_config.libp2p_port);
+    network_config.discv5_config.executor = Some(Box::new(executor.clone()));
+
+    let (_network_globals, mut libp2p) = LibP2PService::new(
+        executor.clone(),
+        network_config,
+        vec![],
+        vec![],
+        vec![0u8; 8],
+        log,
+    )?;
+
+    Swarm::dial_addr(&mut libp2p.swarm, address.clone())
+        .map_err(|e| format!("Could not dial {}: {:?}", address, e))?;
+
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut remote: Option<PeerId> = None;
+    let mut sent_at: Option<Instant> = None;
+    let mut rtt: Option<Duration> = None;
+    let mut identity: Option<(String, String, Vec<String>)> = None;
+
+    loop {
+        let event = tokio::time::timeout_at(deadline, libp2p.next_event())
+            .await
+            .map_err(|_| format!("No answer within {:?}", timeout))?;
+
+        match event {
+            Libp2pEvent::PeerConnected {
+                peer_id,
+                endpoint: ConnectedPoint::Dialer { .. },
+            } if remote.is_none() => {
+                if let Some(expected) = &expected_peer {
+                    if expected != &peer_id {
+                        return Err(
+                            format!("Connected to {} but expected {}", peer_id, expected).into(),
+                        );
+                    }
+                }
+                debug!(log, "Sending ping"; "peer_id" => peer_id.to_string());
+                libp2p.swarm.ping(RequestId::Router, peer_id.clone());
+                sent_at = Some(Instant::now());
+                remote = Some(peer_id);
+            }
+            Libp2pEvent::PeerDisconnected { peer_id, .. } if remote.as_ref() == Some(&peer_id) => {
+                return Err("The peer disconnected before answering".into());
+            }
+            Libp2pEvent::Behaviour(BehaviourEvent::PongReceived { peer_id, .. })
+                if remote.as_ref() == Some(&peer_id) =>
+            {
+                rtt = sent_at.map(|sent| sent.elapsed());
+            }
+            Libp2pEvent::Behaviour(BehaviourEvent::RPCFailed { peer_id, error, .. })
+                if remote.as_ref() == Some(&peer_id) =>
+            {
+                return Err(format!("Ping failed: {}", error).into());
+            }
+            Libp2pEvent::Behaviour(BehaviourEvent::PeerIdentified {
+                peer_id,
+                agent_version,
+                protocol_version,
+                protocols,
+            }) if remote.as_ref() == Some(&peer_id) => {
+                identity = Some((agent_version, protocol_version, protocols));
+            }
+            _ => {}
+        }
+
+        if let (Some(peer_id), Some(rtt)) = (&remote, rtt) {
+            if let Some((agent_version, protocol_version, protocols)) = identity.take() {
+                return Ok(PingReport {
+                    peer_id: peer_id.clone(),
+                    address,
+                    rtt,
+                    agent_version,
+                    protocol_version,
+                    protocols,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_target() {
+        let peer_id = PeerId::random();
+        let address = format!("/ip4/127.0.0.1/tcp/9000/p2p/{}", peer_id);
+        let (parsed, expected) = parse_target(&address).unwrap();
+        assert_eq!(parsed.to_string(), address);
+        assert_eq!(expected, Some(peer_id));
+
+        let (_, expected) = parse_target("/ip4/127.0.0.1/tcp/9000").unwrap();
+        assert_eq!(expected, None);
+        assert!(parse_target("not an address").is_err());
+        assert!(parse_target("enr:-invalid").is_err());
+    }
+}
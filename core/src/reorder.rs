@@ -0,0 +1,146 @@
+//! Restores the publish order of gossip on topics that require it.
+//!
+//! Gossipsub hands over messages in the order they arrive, which can differ from the order they
+//! were published in. The gossipsub sequence numbers cannot restore that order: messages carry no
+//! source, and their sequence numbers are not contiguous per publisher. The order is instead taken
+//! from the payload, by `Subscriber::message_sequence`, which returns the stream a message belongs
+//! to and its number within the stream.
+//!
+//! On ordered topics, messages are released per (topic, stream) in sequence number order. A
+//! message that arrives early is held back until the messages before it arrive, or until more
+//! than `window` messages are held, at which point the gap is skipped. A message older than the
+//! last one released is dropped. Messages the application gives no sequence for are not held.
+//!
+//! This relies on the application numbering the messages of a stream sequentially.
+use lru::LruCache;
+use std::collections::{BTreeMap, HashSet};
+
+/// The default number of early messages held back per (topic, stream).
+pub const DEFAULT_REORDER_WINDOW: usize = 32;
+
+/// The number of (topic, stream) pairs whose order is tracked.
+const MAX_STREAMS: usize = 1024;
+
+/// The messages of a single stream on a single topic.
+struct Stream<T> {
+    /// The sequence number of the next message to release.
+    next: u64,
+    /// Messages that arrived before `next`.
+    pending: BTreeMap<u64, T>,
+}
+
+impl<T> Stream<T> {
+    /// Moves the consecutive messages starting at `next` to `ready`.
+    fn release(&mut self, ready: &mut Vec<T>) {
+        while let Some(message) = self.pending.remove(&self.next) {
+            ready.push(message);
+            self.next = self.next.wrapping_add(1);
+        }
+    }
+}
+
+/// Buffers the gossip on ordered topics until it can be delivered in order.
+pub struct Reorderer<T> {
+    /// The topics whose messages are delivered in order.
+    topics: HashSet<String>,
+    /// The maximum number of messages held back per stream.
+    window: usize,
+    streams: LruCache<(String, Vec<u8>), Stream<T>>,
+    /// The number of messages dropped because they arrived after a later message was released.
+    stale: u64,
+}
+
+impl<T> Reorderer<T> {
+    pub fn new(topics: impl IntoIterator<Item = String>, window: usize) -> Self {
+        Reorderer {
+            topics: topics.into_iter().collect(),
+            window,
+            streams: LruCache::new(MAX_STREAMS),
+            stale: 0,
+        }
+    }
+
+    /// Returns true if messages on `topic` are delivered in order.
+    pub fn is_ordered(&self, topic: &str) -> bool {
+        self.topics.contains(topic)
+    }
+
+    /// Accepts message `sequence_number` of `stream` and returns the messages that can now be
+    /// delivered, in order. Messages on topics that are not ordered are returned immediately.
+    pub fn push(
+        &mut self,
+        topic: &str,
+        stream: Vec<u8>,
+        sequence_number: u64,
+        message: T,
+    ) -> Vec<T> {
+        if !self.is_ordered(topic) {
+            return vec![message];
+        }
+        let key = (topic.to_string(), stream);
+        if !self.streams.contains(&key) {
+            // the first message seen of a stream starts it
+            self.streams.put(
+                key,
+                Stream {
+                    next: sequence_number.wrapping_add(1),
+                    pending: BTreeMap::new(),
+                },
+            );
+            return vec![message];
+        }
+        let stream = self.streams.get_mut(&key).expect("stream exists");
+        if sequence_number < stream.next {
+            self.stale += 1;
+            return vec![];
+        }
+
+        let mut ready = Vec::new();
+        stream.pending.insert(sequence_number, message);
+        stream.release(&mut ready);
+        while stream.pending.len() > self.window {
+            // give up on the gap before the oldest held message
+            if let Some(&oldest) = stream.pending.keys().next() {
+                stream.next = oldest;
+            }
+            stream.release(&mut ready);
+        }
+        ready
+    }
+
+    /// The number of messages dropped because they arrived too late to be delivered in order.
+    pub fn stale(&self) -> u64 {
+        self.stale
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reorders_within_window() {
+        let mut reorderer = Reorderer::new(vec!["ordered".to_string()], 2);
+        let a = || b"a".to_vec();
+        assert_eq!(reorderer.push("ordered", a(), 1, 1), vec![1]);
+        assert!(reorderer.push("ordered", a(), 3, 3).is_empty());
+        // other streams and topics are not held back
+        assert_eq!(reorderer.push("ordered", b"b".to_vec(), 7, 7), vec![7]);
+        assert_eq!(reorderer.push("other", a(), 5, 5), vec![5]);
+        assert_eq!(reorderer.push("ordered", a(), 2, 2), vec![2, 3]);
+        // late messages are dropped
+        assert!(reorderer.push("ordered", a(), 1, 1).is_empty());
+        assert_eq!(reorderer.stale(), 1);
+    }
+
+    #[test]
+    fn test_skips_gap_when_window_is_full() {
+        let mut reorderer = Reorderer::new(vec!["ordered".to_string()], 2);
+        let a = || b"a".to_vec();
+        assert_eq!(reorderer.push("ordered", a(), 1, 1), vec![1]);
+        assert!(reorderer.push("ordered", a(), 4, 4).is_empty());
+        assert!(reorderer.push("ordered", a(), 5, 5).is_empty());
+        assert_eq!(reorderer.push("ordered", a(), 7, 7), vec![4, 5]);
+        assert_eq!(reorderer.push("ordered", a(), 6, 6), vec![6, 7]);
+    }
+}
@@ -0,0 +1,124 @@
+//! The inbound requests waiting for the client's response.
+//!
+//! A request handed to the client is saved in one of 256 slots, whose index is the first byte of
+//! the data passed to `Subscriber::receive_rpc`. The client answers with that index, so several
+//! requests can be open at once and answered in any order. A slot is free again once its request
+//! was answered or cancelled. Each slot records the peer its request came from, so an index given
+//! with another peer is refused without touching the request.
+
+/// The slots of the open requests, received from peers `P`.
+pub struct RequestSlots<P, T> {
+    slots: Vec<Option<(P, T)>>,
+    /// The number of slots in use.
+    len: usize,
+}
+
+impl<P: Clone + PartialEq, T: Copy + PartialEq> RequestSlots<P, T> {
+    pub fn new() -> Self {
+        RequestSlots {
+            slots: vec![None; 256],
+            len: 0,
+        }
+    }
+
+    /// Saves a request of `peer` in the first free slot, returning its index, or `None` if every
+    /// slot is in use.
+    pub fn insert(&mut self, peer: P, request: T) -> Option<u8> {
+        let index = self.slots.iter().position(Option::is_none)?;
+        self.slots[index] = Some((peer, request));
+        self.len += 1;
+        Some(index as u8)
+    }
+
+    /// Returns the request saved at `index`, if it was received from `peer`.
+    pub fn get(&self, index: u8, peer: &P) -> Option<T> {
+        match &self.slots[index as usize] {
+            Some((owner, request)) if owner == peer => Some(*request),
+            _ => None,
+        }
+    }
+
+    /// Frees the slot at `index` if its request was received from `peer`, returning the request.
+    pub fn take(&mut self, index: u8, peer: &P) -> Option<T> {
+        let request = self.get(index, peer)?;
+        self.slots[index as usize] = None;
+        self.len -= 1;
+        Some(request)
+    }
+
+    /// Frees the slot holding `request` of `peer`, returning its index.
+    pub fn remove(&mut self, peer: &P, request: T) -> Option<u8> {
+        let index = self.slots.iter().position(|slot| match slot {
+            Some((owner, saved)) => owner == peer && *saved == request,
+            None => false,
+        })?;
+        self.take(index as u8, peer);
+        Some(index as u8)
+    }
+
+    /// The number of open requests.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<P: Clone + PartialEq, T: Copy + PartialEq> Default for RequestSlots<P, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concurrent_requests_keep_their_index() {
+        let mut slots = RequestSlots::new();
+        let first = slots.insert("a", 10u32).unwrap();
+        let second = slots.insert("a", 20u32).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(slots.len(), 2);
+
+        // answering the second request leaves the first one open at its index
+        assert_eq!(slots.take(second, &"a"), Some(20));
+        assert_eq!(slots.get(first, &"a"), Some(10));
+        assert_eq!(slots.get(second, &"a"), None);
+
+        // the freed slot is reused without moving the open request
+        let third = slots.insert("a", 30u32).unwrap();
+        assert_eq!(third, second);
+        assert_eq!(slots.get(first, &"a"), Some(10));
+
+        assert_eq!(slots.remove(&"a", 10), Some(first));
+        assert_eq!(slots.remove(&"a", 10), None);
+        assert_eq!(slots.take(third, &"a"), Some(30));
+        assert!(slots.is_empty());
+    }
+
+    #[test]
+    fn test_requests_of_another_peer_are_untouched() {
+        let mut slots = RequestSlots::new();
+        let index = slots.insert("a", 10u32).unwrap();
+        assert_eq!(slots.get(index, &"b"), None);
+        assert_eq!(slots.take(index, &"b"), None);
+        assert_eq!(slots.remove(&"b", 10), None);
+        assert_eq!(slots.len(), 1);
+        assert_eq!(slots.take(index, &"a"), Some(10));
+    }
+
+    #[test]
+    fn test_full_slots() {
+        let mut slots = RequestSlots::new();
+        for request in 0..256u32 {
+            assert_eq!(slots.insert("a", request), Some(request as u8));
+        }
+        assert_eq!(slots.insert("a", 256), None);
+        slots.take(7, &"a");
+        assert_eq!(slots.insert("a", 256), Some(7));
+    }
+}
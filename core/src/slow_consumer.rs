@@ -0,0 +1,136 @@
+//! Detects a `Subscriber` too slow for the messages it receives.
+//!
+//! When messages pile up in the delivery queue, or are dropped from it, the cause is usually the
+//! application's callbacks rather than the network. Each delivery worker therefore times the
+//! callbacks it makes, and how long their messages waited in its queue. Over every interval it
+//! counts the callbacks that ran longer than the threshold; if more than half of them did, the
+//! `Subscriber` is reported as the bottleneck, with the figures of the interval.
+use serde_derive::Serialize;
+use std::time::{Duration, Instant};
+
+/// The default time in milliseconds a callback may take before it counts as slow.
+pub const DEFAULT_SLOW_CONSUMER_THRESHOLD: u64 = 100;
+
+/// The period over which callbacks are measured.
+const INTERVAL: Duration = Duration::from_secs(10);
+
+/// The callbacks made by a delivery worker over an interval in which the `Subscriber` was slow.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct ConsumerStats {
+    /// The delivery worker that made the callbacks.
+    pub worker: usize,
+    /// The number of callbacks made in the interval.
+    pub callbacks: usize,
+    /// The number of callbacks that ran longer than the threshold.
+    pub slow_callbacks: usize,
+    /// The mean time a callback ran.
+    pub mean_callback: Duration,
+    /// The longest time a callback ran.
+    pub max_callback: Duration,
+    /// The mean time messages waited in the queue before their callback.
+    pub mean_wait: Duration,
+    /// The longest time a message waited in the queue.
+    pub max_wait: Duration,
+}
+
+/// Measures the callbacks of one delivery worker.
+pub struct ConsumerMonitor {
+    threshold: Duration,
+    interval_start: Instant,
+    stats: ConsumerStats,
+    total_callback: Duration,
+    total_wait: Duration,
+}
+
+impl ConsumerMonitor {
+    pub fn new(worker: usize, threshold: Duration, now: Instant) -> Self {
+        ConsumerMonitor {
+            threshold,
+            interval_start: now,
+            stats: ConsumerStats {
+                worker,
+                ..ConsumerStats::default()
+            },
+            total_callback: Duration::default(),
+            total_wait: Duration::default(),
+        }
+    }
+
+    /// Records a callback that ran for `callback`, on a message that waited `wait` in the queue.
+    /// At the end of an interval in which the `Subscriber` was slow, returns the figures of the
+    /// interval.
+    pub fn record(
+        &mut self,
+        wait: Duration,
+        callback: Duration,
+        now: Instant,
+    ) -> Option<ConsumerStats> {
+        let stats = &mut self.stats;
+        stats.callbacks += 1;
+        if callback > self.threshold {
+            stats.slow_callbacks += 1;
+        }
+        stats.max_callback = stats.max_callback.max(callback);
+        stats.max_wait = stats.max_wait.max(wait);
+        self.total_callback += callback;
+        self.total_wait += wait;
+
+        if now.saturating_duration_since(self.interval_start) < INTERVAL {
+            return None;
+        }
+        let worker = self.stats.worker;
+        let mut stats = std::mem::replace(
+            &mut self.stats,
+            ConsumerStats {
+                worker,
+                ..ConsumerStats::default()
+            },
+        );
+        let callbacks = stats.callbacks as u32;
+        stats.mean_callback = std::mem::take(&mut self.total_callback) / callbacks;
+        stats.mean_wait = std::mem::take(&mut self.total_wait) / callbacks;
+        self.interval_start = now;
+        if stats.slow_callbacks * 2 > stats.callbacks {
+            Some(stats)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_slow_intervals() {
+        let start = Instant::now();
+        let threshold = Duration::from_millis(100);
+        let mut monitor = ConsumerMonitor::new(3, threshold, start);
+        let fast = Duration::from_millis(10);
+        let slow = Duration::from_millis(300);
+
+        // an occasional slow callback is not reported
+        assert_eq!(monitor.record(fast, fast, start), None);
+        assert_eq!(monitor.record(fast, slow, start), None);
+        assert_eq!(monitor.record(fast, fast, start + INTERVAL), None);
+
+        // but most callbacks running slow are
+        assert_eq!(monitor.record(fast, slow, start + INTERVAL), None);
+        let stats = monitor
+            .record(slow, slow, start + 2 * INTERVAL)
+            .expect("slow interval");
+        assert_eq!(
+            stats,
+            ConsumerStats {
+                worker: 3,
+                callbacks: 2,
+                slow_callbacks: 2,
+                mean_callback: slow,
+                max_callback: slow,
+                mean_wait: Duration::from_millis(155),
+                max_wait: slow,
+            }
+        );
+    }
+}
@@ -0,0 +1,2 @@
+//! Fixtures shared by the tests of several modules.
+pub use network::testing::network_globals;
@@ -15,9 +15,10 @@
 #define LEN(x)  (sizeof(x) / sizeof((x)[0]))
 
 
-void on_discovered_peer(const unsigned char* peer_utf8, int peer_length) {
+void on_discovered_peer(const unsigned char* peer_utf8, int peer_length, int is_new) {
     printf("C: discovered peer");
-    printf(",peer=%.*s\n", peer_length, peer_utf8);
+    printf(",peer=%.*s", peer_length, peer_utf8);
+    printf(",new=%d\n", is_new);
 }
 
 void on_receive_gossip(const unsigned char* message_id_utf8, int message_id_length, const unsigned char* peer_id_utf8, int peer_id_length, const unsigned char* topic_utf8, int topic_length, unsigned char* data, int data_length) {
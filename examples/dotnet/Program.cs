@@ -157,11 +157,11 @@ namespace Example
 
     public class Handlers
     {
-        public unsafe void OnDiscoveredPeer(byte* peerUtf8, int peerLength)
+        public unsafe void OnDiscoveredPeer(byte* peerUtf8, int peerLength, int isNew)
         {
             Console.Write("dotnet: peer");
             string peer = new String((sbyte*)peerUtf8, 0, peerLength, Encoding.UTF8);
-            Console.WriteLine($" discovered {peer}");
+            Console.WriteLine($" discovered {peer} new={isNew != 0}");
         }
 
         public unsafe void OnReceiveGossip(byte* messageIdUtf8, int messageIdLength, byte* peerIdUtf8, int peerIdLength, byte* topicUtf8, int topicLength, byte* data, int dataLength)
@@ -38,16 +38,21 @@ public class Example {
                 int req_resp = Integer.parseInt(scanner.next());
                 System.out.print("Enter a Peer: ");
                 String peer = scanner.next();
+                int index = 0;
+                if(req_resp == 1){
+                    System.out.print("Enter the request index: ");
+                    index = Integer.parseInt(scanner.next());
+                }
                 System.out.print("Enter a message: ");
                 String message = scanner.next();
-                Mothra.SendRPC("HELLO".getBytes(),req_resp,peer.getBytes(),message.getBytes());
+                Mothra.SendRPC("HELLO".getBytes(),req_resp,peer.getBytes(),index,message.getBytes());
             }
         }
 
     }
 
-    public static Boolean printDiscoveryMessage(String peer){
-        System.out.println("Java: Discovered a new peer " + peer);
+    public static Boolean printDiscoveryMessage(String peer, Boolean isNew){
+        System.out.println("Java: Discovered " + (isNew ? "a new" : "again") + " peer " + peer);
         return true;
     }
 
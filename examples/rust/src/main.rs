@@ -1,9 +1,12 @@
 extern crate target_info;
 use clap::{App, AppSettings, Arg, ArgMatches};
 use env_logger::Env;
-use mothra::{cli_app, gossip, Mothra, NetworkMessage, Subscriber, TaskExecutor};
-use slog::{debug, info, o, trace, warn, Drain, Level, Logger};
-use std::{thread, time};
+use mothra::{
+    cli_app, enr, gossip, keys, log_level::RuntimeLevelFilter, ping, rpc::ProtocolId, GossipTiming,
+    MessageTopic, Mothra, NetworkErrorKind, NetworkMessage, Subscriber, TaskExecutor,
+};
+use slog::{debug, info, o, trace, warn, Drain, Logger};
+use std::time;
 use tokio::runtime::Runtime;
 use tokio::{signal, sync::mpsc, task};
 
@@ -18,26 +21,60 @@ impl Client {
 impl Subscriber for Client {
     fn init(&mut self, network_send: mpsc::UnboundedSender<NetworkMessage>, fork_id: Vec<u8>) {}
 
-    fn discovered_peer(&self, peer: String) {
+    fn discovered_peer(&self, peer: String, new: bool) {
         println!("Rust: discovered peer");
         println!("peer={:?}", peer);
+        println!("new={:?}", new);
     }
 
-    fn receive_gossip(&self, message_id: String, sequence_number: u64, agent_string: String, peer_id: String, topic: String, data: Vec<u8>) {
+    fn receive_gossip(
+        &self,
+        message_id: String,
+        sequence_number: u64,
+        agent_string: String,
+        peer_id: String,
+        topic: String,
+        topics: Vec<MessageTopic>,
+        data: Vec<u8>,
+        timing: GossipTiming,
+    ) {
         println!("Rust: received gossip");
         println!("message id={:?}", message_id);
         println!("peer id={:?}", peer_id);
         println!("topic={:?}", topic);
+        for message_topic in topics {
+            println!(
+                "topic hash={:?} known={}",
+                message_topic.hash.as_str(),
+                message_topic.topic.is_some()
+            );
+        }
+        println!("received at={:?}", timing.received_at);
         println!("data={:?}", String::from_utf8_lossy(&data));
     }
 
-    fn receive_rpc(&self, method: String, req_resp: u8, peer: String, data: Vec<u8>) {
+    fn receive_rpc(
+        &self,
+        method: String,
+        req_resp: u8,
+        protocol: Option<ProtocolId>,
+        peer: String,
+        data: Vec<u8>,
+    ) {
         println!("Rust: received rpc");
         println!("method={:?}", method);
         println!("req_resp={:?}", req_resp);
+        println!(
+            "protocol={:?}",
+            protocol.map(|protocol| protocol.to_string())
+        );
         println!("peer={:?}", peer);
         println!("data={:?}", String::from_utf8_lossy(&data));
     }
+
+    fn on_error(&self, kind: NetworkErrorKind, context: String) {
+        println!("Rust: network error {:?}: {}", kind, context);
+    }
 }
 
 fn main() {
@@ -62,26 +99,28 @@ fn main() {
         println!("Foo flag found");
     }
 
+    let mothra_matches = matches.subcommand_matches("mothra").unwrap();
     let config = Mothra::get_config(
         Some("rust-example".into()),
         Some(format!("v{}-unstable", env!("CARGO_PKG_VERSION"))),
         Some("rust-example/libp2p".into()),
-        &matches.subcommand_matches("mothra").unwrap(),
+        &mothra_matches,
     );
+    // the enr and key utilities run without starting a node
+    match mothra_matches.subcommand() {
+        ("enr", Some(args)) => std::process::exit(enr::run(&config, args)),
+        ("generate-key", Some(args)) => std::process::exit(keys::generate_key(&config, args)),
+        ("import-key", Some(args)) => std::process::exit(keys::import_key(&config, args)),
+        ("export-peer-id", Some(_)) => std::process::exit(keys::export_peer_id(&config)),
+        _ => {}
+    }
     // configure logging
     env_logger::Builder::from_env(Env::default()).init();
     let decorator = slog_term::TermDecorator::new().build();
     let drain = slog_term::CompactFormat::new(decorator).build().fuse();
     let drain = slog_async::Async::new(drain).build();
-    let drain = match config.debug_level.as_str() {
-        "info" => drain.filter_level(Level::Info),
-        "debug" => drain.filter_level(Level::Debug),
-        "trace" => drain.filter_level(Level::Trace),
-        "warn" => drain.filter_level(Level::Warning),
-        "error" => drain.filter_level(Level::Error),
-        "crit" => drain.filter_level(Level::Critical),
-        _ => drain.filter_level(Level::Info),
-    };
+    // filtered on the config's log level, which NetworkMessage::SetLogLevel changes
+    let drain = RuntimeLevelFilter::new(drain, config.log_level.clone());
     let slog = Logger::root(drain.fuse(), o!());
     let log = slog.new(o!("Rust-Example" => "Rust-Example"));
     let enr_fork_id = [0u8; 32].to_vec();
@@ -98,33 +137,54 @@ fn main() {
         log.new(o!("Rust-Example" => "TaskExecutor")),
     );
     let mothra_log = log.new(o!("Rust-Example" => "Mothra"));
+
+    // run the ping diagnostic instead of the example node if requested
+    if let Some(ping_matches) = mothra_matches.subcommand_matches("ping") {
+        let code = runtime.block_on(ping::run(config, ping_matches, &task_executor, &mothra_log));
+        let _ = network_exit_signal.fire();
+        runtime.shutdown_timeout(tokio::time::Duration::from_millis(300));
+        std::process::exit(code);
+    }
+
+    let shutdown_grace_period = config.shutdown_grace_period;
     runtime.block_on(async move {
-        task::spawn_blocking(move || {
-            let (network_globals, network_send) = Mothra::new(
-                config,
-                enr_fork_id,
-                meta_data,
-                ping_data,
-                &task_executor,
-                client,
-                mothra_log.clone(),
-            )
-            .unwrap();
+        let (network_globals, network_send) = Mothra::new(
+            config,
+            enr_fork_id,
+            meta_data,
+            ping_data,
+            &task_executor,
+            client,
+            mothra_log.clone(),
+        )
+        .unwrap();
+        task::spawn(async move {
             let dur = time::Duration::from_secs(5);
             loop {
-                thread::sleep(dur);
+                tokio::time::delay_for(dur).await;
                 let topic = "/mothra/topic1".to_string();
                 let data = format!("Hello from Rust.  Elapsed time: {:?}", start.elapsed())
                     .as_bytes()
                     .to_vec();
-                gossip(network_send.clone(), topic, data, mothra_log.clone());
+                // the network service has stopped
+                if gossip(network_send.clone(), topic, data, mothra_log.clone()).is_err() {
+                    break;
+                }
             }
         });
         // block the current thread until SIGINT is received.
         signal::ctrl_c().await.expect("failed to listen for event");
+
+        warn!(log, "Sending shutdown signal.");
+        let _ = network_exit_signal.fire();
+        // give the node its grace period to answer pending requests and say goodbye to its peers
+        let _ = tokio::time::timeout(shutdown_grace_period, async {
+            while network_globals.connected_peers() > 0 {
+                tokio::time::delay_for(time::Duration::from_millis(100)).await;
+            }
+        })
+        .await;
     });
 
-    warn!(log, "Sending shutdown signal.");
-    let _ = network_exit_signal.fire();
     runtime.shutdown_timeout(tokio::time::Duration::from_millis(300));
 }
@@ -0,0 +1,307 @@
+//! The invariants a soak run must hold, checked over the samples taken during the run.
+//!
+//! Samples taken during the warm-up, while the nodes connect and their meshes form, are ignored.
+use mothra::{MemoryUsageSnapshot, QueueDepthsSnapshot};
+use serde_derive::Serialize;
+
+/// The state of every node at one point of the run.
+#[derive(Clone, Debug, Serialize)]
+pub struct Sample {
+    /// The seconds since the nodes were started.
+    pub elapsed: u64,
+    /// The resident memory of the process in kB, if it could be read.
+    pub rss_kb: Option<u64>,
+    pub nodes: Vec<NodeSample>,
+}
+
+/// The state of a node at one point of the run.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct NodeSample {
+    /// The peers the node is connected to.
+    pub peers: usize,
+    /// The messages the node has published so far.
+    pub published: usize,
+    /// The messages the node has received so far.
+    pub received: usize,
+    pub queues: QueueDepthsSnapshot,
+    /// The estimated memory held by the node's caches and queues.
+    pub memory: MemoryUsageSnapshot,
+}
+
+/// What the invariants tolerate.
+pub struct Limits {
+    /// The seconds after the start whose samples are ignored.
+    pub warm_up: u64,
+    /// The growth of the resident memory allowed between the start and the end of the run, as a
+    /// fraction of the memory at the start.
+    pub max_memory_growth: f64,
+    /// The number of consecutive samples after which a queue or a node that makes no progress
+    /// is stuck.
+    pub stuck_samples: usize,
+}
+
+/// The outcome of an invariant.
+#[derive(Debug, Serialize)]
+pub struct Invariant {
+    pub name: &'static str,
+    pub passed: bool,
+    /// What failed, or the figures the invariant held with.
+    pub detail: String,
+}
+
+/// Checks every invariant over `samples`.
+pub fn check(samples: &[Sample], limits: &Limits) -> Vec<Invariant> {
+    let steady: Vec<&Sample> = samples
+        .iter()
+        .filter(|sample| sample.elapsed >= limits.warm_up)
+        .collect();
+    vec![
+        memory_growth(&steady, limits),
+        peer_decay(&steady),
+        stuck_queues(&steady, limits),
+        stalled_delivery(&steady, limits),
+    ]
+}
+
+/// The resident memory at the end of the run stays within the allowed growth. The mean of the
+/// first quarter of the samples is compared to that of the last, so a single spike of
+/// allocations does not fail the run.
+fn memory_growth(steady: &[&Sample], limits: &Limits) -> Invariant {
+    let rss: Vec<u64> = steady.iter().filter_map(|sample| sample.rss_kb).collect();
+    let quarter = rss.len() / 4;
+    if quarter == 0 {
+        return Invariant {
+            name: "memory_growth",
+            passed: true,
+            detail: format!("too few memory samples ({}) to measure growth", rss.len()),
+        };
+    }
+    let mean = |values: &[u64]| values.iter().sum::<u64>() as f64 / values.len() as f64;
+    let early = mean(&rss[..quarter]);
+    let late = mean(&rss[rss.len() - quarter..]);
+    let growth = (late - early) / early;
+    Invariant {
+        name: "memory_growth",
+        passed: growth <= limits.max_memory_growth,
+        detail: format!(
+            "resident memory went from {:.0} kB to {:.0} kB, a growth of {:.1}% (at most {:.1}%)",
+            early,
+            late,
+            growth * 100.0,
+            limits.max_memory_growth * 100.0
+        ),
+    }
+}
+
+/// Every node ends the run with at least the peers it had after the warm-up, and had some.
+fn peer_decay(steady: &[&Sample]) -> Invariant {
+    let (first, last) = match (steady.first(), steady.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => {
+            return Invariant {
+                name: "peer_decay",
+                passed: false,
+                detail: "no samples after the warm-up".into(),
+            }
+        }
+    };
+    let failures: Vec<String> = first
+        .nodes
+        .iter()
+        .zip(&last.nodes)
+        .enumerate()
+        .filter_map(|(index, (start, end))| {
+            if start.peers == 0 {
+                Some(format!("node {} had no peers after the warm-up", index))
+            } else if end.peers < start.peers {
+                Some(format!(
+                    "node {} went from {} to {} peers",
+                    index, start.peers, end.peers
+                ))
+            } else {
+                None
+            }
+        })
+        .collect();
+    outcome("peer_decay", failures, "no node lost peers")
+}
+
+/// No queue stays non-empty without ever shrinking for `stuck_samples` samples.
+fn stuck_queues(steady: &[&Sample], limits: &Limits) -> Invariant {
+    let mut failures = Vec::new();
+    let nodes = steady.first().map_or(0, |sample| sample.nodes.len());
+    for index in 0..nodes {
+        let depths: Vec<[(&str, usize); 5]> = steady
+            .iter()
+            .map(|sample| depths(&sample.nodes[index].queues))
+            .collect();
+        for queue in 0..5 {
+            let stuck = first_run(&depths, limits.stuck_samples, |previous, next| {
+                previous[queue].1 > 0 && next[queue].1 >= previous[queue].1
+            });
+            if let Some(start) = stuck {
+                failures.push(format!(
+                    "the {} queue of node {} is stuck from {}s, at depth {}",
+                    depths[start][queue].0, index, steady[start].elapsed, depths[start][queue].1
+                ));
+            }
+        }
+    }
+    outcome("stuck_queues", failures, "every queue drained")
+}
+
+/// No node stops receiving messages for `stuck_samples` samples while the others publish.
+fn stalled_delivery(steady: &[&Sample], limits: &Limits) -> Invariant {
+    let mut failures = Vec::new();
+    let nodes = steady.first().map_or(0, |sample| sample.nodes.len());
+    for index in 0..nodes {
+        // the messages received by the node, and those published by the others
+        let progress: Vec<(usize, usize)> = steady
+            .iter()
+            .map(|sample| {
+                let published: usize = sample.nodes.iter().map(|node| node.published).sum();
+                let node = &sample.nodes[index];
+                (node.received, published - node.published)
+            })
+            .collect();
+        let stalled = first_run(&progress, limits.stuck_samples, |previous, next| {
+            next.0 == previous.0 && next.1 > previous.1
+        });
+        if let Some(start) = stalled {
+            failures.push(format!(
+                "node {} stopped receiving from {}s, after {} messages",
+                index, steady[start].elapsed, progress[start].0
+            ));
+        }
+    }
+    outcome("stalled_delivery", failures, "every node kept receiving")
+}
+
+fn depths(queues: &QueueDepthsSnapshot) -> [(&'static str, usize); 5] {
+    [
+        ("behaviour_events", queues.behaviour_events),
+        ("peers_to_dc", queues.peers_to_dc),
+        ("rpc_events", queues.rpc_events),
+        ("network_messages", queues.network_messages),
+        ("deliveries", queues.deliveries),
+    ]
+}
+
+/// Returns the index of the first of `run` consecutive values in which `stuck` holds between
+/// each value and the next.
+fn first_run<T>(values: &[T], run: usize, stuck: impl Fn(&T, &T) -> bool) -> Option<usize> {
+    let mut start = 0;
+    for (index, pair) in values.windows(2).enumerate() {
+        if !stuck(&pair[0], &pair[1]) {
+            start = index + 1;
+        } else if index + 2 - start >= run {
+            return Some(start);
+        }
+    }
+    None
+}
+
+fn outcome(name: &'static str, failures: Vec<String>, success: &str) -> Invariant {
+    Invariant {
+        name,
+        passed: failures.is_empty(),
+        detail: if failures.is_empty() {
+            success.into()
+        } else {
+            failures.join("; ")
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: Limits = Limits {
+        warm_up: 10,
+        max_memory_growth: 0.5,
+        stuck_samples: 3,
+    };
+
+    /// Two nodes publishing and receiving a message per sample, with `rss_kb` and `peers`.
+    fn samples(rss_kb: &[u64], peers: &[usize]) -> Vec<Sample> {
+        rss_kb
+            .iter()
+            .zip(peers)
+            .enumerate()
+            .map(|(index, (&rss_kb, &peers))| {
+                let node = NodeSample {
+                    peers,
+                    published: index,
+                    received: index,
+                    ..NodeSample::default()
+                };
+                Sample {
+                    elapsed: index as u64 * 10,
+                    rss_kb: Some(rss_kb),
+                    nodes: vec![node.clone(), node],
+                }
+            })
+            .collect()
+    }
+
+    fn passed(invariants: &[Invariant]) -> Vec<(&'static str, bool)> {
+        invariants
+            .iter()
+            .map(|invariant| (invariant.name, invariant.passed))
+            .collect()
+    }
+
+    #[test]
+    fn test_steady_run_passes() {
+        // the warm-up is ignored
+        let samples = samples(&[10, 100, 100, 110, 100, 120], &[0, 1, 1, 1, 1, 1]);
+        assert!(check(&samples, &LIMITS)
+            .iter()
+            .all(|invariant| invariant.passed));
+    }
+
+    #[test]
+    fn test_growth_and_decay_fail() {
+        let samples = samples(&[10, 100, 100, 160, 200, 200], &[0, 1, 1, 1, 1, 0]);
+        assert_eq!(
+            passed(&check(&samples, &LIMITS)),
+            vec![
+                ("memory_growth", false),
+                ("peer_decay", false),
+                ("stuck_queues", true),
+                ("stalled_delivery", true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stuck_queues_and_delivery_fail() {
+        let mut samples = samples(&[100; 6], &[1; 6]);
+        for (sample, depth) in samples.iter_mut().zip(&[0, 3, 2, 5, 5, 6]) {
+            sample.nodes[1].queues.deliveries = *depth;
+        }
+        // node 0 stops receiving at the third sample
+        for sample in samples.iter_mut().skip(3) {
+            sample.nodes[0].received = 2;
+        }
+        let invariants = check(&samples, &LIMITS);
+        assert_eq!(
+            invariants[2].detail,
+            "the deliveries queue of node 1 is stuck from 20s, at depth 2"
+        );
+        assert_eq!(
+            invariants[3].detail,
+            "node 0 stopped receiving from 20s, after 2 messages"
+        );
+    }
+
+    #[test]
+    fn test_first_run() {
+        let values = [1, 2, 2, 1, 3, 4, 5];
+        let rising = |a: &i32, b: &i32| b >= a;
+        assert_eq!(first_run(&values, 3, rising), Some(0));
+        assert_eq!(first_run(&values, 4, rising), Some(3));
+        assert_eq!(first_run(&values, 5, rising), None);
+    }
+}
@@ -0,0 +1,360 @@
+//! Runs a small network in one process for hours, publishing continuously, and checks that it
+//! stays healthy: memory does not grow without bound, nodes keep their peers and no queue gets
+//! stuck. The samples taken and the outcome of each invariant are written out as JSON, and the
+//! process exits with 1 if an invariant failed.
+mod invariants;
+
+use clap::{App, Arg, ArgMatches};
+use invariants::{check, Invariant, Limits, NodeSample, Sample};
+use mothra::{
+    cli_app, gossip, log_level::RuntimeLevelFilter, rpc::ProtocolId, GossipTiming, MessageTopic,
+    Mothra, NetworkGlobals, NetworkMessage, Subscriber, TaskExecutor,
+};
+use serde_derive::Serialize;
+use slog::{info, o, warn, Drain, Logger};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+
+/// The topic every node subscribes and publishes to.
+const TOPIC: &str = "/mothra/soak";
+
+/// Counts the gossip a node receives.
+struct Counter {
+    received: Arc<AtomicUsize>,
+}
+
+impl Subscriber for Counter {
+    fn init(&mut self, _send: mpsc::UnboundedSender<NetworkMessage>, _fork_id: Vec<u8>) {}
+
+    fn discovered_peer(&self, _peer: String, _new: bool) {}
+
+    fn receive_gossip(
+        &self,
+        _message_id: String,
+        _sequence_number: u64,
+        _agent_string: String,
+        _peer_id: String,
+        _topic: String,
+        _topics: Vec<MessageTopic>,
+        _data: Vec<u8>,
+        _timing: GossipTiming,
+    ) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn receive_rpc(
+        &self,
+        _method: String,
+        _req_resp: u8,
+        _protocol: Option<ProtocolId>,
+        _peer: String,
+        _data: Vec<u8>,
+    ) {
+    }
+}
+
+/// A running node of the soak network.
+struct Node {
+    globals: Arc<NetworkGlobals>,
+    published: Arc<AtomicUsize>,
+    received: Arc<AtomicUsize>,
+}
+
+impl Node {
+    fn sample(&self) -> NodeSample {
+        NodeSample {
+            peers: self.globals.connected_peers(),
+            published: self.published.load(Ordering::Relaxed),
+            received: self.received.load(Ordering::Relaxed),
+            queues: self.globals.queue_depths(),
+            memory: self.globals.memory_usage(),
+        }
+    }
+}
+
+/// The results of a run.
+#[derive(Serialize)]
+struct Report {
+    nodes: usize,
+    duration: u64,
+    passed: bool,
+    invariants: Vec<Invariant>,
+    samples: Vec<Sample>,
+}
+
+fn main() {
+    let matches = App::new("soak")
+        .version(clap::crate_version!())
+        .about("Runs a small Mothra network for a long time and checks that it stays healthy")
+        .arg(number_arg(
+            "nodes",
+            "COUNT",
+            "The number of nodes to run.",
+            "4",
+        ))
+        .arg(number_arg(
+            "duration",
+            "SECONDS",
+            "How long to run the network.",
+            "14400",
+        ))
+        .arg(number_arg(
+            "warm-up",
+            "SECONDS",
+            "The time the nodes have to connect, whose samples are not checked.",
+            "60",
+        ))
+        .arg(number_arg(
+            "publish-interval",
+            "MILLIS",
+            "The time between the messages each node publishes.",
+            "100",
+        ))
+        .arg(number_arg(
+            "sample-interval",
+            "SECONDS",
+            "The time between samples of the memory, peers and queues.",
+            "30",
+        ))
+        .arg(
+            Arg::with_name("max-memory-growth")
+                .long("max-memory-growth")
+                .value_name("PERCENT")
+                .help("How much the resident memory may grow over the run.")
+                .takes_value(true)
+                .default_value("50"),
+        )
+        .arg(number_arg(
+            "stuck-samples",
+            "COUNT",
+            "The consecutive samples without progress after which a queue or node is stuck.",
+            "10",
+        ))
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .value_name("FILE")
+                .help("The file to write the results to, as JSON. Defaults to stdout.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("node-args")
+                .long("node-args")
+                .value_name("ARGS")
+                .help("Whitespace separated mothra arguments given to every node.")
+                .takes_value(true)
+                .allow_hyphen_values(true),
+        )
+        .get_matches();
+
+    let nodes = number(&matches, "nodes") as usize;
+    let duration = Duration::from_secs(number(&matches, "duration"));
+    let publish_interval = Duration::from_millis(number(&matches, "publish-interval"));
+    let sample_interval = Duration::from_secs(number(&matches, "sample-interval"));
+    let limits = Limits {
+        warm_up: number(&matches, "warm-up"),
+        max_memory_growth: match matches
+            .value_of("max-memory-growth")
+            .unwrap()
+            .parse::<f64>()
+        {
+            Ok(percent) => percent / 100.0,
+            Err(_) => exit_invalid("max-memory-growth"),
+        },
+        stuck_samples: number(&matches, "stuck-samples") as usize,
+    };
+    if nodes < 2 || publish_interval.as_millis() == 0 || sample_interval.as_secs() == 0 {
+        eprintln!("At least two nodes and non-zero intervals are required");
+        std::process::exit(2);
+    }
+    let node_args: Vec<String> = matches
+        .value_of("node-args")
+        .map(|args| args.split_whitespace().map(String::from).collect())
+        .unwrap_or_default();
+
+    let decorator = slog_term::TermDecorator::new().build();
+    let drain = slog_term::CompactFormat::new(decorator).build().fuse();
+    let drain = Arc::new(slog_async::Async::new(drain).build().fuse());
+    let log = Logger::root(drain.clone(), o!("soak" => "soak"));
+
+    let data_dir = std::env::temp_dir().join(format!("mothra-soak-{}", std::process::id()));
+    let mut runtime = Runtime::new()
+        .map_err(|e| format!("Failed to start runtime: {:?}", e))
+        .unwrap();
+    let (exit_signal, exit) = exit_future::signal();
+    let executor = TaskExecutor::new(runtime.handle().clone(), exit, log.clone());
+
+    let samples = runtime.block_on(async {
+        let mut running = Vec::with_capacity(nodes);
+        let mut first_address = None;
+        for index in 0..nodes {
+            let mut args = vec![
+                "mothra".to_string(),
+                "--auto-ports".into(),
+                "--disable-discovery".into(),
+                "--topics".into(),
+                TOPIC.into(),
+                "--datadir".into(),
+                data_dir
+                    .join(format!("node{}", index))
+                    .to_string_lossy()
+                    .into_owned(),
+            ];
+            // every node dials the first, and gossipsub spreads the messages from there
+            if let Some(address) = &first_address {
+                args.push("--libp2p-addresses".into());
+                args.push(address.clone());
+            }
+            args.extend(node_args.iter().cloned());
+            let node_matches = cli_app().get_matches_from(args);
+            let config = Mothra::get_config(
+                Some("soak".into()),
+                Some(format!("v{}", env!("CARGO_PKG_VERSION"))),
+                Some("soak/libp2p".into()),
+                &node_matches,
+            );
+            if first_address.is_none() {
+                first_address = Some(format!(
+                    "/ip4/127.0.0.1/tcp/{}",
+                    config.network_config.libp2p_port
+                ));
+            }
+            let node_log = Logger::root(
+                RuntimeLevelFilter::new(drain.clone(), config.log_level.clone()).fuse(),
+                o!("node" => index),
+            );
+            let received = Arc::new(AtomicUsize::new(0));
+            let client = Box::new(Counter {
+                received: received.clone(),
+            }) as Box<dyn Subscriber + Send>;
+            let (globals, network_send) = Mothra::new(
+                config,
+                [0u8; 32].to_vec(),
+                [0u8; 32].to_vec(),
+                [0u8; 32].to_vec(),
+                &executor,
+                client,
+                node_log.clone(),
+            )
+            .unwrap();
+            let published = Arc::new(AtomicUsize::new(0));
+            tokio::spawn(publish(
+                index,
+                network_send,
+                publish_interval,
+                published.clone(),
+                node_log,
+            ));
+            running.push(Node {
+                globals,
+                published,
+                received,
+            });
+        }
+        info!(log, "Started the soak network"; "nodes" => nodes, "duration" => ?duration);
+
+        let start = Instant::now();
+        let mut samples = Vec::new();
+        let mut timer = tokio::time::interval(sample_interval);
+        while start.elapsed() < duration {
+            timer.tick().await;
+            let sample = Sample {
+                elapsed: start.elapsed().as_secs(),
+                rss_kb: resident_memory(),
+                nodes: running.iter().map(Node::sample).collect(),
+            };
+            info!(log, "Sampled the soak network"; "elapsed" => sample.elapsed, "rss_kb" => ?sample.rss_kb);
+            samples.push(sample);
+        }
+        samples
+    });
+    let _ = exit_signal.fire();
+    runtime.shutdown_timeout(Duration::from_secs(1));
+    let _ = std::fs::remove_dir_all(&data_dir);
+
+    let invariants = check(&samples, &limits);
+    let passed = invariants.iter().all(|invariant| invariant.passed);
+    for invariant in invariants.iter().filter(|invariant| !invariant.passed) {
+        warn!(log, "Invariant failed"; "invariant" => invariant.name, "detail" => &invariant.detail);
+    }
+    let report = Report {
+        nodes,
+        duration: duration.as_secs(),
+        passed,
+        invariants,
+        samples,
+    };
+    let json = serde_json::to_string_pretty(&report).expect("the report serializes");
+    match matches.value_of("output") {
+        Some(path) => {
+            if let Err(e) = std::fs::write(path, json) {
+                eprintln!("Failed to write the results to {}: {}", path, e);
+                std::process::exit(2);
+            }
+        }
+        None => println!("{}", json),
+    }
+    std::process::exit(if passed { 0 } else { 1 });
+}
+
+/// Publishes a message every `interval` until the node stops.
+async fn publish(
+    index: usize,
+    network_send: mpsc::UnboundedSender<NetworkMessage>,
+    interval: Duration,
+    published: Arc<AtomicUsize>,
+    log: Logger,
+) {
+    let mut timer = tokio::time::interval(interval);
+    loop {
+        timer.tick().await;
+        let sequence = published.load(Ordering::Relaxed);
+        // the sequence number makes every message unique
+        let data = format!("soak message {} from node {}", sequence, index).into_bytes();
+        if gossip(network_send.clone(), TOPIC.into(), data, log.clone()).is_err() {
+            break;
+        }
+        published.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns the resident memory of the process in kB, where `/proc` is available.
+fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find(|line| line.starts_with("VmRSS:"))?
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()
+}
+
+fn number_arg<'a>(
+    name: &'a str,
+    value_name: &'a str,
+    help: &'a str,
+    default: &'a str,
+) -> Arg<'a, 'a> {
+    Arg::with_name(name)
+        .long(name)
+        .value_name(value_name)
+        .help(help)
+        .takes_value(true)
+        .default_value(default)
+}
+
+fn number(matches: &ArgMatches, name: &str) -> u64 {
+    match matches.value_of(name).unwrap().parse() {
+        Ok(value) => value,
+        Err(_) => exit_invalid(name),
+    }
+}
+
+fn exit_invalid(name: &str) -> ! {
+    eprintln!("Invalid --{}", name);
+    std::process::exit(2)
+}